@@ -0,0 +1,23 @@
+//! Embed the git commit of the build in the binary, for the metadata of every run.
+
+use std::process::Command;
+
+fn main() {
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    println!("cargo:rerun-if-changed=.git/refs");
+    println!("cargo:rerun-if-env-changed=GIT_HASH");
+    // A hash given by the environment, for example by CI, is used as is.
+    if std::env::var_os("GIT_HASH").is_some() {
+        return;
+    }
+
+    let hash = Command::new("git")
+        .args(["rev-parse", "HEAD"])
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .map(|output| String::from_utf8_lossy(&output.stdout).trim().to_string());
+    if let Some(hash) = hash {
+        println!("cargo:rustc-env=GIT_HASH={hash}");
+    }
+}
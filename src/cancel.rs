@@ -0,0 +1,154 @@
+//! Stopping a running script early, for example when the user presses Ctrl-C or the run exceeds
+//! `--max-runtime`.
+
+use std::{fmt, future::Future, sync::Arc};
+
+use tokio::sync::watch;
+
+/// Signals a running script to stop. Clones share the same state, so cancelling one cancels all
+/// of them.
+#[derive(Debug, Clone)]
+pub struct CancellationToken {
+    reason: Arc<watch::Sender<Option<Reason>>>,
+    /// How many parts of the script are writing their results, see
+    /// [CancellationToken::finishing].
+    finishing: Arc<watch::Sender<usize>>,
+}
+
+/// Why a script was cancelled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Reason {
+    /// The user pressed Ctrl-C.
+    User,
+    /// The run took longer than `--max-runtime`.
+    Timeout,
+}
+
+impl fmt::Display for Reason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Reason::User => f.write_str("aborted by the user"),
+            Reason::Timeout => f.write_str("exceeded the maximum runtime"),
+        }
+    }
+}
+
+/// The error a script returns when it stopped because it was cancelled.
+#[derive(Debug)]
+pub struct Aborted;
+
+impl fmt::Display for Aborted {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("the run was aborted")
+    }
+}
+
+impl std::error::Error for Aborted {}
+
+/// The error of a run that was cancelled because it took longer than `--max-runtime`.
+#[derive(Debug)]
+pub struct TimedOut;
+
+impl fmt::Display for TimedOut {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("the run exceeded its maximum runtime")
+    }
+}
+
+impl std::error::Error for TimedOut {}
+
+/// Marks a part of a script that writes its results, until it is dropped.
+#[must_use = "the phase ends when this is dropped"]
+pub struct Finishing {
+    finishing: Arc<watch::Sender<usize>>,
+}
+
+impl Drop for Finishing {
+    fn drop(&mut self) {
+        self.finishing.send_modify(|n| *n -= 1);
+    }
+}
+
+impl Default for CancellationToken {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        Self {
+            reason: Arc::new(watch::Sender::new(None)),
+            finishing: Arc::new(watch::Sender::new(0)),
+        }
+    }
+
+    /// Ask everything holding this token to stop, because the user asked to.
+    pub fn cancel(&self) {
+        self.cancel_for(Reason::User);
+    }
+
+    /// Ask everything holding this token to stop for `reason`. Only the first reason is kept.
+    pub fn cancel_for(&self, reason: Reason) {
+        self.reason.send_if_modified(|current| {
+            if current.is_some() {
+                return false;
+            }
+            *current = Some(reason);
+            true
+        });
+    }
+
+    /// Why the token was cancelled, `None` if it was not.
+    pub fn reason(&self) -> Option<Reason> {
+        *self.reason.borrow()
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.reason().is_some()
+    }
+
+    /// Wait until the token is cancelled.
+    pub async fn cancelled(&self) {
+        // The sender is kept alive by `self`, so waiting can not fail.
+        _ = self.reason.subscribe().wait_for(Option::is_some).await;
+    }
+
+    /// Return [Aborted] if the token was cancelled, for checking between the phases of a script.
+    pub fn check(&self) -> anyhow::Result<()> {
+        if self.is_cancelled() {
+            return Err(Aborted.into());
+        }
+        Ok(())
+    }
+
+    /// Run `future` until it completes or the token is cancelled, in which case it is dropped and
+    /// [Aborted] is returned.
+    pub async fn run<T>(
+        &self,
+        future: impl Future<Output = anyhow::Result<T>>,
+    ) -> anyhow::Result<T> {
+        tokio::select! {
+            result = future => result,
+            () = self.cancelled() => Err(Aborted.into()),
+        }
+    }
+
+    /// Mark that the script is writing its results until the returned guard is dropped. A run
+    /// that exceeds `--max-runtime` meanwhile is given a grace period to finish this first.
+    pub fn finishing(&self) -> Finishing {
+        self.finishing.send_modify(|n| *n += 1);
+        Finishing {
+            finishing: self.finishing.clone(),
+        }
+    }
+
+    pub fn is_finishing(&self) -> bool {
+        *self.finishing.borrow() > 0
+    }
+
+    /// Wait until no part of the script is writing its results.
+    pub async fn finished(&self) {
+        _ = self.finishing.subscribe().wait_for(|&n| n == 0).await;
+    }
+}
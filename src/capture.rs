@@ -1,15 +1,32 @@
 use std::{
     io::{Cursor, Read},
     path::PathBuf,
-    time::Duration,
+    time::{Duration, SystemTime},
 };
 
 use anyhow::Context;
 use openssh::Stdio;
+use serde::{Deserialize, Serialize};
 use tokio::fs::File;
 use tracing::debug;
 
-use crate::hosts::Host;
+use crate::{
+    command_log,
+    hosts::Host,
+    utils::{log_text, RemoteCmd},
+};
+
+pub mod analysis;
+pub mod anonymize;
+pub mod csv;
+pub mod merge;
+mod pcapng;
+pub mod trim;
+
+pub use anonymize::{anonymize, AnonymizePolicy, AnonymizeStats};
+pub use csv::{export_csv, CsvOptions, CsvStats};
+pub use merge::{merge, MergeOptions, MergeStats};
+pub use trim::{trim, TrimStats};
 
 /// Defines options for capturing on a network interface.
 #[derive(Debug)]
@@ -22,6 +39,8 @@ pub struct CaptureConfig {
     ///
     /// The file provided path must not yet exists but its parent directory is expected to exist.
     pub output_path: Option<PathBuf>,
+    /// Only capture frames matching this capture filter, in the syntax of `tshark -f`.
+    pub filter: Option<String>,
 }
 
 /// A condition to tell wireshark when to stop capturing.
@@ -44,10 +63,41 @@ pub enum Capture {
     Buffer(Vec<u8>),
 }
 
+/// Statistics about a completed capture.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct CaptureStats {
+    /// Size of the capture in bytes.
+    pub bytes: u64,
+    /// Number of packets captured, as reported by tshark.
+    pub packets: Option<u64>,
+    /// Number of packets dropped during the capture, as reported by tshark.
+    pub dropped: Option<u64>,
+}
+
+impl CaptureStats {
+    /// Read the packet counts from the report tshark prints to its stderr when it exits, which
+    /// contains lines like `1234 packets captured` and `5 packets dropped from mon0`.
+    fn parse_tshark_report(&mut self, stderr: &str) {
+        for line in stderr.lines().map(str::trim) {
+            let Some((count, rest)) = line.split_once(' ') else {
+                continue;
+            };
+            let Ok(count) = count.parse::<u64>() else {
+                continue;
+            };
+            if rest.ends_with("captured") {
+                self.packets = Some(count);
+            } else if rest.contains("dropped") {
+                self.dropped = Some(self.dropped.unwrap_or(0) + count);
+            }
+        }
+    }
+}
+
 impl Host {
     /// Create a capture on a remote host and copy the capture over. Assumes wireshark (cli) is
     /// installed on the remote machine.
-    pub async fn capture(&self, config: &CaptureConfig) -> anyhow::Result<Capture> {
+    pub async fn capture(&self, config: &CaptureConfig) -> anyhow::Result<(Capture, CaptureStats)> {
         let mut result = match &config.output_path {
             Some(output_path) => {
                 let file = File::create_new(output_path)
@@ -63,62 +113,86 @@ impl Host {
             StopCondition::Packets(packets) => format!("packets:{packets}"),
         };
 
-        let mut capture = self
-            .session
-            .command("sudo")
-            .arg("tshark")
-            .arg("-F")
-            .arg("pcapng")
-            .arg("--interface")
-            .arg(&config.interface)
-            .arg("--autostop")
-            .arg(stop_condition)
-            .arg("-w")
-            .arg("-") // Output the pcapng capture to the stdout.
+        let command = RemoteCmd::new("sudo")
+            .args(["tshark", "-F", "pcapng", "--interface", &config.interface])
+            .args(["--autostop", &stop_condition])
+            .args(config.filter.iter().flat_map(|f| ["-f", f]))
+            // Output the pcapng capture to the stdout.
+            .args(["-w", "-"]);
+        let start = SystemTime::now();
+        let mut capture = command
+            .build(&self.session)
             .stdin(Stdio::null())
             .stdout(Stdio::piped())
             .stderr(Stdio::piped())
             .spawn()
             .await
+            .inspect_err(|err| command_log::record(self, &command, start, Err(err)))
             .context("failed to start remote wireshark capture")?;
 
         // SAFETY: `Stdio::piped()` is used above for the stdout, so it should be present.
         let stdout = capture.stdout().as_mut().expect("missing stdout handle");
         // Write the stdout of the process (the capture file in this case) to a file or buffer.
-        match &mut result {
-            Capture::File(outfile) => {
-                tokio::io::copy(stdout, outfile)
-                    .await
-                    .context("failed to write capture to file")?;
-            }
-            Capture::Buffer(items) => {
-                tokio::io::copy(stdout, items)
-                    .await
-                    .context("failed to write capture to buffer")?;
-            }
-        }
+        let bytes = match &mut result {
+            Capture::File(outfile) => tokio::io::copy(stdout, outfile)
+                .await
+                .context("failed to write capture to file")?,
+            Capture::Buffer(items) => tokio::io::copy(stdout, items)
+                .await
+                .context("failed to write capture to buffer")?,
+        };
 
         // Wait for the capture command to finish and ensure no error occurred.
         let output = capture
             .wait_with_output()
             .await
+            .inspect_err(|err| command_log::record(self, &command, start, Err(err)))
             .context("remote capture failed")?;
+        command_log::record(self, &command, start, Ok(output.status));
         if !output.status.success() {
             debug!(
                 host = self.id,
                 "Remote capture failed with status code {} and stderr output: \"{}\"",
                 output.status,
-                String::from_utf8_lossy(&output.stderr)
+                log_text(&output.stderr)
             );
             anyhow::bail!("remote capture failed with status {}", output.status);
         }
+        let mut stats = CaptureStats {
+            bytes,
+            ..Default::default()
+        };
+        stats.parse_tshark_report(&String::from_utf8_lossy(&output.stderr));
 
-        Ok(result)
+        Ok((result, stats))
+    }
+
+    /// Gracefully stop a capture that is running on `interface`, so that it can still be
+    /// collected. This stops all captures started through [Host::capture] on that interface.
+    pub async fn stop_capture(&self, interface: &str) -> anyhow::Result<()> {
+        // The bracket expression prevents the pattern from matching the `sudo` process itself.
+        let command = RemoteCmd::new("sudo").args([
+            "pkill",
+            "-INT",
+            "-f",
+            &format!("[t]shark -F pcapng --interface {interface}"),
+        ]);
+        let status = command
+            .output(self)
+            .await
+            .context("failed to stop remote capture")?
+            .status;
+
+        // `pkill` exits with 1 if no process matched, which means the capture already finished.
+        if !matches!(status.code(), Some(0 | 1)) {
+            anyhow::bail!("stopping capture exited with status code {status}");
+        }
+        Ok(())
     }
 }
 
 impl Capture {
-    pub async fn reader(self: Self) -> CaptureReader {
+    pub async fn reader(self) -> CaptureReader {
         match self {
             Capture::File(file) => CaptureReader::File(file.into_std().await),
             Capture::Buffer(items) => CaptureReader::Buffer(Cursor::new(items)),
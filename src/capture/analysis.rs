@@ -0,0 +1,390 @@
+//! Analysis of captured wireless traffic, using tshark on the controller.
+
+use std::{
+    collections::{BTreeMap, HashSet},
+    path::Path,
+    process::Stdio,
+};
+
+use anyhow::Context;
+use serde::Serialize;
+use tokio::process::Command;
+
+use crate::mac::MacAddr;
+
+/// Statistics about the frames in a capture.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct AirtimeStats {
+    /// The number of frames in the capture.
+    pub frames: u64,
+    /// The number of distinct BSSIDs that frames were sent in.
+    pub bss_count: usize,
+    /// The estimated total time the medium was busy in seconds, as the sum of the durations
+    /// tshark computes from the radiotap header of every frame.
+    pub airtime: f64,
+    /// The number of frames with a bad frame check sequence. Most drivers drop these unless the
+    /// monitor interface was created with the `fcsfail` flag.
+    pub bad_fcs: u64,
+}
+
+impl AirtimeStats {
+    /// The fraction of the time the medium was busy during `duration` seconds.
+    pub fn utilization(&self, duration: f64) -> f64 {
+        if duration > 0.0 {
+            self.airtime / duration
+        } else {
+            0.0
+        }
+    }
+}
+
+/// A tshark display filter matching the frames in the BSS `bssid`.
+pub fn bss_filter(bssid: MacAddr) -> String {
+    format!("wlan.bssid == {bssid}")
+}
+
+/// Analyze a capture stored in a pcapng file, optionally only the frames in the BSS `bssid`.
+/// Requires tshark to be installed on the controller.
+pub async fn airtime(capture: &Path, bssid: Option<MacAddr>) -> anyhow::Result<AirtimeStats> {
+    let filter = bssid.map(bss_filter);
+    let fields = tshark_fields(
+        capture,
+        filter.as_deref(),
+        &["wlan.bssid", "wlan_radio.duration", "radiotap.flags.badfcs"],
+    )
+    .await?;
+    Ok(parse_fields(&fields))
+}
+
+/// The airtime used by frames sent to or from each of the `stations`, in seconds.
+pub async fn station_airtime(
+    capture: &Path,
+    stations: &[MacAddr],
+) -> anyhow::Result<BTreeMap<MacAddr, f64>> {
+    let fields = tshark_fields(
+        capture,
+        None,
+        &["wlan.ra", "wlan.ta", "wlan_radio.duration"],
+    )
+    .await?;
+    Ok(parse_station_airtime(&fields, stations))
+}
+
+/// Parse the output of `tshark -T fields -e wlan.ra -e wlan.ta -e wlan_radio.duration` into the
+/// airtime of every station in seconds. Every station is included, also if it used no airtime.
+pub fn parse_station_airtime(fields: &str, stations: &[MacAddr]) -> BTreeMap<MacAddr, f64> {
+    let mut airtime_us: BTreeMap<MacAddr, u64> = stations.iter().map(|s| (*s, 0)).collect();
+    for line in fields.lines() {
+        let mut fields = line.split('\t').map(str::trim);
+        let (ra, ta) = (
+            fields.next().unwrap_or_default(),
+            fields.next().unwrap_or_default(),
+        );
+        let Some(duration) = fields.next().and_then(|v| v.parse::<u64>().ok()) else {
+            continue;
+        };
+        // Frames between two of the stations are counted for both of them.
+        for station in [ra, ta]
+            .into_iter()
+            .filter_map(|s| s.parse::<MacAddr>().ok())
+        {
+            if let Some(total) = airtime_us.get_mut(&station) {
+                *total += duration;
+            }
+        }
+    }
+    airtime_us
+        .into_iter()
+        .map(|(station, us)| (station, us as f64 / 1_000_000.0))
+        .collect()
+}
+
+/// The times at which association and reassociation responses were sent to the station with
+/// address `station`, in seconds since the unix epoch.
+///
+/// The times come from the clock of the monitor that made the capture.
+pub async fn association_responses(capture: &Path, station: MacAddr) -> anyhow::Result<Vec<f64>> {
+    let filter = format!(
+        "(wlan.fc.type_subtype == 0x0001 || wlan.fc.type_subtype == 0x0003) && wlan.da == {station}"
+    );
+    let fields = tshark_fields(capture, Some(&filter), &["frame.time_epoch"]).await?;
+    Ok(fields
+        .lines()
+        .filter_map(|line| line.trim().parse().ok())
+        .collect())
+}
+
+/// Frames a station sent that show its power save behavior.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct PowerSaveFrames {
+    /// PS-Poll frames, used to retrieve a single buffered frame in legacy power save.
+    pub ps_poll: u64,
+    /// Null data frames, which stations send to announce they go to sleep or wake up.
+    pub null: u64,
+    /// QoS null frames, which can also act as U-APSD triggers.
+    pub qos_null: u64,
+    /// Frames of any kind with the power management bit set.
+    pub power_management: u64,
+}
+
+/// Count the power save related frames sent by each of the `stations` in a capture.
+pub async fn power_save_frames(
+    capture: &Path,
+    stations: &[MacAddr],
+) -> anyhow::Result<BTreeMap<MacAddr, PowerSaveFrames>> {
+    let fields = tshark_fields(
+        capture,
+        Some(
+            "wlan.fc.pwrmgt == 1 || wlan.fc.type_subtype == 0x001a \
+             || wlan.fc.type_subtype == 0x0024 || wlan.fc.type_subtype == 0x002c",
+        ),
+        &["wlan.ta", "wlan.fc.type_subtype", "wlan.fc.pwrmgt"],
+    )
+    .await?;
+    Ok(parse_power_save_frames(&fields, stations))
+}
+
+/// Parse the output of `tshark -T fields -e wlan.ta -e wlan.fc.type_subtype -e wlan.fc.pwrmgt`
+/// into the power save frames of every station. Every station is included, also if it sent none.
+pub fn parse_power_save_frames(
+    fields: &str,
+    stations: &[MacAddr],
+) -> BTreeMap<MacAddr, PowerSaveFrames> {
+    let mut frames: BTreeMap<MacAddr, PowerSaveFrames> = stations
+        .iter()
+        .map(|s| (*s, PowerSaveFrames::default()))
+        .collect();
+    for line in fields.lines() {
+        let mut fields = line.split('\t').map(str::trim);
+        let Some(station) = fields
+            .next()
+            .and_then(|ta| ta.parse::<MacAddr>().ok())
+            .and_then(|ta| frames.get_mut(&ta))
+        else {
+            continue;
+        };
+        // Newer versions of tshark print the subtype in hexadecimal, older ones in decimal.
+        let subtype = fields.next().and_then(|v| match v.strip_prefix("0x") {
+            Some(hex) => u16::from_str_radix(hex, 16).ok(),
+            None => v.parse().ok(),
+        });
+        match subtype {
+            Some(0x1a) => station.ps_poll += 1,
+            Some(0x24) => station.null += 1,
+            Some(0x2c) => station.qos_null += 1,
+            _ => {}
+        }
+        if matches!(fields.next(), Some("1" | "True")) {
+            station.power_management += 1;
+        }
+    }
+    frames
+}
+
+/// The data frames sent to a group address in a capture.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct MulticastFrames {
+    pub frames: u64,
+    /// The size of the frames including their 802.11 headers.
+    pub bytes: u64,
+    /// The number of frames sent at every PHY data rate in Mbit/s, as reported by tshark.
+    pub data_rates: BTreeMap<String, u64>,
+}
+
+/// Count the data frames sent to the MAC address `destination` in a capture.
+pub async fn multicast_frames(
+    capture: &Path,
+    destination: MacAddr,
+) -> anyhow::Result<MulticastFrames> {
+    let filter = format!("wlan.fc.type == 2 && wlan.da == {destination}");
+    let fields = tshark_fields(
+        capture,
+        Some(&filter),
+        &["frame.len", "wlan_radio.data_rate"],
+    )
+    .await?;
+    Ok(parse_multicast_frames(&fields))
+}
+
+/// Parse the output of `tshark -T fields -e frame.len -e wlan_radio.data_rate`.
+pub fn parse_multicast_frames(fields: &str) -> MulticastFrames {
+    let mut frames = MulticastFrames::default();
+    for line in fields.lines() {
+        let mut fields = line.split('\t').map(str::trim);
+        let Some(len) = fields.next().and_then(|v| v.parse::<u64>().ok()) else {
+            continue;
+        };
+        frames.frames += 1;
+        frames.bytes += len;
+        let rate = fields.next().filter(|v| !v.is_empty()).unwrap_or("unknown");
+        *frames.data_rates.entry(rate.to_string()).or_default() += 1;
+    }
+    frames
+}
+
+/// The bytes of the data frames a station sent and received.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct StationBytes {
+    pub sent: u64,
+    pub received: u64,
+}
+
+/// The bytes of the data frames sent by and to each station during its window. The windows are in seconds since the unix epoch by the clock of the
+/// monitor that made the capture.
+///
+/// The size of a frame includes its 802.11 header. Retransmissions are not counted, as they carry
+/// the same data again.
+pub async fn station_data_bytes(
+    capture: &Path,
+    windows: &BTreeMap<MacAddr, (f64, f64)>,
+) -> anyhow::Result<BTreeMap<MacAddr, StationBytes>> {
+    let fields = tshark_fields(
+        capture,
+        Some("wlan.fc.type == 2 && wlan.fc.retry == 0"),
+        &[
+            "frame.time_epoch",
+            "wlan.ta",
+            "wlan.ra",
+            "frame.len",
+            "radiotap.length",
+        ],
+    )
+    .await?;
+    Ok(parse_station_data_bytes(&fields, windows))
+}
+
+/// Parse the output of `tshark -T fields -e frame.time_epoch -e wlan.ta -e wlan.ra -e frame.len
+/// -e radiotap.length` into the bytes of every station in its window. Every station is included,
+/// also if it sent and received nothing.
+pub fn parse_station_data_bytes(
+    fields: &str,
+    windows: &BTreeMap<MacAddr, (f64, f64)>,
+) -> BTreeMap<MacAddr, StationBytes> {
+    let mut bytes: BTreeMap<MacAddr, StationBytes> = windows
+        .keys()
+        .map(|s| (*s, StationBytes::default()))
+        .collect();
+    for line in fields.lines() {
+        let fields: Vec<&str> = line.split('\t').map(str::trim).collect();
+        let [time, ta, ra, len, radiotap, ..] = fields[..] else {
+            continue;
+        };
+        let (Ok(time), Ok(len)) = (time.parse::<f64>(), len.parse::<u64>()) else {
+            continue;
+        };
+        let len = len.saturating_sub(radiotap.parse().unwrap_or(0));
+        let in_window = |station: &str| {
+            let station = station.parse::<MacAddr>().ok()?;
+            let (start, end) = windows.get(&station)?;
+            (*start..=*end).contains(&time).then_some(station)
+        };
+        if let Some(ta) = in_window(ta) {
+            bytes.entry(ta).or_default().sent += len;
+        }
+        if let Some(ra) = in_window(ra) {
+            bytes.entry(ra).or_default().received += len;
+        }
+    }
+    bytes
+}
+
+/// The frames captured in one second.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize)]
+pub struct FrameCounts {
+    pub frames: u64,
+    /// The frames with the retry flag set.
+    pub retries: u64,
+}
+
+impl FrameCounts {
+    /// The fraction of the frames that were retries, `None` without frames.
+    pub fn retry_ratio(&self) -> Option<f64> {
+        (self.frames > 0).then(|| self.retries as f64 / self.frames as f64)
+    }
+}
+
+/// The frames in a capture per second, keyed by the second since the unix epoch by the clock of
+/// the controller. `offset` is how far the clock of the monitor that made the capture was ahead of
+/// the controller.
+pub async fn frame_timeline(
+    capture: &Path,
+    offset: f64,
+) -> anyhow::Result<BTreeMap<i64, FrameCounts>> {
+    let fields = tshark_fields(capture, None, &["frame.time_epoch", "wlan.fc.retry"]).await?;
+    Ok(parse_frame_timeline(&fields, offset))
+}
+
+/// Parse the output of `tshark -T fields -e frame.time_epoch -e wlan.fc.retry` into the frames
+/// per second, see [frame_timeline]. Seconds without frames are left out.
+pub fn parse_frame_timeline(fields: &str, offset: f64) -> BTreeMap<i64, FrameCounts> {
+    let mut timeline: BTreeMap<i64, FrameCounts> = BTreeMap::new();
+    for line in fields.lines() {
+        let mut fields = line.split('\t').map(str::trim);
+        let Some(Ok(time)) = fields.next().map(str::parse::<f64>) else {
+            continue;
+        };
+        let counts = timeline.entry((time - offset).floor() as i64).or_default();
+        counts.frames += 1;
+        if matches!(fields.next(), Some("1" | "True")) {
+            counts.retries += 1;
+        }
+    }
+    timeline
+}
+
+/// Print `fields` of every frame in a capture matching the display `filter` with tshark.
+async fn tshark_fields(
+    capture: &Path,
+    filter: Option<&str>,
+    fields: &[&str],
+) -> anyhow::Result<String> {
+    let mut command = Command::new("tshark");
+    command.arg("-r").arg(capture).args(["-T", "fields"]);
+    if let Some(filter) = filter {
+        command.args(["-Y", filter]);
+    }
+    for field in fields {
+        command.args(["-e", field]);
+    }
+    let output = command
+        .stdin(Stdio::null())
+        .output()
+        .await
+        .context("failed to run tshark, is it installed?")?;
+    if !output.status.success() {
+        anyhow::bail!(
+            "analyzing capture exited with error code {}: {}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+/// Parse the output of `tshark -T fields -e wlan.bssid -e wlan_radio.duration -e
+/// radiotap.flags.badfcs`, which has a line per frame with the tab-separated fields. Fields can be
+/// empty, for example for frames without a BSSID.
+pub fn parse_fields(fields: &str) -> AirtimeStats {
+    let mut stats = AirtimeStats::default();
+    let mut bssids = HashSet::new();
+    let mut airtime_us = 0u64;
+    for line in fields.lines() {
+        stats.frames += 1;
+        let mut fields = line.split('\t');
+        let bssid = fields.next().unwrap_or_default().trim();
+        if !bssid.is_empty() && bssid != "ff:ff:ff:ff:ff:ff" {
+            bssids.insert(bssid.to_string());
+        }
+        airtime_us += fields
+            .next()
+            .and_then(|v| v.trim().parse::<u64>().ok())
+            .unwrap_or_default();
+        // Older versions of tshark print flags as `1`, newer ones as `True`.
+        if matches!(fields.next().map(str::trim), Some("1" | "True")) {
+            stats.bad_fcs += 1;
+        }
+    }
+    stats.bss_count = bssids.len();
+    stats.airtime = airtime_us as f64 / 1_000_000.0;
+    stats
+}
@@ -0,0 +1,490 @@
+//! Replace the MAC addresses in the 802.11 headers of a capture with pseudonyms, so captures can
+//! be shared.
+//!
+//! Every address is replaced by a keyed hash of it, so an address gets the same pseudonym in all
+//! captures anonymized with the same key. Group addresses, like the broadcast address, are kept.
+//! Addresses in the frame bodies, like those in the payload of unencrypted data frames, are not
+//! replaced.
+
+use std::{
+    collections::BTreeMap,
+    io::{Read, Write},
+};
+
+use anyhow::Context;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+
+use crate::capture::pcapng::{
+    radiotap_header, write_block, BlockReader, ENHANCED_PACKET, IF_FCSLEN, INTERFACE_DESCRIPTION,
+    LINKTYPE_IEEE802_11, LINKTYPE_RADIOTAP, SECTION_HEADER, SIMPLE_PACKET,
+};
+
+/// How addresses are replaced.
+#[derive(Debug, Clone)]
+pub struct AnonymizePolicy {
+    /// The secret the pseudonyms are derived from. Anyone with the key can check whether a
+    /// pseudonym belongs to an address.
+    pub key: Vec<u8>,
+    /// Keep the first three bytes of the addresses, which identify the vendor of the device.
+    pub keep_oui: bool,
+}
+
+impl AnonymizePolicy {
+    /// The pseudonym of `address`. Group addresses are returned as they are. The bits marking an
+    /// address as a group or locally administered address are kept.
+    pub fn pseudonym(&self, address: [u8; 6]) -> [u8; 6] {
+        if address[0] & 0b01 != 0 {
+            return address;
+        }
+        let hash = hmac_sha256(&self.key, &address);
+        let mut pseudonym: [u8; 6] = hash[..6].try_into().unwrap();
+        pseudonym[0] = pseudonym[0] & !0b11 | address[0] & 0b11;
+        if self.keep_oui {
+            pseudonym[..3].copy_from_slice(&address[..3]);
+        }
+        pseudonym
+    }
+}
+
+/// What was anonymized.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct AnonymizeStats {
+    /// The number of packets in the capture.
+    pub packets: u64,
+    /// The number of packets that are not 802.11 frames or are too short, which are kept as they
+    /// are.
+    pub skipped: u64,
+    /// The pseudonym of every address that was replaced.
+    pub addresses: BTreeMap<String, String>,
+}
+
+/// Copy the capture in `reader` to `writer`, replacing the addresses in the 802.11 header of
+/// every frame with their pseudonym from `policy`.
+///
+/// The frame check sequence of frames that end in one is computed again if it was valid, so the
+/// frames do not show up as corrupt. Frames with an invalid checksum keep it.
+pub fn anonymize(
+    reader: impl Read,
+    mut writer: impl Write,
+    policy: &AnonymizePolicy,
+) -> anyhow::Result<AnonymizeStats> {
+    let mut reader = BlockReader::new(reader);
+    let mut stats = AnonymizeStats::default();
+    // The link type and the number of bytes of frame check sequence of every interface.
+    let mut interfaces: Vec<(u16, Option<u8>)> = Vec::new();
+    while let Some((kind, mut body)) = reader.next_block().context("invalid capture")? {
+        match kind {
+            SECTION_HEADER => interfaces.clear(),
+            INTERFACE_DESCRIPTION if body.len() >= 8 => {
+                let fcs_len = reader
+                    .options(&body[8..])
+                    .find(|(code, _)| *code == IF_FCSLEN)
+                    .and_then(|(_, value)| value.first().copied());
+                interfaces.push((reader.u16(&body[..2]), fcs_len));
+            }
+            ENHANCED_PACKET | SIMPLE_PACKET => {
+                stats.packets += 1;
+                // Enhanced packets name their interface, simple packets are on the first.
+                let (interface, start, captured, original) = if kind == ENHANCED_PACKET {
+                    let header = body.get(..20).context("packet block is too short")?;
+                    (
+                        reader.u32(&header[..4]) as usize,
+                        20,
+                        reader.u32(&header[12..16]) as usize,
+                        reader.u32(&header[16..20]) as usize,
+                    )
+                } else {
+                    let original = reader.u32(body.get(..4).context("packet block is too short")?);
+                    let captured = (original as usize).min(body.len() - 4);
+                    (0, 4, captured, original as usize)
+                };
+                let &(link_type, fcs_len) = interfaces
+                    .get(interface)
+                    .with_context(|| format!("packet is on undescribed interface {interface}"))?;
+                let data = body
+                    .get_mut(start..start + captured)
+                    .context("packet data is cut off")?;
+                // The checksum is only there if the whole frame was captured.
+                let complete = captured == original;
+                if !anonymize_packet(data, link_type, fcs_len, complete, policy, &mut stats) {
+                    stats.skipped += 1;
+                }
+            }
+            _ => {}
+        }
+        write_block(&mut writer, kind, &body, reader.big_endian())
+            .context("failed to write anonymized capture")?;
+    }
+    writer
+        .flush()
+        .context("failed to write anonymized capture")?;
+    Ok(stats)
+}
+
+/// Replace the addresses of the packet `data`. Returns false if it is not an 802.11 frame with
+/// a complete header.
+fn anonymize_packet(
+    data: &mut [u8],
+    link_type: u16,
+    fcs_len: Option<u8>,
+    complete: bool,
+    policy: &AnonymizePolicy,
+    stats: &mut AnonymizeStats,
+) -> bool {
+    let (frame, has_fcs) = match link_type {
+        LINKTYPE_RADIOTAP => match radiotap_header(data) {
+            Some((length, fcs)) => (&mut data[length..], fcs || fcs_len == Some(4)),
+            None => return false,
+        },
+        LINKTYPE_IEEE802_11 => (data, fcs_len == Some(4)),
+        _ => return false,
+    };
+    let has_fcs = has_fcs && complete && frame.len() >= 4;
+    let end = if has_fcs {
+        frame.len() - 4
+    } else {
+        frame.len()
+    };
+    let valid_fcs = has_fcs && crc32fast::hash(&frame[..end]).to_le_bytes() == frame[end..];
+
+    let Some(offsets) = address_offsets(&frame[..end]) else {
+        return false;
+    };
+    for offset in offsets {
+        let address: [u8; 6] = frame[offset..offset + 6].try_into().unwrap();
+        let pseudonym = policy.pseudonym(address);
+        if pseudonym != address {
+            stats
+                .addresses
+                .entry(format_address(&address))
+                .or_insert_with(|| format_address(&pseudonym));
+            frame[offset..offset + 6].copy_from_slice(&pseudonym);
+        }
+    }
+    if valid_fcs {
+        let fcs = crc32fast::hash(&frame[..end]);
+        frame[end..].copy_from_slice(&fcs.to_le_bytes());
+    }
+    true
+}
+
+/// The offsets of the addresses in the header of the 802.11 `frame`, `None` if the frame is too
+/// short to have the header its type implies.
+fn address_offsets(frame: &[u8]) -> Option<Vec<usize>> {
+    let control = frame.get(..2)?;
+    let kind = control[0] >> 2 & 0b11;
+    let subtype = control[0] >> 4;
+    let (to_ds, from_ds) = (control[1] & 0b01 != 0, control[1] & 0b10 != 0);
+    let offsets = match kind {
+        // Management and data frames, which have a fourth address between access points.
+        0 => vec![4, 10, 16],
+        2 if to_ds && from_ds => vec![4, 10, 16, 24],
+        2 => vec![4, 10, 16],
+        // Control frames, of which a control wrapper, CTS and ACK only have a receiver address.
+        1 if matches!(subtype, 7 | 12 | 13) => vec![4],
+        1 => vec![4, 10],
+        // Extension frames have no addresses in a fixed place.
+        _ => vec![],
+    };
+    let end = offsets.last().map_or(0, |offset| offset + 6);
+    (frame.len() >= end).then_some(offsets)
+}
+
+fn format_address(address: &[u8; 6]) -> String {
+    address
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect::<Vec<_>>()
+        .join(":")
+}
+
+/// The HMAC-SHA256 of `message` with `key`.
+fn hmac_sha256(key: &[u8], message: &[u8]) -> [u8; 32] {
+    const BLOCK_SIZE: usize = 64;
+    let mut block = [0u8; BLOCK_SIZE];
+    if key.len() > BLOCK_SIZE {
+        block[..32].copy_from_slice(&Sha256::digest(key));
+    } else {
+        block[..key.len()].copy_from_slice(key);
+    }
+    let pad = |byte: u8| block.map(|b| b ^ byte);
+    let inner = Sha256::new()
+        .chain_update(pad(0x36))
+        .chain_update(message)
+        .finalize();
+    Sha256::new()
+        .chain_update(pad(0x5c))
+        .chain_update(inner)
+        .finalize()
+        .into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::capture::pcapng::tests::{packets, CaptureBuilder};
+
+    const STATION: [u8; 6] = [0x00, 0x1b, 0x2c, 0x3d, 0x4e, 0x5f];
+    const ACCESS_POINT: [u8; 6] = [0x02, 0x11, 0x22, 0x33, 0x44, 0x55];
+    const BROADCAST: [u8; 6] = [0xff; 6];
+
+    fn policy(key: &str) -> AnonymizePolicy {
+        AnonymizePolicy {
+            key: key.as_bytes().to_vec(),
+            keep_oui: false,
+        }
+    }
+
+    fn hex(bytes: &[u8]) -> String {
+        bytes.iter().map(|b| format!("{b:02x}")).collect()
+    }
+
+    /// An 802.11 header with frame control `control` and `addresses`, with the sequence control
+    /// before a fourth address.
+    fn frame(control: [u8; 2], addresses: &[[u8; 6]]) -> Vec<u8> {
+        let mut frame = control.to_vec();
+        frame.extend([0, 0]);
+        for (i, address) in addresses.iter().enumerate() {
+            if i == 3 {
+                frame.extend([0x10, 0x00]);
+            }
+            frame.extend(address);
+        }
+        frame
+    }
+
+    /// A data frame from the station to the access point.
+    fn data_frame() -> Vec<u8> {
+        let mut frame = frame([0x08, 0x01], &[ACCESS_POINT, STATION, ACCESS_POINT]);
+        frame.extend([0x10, 0x00]);
+        frame.extend(b"payload");
+        frame
+    }
+
+    /// `frame` behind a radiotap header with only the flags, ending in a frame check sequence if
+    /// `fcs` is given.
+    fn radiotap(frame: &[u8], fcs: Option<u32>) -> Vec<u8> {
+        let flags = if fcs.is_some() { 0x10 } else { 0 };
+        let mut data = vec![0, 0, 9, 0, 0b10, 0, 0, 0, flags];
+        data.extend(frame);
+        if let Some(fcs) = fcs {
+            data.extend(fcs.to_le_bytes());
+        }
+        data
+    }
+
+    fn anonymized(capture: &[u8], policy: &AnonymizePolicy) -> (Vec<u8>, AnonymizeStats) {
+        let mut output = Vec::new();
+        let stats = anonymize(capture, &mut output, policy).unwrap();
+        (output, stats)
+    }
+
+    fn address(data: &[u8], offset: usize) -> [u8; 6] {
+        data[offset..offset + 6].try_into().unwrap()
+    }
+
+    #[test]
+    fn hmac_matches_rfc_4231() {
+        let cases: [(&[u8], &[u8], &str); 3] = [
+            (
+                &[0x0b; 20],
+                b"Hi There",
+                "b0344c61d8db38535ca8afceaf0bf12b881dc200c9833da726e9376c2e32cff7",
+            ),
+            (
+                b"Jefe",
+                b"what do ya want for nothing?",
+                "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843",
+            ),
+            // A key longer than a block is hashed first.
+            (
+                &[0xaa; 131],
+                b"Test Using Larger Than Block-Size Key - Hash Key First",
+                "60e431591ee0b67f0d8a26aacbf5b77f8e0bc6213728c5140546040f0ee37f54",
+            ),
+        ];
+        for (key, message, expected) in cases {
+            assert_eq!(hex(&hmac_sha256(key, message)), expected);
+        }
+    }
+
+    #[test]
+    fn group_addresses_are_kept() {
+        let policy = policy("secret");
+        assert_eq!(policy.pseudonym(BROADCAST), BROADCAST);
+        let multicast = [0x01, 0x00, 0x5e, 0x00, 0x00, 0xfb];
+        assert_eq!(policy.pseudonym(multicast), multicast);
+    }
+
+    #[test]
+    fn pseudonyms_keep_the_address_kind() {
+        let policy = policy("secret");
+        let global = policy.pseudonym(STATION);
+        assert_ne!(global, STATION);
+        assert_eq!(global[0] & 0b11, 0);
+        let local = policy.pseudonym(ACCESS_POINT);
+        assert_ne!(local, ACCESS_POINT);
+        assert_eq!(local[0] & 0b11, 0b10);
+    }
+
+    #[test]
+    fn pseudonyms_depend_on_the_key() {
+        assert_eq!(
+            policy("secret").pseudonym(STATION),
+            policy("secret").pseudonym(STATION)
+        );
+        assert_ne!(
+            policy("secret").pseudonym(STATION),
+            policy("other").pseudonym(STATION)
+        );
+    }
+
+    #[test]
+    fn pseudonyms_can_keep_the_oui() {
+        let policy = AnonymizePolicy {
+            keep_oui: true,
+            ..policy("secret")
+        };
+        let pseudonym = policy.pseudonym(STATION);
+        assert_eq!(pseudonym[..3], STATION[..3]);
+        assert_ne!(pseudonym[3..], STATION[3..]);
+    }
+
+    #[test]
+    fn addresses_of_every_frame_kind() {
+        // A beacon, a data frame to the access point and one between access points.
+        assert_eq!(
+            address_offsets(&frame([0x80, 0x00], &[BROADCAST; 3])),
+            Some(vec![4, 10, 16])
+        );
+        assert_eq!(address_offsets(&data_frame()), Some(vec![4, 10, 16]));
+        assert_eq!(
+            address_offsets(&frame([0x08, 0x03], &[STATION; 4])),
+            Some(vec![4, 10, 16, 24])
+        );
+        // An RTS, a CTS and an ACK.
+        assert_eq!(
+            address_offsets(&frame([0xb4, 0x00], &[STATION; 2])),
+            Some(vec![4, 10])
+        );
+        assert_eq!(
+            address_offsets(&frame([0xc4, 0x00], &[STATION])),
+            Some(vec![4])
+        );
+        assert_eq!(
+            address_offsets(&frame([0xd4, 0x00], &[STATION])),
+            Some(vec![4])
+        );
+        // An extension frame.
+        assert_eq!(address_offsets(&[0x0c, 0x00, 0x00]), Some(vec![]));
+    }
+
+    #[test]
+    fn headers_that_are_cut_off() {
+        assert_eq!(address_offsets(&[0x08]), None);
+        assert_eq!(address_offsets(&data_frame()[..21]), None);
+        // The fourth address is missing.
+        assert_eq!(address_offsets(&frame([0x08, 0x03], &[STATION; 3])), None);
+        assert_eq!(address_offsets(&frame([0xb4, 0x00], &[STATION])), None);
+    }
+
+    #[test]
+    fn valid_checksum_is_computed_again() {
+        let frame = data_frame();
+        let capture = CaptureBuilder::new(false)
+            .interface(LINKTYPE_RADIOTAP, 6, 0)
+            .packet(0, 1, &radiotap(&frame, Some(crc32fast::hash(&frame))))
+            .build();
+        let policy = policy("secret");
+        let (output, stats) = anonymized(&capture, &policy);
+        assert_eq!((stats.packets, stats.skipped), (1, 0));
+
+        let data = &packets(&output)[0].2;
+        let (header, frame) = data.split_at(9);
+        assert_eq!(header, &radiotap(&[], Some(0))[..9]);
+        let (frame, fcs) = frame.split_at(frame.len() - 4);
+        assert_eq!(address(frame, 4), policy.pseudonym(ACCESS_POINT));
+        assert_eq!(address(frame, 10), policy.pseudonym(STATION));
+        assert_eq!(address(frame, 16), policy.pseudonym(ACCESS_POINT));
+        assert_eq!(&frame[22..], &data_frame()[22..]);
+        assert_eq!(fcs, crc32fast::hash(frame).to_le_bytes());
+    }
+
+    #[test]
+    fn invalid_checksum_is_kept() {
+        let capture = CaptureBuilder::new(true)
+            .interface(LINKTYPE_RADIOTAP, 6, 0)
+            .packet(0, 1, &radiotap(&data_frame(), Some(0xdeadbeef)))
+            .build();
+        let policy = policy("secret");
+        let (output, _) = anonymized(&capture, &policy);
+        let data = &packets(&output)[0].2;
+        let (frame, fcs) = data[9..].split_at(data.len() - 13);
+        assert_eq!(address(frame, 10), policy.pseudonym(STATION));
+        assert_eq!(fcs, 0xdeadbeef_u32.to_le_bytes());
+    }
+
+    #[test]
+    fn frames_without_checksum() {
+        let ack = frame([0xd4, 0x00], &[STATION]);
+        let capture = CaptureBuilder::new(false)
+            .interface(LINKTYPE_IEEE802_11, 6, 0)
+            .packet(0, 1, &ack)
+            .packet(
+                0,
+                2,
+                &frame([0x80, 0x00], &[BROADCAST, ACCESS_POINT, ACCESS_POINT]),
+            )
+            .build();
+        let policy = policy("secret");
+        let (output, stats) = anonymized(&capture, &policy);
+        let packets = packets(&output);
+        assert_eq!(packets[0].2[..4], ack[..4]);
+        assert_eq!(address(&packets[0].2, 4), policy.pseudonym(STATION));
+        assert_eq!(packets[0].2.len(), ack.len());
+        assert_eq!(address(&packets[1].2, 4), BROADCAST);
+        assert_eq!(address(&packets[1].2, 10), policy.pseudonym(ACCESS_POINT));
+        assert_eq!(stats.addresses.len(), 2);
+        assert!(!stats.addresses.contains_key("ff:ff:ff:ff:ff:ff"));
+    }
+
+    #[test]
+    fn other_packets_are_kept() {
+        let ethernet = [0u8; 20];
+        let capture = CaptureBuilder::new(false)
+            .interface(1, 6, 0)
+            .packet(0, 1, &ethernet)
+            .interface(LINKTYPE_IEEE802_11, 6, 0)
+            .packet(1, 2, &data_frame()[..12])
+            .build();
+        let (output, stats) = anonymized(&capture, &policy("secret"));
+        assert_eq!((stats.packets, stats.skipped), (2, 2));
+        assert!(stats.addresses.is_empty());
+        assert_eq!(output, capture);
+    }
+
+    #[test]
+    fn pseudonyms_are_stable_across_captures() {
+        let first = CaptureBuilder::new(false)
+            .interface(LINKTYPE_IEEE802_11, 6, 0)
+            .packet(0, 1, &data_frame())
+            .build();
+        let second = CaptureBuilder::new(true)
+            .interface(LINKTYPE_RADIOTAP, 9, 0)
+            .packet(0, 5, &radiotap(&frame([0xd4, 0x00], &[STATION]), None))
+            .build();
+        let policy = policy("secret");
+        let (first, first_stats) = anonymized(&first, &policy);
+        let (second, second_stats) = anonymized(&second, &policy);
+        let station = format_address(&STATION);
+        assert_eq!(
+            first_stats.addresses[&station],
+            second_stats.addresses[&station]
+        );
+        assert_eq!(
+            address(&packets(&first)[0].2, 10),
+            address(&packets(&second)[0].2, 9 + 4)
+        );
+    }
+}
@@ -0,0 +1,211 @@
+//! Export the frames of a capture to CSV with a fixed set of columns, for analysis pipelines that
+//! do not read pcapng. The frames are dissected by tshark on the controller, like the analysis.
+
+use std::{
+    fs::File,
+    io::{BufRead, BufReader, BufWriter, Read, Write},
+    path::Path,
+    process::{Command, Stdio},
+};
+
+use anyhow::Context;
+use flate2::{write::GzEncoder, Compression};
+use serde::Serialize;
+use tracing::{info, warn};
+
+use crate::{capture::CaptureReader, hosts::HostId, utils::host_file};
+
+/// The header of the CSV.
+pub const CSV_HEADER: &str =
+    "timestamp,src,dst,bssid,type_subtype,retry,mcs,rssi,length,parse_error";
+
+/// The tshark fields of the columns, in the same order, followed by the field that marks
+/// malformed frames. The addresses are those of the transmitter and receiver, which every frame
+/// has, unlike the source and destination.
+const FIELDS: [&str; 10] = [
+    "frame.time_epoch",
+    "wlan.ta",
+    "wlan.ra",
+    "wlan.bssid",
+    "wlan.fc.type_subtype",
+    "wlan.fc.retry",
+    "wlan_radio.mcs.index",
+    "wlan_radio.signal_dbm",
+    "frame.len",
+    "_ws.malformed",
+];
+
+/// Which frames are exported.
+#[derive(Debug, Clone, Default)]
+pub struct CsvOptions {
+    /// Only export frames matching this display filter, in the syntax of `tshark -Y`.
+    pub filter: Option<String>,
+}
+
+/// What was exported.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct CsvStats {
+    /// The number of rows written.
+    pub frames: u64,
+    /// The number of rows of frames that could not be parsed completely.
+    pub parse_errors: u64,
+}
+
+/// Write a CSV row for every frame of the capture in `reader` to `writer`, starting with
+/// [CSV_HEADER]. The capture is streamed through tshark, which has to be installed on the
+/// controller.
+///
+/// Frames that can not be parsed get a row with the columns that could not be read left empty
+/// and `parse_error` set to 1.
+pub fn export_csv(
+    mut reader: impl Read + Send,
+    mut writer: impl Write,
+    options: &CsvOptions,
+) -> anyhow::Result<CsvStats> {
+    let mut command = Command::new("tshark");
+    command.args(["-r", "-", "-T", "fields", "-E", "occurrence=f"]);
+    if let Some(filter) = &options.filter {
+        command.args(["-Y", filter]);
+    }
+    for field in FIELDS {
+        command.args(["-e", field]);
+    }
+    let mut tshark = command
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .context("failed to run tshark, is it installed?")?;
+    // SAFETY: all handles are piped above, so they should be present.
+    let mut stdin = tshark.stdin.take().expect("missing stdin handle");
+    let stdout = tshark.stdout.take().expect("missing stdout handle");
+    let mut stderr = tshark.stderr.take().expect("missing stderr handle");
+
+    let mut stats = CsvStats::default();
+    let (copied, errors, written) = std::thread::scope(|scope| {
+        // The capture is fed to tshark while its output is read, so neither is held in memory.
+        let copied = scope.spawn(move || std::io::copy(&mut reader, &mut stdin));
+        let errors = scope.spawn(move || {
+            let mut errors = String::new();
+            _ = stderr.read_to_string(&mut errors);
+            errors
+        });
+        let written = (|| {
+            writeln!(writer, "{CSV_HEADER}")?;
+            for line in BufReader::new(stdout).lines() {
+                let (row, complete) = csv_row(&line?);
+                writeln!(writer, "{row}")?;
+                stats.frames += 1;
+                stats.parse_errors += u64::from(!complete);
+            }
+            writer.flush()
+        })();
+        (copied.join(), errors.join(), written)
+    });
+    let status = tshark.wait().context("failed to wait for tshark")?;
+    if !status.success() {
+        anyhow::bail!(
+            "tshark exited with error code {status}: {}",
+            errors.unwrap_or_default().trim()
+        );
+    }
+    copied
+        .expect("capture copy panicked")
+        .context("failed to pass capture to tshark")?;
+    written.context("failed to write CSV")?;
+    Ok(stats)
+}
+
+/// Convert a line of tshark output with [FIELDS] to a CSV row. Returns whether every column
+/// could be parsed.
+fn csv_row(line: &str) -> (String, bool) {
+    let fields: Vec<&str> = line.split('\t').map(str::trim).collect();
+    let mut complete = fields.len() == FIELDS.len();
+    let field = |i: usize| fields.get(i).copied().unwrap_or_default();
+    let mut column = |value: &str, valid: bool| -> String {
+        if value.is_empty() {
+            String::new()
+        } else if valid {
+            value.to_string()
+        } else {
+            complete = false;
+            String::new()
+        }
+    };
+
+    let address =
+        |v: &str| v.len() == 17 && v.split(':').all(|b| u8::from_str_radix(b, 16).is_ok());
+    let timestamp = column(field(0), field(0).parse::<f64>().is_ok());
+    let src = column(field(1), address(field(1)));
+    let dst = column(field(2), address(field(2)));
+    let bssid = column(field(3), address(field(3)));
+    // tshark prints the type and subtype in hex, like `0x0028`.
+    let type_subtype = field(4)
+        .strip_prefix("0x")
+        .and_then(|v| u16::from_str_radix(v, 16).ok())
+        .map(|v| v.to_string());
+    let type_subtype = column(
+        type_subtype.as_deref().unwrap_or(field(4)),
+        type_subtype.is_some(),
+    );
+    // Older versions of tshark print flags as `1`, newer ones as `True`.
+    let retry = match field(5) {
+        "1" | "True" => "1",
+        "0" | "False" => "0",
+        other => other,
+    };
+    let retry = column(retry, matches!(retry, "0" | "1"));
+    let mcs = column(field(6), field(6).parse::<u8>().is_ok());
+    let rssi = column(field(7), field(7).parse::<i16>().is_ok());
+    let length = column(field(8), field(8).parse::<u32>().is_ok());
+    if timestamp.is_empty() || !field(9).is_empty() {
+        complete = false;
+    }
+
+    let row = format!(
+        "{timestamp},{src},{dst},{bssid},{type_subtype},{retry},{mcs},{rssi},{length},{}",
+        u8::from(!complete)
+    );
+    (row, complete)
+}
+
+/// Export the capture at `capture` to a gzipped CSV at `output`, see [export_csv].
+pub async fn export_csv_file(
+    capture: &Path,
+    output: &Path,
+    options: CsvOptions,
+) -> anyhow::Result<CsvStats> {
+    let reader =
+        File::open(capture).with_context(|| format!("could not open {}", capture.display()))?;
+    let file =
+        File::create(output).with_context(|| format!("could not create {}", output.display()))?;
+    tokio::task::spawn_blocking(move || {
+        let mut encoder = GzEncoder::new(BufWriter::new(file), Compression::default());
+        let stats = export_csv(CaptureReader::File(reader), &mut encoder, &options)?;
+        encoder
+            .finish()
+            .and_then(|mut file| file.flush())
+            .context("failed to write CSV")?;
+        Ok(stats)
+    })
+    .await
+    .context("CSV export panicked")?
+}
+
+/// Export the captures of the monitor `hosts` in `out_path` to `<host>.frames.csv.gz` next to
+/// them. Captures that can not be exported are skipped with a warning.
+pub async fn export_captures(out_path: &Path, hosts: &[HostId]) {
+    for host in hosts {
+        let capture = host_file(out_path, host, "pcapng");
+        let output = host_file(out_path, host, "frames.csv.gz");
+        match export_csv_file(&capture, &output, CsvOptions::default()).await {
+            Ok(stats) => info!(
+                host,
+                "Exported {} frames to CSV, {} could not be parsed",
+                stats.frames,
+                stats.parse_errors
+            ),
+            Err(err) => warn!(host, "Could not export the capture to CSV: {err:#}"),
+        }
+    }
+}
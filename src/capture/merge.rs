@@ -0,0 +1,485 @@
+//! Merge the pcapng captures of several monitors into a single capture, ordered by time.
+//!
+//! Only the packets are kept: every source gets one interface in the merged capture, named after
+//! its host, and its enhanced packet blocks are copied with their timestamps corrected for the
+//! clock offset of the host. Other blocks, like statistics and name resolution, are dropped.
+
+use std::{
+    cmp::Reverse,
+    collections::{hash_map::DefaultHasher, BTreeMap, BinaryHeap, VecDeque},
+    hash::{Hash, Hasher},
+    io::Write,
+    time::Duration,
+};
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    capture::{
+        pcapng::{
+            packet_ticks, padded, radiotap_header, write_block, BlockReader, InterfaceClock,
+            BYTE_ORDER_MAGIC, ENHANCED_PACKET, IF_NAME, IF_TSRESOL, INTERFACE_DESCRIPTION,
+            LINKTYPE_RADIOTAP, OPT_END, SECTION_HEADER, SHB_USERAPPL,
+        },
+        CaptureReader,
+    },
+    hosts::HostId,
+};
+
+/// How the captures are merged.
+#[derive(Debug, Clone, Default)]
+pub struct MergeOptions {
+    /// How far the clock of each host is ahead of the controller in seconds, see
+    /// [crate::utils::clock_offset]. Hosts without an offset are not corrected.
+    pub clock_offsets: BTreeMap<HostId, f64>,
+    /// Drop a frame if another monitor captured the same frame at most this long before it.
+    pub dedup_window: Option<Duration>,
+}
+
+/// What was written to the merged capture.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct MergeStats {
+    /// The number of packets in the merged capture.
+    pub packets: u64,
+    /// The number of packets dropped because another monitor captured them too.
+    pub duplicates: u64,
+}
+
+/// Merge the captures in `inputs` into a single pcapng capture written to `output`.
+///
+/// Each capture is expected to be ordered by time, as tshark writes them. Every source has to
+/// capture with a single link type.
+pub fn merge(
+    inputs: Vec<(HostId, CaptureReader)>,
+    mut output: impl Write,
+    options: &MergeOptions,
+) -> anyhow::Result<MergeStats> {
+    let mut sources = inputs
+        .into_iter()
+        .map(|(host, reader)| {
+            let offset = options.clock_offsets.get(&host).copied().unwrap_or(0.0);
+            Source::new(host, reader, offset)
+        })
+        .collect::<Vec<_>>();
+
+    // The first packet of every source is read before writing the header, so the link type of
+    // each source is known.
+    let mut heap = BinaryHeap::new();
+    for (i, source) in sources.iter_mut().enumerate() {
+        if let Some(packet) = source.next_packet()? {
+            heap.push(Reverse((packet.timestamp, i, packet)));
+        }
+    }
+
+    write_section_header(&mut output)?;
+    for source in &sources {
+        let (link_type, snap_len) = source.link.unwrap_or((LINKTYPE_RADIOTAP, 0));
+        write_interface(&mut output, &source.host, link_type, snap_len)?;
+    }
+
+    let mut stats = MergeStats::default();
+    let mut recent: VecDeque<(u64, u64, usize)> = VecDeque::new();
+    while let Some(Reverse((timestamp, i, packet))) = heap.pop() {
+        let duplicate = options.dedup_window.is_some_and(|window| {
+            let window = window.as_nanos() as u64;
+            while recent
+                .front()
+                .is_some_and(|&(seen, _, _)| seen + window < timestamp)
+            {
+                recent.pop_front();
+            }
+            let hash = frame_hash(sources[i].link.map(|(t, _)| t), &packet.data);
+            let duplicate = recent
+                .iter()
+                .any(|&(_, other, source)| other == hash && source != i);
+            if !duplicate {
+                recent.push_back((timestamp, hash, i));
+            }
+            duplicate
+        });
+        if duplicate {
+            stats.duplicates += 1;
+        } else {
+            write_packet(&mut output, i as u32, &packet)?;
+            stats.packets += 1;
+        }
+
+        if let Some(next) = sources[i].next_packet()? {
+            heap.push(Reverse((next.timestamp, i, next)));
+        }
+    }
+    output.flush().context("failed to write merged capture")?;
+    Ok(stats)
+}
+
+/// A packet of a source, with its timestamp in nanoseconds since the UNIX epoch by the clock of
+/// the controller.
+#[derive(PartialEq, Eq, PartialOrd, Ord)]
+struct Packet {
+    timestamp: u64,
+    original_length: u32,
+    data: Vec<u8>,
+}
+
+/// The capture of a single monitor, read one block at a time.
+struct Source {
+    host: HostId,
+    reader: BlockReader<CaptureReader>,
+    /// The clock offset of the host in nanoseconds.
+    offset: i128,
+    /// The interfaces of the current section.
+    interfaces: Vec<InterfaceClock>,
+    /// The link type and snapshot length of the first interface.
+    link: Option<(u16, u32)>,
+}
+
+impl Source {
+    fn new(host: HostId, reader: CaptureReader, offset: f64) -> Self {
+        Self {
+            host,
+            reader: BlockReader::new(reader),
+            offset: (offset * 1e9) as i128,
+            interfaces: Vec::new(),
+            link: None,
+        }
+    }
+
+    /// Read blocks until the next packet, `None` at the end of the capture.
+    fn next_packet(&mut self) -> anyhow::Result<Option<Packet>> {
+        loop {
+            let Some((kind, body)) = self
+                .reader
+                .next_block()
+                .with_context(|| format!("invalid capture of `{}`", self.host))?
+            else {
+                return Ok(None);
+            };
+            match kind {
+                SECTION_HEADER => self.interfaces.clear(),
+                INTERFACE_DESCRIPTION => self.add_interface(&body)?,
+                ENHANCED_PACKET => return self.packet(&body).map(Some),
+                _ => {}
+            }
+        }
+    }
+
+    fn add_interface(&mut self, body: &[u8]) -> anyhow::Result<()> {
+        if body.len() < 8 {
+            anyhow::bail!("interface description of `{}` is too short", self.host);
+        }
+        let link_type = self.reader.u16(&body[..2]);
+        let snap_len = self.reader.u32(&body[4..8]);
+        match self.link {
+            None => self.link = Some((link_type, snap_len)),
+            Some((first, _)) if first != link_type => anyhow::bail!(
+                "the capture of `{}` has interfaces with link types {first} and {link_type}",
+                self.host
+            ),
+            Some(_) => {}
+        }
+
+        self.interfaces
+            .push(InterfaceClock::new(&self.reader, body));
+        Ok(())
+    }
+
+    fn packet(&self, body: &[u8]) -> anyhow::Result<Packet> {
+        if body.len() < 20 {
+            anyhow::bail!("packet block of `{}` is too short", self.host);
+        }
+        let id = self.reader.u32(&body[..4]) as usize;
+        let interface = self.interfaces.get(id).with_context(|| {
+            format!("packet of `{}` is on undescribed interface {id}", self.host)
+        })?;
+        let ticks = packet_ticks(&self.reader, body);
+        let captured = self.reader.u32(&body[12..16]) as usize;
+        let original_length = self.reader.u32(&body[16..20]);
+        let data = body
+            .get(20..20 + captured)
+            .with_context(|| format!("packet data of `{}` is cut off", self.host))?;
+
+        let nanos = interface.nanos(ticks) - self.offset;
+        Ok(Packet {
+            timestamp: nanos.clamp(0, i128::from(u64::MAX)) as u64,
+            original_length,
+            data: data.to_vec(),
+        })
+    }
+}
+
+/// A hash of the 802.11 frame in `data`, without the radiotap header, as that differs between
+/// the monitors that captured the frame. The frame check sequence is part of the hash if the
+/// monitors captured it.
+fn frame_hash(link_type: Option<u16>, data: &[u8]) -> u64 {
+    let frame = match link_type {
+        Some(LINKTYPE_RADIOTAP) => match radiotap_header(data) {
+            Some((length, _)) => &data[length..],
+            None => data,
+        },
+        _ => data,
+    };
+    let mut hasher = DefaultHasher::new();
+    frame.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Write a little endian block with `body`, which has to be padded to 32 bits already.
+fn write_le_block(output: &mut impl Write, kind: u32, body: &[u8]) -> anyhow::Result<()> {
+    write_block(output, kind, body, false).context("failed to write merged capture")
+}
+
+fn push_option(body: &mut Vec<u8>, code: u16, value: &[u8]) {
+    body.extend_from_slice(&code.to_le_bytes());
+    body.extend_from_slice(&(value.len() as u16).to_le_bytes());
+    body.extend_from_slice(value);
+    body.resize(body.len() + padded(value.len()) - value.len(), 0);
+}
+
+fn write_section_header(output: &mut impl Write) -> anyhow::Result<()> {
+    let mut body = Vec::new();
+    body.extend_from_slice(&BYTE_ORDER_MAGIC.to_le_bytes());
+    // Version 1.0, with an unknown section length.
+    body.extend_from_slice(&1u16.to_le_bytes());
+    body.extend_from_slice(&0u16.to_le_bytes());
+    body.extend_from_slice(&(-1i64).to_le_bytes());
+    push_option(&mut body, SHB_USERAPPL, b"wifi-experiment-controller");
+    push_option(&mut body, OPT_END, &[]);
+    write_le_block(output, SECTION_HEADER, &body)
+}
+
+/// Describe the interface of a source, with timestamps in nanoseconds.
+fn write_interface(
+    output: &mut impl Write,
+    host: &str,
+    link_type: u16,
+    snap_len: u32,
+) -> anyhow::Result<()> {
+    let mut body = Vec::new();
+    body.extend_from_slice(&link_type.to_le_bytes());
+    body.extend_from_slice(&0u16.to_le_bytes());
+    body.extend_from_slice(&snap_len.to_le_bytes());
+    push_option(&mut body, IF_NAME, host.as_bytes());
+    push_option(&mut body, IF_TSRESOL, &[9]);
+    push_option(&mut body, OPT_END, &[]);
+    write_le_block(output, INTERFACE_DESCRIPTION, &body)
+}
+
+fn write_packet(output: &mut impl Write, interface: u32, packet: &Packet) -> anyhow::Result<()> {
+    let mut body = Vec::with_capacity(20 + padded(packet.data.len()));
+    body.extend_from_slice(&interface.to_le_bytes());
+    body.extend_from_slice(&((packet.timestamp >> 32) as u32).to_le_bytes());
+    body.extend_from_slice(&(packet.timestamp as u32).to_le_bytes());
+    body.extend_from_slice(&(packet.data.len() as u32).to_le_bytes());
+    body.extend_from_slice(&packet.original_length.to_le_bytes());
+    body.extend_from_slice(&packet.data);
+    body.resize(20 + padded(packet.data.len()), 0);
+    write_le_block(output, ENHANCED_PACKET, &body)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+    use crate::capture::pcapng::{
+        tests::{blocks, packets, CaptureBuilder},
+        INTERFACE_STATISTICS,
+    };
+
+    /// A radiotap header without fields.
+    const RADIOTAP: [u8; 8] = [0, 0, 8, 0, 0, 0, 0, 0];
+    /// A radiotap header with the rate, as another monitor would capture the frame.
+    const RADIOTAP_RATE: [u8; 9] = [0, 0, 9, 0, 0b100, 0, 0, 0, 12];
+
+    fn frame(radiotap: &[u8], payload: &[u8]) -> Vec<u8> {
+        [radiotap, payload].concat()
+    }
+
+    fn input(host: &str, capture: CaptureBuilder) -> (HostId, CaptureReader) {
+        let reader = CaptureReader::Buffer(Cursor::new(capture.build()));
+        (host.to_string(), reader)
+    }
+
+    fn run(inputs: Vec<(HostId, CaptureReader)>, options: &MergeOptions) -> (MergeStats, Vec<u8>) {
+        let mut output = Vec::new();
+        let stats = merge(inputs, &mut output, options).unwrap();
+        (stats, output)
+    }
+
+    #[test]
+    fn packets_are_ordered_by_time() {
+        let a = CaptureBuilder::new(false)
+            .interface(LINKTYPE_RADIOTAP, 9, 0)
+            .packet(0, 1_000, &frame(&RADIOTAP, b"a1"))
+            .packet(0, 3_000, &frame(&RADIOTAP, b"a3"));
+        let b = CaptureBuilder::new(false)
+            .interface(LINKTYPE_RADIOTAP, 9, 0)
+            .packet(0, 2_000, &frame(&RADIOTAP, b"b2"))
+            .packet(0, 4_000, &frame(&RADIOTAP, b"b4"));
+        let (stats, output) = run(vec![input("a", a), input("b", b)], &Default::default());
+
+        assert_eq!(stats.packets, 4);
+        assert_eq!(stats.duplicates, 0);
+        let merged = packets(&output)
+            .into_iter()
+            .map(|(interface, time, data)| (interface, time, data[8..].to_vec()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            merged,
+            [
+                (0, 1_000, b"a1".to_vec()),
+                (1, 2_000, b"b2".to_vec()),
+                (0, 3_000, b"a3".to_vec()),
+                (1, 4_000, b"b4".to_vec()),
+            ]
+        );
+    }
+
+    #[test]
+    fn every_source_gets_an_interface_named_after_its_host() {
+        let a = CaptureBuilder::new(false)
+            .interface(LINKTYPE_RADIOTAP, 9, 0)
+            .packet(0, 1_000, &RADIOTAP);
+        // A source without packets still gets its interface.
+        let b = CaptureBuilder::new(false).interface(LINKTYPE_RADIOTAP, 9, 0);
+        let (_, output) = run(
+            vec![input("mon-a", a), input("mon-b", b)],
+            &Default::default(),
+        );
+
+        let blocks = blocks(&output);
+        let kinds = blocks.iter().map(|(kind, _)| *kind).collect::<Vec<_>>();
+        assert_eq!(
+            kinds,
+            [
+                SECTION_HEADER,
+                INTERFACE_DESCRIPTION,
+                INTERFACE_DESCRIPTION,
+                ENHANCED_PACKET
+            ]
+        );
+        let reader = BlockReader::new(&[][..]);
+        for ((_, body), host) in blocks[1..3].iter().zip(["mon-a", "mon-b"]) {
+            assert_eq!(u16::from_le_bytes([body[0], body[1]]), LINKTYPE_RADIOTAP);
+            let options = reader.options(&body[8..]).collect::<Vec<_>>();
+            assert_eq!(
+                options,
+                [(IF_NAME, host.as_bytes()), (IF_TSRESOL, &[9][..])]
+            );
+        }
+    }
+
+    #[test]
+    fn blocks_are_rewritten_to_nanoseconds_and_little_endian() {
+        // Microseconds, big endian, with an offset of 100 seconds on the interface.
+        let a = CaptureBuilder::new(true)
+            .interface(LINKTYPE_RADIOTAP, 6, 100)
+            .block(INTERFACE_STATISTICS, &[0; 12])
+            .packet(0, 1_500_000, &frame(&RADIOTAP, b"big"));
+        let (stats, output) = run(vec![input("a", a)], &Default::default());
+
+        assert_eq!(stats.packets, 1);
+        // The statistics are dropped.
+        assert_eq!(blocks(&output).len(), 3);
+        assert_eq!(output[8..12], BYTE_ORDER_MAGIC.to_le_bytes());
+        assert_eq!(
+            packets(&output),
+            [(0, 101_500_000_000, frame(&RADIOTAP, b"big"))]
+        );
+    }
+
+    #[test]
+    fn timestamps_are_corrected_for_the_clock_offset() {
+        let a = CaptureBuilder::new(false)
+            .interface(LINKTYPE_RADIOTAP, 9, 0)
+            .packet(0, 10_000_000_000, &frame(&RADIOTAP, b"a"));
+        // The clock of `b` is 1.5 seconds ahead, so its packet was captured first.
+        let b = CaptureBuilder::new(false)
+            .interface(LINKTYPE_RADIOTAP, 9, 0)
+            .packet(0, 11_000_000_000, &frame(&RADIOTAP, b"b"));
+        let options = MergeOptions {
+            clock_offsets: [("b".to_string(), 1.5)].into(),
+            dedup_window: None,
+        };
+        let (_, output) = run(vec![input("a", a), input("b", b)], &options);
+
+        let times = packets(&output)
+            .into_iter()
+            .map(|(interface, time, _)| (interface, time))
+            .collect::<Vec<_>>();
+        assert_eq!(times, [(1, 9_500_000_000), (0, 10_000_000_000)]);
+    }
+
+    #[test]
+    fn frames_captured_by_another_monitor_in_the_window_are_dropped() {
+        let a = CaptureBuilder::new(false)
+            .interface(LINKTYPE_RADIOTAP, 9, 0)
+            .packet(0, 1_000_000, &frame(&RADIOTAP, b"beacon"))
+            .packet(0, 5_000_000, &frame(&RADIOTAP, b"data"));
+        // The same frames with another radiotap header, the first within the window and the
+        // second after it.
+        let b = CaptureBuilder::new(false)
+            .interface(LINKTYPE_RADIOTAP, 9, 0)
+            .packet(0, 1_800_000, &frame(&RADIOTAP_RATE, b"beacon"))
+            .packet(0, 6_000_001, &frame(&RADIOTAP_RATE, b"data"));
+        let options = MergeOptions {
+            clock_offsets: BTreeMap::new(),
+            dedup_window: Some(Duration::from_millis(1)),
+        };
+        let (stats, output) = run(vec![input("a", a), input("b", b)], &options);
+
+        assert_eq!(stats.packets, 3);
+        assert_eq!(stats.duplicates, 1);
+        let merged = packets(&output)
+            .into_iter()
+            .map(|(interface, time, _)| (interface, time))
+            .collect::<Vec<_>>();
+        assert_eq!(merged, [(0, 1_000_000), (0, 5_000_000), (1, 6_000_001)]);
+    }
+
+    #[test]
+    fn frames_at_the_end_of_the_window_are_dropped() {
+        let a = CaptureBuilder::new(false)
+            .interface(LINKTYPE_RADIOTAP, 9, 0)
+            .packet(0, 1_000_000, &frame(&RADIOTAP, b"beacon"));
+        let b = CaptureBuilder::new(false)
+            .interface(LINKTYPE_RADIOTAP, 9, 0)
+            .packet(0, 2_000_000, &frame(&RADIOTAP, b"beacon"));
+        let options = MergeOptions {
+            clock_offsets: BTreeMap::new(),
+            dedup_window: Some(Duration::from_millis(1)),
+        };
+        let (stats, _) = run(vec![input("a", a), input("b", b)], &options);
+        assert_eq!(stats.duplicates, 1);
+    }
+
+    #[test]
+    fn repeated_frames_of_one_monitor_are_kept() {
+        // Retransmissions captured by the same monitor are separate frames.
+        let a = CaptureBuilder::new(false)
+            .interface(LINKTYPE_RADIOTAP, 9, 0)
+            .packet(0, 1_000, &frame(&RADIOTAP, b"retry"))
+            .packet(0, 2_000, &frame(&RADIOTAP, b"retry"));
+        let options = MergeOptions {
+            clock_offsets: BTreeMap::new(),
+            dedup_window: Some(Duration::from_millis(1)),
+        };
+        let (stats, _) = run(vec![input("a", a)], &options);
+        assert_eq!(stats.packets, 2);
+        assert_eq!(stats.duplicates, 0);
+    }
+
+    #[test]
+    fn sources_with_several_link_types_are_rejected() {
+        let a = CaptureBuilder::new(false)
+            .interface(LINKTYPE_RADIOTAP, 9, 0)
+            .interface(105, 9, 0)
+            .packet(1, 1_000, b"frame");
+        let err = merge(vec![input("a", a)], Vec::new(), &Default::default()).unwrap_err();
+        assert!(
+            format!("{err:#}").contains("link types 127 and 105"),
+            "{err:#}"
+        );
+    }
+}
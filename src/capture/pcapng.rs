@@ -0,0 +1,402 @@
+//! Reading and writing the blocks of pcapng captures, as far as the controller needs to.
+
+use std::io::{BufReader, ErrorKind, Read, Write};
+
+use anyhow::Context;
+
+pub const SECTION_HEADER: u32 = 0x0A0D_0D0A;
+pub const INTERFACE_DESCRIPTION: u32 = 0x0000_0001;
+pub const SIMPLE_PACKET: u32 = 0x0000_0003;
+pub const INTERFACE_STATISTICS: u32 = 0x0000_0005;
+pub const ENHANCED_PACKET: u32 = 0x0000_0006;
+pub const BYTE_ORDER_MAGIC: u32 = 0x1A2B_3C4D;
+
+pub const OPT_END: u16 = 0;
+pub const SHB_USERAPPL: u16 = 4;
+pub const IF_NAME: u16 = 2;
+pub const IF_TSRESOL: u16 = 9;
+pub const IF_TSOFFSET: u16 = 14;
+pub const IF_FCSLEN: u16 = 13;
+
+/// The link type of 802.11 frames without a radiotap header.
+pub const LINKTYPE_IEEE802_11: u16 = 105;
+/// The link type of 802.11 frames with a radiotap header, which the monitors capture.
+pub const LINKTYPE_RADIOTAP: u16 = 127;
+
+/// Blocks larger than this are taken as a corrupt capture rather than allocated.
+const MAX_BLOCK_LENGTH: usize = 64 * 1024 * 1024;
+
+/// Reads the blocks of a capture one at a time, in the byte order of their section.
+pub struct BlockReader<R> {
+    reader: BufReader<R>,
+    big_endian: bool,
+    started: bool,
+}
+
+impl<R: Read> BlockReader<R> {
+    pub fn new(reader: R) -> Self {
+        Self {
+            reader: BufReader::new(reader),
+            big_endian: false,
+            started: false,
+        }
+    }
+
+    /// Whether the current section is big endian.
+    pub fn big_endian(&self) -> bool {
+        self.big_endian
+    }
+
+    /// Read the next block, returning its type and its body without the trailing length. `None`
+    /// at the end of the capture.
+    pub fn next_block(&mut self) -> anyhow::Result<Option<(u32, Vec<u8>)>> {
+        let mut header = [0; 8];
+        match self.reader.read_exact(&mut header[..4]) {
+            Err(err) if err.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+            result => result.context("failed to read capture")?,
+        }
+        self.reader
+            .read_exact(&mut header[4..])
+            .context("capture ends in a block header")?;
+
+        // The section header has the same type in both byte orders and sets the byte order of
+        // the blocks that follow, including its own length.
+        let kind = u32::from_le_bytes(header[..4].try_into().unwrap());
+        let mut magic = [0; 4];
+        if kind == SECTION_HEADER {
+            self.reader
+                .read_exact(&mut magic)
+                .context("capture ends in a section header")?;
+            self.big_endian = match (u32::from_le_bytes(magic), u32::from_be_bytes(magic)) {
+                (BYTE_ORDER_MAGIC, _) => false,
+                (_, BYTE_ORDER_MAGIC) => true,
+                _ => anyhow::bail!("section header has an invalid byte order magic"),
+            };
+            self.started = true;
+        } else if !self.started {
+            anyhow::bail!("not a pcapng capture");
+        }
+
+        let kind = self.u32(&header[..4]);
+        let length = self.u32(&header[4..]) as usize;
+        if length < 12 || !length.is_multiple_of(4) || length > MAX_BLOCK_LENGTH {
+            anyhow::bail!("block has an invalid length of {length} bytes");
+        }
+        let mut body = vec![0; length - 8];
+        let read = if kind == SECTION_HEADER {
+            body[..4].copy_from_slice(&magic);
+            &mut body[4..]
+        } else {
+            &mut body[..]
+        };
+        self.reader
+            .read_exact(read)
+            .context("capture ends in the middle of a block")?;
+        body.truncate(length - 12);
+        Ok(Some((kind, body)))
+    }
+
+    /// The options in `data`, as pairs of their code and value.
+    pub fn options<'a>(&self, mut data: &'a [u8]) -> impl Iterator<Item = (u16, &'a [u8])> + 'a {
+        let big_endian = self.big_endian;
+        std::iter::from_fn(move || {
+            if data.len() < 4 {
+                return None;
+            }
+            let code = read_u16(big_endian, &data[..2]);
+            let length = read_u16(big_endian, &data[2..4]) as usize;
+            let value = data.get(4..4 + length)?;
+            data = data.get(4 + padded(length)..).unwrap_or_default();
+            (code != OPT_END).then_some((code, value))
+        })
+    }
+
+    pub fn u16(&self, bytes: &[u8]) -> u16 {
+        read_u16(self.big_endian, bytes)
+    }
+
+    pub fn u32(&self, bytes: &[u8]) -> u32 {
+        let bytes = bytes[..4].try_into().unwrap();
+        if self.big_endian {
+            u32::from_be_bytes(bytes)
+        } else {
+            u32::from_le_bytes(bytes)
+        }
+    }
+}
+
+/// How the timestamps of an interface are converted to time, from the options of its
+/// description.
+#[derive(Debug, Clone, Copy)]
+pub struct InterfaceClock {
+    /// Converts a timestamp of the interface to nanoseconds.
+    resolution: Resolution,
+    /// Seconds added to every timestamp of the interface.
+    offset: i64,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Resolution {
+    /// Ticks of 10^-n seconds.
+    Decimal(u32),
+    /// Ticks of 2^-n seconds.
+    Binary(u32),
+}
+
+impl InterfaceClock {
+    /// Read the clock from the options of an interface description `body`, which is in the byte
+    /// order of `reader`. Timestamps are in microseconds if the interface does not say otherwise.
+    pub fn new<R: Read>(reader: &BlockReader<R>, body: &[u8]) -> Self {
+        let mut clock = Self {
+            resolution: Resolution::Decimal(6),
+            offset: 0,
+        };
+        for (code, value) in reader.options(body.get(8..).unwrap_or_default()) {
+            match (code, value) {
+                (IF_TSRESOL, &[v, ..]) if v & 0x80 == 0 => {
+                    clock.resolution = Resolution::Decimal(u32::from(v))
+                }
+                (IF_TSRESOL, &[v, ..]) => {
+                    clock.resolution = Resolution::Binary(u32::from(v & 0x7F))
+                }
+                (IF_TSOFFSET, value) if value.len() >= 8 => {
+                    let bytes = value[..8].try_into().unwrap();
+                    clock.offset = if reader.big_endian() {
+                        i64::from_be_bytes(bytes)
+                    } else {
+                        i64::from_le_bytes(bytes)
+                    };
+                }
+                _ => {}
+            }
+        }
+        clock
+    }
+
+    /// The time of the timestamp `ticks` in nanoseconds since the unix epoch.
+    pub fn nanos(&self, ticks: u64) -> i128 {
+        let ticks = i128::from(ticks);
+        let nanos = match self.resolution {
+            Resolution::Decimal(n) if n <= 9 => ticks * 10i128.pow(9 - n),
+            Resolution::Decimal(n) => ticks / 10i128.pow((n - 9).min(38)),
+            Resolution::Binary(n) => (ticks * 1_000_000_000) >> n.min(127),
+        };
+        nanos + i128::from(self.offset) * 1_000_000_000
+    }
+}
+
+/// The timestamp of an enhanced packet block `body`, in ticks of its interface.
+pub fn packet_ticks<R: Read>(reader: &BlockReader<R>, body: &[u8]) -> u64 {
+    u64::from(reader.u32(&body[4..8])) << 32 | u64::from(reader.u32(&body[8..12]))
+}
+
+fn read_u16(big_endian: bool, bytes: &[u8]) -> u16 {
+    let bytes = bytes[..2].try_into().unwrap();
+    if big_endian {
+        u16::from_be_bytes(bytes)
+    } else {
+        u16::from_le_bytes(bytes)
+    }
+}
+
+/// The length of an option value or packet including its padding to 32 bits.
+pub fn padded(length: usize) -> usize {
+    length.next_multiple_of(4)
+}
+
+/// Write a block with `body`, which has to be padded to 32 bits already and be in the same byte
+/// order.
+pub fn write_block(
+    output: &mut impl Write,
+    kind: u32,
+    body: &[u8],
+    big_endian: bool,
+) -> std::io::Result<()> {
+    let length = (body.len() + 12) as u32;
+    let (kind, length) = if big_endian {
+        (kind.to_be_bytes(), length.to_be_bytes())
+    } else {
+        (kind.to_le_bytes(), length.to_le_bytes())
+    };
+    output.write_all(&kind)?;
+    output.write_all(&length)?;
+    output.write_all(body)?;
+    output.write_all(&length)
+}
+
+/// The length of the radiotap header at the start of `data`, and whether the frame after it ends
+/// in a frame check sequence according to its flags. `None` if the header is cut off.
+pub fn radiotap_header(data: &[u8]) -> Option<(usize, bool)> {
+    let length = u16::from_le_bytes([*data.get(2)?, *data.get(3)?]) as usize;
+    if length < 8 || length > data.len() {
+        return None;
+    }
+    let present = u32::from_le_bytes(data[4..8].try_into().unwrap());
+    // Extended presence bitmaps follow the first one while their last bit is set.
+    let mut fields = 8;
+    let mut last = present;
+    while last & 1 << 31 != 0 {
+        last = u32::from_le_bytes(data.get(fields..fields + 4)?.try_into().unwrap());
+        fields += 4;
+    }
+    // The flags are the second field, after the 8 byte TSFT that is aligned to 8 bytes.
+    if present & 0b10 == 0 {
+        return Some((length, false));
+    }
+    if present & 0b1 != 0 {
+        fields = fields.next_multiple_of(8) + 8;
+    }
+    let flags = *data.get(fields).filter(|_| fields < length)?;
+    Some((length, flags & 0x10 != 0))
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    /// Builds a capture block by block, in either byte order.
+    pub(crate) struct CaptureBuilder {
+        big_endian: bool,
+        data: Vec<u8>,
+    }
+
+    impl CaptureBuilder {
+        /// Start a capture with a section header of unknown length.
+        pub(crate) fn new(big_endian: bool) -> Self {
+            let builder = Self {
+                big_endian,
+                data: Vec::new(),
+            };
+            let mut body = builder.u32(BYTE_ORDER_MAGIC);
+            body.extend(builder.u16(1));
+            body.extend(builder.u16(0));
+            body.extend_from_slice(&[0xFF; 8]);
+            builder.block(SECTION_HEADER, &body)
+        }
+
+        fn u16(&self, value: u16) -> Vec<u8> {
+            if self.big_endian {
+                value.to_be_bytes().to_vec()
+            } else {
+                value.to_le_bytes().to_vec()
+            }
+        }
+
+        fn u32(&self, value: u32) -> Vec<u8> {
+            if self.big_endian {
+                value.to_be_bytes().to_vec()
+            } else {
+                value.to_le_bytes().to_vec()
+            }
+        }
+
+        /// Add a block with `body`, padded to 32 bits.
+        pub(crate) fn block(mut self, kind: u32, body: &[u8]) -> Self {
+            let mut body = body.to_vec();
+            body.resize(padded(body.len()), 0);
+            write_block(&mut self.data, kind, &body, self.big_endian).unwrap();
+            self
+        }
+
+        /// Describe an interface, with ticks of 10^-`resolution` seconds and `offset` seconds
+        /// added to its timestamps.
+        pub(crate) fn interface(self, link_type: u16, resolution: u8, offset: i64) -> Self {
+            let mut body = self.u16(link_type);
+            body.extend(self.u16(0));
+            body.extend(self.u32(0));
+            body.extend(self.u16(IF_TSRESOL));
+            body.extend(self.u16(1));
+            body.extend_from_slice(&[resolution, 0, 0, 0]);
+            if offset != 0 {
+                body.extend(self.u16(IF_TSOFFSET));
+                body.extend(self.u16(8));
+                body.extend(if self.big_endian {
+                    offset.to_be_bytes()
+                } else {
+                    offset.to_le_bytes()
+                });
+            }
+            body.extend(self.u32(0));
+            self.block(INTERFACE_DESCRIPTION, &body)
+        }
+
+        /// Add an enhanced packet block with `data` at `ticks` of `interface`.
+        pub(crate) fn packet(self, interface: u32, ticks: u64, data: &[u8]) -> Self {
+            let mut body = self.u32(interface);
+            body.extend(self.u32((ticks >> 32) as u32));
+            body.extend(self.u32(ticks as u32));
+            body.extend(self.u32(data.len() as u32));
+            body.extend(self.u32(data.len() as u32));
+            body.extend_from_slice(data);
+            self.block(ENHANCED_PACKET, &body)
+        }
+
+        pub(crate) fn build(self) -> Vec<u8> {
+            self.data
+        }
+    }
+
+    /// The blocks of `capture`, as their type and body.
+    pub(crate) fn blocks(capture: &[u8]) -> Vec<(u32, Vec<u8>)> {
+        let mut reader = BlockReader::new(capture);
+        std::iter::from_fn(|| reader.next_block().unwrap()).collect()
+    }
+
+    /// The packets of `capture`, as their interface, time in nanoseconds and data.
+    pub(crate) fn packets(capture: &[u8]) -> Vec<(u32, i128, Vec<u8>)> {
+        let mut reader = BlockReader::new(capture);
+        let mut interfaces = Vec::new();
+        let mut packets = Vec::new();
+        while let Some((kind, body)) = reader.next_block().unwrap() {
+            match kind {
+                SECTION_HEADER => interfaces.clear(),
+                INTERFACE_DESCRIPTION => interfaces.push(InterfaceClock::new(&reader, &body)),
+                ENHANCED_PACKET => {
+                    let interface = reader.u32(&body[..4]);
+                    let time = interfaces[interface as usize].nanos(packet_ticks(&reader, &body));
+                    let length = reader.u32(&body[12..16]) as usize;
+                    packets.push((interface, time, body[20..20 + length].to_vec()));
+                }
+                _ => {}
+            }
+        }
+        packets
+    }
+
+    #[test]
+    fn blocks_in_both_byte_orders() {
+        for big_endian in [false, true] {
+            let capture = CaptureBuilder::new(big_endian)
+                .interface(LINKTYPE_RADIOTAP, 6, 10)
+                .packet(0, 1_500_000, b"frame")
+                .build();
+            assert_eq!(
+                packets(&capture),
+                [(0, 11_500_000_000, b"frame".to_vec())],
+                "big endian: {big_endian}"
+            );
+        }
+    }
+
+    #[test]
+    fn captures_that_are_cut_off_or_not_pcapng() {
+        let capture = CaptureBuilder::new(false)
+            .interface(LINKTYPE_RADIOTAP, 9, 0)
+            .build();
+        let mut reader = BlockReader::new(&capture[..capture.len() - 2]);
+        reader.next_block().unwrap();
+        assert!(reader.next_block().is_err());
+
+        let mut reader = BlockReader::new(&capture[28..]);
+        assert!(reader.next_block().is_err());
+    }
+
+    #[test]
+    fn binary_clock_resolution() {
+        let clock = InterfaceClock {
+            resolution: Resolution::Binary(10),
+            offset: 0,
+        };
+        assert_eq!(clock.nanos(1024 * 3 + 512), 3_500_000_000);
+    }
+}
@@ -0,0 +1,296 @@
+//! Trim a capture to the packets in a window of time, like the time the traffic of a run was
+//! sent, leaving out the padding and association traffic around it.
+
+use std::{
+    io::{BufWriter, Read, Write},
+    path::Path,
+};
+
+use anyhow::Context;
+use serde::Serialize;
+use tracing::{info, warn};
+
+use crate::{
+    capture::pcapng::{
+        packet_ticks, write_block, BlockReader, InterfaceClock, ENHANCED_PACKET,
+        INTERFACE_DESCRIPTION, INTERFACE_STATISTICS, SECTION_HEADER, SIMPLE_PACKET,
+    },
+    hosts::HostId,
+    utils::host_file,
+};
+
+/// The obsolete packet block, which is dropped like the other packets outside of the window.
+const OBSOLETE_PACKET: u32 = 0x0000_0002;
+
+/// What was kept of a trimmed capture.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct TrimStats {
+    /// The packets inside the window.
+    pub packets: u64,
+    /// The packets outside of the window, or without a timestamp.
+    pub dropped: u64,
+}
+
+/// Copy the capture in `reader` to `writer`, keeping only the enhanced packet blocks with a
+/// timestamp between `start` and `end`, in seconds since the unix epoch by the clock of the host
+/// that made the capture. Packets at exactly `start` or `end` are kept.
+///
+/// Section headers, interface descriptions and other blocks describing the capture are kept, so
+/// the result is a valid capture also if no packets are in the window. Simple packet blocks have
+/// no timestamp and are dropped, as are the interface statistics, which count the packets of the
+/// whole capture.
+pub fn trim(
+    reader: impl Read,
+    mut writer: impl Write,
+    start: f64,
+    end: f64,
+) -> anyhow::Result<TrimStats> {
+    if start.is_nan() || end.is_nan() || start > end {
+        anyhow::bail!("the window to trim to ends before it starts");
+    }
+    let (start, end) = (nanos(start), nanos(end));
+    let mut reader = BlockReader::new(reader);
+    let mut stats = TrimStats::default();
+    let mut interfaces = Vec::new();
+    while let Some((kind, mut body)) = reader.next_block().context("invalid capture")? {
+        match kind {
+            SECTION_HEADER => {
+                interfaces.clear();
+                // Dropping packets changes the length of the section, which is then unknown.
+                if let Some(length) = body.get_mut(8..16) {
+                    length.fill(0xFF);
+                }
+            }
+            INTERFACE_DESCRIPTION => interfaces.push(InterfaceClock::new(&reader, &body)),
+            ENHANCED_PACKET => {
+                if body.len() < 20 {
+                    anyhow::bail!("packet block is too short");
+                }
+                let interface = reader.u32(&body[..4]) as usize;
+                let clock = interfaces
+                    .get(interface)
+                    .with_context(|| format!("packet is on undescribed interface {interface}"))?;
+                let time = clock.nanos(packet_ticks(&reader, &body));
+                if !(start..=end).contains(&time) {
+                    stats.dropped += 1;
+                    continue;
+                }
+                stats.packets += 1;
+            }
+            SIMPLE_PACKET | OBSOLETE_PACKET => {
+                stats.dropped += 1;
+                continue;
+            }
+            INTERFACE_STATISTICS => continue,
+            _ => {}
+        }
+        write_block(&mut writer, kind, &body, reader.big_endian())
+            .context("failed to write trimmed capture")?;
+    }
+    writer.flush().context("failed to write trimmed capture")?;
+    Ok(stats)
+}
+
+/// Trim the capture at `capture` to `output`, see [trim]. If `output` is the capture itself, it
+/// is replaced once the trimmed capture is written.
+pub async fn trim_file(
+    capture: &Path,
+    output: &Path,
+    start: f64,
+    end: f64,
+) -> anyhow::Result<TrimStats> {
+    let (capture, output) = (capture.to_owned(), output.to_owned());
+    tokio::task::spawn_blocking(move || {
+        let temporary = output.with_extension("pcapng.tmp");
+        let reader = std::fs::File::open(&capture)
+            .with_context(|| format!("could not open {}", capture.display()))?;
+        let writer = std::fs::File::create(&temporary)
+            .with_context(|| format!("could not create {}", temporary.display()))?;
+        let stats = match trim(reader, BufWriter::new(writer), start, end) {
+            Ok(stats) => stats,
+            Err(err) => {
+                _ = std::fs::remove_file(&temporary);
+                return Err(err.context(format!("could not trim {}", capture.display())));
+            }
+        };
+        std::fs::rename(&temporary, &output)
+            .with_context(|| format!("could not write {}", output.display()))?;
+        Ok(stats)
+    })
+    .await
+    .context("trim task panicked")?
+}
+
+/// Trim the captures of the monitors in `out_path` to the window from `start` to `end`, in
+/// seconds since the unix epoch by the clock of the controller. Every capture is shifted by the
+/// clock offset of its host, how far its clock is ahead of the controller.
+///
+/// The trimmed captures are written to `<host>.trimmed.pcapng`, or replace the captures if
+/// `in_place` is set. Captures that can not be trimmed are kept with a warning.
+pub async fn trim_captures(
+    out_path: &Path,
+    captures: &[(HostId, f64)],
+    start: f64,
+    end: f64,
+    in_place: bool,
+) {
+    for (host, offset) in captures {
+        let capture = host_file(out_path, host, "pcapng");
+        let output = if in_place {
+            capture.clone()
+        } else {
+            host_file(out_path, host, "trimmed.pcapng")
+        };
+        match trim_file(&capture, &output, start + offset, end + offset).await {
+            Ok(stats) if stats.packets == 0 => {
+                warn!(host, "No packets were captured while the traffic ran")
+            }
+            Ok(stats) => info!(
+                host,
+                "Trimmed the capture to {} packets, dropped {}", stats.packets, stats.dropped
+            ),
+            Err(err) => warn!(host, "Could not trim the capture: {err:#}"),
+        }
+    }
+}
+
+/// `seconds` since the unix epoch in nanoseconds, rounded to the nearest nanosecond.
+fn nanos(seconds: f64) -> i128 {
+    let whole = seconds.floor();
+    whole as i128 * 1_000_000_000 + ((seconds - whole) * 1e9).round() as i128
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::capture::pcapng::{
+        tests::{blocks, packets, CaptureBuilder},
+        BYTE_ORDER_MAGIC, LINKTYPE_RADIOTAP,
+    };
+
+    /// A capture in nanoseconds with packets around the window from 10 to 12 seconds.
+    fn capture() -> CaptureBuilder {
+        CaptureBuilder::new(false)
+            .interface(LINKTYPE_RADIOTAP, 9, 0)
+            .packet(0, 9_999_999_999, b"before")
+            .packet(0, 10_000_000_000, b"start")
+            .packet(0, 11_000_000_000, b"inside")
+            .packet(0, 12_000_000_000, b"end")
+            .packet(0, 12_000_000_001, b"after")
+    }
+
+    fn run(capture: CaptureBuilder, start: f64, end: f64) -> (TrimStats, Vec<u8>) {
+        let mut output = Vec::new();
+        let stats = trim(&capture.build()[..], &mut output, start, end).unwrap();
+        (stats, output)
+    }
+
+    fn data(capture: &[u8]) -> Vec<Vec<u8>> {
+        packets(capture)
+            .into_iter()
+            .map(|(_, _, data)| data)
+            .collect()
+    }
+
+    #[test]
+    fn packets_at_the_boundaries_are_kept() {
+        let (stats, output) = run(capture(), 10.0, 12.0);
+        assert_eq!(stats.packets, 3);
+        assert_eq!(stats.dropped, 2);
+        assert_eq!(data(&output), [&b"start"[..], b"inside", b"end"]);
+    }
+
+    #[test]
+    fn a_nanosecond_outside_of_the_window_is_dropped() {
+        let (_, output) = run(capture(), 10.000_000_001, 11.999_999_999);
+        assert_eq!(data(&output), [b"inside"]);
+        let (_, output) = run(capture(), 9.999_999_999, 12.000_000_001);
+        assert_eq!(data(&output).len(), 5);
+    }
+
+    #[test]
+    fn a_window_of_a_single_instant() {
+        let (stats, output) = run(capture(), 11.0, 11.0);
+        assert_eq!(stats.packets, 1);
+        assert_eq!(data(&output), [b"inside"]);
+    }
+
+    #[test]
+    fn an_empty_result_is_still_a_capture() {
+        let (stats, output) = run(capture(), 20.0, 30.0);
+        assert_eq!(stats.packets, 0);
+        assert_eq!(stats.dropped, 5);
+        let kinds = blocks(&output)
+            .into_iter()
+            .map(|(kind, _)| kind)
+            .collect::<Vec<_>>();
+        assert_eq!(kinds, [SECTION_HEADER, INTERFACE_DESCRIPTION]);
+    }
+
+    #[test]
+    fn the_section_length_becomes_unknown() {
+        let (_, output) = run(capture(), 10.0, 12.0);
+        let (_, header) = &blocks(&output)[0];
+        assert_eq!(header[8..16], [0xFF; 8]);
+    }
+
+    #[test]
+    fn statistics_and_packets_without_a_timestamp_are_dropped() {
+        let capture = capture()
+            .block(SIMPLE_PACKET, &[0, 0, 0, 4, b'x', 0, 0, 0])
+            .block(INTERFACE_STATISTICS, &[0; 12]);
+        let (stats, output) = run(capture, 10.0, 12.0);
+        assert_eq!(stats.packets, 3);
+        assert_eq!(stats.dropped, 3);
+        let kinds = blocks(&output)
+            .into_iter()
+            .map(|(kind, _)| kind)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            kinds,
+            [
+                SECTION_HEADER,
+                INTERFACE_DESCRIPTION,
+                ENHANCED_PACKET,
+                ENHANCED_PACKET,
+                ENHANCED_PACKET
+            ]
+        );
+    }
+
+    #[test]
+    fn the_byte_order_and_clock_of_the_capture_are_used() {
+        // Microseconds in a big endian section, with 10 seconds added to every timestamp.
+        let capture = CaptureBuilder::new(true)
+            .interface(LINKTYPE_RADIOTAP, 6, 10)
+            .packet(0, 999_999, b"before")
+            .packet(0, 1_000_000, b"inside");
+        let (stats, output) = run(capture, 11.0, 12.0);
+        assert_eq!(stats.packets, 1);
+        assert_eq!(output[8..12], BYTE_ORDER_MAGIC.to_be_bytes());
+        assert_eq!(packets(&output), [(0, 11_000_000_000, b"inside".to_vec())]);
+    }
+
+    #[test]
+    fn invalid_windows_are_rejected() {
+        let mut output = Vec::new();
+        let capture = capture().build();
+        assert!(trim(&capture[..], &mut output, 12.0, 10.0).is_err());
+        assert!(trim(&capture[..], &mut output, f64::NAN, 10.0).is_err());
+        assert!(trim(&capture[..], &mut output, 10.0, f64::NAN).is_err());
+        assert!(output.is_empty());
+    }
+
+    #[test]
+    fn packets_on_undescribed_interfaces_are_rejected() {
+        let capture = CaptureBuilder::new(false).packet(0, 0, b"orphan").build();
+        assert!(trim(&capture[..], Vec::new(), 0.0, 1.0).is_err());
+    }
+
+    #[test]
+    fn nanos_rounds_to_the_nearest_nanosecond() {
+        assert_eq!(nanos(10.000_000_001), 10_000_000_001);
+        assert_eq!(nanos(-0.5), -500_000_000);
+        assert_eq!(nanos(1_700_000_000.25), 1_700_000_000_250_000_000);
+    }
+}
@@ -0,0 +1,157 @@
+//! An audit log of every command the controller runs on the hosts, so a run can be reviewed
+//! without reading the source.
+//!
+//! While a log is active, every command is appended to `commands.jsonl` in the output directory
+//! as a JSON object per line. Separately, the commands that failed can be counted by host.
+
+use std::{
+    collections::BTreeMap,
+    fmt::Display,
+    fs::File,
+    io::{BufWriter, Write},
+    path::Path,
+    process::ExitStatus,
+    sync::{Arc, Mutex, RwLock},
+    time::SystemTime,
+};
+
+use anyhow::Context;
+use serde::Serialize;
+use tracing::warn;
+
+use crate::{
+    hosts::{Host, HostId},
+    utils::unix_time,
+};
+
+/// The log commands are currently recorded to, if any.
+static ACTIVE: RwLock<Option<Arc<CommandLog>>> = RwLock::new(None);
+
+/// A log of the commands run during a script, see the module documentation.
+pub struct CommandLog {
+    writer: Mutex<BufWriter<File>>,
+}
+
+/// A single line of `commands.jsonl`.
+#[derive(Debug, Serialize)]
+struct Entry<'a> {
+    /// When the command was started, in seconds since the unix epoch.
+    time: f64,
+    host: &'a str,
+    command: String,
+    /// The exit code, `None` if the command was killed by a signal or could not be run.
+    status: Option<i32>,
+    /// Why the command could not be run.
+    error: Option<String>,
+    /// How long the command ran in seconds.
+    duration: f64,
+}
+
+impl CommandLog {
+    /// Start recording every command to `commands.jsonl` in `out_path`, which must exist.
+    pub fn start(out_path: &Path) -> anyhow::Result<Arc<Self>> {
+        let path = out_path.join("commands.jsonl");
+        let file = File::options()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .with_context(|| format!("could not open {}", path.display()))?;
+        let log = Arc::new(CommandLog {
+            writer: Mutex::new(BufWriter::new(file)),
+        });
+        *ACTIVE.write().expect("command log lock is poisoned") = Some(log.clone());
+        Ok(log)
+    }
+
+    /// Stop recording commands and write out the buffered entries.
+    pub fn finish(&self) -> anyhow::Result<()> {
+        ACTIVE.write().expect("command log lock is poisoned").take();
+        self.writer
+            .lock()
+            .expect("command log lock is poisoned")
+            .flush()
+            .context("failed to write command log")
+    }
+
+    fn append(&self, entry: &Entry) -> anyhow::Result<()> {
+        let mut line = serde_json::to_vec(entry).context("failed to serialize command")?;
+        line.push(b'\n');
+        self.writer
+            .lock()
+            .expect("command log lock is poisoned")
+            .write_all(&line)
+            .context("failed to write command log")
+    }
+}
+
+/// Record a command that completed on `host`, if a log is active. `result` is the exit status, or
+/// why the command could not be run.
+pub fn record(
+    host: &Host,
+    command: &dyn Display,
+    start: SystemTime,
+    result: Result<ExitStatus, &dyn Display>,
+) {
+    match result {
+        Ok(status) if status.success() => {}
+        Ok(status) => count_failure(host, || format!("`{command}` exited with {status}")),
+        Err(err) => count_failure(host, || format!("`{command}` could not be run: {err}")),
+    }
+
+    let Some(log) = ACTIVE.read().expect("command log lock is poisoned").clone() else {
+        return;
+    };
+    let (status, error) = match result {
+        Ok(status) => (status.code(), None),
+        Err(err) => (None, Some(err.to_string())),
+    };
+    let entry = Entry {
+        time: unix_time(start),
+        host: &host.id,
+        command: command.to_string(),
+        status,
+        error,
+        duration: start.elapsed().unwrap_or_default().as_secs_f64(),
+    };
+    if let Err(err) = log.append(&entry) {
+        warn!(host = host.id, "Could not record command: {err:?}");
+    }
+}
+
+/// The failed commands of a run, counted by host while a tally is active, so the outcome of a run
+/// can say which hosts had trouble. Unlike the log this is kept in memory.
+static FAILURES: Mutex<Option<BTreeMap<HostId, HostErrors>>> = Mutex::new(None);
+
+/// The failed commands on a single host.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct HostErrors {
+    /// How many commands failed.
+    pub failures: u64,
+    /// Why the last command failed.
+    pub last_error: String,
+}
+
+/// Start counting failed commands, see [finish_tally].
+pub fn start_tally() {
+    *FAILURES.lock().expect("command log lock is poisoned") = Some(BTreeMap::new());
+}
+
+/// Stop counting failed commands and return them by host.
+pub fn finish_tally() -> BTreeMap<HostId, HostErrors> {
+    FAILURES
+        .lock()
+        .expect("command log lock is poisoned")
+        .take()
+        .unwrap_or_default()
+}
+
+/// Count a command that failed on `host`, if a tally is active.
+fn count_failure(host: &Host, error: impl FnOnce() -> String) {
+    let mut failures = FAILURES.lock().expect("command log lock is poisoned");
+    let Some(failures) = failures.as_mut() else {
+        return;
+    };
+    let errors = failures.entry(host.id.clone()).or_default();
+    errors.failures += 1;
+    errors.last_error = error();
+}
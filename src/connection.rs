@@ -1,31 +1,218 @@
-use openssh::Stdio;
-use tracing::error;
+use std::time::Duration;
 
-use crate::hosts::Host;
+use anyhow::Context;
+use tracing::{debug, error, info};
+
+use crate::{
+    hosts::Host,
+    utils::{ipv4_of, RemoteCmd},
+};
 
 impl Host {
     /// Connect to a wireless network, optionally with a password.
     pub async fn associate(&self, ssid: &str, password: Option<&str>) -> anyhow::Result<()> {
-        let mut command = self.session.command("sudo");
-        command.args(["nmcli", "device", "wifi", "connect", ssid]);
-
-        if let Some(password) = password {
-            command.args(["password", password]);
+        if let Err(err) = self.command_checked(connect_command(ssid, password)).await {
+            error!(host = self.id, "failed to connect to Wi-Fi network");
+            return Err(err).context("failed to connect to Wi-Fi network");
         }
+        Ok(())
+    }
 
-        command
-            .stdin(Stdio::null())
-            .stdout(Stdio::null())
-            .stderr(Stdio::null());
+    /// Get the SSID of the wireless network the host is currently connected to, if any.
+    pub async fn connected_ssid(&self) -> anyhow::Result<Option<String>> {
+        let out = self
+            .command_checked(RemoteCmd::new("nmcli").args([
+                "--terse",
+                "--fields",
+                "ACTIVE,SSID",
+                "device",
+                "wifi",
+                "list",
+                "--rescan",
+                "no",
+            ]))
+            .await
+            .context("failed to list Wi-Fi networks")?;
 
-        let out = command.output().await?;
-        if !out.status.success() {
-            error!(host = self.id, "failed to connect to Wi-Fi network");
+        // Terse output separates fields with `:`, escaping `:` and `\` in the values themselves.
+        let out = String::from_utf8_lossy(&out.stdout);
+        Ok(out
+            .lines()
+            .find_map(|line| line.strip_prefix("yes:"))
+            .map(unescape_terse))
+    }
+
+    /// The IP address of the main wireless interface. Uses the `interface-ip` from the hosts file
+    /// if set, otherwise it is looked up from the `interface-name`.
+    pub async fn ip_address(&self) -> anyhow::Result<String> {
+        if let Some(ip) = self.extra_data.interface_ip() {
+            return Ok(ip.to_string());
+        }
+        let Some(ifname) = self.extra_data.interface_name() else {
             anyhow::bail!(
-                "connecting to Wi-Fi network exited with error code {}",
-                out.status
+                "host `{}` should have an interface name or IP address configured",
+                self.id
             );
+        };
+
+        debug!(host = self.id, "Getting ip of {ifname}");
+        let output = self
+            .shell_checked(format!(
+                "ip -4 a show {ifname} | awk '/inet/ {{print $2}}' | cut -d/ -f1"
+            ))
+            .await
+            .context("failed to get the IP address")?;
+
+        let Some(ip) = ipv4_of(&output.stdout)
+            .with_context(|| format!("could not parse the IP address of {ifname}"))?
+        else {
+            anyhow::bail!("interface {ifname} has no IP address");
+        };
+        debug!(host = self.id, "Found ip: {ip}");
+        Ok(ip.to_string())
+    }
+
+    /// Connect to a wireless network without a password, unless the host is already connected
+    /// to it.
+    pub async fn ensure_associated(&self, ssid: &str) -> anyhow::Result<()> {
+        if self.connected_ssid().await?.as_deref() == Some(ssid) {
+            return Ok(());
         }
+        info!(host = self.id, "Associating with {ssid}");
+        self.associate(ssid, None).await
+    }
+
+    /// Disconnect the main wireless interface from its network.
+    pub async fn disconnect(&self) -> anyhow::Result<()> {
+        let ifname = self.wireless_interface()?;
+        self.nmcli_device(&["disconnect", ifname]).await
+    }
+
+    /// Take the main wireless interface down and up again, to recover it from a stuck connection
+    /// attempt.
+    pub async fn reset_interface(&self) -> anyhow::Result<()> {
+        let ifname = self.wireless_interface()?;
+        info!(host = self.id, "Resetting {ifname}");
+        self.nmcli_device(&["down", ifname]).await?;
+        self.nmcli_device(&["up", ifname]).await
+    }
+
+    /// Wait until the main wireless interface is associated to an access point. This waits
+    /// indefinitely, so it should be combined with a timeout.
+    pub async fn wait_for_association(&self) -> anyhow::Result<()> {
+        let ifname = self.wireless_interface()?;
+        self.poll_until(&format!("iw dev {ifname} link"), |out| {
+            out.starts_with("Connected to")
+        })
+        .await
+    }
+
+    /// Wait until the main wireless interface has an IPv4 address, for example after DHCP
+    /// completed. This waits indefinitely, so it should be combined with a timeout.
+    pub async fn wait_for_link(&self) -> anyhow::Result<()> {
+        let ifname = self.wireless_interface()?;
+        self.poll_until(&format!("ip -4 -o addr show dev {ifname}"), |out| {
+            out.contains(" inet ")
+        })
+        .await
+    }
+
+    fn wireless_interface(&self) -> anyhow::Result<&str> {
+        self.extra_data
+            .interface_name()
+            .ok_or_else(|| anyhow::anyhow!("host `{}` has no interface name configured", self.id))
+    }
+
+    async fn nmcli_device(&self, args: &[&str]) -> anyhow::Result<()> {
+        self.command_checked(RemoteCmd::new("sudo").args(["nmcli", "device"]).args(args))
+            .await?;
         Ok(())
     }
+
+    /// Run a command repeatedly until its output matches.
+    async fn poll_until(&self, command: &str, done: impl Fn(&str) -> bool) -> anyhow::Result<()> {
+        loop {
+            let out = self.shell_checked(command).await?;
+            if done(String::from_utf8_lossy(&out.stdout).trim_start()) {
+                return Ok(());
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+    }
+}
+
+/// The command connecting to the network `ssid`. The SSID and password are passed as separate
+/// arguments, so they arrive unchanged whatever characters they contain.
+fn connect_command(ssid: &str, password: Option<&str>) -> RemoteCmd {
+    let command = RemoteCmd::new("sudo").args(["nmcli", "device", "wifi", "connect", ssid]);
+    match password {
+        Some(password) => command.args(["password", password]),
+        None => command,
+    }
+}
+
+/// Undo the escaping of a value in the terse output of nmcli, where a backslash escapes the
+/// character that follows it.
+fn unescape_terse(value: &str) -> String {
+    let mut unescaped = String::with_capacity(value.len());
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => unescaped.extend(chars.next()),
+            c => unescaped.push(c),
+        }
+    }
+    unescaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::tests::shell_words;
+
+    /// SSIDs that break commands that do not quote them.
+    const HOSTILE_SSIDS: &[&str] = &[
+        "my network",
+        "Bob's Wi-Fi",
+        "\"quoted\"",
+        "$HOME",
+        "net; reboot",
+        "caf\u{e9} \u{1f4f6}",
+        r"back\slash:colon",
+    ];
+
+    #[test]
+    fn connect_command_with_hostile_ssids() {
+        for ssid in HOSTILE_SSIDS {
+            let command = connect_command(ssid, None);
+            assert_eq!(
+                shell_words(&command.exec_line()),
+                ["sudo", "nmcli", "device", "wifi", "connect", ssid],
+                "{command}"
+            );
+        }
+    }
+
+    #[test]
+    fn connect_command_with_a_hostile_password() {
+        let command = connect_command("Bob's Wi-Fi", Some("pa$$ 'word'"));
+        assert_eq!(
+            command.to_string(),
+            r"sudo nmcli device wifi connect 'Bob'\''s Wi-Fi' password 'pa$$ '\''word'\'''"
+        );
+        assert_eq!(
+            shell_words(&command.exec_line())[5..],
+            ["Bob's Wi-Fi", "password", "pa$$ 'word'"]
+        );
+    }
+
+    #[test]
+    fn terse_ssids_are_unescaped() {
+        assert_eq!(unescape_terse("my network"), "my network");
+        assert_eq!(unescape_terse(r"back\\slash\:colon"), r"back\slash:colon");
+        assert_eq!(unescape_terse("Bob's Wi-Fi"), "Bob's Wi-Fi");
+        assert_eq!(unescape_terse("caf\u{e9}\\:"), "caf\u{e9}:");
+        // A trailing backslash escapes nothing.
+        assert_eq!(unescape_terse(r"net\"), "net");
+    }
 }
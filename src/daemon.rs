@@ -0,0 +1,537 @@
+//! Long-running remote processes, such as iperf servers and captures, that are stopped when the
+//! controller no longer needs them.
+
+use std::{
+    collections::BTreeMap,
+    fmt::Display,
+    future::Future,
+    process::Output,
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime},
+};
+
+use anyhow::Context;
+use openssh::Stdio;
+use tokio::{
+    io::{AsyncBufReadExt, AsyncReadExt, BufReader},
+    runtime::Handle,
+    sync::oneshot,
+    task::{JoinHandle, JoinSet},
+    time::{sleep, timeout},
+};
+use tracing::{debug, warn};
+
+use crate::{
+    command_log,
+    hosts::{Host, HostId},
+    utils::{exit_error, RemoteCmd},
+};
+
+/// How long a daemon gets to exit after being asked to, before it is killed.
+pub const STOP_GRACE_PERIOD: Duration = Duration::from_secs(2);
+
+/// How often to check whether a daemon stopped with [terminate] exited.
+const EXIT_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// The command lines of the daemons the controller started that did not exit yet, by host and
+/// PID. After an aborted run only these are stopped, as other processes on the hosts may not
+/// belong to the controller.
+static STARTED: Mutex<BTreeMap<(HostId, u32), String>> = Mutex::new(BTreeMap::new());
+
+/// Keeps a daemon in [STARTED] until it is dropped.
+struct Registration {
+    key: (HostId, u32),
+}
+
+impl Registration {
+    fn new(host: &str, pid: u32, command: &RemoteCmd) -> Self {
+        let key = (host.to_string(), pid);
+        started().insert(key.clone(), command.to_string());
+        Registration { key }
+    }
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        started().remove(&self.key);
+    }
+}
+
+fn started() -> std::sync::MutexGuard<'static, BTreeMap<(HostId, u32), String>> {
+    STARTED.lock().expect("daemon registry lock is poisoned")
+}
+
+/// The daemons started on `host` that did not exit yet, as their PID and command line.
+pub fn started_on(host: &str) -> Vec<(u32, String)> {
+    started()
+        .iter()
+        .filter(|((id, _), _)| id == host)
+        .map(|((_, pid), command)| (*pid, command.clone()))
+        .collect()
+}
+
+/// Stop the daemon with `pid` on `host` as [RemoteDaemon::stop] does, without a handle to it.
+/// Returns whether it was still running, processes the controller did not start are left alone.
+pub async fn terminate(host: &Host, pid: u32) -> anyhow::Result<bool> {
+    let key = (host.id.clone(), pid);
+    let running = || started().contains_key(&key);
+    if !running() {
+        return Ok(false);
+    }
+    send_signal(host, pid, "TERM").await?;
+    let stopped = async {
+        while running() {
+            sleep(EXIT_POLL_INTERVAL).await;
+        }
+    };
+    if timeout(STOP_GRACE_PERIOD, stopped).await.is_err() {
+        send_signal(host, pid, "KILL").await?;
+    }
+    Ok(true)
+}
+
+/// A host daemons run on, which can signal them.
+pub trait DaemonHost: Send + Sync + 'static {
+    fn id(&self) -> &str;
+
+    /// Send a signal, like `TERM`, to a process. Succeeds if the process already exited.
+    fn signal(&self, pid: u32, signal: &str) -> impl Future<Output = anyhow::Result<()>> + Send;
+}
+
+impl DaemonHost for Host {
+    fn id(&self) -> &str {
+        &self.id
+    }
+
+    fn signal(&self, pid: u32, signal: &str) -> impl Future<Output = anyhow::Result<()>> + Send {
+        send_signal(self, pid, signal)
+    }
+}
+
+/// A process running on a host in the background.
+///
+/// If the handle is dropped while the process is still running, the process is stopped as by
+/// [RemoteDaemon::stop]. This is best effort, as it needs the runtime to still be running.
+pub struct RemoteDaemon<H: DaemonHost = Host> {
+    host: Arc<H>,
+    command: RemoteCmd,
+    pid: u32,
+    /// The task collecting the output, `None` once it was taken to wait for it.
+    task: Option<JoinHandle<Result<Output, openssh::Error>>>,
+}
+
+impl Host {
+    /// Start `command` in the background. Returns once the process is running.
+    pub async fn spawn_daemon(
+        self: &Arc<Self>,
+        command: impl Into<RemoteCmd>,
+    ) -> anyhow::Result<RemoteDaemon> {
+        let command = command.into();
+        // The shell reports its PID, which the command takes over.
+        let wrapped = RemoteCmd::shell(format!("echo $$; exec {}", command.exec_line()));
+        let (pid_sender, pid) = oneshot::channel();
+        let mut task = tokio::spawn({
+            let host = self.clone();
+            let command = command.clone();
+            async move {
+                let start = SystemTime::now();
+                let result = run(&host, &wrapped, &command, pid_sender).await;
+                let status = match &result {
+                    Ok(output) => Ok(output.status),
+                    Err(err) => Err(err as &dyn Display),
+                };
+                command_log::record(&host, &command, start, status);
+                result
+            }
+        });
+
+        let Ok(pid) = pid.await else {
+            // The process did not report its PID, so it already exited.
+            let output = (&mut task)
+                .await
+                .context("daemon task failed")?
+                .with_context(|| format!("failed to start `{command}` on `{}`", self.id))?;
+            anyhow::bail!("{}", exit_error(&command.to_string(), &output));
+        };
+        debug!(host = self.id, pid, "Started `{command}`");
+        Ok(RemoteDaemon {
+            host: self.clone(),
+            command,
+            pid,
+            task: Some(task),
+        })
+    }
+}
+
+impl<H: DaemonHost> RemoteDaemon<H> {
+    pub fn host(&self) -> &Arc<H> {
+        &self.host
+    }
+
+    /// The process ID of the daemon on its host.
+    pub fn pid(&self) -> u32 {
+        self.pid
+    }
+
+    /// Whether the daemon has already exited.
+    pub fn is_finished(&self) -> bool {
+        self.task.as_ref().is_none_or(JoinHandle::is_finished)
+    }
+
+    /// Wait for the daemon to exit by itself.
+    pub async fn wait(mut self) -> anyhow::Result<Output> {
+        let task = self.task.take().expect("the task is only taken once");
+        self.finish(task.await)
+    }
+
+    /// Wait for the daemon to exit by itself, stopping it if it is still running after `limit`.
+    /// Returns `None` if it had to be stopped.
+    pub async fn wait_timeout(mut self, limit: Duration) -> Option<anyhow::Result<Output>> {
+        let task = self.task.as_mut().expect("the task is only taken once");
+        match timeout(limit, task).await {
+            Ok(joined) => {
+                self.task = None;
+                Some(self.finish(joined))
+            }
+            Err(_) => {
+                let host = self.host.clone();
+                if let Err(err) = self.stop().await {
+                    warn!(host = host.id(), "Could not stop daemon: {err:?}");
+                }
+                None
+            }
+        }
+    }
+
+    /// Ask the daemon to exit, killing it if it does not within [STOP_GRACE_PERIOD].
+    pub async fn stop(mut self) -> anyhow::Result<Output> {
+        // The task is kept until the daemon exited, so it is still killed when the handle is
+        // dropped if it can not be signalled now.
+        let task = self.task.as_mut().expect("the task is only taken once");
+        if task.is_finished() {
+            let joined = task.await;
+            self.task = None;
+            return self.finish(joined);
+        }
+        debug!(
+            host = self.host.id(),
+            pid = self.pid,
+            "Stopping `{}`",
+            self.command
+        );
+        self.host.signal(self.pid, "TERM").await?;
+        let task = self.task.as_mut().expect("the task is only taken once");
+        let waited = timeout(STOP_GRACE_PERIOD, &mut *task).await;
+        let joined = match waited {
+            Ok(joined) => joined,
+            Err(_) => {
+                warn!(
+                    host = self.host.id(),
+                    "`{}` did not exit within {STOP_GRACE_PERIOD:?}, killing it", self.command
+                );
+                self.host.signal(self.pid, "KILL").await?;
+                task.await
+            }
+        };
+        self.task = None;
+        self.finish(joined)
+    }
+
+    fn finish(
+        &self,
+        joined: Result<Result<Output, openssh::Error>, tokio::task::JoinError>,
+    ) -> anyhow::Result<Output> {
+        joined
+            .context("daemon task failed")?
+            .with_context(|| format!("failed to run `{}` on `{}`", self.command, self.host.id()))
+    }
+}
+
+impl<H: DaemonHost> Drop for RemoteDaemon<H> {
+    fn drop(&mut self) {
+        let Some(task) = self.task.take() else {
+            return;
+        };
+        if task.is_finished() {
+            return;
+        }
+        let Ok(runtime) = Handle::try_current() else {
+            return;
+        };
+        warn!(
+            host = self.host.id(),
+            "Killing `{}` as it is no longer needed", self.command
+        );
+        let host = self.host.clone();
+        let pid = self.pid;
+        // Commands run through sudo only pass on signals they can catch, so the daemon first gets
+        // the chance to exit.
+        runtime.spawn(async move {
+            let result = match host.signal(pid, "TERM").await {
+                Ok(()) => {
+                    sleep(STOP_GRACE_PERIOD).await;
+                    host.signal(pid, "KILL").await
+                }
+                Err(err) => Err(err),
+            };
+            if let Err(err) = result {
+                warn!(host = host.id(), pid, "Could not kill daemon: {err:?}");
+            }
+        });
+    }
+}
+
+/// Stop all daemons at the same time, logging those that could not be stopped.
+pub async fn stop_all<H: DaemonHost>(daemons: impl IntoIterator<Item = RemoteDaemon<H>>) {
+    let mut stopping = JoinSet::new();
+    for daemon in daemons {
+        stopping.spawn(async move {
+            let id = daemon.host.id().to_string();
+            (id, daemon.stop().await)
+        });
+    }
+    for (host, result) in stopping.join_all().await {
+        if let Err(err) = result {
+            warn!(host, "Could not stop daemon: {err:?}");
+        }
+    }
+}
+
+/// Send a signal to a process, through sudo if it runs as another user. Succeeds if the process
+/// already exited.
+async fn send_signal(host: &Host, pid: u32, signal: &str) -> anyhow::Result<()> {
+    host.shell_checked(format!(
+        "kill -s {signal} {pid} 2>/dev/null || sudo -n kill -s {signal} {pid} 2>/dev/null || \
+         [ ! -e /proc/{pid} ]"
+    ))
+    .await
+    .with_context(|| format!("failed to send SIG{signal} to {pid}"))?;
+    Ok(())
+}
+
+/// Run the wrapped command of a daemon, sending the PID it reports on its first line of output.
+/// The daemon is registered as started until it exits.
+async fn run(
+    host: &Host,
+    wrapped: &RemoteCmd,
+    command: &RemoteCmd,
+    pid: oneshot::Sender<u32>,
+) -> Result<Output, openssh::Error> {
+    let mut registration = None;
+    let mut child = wrapped
+        .build(&host.session)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .await?;
+
+    // SAFETY: Both streams were set to `Stdio::piped()` above.
+    let stdout = child.stdout().take().expect("missing stdout handle");
+    let mut stderr = child.stderr().take().expect("missing stderr handle");
+
+    let read_stdout = async {
+        let mut reader = BufReader::new(stdout);
+        let mut first = String::new();
+        reader.read_line(&mut first).await?;
+        if let Ok(reported) = first.trim().parse() {
+            registration = Some(Registration::new(&host.id, reported, command));
+            _ = pid.send(reported);
+        }
+        let mut rest = Vec::new();
+        reader.read_to_end(&mut rest).await?;
+        Ok(rest)
+    };
+    let mut stderr_output = Vec::new();
+    let (stdout, _) = tokio::try_join!(read_stdout, stderr.read_to_end(&mut stderr_output))
+        .map_err(openssh::Error::ChildIo)?;
+
+    let status = child.wait().await?;
+    drop(registration);
+    Ok(Output {
+        status,
+        stdout,
+        stderr: stderr_output,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        path::Path,
+        sync::atomic::{AtomicUsize, Ordering},
+        time::Instant,
+    };
+
+    use super::*;
+
+    /// Runs daemons on the machine running the tests.
+    #[derive(Default)]
+    struct LocalHost {
+        /// How many of the next signals fail to be sent.
+        failing_signals: AtomicUsize,
+    }
+
+    impl DaemonHost for LocalHost {
+        fn id(&self) -> &str {
+            "local"
+        }
+
+        async fn signal(&self, pid: u32, signal: &str) -> anyhow::Result<()> {
+            let failing = self.failing_signals.load(Ordering::SeqCst);
+            if failing > 0 {
+                self.failing_signals.store(failing - 1, Ordering::SeqCst);
+                anyhow::bail!("could not reach the host");
+            }
+            let status = tokio::process::Command::new("kill")
+                .args(["-s", signal, &pid.to_string()])
+                .stderr(std::process::Stdio::null())
+                .status()
+                .await?;
+            anyhow::ensure!(status.success() || !is_running(pid), "kill failed");
+            Ok(())
+        }
+    }
+
+    fn spawn(host: LocalHost, script: &str) -> RemoteDaemon<LocalHost> {
+        let child = tokio::process::Command::new("sh")
+            .args(["-c", script])
+            .stdout(std::process::Stdio::piped())
+            .stderr(std::process::Stdio::piped())
+            .spawn()
+            .unwrap();
+        let pid = child.id().unwrap();
+        RemoteDaemon {
+            host: Arc::new(host),
+            command: RemoteCmd::shell(script),
+            pid,
+            task: Some(tokio::spawn(async move {
+                child
+                    .wait_with_output()
+                    .await
+                    .map_err(openssh::Error::ChildIo)
+            })),
+        }
+    }
+
+    fn is_running(pid: u32) -> bool {
+        // Processes that exited are gone once the task waiting for them reaped them.
+        Path::new(&format!("/proc/{pid}")).exists()
+    }
+
+    async fn wait_exited(pid: u32) -> bool {
+        for _ in 0..100 {
+            if !is_running(pid) {
+                return true;
+            }
+            sleep(Duration::from_millis(20)).await;
+        }
+        false
+    }
+
+    #[tokio::test]
+    async fn stop_terminates_daemon() {
+        let daemon = spawn(LocalHost::default(), "exec sleep 30");
+        let pid = daemon.pid();
+        let start = Instant::now();
+        let output = daemon.stop().await.unwrap();
+        assert!(!output.status.success());
+        assert!(start.elapsed() < STOP_GRACE_PERIOD);
+        assert!(wait_exited(pid).await);
+    }
+
+    #[tokio::test]
+    async fn stop_kills_daemon_ignoring_term() {
+        let daemon = spawn(LocalHost::default(), "trap '' TERM; exec sleep 30");
+        let pid = daemon.pid();
+        // The shell ignores the signal once it runs the command.
+        while std::fs::read_to_string(format!("/proc/{pid}/comm")).unwrap() != "sleep\n" {
+            sleep(Duration::from_millis(10)).await;
+        }
+        let start = Instant::now();
+        let output = daemon.stop().await.unwrap();
+        assert!(!output.status.success());
+        assert!(start.elapsed() >= STOP_GRACE_PERIOD);
+        assert!(wait_exited(pid).await);
+    }
+
+    #[tokio::test]
+    async fn stop_after_exit_returns_output() {
+        let daemon = spawn(LocalHost::default(), "echo done");
+        while !daemon.is_finished() {
+            sleep(Duration::from_millis(10)).await;
+        }
+        let output = daemon.stop().await.unwrap();
+        assert!(output.status.success());
+        assert_eq!(output.stdout, b"done\n");
+    }
+
+    #[tokio::test]
+    async fn wait_timeout_returns_output_in_time() {
+        let daemon = spawn(LocalHost::default(), "sleep 0.1; echo done");
+        let output = daemon.wait_timeout(Duration::from_secs(10)).await;
+        assert_eq!(output.unwrap().unwrap().stdout, b"done\n");
+    }
+
+    #[tokio::test]
+    async fn wait_timeout_stops_daemon() {
+        let daemon = spawn(LocalHost::default(), "exec sleep 30");
+        let pid = daemon.pid();
+        let output = daemon.wait_timeout(Duration::from_millis(100)).await;
+        assert!(output.is_none());
+        assert!(wait_exited(pid).await);
+    }
+
+    #[tokio::test]
+    async fn drop_stops_daemon() {
+        let daemon = spawn(LocalHost::default(), "exec sleep 30");
+        let pid = daemon.pid();
+        drop(daemon);
+        assert!(wait_exited(pid).await);
+    }
+
+    #[tokio::test]
+    async fn failed_stop_still_stops_daemon_on_drop() {
+        let host = LocalHost {
+            failing_signals: AtomicUsize::new(1),
+        };
+        let daemon = spawn(host, "exec sleep 30");
+        let pid = daemon.pid();
+        assert!(daemon.stop().await.is_err());
+        assert!(wait_exited(pid).await);
+    }
+
+    #[test]
+    fn started_daemons_are_registered_until_they_exit() {
+        let command = RemoteCmd::new("iperf3").args(["-s", "-p", "5001"]);
+        let first = Registration::new("registry-test", 100, &command);
+        let second = Registration::new("registry-test", 101, &RemoteCmd::shell("sleep 10"));
+        let other = Registration::new("registry-test-other", 100, &command);
+        assert_eq!(
+            started_on("registry-test"),
+            [
+                (100, "iperf3 -s -p 5001".to_string()),
+                (101, "sleep 10".to_string())
+            ]
+        );
+
+        drop(first);
+        assert_eq!(started_on("registry-test"), [(101, "sleep 10".to_string())]);
+        drop((second, other));
+        assert!(started_on("registry-test").is_empty());
+        assert!(started_on("registry-test-other").is_empty());
+    }
+
+    #[tokio::test]
+    async fn stop_all_stops_every_daemon() {
+        let daemons = [
+            spawn(LocalHost::default(), "exec sleep 30"),
+            spawn(LocalHost::default(), "exec sleep 30"),
+        ];
+        let pids: Vec<_> = daemons.iter().map(RemoteDaemon::pid).collect();
+        stop_all(daemons).await;
+        for pid in pids {
+            assert!(wait_exited(pid).await);
+        }
+    }
+}
@@ -1 +1,2 @@
+pub mod ap;
 pub mod wifi;
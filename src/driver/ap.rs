@@ -0,0 +1,447 @@
+//! Configuration of access points, either through `uci` on OpenWrt or by writing a
+//! `hostapd.conf` on a generic Linux host.
+
+use std::fmt::Write;
+
+use anyhow::Context;
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use tracing::debug;
+
+use crate::{hosts::Host, utils::shell_quote};
+
+/// How the access point is configured.
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ApPlatform {
+    /// Configure the radio with `uci` and apply it with `wifi reload`.
+    Openwrt,
+    /// Write a `hostapd.conf` and restart hostapd.
+    Hostapd,
+}
+
+/// The security of the network.
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Security {
+    Open,
+    /// WPA2 with a pre-shared key.
+    Wpa2,
+    /// WPA3 with SAE.
+    Wpa3,
+}
+
+/// The radio configuration of an access point.
+#[derive(Debug, Clone, Serialize)]
+pub struct ApConfig {
+    pub ssid: String,
+    /// The frequency of the primary channel in MHz.
+    pub frequency: u32,
+    /// The width of the channel in MHz.
+    pub bandwidth: u32,
+    pub security: Security,
+    pub passphrase: Option<String>,
+    /// Whether to enable 802.11ax.
+    pub he: bool,
+    /// The country code to configure, if any.
+    pub country: Option<String>,
+}
+
+/// The frequency band of a channel.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Band {
+    Ghz2,
+    Ghz5,
+    Ghz6,
+}
+
+impl ApConfig {
+    /// Check that the configuration can be expressed for both platforms.
+    pub fn validate(&self) -> anyhow::Result<()> {
+        let (band, number) = channel(self.frequency)
+            .with_context(|| format!("{} MHz is not a known channel", self.frequency))?;
+        self.htmode(band)?;
+        center_channel(band, number, self.bandwidth).with_context(|| {
+            format!(
+                "channel {number} is not part of a {} MHz wide channel",
+                self.bandwidth
+            )
+        })?;
+        if band == Band::Ghz6 && !self.he {
+            anyhow::bail!("channels in the 6 GHz band require HE");
+        }
+        match (self.security, &self.passphrase) {
+            (Security::Open, Some(_)) => anyhow::bail!("an open network does not use a passphrase"),
+            (Security::Wpa2 | Security::Wpa3, None) => {
+                anyhow::bail!("a passphrase is required for {:?}", self.security)
+            }
+            (_, Some(passphrase)) if !(8..=63).contains(&passphrase.len()) => {
+                anyhow::bail!("the passphrase must be between 8 and 63 characters")
+            }
+            _ => {}
+        }
+        if let Some(country) = &self.country {
+            if country.len() != 2 || !country.chars().all(|c| c.is_ascii_alphabetic()) {
+                anyhow::bail!("the country code must be two letters, not {country:?}");
+            }
+        }
+        if !(1..=32).contains(&self.ssid.len()) {
+            anyhow::bail!("the SSID must be between 1 and 32 bytes");
+        }
+        // Both configuration formats end a value at the end of the line.
+        let values = [Some(&self.ssid), self.passphrase.as_ref()];
+        if values
+            .into_iter()
+            .flatten()
+            .any(|v| v.contains(['\n', '\r', '\0']))
+        {
+            anyhow::bail!("the SSID and passphrase can not contain newlines or NUL characters");
+        }
+        Ok(())
+    }
+
+    /// The `htmode` of the radio as used by OpenWrt, for example `HE80`.
+    fn htmode(&self, band: Band) -> anyhow::Result<String> {
+        let mode = match (band, self.he, self.bandwidth) {
+            (_, true, 20 | 40 | 80 | 160) => "HE",
+            (Band::Ghz2, false, 20 | 40) => "HT",
+            (Band::Ghz5, false, 20 | 40 | 80 | 160) => "VHT",
+            _ => anyhow::bail!(
+                "a width of {} MHz is not supported on {} MHz",
+                self.bandwidth,
+                self.frequency
+            ),
+        };
+        Ok(format!("{mode}{}", self.bandwidth))
+    }
+
+    /// The `uci batch` commands that configure the radio `radio` and its interface section
+    /// `iface`, including the commit.
+    pub fn uci_batch(&self, radio: &str, iface: &str) -> anyhow::Result<String> {
+        let (band, channel) = channel(self.frequency).context("unknown channel")?;
+        let mut batch = String::new();
+        let band_name = match band {
+            Band::Ghz2 => "2g",
+            Band::Ghz5 => "5g",
+            Band::Ghz6 => "6g",
+        };
+        _ = writeln!(batch, "set wireless.{radio}.band='{band_name}'");
+        _ = writeln!(batch, "set wireless.{radio}.channel='{channel}'");
+        _ = writeln!(
+            batch,
+            "set wireless.{radio}.htmode='{}'",
+            self.htmode(band)?
+        );
+        if let Some(country) = &self.country {
+            _ = writeln!(batch, "set wireless.{radio}.country={}", uci_quote(country));
+        }
+        _ = writeln!(batch, "set wireless.{radio}.disabled='0'");
+        _ = writeln!(batch, "set wireless.{iface}.device='{radio}'");
+        _ = writeln!(batch, "set wireless.{iface}.mode='ap'");
+        _ = writeln!(batch, "set wireless.{iface}.ssid={}", uci_quote(&self.ssid));
+        let encryption = match self.security {
+            Security::Open => "none",
+            Security::Wpa2 => "psk2",
+            Security::Wpa3 => "sae",
+        };
+        _ = writeln!(batch, "set wireless.{iface}.encryption='{encryption}'");
+        match &self.passphrase {
+            Some(passphrase) => {
+                _ = writeln!(batch, "set wireless.{iface}.key={}", uci_quote(passphrase))
+            }
+            None => _ = writeln!(batch, "delete wireless.{iface}.key"),
+        }
+        _ = writeln!(batch, "set wireless.{iface}.disabled='0'");
+        batch.push_str("commit wireless\n");
+        Ok(batch)
+    }
+
+    /// A `hostapd.conf` for the interface `interface`.
+    pub fn hostapd_conf(&self, interface: &str) -> anyhow::Result<String> {
+        let (band, channel) = channel(self.frequency).context("unknown channel")?;
+        // Validates the combination of band and width.
+        self.htmode(band)?;
+        let center = center_channel(band, channel, self.bandwidth)
+            .context("the channel does not fit in a channel of the requested width")?;
+        // The width as encoded by hostapd for VHT and HE operation.
+        let chwidth = match self.bandwidth {
+            80 => 1,
+            160 => 2,
+            _ => 0,
+        };
+
+        let mut conf = String::new();
+        _ = writeln!(conf, "interface={interface}");
+        conf.push_str("driver=nl80211\n");
+        _ = writeln!(conf, "ssid={}", self.ssid);
+        if let Some(country) = &self.country {
+            _ = writeln!(conf, "country_code={country}");
+            conf.push_str("ieee80211d=1\n");
+        }
+        _ = writeln!(
+            conf,
+            "hw_mode={}",
+            if band == Band::Ghz2 { "g" } else { "a" }
+        );
+        _ = writeln!(conf, "channel={channel}");
+        if band == Band::Ghz6 {
+            // The global operating class of the 6 GHz band for the width.
+            let op_class = match self.bandwidth {
+                20 => 131,
+                40 => 132,
+                80 => 133,
+                _ => 134,
+            };
+            _ = writeln!(conf, "op_class={op_class}");
+        } else {
+            conf.push_str("ieee80211n=1\nwmm_enabled=1\n");
+            // The secondary channel of HT follows the 40 MHz channel containing the primary.
+            if let Some(ht_center) =
+                center_channel(band, channel, 40).filter(|_| self.bandwidth >= 40)
+            {
+                let secondary = if channel < ht_center { "+" } else { "-" };
+                _ = writeln!(conf, "ht_capab=[HT40{secondary}]");
+            }
+        }
+        if band == Band::Ghz5 {
+            conf.push_str("ieee80211ac=1\n");
+            _ = writeln!(conf, "vht_oper_chwidth={chwidth}");
+            _ = writeln!(conf, "vht_oper_centr_freq_seg0_idx={center}");
+        }
+        if self.he {
+            conf.push_str("ieee80211ax=1\n");
+            _ = writeln!(conf, "he_oper_chwidth={chwidth}");
+            _ = writeln!(conf, "he_oper_centr_freq_seg0_idx={center}");
+        }
+        match self.security {
+            Security::Open => {}
+            Security::Wpa2 => conf.push_str("wpa=2\nwpa_key_mgmt=WPA-PSK\nrsn_pairwise=CCMP\n"),
+            Security::Wpa3 => {
+                conf.push_str("wpa=2\nwpa_key_mgmt=SAE\nrsn_pairwise=CCMP\nieee80211w=2\n")
+            }
+        }
+        if let Some(passphrase) = &self.passphrase {
+            _ = writeln!(conf, "wpa_passphrase={passphrase}");
+        }
+        Ok(conf)
+    }
+}
+
+/// Quote a value for `uci batch`, which splits and unquotes its arguments like a shell.
+fn uci_quote(value: &str) -> String {
+    format!("'{}'", value.replace('\'', r"'\''"))
+}
+
+/// The band and channel number of the channel at `frequency` in MHz.
+pub fn channel(frequency: u32) -> Option<(Band, u32)> {
+    match frequency {
+        2484 => Some((Band::Ghz2, 14)),
+        2412..=2472 if (frequency - 2407).is_multiple_of(5) => {
+            Some((Band::Ghz2, (frequency - 2407) / 5))
+        }
+        5180..=5885 if frequency.is_multiple_of(5) => {
+            let channel = (frequency - 5000) / 5;
+            // The primary channels of the band are 36 to 64, 100 to 144 and 149 to 177.
+            let primary = match channel {
+                36..=64 | 100..=144 => channel.is_multiple_of(4),
+                149..=177 => (channel - 149).is_multiple_of(4),
+                _ => false,
+            };
+            primary.then_some((Band::Ghz5, channel))
+        }
+        5955..=7115 if (frequency - 5955).is_multiple_of(20) => {
+            Some((Band::Ghz6, (frequency - 5950) / 5))
+        }
+        _ => None,
+    }
+}
+
+/// The channel number of the center of the `bandwidth` MHz wide channel containing the primary
+/// channel `channel`.
+pub fn center_channel(band: Band, channel: u32, bandwidth: u32) -> Option<u32> {
+    if bandwidth == 20 {
+        return Some(channel);
+    }
+    match band {
+        // 40 MHz channels in the 2.4 GHz band overlap, so the secondary channel is placed above
+        // the primary channel where possible.
+        Band::Ghz2 => (bandwidth == 40).then(|| {
+            if channel <= 9 {
+                channel + 2
+            } else {
+                channel - 2
+            }
+        }),
+        Band::Ghz5 => {
+            let centers: &[u32] = match bandwidth {
+                40 => &[
+                    38, 46, 54, 62, 102, 110, 118, 126, 134, 142, 151, 159, 167, 175,
+                ],
+                80 => &[42, 58, 106, 122, 138, 155, 171],
+                160 => &[50, 114, 163],
+                _ => return None,
+            };
+            // The primary channel is at most half the width minus 10 MHz from the center.
+            let offset = bandwidth / 10 - 2;
+            centers
+                .iter()
+                .copied()
+                .find(|center| channel.abs_diff(*center) <= offset)
+        }
+        Band::Ghz6 => {
+            // Channels are 4 channel numbers apart, starting at channel 1.
+            let span = bandwidth / 5;
+            Some((channel - 1) / span * span + 1 + (span - 4) / 2)
+        }
+    }
+}
+
+/// Whether `host` runs OpenWrt.
+pub async fn detect_platform(host: &Host) -> anyhow::Result<ApPlatform> {
+    let output = host
+        .session
+        .shell("test -f /etc/openwrt_release")
+        .output()
+        .await
+        .context("failed to detect the platform of the access point")?;
+    let platform = if output.status.success() {
+        ApPlatform::Openwrt
+    } else {
+        ApPlatform::Hostapd
+    };
+    debug!(host = host.id, ?platform, "Detected access point platform");
+    Ok(platform)
+}
+
+/// Apply `uci batch` commands, including their commit, and reload the wireless configuration.
+pub async fn apply_uci(host: &Host, batch: &str) -> anyhow::Result<()> {
+    let output = host
+        .session
+        .shell(format!("uci batch <<'EOF'\n{batch}EOF\nwifi reload"))
+        .output()
+        .await
+        .context("failed to apply uci configuration")?;
+
+    if !output.status.success() {
+        anyhow::bail!(
+            "applying uci configuration exited with error code {}: {}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    Ok(())
+}
+
+/// Write `conf` to the hostapd configuration at `path` and restart hostapd.
+pub async fn apply_hostapd(host: &Host, path: &str, conf: &str) -> anyhow::Result<()> {
+    let output = host
+        .session
+        .shell(format!(
+            "sudo tee {} >/dev/null <<'EOF'\n{conf}EOF\nsudo systemctl restart hostapd",
+            shell_quote(path)
+        ))
+        .output()
+        .await
+        .context("failed to apply hostapd configuration")?;
+
+    if !output.status.success() {
+        anyhow::bail!(
+            "applying hostapd configuration exited with error code {}: {}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::tests::shell_words;
+
+    fn config(ssid: &str, passphrase: Option<&str>) -> ApConfig {
+        ApConfig {
+            ssid: ssid.to_string(),
+            frequency: 5180,
+            bandwidth: 80,
+            security: match passphrase {
+                Some(_) => Security::Wpa2,
+                None => Security::Open,
+            },
+            passphrase: passphrase.map(str::to_string),
+            he: true,
+            country: Some("NL".to_string()),
+        }
+    }
+
+    /// The value `uci batch` reads from the line setting `option`.
+    fn uci_value(batch: &str, option: &str) -> String {
+        let line = batch
+            .lines()
+            .find(|l| l.starts_with(&format!("set wireless.default_radio0.{option}=")))
+            .unwrap();
+        let words = shell_words(line);
+        assert_eq!(words.len(), 2, "{line}");
+        words[1].split_once('=').unwrap().1.to_string()
+    }
+
+    #[test]
+    fn uci_batch_with_hostile_ssids() {
+        for ssid in [
+            "Bob's Wi-Fi",
+            "\"quoted\"",
+            "$HOME; reboot",
+            "caf\u{e9} \u{1f4f6}",
+        ] {
+            let config = config(ssid, Some("it's a $ecret"));
+            config.validate().unwrap();
+            let batch = config.uci_batch("radio0", "default_radio0").unwrap();
+            assert_eq!(uci_value(&batch, "ssid"), ssid, "{batch}");
+            assert_eq!(uci_value(&batch, "key"), "it's a $ecret", "{batch}");
+        }
+    }
+
+    #[test]
+    fn values_ending_a_line_are_rejected() {
+        assert!(config("net\nreboot", None).validate().is_err());
+        assert!(config("net", Some("secret\rpass")).validate().is_err());
+        assert!(config("net\0", None).validate().is_err());
+        assert!(config("", None).validate().is_err());
+        assert!(config(&"x".repeat(33), None).validate().is_err());
+        // The limit is in bytes, as in the standard.
+        assert!(config(&"\u{e9}".repeat(17), None).validate().is_err());
+        assert!(config(&"\u{e9}".repeat(16), None).validate().is_ok());
+    }
+
+    #[test]
+    fn country_must_be_two_letters() {
+        let mut config = config("net", None);
+        config.country = Some("N'".to_string());
+        assert!(config.validate().is_err());
+        config.country = Some("NLD".to_string());
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn channels_of_every_band() {
+        assert_eq!(channel(2412), Some((Band::Ghz2, 1)));
+        assert_eq!(channel(2484), Some((Band::Ghz2, 14)));
+        assert_eq!(channel(5180), Some((Band::Ghz5, 36)));
+        assert_eq!(channel(5320), Some((Band::Ghz5, 64)));
+        assert_eq!(channel(5500), Some((Band::Ghz5, 100)));
+        assert_eq!(channel(5720), Some((Band::Ghz5, 144)));
+        assert_eq!(channel(5745), Some((Band::Ghz5, 149)));
+        assert_eq!(channel(5885), Some((Band::Ghz5, 177)));
+        assert_eq!(channel(5955), Some((Band::Ghz6, 1)));
+        assert_eq!(channel(7115), Some((Band::Ghz6, 233)));
+    }
+
+    #[test]
+    fn frequencies_between_channels() {
+        // Channels 32, 33, 35, 38 and 42 are not primary channels, 68 to 96 are not in the band.
+        for frequency in [
+            2400, 2414, 5160, 5165, 5175, 5190, 5210, 5340, 5480, 5725, 5740, 5890,
+        ] {
+            assert_eq!(channel(frequency), None, "{frequency}");
+        }
+        assert_eq!(channel(5965), None);
+    }
+}
@@ -1 +1,780 @@
+//! Driver independent configuration of wireless interfaces through `iw`.
+
+use std::fmt;
+
+use anyhow::Context;
+use openssh::Stdio;
+use serde::{Deserialize, Serialize};
+use tracing::error;
+
+use crate::{
+    hosts::Host,
+    mac::MacAddr,
+    utils::{shell_quote, RemoteCmd},
+};
+
 pub mod iwlwifi;
+pub mod mt76;
+
+/// An association ID, which an access point hands out to every station it associates.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct Aid(u16);
+
+impl Aid {
+    /// The highest association ID an access point hands out.
+    pub const MAX: u16 = 2007;
+
+    /// The association ID `value`, `None` if it is not between 1 and [Aid::MAX].
+    pub fn new(value: u16) -> Option<Self> {
+        (1..=Self::MAX).contains(&value).then_some(Aid(value))
+    }
+
+    pub fn value(self) -> u16 {
+        self.0
+    }
+}
+
+impl fmt::Display for Aid {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// The transmit bitrate of a station, as reported by `iw dev <if> station dump`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StationBitrate {
+    /// The MAC address of the station.
+    pub station: String,
+    /// The bitrate as reported by iw, for example `433.3 MBit/s VHT-MCS 9 80MHz short GI`.
+    pub tx_bitrate: String,
+    /// The MCS index of the bitrate, if it is not a legacy rate.
+    pub mcs: Option<u8>,
+}
+
+/// The operating parameters of a wireless interface, as reported by `iw dev <if> info`.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct InterfaceInfo {
+    /// The MAC address of the interface, which is the BSSID for access points.
+    pub addr: Option<MacAddr>,
+    /// The frequency of the channel in MHz.
+    pub frequency: Option<u32>,
+    /// The width of the channel in MHz.
+    pub width: Option<u32>,
+}
+
+/// The connection of a client interface, as reported by `iw dev <if> link`.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct LinkInfo {
+    /// The BSSID of the access point the interface is connected to, if it is connected.
+    pub bssid: Option<String>,
+    /// The frequency of the channel in MHz.
+    pub frequency: Option<u32>,
+    /// The signal strength of the access point in dBm.
+    pub signal: Option<i32>,
+    /// The transmit bitrate as reported by iw.
+    pub tx_bitrate: Option<String>,
+}
+
+/// A BSS found by `iw dev <if> scan`.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ScanResult {
+    pub bssid: String,
+    pub ssid: Option<String>,
+    /// The frequency of the channel in MHz.
+    pub frequency: Option<u32>,
+    /// The signal strength in dBm.
+    pub signal: Option<f64>,
+}
+
+/// The driver of an interface, as reported by `ethtool -i <if>`.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct DriverInfo {
+    pub driver: Option<String>,
+    pub version: Option<String>,
+    pub firmware: Option<String>,
+}
+
+/// Get the channel and address of `interface`.
+pub async fn interface_info(host: &Host, interface: &str) -> anyhow::Result<InterfaceInfo> {
+    let output = host
+        .session
+        .shell(format!("iw dev {interface} info"))
+        .output()
+        .await
+        .context("failed to get interface info")?;
+
+    if !output.status.success() {
+        anyhow::bail!(
+            "getting interface info exited with error code {}",
+            output.status
+        );
+    }
+    Ok(parse_interface_info(&String::from_utf8_lossy(
+        &output.stdout,
+    )))
+}
+
+/// Parse the output of `iw dev <if> info`.
+///
+/// The channel is reported in a line like `channel 36 (5180 MHz), width: 80 MHz, center1: 5210 MHz`.
+pub fn parse_interface_info(info: &str) -> InterfaceInfo {
+    let mut result = InterfaceInfo::default();
+    for line in info.lines() {
+        let line = line.trim();
+        if let Some(addr) = line.strip_prefix("addr ") {
+            result.addr = addr.parse().ok();
+        } else if line.starts_with("channel ") {
+            result.frequency = line
+                .split_once('(')
+                .and_then(|(_, rest)| rest.split_whitespace().next())
+                .and_then(|v| v.parse().ok());
+            result.width = line
+                .split_once("width:")
+                .and_then(|(_, rest)| rest.split_whitespace().next())
+                .and_then(|v| v.parse().ok());
+        }
+    }
+    result
+}
+
+/// Get the access point `interface` is connected to and the signal strength.
+pub async fn link_info(host: &Host, interface: &str) -> anyhow::Result<LinkInfo> {
+    let output = host
+        .session
+        .shell(format!("iw dev {interface} link"))
+        .output()
+        .await
+        .context("failed to get link info")?;
+
+    if !output.status.success() {
+        anyhow::bail!("getting link info exited with error code {}", output.status);
+    }
+    Ok(parse_link_info(&String::from_utf8_lossy(&output.stdout)))
+}
+
+/// Parse the output of `iw dev <if> link`, which starts with `Connected to <bssid> (on <if>)`
+/// followed by lines like `freq: 5180` and `signal: -45 dBm`, or is `Not connected.`.
+pub fn parse_link_info(link: &str) -> LinkInfo {
+    let mut result = LinkInfo::default();
+    for line in link.lines() {
+        let line = line.trim();
+        if let Some(rest) = line.strip_prefix("Connected to ") {
+            result.bssid = rest.split_whitespace().next().map(str::to_string);
+        } else if let Some(freq) = line.strip_prefix("freq:") {
+            // Newer versions of iw report the frequency with a decimal, like `5180.0`.
+            result.frequency = freq.trim().parse::<f64>().ok().map(|f| f as u32);
+        } else if let Some(signal) = line.strip_prefix("signal:") {
+            result.signal = signal
+                .split_whitespace()
+                .next()
+                .and_then(|v| v.parse().ok());
+        } else if let Some(bitrate) = line.strip_prefix("tx bitrate:") {
+            result.tx_bitrate = Some(bitrate.trim().to_string());
+        }
+    }
+    result
+}
+
+/// Get the driver and firmware version of `interface`.
+pub async fn driver_info(host: &Host, interface: &str) -> anyhow::Result<DriverInfo> {
+    let output = host
+        .session
+        .shell(format!("ethtool -i {interface}"))
+        .output()
+        .await
+        .context("failed to get driver info")?;
+
+    if !output.status.success() {
+        anyhow::bail!(
+            "getting driver info exited with error code {}",
+            output.status
+        );
+    }
+    Ok(parse_driver_info(&String::from_utf8_lossy(&output.stdout)))
+}
+
+/// Parse the output of `ethtool -i <if>`, which contains lines like `driver: iwlwifi` and
+/// `firmware-version: 77.ad46c98b.0`.
+pub fn parse_driver_info(info: &str) -> DriverInfo {
+    let mut result = DriverInfo::default();
+    for (key, value) in info.lines().filter_map(|line| line.split_once(':')) {
+        let value = Some(value.trim().to_string()).filter(|v| !v.is_empty());
+        match key.trim() {
+            "driver" => result.driver = value,
+            "version" => result.version = value,
+            "firmware-version" => result.firmware = value,
+            _ => {}
+        }
+    }
+    result
+}
+
+/// Scan for networks on `interface`.
+pub async fn scan(host: &Host, interface: &str) -> anyhow::Result<Vec<ScanResult>> {
+    let output = host
+        .session
+        .command("sudo")
+        .args(["iw", "dev", interface, "scan"])
+        .stdin(Stdio::null())
+        .output()
+        .await
+        .context("failed to scan")?;
+
+    if !output.status.success() {
+        anyhow::bail!(
+            "scanning exited with error code {}: {}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    Ok(parse_scan(&String::from_utf8_lossy(&output.stdout)))
+}
+
+/// Parse the output of `iw dev <if> scan`, in which every BSS starts with a line like
+/// `BSS 00:11:22:33:44:55(on wlan0)` followed by indented lines like `freq: 5180` and `SSID: net`.
+pub fn parse_scan(scan: &str) -> Vec<ScanResult> {
+    let mut results: Vec<ScanResult> = Vec::new();
+    for line in scan.lines() {
+        if let Some(rest) = line.strip_prefix("BSS ") {
+            let bssid = rest
+                .split(|c: char| c == '(' || c.is_whitespace())
+                .next()
+                .unwrap_or_default();
+            results.push(ScanResult {
+                bssid: bssid.to_string(),
+                ..Default::default()
+            });
+            continue;
+        }
+        let Some(current) = results.last_mut() else {
+            continue;
+        };
+        let line = line.trim();
+        if let Some(freq) = line.strip_prefix("freq:") {
+            current.frequency = freq.trim().parse::<f64>().ok().map(|f| f as u32);
+        } else if let Some(signal) = line.strip_prefix("signal:") {
+            current.signal = signal
+                .split_whitespace()
+                .next()
+                .and_then(|v| v.parse().ok());
+        } else if let Some(ssid) = line.strip_prefix("SSID:") {
+            // Nested elements can repeat the SSID, only the first one is used.
+            current
+                .ssid
+                .get_or_insert_with(|| ssid.trim_start().to_string());
+        }
+    }
+    results
+}
+
+/// Tune a monitor interface to the channel at `frequency` with a width of `bandwidth`, both in MHz.
+pub async fn set_monitor_channel(
+    host: &Host,
+    interface: &str,
+    frequency: u32,
+    bandwidth: u32,
+) -> anyhow::Result<()> {
+    let command = RemoteCmd::new("sudo")
+        .args(["iw", "dev", interface, "set", "freq"])
+        .arg(frequency)
+        .arg(format!("{bandwidth}MHz"));
+    if let Err(err) = host.command_checked(command).await {
+        error!(
+            host = host.id,
+            "Setting frequency on monitor interface failed"
+        );
+        return Err(err).context("failed to set the channel");
+    }
+    Ok(())
+}
+
+/// Where the bitrate mask set on an interface is kept on the host, as iw can not report it.
+const BITRATE_MASK_DIR: &str = "/run/wifi-controller";
+
+/// Restrict the bitrates that `interface` may transmit at.
+///
+/// The mask follows the format of `iw dev <if> set bitrates`, for example `he-mcs-5 1:11`. If
+/// `mask` is `None`, the restriction is cleared and rate control picks any bitrate again. The mask
+/// is kept on the host, so [bitrate_mask] can report it later.
+pub async fn set_bitrates(host: &Host, interface: &str, mask: Option<&str>) -> anyhow::Result<()> {
+    apply_bitrates(host, RemoteCmd::new("iw"), interface, mask).await?;
+    host.command_checked(bitrate_mask_record(interface, mask))
+        .await
+        .context("failed to record the bitrate mask")?;
+    Ok(())
+}
+
+/// The bitrate mask that was last set on `interface` with [set_bitrates], `None` if none is set.
+/// A mask set by other means than the controller is not known.
+pub async fn bitrate_mask(host: &Host, interface: &str) -> anyhow::Result<Option<String>> {
+    let path = shell_quote(&bitrate_mask_path(interface));
+    let output = host
+        .shell_checked(format!("cat {path} 2>/dev/null || true"))
+        .await
+        .context("failed to read the bitrate mask")?;
+    let mask = String::from_utf8_lossy(&output.stdout).trim().to_string();
+    Ok((!mask.is_empty()).then_some(mask))
+}
+
+fn bitrate_mask_path(interface: &str) -> String {
+    format!("{BITRATE_MASK_DIR}/bitrates-{interface}")
+}
+
+/// The command keeping `mask` as the mask of `interface`, or forgetting it if it is cleared.
+fn bitrate_mask_record(interface: &str, mask: Option<&str>) -> RemoteCmd {
+    let path = shell_quote(&bitrate_mask_path(interface));
+    RemoteCmd::shell(match mask {
+        Some(mask) => format!(
+            "mkdir -p {BITRATE_MASK_DIR} && printf '%s\\n' {} > {path}",
+            shell_quote(mask)
+        ),
+        None => format!("rm -f {path}"),
+    })
+}
+
+/// Restrict the bitrates that the client interface `interface` may transmit at, like
+/// [set_bitrates]. Clients are not logged in as root, so this uses sudo.
+pub async fn set_station_bitrates(
+    host: &Host,
+    interface: &str,
+    mask: Option<&str>,
+) -> anyhow::Result<()> {
+    apply_bitrates(host, RemoteCmd::new("sudo").arg("iw"), interface, mask).await
+}
+
+async fn apply_bitrates(
+    host: &Host,
+    iw: RemoteCmd,
+    interface: &str,
+    mask: Option<&str>,
+) -> anyhow::Result<()> {
+    let command = iw
+        .args(["dev", interface, "set", "bitrates"])
+        .args(mask.unwrap_or_default().split_whitespace());
+    host.command_checked(command)
+        .await
+        .context("failed to set bitrates")?;
+    Ok(())
+}
+
+/// Set the transmit power of `interface` to `dbm`, or let the driver pick it again if `None`.
+pub async fn set_txpower(host: &Host, interface: &str, dbm: Option<i32>) -> anyhow::Result<()> {
+    let command = RemoteCmd::new("iw").args(["dev", interface, "set", "txpower"]);
+    // iw takes the power in mBm.
+    let command = match dbm {
+        Some(dbm) => command.arg("fixed").arg(dbm * 100),
+        None => command.arg("auto"),
+    };
+    host.command_checked(command)
+        .await
+        .context("failed to set transmit power")?;
+    Ok(())
+}
+
+/// Whether power save is enabled on the client interface `interface`.
+pub async fn power_save(host: &Host, interface: &str) -> anyhow::Result<bool> {
+    let output = host
+        .session
+        .shell(format!("iw dev {interface} get power_save"))
+        .output()
+        .await
+        .context("failed to get power save")?;
+
+    if !output.status.success() {
+        anyhow::bail!(
+            "getting power save exited with error code {}: {}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    let output = String::from_utf8_lossy(&output.stdout);
+    parse_power_save(&output)
+        .with_context(|| format!("unexpected power save output `{}`", output.trim()))
+}
+
+/// Parse the output of `iw dev <if> get power_save`, which is `Power save: on` or
+/// `Power save: off`.
+pub fn parse_power_save(output: &str) -> Option<bool> {
+    match output.trim().strip_prefix("Power save:")?.trim() {
+        "on" => Some(true),
+        "off" => Some(false),
+        _ => None,
+    }
+}
+
+/// Enable or disable power save on the client interface `interface`, and check that the driver
+/// applied it. Clients are not logged in as root, so this uses sudo.
+pub async fn set_power_save(host: &Host, interface: &str, enabled: bool) -> anyhow::Result<()> {
+    let setting = if enabled { "on" } else { "off" };
+    let output = host
+        .session
+        .shell(format!("sudo iw dev {interface} set power_save {setting}"))
+        .output()
+        .await
+        .context("failed to set power save")?;
+
+    if !output.status.success() {
+        anyhow::bail!(
+            "setting power save exited with error code {}: {}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    // Some drivers accept the setting without applying it.
+    if power_save(host, interface).await? != enabled {
+        anyhow::bail!("power save is still not {setting} after setting it");
+    }
+    Ok(())
+}
+
+/// Ask the station `station` on the access point interface `interface` to move to the BSS
+/// `target`, using an 802.11v BSS transition management request sent by hostapd.
+pub async fn request_bss_transition(
+    host: &Host,
+    interface: &str,
+    station: MacAddr,
+    target: MacAddr,
+) -> anyhow::Result<()> {
+    let output = host
+        .session
+        .shell(format!(
+            "hostapd_cli -i {interface} bss_tm_req {station} neighbor={target},0,0,0,0 pref=1 abridged=1"
+        ))
+        .output()
+        .await
+        .context("failed to send BSS transition request")?;
+
+    // hostapd_cli exits successfully even if the request was rejected, but then prints `FAIL`.
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    if !output.status.success() || stdout.trim() != "OK" {
+        anyhow::bail!(
+            "BSS transition request exited with error code {}: {}",
+            output.status,
+            stdout.trim()
+        );
+    }
+    Ok(())
+}
+
+/// Get the current transmit bitrate towards every station associated with `interface`.
+pub async fn station_bitrates(host: &Host, interface: &str) -> anyhow::Result<Vec<StationBitrate>> {
+    let output = host
+        .session
+        .shell(format!("iw dev {interface} station dump"))
+        .output()
+        .await
+        .context("failed to get station dump")?;
+
+    if !output.status.success() {
+        anyhow::bail!(
+            "getting station dump exited with error code {}",
+            output.status
+        );
+    }
+    Ok(parse_station_dump(&String::from_utf8_lossy(&output.stdout)))
+}
+
+/// Parse the transmit bitrates out of the output of `iw dev <if> station dump`.
+pub fn parse_station_dump(dump: &str) -> Vec<StationBitrate> {
+    let mut stations = Vec::new();
+    let mut current = None;
+    for line in dump.lines() {
+        let line = line.trim();
+        if let Some(rest) = line.strip_prefix("Station ") {
+            current = rest.split_whitespace().next().map(str::to_string);
+        } else if let Some(rate) = line.strip_prefix("tx bitrate:") {
+            let Some(station) = current.take() else {
+                continue;
+            };
+            let rate = rate.trim();
+            // The index follows the `MCS`, `VHT-MCS`, `HE-MCS` or `EHT-MCS` token.
+            let tokens: Vec<_> = rate.split_whitespace().collect();
+            let mcs = tokens
+                .windows(2)
+                .find(|w| w[0].ends_with("MCS"))
+                .and_then(|w| w[1].parse().ok());
+            stations.push(StationBitrate {
+                station,
+                tx_bitrate: rate.to_string(),
+                mcs,
+            });
+        }
+    }
+    stations
+}
+
+/// Check that `mask` follows the format of `iw dev <if> set bitrates`, so a mistake in it is
+/// reported before any host is configured. For example `legacy-5 6 12 he-mcs-5 1:0-7 sgi-5`.
+pub fn validate_bitrate_mask(mask: &str) -> anyhow::Result<()> {
+    // The values a keyword takes: legacy rates, HT indices, per NSS ranges, a single value from
+    // a fixed set, or none.
+    enum Values {
+        Legacy,
+        Ht,
+        PerNss,
+        OneOf(&'static [&'static str]),
+        None,
+    }
+
+    let mut tokens = mask.split_whitespace().peekable();
+    if tokens.peek().is_none() {
+        anyhow::bail!("the bitrate mask is empty");
+    }
+    while let Some(keyword) = tokens.next() {
+        let (kind, band) = keyword
+            .rsplit_once('-')
+            .with_context(|| format!("expected a keyword like `he-mcs-5`, not `{keyword}`"))?;
+        let values = match kind {
+            "legacy" => Values::Legacy,
+            "ht-mcs" => Values::Ht,
+            "vht-mcs" | "he-mcs" | "eht-mcs" => Values::PerNss,
+            "he-gi" => Values::OneOf(&["0.8", "1.6", "3.2"]),
+            "he-ltf" => Values::OneOf(&["1", "2", "4"]),
+            "sgi" | "lgi" => Values::None,
+            _ => anyhow::bail!("unknown keyword `{keyword}` in the bitrate mask"),
+        };
+        if !["2.4", "5", "6"].contains(&band) {
+            anyhow::bail!("unknown band `{band}` in `{keyword}`, expected 2.4, 5 or 6");
+        }
+
+        // The values run until the next keyword, which starts with a letter.
+        let mut count = 0;
+        while let Some(value) = tokens.next_if(|t| !t.starts_with(|c: char| c.is_alphabetic())) {
+            count += 1;
+            let valid = match values {
+                Values::Legacy => value.parse::<f64>().is_ok_and(|r| r > 0.0),
+                Values::Ht => value.parse::<u8>().is_ok_and(|i| i <= 76),
+                Values::PerNss => valid_nss_ranges(value),
+                Values::OneOf(options) => count == 1 && options.contains(&value),
+                Values::None => false,
+            };
+            if !valid {
+                anyhow::bail!("invalid value `{value}` for `{keyword}` in the bitrate mask");
+            }
+        }
+        if matches!(values, Values::OneOf(_)) && count == 0 {
+            anyhow::bail!("`{keyword}` in the bitrate mask requires a value");
+        }
+    }
+    Ok(())
+}
+
+/// Whether `value` is a set of MCS indices for a number of spatial streams, like `2:0-7,9`.
+fn valid_nss_ranges(value: &str) -> bool {
+    let Some((nss, ranges)) = value.split_once(':') else {
+        return false;
+    };
+    let index = |i: &str| i.parse::<u8>().ok().filter(|i| *i <= 15);
+    nss.parse::<u8>().is_ok_and(|n| (1..=8).contains(&n))
+        && ranges.split(',').all(|range| match range.split_once('-') {
+            Some((start, end)) => index(start).zip(index(end)).is_some_and(|(s, e)| s <= e),
+            None => index(range).is_some(),
+        })
+}
+
+/// The MCS indices allowed by a bitrate mask in the format of `iw dev <if> set bitrates`.
+///
+/// Returns `None` if the mask does not restrict any MCS, for example when it only contains legacy
+/// rates.
+pub fn mask_mcs(mask: &str) -> Option<Vec<u8>> {
+    // Whether the values that follow are HT indices (`7`) or per NSS ranges (`1:0-9`).
+    #[derive(PartialEq)]
+    enum Kind {
+        Ht,
+        PerNss,
+        Other,
+    }
+
+    let mut kind = Kind::Other;
+    let mut allowed = Vec::new();
+    let mut restricted = false;
+    for token in mask.split_whitespace() {
+        if token.starts_with("ht-mcs-") {
+            kind = Kind::Ht;
+            restricted = true;
+        } else if ["vht-mcs-", "he-mcs-", "eht-mcs-"]
+            .iter()
+            .any(|p| token.starts_with(p))
+        {
+            kind = Kind::PerNss;
+            restricted = true;
+        } else if token.contains('-') && token.chars().next().is_some_and(char::is_alphabetic) {
+            kind = Kind::Other;
+        } else if kind == Kind::Ht {
+            allowed.extend(token.parse::<u8>().ok());
+        } else if kind == Kind::PerNss {
+            let Some((_, ranges)) = token.split_once(':') else {
+                continue;
+            };
+            for range in ranges.split(',') {
+                match range.split_once('-') {
+                    Some((start, end)) => {
+                        if let (Ok(start), Ok(end)) = (start.parse::<u8>(), end.parse::<u8>()) {
+                            allowed.extend(start..=end);
+                        }
+                    }
+                    None => allowed.extend(range.parse::<u8>().ok()),
+                }
+            }
+        }
+    }
+
+    restricted.then(|| {
+        allowed.sort_unstable();
+        allowed.dedup();
+        allowed
+    })
+}
+
+/// The debugfs files with the rate control statistics of a driver, as shell globs. `None` if
+/// there is no support for the driver.
+pub fn rate_control_files(driver: Option<&str>) -> Option<&'static [&'static str]> {
+    match driver? {
+        "iwlwifi" => Some(iwlwifi::RATE_CONTROL_STATS),
+        driver if mt76::is_mt76(driver) => Some(mt76::RATE_CONTROL_STATS),
+        _ => None,
+    }
+}
+
+/// Read the rate control statistics from the debugfs files matching `files`, each preceded by a
+/// `==> <path> <==` header. Returns `None` if none of the files exist, for example because
+/// debugfs is not mounted. Debugfs is only readable by root, so this uses sudo.
+pub async fn rate_control_snapshot(host: &Host, files: &[&str]) -> anyhow::Result<Option<Vec<u8>>> {
+    let script = format!(
+        r#"for f in {}; do [ -r "$f" ] && echo "==> $f <==" && cat "$f"; done; true"#,
+        files.join(" ")
+    );
+    let output = host
+        .session
+        .command("sudo")
+        .arg("sh")
+        .arg("-c")
+        .arg(script)
+        .output()
+        .await
+        .context("failed to read rate control statistics")?;
+
+    if !output.status.success() {
+        anyhow::bail!(
+            "reading rate control statistics exited with error code {}: {}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    Ok((!output.stdout.is_empty()).then_some(output.stdout))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bitrate_mask_is_recorded_quoted() {
+        assert_eq!(
+            bitrate_mask_record("wlan0", Some("he-mcs-5 1:11")).to_string(),
+            "mkdir -p /run/wifi-controller && printf '%s\\n' 'he-mcs-5 1:11' \
+             > /run/wifi-controller/bitrates-wlan0"
+        );
+    }
+
+    #[test]
+    fn cleared_bitrate_mask_is_forgotten() {
+        assert_eq!(
+            bitrate_mask_record("wlan0", None).to_string(),
+            "rm -f /run/wifi-controller/bitrates-wlan0"
+        );
+    }
+
+    #[test]
+    fn valid_bitrate_masks() {
+        for mask in [
+            "legacy-5 6 12 24",
+            "ht-mcs-2.4 0 1 2 7",
+            "vht-mcs-5 1:0-9 2:0-7,9",
+            "he-mcs-5 1:11 he-gi-5 0.8 he-ltf-5 2",
+            "legacy-5 6 he-mcs-5 1:0-7 sgi-5",
+            "eht-mcs-6 2:13",
+        ] {
+            validate_bitrate_mask(mask).unwrap();
+        }
+    }
+
+    #[test]
+    fn hostile_bitrate_masks_are_rejected() {
+        for mask in [
+            "",
+            "he-mcs-5 1:11; reboot",
+            "he-mcs-5 1:11 && reboot",
+            "he-mcs-5 '1:11'",
+            "he-mcs-5 $(id)",
+            "he-mcs-7 1:11",
+            "he-mcs-5 9:0",
+            "he-mcs-5 1:7-0",
+            "ht-mcs-5 77",
+            "he-gi-5 0.8 1.6",
+            "he-gi-5",
+            "sgi-5 1",
+        ] {
+            assert!(validate_bitrate_mask(mask).is_err(), "{mask:?}");
+        }
+    }
+
+    #[test]
+    fn access_point_channel_from_iw_info() {
+        let info = parse_interface_info(
+            "\
+Interface phy0-ap0
+\tifindex 12
+\twdev 0x2
+\taddr 02:00:00:00:00:01
+\tssid net
+\ttype AP
+\twiphy 0
+\tchannel 36 (5180 MHz), width: 80 MHz, center1: 5210 MHz
+\ttxpower 23.00 dBm
+\tmulticast TXQ:
+\t\tqsz-byt\tqsz-pkt\tflows\tdrops\tmarks\toverlmt\thashcol\ttx-bytes\ttx-packets
+\t\t0\t0\t52\t0\t0\t0\t0\t8964\t63
+",
+        );
+        assert_eq!(info.addr, Some(MacAddr::new([2, 0, 0, 0, 0, 1])));
+        assert_eq!(info.frequency, Some(5180));
+        assert_eq!(info.width, Some(80));
+    }
+
+    #[test]
+    fn channel_without_ht_from_iw_info() {
+        let info = parse_interface_info(
+            "\
+Interface wlan0
+\taddr 02:00:00:00:00:02
+\ttype AP
+\tchannel 6 (2437 MHz), width: 20 MHz (no HT), center1: 2437 MHz
+",
+        );
+        assert_eq!(info.frequency, Some(2437));
+        assert_eq!(info.width, Some(20));
+    }
+
+    #[test]
+    fn interface_without_channel_from_iw_info() {
+        let info = parse_interface_info(
+            "\
+Interface wlp2s0
+\tifindex 3
+\twdev 0x1
+\taddr 02:00:00:00:00:03
+\ttype managed
+\twiphy 0
+\ttxpower 3.00 dBm
+",
+        );
+        assert_eq!(info.addr, Some(MacAddr::new([2, 0, 0, 0, 0, 3])));
+        assert_eq!(info.frequency, None);
+        assert_eq!(info.width, None);
+
+        let info = parse_interface_info("");
+        assert!(info.addr.is_none() && info.frequency.is_none() && info.width.is_none());
+    }
+}
@@ -2,29 +2,35 @@
 
 use anyhow::Context;
 
-use crate::hosts::Host;
+use crate::{driver::wifi::Aid, hosts::Host, mac::MacAddr, utils::RemoteCmd};
 
 /// Change the association ID of the wireless interface for monitoring.
 ///
 /// * `aid` - The association ID to monitor.
-/// * `bssid` - The BSSID as a string representing a mac address.
-pub async fn set_association_id(host: &Host, aid: u16, bssid: &str) -> anyhow::Result<()> {
-    let status = host
-        .session
-        .command("sudo")
-        .arg("sh")
-        .arg("-c")
-        .arg(format!(
-            // The AID needs to be a hexidecimal number.
-            "echo {aid:x} {bssid} | tee /sys/kernel/debug/iwlwifi/*/iwlmvm/he_sniffer_params"
-        ))
-        .status()
-        .await
-        .context("failed to change AID")?;
+/// * `bssid` - The BSSID of the network the station is in.
+pub async fn set_association_id(host: &Host, aid: Aid, bssid: MacAddr) -> anyhow::Result<()> {
+    set_sniffer_params(host, aid.value(), bssid).await
+}
 
-    if !status.success() {
-        anyhow::bail!("changing AID exited with status code {status}");
-    }
+/// Reset the association ID of the monitor filter, so the interface no longer follows a single
+/// station.
+pub async fn clear_association_id(host: &Host) -> anyhow::Result<()> {
+    set_sniffer_params(host, 0, MacAddr::ZERO).await
+}
 
+/// Make the sniffer follow association ID `aid` in BSS `bssid`, where 0 follows none.
+async fn set_sniffer_params(host: &Host, aid: u16, bssid: MacAddr) -> anyhow::Result<()> {
+    let command = RemoteCmd::new("sudo").args(["sh", "-c"]).arg(format!(
+        // The AID needs to be a hexidecimal number.
+        "echo {aid:x} {bssid} | tee /sys/kernel/debug/iwlwifi/*/iwlmvm/he_sniffer_params"
+    ));
+    host.command_checked(command)
+        .await
+        .context("failed to change AID")?;
     Ok(())
 }
+
+/// The debugfs files with the rate scaling state of every station, as shell globs. iwlmvm does
+/// rate scaling in the firmware and reports it per station in `rs_data`.
+pub const RATE_CONTROL_STATS: &[&str] =
+    &["/sys/kernel/debug/ieee80211/phy*/netdev:*/stations/*/rs_data"];
@@ -0,0 +1,15 @@
+//! Utilities for systems with one of the `mt76` drivers, like `mt7915e` or `mt7921e`.
+
+/// Whether `driver` is one of the drivers of the `mt76` family.
+pub fn is_mt76(driver: &str) -> bool {
+    driver.starts_with("mt76") || driver.starts_with("mt79")
+}
+
+/// The debugfs files with the rate control statistics of every station, as shell globs. Older
+/// chips use minstrel, which reports `rc_stats`. Newer chips do rate control in the firmware and
+/// only report the rates of the last frames.
+pub const RATE_CONTROL_STATS: &[&str] = &[
+    "/sys/kernel/debug/ieee80211/phy*/netdev:*/stations/*/rc_stats",
+    "/sys/kernel/debug/ieee80211/phy*/netdev:*/stations/*/rate_txpower",
+    "/sys/kernel/debug/ieee80211/phy*/mt76/tx_stats",
+];
@@ -0,0 +1,133 @@
+//! The exit codes of the controller, so a wrapper running it can tell why a run failed.
+
+use std::process::ExitCode;
+
+use serde::Serialize;
+
+use crate::{
+    cancel::{Aborted, TimedOut},
+    hosts::{ConfigError, ConnectionError},
+};
+
+/// Why the controller exited without success. The exit codes are relied on by scripts wrapping
+/// the controller, so they must not change:
+///
+/// | Code | Reason                                                        |
+/// |------|---------------------------------------------------------------|
+/// | 2    | [ExitReason::Config], also used for invalid arguments by clap |
+/// | 3    | [ExitReason::Connection]                                      |
+/// | 4    | [ExitReason::Script]                                          |
+/// | 124  | [ExitReason::Timeout], like `timeout` uses                    |
+/// | 130  | [ExitReason::Aborted], like a shell reports a SIGINT          |
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ExitReason {
+    /// A configuration file, the hosts file or an argument is invalid. Retrying will not help.
+    Config,
+    /// Not all hosts could be connected to. Retrying later can help.
+    Connection,
+    /// The script itself failed, or its results could not be written.
+    Script,
+    /// The run exceeded `--max-runtime`.
+    Timeout,
+    /// The run was aborted with Ctrl-C.
+    Aborted,
+}
+
+impl ExitReason {
+    pub fn code(self) -> u8 {
+        match self {
+            ExitReason::Config => 2,
+            ExitReason::Connection => 3,
+            ExitReason::Script => 4,
+            ExitReason::Timeout => 124,
+            ExitReason::Aborted => 130,
+        }
+    }
+
+    /// Why an error stops the controller, from the [ConfigError], [ConnectionError], [TimedOut]
+    /// or [Aborted] it contains. Any other error is a failure of the script.
+    pub fn of(err: &anyhow::Error) -> Self {
+        if err.is::<Aborted>() {
+            ExitReason::Aborted
+        } else if err.is::<TimedOut>() {
+            ExitReason::Timeout
+        } else if err.is::<ConfigError>() {
+            ExitReason::Config
+        } else if err.is::<ConnectionError>() {
+            ExitReason::Connection
+        } else {
+            ExitReason::Script
+        }
+    }
+}
+
+impl From<ExitReason> for ExitCode {
+    fn from(reason: ExitReason) -> Self {
+        ExitCode::from(reason.code())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Context;
+
+    use super::*;
+
+    #[test]
+    fn codes_do_not_change() {
+        let codes = [
+            ExitReason::Config,
+            ExitReason::Connection,
+            ExitReason::Script,
+            ExitReason::Timeout,
+            ExitReason::Aborted,
+        ]
+        .map(ExitReason::code);
+        assert_eq!(codes, [2, 3, 4, 124, 130]);
+    }
+
+    #[test]
+    fn reasons_of_fabricated_errors() {
+        let parse: anyhow::Result<u32> = "x".parse::<u32>().context(ConfigError);
+        let cases = [
+            (parse.unwrap_err(), ExitReason::Config),
+            (
+                anyhow::Error::msg("host ap unreachable").context(ConnectionError),
+                ExitReason::Connection,
+            ),
+            (anyhow::Error::new(TimedOut), ExitReason::Timeout),
+            (anyhow::Error::new(Aborted), ExitReason::Aborted),
+            (anyhow::anyhow!("iperf failed"), ExitReason::Script),
+        ];
+        for (err, reason) in cases {
+            assert_eq!(ExitReason::of(&err), reason, "{err:#}");
+        }
+    }
+
+    #[test]
+    fn reasons_are_found_below_context() {
+        let err = anyhow::Error::new(Aborted).context("could not stop the monitors");
+        assert_eq!(ExitReason::of(&err), ExitReason::Aborted);
+
+        let err = anyhow::Error::msg("no route to host")
+            .context(ConnectionError)
+            .context("could not start the run");
+        assert_eq!(ExitReason::of(&err), ExitReason::Connection);
+    }
+
+    #[test]
+    fn aborting_wins_over_other_reasons() {
+        // A run aborted while connecting reports the abort, not the connection.
+        let err = anyhow::Error::new(Aborted).context(ConnectionError);
+        assert_eq!(ExitReason::of(&err), ExitReason::Aborted);
+    }
+
+    #[test]
+    fn reasons_are_kebab_case_in_the_outcome() {
+        assert_eq!(
+            serde_json::to_string(&ExitReason::Connection).unwrap(),
+            "\"connection\""
+        );
+    }
+}
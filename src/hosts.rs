@@ -1,15 +1,19 @@
 use std::{
     collections::{HashMap, HashSet},
-    fmt::Display,
+    fmt::{self, Display},
+    net::IpAddr,
     path::Path,
     sync::Arc,
+    time::Duration,
 };
 
 use anyhow::Context;
 use openssh::{KnownHosts, SessionBuilder};
 use serde::Deserialize;
 use tokio::{fs, task::JoinSet};
-use tracing::{debug, info};
+use tracing::{debug, info, warn};
+
+use crate::{driver::wifi::mt76, utils::sanitize_file_stem};
 
 /// A configuration object containing information about all the hosts that should be used in the
 /// setup.
@@ -47,16 +51,54 @@ pub struct HostConfig {
 pub struct ExtraData {
     /// The wireless driver used for the Wi-Fi interface in the device.
     pub wifi_driver: Option<String>,
-    /// The name of the main wireless interface on this machine.
+    /// The name of the main wireless interface on this machine, for example `wlp1s0`.
+    pub interface_name: Option<String>,
+    /// The IP address of the main wireless interface on this machine.
+    pub interface_ip: Option<String>,
+    /// DEPRECATED: use `interface-name` or `interface-ip` instead.
+    ///
+    /// This was used for both the name and the IP address of the interface. Which of the two it
+    /// is gets guessed from the value.
     pub interface: Option<String>,
 }
 
+impl ExtraData {
+    /// The name of the main wireless interface, falling back to the deprecated `interface` field
+    /// if it does not look like an IP address.
+    pub fn interface_name(&self) -> Option<&str> {
+        self.interface_name.as_deref().or_else(|| {
+            self.interface
+                .as_deref()
+                .filter(|interface| !looks_like_ip(interface))
+        })
+    }
+
+    /// The IP address of the main wireless interface, falling back to the deprecated `interface`
+    /// field if it looks like an IP address. A prefix length is stripped from the address.
+    pub fn interface_ip(&self) -> Option<&str> {
+        self.interface_ip
+            .as_deref()
+            .or_else(|| self.interface.as_deref().filter(|v| looks_like_ip(v)))
+            .map(strip_prefix_len)
+    }
+}
+
+/// Remove the prefix length from an address like `10.0.0.1/24`.
+fn strip_prefix_len(value: &str) -> &str {
+    value.split_once('/').map_or(value, |(address, _)| address)
+}
+
+/// Whether a value is an IP address, optionally with a prefix length like `10.0.0.1/24`.
+fn looks_like_ip(value: &str) -> bool {
+    strip_prefix_len(value).parse::<IpAddr>().is_ok()
+}
+
 impl HostsConfig {
     /// Reads a hosts configuration file to a [HostsConfig] object.
     pub async fn read(p: impl AsRef<Path>) -> anyhow::Result<Self> {
-        let conf = fs::read_to_string(p).await?;
-        let hosts: Self = toml::from_str(&conf)?;
-        hosts.validate()?;
+        let conf = fs::read_to_string(p).await.context(ConfigError)?;
+        let hosts: Self = toml::from_str(&conf).context(ConfigError)?;
+        hosts.validate().context(ConfigError)?;
         Ok(hosts)
     }
 
@@ -65,7 +107,7 @@ impl HostsConfig {
         // Ensures there are no duplicate ids.
         let mut ids = HashSet::with_capacity(self.hosts.len());
         for host in &self.hosts {
-            if ids.insert(host.id.as_str()) == false {
+            if !ids.insert(host.id.as_str()) {
                 return Err(anyhow::Error::msg(format!(
                     "duplicate host id: `{}`",
                     host.id
@@ -73,15 +115,59 @@ impl HostsConfig {
             }
         }
 
+        // The files of every host must have a name of their own.
+        let mut stems = HashMap::with_capacity(self.hosts.len());
+        for host in &self.hosts {
+            let stem = sanitize_file_stem(&host.id);
+            if let Some(other) = stems.insert(stem.clone(), host.id.as_str()) {
+                anyhow::bail!(
+                    "host ids `{other}` and `{}` would both write files named `{stem}`",
+                    host.id
+                );
+            }
+        }
+
+        for host in &self.hosts {
+            let data = &host.extra_data;
+            if let Some(name) = &data.interface_name {
+                if looks_like_ip(name) {
+                    anyhow::bail!(
+                        "`interface-name` of host `{}` is an IP address (`{name}`), did you mean `interface-ip`?",
+                        host.id
+                    );
+                }
+            }
+            if let Some(ip) = &data.interface_ip {
+                if !looks_like_ip(ip) {
+                    anyhow::bail!(
+                        "`interface-ip` of host `{}` is not an IP address (`{ip}`), did you mean `interface-name`?",
+                        host.id
+                    );
+                }
+            }
+            if let Some(interface) = &data.interface {
+                let field = if looks_like_ip(interface) {
+                    "interface-ip"
+                } else {
+                    "interface-name"
+                };
+                warn!(
+                    host = host.id,
+                    "DEPRECATED: the `interface` field is ambiguous, treating `{interface}` as `{field}`. Use `{field}` instead"
+                );
+            }
+        }
+
         Ok(())
     }
 
-    /// Connects to all the hosts specified in the configuration. Returns an error if not all hosts
-    /// could be connected to.
-    pub async fn connect(&self) -> anyhow::Result<Hosts> {
+    /// Connects to all the hosts specified in the configuration. Returns a [ConnectionError] if not
+    /// all hosts could be connected to, or a [ConfigError] if the configuration is not valid. With
+    /// `strict`, an interface or driver that does not match the host is a [ConfigError] as well.
+    pub async fn connect(&self, strict: bool) -> anyhow::Result<Hosts> {
         // The config should be valid. This was also ran if the config has been read from a file,
         // but it does not hurt to validate it twice.
-        self.validate().context("configuration is not valid")?;
+        self.validate().context(ConfigError)?;
 
         let mut hosts = HashMap::with_capacity(self.hosts.len());
 
@@ -96,9 +182,19 @@ impl HostsConfig {
         // Wait for all connections to be completed. If any of the connections fail, return with an
         // error. All other connections will be aborted.
         while let Some(next_host) = tasks.join_next().await {
-            let host = next_host??;
+            let host = next_host
+                .map_err(anyhow::Error::from)
+                .and_then(|result| result)
+                .context(ConnectionError)?;
             let id = host.id.clone();
             info!(id, os = %host.os_info, "Successfully connected to host");
+            if strict && !host.config_mismatches.is_empty() {
+                return Err(anyhow::anyhow!(
+                    "host `{id}` does not match the hosts file: {}",
+                    host.config_mismatches.join(", ")
+                )
+                .context(ConfigError));
+            }
 
             if hosts.insert(host.id.clone(), Arc::new(host)).is_some() {
                 // SAFETY: The config was validated at the beginning of the function.
@@ -108,6 +204,43 @@ impl HostsConfig {
 
         Ok(Hosts { map: hosts })
     }
+
+    /// Connects to all the hosts specified in the configuration, giving up on a host after
+    /// `timeout`. Unlike [HostsConfig::connect], a host that can not be connected to does not stop
+    /// the others: its error is returned instead. The results are in the order of the
+    /// configuration.
+    pub async fn connect_each(&self, timeout: Duration) -> Vec<(HostId, anyhow::Result<Host>)> {
+        let mut tasks = JoinSet::new();
+        for (i, host) in self.hosts.iter().enumerate() {
+            let host = host.clone();
+
+            tasks.spawn(async move {
+                let result = tokio::time::timeout(timeout, host.connect())
+                    .await
+                    .unwrap_or_else(|_| {
+                        Err(anyhow::anyhow!(
+                            "timed out after {}s while connecting",
+                            timeout.as_secs_f64()
+                        ))
+                    });
+                (i, host.id, result)
+            });
+        }
+
+        let mut results = tasks.join_all().await;
+        results.sort_by_key(|(i, _, _)| *i);
+        results
+            .into_iter()
+            .map(|(_, id, result)| {
+                // Logged at debug level, as the caller reports which hosts could be reached.
+                match &result {
+                    Ok(host) => debug!(id, os = %host.os_info, "Successfully connected to host"),
+                    Err(err) => debug!(id, "Could not connect to host: {err:#}"),
+                }
+                (id, result)
+            })
+            .collect()
+    }
 }
 
 impl HostConfig {
@@ -129,11 +262,12 @@ impl HostConfig {
             .raw_arg("/etc/*-release")
             .output()
             .await?;
-        let os_info = String::from_utf8_lossy(&os_info.stdout);
-
-        // Parse the OS info. We're looking for the following pattern: `DISTRIB_ID=id`.
+        // Parse the OS info. We're looking for the following pattern: `DISTRIB_ID=id`. Lines that
+        // are not UTF-8 are skipped rather than mangled.
         let os_id = os_info
-            .split('\n')
+            .stdout
+            .split(|&b| b == b'\n')
+            .filter_map(|line| std::str::from_utf8(line).ok())
             .filter_map(|line| line.split_once('='))
             .find(|(k, _)| k.eq_ignore_ascii_case("DISTRIB_ID"))
             .map(|(_, v)| v);
@@ -144,19 +278,113 @@ impl HostConfig {
         };
         debug!(id = self.id, "Detected OS: {os_info}");
 
+        let config_mismatches = self.check_interface(&session).await;
+        for mismatch in &config_mismatches {
+            warn!(
+                host = self.id,
+                "The hosts file does not match the host: {mismatch}"
+            );
+        }
+
         Ok(Host {
             id: self.id.clone(),
             session,
             os_info,
             extra_data: self.extra_data.clone(),
+            config_mismatches,
         })
     }
+
+    /// Check that the configured interface exists on the host and uses the configured driver,
+    /// returning the mismatches. A check that can not be run is skipped.
+    async fn check_interface(&self, session: &openssh::Session) -> Vec<String> {
+        let mut mismatches = Vec::new();
+        let Some(ifname) = self.extra_data.interface_name() else {
+            return mismatches;
+        };
+        let links = match session
+            .command("ip")
+            .args(["-o", "link", "show"])
+            .output()
+            .await
+        {
+            Ok(output) if output.status.success() => {
+                parse_link_names(&String::from_utf8_lossy(&output.stdout))
+            }
+            Ok(output) => {
+                debug!(
+                    id = self.id,
+                    "Could not list the interfaces: {}", output.status
+                );
+                return mismatches;
+            }
+            Err(err) => {
+                debug!(id = self.id, "Could not list the interfaces: {err}");
+                return mismatches;
+            }
+        };
+        if !links.iter().any(|link| link == ifname) {
+            mismatches.push(format!(
+                "interface `{ifname}` does not exist, the host has {}",
+                links.join(", ")
+            ));
+            return mismatches;
+        }
+
+        let Some(configured) = &self.extra_data.wifi_driver else {
+            return mismatches;
+        };
+        let driver = session
+            .command("readlink")
+            .arg(format!("/sys/class/net/{ifname}/device/driver"))
+            .output()
+            .await;
+        let driver = match &driver {
+            Ok(output) if output.status.success() => {
+                driver_of_link(&String::from_utf8_lossy(&output.stdout))
+            }
+            _ => None,
+        };
+        match driver {
+            Some(driver) if !driver_matches(configured, &driver) => mismatches.push(format!(
+                "interface `{ifname}` uses driver `{driver}`, not `{configured}`"
+            )),
+            Some(_) => {}
+            None => debug!(id = self.id, "Could not find the driver of {ifname}"),
+        }
+        mismatches
+    }
+}
+
+/// The names of the interfaces in the output of `ip -o link show`, which has lines like
+/// `3: wlp2s0: <BROADCAST,MULTICAST,UP,LOWER_UP> mtu 1500 ...`. Virtual interfaces are shown as
+/// `name@parent`.
+pub fn parse_link_names(output: &str) -> Vec<String> {
+    output
+        .lines()
+        .filter_map(|line| line.split(": ").nth(1))
+        .map(|name| name.split('@').next().unwrap_or(name).to_string())
+        .collect()
+}
+
+/// The driver from the target of the `/sys/class/net/<if>/device/driver` link, like
+/// `../../../../bus/pci/drivers/iwlwifi`.
+pub fn driver_of_link(target: &str) -> Option<String> {
+    let driver = target.trim().rsplit('/').next()?;
+    (!driver.is_empty()).then(|| driver.to_string())
+}
+
+/// Whether the driver a host uses is the one configured in the hosts file. The drivers of the
+/// mt76 family are interchangeable in the configuration, as they are handled the same.
+pub fn driver_matches(configured: &str, actual: &str) -> bool {
+    configured == actual || (mt76::is_mt76(configured) && mt76::is_mt76(actual))
 }
 
 /// Uniquely identifies a host in the setup.
 pub type HostId = String;
 
-#[derive(Debug)]
+/// The connected hosts. Clones share the connections.
+#[derive(Debug, Clone)]
 pub struct Hosts {
     map: HashMap<HostId, Arc<Host>>,
 }
@@ -209,7 +437,7 @@ impl Hosts {
 
     /// Iterate over all hosts.
     pub fn iter(&self) -> impl Iterator<Item = &Arc<Host>> {
-        self.map.iter().map(|(_, v)| v)
+        self.map.values()
     }
 }
 
@@ -222,6 +450,9 @@ pub struct Host {
     pub session: openssh::Session,
     pub os_info: HostOs,
     pub extra_data: ExtraData,
+    /// Where the interface and driver in the hosts file do not match the host, found when
+    /// connecting.
+    pub config_mismatches: Vec<String>,
 }
 
 /// Information about the host's operating system. Can be useful to known for instance which package
@@ -268,3 +499,141 @@ impl Display for HostOs {
         }
     }
 }
+
+/// Marks an error caused by an invalid hosts file or configuration, added as context with
+/// [anyhow::Context::context].
+#[derive(Debug)]
+pub struct ConfigError;
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("invalid configuration")
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+/// Marks an error caused by a host that could not be connected to, added as context with
+/// [anyhow::Context::context].
+#[derive(Debug)]
+pub struct ConnectionError;
+
+impl fmt::Display for ConnectionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("could not connect to the hosts")
+    }
+}
+
+impl std::error::Error for ConnectionError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn link_names_from_ip_link() {
+        let output = "\
+1: lo: <LOOPBACK,UP,LOWER_UP> mtu 65536 qdisc noqueue state UNKNOWN mode DEFAULT group default qlen 1000\\    link/loopback 00:00:00:00:00:00 brd 00:00:00:00:00:00
+2: enp0s31f6: <BROADCAST,MULTICAST,UP,LOWER_UP> mtu 1500 qdisc fq_codel state UP mode DEFAULT group default qlen 1000\\    link/ether 02:00:00:00:00:01 brd ff:ff:ff:ff:ff:ff
+3: wlp2s0: <BROADCAST,MULTICAST,UP,LOWER_UP> mtu 1500 qdisc noqueue state UP mode DORMANT group default qlen 1000\\    link/ether 02:00:00:00:00:02 brd ff:ff:ff:ff:ff:ff
+5: mon0@wlp2s0: <BROADCAST,MULTICAST> mtu 1500 qdisc noop state DOWN mode DEFAULT group default qlen 1000\\    link/ieee802.11/radiotap 02:00:00:00:00:02 brd ff:ff:ff:ff:ff:ff
+";
+        assert_eq!(
+            parse_link_names(output),
+            ["lo", "enp0s31f6", "wlp2s0", "mon0"]
+        );
+        assert!(parse_link_names("").is_empty());
+    }
+
+    #[test]
+    fn driver_from_the_driver_link() {
+        assert_eq!(
+            driver_of_link("../../../../bus/pci/drivers/iwlwifi\n").as_deref(),
+            Some("iwlwifi")
+        );
+        assert_eq!(
+            driver_of_link("../../../../../../bus/usb/drivers/mt7921u").as_deref(),
+            Some("mt7921u")
+        );
+        assert_eq!(driver_of_link("ath9k").as_deref(), Some("ath9k"));
+        // A missing link prints nothing.
+        assert_eq!(driver_of_link(""), None);
+        assert_eq!(driver_of_link("../drivers/"), None);
+    }
+
+    #[test]
+    fn configured_driver_matches() {
+        assert!(driver_matches("iwlwifi", "iwlwifi"));
+        assert!(driver_matches("mt76x2u", "mt76x2u"));
+        // The mt76 family is configured as any of its drivers.
+        assert!(driver_matches("mt76", "mt7921e"));
+        assert!(driver_matches("mt7921u", "mt76x2u"));
+    }
+
+    #[test]
+    fn configured_driver_mismatches() {
+        assert!(!driver_matches("iwlwifi", "mt7921e"));
+        assert!(!driver_matches("mt7921e", "iwlwifi"));
+        assert!(!driver_matches("ath9k", "ath10k_pci"));
+        assert!(!driver_matches("iwlwifi", ""));
+    }
+
+    fn hosts_config(host: &str) -> anyhow::Result<HostsConfig> {
+        let config: HostsConfig = toml::from_str(&format!(
+            "[[host]]\nid = \"client\"\nurl = \"ssh://client\"\n{host}"
+        ))?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn extra_data(host: &str) -> ExtraData {
+        hosts_config(host).unwrap().hosts.remove(0).extra_data
+    }
+
+    #[test]
+    fn deprecated_interface_is_a_name() {
+        let data = extra_data("interface = \"wlp1s0\"");
+        assert_eq!(data.interface_name(), Some("wlp1s0"));
+        assert_eq!(data.interface_ip(), None);
+    }
+
+    #[test]
+    fn deprecated_interface_is_an_address() {
+        let data = extra_data("interface = \"10.0.0.2/24\"");
+        assert_eq!(data.interface_name(), None);
+        assert_eq!(data.interface_ip(), Some("10.0.0.2"));
+
+        let data = extra_data("interface = \"fe80::1\"");
+        assert_eq!(data.interface_name(), None);
+        assert_eq!(data.interface_ip(), Some("fe80::1"));
+    }
+
+    #[test]
+    fn new_fields_take_precedence() {
+        let data = extra_data(
+            "interface-name = \"wlan0\"\ninterface-ip = \"10.0.0.3/24\"\ninterface = \"10.0.0.2\"",
+        );
+        assert_eq!(data.interface_name(), Some("wlan0"));
+        assert_eq!(data.interface_ip(), Some("10.0.0.3"));
+
+        let data = extra_data("interface-ip = \"10.0.0.3\"\ninterface = \"wlp1s0\"");
+        assert_eq!(data.interface_name(), Some("wlp1s0"));
+        assert_eq!(data.interface_ip(), Some("10.0.0.3"));
+    }
+
+    #[test]
+    fn interface_fields_with_the_wrong_kind_of_value() {
+        let err = hosts_config("interface-name = \"10.0.0.2\"").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "`interface-name` of host `client` is an IP address (`10.0.0.2`), did you mean \
+             `interface-ip`?"
+        );
+        let err = hosts_config("interface-ip = \"wlp1s0\"").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "`interface-ip` of host `client` is not an IP address (`wlp1s0`), did you mean \
+             `interface-name`?"
+        );
+    }
+}
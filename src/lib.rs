@@ -1,8 +1,25 @@
+pub mod cancel;
 pub mod capture;
+pub mod command_log;
 pub mod connection;
+pub mod daemon;
 pub mod driver;
+pub mod exit;
 pub mod hosts;
+pub mod lock;
+pub mod logging;
+pub mod mac;
+pub mod metrics;
 pub mod monitor;
+pub mod notify;
+pub mod outcome;
+pub mod output;
 pub mod package;
+pub mod post_run;
+pub mod progress;
+pub mod results;
 pub mod scripts;
+pub mod sqlite;
+pub mod timing;
+pub mod transfer;
 pub mod utils;
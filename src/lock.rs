@@ -0,0 +1,209 @@
+//! Advisory locks on the hosts, so two controllers do not run experiments on the same hosts at
+//! the same time and corrupt each other's results.
+//!
+//! A run writes [LOCK_PATH] on every host before the script starts and removes it afterwards. A
+//! lock of another controller is respected unless it is older than the staleness threshold, for
+//! example because that controller crashed, or `--steal-lock` is given.
+
+use std::{sync::Arc, time::Duration};
+
+use anyhow::Context;
+use ron::ser::to_string;
+use serde::{Deserialize, Serialize};
+use tokio::task::JoinSet;
+use tracing::{debug, info, warn};
+
+use crate::{
+    hosts::Host,
+    utils::{controller_name, shell_quote, unix_time},
+};
+
+/// Where the lock is written on the hosts.
+pub const LOCK_PATH: &str = "/tmp/wifi-exp-controller.lock";
+
+/// The contents of a lock file.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LockInfo {
+    /// The user and machine running the controller, as `user@hostname`.
+    pub controller: String,
+    /// The process ID of the controller.
+    pub pid: u32,
+    /// When the lock was taken, in seconds since the unix epoch.
+    pub start: f64,
+}
+
+/// How locks are taken, from the command line.
+#[derive(Debug, Clone, Copy)]
+pub struct LockOptions {
+    /// Locks older than this are ignored.
+    pub stale_after: Duration,
+    /// Take the lock even if another controller holds it.
+    pub steal: bool,
+}
+
+/// The locks held on the hosts of a run, released with [HostLocks::release].
+#[derive(Debug)]
+pub struct HostLocks {
+    info: LockInfo,
+    hosts: Vec<Arc<Host>>,
+}
+
+impl LockInfo {
+    /// A lock for this controller, taken now.
+    pub async fn current() -> Self {
+        Self {
+            controller: controller_name().await,
+            pid: std::process::id(),
+            start: unix_time(std::time::SystemTime::now()),
+        }
+    }
+
+    /// How long ago the lock was taken.
+    fn age(&self) -> Duration {
+        let now = unix_time(std::time::SystemTime::now());
+        Duration::from_secs_f64((now - self.start).max(0.0))
+    }
+}
+
+/// Lock all `hosts` for this controller. Fails without holding any locks if a host is locked by
+/// another controller, unless that lock is stale or `options.steal` is set.
+pub async fn acquire(
+    hosts: impl IntoIterator<Item = Arc<Host>>,
+    options: LockOptions,
+) -> anyhow::Result<HostLocks> {
+    let info = LockInfo::current().await;
+    let contents = to_string(&info).context("failed to serialize lock")?;
+
+    let mut tasks = JoinSet::new();
+    for host in hosts {
+        let contents = contents.clone();
+        tasks.spawn(async move {
+            let result = lock_host(&host, &contents, options).await;
+            (host, result)
+        });
+    }
+    let mut locked = Vec::new();
+    let mut errors = Vec::new();
+    for (host, result) in tasks.join_all().await {
+        match result {
+            Ok(()) => locked.push(host),
+            Err(err) => errors.push(format!("{err:#}")),
+        }
+    }
+
+    let locks = HostLocks {
+        info,
+        hosts: locked,
+    };
+    if !errors.is_empty() {
+        locks.release().await;
+        anyhow::bail!("could not lock the hosts: {}", errors.join("; "));
+    }
+    Ok(locks)
+}
+
+/// Write the lock to `host`, unless another controller holds a fresh lock.
+async fn lock_host(host: &Host, contents: &str, options: LockOptions) -> anyhow::Result<()> {
+    let existing = read(host).await?;
+    let overwrite = match existing {
+        None => false,
+        Some(Ok(lock)) if lock.age() > options.stale_after => {
+            warn!(
+                host = host.id,
+                "Ignoring stale lock of {} (pid {}) taken {:.0}s ago",
+                lock.controller,
+                lock.pid,
+                lock.age().as_secs_f64()
+            );
+            true
+        }
+        Some(Ok(lock)) if options.steal => {
+            warn!(
+                host = host.id,
+                "Stealing the lock of {} (pid {})", lock.controller, lock.pid
+            );
+            true
+        }
+        Some(Ok(lock)) => anyhow::bail!(
+            "`{}` is in use by {} (pid {}) since {:.0}s ago, pass --steal-lock if it is not",
+            host.id,
+            lock.controller,
+            lock.pid,
+            lock.age().as_secs_f64()
+        ),
+        Some(Err(err)) => {
+            warn!(host = host.id, "Replacing unreadable lock: {err:#}");
+            true
+        }
+    };
+
+    // Without an earlier lock, `noclobber` makes the write fail if another controller took the
+    // lock in the meantime.
+    let noclobber = if overwrite { "" } else { "set -C; " };
+    host.shell_checked(format!(
+        "{noclobber}printf '%s\\n' {} > {LOCK_PATH}",
+        shell_quote(contents)
+    ))
+    .await
+    .with_context(|| format!("could not lock `{}`", host.id))?;
+    debug!(host = host.id, "Locked host");
+    Ok(())
+}
+
+/// Read the lock of `host`, `None` if it has none or `Some(Err(_))` if it can not be parsed.
+async fn read(host: &Host) -> anyhow::Result<Option<anyhow::Result<LockInfo>>> {
+    let output = host
+        .shell_checked(format!("cat {LOCK_PATH} 2>/dev/null || true"))
+        .await
+        .with_context(|| format!("could not read the lock of `{}`", host.id))?;
+    let contents = String::from_utf8_lossy(&output.stdout);
+    if contents.trim().is_empty() {
+        return Ok(None);
+    }
+    Ok(Some(
+        ron::from_str(contents.trim()).context("invalid lock file"),
+    ))
+}
+
+impl HostLocks {
+    /// Remove the locks from the hosts, leaving locks that were taken over by another controller.
+    /// Failures are logged, the lock of an unreachable host becomes stale eventually.
+    pub async fn release(self) {
+        let mut tasks = JoinSet::new();
+        for host in self.hosts {
+            let info = self.info.clone();
+            tasks.spawn(async move {
+                match read(&host).await {
+                    Ok(Some(Ok(lock))) if lock == info => {}
+                    // Removed during the cleanup after aborting.
+                    Ok(None) => return,
+                    Ok(Some(_)) => {
+                        info!(
+                            host = host.id,
+                            "Not removing the lock of another controller"
+                        );
+                        return;
+                    }
+                    Err(err) => {
+                        warn!(host = host.id, "Could not release the lock: {err:#}");
+                        return;
+                    }
+                }
+                if let Err(err) = remove(&host).await {
+                    warn!(host = host.id, "Could not release the lock: {err:#}");
+                }
+            });
+        }
+        tasks.join_all().await;
+    }
+}
+
+/// Remove the lock of `host`, whoever holds it. Returns whether there was a lock.
+pub async fn remove(host: &Host) -> anyhow::Result<bool> {
+    let output = host
+        .shell_checked(format!(
+            "if [ -e {LOCK_PATH} ]; then rm -f {LOCK_PATH} && echo removed; fi"
+        ))
+        .await?;
+    Ok(!output.stdout.is_empty())
+}
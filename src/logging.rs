@@ -0,0 +1,118 @@
+//! Logging to the console and to `controller.log` in the output directory.
+//!
+//! The output directory is only known after the arguments are parsed, so the log file is attached
+//! later. Everything logged before that is kept in memory and written once the file is attached.
+
+use std::{
+    fs::File,
+    io::{self, Write},
+    path::Path,
+    sync::{Arc, Mutex},
+};
+
+use anyhow::Context;
+use clap::ValueEnum;
+use tracing_subscriber::{
+    filter::LevelFilter, fmt::MakeWriter, layer::SubscriberExt, util::SubscriberInitExt, EnvFilter,
+    Layer,
+};
+
+/// How the log is printed to the console.
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogFormat {
+    /// Human-readable lines.
+    Human,
+    /// A JSON object per line, with the fields of events and spans as separate keys.
+    Json,
+}
+
+/// The `controller.log` file, receiving the full log at debug level.
+#[derive(Clone)]
+pub struct LogFile {
+    state: Arc<Mutex<State>>,
+}
+
+enum State {
+    /// The file is not attached yet.
+    Buffer(Vec<u8>),
+    File(File),
+    /// The file could not be created or is not wanted.
+    Disabled,
+}
+
+/// Set up logging to the console with `log_level` and `format`, and to a log file that is
+/// attached later with [LogFile::attach].
+pub fn init(log_level: &str, format: LogFormat) -> anyhow::Result<LogFile> {
+    let filter = EnvFilter::builder()
+        .parse(log_level)
+        .context("failed to parse log level")?;
+    let console = match format {
+        LogFormat::Human => tracing_subscriber::fmt::layer().with_filter(filter).boxed(),
+        LogFormat::Json => tracing_subscriber::fmt::layer()
+            .json()
+            .with_filter(filter)
+            .boxed(),
+    };
+
+    let file = LogFile {
+        state: Arc::new(Mutex::new(State::Buffer(Vec::new()))),
+    };
+    let file_layer = tracing_subscriber::fmt::layer()
+        .with_ansi(false)
+        .with_writer(file.clone())
+        .with_filter(LevelFilter::DEBUG);
+
+    tracing_subscriber::registry()
+        .with(console)
+        .with(file_layer)
+        .init();
+    Ok(file)
+}
+
+impl LogFile {
+    /// Write the log to `controller.log` in `out_path`, which must exist, starting with what was
+    /// logged so far. If that fails, the log is only printed to the console.
+    pub fn attach(&self, out_path: &Path) -> anyhow::Result<()> {
+        let mut state = self.state.lock().expect("log file lock is poisoned");
+        let path = out_path.join("controller.log");
+        let buffered = std::mem::replace(&mut *state, State::Disabled);
+        let mut file =
+            File::create(&path).with_context(|| format!("could not create {}", path.display()))?;
+        if let State::Buffer(buffer) = buffered {
+            file.write_all(&buffer)
+                .with_context(|| format!("could not write {}", path.display()))?;
+        }
+        *state = State::File(file);
+        Ok(())
+    }
+
+    /// Stop keeping the log in memory, for runs without an output directory.
+    pub fn disable(&self) {
+        *self.state.lock().expect("log file lock is poisoned") = State::Disabled;
+    }
+}
+
+impl Write for &LogFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match &mut *self.state.lock().expect("log file lock is poisoned") {
+            State::Buffer(buffer) => buffer.write(buf),
+            State::File(file) => file.write(buf),
+            State::Disabled => Ok(buf.len()),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match &mut *self.state.lock().expect("log file lock is poisoned") {
+            State::File(file) => file.flush(),
+            State::Buffer(_) | State::Disabled => Ok(()),
+        }
+    }
+}
+
+impl<'a> MakeWriter<'a> for LogFile {
+    type Writer = &'a LogFile;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        self
+    }
+}
@@ -0,0 +1,112 @@
+//! MAC addresses, as used for BSSIDs and the addresses of stations.
+
+use std::{fmt, str::FromStr};
+
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+
+/// A MAC address. It is written in lowercase separated by `:`, the form tshark and iw use, and
+/// stored in files as such.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct MacAddr([u8; 6]);
+
+impl MacAddr {
+    /// The address of all zeros.
+    pub const ZERO: MacAddr = MacAddr([0; 6]);
+
+    pub const fn new(octets: [u8; 6]) -> Self {
+        MacAddr(octets)
+    }
+}
+
+impl FromStr for MacAddr {
+    type Err = String;
+
+    /// Parses six pairs of hexadecimal digits separated by `:` or `-`, in either case.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let invalid =
+            || format!("invalid MAC address `{s}`, expected one like `aa:bb:cc:dd:ee:ff`");
+        let mut octets = [0; 6];
+        let mut parts = s.split([':', '-']);
+        for octet in &mut octets {
+            let part = parts.next().ok_or_else(invalid)?;
+            if part.len() != 2 || !part.chars().all(|c| c.is_ascii_hexdigit()) {
+                return Err(invalid());
+            }
+            *octet = u8::from_str_radix(part, 16).map_err(|_| invalid())?;
+        }
+        if parts.next().is_some() {
+            return Err(invalid());
+        }
+        Ok(MacAddr(octets))
+    }
+}
+
+impl fmt::Display for MacAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let [a, b, c, d, e, g] = self.0;
+        write!(f, "{a:02x}:{b:02x}:{c:02x}:{d:02x}:{e:02x}:{g:02x}")
+    }
+}
+
+impl Serialize for MacAddr {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for MacAddr {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(de::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn addresses_in_any_notation() {
+        let expected = MacAddr::new([0x00, 0x1b, 0x2c, 0x3d, 0x4e, 0x5f]);
+        for s in [
+            "00:1b:2c:3d:4e:5f",
+            "00-1b-2c-3d-4e-5f",
+            "00:1B:2C:3D:4E:5F",
+            " 00-1B-2c-3D-4e-5F\n",
+        ] {
+            assert_eq!(s.parse(), Ok(expected), "{s:?}");
+        }
+        assert_eq!(expected.to_string(), "00:1b:2c:3d:4e:5f");
+        assert_eq!(MacAddr::ZERO.to_string(), "00:00:00:00:00:00");
+    }
+
+    #[test]
+    fn invalid_addresses() {
+        for s in [
+            "",
+            "00:1b:2c:3d:4e",
+            "00:1b:2c:3d:4e:5f:60",
+            "00:1b:2c:3d:4e:5g",
+            "0:1b:2c:3d:4e:5f",
+            "001b:2c:3d:4e:5f:",
+            "+0:1b:2c:3d:4e:5f",
+        ] {
+            assert!(s.parse::<MacAddr>().is_err(), "{s:?}");
+        }
+    }
+
+    #[test]
+    fn addresses_are_stored_as_strings() {
+        let address: MacAddr = "02-AA-BB-CC-DD-EE".parse().unwrap();
+        let json = serde_json::to_string(&address).unwrap();
+        assert_eq!(json, r#""02:aa:bb:cc:dd:ee""#);
+        assert_eq!(serde_json::from_str::<MacAddr>(&json).unwrap(), address);
+        let ron = ron::to_string(&Some(address)).unwrap();
+        assert_eq!(
+            ron::from_str::<Option<MacAddr>>(&ron).unwrap(),
+            Some(address)
+        );
+        assert!(serde_json::from_str::<MacAddr>(r#""not an address""#).is_err());
+    }
+}
@@ -1,17 +1,27 @@
-pub mod capture;
-pub mod connection;
-pub mod driver;
-pub mod hosts;
-pub mod monitor;
-pub mod package;
+use std::{
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    process::ExitCode,
+    time::{Duration, Instant, SystemTime},
+};
 
-use std::{path::PathBuf, process::ExitCode, time::SystemTime};
-
-use clap::Parser;
-use controller::scripts::Script;
-use controller::{hosts::HostsConfig, scripts};
-use tracing::{debug, error};
-use tracing_subscriber::EnvFilter;
+use clap::{CommandFactory, FromArgMatches, Parser, Subcommand};
+use controller::cancel::{CancellationToken, Reason};
+use controller::scripts::{config, list_hosts::ListHostsArgs, RunOptions, Script};
+use controller::{
+    exit::ExitReason,
+    hosts::HostsConfig,
+    lock::LockOptions,
+    logging::{self, LogFormat},
+    notify::NotifyOptions,
+    outcome::Outcome,
+    output::{expand_path, OnExists, OutputDir, Placeholders, DEFAULT_TIMESTAMP_FORMAT},
+    post_run::PostRunOptions,
+    scripts,
+    sqlite::RunLabels,
+    utils::parse_duration,
+};
+use tracing::{debug, error, info, warn};
 
 /// Controller program for Wi-Fi experiments and benchmarks.
 #[derive(Parser, Debug, Clone)]
@@ -23,65 +33,544 @@ struct Args {
     /// for example: `info,controller=debug.`
     #[arg(short = 'L', long, env, default_value = "INFO")]
     log_level: String,
+    /// How the log is printed. The full log is also written to `controller.log` in the output
+    /// directory at debug level.
+    #[arg(long, value_enum, default_value = "human")]
+    log_format: LogFormat,
     /// Hosts configuration file path.
     #[clap(short = 'H', long, value_parser, default_value = "./hosts.toml")]
     hosts_file: String,
     /// The path to write output to to.
     ///
-    /// The `<timestamp>` placeholder can be used to fill in the current timestamp in seconds.
-    #[clap(short = 'O', long = "out", default_value = "results/<timestamp>")]
+    /// Can contain the placeholders `<date>` and `<timestamp>` for the time formatted with
+    /// `--timestamp-format`, `<script>` for the name of the script and `<label>` for `--label`.
+    /// If the directory of a time based path exists already, a `-2`, `-3`, ... suffix is added.
+    #[clap(short = 'O', long = "out", default_value = "results/<date>_<script>")]
     output_path: String,
-    /// The specific script to run.
-    #[command(subcommand)]
-    script: Script,
+    /// How the time is formatted for the `<date>` and `<timestamp>` placeholders of the output
+    /// path, with the strftime conversions `%Y`, `%y`, `%m`, `%d`, `%H`, `%M`, `%S`, `%j`, `%s`
+    /// (the unix time), `%F`, `%T` and `%%`. Characters that can not be used in a directory
+    /// name, like `/` and `:`, are replaced by `-`.
+    #[clap(long, default_value = DEFAULT_TIMESTAMP_FORMAT)]
+    timestamp_format: String,
+    /// Format the time in the output path in UTC rather than the local time zone.
+    #[clap(long)]
+    utc: bool,
+    /// A label for the run, filled in for the `<label>` placeholder of the output path.
+    #[clap(long)]
+    label: Option<String>,
+    /// Continue a repeated or swept experiment in the output directory of an earlier run of it,
+    /// instead of `--out`. Iterations that completed are skipped, the others are run again. The
+    /// arguments must be the same as those of the earlier run.
+    #[clap(long, value_name = "PREVIOUS_OUT_DIR", conflicts_with = "output_path")]
+    resume: Option<PathBuf>,
+    /// What to do if the output directory already contains files.
+    #[clap(long, value_enum, default_value = "fail")]
+    on_exists: OnExists,
+    /// Write to an output directory that already contains the results of an earlier run,
+    /// deleting them. Same as `--on-exists overwrite`, unless `--on-exists` is given as well.
+    #[clap(long)]
+    force: bool,
+    /// Do not record the commands run on the hosts in `commands.jsonl` in the output directory.
+    #[clap(long)]
+    no_command_log: bool,
+    /// Validate the arguments and print what the script would do, without running anything on
+    /// the hosts. The hosts are still connected to.
+    #[clap(long)]
+    dry_run: bool,
+    /// Read the script and its arguments from a TOML or RON file. Arguments passed on the command
+    /// line after the name of the script override those in the file.
+    #[clap(long)]
+    config: Option<PathBuf>,
+    /// Stop the script and clean up the hosts if it runs longer than this, like `90s`, `15m` or
+    /// `2h`. The output directory is then marked with a `TIMED_OUT` file.
+    #[clap(long, value_parser = parse_duration)]
+    max_runtime: Option<Duration>,
+    /// The directory in which every run is added to `index.csv`, and a `latest` link points at
+    /// the output of the last successful run. Defaults to the parent of the output directory.
+    #[clap(long)]
+    index_dir: Option<PathBuf>,
+    /// Do not add the run to `index.csv` or update the `latest` link.
+    #[clap(long)]
+    no_index: bool,
+    /// Show the current phase of the script and how long it has been running, on a status line
+    /// if stderr is a terminal and as a periodic log line otherwise.
+    #[clap(long)]
+    progress: bool,
+    /// A command run with `sh -c` on the controller when the run finishes, also if it failed.
+    ///
+    /// The command gets the environment variables `OUT_DIR` with the output directory, `STATUS`
+    /// with `completed`, `failed`, `aborted` or `timed-out`, `DURATION` with the duration of the
+    /// run in seconds and `SCRIPT` with the name of the script. A failing command is only logged.
+    #[clap(long)]
+    notify_cmd: Option<String>,
+    /// A URL to POST to when the run finishes, also if it failed. Requires the `notify-http`
+    /// feature.
+    ///
+    /// The body is a JSON object like `{"out_dir": "results/...", "status": "completed",
+    /// "duration": 61.5, "script": "iperf"}`, with the same values as for `--notify-cmd`. A failing
+    /// request is only logged.
+    #[clap(long)]
+    notify_url: Option<String>,
+    /// Serve live metrics of the run on this address, like `0.0.0.0:9200`, at `/metrics` in the
+    /// Prometheus text format. Requires the `metrics` feature.
+    #[clap(long)]
+    metrics_listen: Option<SocketAddr>,
+    /// Export the results of the run to this SQLite database when it finishes, creating the
+    /// database if it does not exist. With `reparse` and `report` the runs in their directory are
+    /// exported. Requires the `sqlite` feature.
+    #[clap(long)]
+    export_sqlite: Option<PathBuf>,
+    /// A command run with `sh -c` on the controller after the run completed, like an analysis
+    /// script. It gets the output directory as its last argument and in `OUT_DIR`, its output is
+    /// added to the log and how it went is recorded in `meta.ron`.
+    #[clap(long)]
+    post_run: Option<String>,
+    /// Also run the `--post-run` command if the run failed.
+    #[clap(long, requires = "post_run")]
+    post_run_always: bool,
+    /// Fail the run if the `--post-run` command fails, rather than only reporting it.
+    #[clap(long, requires = "post_run")]
+    post_run_strict: bool,
+    /// Write a JSON object describing how the run went to this file when it finishes, with the
+    /// output directory, the status, why the controller exits, the duration, the commands that
+    /// failed on each host and key numbers of the results. It has a `schema` field with the
+    /// version of its format.
+    #[clap(long)]
+    outcome_file: Option<PathBuf>,
+    /// Print the same JSON object as `--outcome-file` as the last line of stdout.
+    #[clap(long)]
+    outcome_stdout: bool,
+    /// Fail if the interface or driver of a host in the hosts file does not match the host,
+    /// rather than warning about it.
+    #[clap(long)]
+    strict_hosts: bool,
+    /// Lock the hosts even if another controller is using them.
+    #[clap(long)]
+    steal_lock: bool,
+    /// Ignore the locks of other controllers that are older than this, like `12h`, as that
+    /// controller probably crashed.
+    #[clap(long, value_parser = parse_duration, default_value = "12h")]
+    lock_stale_after: Duration,
+}
+
+/// The command line, with a subcommand for every script.
+fn command() -> clap::Command {
+    Script::augment_subcommands(Args::command()).subcommand_required(true)
+}
+
+/// How long a script that exceeded `--max-runtime` gets to finish writing its results, and after
+/// that to clean up, before the controller exits anyway.
+const TIMEOUT_GRACE_PERIOD: Duration = Duration::from_secs(30);
+
+/// Cancel `cancel` once the script ran for `max_runtime`, and exit if it did not stop in time.
+///
+/// A script that is writing its results then gets [TIMEOUT_GRACE_PERIOD] to finish them first.
+fn arm_watchdog(max_runtime: Duration, cancel: CancellationToken) {
+    tokio::spawn(async move {
+        watchdog(max_runtime, TIMEOUT_GRACE_PERIOD, &cancel).await;
+        error!("The run did not stop in time, exiting without cleaning up");
+        std::process::exit(ExitReason::Timeout.code().into());
+    });
+}
+
+/// Cancel `cancel` for [Reason::Timeout] after `max_runtime`, or up to `grace` later if the
+/// script is writing its results, and return `grace` after that.
+async fn watchdog(max_runtime: Duration, grace: Duration, cancel: &CancellationToken) {
+    tokio::time::sleep(max_runtime).await;
+    if cancel.is_finishing() {
+        warn!("The maximum runtime passed while the results are written, waiting for them");
+        _ = tokio::time::timeout(grace, cancel.finished()).await;
+    }
+    error!(
+        "The run exceeded the maximum runtime of {:.0}s, stopping it",
+        max_runtime.as_secs_f64()
+    );
+    cancel.cancel_for(Reason::Timeout);
+    tokio::time::sleep(grace).await;
+}
+
+/// Cancel `cancel` on the first Ctrl-C, so the script can clean up, and exit immediately on the
+/// second.
+fn handle_ctrl_c(cancel: CancellationToken) {
+    tokio::spawn(async move {
+        if tokio::signal::ctrl_c().await.is_err() {
+            return;
+        }
+        warn!("Aborting, cleaning up the hosts. Press Ctrl-C again to exit immediately");
+        cancel.cancel();
+        if tokio::signal::ctrl_c().await.is_ok() {
+            error!("Exiting without cleaning up");
+            std::process::exit(ExitReason::Aborted.code().into());
+        }
+    });
+}
+
+/// Export the runs in `dir` to the database of `--export-sqlite`, if it was given, after `reparse`
+/// or `report`.
+async fn export_runs(db: Option<&Path>, dir: &Path) -> ExitCode {
+    let Some(db) = db else {
+        return ExitCode::SUCCESS;
+    };
+    match controller::sqlite::export(db, dir, RunLabels::default()).await {
+        Ok(runs) => {
+            info!("Exported {runs} runs to {}", db.display());
+            ExitCode::SUCCESS
+        }
+        Err(err) => {
+            error!("Could not export the results: {err:#}");
+            ExitReason::of(&err).into()
+        }
+    }
+}
+
+/// Print the hosts of the hosts file for `list-hosts`.
+async fn list_hosts(hosts_file: &str, args: &ListHostsArgs) -> ExitCode {
+    let hosts_config = match HostsConfig::read(hosts_file).await {
+        Ok(v) => v,
+        Err(err) => {
+            error!("Unable to parse `{hosts_file}`: {err:#}");
+            return ExitReason::of(&err).into();
+        }
+    };
+    match scripts::list_hosts::run(args, &hosts_config).await {
+        Ok(listing) => {
+            println!("{}", listing.trim_end());
+            ExitCode::SUCCESS
+        }
+        Err(err) => {
+            error!("Could not list the hosts: {err:?}");
+            ExitReason::of(&err).into()
+        }
+    }
 }
 
 #[tokio::main]
 async fn main() -> ExitCode {
-    // Parse command-line arguments based on the [Args] struct.
-    let args = Args::parse();
+    // Parse command-line arguments based on the [Args] struct. With a config file the arguments
+    // of the script are optional, as they can also be in the file.
+    let matches = command()
+        .try_get_matches()
+        .unwrap_or_else(|err| match config::relax(command()).try_get_matches() {
+            Ok(matches) if matches.contains_id("config") => matches,
+            _ => err.exit(),
+        });
+    let args = Args::from_arg_matches(&matches).unwrap_or_else(|err| err.exit());
 
     // Set up human-readable logging using the `tracing-subcriber` crate.
-    tracing_subscriber::fmt()
-        .with_env_filter(match EnvFilter::builder().parse(args.log_level) {
+    let log_file = match logging::init(&args.log_level, args.log_format) {
+        Ok(v) => v,
+        Err(err) => {
+            eprintln!("Failed to parse log_level argument: {err:?}");
+            return ExitReason::Config.into();
+        }
+    };
+    debug!("Debug logging is enabled");
+
+    if args.notify_url.is_some() && !cfg!(feature = "notify-http") {
+        error!("--notify-url requires the controller to be built with the `notify-http` feature");
+        return ExitReason::Config.into();
+    }
+    if args.metrics_listen.is_some() && !cfg!(feature = "metrics") {
+        error!("--metrics-listen requires the controller to be built with the `metrics` feature");
+        return ExitReason::Config.into();
+    }
+    if args.export_sqlite.is_some() && !cfg!(feature = "sqlite") {
+        error!("--export-sqlite requires the controller to be built with the `sqlite` feature");
+        return ExitReason::Config.into();
+    }
+
+    let (script_name, mut script) = match &args.config {
+        Some(path) => match config::load(path, &matches) {
             Ok(v) => v,
             Err(err) => {
-                eprintln!("Failed to parse log_level argument: {err:?}");
-                return ExitCode::FAILURE;
+                error!("Could not load the config file: {err:#}");
+                return ExitReason::Config.into();
             }
-        })
-        .init();
-    debug!("Debug logging is enabled");
+        },
+        None => {
+            let script = Script::from_arg_matches(&matches).unwrap_or_else(|err| err.exit());
+            let name = matches.subcommand_name().expect("a script is required");
+            (name.to_string(), script)
+        }
+    };
+    // Checked before connecting, so a mistake in the arguments does not wait for the hosts.
+    if let Err(err) = script.check_channels().and_then(|()| script.check_hosts()) {
+        if args.config.is_none() {
+            // Reported like the other invalid arguments, before anything runs.
+            command()
+                .error(clap::error::ErrorKind::ValueValidation, err)
+                .exit();
+        }
+        error!("Invalid config file: {err}");
+        return ExitReason::Config.into();
+    }
+
+    // Listing the hosts does not need an output directory, and must work when not all hosts can
+    // be connected to.
+    if let Script::ListHosts(list_args) = &script {
+        log_file.disable();
+        return list_hosts(&args.hosts_file, list_args).await;
+    }
+    // Reparsing, comparing, anonymizing and reporting only read and write the results directories
+    // they are given.
+    if let Script::Reparse(reparse_args) = &script {
+        log_file.disable();
+        return match scripts::reparse::run(reparse_args) {
+            Ok(_) => export_runs(args.export_sqlite.as_deref(), &reparse_args.dir).await,
+            Err(err) => {
+                error!("Could not reparse the results: {err:#}");
+                ExitReason::of(&err).into()
+            }
+        };
+    }
+    if let Script::Compare(compare_args) = &script {
+        log_file.disable();
+        return match scripts::compare::run(compare_args) {
+            Ok(_) => ExitCode::SUCCESS,
+            Err(err) => {
+                error!("Could not compare the runs: {err:#}");
+                ExitReason::of(&err).into()
+            }
+        };
+    }
+    if let Script::Anonymize(anonymize_args) = &script {
+        log_file.disable();
+        return match scripts::anonymize::run(anonymize_args) {
+            Ok(_) => ExitCode::SUCCESS,
+            Err(err) => {
+                error!("Could not anonymize the captures: {err:#}");
+                ExitReason::of(&err).into()
+            }
+        };
+    }
+    if let Script::Report(report_args) = &script {
+        log_file.disable();
+        return match scripts::report::run(report_args) {
+            Ok(_) => export_runs(args.export_sqlite.as_deref(), &report_args.dir).await,
+            Err(err) => {
+                error!("Could not write the report: {err:#}");
+                ExitReason::of(&err).into()
+            }
+        };
+    }
+
+    let placeholders = Placeholders {
+        time: SystemTime::now(),
+        time_format: &args.timestamp_format,
+        utc: args.utc,
+        script: &script_name,
+        label: args.label.as_deref(),
+    };
+    let out_path = match &args.resume {
+        Some(path) if !path.is_dir() => {
+            error!("Can not resume {}, it is not a directory", path.display());
+            return ExitReason::Config.into();
+        }
+        Some(path) => path.clone(),
+        None => match expand_path(&args.output_path, &placeholders) {
+            Ok(v) => v,
+            Err(err) => {
+                error!("Invalid output path: {err:#}");
+                return ExitReason::Config.into();
+            }
+        },
+    };
+    let on_exists = match args.on_exists {
+        OnExists::Fail if args.force => OnExists::Overwrite,
+        on_exists => on_exists,
+    };
+    let out_dir = if script.resumes() || args.resume.is_some() {
+        OutputDir::reuse(out_path)
+    } else {
+        match OutputDir::choose(out_path, on_exists) {
+            Ok(v) => v,
+            Err(err) => {
+                error!("{err:#}");
+                return ExitReason::Config.into();
+            }
+        }
+    };
+    let out_path = out_dir.path.clone();
 
     let hosts_config = match HostsConfig::read(&args.hosts_file).await {
         Ok(v) => v,
         Err(err) => {
-            error!("Unable to parse `{}`: {err}", args.hosts_file);
-            return ExitCode::FAILURE;
+            error!("Unable to parse `{}`: {err:#}", args.hosts_file);
+            return ExitReason::of(&err).into();
         }
     };
 
-    let hosts = match hosts_config.connect().await {
+    let connect_start = Instant::now();
+    let hosts = match hosts_config.connect(args.strict_hosts).await {
         Ok(v) => v,
         Err(err) => {
             error!("Could not initialize ssh connections: {err:?}");
-            return ExitCode::FAILURE;
+            return ExitReason::of(&err).into();
         }
     };
+    let connect_time = connect_start..Instant::now();
+
+    if args.dry_run {
+        log_file.disable();
+    } else {
+        if let Err(err) = out_dir.create() {
+            error!("Could not prepare the output directory: {err:#}");
+            return ExitReason::Script.into();
+        }
+        if let Err(err) = log_file.attach(&out_path) {
+            warn!("Could not write the log to the output directory: {err:#}");
+        }
+    }
 
-    let out_path: PathBuf = {
-        let now = SystemTime::now()
-            .duration_since(SystemTime::UNIX_EPOCH)
-            .unwrap()
-            .as_secs()
-            .to_string();
-        args.output_path.replace("<timestamp>", &now).into()
+    if let Some(addr) = args.metrics_listen {
+        if let Err(err) = controller::metrics::serve(addr).await {
+            error!("Could not serve metrics: {err:#}");
+            return ExitReason::Config.into();
+        }
+    }
+
+    let cancel = CancellationToken::new();
+    handle_ctrl_c(cancel.clone());
+    if let Some(max_runtime) = args.max_runtime {
+        arm_watchdog(max_runtime, cancel.clone());
+    }
+    let index_dir = match &args.index_dir {
+        Some(dir) => Some(dir.as_path()),
+        None => out_path.parent(),
     };
+    let options = RunOptions {
+        script_name: &script_name,
+        label: args.label.as_deref(),
+        hosts_file: Path::new(&args.hosts_file),
+        log_commands: !args.no_command_log,
+        dry_run: args.dry_run,
+        progress: args.progress,
+        index_dir: index_dir.filter(|_| !args.no_index),
+        notify: NotifyOptions {
+            command: args.notify_cmd.as_deref(),
+            url: args.notify_url.as_deref(),
+        },
+        lock: LockOptions {
+            stale_after: args.lock_stale_after,
+            steal: args.steal_lock,
+        },
+        post_run: PostRunOptions {
+            command: args.post_run.as_deref(),
+            always: args.post_run_always,
+            strict: args.post_run_strict,
+        },
+        export_sqlite: args.export_sqlite.as_deref(),
+        connect_time: Some(connect_time),
+    };
+    let report = scripts::run(script, hosts, &out_path, &options, &cancel).await;
+    let reason = report.result.as_ref().err().map(|err| {
+        let reason = ExitReason::of(err);
+        match reason {
+            ExitReason::Aborted => error!(
+                "Script was aborted, partial results are in {}",
+                out_path.display()
+            ),
+            ExitReason::Timeout => error!(
+                "Script exceeded the maximum runtime, partial results are in {}",
+                out_path.display()
+            ),
+            _ => error!("Script exited with an error: {err:?}"),
+        }
+        reason
+    });
+
+    let outcome = Outcome::new(&report, &out_path, &script_name);
+    if let Some(path) = &args.outcome_file {
+        if let Err(err) = outcome.write(path) {
+            error!("Could not write the outcome: {err:#}");
+        }
+    }
+    if args.outcome_stdout {
+        match outcome.to_json() {
+            Ok(json) => println!("{json}"),
+            Err(err) => error!("Could not print the outcome: {err:#}"),
+        }
+    }
+    reason.map_or(ExitCode::SUCCESS, ExitCode::from)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
 
-    if let Err(err) = scripts::run(args.script, hosts, &out_path).await {
-        error!("Script exited with an error: {err:?}");
-        return ExitCode::FAILURE;
+    use controller::cancel::Aborted;
+
+    use super::*;
+
+    const MAX_RUNTIME: Duration = Duration::from_millis(50);
+    const GRACE: Duration = Duration::from_millis(300);
+
+    /// A script that sleeps far longer than the maximum runtime unless it is cancelled.
+    async fn sleeping_script(cancel: &CancellationToken) -> anyhow::Result<()> {
+        cancel
+            .run(async {
+                tokio::time::sleep(Duration::from_secs(60)).await;
+                Ok(())
+            })
+            .await
+    }
+
+    #[tokio::test]
+    async fn scripts_are_cancelled_after_the_maximum_runtime() {
+        let cancel = CancellationToken::new();
+        let watchdog = tokio::spawn({
+            let cancel = cancel.clone();
+            async move { watchdog(MAX_RUNTIME, GRACE, &cancel).await }
+        });
+
+        let start = Instant::now();
+        let err = sleeping_script(&cancel).await.unwrap_err();
+        assert!(err.is::<Aborted>());
+        assert_eq!(cancel.reason(), Some(Reason::Timeout));
+        let elapsed = start.elapsed();
+        assert!(elapsed >= MAX_RUNTIME && elapsed < GRACE, "{elapsed:?}");
+
+        // The controller exits if the script did not stop within the grace period.
+        watchdog.await.unwrap();
+        assert!(start.elapsed() >= MAX_RUNTIME + GRACE);
     }
 
-    ExitCode::SUCCESS
+    #[tokio::test]
+    async fn scripts_writing_their_results_get_to_finish_them() {
+        let cancel = CancellationToken::new();
+        let writing = cancel.finishing();
+        tokio::spawn({
+            let cancel = cancel.clone();
+            async move { watchdog(MAX_RUNTIME, GRACE, &cancel).await }
+        });
+
+        tokio::time::sleep(MAX_RUNTIME * 3).await;
+        assert!(!cancel.is_cancelled());
+        let start = Instant::now();
+        drop(writing);
+
+        sleeping_script(&cancel).await.unwrap_err();
+        assert_eq!(cancel.reason(), Some(Reason::Timeout));
+        assert!(start.elapsed() < GRACE, "{:?}", start.elapsed());
+    }
+
+    #[tokio::test]
+    async fn scripts_that_do_not_finish_their_results_are_cancelled_anyway() {
+        let cancel = CancellationToken::new();
+        let _writing = cancel.finishing();
+        tokio::spawn({
+            let cancel = cancel.clone();
+            async move { watchdog(MAX_RUNTIME, GRACE, &cancel).await }
+        });
+
+        let start = Instant::now();
+        sleeping_script(&cancel).await.unwrap_err();
+        assert_eq!(cancel.reason(), Some(Reason::Timeout));
+        assert!(
+            start.elapsed() >= MAX_RUNTIME + GRACE,
+            "{:?}",
+            start.elapsed()
+        );
+    }
 }
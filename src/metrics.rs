@@ -0,0 +1,354 @@
+//! Live metrics of a run, served in the Prometheus text exposition format with
+//! `--metrics-listen`, so long campaigns can be followed on a dashboard.
+//!
+//! The metrics are only recorded once [serve] is called. Before that, and without the `metrics`
+//! feature, the recording functions do nothing.
+
+use std::net::SocketAddr;
+
+use crate::progress::Phase;
+
+/// Whether metrics are recorded, to skip work that is only needed for them.
+pub fn enabled() -> bool {
+    #[cfg(feature = "metrics")]
+    return server::registry().is_some();
+    #[cfg(not(feature = "metrics"))]
+    false
+}
+
+/// Record the phase the script entered.
+pub fn set_phase(phase: Phase) {
+    #[cfg(feature = "metrics")]
+    if let Some(registry) = server::registry() {
+        registry.lock().phase = Some(phase.to_string());
+    }
+    #[cfg(not(feature = "metrics"))]
+    let _ = phase;
+}
+
+/// Record the throughput a client reported for its last interval, in bits per second.
+pub fn set_throughput(host: &str, bits_per_second: f64) {
+    #[cfg(feature = "metrics")]
+    if let Some(registry) = server::registry() {
+        registry
+            .lock()
+            .throughput
+            .insert(host.to_string(), bits_per_second);
+    }
+    #[cfg(not(feature = "metrics"))]
+    let _ = (host, bits_per_second);
+}
+
+/// Record whether a monitor host is capturing.
+pub fn set_capturing(host: &str, capturing: bool) {
+    #[cfg(feature = "metrics")]
+    if let Some(registry) = server::registry() {
+        registry
+            .lock()
+            .capturing
+            .insert(host.to_string(), capturing);
+    }
+    #[cfg(not(feature = "metrics"))]
+    let _ = (host, capturing);
+}
+
+/// Record the packet counts of a capture that completed.
+pub fn add_capture_packets(host: &str, packets: u64, dropped: u64) {
+    #[cfg(feature = "metrics")]
+    if let Some(registry) = server::registry() {
+        let mut registry = registry.lock();
+        *registry.packets.entry(host.to_string()).or_default() += packets;
+        *registry.dropped.entry(host.to_string()).or_default() += dropped;
+    }
+    #[cfg(not(feature = "metrics"))]
+    let _ = (host, packets, dropped);
+}
+
+/// Record a command that ran on `host`, and whether it failed.
+pub fn record_command(host: &str, failed: bool) {
+    #[cfg(feature = "metrics")]
+    if let Some(registry) = server::registry() {
+        let mut registry = registry.lock();
+        *registry.commands.entry(host.to_string()).or_default() += 1;
+        if failed {
+            *registry.failures.entry(host.to_string()).or_default() += 1;
+        }
+    }
+    #[cfg(not(feature = "metrics"))]
+    let _ = (host, failed);
+}
+
+/// Start recording metrics and serve them on `addr` at `/metrics`, until the controller exits.
+#[cfg(feature = "metrics")]
+pub async fn serve(addr: SocketAddr) -> anyhow::Result<SocketAddr> {
+    server::serve(addr).await
+}
+
+/// Without the `metrics` feature there are no metrics to serve.
+#[cfg(not(feature = "metrics"))]
+pub async fn serve(_addr: SocketAddr) -> anyhow::Result<SocketAddr> {
+    anyhow::bail!("the controller was built without the `metrics` feature")
+}
+
+#[cfg(feature = "metrics")]
+mod server {
+    use std::{
+        collections::BTreeMap,
+        fmt::{Display, Write as _},
+        net::SocketAddr,
+        sync::{Mutex, MutexGuard, OnceLock},
+        time::Duration,
+    };
+
+    use anyhow::Context;
+    use tokio::{
+        io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+        net::{TcpListener, TcpStream},
+        time::sleep,
+    };
+    use tracing::{debug, info, warn};
+
+    /// The metrics of the run, set once they are served.
+    static REGISTRY: OnceLock<Registry> = OnceLock::new();
+
+    /// How long to wait before accepting again after accepting a connection failed, for example
+    /// because the controller ran out of file descriptors.
+    const ACCEPT_RETRY_DELAY: Duration = Duration::from_millis(500);
+
+    /// The current value of every metric, by host where it applies.
+    #[derive(Debug, Default)]
+    pub struct Metrics {
+        pub phase: Option<String>,
+        pub throughput: BTreeMap<String, f64>,
+        pub capturing: BTreeMap<String, bool>,
+        pub packets: BTreeMap<String, u64>,
+        pub dropped: BTreeMap<String, u64>,
+        pub commands: BTreeMap<String, u64>,
+        pub failures: BTreeMap<String, u64>,
+    }
+
+    #[derive(Debug, Default)]
+    pub struct Registry(Mutex<Metrics>);
+
+    impl Registry {
+        pub fn lock(&self) -> MutexGuard<'_, Metrics> {
+            self.0.lock().expect("metrics lock is poisoned")
+        }
+    }
+
+    pub fn registry() -> Option<&'static Registry> {
+        REGISTRY.get()
+    }
+
+    pub async fn serve(addr: SocketAddr) -> anyhow::Result<SocketAddr> {
+        let listener = TcpListener::bind(addr)
+            .await
+            .with_context(|| format!("could not listen on {addr}"))?;
+        let addr = listener
+            .local_addr()
+            .context("could not get listen address")?;
+        REGISTRY.get_or_init(Registry::default);
+        info!("Serving metrics on http://{addr}/metrics");
+        tokio::spawn(async move {
+            loop {
+                let (stream, peer) = match listener.accept().await {
+                    Ok(accepted) => accepted,
+                    Err(err) => {
+                        warn!("Could not accept a metrics connection: {err}");
+                        sleep(ACCEPT_RETRY_DELAY).await;
+                        continue;
+                    }
+                };
+                tokio::spawn(async move {
+                    if let Err(err) = respond(stream).await {
+                        debug!("Could not serve metrics to {peer}: {err:#}");
+                    }
+                });
+            }
+        });
+        Ok(addr)
+    }
+
+    /// Answer a single HTTP request, closing the connection afterwards.
+    async fn respond(stream: TcpStream) -> anyhow::Result<()> {
+        let mut stream = BufReader::new(stream);
+        let mut request = String::new();
+        stream.read_line(&mut request).await?;
+        // The headers are not needed, but are read so the client does not see a reset.
+        let mut header = String::new();
+        while stream.read_line(&mut header).await? > 2 {
+            header.clear();
+        }
+
+        let mut parts = request.split_whitespace();
+        let (status, body) = match (parts.next(), parts.next()) {
+            (Some("GET"), Some("/metrics")) => {
+                let registry = REGISTRY.get_or_init(Registry::default);
+                ("200 OK", render(&registry.lock()))
+            }
+            (Some("GET"), _) => ("404 Not Found", "not found\n".to_string()),
+            _ => (
+                "405 Method Not Allowed",
+                "only GET is supported\n".to_string(),
+            ),
+        };
+        let response = format!(
+            "HTTP/1.1 {status}\r\nContent-Type: text/plain; version=0.0.4\r\n\
+             Content-Length: {}\r\nConnection: close\r\n\r\n{body}",
+            body.len()
+        );
+        stream.get_mut().write_all(response.as_bytes()).await?;
+        stream.get_mut().shutdown().await?;
+        Ok(())
+    }
+
+    /// Write the metrics in the text exposition format.
+    fn render(metrics: &Metrics) -> String {
+        let mut out = String::new();
+        let phase = metrics
+            .phase
+            .iter()
+            .map(|phase| (format!("{{phase=\"{}\"}}", escape(phase)), 1));
+        family(
+            &mut out,
+            "phase",
+            "gauge",
+            "The phase the script is in.",
+            phase,
+        );
+        family(
+            &mut out,
+            "client_throughput_bits_per_second",
+            "gauge",
+            "The throughput of the last interval reported by a client.",
+            by_host(&metrics.throughput),
+        );
+        family(
+            &mut out,
+            "monitor_capturing",
+            "gauge",
+            "Whether a monitor host is capturing.",
+            by_host(&metrics.capturing).map(|(labels, v)| (labels, u8::from(*v))),
+        );
+        family(
+            &mut out,
+            "capture_packets_total",
+            "counter",
+            "Packets captured by a monitor host, counted when its capture completes.",
+            by_host(&metrics.packets),
+        );
+        family(
+            &mut out,
+            "capture_dropped_packets_total",
+            "counter",
+            "Packets dropped by a monitor host, counted when its capture completes.",
+            by_host(&metrics.dropped),
+        );
+        family(
+            &mut out,
+            "commands_total",
+            "counter",
+            "Commands run on a host.",
+            by_host(&metrics.commands),
+        );
+        family(
+            &mut out,
+            "command_failures_total",
+            "counter",
+            "Commands on a host that could not be run or exited with an error.",
+            by_host(&metrics.failures),
+        );
+        out
+    }
+
+    /// Write a metric with all its samples, which are pairs of labels and a value.
+    fn family<V: Display>(
+        out: &mut String,
+        name: &str,
+        kind: &str,
+        help: &str,
+        samples: impl Iterator<Item = (String, V)>,
+    ) {
+        _ = writeln!(out, "# HELP wifi_controller_{name} {help}");
+        _ = writeln!(out, "# TYPE wifi_controller_{name} {kind}");
+        for (labels, value) in samples {
+            _ = writeln!(out, "wifi_controller_{name}{labels} {value}");
+        }
+    }
+
+    /// The samples of a metric by host.
+    fn by_host<V>(values: &BTreeMap<String, V>) -> impl Iterator<Item = (String, &V)> {
+        values
+            .iter()
+            .map(|(host, value)| (format!("{{host=\"{}\"}}", escape(host)), value))
+    }
+
+    /// Escape a label value.
+    fn escape(value: &str) -> String {
+        value
+            .replace('\\', "\\\\")
+            .replace('"', "\\\"")
+            .replace('\n', "\\n")
+    }
+}
+
+#[cfg(all(test, feature = "metrics"))]
+mod tests {
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpStream,
+    };
+
+    use super::*;
+
+    /// Send a GET request for `path` and return the whole response.
+    async fn get(addr: SocketAddr, path: &str) -> String {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let request = format!("GET {path} HTTP/1.1\r\nHost: {addr}\r\n\r\n");
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        response
+    }
+
+    #[tokio::test]
+    async fn scrape_metrics_of_a_run() {
+        let addr = serve("127.0.0.1:0".parse().unwrap()).await.unwrap();
+        assert!(enabled());
+
+        // What a run records while it provisions, captures and collects the results.
+        set_phase(Phase::Provisioning);
+        record_command("ap", false);
+        record_command("client\"1", true);
+        set_capturing("monitor", true);
+        set_throughput("client\"1", 12.5e6);
+        set_capturing("monitor", false);
+        add_capture_packets("monitor", 1000, 3);
+        set_phase(Phase::Collecting);
+
+        let response = get(addr, "/metrics").await;
+        let (head, body) = response.split_once("\r\n\r\n").unwrap();
+        assert!(head.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(head.contains(&format!("Content-Length: {}", body.len())));
+        for line in [
+            "# TYPE wifi_controller_phase gauge",
+            "wifi_controller_phase{phase=\"collecting results\"} 1",
+            "wifi_controller_client_throughput_bits_per_second{host=\"client\\\"1\"} 12500000",
+            "wifi_controller_monitor_capturing{host=\"monitor\"} 0",
+            "wifi_controller_capture_packets_total{host=\"monitor\"} 1000",
+            "wifi_controller_capture_dropped_packets_total{host=\"monitor\"} 3",
+            "wifi_controller_commands_total{host=\"ap\"} 1",
+            "wifi_controller_commands_total{host=\"client\\\"1\"} 1",
+            "wifi_controller_command_failures_total{host=\"client\\\"1\"} 1",
+        ] {
+            assert!(
+                body.lines().any(|l| l == line),
+                "missing `{line}` in:\n{body}"
+            );
+        }
+        assert!(!body.contains("command_failures_total{host=\"ap\"}"));
+
+        let response = get(addr, "/other").await;
+        assert!(response.starts_with("HTTP/1.1 404 Not Found\r\n"));
+    }
+}
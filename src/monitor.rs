@@ -1,21 +1,46 @@
-use std::{path::PathBuf, time::Duration};
+use std::{
+    collections::BTreeMap,
+    future::Future,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
 
 use anyhow::{anyhow, Context};
-use openssh::Stdio;
-use tokio::{fs, io::AsyncReadExt, task::JoinSet};
-use tracing::{debug, error, info};
+use ron::ser::{to_string_pretty, PrettyConfig};
+use serde::{Deserialize, Serialize};
+use tokio::{fs, select, task::JoinSet};
+use tracing::{debug, error, info, warn};
 
 use crate::{
-    capture::{Capture, CaptureConfig, StopCondition},
-    driver::wifi::iwlwifi,
-    hosts::{HostId, Hosts},
+    cancel::{Aborted, CancellationToken, Reason},
+    capture::{
+        self, analysis, Capture, CaptureConfig, CaptureReader, CaptureStats, MergeOptions,
+        MergeStats, StopCondition,
+    },
+    driver::wifi::{self, iwlwifi, Aid},
+    hosts::{Host, HostId, Hosts},
+    mac::MacAddr,
+    metrics,
+    progress::{self, Phase},
+    results::{Artifact, SchemaVersion},
+    timing::{self, TimedPhase},
+    utils::{clock_offset, host_file, RemoteCmd},
 };
 
+/// How long the AID capture may run, it stops by itself after 10 seconds.
+const AID_CAPTURE_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// How long a capture starts before the measured traffic by default.
+pub const DEFAULT_LEAD_IN: Duration = Duration::from_secs(2);
+/// How long a capture continues after the measured traffic by default.
+pub const DEFAULT_LEAD_OUT: Duration = Duration::from_secs(2);
+
 pub struct MonitorConfig {
     /// The SSID of the network to monitor.
     pub ssid: String,
-    /// Thee BSS ID of the network to monitor.
-    pub bssid: String,
+    /// The BSSID of the network to monitor. Required to discover and set association IDs.
+    pub bssid: Option<MacAddr>,
     /// Frequency of the channel in MHz.
     pub frequency: u32,
     /// Bandwidth of the channel in MHz.
@@ -24,8 +49,13 @@ pub struct MonitorConfig {
     pub monitors: Vec<HostId>,
     /// The hosts to monitor.
     pub targets: Vec<HostId>,
-    /// How long the capture should last.
+    /// How long the measured traffic lasts. The capture runs `lead_in` longer before it and
+    /// `lead_out` longer after it.
     pub duration: Duration,
+    /// How long the capture starts before the measured traffic, which covers starting it.
+    pub lead_in: Duration,
+    /// How long the capture continues after the measured traffic, which covers stopping it.
+    pub lead_out: Duration,
     /// Where to write the captures to.
     pub output_path: Option<PathBuf>,
     /// If true, gathers the association IDs of all the other hosts and assign each one to a
@@ -33,11 +63,27 @@ pub struct MonitorConfig {
     ///
     /// This requires that the monitor driver supports manually setting an association ID.
     pub set_aids: bool,
+    /// Association IDs found by an earlier monitor. If set together with `set_aids`, these are
+    /// assigned to the monitors instead of associating the targets again to discover them. Only
+    /// the targets that are not connected to the network are associated, adding their AIDs.
+    pub known_aids: Option<Vec<Aid>>,
+    /// Only capture frames matching this capture filter, in the syntax of `tshark -f`.
+    pub filter: Option<String>,
+    /// Merge the captures into `merged.pcapng` once they are collected, correcting for the clock
+    /// offsets of the monitor hosts. Requires an output path.
+    pub merge_captures: bool,
+    /// When merging, drop frames that another monitor captured at most this long before.
+    pub dedup_window: Option<Duration>,
 }
 
 impl MonitorConfig {
+    /// How long the capture runs, including the lead-in and lead-out.
+    pub fn capture_duration(&self) -> Duration {
+        self.lead_in + self.duration + self.lead_out
+    }
+
     /// Start monitoring traffic.
-    pub async fn start(self: Self, hosts: &Hosts) -> anyhow::Result<Monitor> {
+    pub async fn start(self, hosts: &Hosts) -> anyhow::Result<Monitor> {
         if let Some(output_path) = &self.output_path {
             fs::create_dir_all(output_path)
                 .await
@@ -54,80 +100,33 @@ impl MonitorConfig {
         let connected_hosts = hosts
             .get_many(self.targets.iter().map(|v| v.as_str()))
             .map_err(|missing| anyhow!("no host with id `{missing}`"))?
-            .into_iter()
             .cloned()
             .collect::<Vec<_>>();
 
+        let mut aids = Vec::new();
         if self.set_aids {
-            let h = monitor_hosts
-                .get(0)
-                .context("monitoring requires at least one monitor host")?;
-            debug!(host = h.id, "Listening for AIDs");
-
-            // Set up the actual capture that will find te association ids.
-            let mut aid_capture = h
-                .session
-                .command("sudo")
-                .args([
-                    "tshark",
-                    "-T",
-                    "fields",
-                    "--interface",
-                    "mon0",
-                    // Return only the association ID.
-                    "-e",
-                    "wlan.fixed.aid",
-                    // Filter out all packets that arent "association response" or packets in a
-                    // different BSS.
-                    "-Y",
-                    &format!(
-                        "wlan.fc.type_subtype == 0x0001 && wlan.bssid == {}",
-                        self.bssid
-                    ),
-                    "--autostop",
-                    "duration:10",
-                ])
-                .stdin(Stdio::null())
-                .stdout(Stdio::piped())
-                .stderr(Stdio::inherit())
-                .spawn()
-                .await
-                .context("failed to start AID monitor capture")?;
-
-            // Connect all the non monitor hosts to the AP so the monitor can find their AID.
-            let mut connection_join_set = JoinSet::new();
-            for connected_host in connected_hosts {
-                let ssid = self.ssid.clone();
-                connection_join_set
-                    .spawn(async move { connected_host.associate(&ssid, None).await });
-            }
-            // Ensure all the nodes have successfully associated to the network.
-            for result in connection_join_set.join_all().await {
-                result?;
-            }
-
-            let mut aids = String::new();
-            aid_capture
-                .stdout()
-                .as_mut()
-                .expect("stdout was previously set to Stdio::piped()")
-                .read_to_string(&mut aids)
-                .await
-                .context("failed to read AID capture output to string")?;
-            _ = aid_capture.disconnect().await;
-
-            // Parse the tshark output into the individual AIDs.
-            let aids = aids
-                .lines()
-                .map(|v| v.strip_prefix("0x").unwrap_or(v))
-                .map(|v| u16::from_str_radix(v, 16))
-                .try_fold(Vec::new(), |mut acc, next| {
-                    acc.push(next?);
-                    anyhow::Result::<_>::Ok(acc)
-                })
-                .context("could not parse association ID")?;
-
-            debug!("Got {} aids: {:?}", aids.len(), aids);
+            aids = match self.known_aids.clone() {
+                Some(mut aids) => {
+                    debug!("Reusing {} previously discovered aids", aids.len());
+                    // Targets that are not connected were not there when the AIDs were
+                    // discovered, so only those are associated to discover theirs.
+                    let mut added = Vec::new();
+                    for host in connected_hosts {
+                        let ssid = host
+                            .connected_ssid()
+                            .await
+                            .context("failed to check association")?;
+                        if ssid.as_deref() != Some(self.ssid.as_str()) {
+                            added.push(host);
+                        }
+                    }
+                    if !added.is_empty() {
+                        aids.extend(self.discover_aids(&monitor_hosts, added).await?);
+                    }
+                    aids
+                }
+                None => self.discover_aids(&monitor_hosts, connected_hosts).await?,
+            };
 
             // Each monitor should ideally have a different AID to sniff different traffic.
             if aids.len() < self.monitors.len() {
@@ -137,63 +136,17 @@ impl MonitorConfig {
                     aids.len()
                 );
             }
-
-            for (aid, host) in aids.iter().zip(monitor_hosts.iter()) {
-                debug!(
-                    host = host.id,
-                    aid, "Changing association ID on monitor host"
-                );
-                match host.extra_data.wifi_driver.as_ref().map(|s| s.as_str()) {
-                    Some("iwlwifi") => iwlwifi::set_association_id(&host, *aid, &self.bssid)
-                        .await
-                        .context("failed to set AID")?,
-                    other => {
-                        anyhow::bail!(
-                            "cannot set association ID for unsupported driver ({}) on host {}",
-                            other.unwrap_or("unknown"),
-                            host.id,
-                        );
-                    }
-                }
-            }
         }
 
-        // Adjust the monitor intefaces to listen on the right frequency + bandwidth.
+        // Set the AIDs and adjust the monitor intefaces to listen on the right frequency +
+        // bandwidth.
+        let capture_start = timing::phase(TimedPhase::CaptureStart);
         let mut tasks = JoinSet::new();
-        monitor_hosts.iter().cloned().for_each(|h| {
-            tasks.spawn(async move {
-                let res = h
-                    .session
-                    .command("sudo")
-                    .args([
-                        "iw",
-                        "dev",
-                        "mon0",
-                        "set",
-                        "freq",
-                        &format!("{}", self.frequency),
-                        &format!("{}MHz", self.bandwidth),
-                    ])
-                    .stdout(Stdio::null())
-                    .stderr(Stdio::piped())
-                    .output()
-                    .await;
-                match res {
-                    Ok(v) if v.status.success() => Ok(v),
-                    Ok(v) => {
-                        // The command returned with an error.
-                        error!(
-                            host = h.id,
-                            "Setting frequecy on monitor interface failed with status code `{}` and stderr `{}`",
-                            v.status,
-                            String::from_utf8_lossy(&v.stderr),
-                        );
-                        Err(anyhow!("command exited with status code {}", v.status))
-                    }
-                    Err(err) => Err(err).context("command failed"),
-                }
-            });
-        });
+        for (i, host) in monitor_hosts.iter().cloned().enumerate() {
+            let aid = aids.get(i).copied();
+            let config = self.tuning();
+            tasks.spawn(async move { config.tune(&host, aid).await });
+        }
         if let Some(err) = tasks
             .join_all()
             .await
@@ -201,8 +154,28 @@ impl MonitorConfig {
             .filter_map(|result| result.err())
             .next()
         {
-            return Err(err)
-                .context("could not change frequency and bandwidth of monitor interface");
+            return Err(err).context("could not tune monitor interface");
+        }
+
+        // The clocks are measured before capturing, as the hosts are busy afterwards.
+        let mut clock_offsets = BTreeMap::new();
+        if self.merge_captures {
+            let mut tasks = JoinSet::new();
+            for host in monitor_hosts.iter().cloned() {
+                tasks.spawn(async move { (clock_offset(&host).await, host) });
+            }
+            for (result, host) in tasks.join_all().await {
+                match result {
+                    Ok(offset) => {
+                        debug!(host = host.id, "Clock is {offset:.6}s ahead");
+                        clock_offsets.insert(host.id.clone(), offset);
+                    }
+                    Err(err) => warn!(
+                        host = host.id,
+                        "Could not measure clock, its capture is merged uncorrected: {err:#}"
+                    ),
+                }
+            }
         }
 
         // Start the capture on all the monitor hosts.
@@ -211,47 +184,854 @@ impl MonitorConfig {
             "Starting monitor with {} monitor hosts",
             monitor_hosts.len()
         );
-        for monitor_host in monitor_hosts {
+        let capture_duration = self.capture_duration();
+        info!(
+            "Capturing for {:.1}s: {:.1}s lead-in, {:.1}s of measured traffic and {:.1}s lead-out",
+            capture_duration.as_secs_f64(),
+            self.lead_in.as_secs_f64(),
+            self.duration.as_secs_f64(),
+            self.lead_out.as_secs_f64()
+        );
+        for monitor_host in monitor_hosts.iter().cloned() {
             let output_path = self.output_path.clone();
+            let filter = self.filter.clone();
             captures.spawn(async move {
-                monitor_host
+                let start = SystemTime::now();
+                metrics::set_capturing(&monitor_host.id, true);
+                let result = monitor_host
                     .capture(&CaptureConfig {
                         interface: "mon0".to_string(),
-                        stop_condition: StopCondition::Duration(self.duration),
-                        output_path: output_path
-                            .map(|v| v.join(&monitor_host.id).with_extension("pcapng")),
+                        stop_condition: StopCondition::Duration(capture_duration),
+                        output_path: output_path.map(|v| host_file(&v, &monitor_host.id, "pcapng")),
+                        filter,
                     })
-                    .await
-                    .map(|res| (monitor_host.id.clone(), res))
+                    .await;
+                metrics::set_capturing(&monitor_host.id, false);
+                if let Ok((_, stats)) = &result {
+                    metrics::add_capture_packets(
+                        &monitor_host.id,
+                        stats.packets.unwrap_or(0),
+                        stats.dropped.unwrap_or(0),
+                    );
+                }
+                CaptureTask {
+                    host: monitor_host.id.clone(),
+                    start,
+                    end: SystemTime::now(),
+                    result,
+                }
+            });
+        }
+        capture_start.end();
+        progress::enter(Phase::Capturing(capture_duration));
+        Ok(Monitor {
+            captures,
+            monitor_hosts,
+            metadata: MonitorMetadata {
+                schema_version: MonitorMetadata::SCHEMA_VERSION,
+                frequency: self.frequency,
+                bandwidth: self.bandwidth,
+                duration: capture_duration.as_secs_f64(),
+                window: Some(CaptureWindow {
+                    lead_in: self.lead_in.as_secs_f64(),
+                    measurement: self.duration.as_secs_f64(),
+                    lead_out: self.lead_out.as_secs_f64(),
+                }),
+                aids: aids.clone(),
+                captures: Vec::new(),
+                partial: None,
+                clock_offsets: clock_offsets.clone(),
+                merged: None,
+            },
+            merge: self.merge_captures.then_some(MergeOptions {
+                clock_offsets,
+                dedup_window: self.dedup_window,
+            }),
+            output_path: self.output_path,
+            aids,
+        })
+    }
+
+    /// How the monitor hosts are configured before they start capturing.
+    pub fn tuning(&self) -> MonitorTuning {
+        MonitorTuning {
+            bssid: self.bssid,
+            frequency: self.frequency,
+            bandwidth: self.bandwidth,
+        }
+    }
+
+    /// Associate the targets to the network while listening for association responses on the
+    /// first monitor host, returning the association IDs that were handed out.
+    ///
+    /// If the capture ends before the targets are associated, it is restarted once for the
+    /// targets that were not observed yet.
+    pub async fn discover_aids(
+        &self,
+        monitor_hosts: &[Arc<Host>],
+        connected_hosts: Vec<Arc<Host>>,
+    ) -> anyhow::Result<Vec<Aid>> {
+        let h = monitor_hosts
+            .first()
+            .context("monitoring requires at least one monitor host")?;
+        let bssid = self
+            .bssid
+            .context("discovering association IDs requires the BSSID")?;
+        debug!(host = h.id, "Listening for AIDs");
+        progress::enter(Phase::AidDiscovery);
+        let _timing = timing::phase(TimedPhase::AidDiscovery);
+
+        // The address of a target recognizes its association response.
+        let mut targets = Vec::with_capacity(connected_hosts.len());
+        for host in &connected_hosts {
+            let addr = match host.extra_data.interface_name() {
+                Some(ifname) => match wifi::interface_info(host, ifname).await {
+                    Ok(info) => info.addr,
+                    Err(err) => {
+                        warn!(host = host.id, "Could not get the MAC address: {err:#}");
+                        None
+                    }
+                },
+                None => None,
+            };
+            targets.push(AidTarget {
+                host: host.id.clone(),
+                addr,
             });
         }
-        Ok(Monitor { captures })
+
+        let aids = discover_with_restart(&targets, |pending| {
+            let hosts = connected_hosts
+                .iter()
+                .filter(|host| pending.contains(&host.id))
+                .cloned()
+                .collect();
+            self.capture_aids(h, bssid, hosts)
+        })
+        .await?;
+
+        debug!("Got {} aids: {:?}", aids.len(), aids);
+        Ok(aids)
+    }
+
+    /// Associate `connected_hosts` to the network while capturing the association responses on
+    /// `h`.
+    async fn capture_aids(
+        &self,
+        h: &Arc<Host>,
+        bssid: MacAddr,
+        connected_hosts: Vec<Arc<Host>>,
+    ) -> anyhow::Result<AidCapture> {
+        // Set up the actual capture that will find te association ids.
+        let command = aid_capture_command(bssid);
+        let aid_capture = h
+            .spawn_daemon(command)
+            .await
+            .context("failed to start AID monitor capture")?;
+
+        // Connect all the non monitor hosts to the AP so the monitor can find their AID. If one
+        // fails, dropping the capture stops it.
+        let mut connection_join_set = JoinSet::new();
+        for connected_host in connected_hosts {
+            let ssid = self.ssid.clone();
+            connection_join_set.spawn(timing::inherit(async move {
+                let _timing = timing::host_phase(TimedPhase::Associate, &connected_host.id);
+                connected_host.associate(&ssid, None).await
+            }));
+        }
+        // Ensure all the nodes have successfully associated to the network.
+        for result in connection_join_set.join_all().await {
+            result?;
+        }
+        let ended_early = aid_capture.is_finished();
+
+        let output = aid_capture
+            .wait_timeout(AID_CAPTURE_TIMEOUT)
+            .await
+            .context("AID capture did not stop by itself")??;
+        let failure = if ended_early || !output.status.success() {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            error!(host = h.id, "AID capture failed:\n{}", stderr.trim_end());
+            let when = if ended_early {
+                "before the targets were associated"
+            } else {
+                "while the targets associated"
+            };
+            Some(format!(
+                "tshark on `{}` exited {when} with {}",
+                h.id, output.status
+            ))
+        } else {
+            None
+        };
+        let aids = String::from_utf8(output.stdout).context("AID capture output is not UTF-8")?;
+        Ok(AidCapture {
+            responses: parse_aid_responses(&aids)?,
+            failure,
+        })
+    }
+}
+
+/// The capture printing the station and AID of every association response in the BSS `bssid`.
+fn aid_capture_command(bssid: MacAddr) -> RemoteCmd {
+    RemoteCmd::new("sudo").args([
+        "tshark",
+        "-T",
+        "fields",
+        "--interface",
+        "mon0",
+        // Return the station and the association ID it was given.
+        "-e",
+        "wlan.da",
+        "-e",
+        "wlan.fixed.aid",
+        // Filter out all packets that arent "association response" or packets in a
+        // different BSS.
+        "-Y",
+        &format!(
+            "wlan.fc.type_subtype == 0x0001 && {}",
+            analysis::bss_filter(bssid)
+        ),
+        "--autostop",
+        "duration:10",
+    ])
+}
+
+/// How often the AID discovery capture is started before giving up.
+const AID_CAPTURE_ATTEMPTS: usize = 2;
+
+/// A host that is associated to discover its association ID.
+#[derive(Debug, Clone)]
+pub struct AidTarget {
+    pub host: HostId,
+    /// The MAC address of its wireless interface, `None` if it could not be looked up.
+    pub addr: Option<MacAddr>,
+}
+
+/// What a single AID discovery capture observed.
+#[derive(Debug, Clone, Default)]
+pub struct AidCapture {
+    /// The stations that were sent an association response with the AID they were given, in the
+    /// order they were sent.
+    pub responses: Vec<(MacAddr, Aid)>,
+    /// Why the capture ended before the targets were associated, if it did.
+    pub failure: Option<String>,
+}
+
+/// Discover the AIDs of `targets` with `capture`, which associates the targets it is given while
+/// capturing. A capture that ends early is started once more for the targets that were not
+/// observed yet. Returns the AIDs in the order they were handed out.
+pub async fn discover_with_restart<F, Fut>(
+    targets: &[AidTarget],
+    mut capture: F,
+) -> anyhow::Result<Vec<Aid>>
+where
+    F: FnMut(Vec<HostId>) -> Fut,
+    Fut: Future<Output = anyhow::Result<AidCapture>>,
+{
+    let mut responses: Vec<(MacAddr, Aid)> = Vec::new();
+    let mut pending: Vec<HostId> = targets.iter().map(|t| t.host.clone()).collect();
+    for attempt in 1..=AID_CAPTURE_ATTEMPTS {
+        let result = capture(pending).await?;
+        for (station, aid) in result.responses {
+            add_response(&mut responses, station, aid);
+        }
+        pending = unobserved(targets, &responses);
+        let Some(failure) = result.failure else {
+            break;
+        };
+        if pending.is_empty() {
+            warn!("AID capture ended early, but all targets were observed: {failure}");
+            break;
+        }
+        if attempt == AID_CAPTURE_ATTEMPTS {
+            let observed: Vec<_> = targets
+                .iter()
+                .map(|t| &t.host)
+                .filter(|host| !pending.contains(host))
+                .collect();
+            anyhow::bail!(
+                "AID discovery failed after {attempt} attempts, {failure}: observed {observed:?}, \
+                 missing {pending:?}"
+            );
+        }
+        warn!("AID capture ended early, {failure}. Restarting it for {pending:?}");
+    }
+    Ok(responses.into_iter().map(|(_, aid)| aid).collect())
+}
+
+/// The targets without an association response. A target whose address is unknown counts as
+/// observed for every response to a station that is not one of the other targets.
+fn unobserved(targets: &[AidTarget], responses: &[(MacAddr, Aid)]) -> Vec<HostId> {
+    let known: Vec<MacAddr> = targets.iter().filter_map(|t| t.addr).collect();
+    let mut unknown = responses
+        .iter()
+        .filter(|(station, _)| !known.contains(station))
+        .count();
+    targets
+        .iter()
+        .filter(|t| match t.addr {
+            Some(addr) => !responses.iter().any(|(station, _)| *station == addr),
+            None if unknown > 0 => {
+                unknown -= 1;
+                false
+            }
+            None => true,
+        })
+        .map(|t| t.host.clone())
+        .collect()
+}
+
+/// Add the association response to `station`, replacing an earlier one to it: a station that
+/// associates again is given a new AID, and a retransmitted response is the same frame again.
+fn add_response(responses: &mut Vec<(MacAddr, Aid)>, station: MacAddr, aid: Aid) {
+    responses.retain(|(s, _)| *s != station);
+    responses.push((station, aid));
+}
+
+/// Parse the association responses from the AID capture, one station and AID per line, keeping
+/// the latest response to every station. Responses with an AID an access point can not hand out
+/// are skipped.
+pub fn parse_aid_responses(output: &str) -> anyhow::Result<Vec<(MacAddr, Aid)>> {
+    let mut responses = Vec::new();
+    for line in output.lines().filter(|line| !line.trim().is_empty()) {
+        let (station, aid) = line
+            .split_once('\t')
+            .with_context(|| format!("expected a station and AID, got `{line}`"))?;
+        let station = station.parse().map_err(|err: String| anyhow!(err))?;
+        let aid = aid.trim();
+        let hex = aid
+            .strip_prefix("0x")
+            .or_else(|| aid.strip_prefix("0X"))
+            .unwrap_or(aid);
+        let value = u16::from_str_radix(hex, 16)
+            .with_context(|| format!("could not parse association ID `{aid}`"))?;
+        // The two most significant bits of the field are reserved, and set by access points.
+        match Aid::new(value & 0x3fff) {
+            Some(aid) => add_response(&mut responses, station, aid),
+            None => warn!(
+                %station,
+                "Ignoring association response with invalid AID `{aid}`, expected 1 to {}",
+                Aid::MAX
+            ),
+        }
+    }
+    Ok(responses)
+}
+
+/// What a monitor host is configured with before capturing.
+pub struct MonitorTuning {
+    pub bssid: Option<MacAddr>,
+    pub frequency: u32,
+    pub bandwidth: u32,
+}
+
+impl MonitorTuning {
+    /// Set the association ID of the monitor interface of `host`, if given, and switch it to the
+    /// channel of the network.
+    pub async fn tune(&self, host: &Host, aid: Option<Aid>) -> anyhow::Result<()> {
+        if let Some(aid) = aid {
+            debug!(
+                host = host.id,
+                %aid, "Changing association ID on monitor host"
+            );
+            match host.extra_data.wifi_driver.as_deref() {
+                Some("iwlwifi") => {
+                    let bssid = self.bssid.context("setting the AID requires the BSSID")?;
+                    iwlwifi::set_association_id(host, aid, bssid)
+                        .await
+                        .context("failed to set AID")?
+                }
+                other => {
+                    anyhow::bail!(
+                        "cannot set association ID for unsupported driver ({}) on host {}",
+                        other.unwrap_or("unknown"),
+                        host.id,
+                    );
+                }
+            }
+        }
+        wifi::set_monitor_channel(host, "mon0", self.frequency, self.bandwidth)
+            .await
+            .context("could not change frequency and bandwidth of monitor interface")
+    }
+}
+
+/// The outcome of the capture on a single monitor host.
+struct CaptureTask {
+    host: HostId,
+    start: SystemTime,
+    end: SystemTime,
+    result: anyhow::Result<(Capture, CaptureStats)>,
+}
+
+/// Information about a monitor run, written to `monitor.ron` in the output directory.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MonitorMetadata {
+    #[serde(default)]
+    pub schema_version: SchemaVersion,
+    /// Frequency of the channel in MHz.
+    pub frequency: u32,
+    /// Bandwidth of the channel in MHz.
+    pub bandwidth: u32,
+    /// The configured capture duration in seconds.
+    pub duration: f64,
+    /// How the capture duration is made up of the measured traffic and the time around it.
+    /// `None` for captures from before it was recorded.
+    #[serde(default)]
+    pub window: Option<CaptureWindow>,
+    /// The association IDs assigned to the monitors, in the same order as the monitors.
+    pub aids: Vec<Aid>,
+    pub captures: Vec<CaptureMetadata>,
+    /// Set to the reason the captures were stopped early, if they were.
+    pub partial: Option<String>,
+    /// How far the clock of each monitor host was ahead of the controller in seconds, measured
+    /// to merge the captures.
+    #[serde(default)]
+    pub clock_offsets: BTreeMap<HostId, f64>,
+    /// What was written to `merged.pcapng`, if the captures were merged.
+    pub merged: Option<MergeStats>,
+}
+
+/// The part of a capture in which the measured traffic was scheduled, in seconds. The traffic
+/// starts `lead_in` after the capture started and lasts `measurement`, after which the capture
+/// continues for `lead_out`.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct CaptureWindow {
+    pub lead_in: f64,
+    pub measurement: f64,
+    pub lead_out: f64,
+}
+
+impl MonitorMetadata {
+    /// How far the clock of monitor `id` is ahead of the controller in seconds. If it was not
+    /// measured when the capture started it is measured now, and taken as 0 if that fails.
+    pub async fn clock_offset(&self, hosts: &Hosts, id: &str) -> f64 {
+        if let Some(offset) = self.clock_offsets.get(id) {
+            return *offset;
+        }
+        let Some(host) = hosts.get(id) else {
+            return 0.0;
+        };
+        match clock_offset(host).await {
+            Ok(offset) => offset,
+            Err(err) => {
+                warn!(
+                    host = id,
+                    "Could not measure clock, assuming it is in sync: {err:#}"
+                );
+                0.0
+            }
+        }
     }
 }
 
+/// Information about the capture of a single monitor host.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CaptureMetadata {
+    pub host: HostId,
+    /// Controller time at which the capture was started, in seconds since the UNIX epoch.
+    pub start: f64,
+    /// Controller time at which the capture finished, in seconds since the UNIX epoch.
+    pub end: f64,
+    /// Statistics about the capture, if it completed.
+    pub stats: Option<CaptureStats>,
+    /// The error that occurred during the capture, if any.
+    pub error: Option<String>,
+}
+
+/// The completed captures of a monitor.
+pub struct MonitorOutput {
+    pub captures: Vec<(HostId, Capture)>,
+    pub metadata: MonitorMetadata,
+}
+
 pub struct Monitor {
-    captures: JoinSet<anyhow::Result<(HostId, Capture)>>,
+    captures: JoinSet<CaptureTask>,
+    monitor_hosts: Vec<Arc<Host>>,
+    metadata: MonitorMetadata,
+    merge: Option<MergeOptions>,
+    output_path: Option<PathBuf>,
+    aids: Vec<Aid>,
 }
 
 impl Monitor {
+    /// The association IDs that were assigned to the monitors, if any.
+    pub fn aids(&self) -> &[Aid] {
+        &self.aids
+    }
+
     /// Waits for all the captures to complete and returns their results.
-    pub async fn wait(self: Self) -> anyhow::Result<Vec<(HostId, Capture)>> {
-        let result =
-            self.captures
-                .join_all()
-                .await
-                .into_iter()
-                .try_fold(Vec::new(), |mut acc, item| {
-                    acc.push(item.context("capture returned an error")?);
-                    anyhow::Result::<_>::Ok(acc)
-                })?;
+    pub async fn wait(self) -> anyhow::Result<MonitorOutput> {
+        let _timing = timing::phase(TimedPhase::CaptureCollect);
+        let result = self.collect(Vec::new()).await?;
+        info!("Monitor complete");
+        Ok(result)
+    }
 
+    /// Waits for all the captures to complete like [Monitor::wait], unless `cancel` is cancelled
+    /// first. Then the captures are stopped and collected like [Monitor::stop_and_collect], and
+    /// [Aborted] is returned.
+    pub async fn wait_or_stop(
+        mut self,
+        cancel: &CancellationToken,
+    ) -> anyhow::Result<MonitorOutput> {
+        let _timing = timing::phase(TimedPhase::CaptureCollect);
+        let mut done = Vec::new();
+        loop {
+            select! {
+                joined = self.captures.join_next() => match joined {
+                    Some(task) => done.push(task.context("capture task failed")?),
+                    None => break,
+                },
+                () = cancel.cancelled() => {
+                    let reason = cancel.reason().unwrap_or(Reason::User);
+                    if let Err(err) = self.stop(reason.to_string(), done).await {
+                        warn!("Could not collect the captures: {err:?}");
+                    }
+                    return Err(Aborted.into());
+                }
+            }
+        }
+        let result = self.collect(done).await?;
         info!("Monitor complete");
         Ok(result)
     }
 
+    /// Stops the captures before their configured duration, keeping what has been captured so
+    /// far. The reason is recorded in the monitor metadata.
+    pub async fn stop_and_collect(
+        self,
+        reason: impl Into<String>,
+    ) -> anyhow::Result<MonitorOutput> {
+        self.stop(reason.into(), Vec::new()).await
+    }
+
+    /// Stops the captures that did not complete yet, and collects them along with the completed
+    /// captures in `done`.
+    async fn stop(
+        mut self,
+        reason: String,
+        done: Vec<CaptureTask>,
+    ) -> anyhow::Result<MonitorOutput> {
+        let _timing = timing::phase(TimedPhase::CaptureCollect);
+        info!("Stopping monitor early: {reason}");
+        self.metadata.partial = Some(reason);
+
+        let mut tasks = JoinSet::new();
+        for host in self.monitor_hosts.iter().cloned() {
+            tasks.spawn(async move { (host.stop_capture("mon0").await, host) });
+        }
+        for (result, host) in tasks.join_all().await {
+            if let Err(err) = result {
+                warn!(host = host.id, "Could not stop capture: {err:?}");
+            }
+        }
+
+        self.collect(done).await
+    }
+
+    /// Waits for the capture tasks that did not complete yet, and writes the monitor metadata
+    /// for those and the completed captures in `done`.
+    async fn collect(mut self, mut done: Vec<CaptureTask>) -> anyhow::Result<MonitorOutput> {
+        let mut result = Ok(Vec::new());
+        done.extend(std::mem::take(&mut self.captures).join_all().await);
+        for task in done {
+            let unix = |t: SystemTime| {
+                t.duration_since(SystemTime::UNIX_EPOCH)
+                    .unwrap_or_default()
+                    .as_secs_f64()
+            };
+            self.metadata.captures.push(CaptureMetadata {
+                host: task.host.clone(),
+                start: unix(task.start),
+                end: unix(task.end),
+                stats: task.result.as_ref().ok().map(|(_, stats)| stats.clone()),
+                error: task.result.as_ref().err().map(|err| format!("{err:#}")),
+            });
+
+            match (&mut result, task.result) {
+                (Ok(captures), Ok((capture, _))) => captures.push((task.host, capture)),
+                (Ok(_), Err(err)) => result = Err(err.context("capture returned an error")),
+                (Err(_), _) => {}
+            }
+        }
+
+        match (&result, self.merge.take(), &self.output_path) {
+            (Ok(captures), Some(options), Some(output_path)) => {
+                let hosts = captures.iter().map(|(host, _)| host.clone()).collect();
+                match merge_files(output_path, hosts, options).await {
+                    Ok(stats) => {
+                        info!(
+                            "Merged {} packets into merged.pcapng, dropping {} duplicates",
+                            stats.packets, stats.duplicates
+                        );
+                        self.metadata.merged = Some(stats);
+                    }
+                    Err(err) => warn!("Could not merge the captures: {err:#}"),
+                }
+            }
+            (Ok(_), Some(_), None) => warn!("Not merging the captures, they are not saved"),
+            _ => {}
+        }
+
+        if let Some(output_path) = &self.output_path {
+            let metadata = to_string_pretty(&self.metadata, PrettyConfig::new())
+                .context("failed to serialize monitor metadata")?;
+            fs::write(output_path.join("monitor.ron"), metadata)
+                .await
+                .context("failed to write monitor metadata")?;
+        }
+
+        Ok(MonitorOutput {
+            captures: result?,
+            metadata: self.metadata,
+        })
+    }
+
     /// Immediately stops the captures, throwing away the results.
-    pub fn abort(self: &mut Self) {
+    pub fn abort(&mut self) {
         self.captures.abort_all();
     }
 }
+
+/// Merge the saved captures of `hosts` in `output_path` into `merged.pcapng`.
+async fn merge_files(
+    output_path: &Path,
+    hosts: Vec<HostId>,
+    options: MergeOptions,
+) -> anyhow::Result<MergeStats> {
+    let output_path = output_path.to_owned();
+    tokio::task::spawn_blocking(move || {
+        let inputs = hosts
+            .into_iter()
+            .map(|host| {
+                let path = host_file(&output_path, &host, "pcapng");
+                let file = std::fs::File::open(&path)
+                    .with_context(|| format!("could not open {}", path.display()))?;
+                Ok((host, CaptureReader::File(file)))
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        let path = output_path.join("merged.pcapng");
+        let file = std::fs::File::create(&path)
+            .with_context(|| format!("could not create {}", path.display()))?;
+        capture::merge(inputs, std::io::BufWriter::new(file), &options)
+    })
+    .await
+    .context("merge task panicked")?
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mac(addr: &str) -> MacAddr {
+        addr.parse().unwrap()
+    }
+
+    fn aid(value: u16) -> Aid {
+        Aid::new(value).unwrap()
+    }
+
+    #[test]
+    fn aid_responses_from_tshark() {
+        let output = "\
+            02:00:00:00:00:01\t0xc001\n\
+            02:00:00:00:00:02\t0xC002\n\
+            02:00:00:00:00:03\t0x0003\n";
+        assert_eq!(
+            parse_aid_responses(output).unwrap(),
+            [
+                (mac("02:00:00:00:00:01"), aid(1)),
+                (mac("02:00:00:00:00:02"), aid(2)),
+                (mac("02:00:00:00:00:03"), aid(3)),
+            ]
+        );
+    }
+
+    #[test]
+    fn aid_responses_without_output() {
+        assert_eq!(parse_aid_responses("").unwrap(), []);
+        assert_eq!(parse_aid_responses("\n  \n").unwrap(), []);
+    }
+
+    #[test]
+    fn aid_response_prefixes() {
+        let output = "\
+            02:00:00:00:00:01\t0x0a\n\
+            02:00:00:00:00:02\t0X0B\n\
+            02:00:00:00:00:03\t0c\n";
+        assert_eq!(
+            parse_aid_responses(output).unwrap(),
+            [
+                (mac("02:00:00:00:00:01"), aid(10)),
+                (mac("02:00:00:00:00:02"), aid(11)),
+                (mac("02:00:00:00:00:03"), aid(12)),
+            ]
+        );
+    }
+
+    #[test]
+    fn aid_responses_keep_the_latest_per_station() {
+        // A retransmitted response, and a station that associated again and got a new AID.
+        let output = "\
+            02:00:00:00:00:01\t0xc001\n\
+            02:00:00:00:00:01\t0xc001\n\
+            02:00:00:00:00:02\t0xc002\n\
+            02:00:00:00:00:01\t0xc003\n";
+        assert_eq!(
+            parse_aid_responses(output).unwrap(),
+            [
+                (mac("02:00:00:00:00:02"), aid(2)),
+                (mac("02:00:00:00:00:01"), aid(3)),
+            ]
+        );
+    }
+
+    #[test]
+    fn aid_responses_outside_the_valid_range_are_skipped() {
+        let output = "\
+            02:00:00:00:00:01\t0xc000\n\
+            02:00:00:00:00:02\t0xc7d7\n\
+            02:00:00:00:00:03\t0xc7d8\n\
+            02:00:00:00:00:04\t0x3fff\n";
+        // 0x7d7 is 2007, the highest AID. The reserved bits do not make 0 or 2008 valid.
+        assert_eq!(
+            parse_aid_responses(output).unwrap(),
+            [(mac("02:00:00:00:00:02"), aid(2007))]
+        );
+    }
+
+    #[test]
+    fn aid_response_reserved_bits_are_ignored() {
+        for value in ["0x0005", "0x4005", "0x8005", "0xc005"] {
+            let output = format!("02:00:00:00:00:01\t{value}\n");
+            assert_eq!(
+                parse_aid_responses(&output).unwrap(),
+                [(mac("02:00:00:00:00:01"), aid(5))],
+                "{value}"
+            );
+        }
+    }
+
+    #[test]
+    fn malformed_aid_responses_fail() {
+        assert!(parse_aid_responses("02:00:00:00:00:01 0xc001\n").is_err());
+        assert!(parse_aid_responses("02:00:00:00:00:01\t0xzz\n").is_err());
+        assert!(parse_aid_responses("02:00:00:00:00:01\t0x10000\n").is_err());
+        assert!(parse_aid_responses("not a station\t0xc001\n").is_err());
+    }
+
+    fn target(host: &str, addr: Option<&str>) -> AidTarget {
+        AidTarget {
+            host: host.to_string(),
+            addr: addr.map(mac),
+        }
+    }
+
+    /// Run the discovery against `captures`, returning its result and the targets every capture
+    /// was started for.
+    async fn discover(
+        targets: &[AidTarget],
+        captures: Vec<AidCapture>,
+    ) -> (anyhow::Result<Vec<Aid>>, Vec<Vec<HostId>>) {
+        let mut captures = captures.into_iter();
+        let mut started = Vec::new();
+        let result = discover_with_restart(targets, |pending| {
+            started.push(pending);
+            let capture = captures.next().expect("no more captures");
+            async move { Ok(capture) }
+        })
+        .await;
+        (result, started)
+    }
+
+    #[tokio::test]
+    async fn aid_discovery_ending_early_with_all_targets_observed() {
+        let targets = [
+            target("a", Some("02:00:00:00:00:01")),
+            target("b", Some("02:00:00:00:00:02")),
+        ];
+        let capture = AidCapture {
+            responses: vec![
+                (mac("02:00:00:00:00:02"), aid(7)),
+                (mac("02:00:00:00:00:01"), aid(8)),
+            ],
+            failure: Some("tshark exited early".to_string()),
+        };
+        let (result, started) = discover(&targets, vec![capture]).await;
+        assert_eq!(result.unwrap(), [aid(7), aid(8)]);
+        assert_eq!(started, [["a", "b"]]);
+    }
+
+    #[tokio::test]
+    async fn aid_discovery_restarts_for_missing_targets() {
+        let targets = [
+            target("a", Some("02:00:00:00:00:01")),
+            target("b", Some("02:00:00:00:00:02")),
+            target("c", None),
+        ];
+        let captures = vec![
+            AidCapture {
+                responses: vec![(mac("02:00:00:00:00:01"), aid(1))],
+                failure: Some("tshark exited early".to_string()),
+            },
+            AidCapture {
+                // The station of `c` is unknown, so any other station counts for it.
+                responses: vec![
+                    (mac("02:00:00:00:00:02"), aid(2)),
+                    (mac("02:00:00:00:00:09"), aid(3)),
+                ],
+                failure: None,
+            },
+        ];
+        let (result, started) = discover(&targets, captures).await;
+        assert_eq!(result.unwrap(), [aid(1), aid(2), aid(3)]);
+        assert_eq!(started, [vec!["a", "b", "c"], vec!["b", "c"]]);
+    }
+
+    #[tokio::test]
+    async fn aid_discovery_fails_after_two_early_exits() {
+        let targets = [
+            target("a", Some("02:00:00:00:00:01")),
+            target("b", Some("02:00:00:00:00:02")),
+        ];
+        let early = |responses| AidCapture {
+            responses,
+            failure: Some("tshark exited early".to_string()),
+        };
+        let captures = vec![
+            early(vec![(mac("02:00:00:00:00:01"), aid(1))]),
+            early(Vec::new()),
+        ];
+        let (result, started) = discover(&targets, captures).await;
+        let err = result.unwrap_err().to_string();
+        assert!(err.contains("after 2 attempts"), "{err}");
+        assert!(err.contains(r#"observed ["a"], missing ["b"]"#), "{err}");
+        assert_eq!(started, [vec!["a", "b"], vec!["b"]]);
+    }
+
+    #[test]
+    fn aid_capture_filters_on_the_bssid() {
+        let command = aid_capture_command(mac("02:AB:00:00:00:01"));
+        assert_eq!(
+            command.to_string(),
+            "sudo tshark -T fields --interface mon0 -e wlan.da -e wlan.fixed.aid -Y \
+             'wlan.fc.type_subtype == 0x0001 && wlan.bssid == 02:ab:00:00:00:01' \
+             --autostop duration:10"
+        );
+    }
+
+    #[test]
+    fn hostile_bssids_are_rejected() {
+        for bssid in [
+            "02:00:00:00:00:01 || 1",
+            "02:00:00:00:00:01' -w /tmp/x",
+            "02:00:00:00:00:0$",
+            "02:00:00:00:00",
+            "02:00:00:00:00:01:02",
+        ] {
+            assert!(bssid.parse::<MacAddr>().is_err(), "{bssid}");
+        }
+    }
+}
@@ -0,0 +1,143 @@
+//! Notifications sent when a run finishes, so long runs do not need to be watched.
+
+use std::{path::Path, time::Duration};
+
+use anyhow::Context;
+use serde::Serialize;
+use tokio::process::Command;
+use tracing::{debug, warn};
+
+/// How a run finished, as passed to the notification command and URL.
+#[derive(Debug, Clone, Serialize)]
+pub struct Notification<'a> {
+    /// The output directory of the run.
+    pub out_dir: &'a Path,
+    /// How the run finished, like `completed` or `failed`.
+    pub status: &'a str,
+    /// How long the run took in seconds.
+    pub duration: f64,
+    /// The name of the script.
+    pub script: &'a str,
+}
+
+/// Where notifications are sent, from `--notify-cmd` and `--notify-url`.
+#[derive(Debug, Clone, Default)]
+pub struct NotifyOptions<'a> {
+    pub command: Option<&'a str>,
+    pub url: Option<&'a str>,
+}
+
+/// How long a notification command or request may take.
+const NOTIFY_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Send `notification` to everything in `options`. Failures are logged, as the run itself is done.
+pub async fn notify(options: &NotifyOptions<'_>, notification: &Notification<'_>) {
+    if let Some(command) = options.command {
+        debug!("Running notification command `{command}`");
+        if let Err(err) = run_command(command, notification).await {
+            warn!("Notification command failed: {err:#}");
+        }
+    }
+    if let Some(url) = options.url {
+        debug!("Sending notification to {url}");
+        if let Err(err) = post(url, notification).await {
+            warn!("Could not send the notification to {url}: {err:#}");
+        }
+    }
+}
+
+/// Run `command` with `sh -c` on the controller, with `OUT_DIR`, `STATUS`, `DURATION` and
+/// `SCRIPT` set to the fields of `notification`.
+pub async fn run_command(command: &str, notification: &Notification<'_>) -> anyhow::Result<()> {
+    let child = Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .env("OUT_DIR", notification.out_dir)
+        .env("STATUS", notification.status)
+        .env("DURATION", format!("{:.0}", notification.duration))
+        .env("SCRIPT", notification.script)
+        .kill_on_drop(true)
+        .status();
+    let status = tokio::time::timeout(NOTIFY_TIMEOUT, child)
+        .await
+        .context("command timed out")?
+        .context("could not run command")?;
+    if !status.success() {
+        anyhow::bail!("command exited with {status}");
+    }
+    Ok(())
+}
+
+/// POST `notification` as a JSON object to `url`.
+#[cfg(feature = "notify-http")]
+pub async fn post(url: &str, notification: &Notification<'_>) -> anyhow::Result<()> {
+    let body = serde_json::to_value(notification).context("failed to serialize notification")?;
+    let url = url.to_string();
+    tokio::task::spawn_blocking(move || {
+        ureq::post(&url)
+            .timeout(NOTIFY_TIMEOUT)
+            .send_json(body)
+            .map(|_| ())
+            .context("request failed")
+    })
+    .await
+    .context("request task failed")?
+}
+
+/// Without the `notify-http` feature there is no HTTP client to send notifications with.
+#[cfg(not(feature = "notify-http"))]
+pub async fn post(_url: &str, _notification: &Notification<'_>) -> anyhow::Result<()> {
+    anyhow::bail!("the controller was built without the `notify-http` feature")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::shell_quote;
+
+    fn notification(out_dir: &Path) -> Notification<'_> {
+        Notification {
+            out_dir,
+            status: "completed",
+            duration: 61.6,
+            script: "iperf",
+        }
+    }
+
+    #[tokio::test]
+    async fn command_gets_the_run_in_its_environment() {
+        let dir = tempfile::tempdir().unwrap();
+        let env = dir.path().join("env");
+        let out_dir = dir.path().join("run $1 'x'");
+        let command = format!(
+            "printf '%s\\n' \"$OUT_DIR\" \"$STATUS\" \"$DURATION\" \"$SCRIPT\" > {}",
+            shell_quote(&env.to_string_lossy())
+        );
+        run_command(&command, &notification(&out_dir))
+            .await
+            .unwrap();
+
+        let written = std::fs::read_to_string(&env).unwrap();
+        assert_eq!(
+            written.lines().collect::<Vec<_>>(),
+            [&*out_dir.to_string_lossy(), "completed", "62", "iperf"]
+        );
+    }
+
+    #[tokio::test]
+    async fn failing_commands_are_errors() {
+        let err = run_command("exit 3", &notification(Path::new("out")))
+            .await
+            .unwrap_err();
+        assert!(format!("{err:#}").contains("exit status: 3"), "{err:#}");
+    }
+
+    #[cfg(not(feature = "notify-http"))]
+    #[tokio::test]
+    async fn urls_need_the_http_feature() {
+        let err = post("http://localhost:1/", &notification(Path::new("out")))
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("notify-http"), "{err}");
+    }
+}
@@ -0,0 +1,144 @@
+//! A JSON document describing how a run went, written with `--outcome-file` or
+//! `--outcome-stdout`, so the controller can be orchestrated without parsing its log.
+
+use std::{collections::BTreeMap, path::Path};
+
+use anyhow::Context;
+use serde::Serialize;
+
+use crate::{
+    command_log::HostErrors,
+    exit::ExitReason,
+    hosts::HostId,
+    scripts::{KeyNumbers, RunReport},
+};
+
+/// The version of the [Outcome] document. It is increased when a field is removed or its meaning
+/// changes, not when a field is added.
+pub const SCHEMA_VERSION: u32 = 1;
+
+/// How a run went.
+#[derive(Debug, Clone, Serialize)]
+pub struct Outcome<'a> {
+    /// Always [SCHEMA_VERSION].
+    pub schema: u32,
+    /// The output directory the results were written to.
+    pub out_dir: &'a Path,
+    /// The name of the script.
+    pub script: &'a str,
+    /// How the script finished: `completed`, `failed`, `aborted` or `timed-out`.
+    pub status: &'static str,
+    /// Why the controller exits without success, `None` if it succeeded.
+    pub exit_reason: Option<ExitReason>,
+    /// The exit code of the controller.
+    pub exit_code: u8,
+    /// The error the run failed with.
+    pub error: Option<String>,
+    /// How long the run took in seconds.
+    pub duration: f64,
+    /// The commands that failed on each host.
+    pub host_errors: &'a BTreeMap<HostId, HostErrors>,
+    /// Key numbers of the results, like `total_throughput` for iperf, if the script provides them.
+    pub summary: &'a KeyNumbers,
+}
+
+impl<'a> Outcome<'a> {
+    pub fn new(report: &'a RunReport, out_dir: &'a Path, script: &'a str) -> Self {
+        let exit_reason = report.result.as_ref().err().map(ExitReason::of);
+        Self {
+            schema: SCHEMA_VERSION,
+            out_dir,
+            script,
+            status: report.status.name(),
+            exit_reason,
+            exit_code: exit_reason.map_or(0, ExitReason::code),
+            error: report.result.as_ref().err().map(|err| format!("{err:#}")),
+            duration: report.duration.as_secs_f64(),
+            host_errors: &report.host_errors,
+            summary: &report.numbers,
+        }
+    }
+
+    /// The document on a single line.
+    pub fn to_json(&self) -> anyhow::Result<String> {
+        serde_json::to_string(self).context("failed to serialize outcome")
+    }
+
+    /// Write the document to `path`, replacing it if it exists.
+    pub fn write(&self, path: &Path) -> anyhow::Result<()> {
+        let mut json = self.to_json()?;
+        json.push('\n');
+        std::fs::write(path, json).with_context(|| format!("could not write {}", path.display()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::{cancel::TimedOut, scripts::iterations::Status};
+
+    fn report(result: anyhow::Result<()>, status: Status) -> RunReport {
+        RunReport {
+            result,
+            status,
+            duration: Duration::from_millis(12_500),
+            host_errors: BTreeMap::new(),
+            numbers: BTreeMap::from([("total_throughput".to_string(), 94.5e6)]),
+        }
+    }
+
+    #[test]
+    fn document_of_a_completed_run() {
+        let report = report(Ok(()), Status::Completed);
+        let outcome = Outcome::new(&report, Path::new("results/run"), "iperf");
+        assert_eq!(
+            outcome.to_json().unwrap(),
+            r#"{"schema":1,"out_dir":"results/run","script":"iperf","status":"completed","exit_reason":null,"exit_code":0,"error":null,"duration":12.5,"host_errors":{},"summary":{"total_throughput":94500000.0}}"#
+        );
+    }
+
+    #[test]
+    fn document_of_a_run_that_timed_out() {
+        let mut report = report(
+            Err(anyhow::Error::new(TimedOut).context("iperf did not finish")),
+            Status::TimedOut,
+        );
+        report.host_errors.insert(
+            "sta1".to_string(),
+            HostErrors {
+                failures: 2,
+                last_error: "exit status 1".to_string(),
+            },
+        );
+        let outcome = Outcome::new(&report, Path::new("out"), "iperf");
+        let json: serde_json::Value = serde_json::from_str(&outcome.to_json().unwrap()).unwrap();
+
+        assert_eq!(json["schema"], 1);
+        assert_eq!(json["status"], "timed-out");
+        assert_eq!(json["exit_reason"], "timeout");
+        assert_eq!(json["exit_code"], 124);
+        assert_eq!(
+            json["error"].as_str().unwrap(),
+            format!("iperf did not finish: {TimedOut}")
+        );
+        assert_eq!(json["host_errors"]["sta1"]["failures"], 2);
+    }
+
+    #[test]
+    fn written_document_is_a_single_line() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("outcome.json");
+        std::fs::write(&path, "an earlier outcome\nwith two lines\n").unwrap();
+        let report = report(Ok(()), Status::Completed);
+        Outcome::new(&report, Path::new("out"), "iperf")
+            .write(&path)
+            .unwrap();
+
+        let written = std::fs::read_to_string(&path).unwrap();
+        assert_eq!(written.lines().count(), 1);
+        assert!(written.starts_with(r#"{"schema":1,"#));
+        assert!(written.ends_with("}\n"));
+    }
+}
@@ -0,0 +1,570 @@
+//! The output directory of a run.
+
+use std::{
+    fs::OpenOptions,
+    io::{ErrorKind, Write},
+    mem::MaybeUninit,
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::Context;
+use clap::ValueEnum;
+use tracing::warn;
+
+/// What to do when the output directory of a run already contains files.
+#[derive(ValueEnum, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum OnExists {
+    /// Refuse to start the run.
+    #[default]
+    Fail,
+    /// Delete the earlier contents before the run starts. Only a directory with the results of
+    /// an earlier run is deleted.
+    Overwrite,
+    /// Write to a sibling directory with a `-2`, `-3`, ... suffix instead.
+    Suffix,
+}
+
+/// The directory a run writes its output to, decided before the run starts so a run is not
+/// wasted on files that already exist.
+#[derive(Debug, Clone)]
+pub struct OutputDir {
+    pub path: PathBuf,
+    /// Whether the earlier contents are deleted when the directory is created.
+    wipe: bool,
+}
+
+/// The default of `--timestamp-format`, like `2025-03-14_15-09-26`.
+pub const DEFAULT_TIMESTAMP_FORMAT: &str = "%Y-%m-%d_%H-%M-%S";
+
+/// The values the placeholders in the output path template are filled in with.
+#[derive(Debug, Clone)]
+pub struct Placeholders<'a> {
+    /// When the run started, for `<timestamp>` and `<date>`.
+    pub time: SystemTime,
+    /// How `<timestamp>` and `<date>` are formatted, see [format_time].
+    pub time_format: &'a str,
+    /// Format the time in UTC rather than the local time zone.
+    pub utc: bool,
+    /// The name of the script, for `<script>`.
+    pub script: &'a str,
+    /// The label given on the command line, for `<label>`.
+    pub label: Option<&'a str>,
+}
+
+/// Fill in the placeholders of an output path template:
+///
+/// * `<timestamp>`, `<date>` - The time the run started, formatted with `--timestamp-format`.
+/// * `<script>` - The name of the script, like `iperf`.
+/// * `<label>` - The label given with `--label`.
+///
+/// Any other placeholder is an error, so a typo does not end up in the directory name.
+///
+/// Two runs started within the same second would get the same directory, so if the template
+/// contains the time and the directory already exists, a `-2`, `-3`, ... suffix is added.
+pub fn expand_path(template: &str, placeholders: &Placeholders) -> anyhow::Result<PathBuf> {
+    let mut path = String::with_capacity(template.len());
+    let mut rest = template;
+    let mut timed = false;
+    while let Some(start) = rest.find('<') {
+        path.push_str(&rest[..start]);
+        let Some(len) = rest[start..].find('>') else {
+            anyhow::bail!("unclosed placeholder in `{template}`");
+        };
+        let name = &rest[start + 1..start + len];
+        match name {
+            "timestamp" | "date" => {
+                let time = format_time(
+                    placeholders.time,
+                    placeholders.time_format,
+                    placeholders.utc,
+                )
+                .context("invalid timestamp format")?;
+                path.push_str(&sanitize(&time));
+                timed = true;
+            }
+            "script" => path.push_str(placeholders.script),
+            "label" => {
+                let Some(label) = placeholders.label else {
+                    anyhow::bail!("the output path contains `<label>`, but no --label was given");
+                };
+                if label.is_empty() || label.contains('/') {
+                    anyhow::bail!("the label `{label}` can not be used in a directory name");
+                }
+                path.push_str(label);
+            }
+            _ => anyhow::bail!(
+                "unknown placeholder `<{name}>` in `{template}`, expected <timestamp>, <date>, \
+                 <script> or <label>"
+            ),
+        }
+        rest = &rest[start + len + 1..];
+    }
+    path.push_str(rest);
+
+    let path = PathBuf::from(path);
+    if !timed || !path.exists() {
+        return Ok(path);
+    }
+    let Some(name) = path.file_name().map(|n| n.to_string_lossy().into_owned()) else {
+        anyhow::bail!("{} has no name to add a suffix to", path.display());
+    };
+    for n in 2.. {
+        let candidate = path.with_file_name(format!("{name}-{n}"));
+        if !candidate.exists() {
+            return Ok(candidate);
+        }
+    }
+    unreachable!("there is a free suffix")
+}
+
+/// Format `time` with a strftime-like `format`, in the local time zone unless `utc` is set.
+///
+/// Supported are `%Y` (year), `%y` (year without century), `%m` (month), `%d` (day), `%H`
+/// (hour), `%M` (minute), `%S` (second), `%j` (day of the year), `%s` (unix time), `%F` (same as
+/// `%Y-%m-%d`), `%T` (same as `%H:%M:%S`) and `%%`. Anything else after a `%` is an error.
+pub fn format_time(time: SystemTime, format: &str, utc: bool) -> anyhow::Result<String> {
+    let secs = time
+        .duration_since(UNIX_EPOCH)
+        .context("time is before the unix epoch")?
+        .as_secs();
+    let tm = broken_down_time(secs as libc::time_t, utc)?;
+    let mut out = String::with_capacity(format.len() * 2);
+    let mut chars = format.chars();
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            out.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('Y') => out.push_str(&format!("{:04}", tm.tm_year + 1900)),
+            Some('y') => out.push_str(&format!("{:02}", (tm.tm_year + 1900) % 100)),
+            Some('m') => out.push_str(&format!("{:02}", tm.tm_mon + 1)),
+            Some('d') => out.push_str(&format!("{:02}", tm.tm_mday)),
+            Some('H') => out.push_str(&format!("{:02}", tm.tm_hour)),
+            Some('M') => out.push_str(&format!("{:02}", tm.tm_min)),
+            Some('S') => out.push_str(&format!("{:02}", tm.tm_sec)),
+            Some('j') => out.push_str(&format!("{:03}", tm.tm_yday + 1)),
+            Some('s') => out.push_str(&secs.to_string()),
+            Some('F') => out.push_str(&format!(
+                "{:04}-{:02}-{:02}",
+                tm.tm_year + 1900,
+                tm.tm_mon + 1,
+                tm.tm_mday
+            )),
+            Some('T') => out.push_str(&format!(
+                "{:02}:{:02}:{:02}",
+                tm.tm_hour, tm.tm_min, tm.tm_sec
+            )),
+            Some('%') => out.push('%'),
+            Some(other) => anyhow::bail!("unknown conversion `%{other}` in `{format}`"),
+            None => anyhow::bail!("`{format}` ends in a lone `%`"),
+        }
+    }
+    if out.is_empty() {
+        anyhow::bail!("`{format}` formats to an empty name");
+    }
+    Ok(out)
+}
+
+/// Split `secs` since the unix epoch into the date and time, in UTC or the local time zone.
+fn broken_down_time(secs: libc::time_t, utc: bool) -> anyhow::Result<libc::tm> {
+    let mut tm = MaybeUninit::<libc::tm>::uninit();
+    // SAFETY: `localtime_r` and `gmtime_r` only write to `tm`, and it is only read if that
+    // succeeded.
+    unsafe {
+        let result = if utc {
+            libc::gmtime_r(&secs, tm.as_mut_ptr())
+        } else {
+            libc::localtime_r(&secs, tm.as_mut_ptr())
+        };
+        if result.is_null() {
+            anyhow::bail!("could not determine the date");
+        }
+        Ok(tm.assume_init())
+    }
+}
+
+/// Replace the characters that can not be used in a directory name, or that cause trouble on
+/// other systems the results are copied to, with `-`.
+fn sanitize(name: &str) -> String {
+    name.chars()
+        .map(|c| match c {
+            '/' | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|' => '-',
+            c if c.is_control() => '-',
+            c => c,
+        })
+        .collect()
+}
+
+impl OutputDir {
+    /// Decide where to write to if `path` already contains files, without changing anything yet.
+    pub fn choose(path: PathBuf, on_exists: OnExists) -> anyhow::Result<Self> {
+        if is_empty_or_missing(&path)? {
+            return Ok(Self { path, wipe: false });
+        }
+        match on_exists {
+            OnExists::Fail => anyhow::bail!(
+                "{} already contains files, pass --force or --on-exists to write to it anyway",
+                path.display()
+            ),
+            OnExists::Overwrite if is_earlier_run(&path) => Ok(Self { path, wipe: true }),
+            OnExists::Overwrite => anyhow::bail!(
+                "{} contains files but not the results of an earlier run, refusing to delete it",
+                path.display()
+            ),
+            OnExists::Suffix => {
+                let Some(name) = path.file_name().map(|n| n.to_string_lossy().into_owned()) else {
+                    anyhow::bail!("{} has no name to add a suffix to", path.display());
+                };
+                for n in 2.. {
+                    let candidate = path.with_file_name(format!("{name}-{n}"));
+                    if is_empty_or_missing(&candidate)? {
+                        return Ok(Self {
+                            path: candidate,
+                            wipe: false,
+                        });
+                    }
+                }
+                unreachable!("there is a free suffix")
+            }
+        }
+    }
+
+    /// Use `path` with what it already contains, for scripts that continue an earlier run.
+    pub fn reuse(path: PathBuf) -> Self {
+        Self { path, wipe: false }
+    }
+
+    /// Create the directory, deleting its earlier contents if that was decided.
+    pub fn create(&self) -> anyhow::Result<()> {
+        if self.wipe {
+            warn!("Deleting the earlier contents of {}", self.path.display());
+            std::fs::remove_dir_all(&self.path)
+                .with_context(|| format!("could not delete {}", self.path.display()))?;
+        }
+        std::fs::create_dir_all(&self.path)
+            .with_context(|| format!("could not create {}", self.path.display()))
+    }
+}
+
+/// Whether `path` contains the results of an earlier run, which every run starts by writing.
+fn is_earlier_run(path: &Path) -> bool {
+    ["meta.ron", "arguments.ron"]
+        .iter()
+        .any(|name| path.join(name).is_file())
+}
+
+/// Whether `path` does not exist or is an empty directory.
+fn is_empty_or_missing(path: &Path) -> anyhow::Result<bool> {
+    match std::fs::read_dir(path) {
+        Ok(mut entries) => Ok(entries.next().is_none()),
+        Err(err) if err.kind() == ErrorKind::NotFound => Ok(true),
+        Err(err) => Err(err).with_context(|| format!("could not read {}", path.display())),
+    }
+}
+
+/// A line of the `index.csv` that lists every run.
+#[derive(Debug, Clone)]
+pub struct IndexEntry<'a> {
+    /// When the run started.
+    pub start: SystemTime,
+    pub script: &'a str,
+    pub label: Option<&'a str>,
+    pub out_path: &'a Path,
+    /// How the run finished, like `completed` or `failed`.
+    pub status: &'a str,
+}
+
+/// Append `entry` to `index.csv` in `base`, creating it with a header if it does not exist yet.
+///
+/// The line is appended with a single write, so controllers finishing at the same time do not
+/// mix up their lines.
+pub fn append_index(base: &Path, entry: &IndexEntry) -> anyhow::Result<()> {
+    std::fs::create_dir_all(base)
+        .with_context(|| format!("could not create {}", base.display()))?;
+    let path = base.join("index.csv");
+    let line = [
+        format_time(entry.start, DEFAULT_TIMESTAMP_FORMAT, false)?.as_str(),
+        entry.script,
+        entry.label.unwrap_or(""),
+        &entry.out_path.to_string_lossy(),
+        entry.status,
+    ]
+    .map(csv_field)
+    .join(",")
+        + "\n";
+
+    // Only the controller that creates the file writes the header.
+    let (mut file, contents) = match OpenOptions::new().append(true).create_new(true).open(&path) {
+        Ok(file) => (file, format!("start,script,label,path,status\n{line}")),
+        Err(err) if err.kind() == ErrorKind::AlreadyExists => {
+            let file = OpenOptions::new()
+                .append(true)
+                .open(&path)
+                .with_context(|| format!("could not open {}", path.display()))?;
+            (file, line)
+        }
+        Err(err) => {
+            return Err(err).with_context(|| format!("could not create {}", path.display()));
+        }
+    };
+    file.write_all(contents.as_bytes())
+        .with_context(|| format!("could not write to {}", path.display()))
+}
+
+/// Quote a CSV field if it contains a separator, quote or newline.
+pub fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+/// Point the `latest` link in `base` at `out_path`, replacing the link of an earlier run.
+///
+/// The link is relative if `out_path` is inside `base`, so the results can be moved together.
+#[cfg(unix)]
+pub fn link_latest(base: &Path, out_path: &Path) -> anyhow::Result<()> {
+    let target = match out_path.strip_prefix(base) {
+        Ok(relative) => relative.to_path_buf(),
+        Err(_) => std::path::absolute(out_path)
+            .with_context(|| format!("could not resolve {}", out_path.display()))?,
+    };
+    // Create the new link next to the old one and move it over it, so `latest` always exists.
+    let link = base.join("latest");
+    let temp = base.join(format!(".latest-{}", std::process::id()));
+    std::os::unix::fs::symlink(&target, &temp)
+        .with_context(|| format!("could not create a link at {}", temp.display()))?;
+    std::fs::rename(&temp, &link).map_err(|err| {
+        _ = std::fs::remove_file(&temp);
+        anyhow::Error::new(err).context(format!("could not replace {}", link.display()))
+    })
+}
+
+/// Links are only supported on unix.
+#[cfg(not(unix))]
+pub fn link_latest(_base: &Path, _out_path: &Path) -> anyhow::Result<()> {
+    anyhow::bail!("links are not supported on this platform")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    /// 2025-03-14 15:09:26 UTC.
+    const START: Duration = Duration::from_secs(1_741_964_966);
+
+    fn placeholders(label: Option<&str>) -> Placeholders<'_> {
+        Placeholders {
+            time: UNIX_EPOCH + START,
+            time_format: DEFAULT_TIMESTAMP_FORMAT,
+            utc: true,
+            script: "iperf",
+            label,
+        }
+    }
+
+    #[test]
+    fn every_placeholder_is_filled_in() {
+        let placeholders = placeholders(Some("office"));
+        let expand = |template| expand_path(template, &placeholders).unwrap();
+        assert_eq!(
+            expand("results/<timestamp>"),
+            Path::new("results/2025-03-14_15-09-26")
+        );
+        assert_eq!(
+            expand("results/<date>"),
+            Path::new("results/2025-03-14_15-09-26")
+        );
+        assert_eq!(expand("results/<script>"), Path::new("results/iperf"));
+        assert_eq!(expand("results/<label>"), Path::new("results/office"));
+        assert_eq!(
+            expand("out/<script>-<label>/<date>"),
+            Path::new("out/iperf-office/2025-03-14_15-09-26")
+        );
+        assert_eq!(expand("results/plain"), Path::new("results/plain"));
+    }
+
+    #[test]
+    fn invalid_placeholders_are_rejected() {
+        let placeholders = placeholders(None);
+        let err = |template| format!("{:#}", expand_path(template, &placeholders).unwrap_err());
+        assert!(err("out/<timestmap>").contains("unknown placeholder `<timestmap>`"));
+        assert!(err("out/<script").contains("unclosed placeholder"));
+        assert!(err("out/<label>").contains("no --label was given"));
+
+        for label in ["", "a/b"] {
+            let placeholders = Placeholders {
+                label: Some(label),
+                ..placeholders.clone()
+            };
+            assert!(expand_path("out/<label>", &placeholders).is_err());
+        }
+    }
+
+    #[test]
+    fn characters_of_the_time_that_break_paths_are_replaced() {
+        let placeholders = Placeholders {
+            time_format: "%F %T",
+            ..placeholders(None)
+        };
+        assert_eq!(
+            expand_path("out/<timestamp>", &placeholders).unwrap(),
+            Path::new("out/2025-03-14 15-09-26")
+        );
+    }
+
+    #[test]
+    fn timed_directories_that_exist_get_a_suffix() {
+        let dir = tempfile::tempdir().unwrap();
+        let template = format!("{}/<script>-<timestamp>", dir.path().display());
+        let placeholders = placeholders(None);
+        let first = expand_path(&template, &placeholders).unwrap();
+        assert_eq!(first, dir.path().join("iperf-2025-03-14_15-09-26"));
+
+        std::fs::create_dir(&first).unwrap();
+        let second = expand_path(&template, &placeholders).unwrap();
+        assert_eq!(second, dir.path().join("iperf-2025-03-14_15-09-26-2"));
+
+        std::fs::create_dir(&second).unwrap();
+        let third = expand_path(&template, &placeholders).unwrap();
+        assert_eq!(third, dir.path().join("iperf-2025-03-14_15-09-26-3"));
+    }
+
+    #[test]
+    fn untimed_directories_that_exist_are_kept() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir(dir.path().join("iperf")).unwrap();
+        let template = format!("{}/<script>", dir.path().display());
+        assert_eq!(
+            expand_path(&template, &placeholders(None)).unwrap(),
+            dir.path().join("iperf")
+        );
+    }
+
+    /// A directory `name` in `dir` with a file in it.
+    fn populated(dir: &Path, name: &str) -> PathBuf {
+        let path = dir.join(name);
+        std::fs::create_dir(&path).unwrap();
+        std::fs::write(path.join("meta.ron"), "()").unwrap();
+        std::fs::write(path.join("summary.ron"), "()").unwrap();
+        path
+    }
+
+    #[test]
+    fn empty_or_missing_directories_are_used_with_any_policy() {
+        let dir = tempfile::tempdir().unwrap();
+        let empty = dir.path().join("empty");
+        std::fs::create_dir(&empty).unwrap();
+        for on_exists in [OnExists::Fail, OnExists::Overwrite, OnExists::Suffix] {
+            for path in [empty.clone(), dir.path().join("missing")] {
+                let output = OutputDir::choose(path.clone(), on_exists).unwrap();
+                assert_eq!(output.path, path);
+                assert!(!output.wipe);
+            }
+        }
+    }
+
+    #[test]
+    fn directories_with_files_fail_by_default() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = populated(dir.path(), "run");
+        let err = OutputDir::choose(path.clone(), OnExists::default()).unwrap_err();
+        assert!(err.to_string().contains("already contains files"), "{err}");
+        assert!(path.join("summary.ron").exists());
+    }
+
+    #[test]
+    fn overwrite_deletes_the_earlier_contents_on_create() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = populated(dir.path(), "run");
+        let output = OutputDir::choose(path.clone(), OnExists::Overwrite).unwrap();
+        assert_eq!(output.path, path);
+        // Nothing is deleted until the directory is created.
+        assert!(path.join("summary.ron").exists());
+
+        output.create().unwrap();
+        assert!(path.is_dir());
+        assert!(!path.join("summary.ron").exists());
+    }
+
+    #[test]
+    fn overwrite_only_deletes_earlier_runs() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("home");
+        std::fs::create_dir(&path).unwrap();
+        std::fs::write(path.join("notes.txt"), "keep").unwrap();
+        let err = OutputDir::choose(path.clone(), OnExists::Overwrite).unwrap_err();
+        assert!(
+            err.to_string()
+                .contains("not the results of an earlier run"),
+            "{err}"
+        );
+        assert!(path.join("notes.txt").exists());
+
+        // A run that failed before writing its metadata has its arguments.
+        let path = dir.path().join("run");
+        std::fs::create_dir(&path).unwrap();
+        std::fs::write(path.join("arguments.ron"), "()").unwrap();
+        let output = OutputDir::choose(path.clone(), OnExists::Overwrite).unwrap();
+        output.create().unwrap();
+        assert!(!path.join("arguments.ron").exists());
+    }
+
+    #[test]
+    fn suffix_picks_the_first_free_sibling() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = populated(dir.path(), "run");
+        populated(dir.path(), "run-2");
+        // An empty sibling is free.
+        std::fs::create_dir(dir.path().join("run-3")).unwrap();
+
+        let output = OutputDir::choose(path, OnExists::Suffix).unwrap();
+        assert_eq!(output.path, dir.path().join("run-3"));
+        assert!(!output.wipe);
+        output.create().unwrap();
+        assert!(dir.path().join("run-2/summary.ron").exists());
+    }
+
+    #[test]
+    fn reused_directories_keep_their_contents() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = populated(dir.path(), "run");
+        OutputDir::reuse(path.clone()).create().unwrap();
+        assert!(path.join("summary.ron").exists());
+    }
+
+    fn format(format: &str) -> anyhow::Result<String> {
+        format_time(UNIX_EPOCH + START, format, true)
+    }
+
+    #[test]
+    fn every_conversion_is_formatted() {
+        assert_eq!(format("%Y-%m-%d %H:%M:%S").unwrap(), "2025-03-14 15:09:26");
+        assert_eq!(format("%y%j").unwrap(), "25073");
+        assert_eq!(format("%s").unwrap(), "1741964966");
+        assert_eq!(format("%FT%T").unwrap(), "2025-03-14T15:09:26");
+        assert_eq!(format("100%%").unwrap(), "100%");
+        assert_eq!(format("run").unwrap(), "run");
+    }
+
+    #[test]
+    fn invalid_formats_are_rejected() {
+        let err = |f| format!("{:#}", format(f).unwrap_err());
+        assert!(err("%Y-%q").contains("unknown conversion `%q`"));
+        assert!(err("%Y%").contains("lone `%`"));
+        assert!(err("").contains("empty name"));
+        assert!(format_time(UNIX_EPOCH - Duration::from_secs(1), "%s", true).is_err());
+    }
+
+    #[test]
+    fn the_local_time_zone_is_used_without_utc() {
+        // Neither the unix time nor the seconds depend on the time zone.
+        let local = format_time(UNIX_EPOCH + START, "%s %S", false).unwrap();
+        assert_eq!(local, "1741964966 26");
+    }
+}
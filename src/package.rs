@@ -8,6 +8,8 @@ use crate::hosts::{Host, HostOs};
 pub enum Package {
     Wireshark,
     Iperf3,
+    /// iperf 2, which unlike iperf3 supports multicast.
+    Iperf2,
 }
 
 impl Package {
@@ -20,12 +22,52 @@ impl Package {
         let pkg = match self {
             Package::Wireshark => "wireshark",
             Package::Iperf3 => "iperf3",
+            Package::Iperf2 => "iperf",
         };
         Some(pkg)
     }
+
+    /// The name of the binary that is installed by the package.
+    pub fn binary(&self) -> &'static str {
+        match self {
+            Package::Wireshark => "tshark",
+            Package::Iperf3 => "iperf3",
+            Package::Iperf2 => "iperf",
+        }
+    }
 }
 
 impl Host {
+    /// Whether the binary of a package is available in the PATH.
+    pub async fn has_package(&self, pkg: Package) -> anyhow::Result<bool> {
+        let status = self
+            .session
+            .shell(format!("command -v {}", pkg.binary()))
+            .stdout(Stdio::null())
+            .status()
+            .await
+            .context("failed to look for package")?;
+        Ok(status.success())
+    }
+
+    /// Make sure a package is available, installing it if possible.
+    pub async fn ensure_package(&self, pkg: Package) -> anyhow::Result<()> {
+        if self.has_package(pkg).await? {
+            return Ok(());
+        }
+        debug!(host = self.id, "Installing {pkg:?}");
+        self.install_package(pkg)
+            .await
+            .with_context(|| format!("{} is not installed", pkg.binary()))?;
+        if !self.has_package(pkg).await? {
+            anyhow::bail!(
+                "{} is still not available after installing it",
+                pkg.binary()
+            );
+        }
+        Ok(())
+    }
+
     /// Installs a package on a system if it is not yet installed, making it abailable to be used in
     /// the PATH.
     pub async fn install_package(&self, pkg: Package) -> anyhow::Result<&Self> {
@@ -0,0 +1,77 @@
+//! A command run on the controller after a run, like an analysis script, with `--post-run`.
+
+use std::{path::Path, time::Instant};
+
+use serde::{Deserialize, Serialize};
+use tokio::process::Command;
+use tracing::{info, warn};
+
+use crate::utils::{run_local_streaming, Line};
+
+/// When and how the post-run command is run, from the command line.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct PostRunOptions<'a> {
+    /// The command, run with `sh -c`.
+    pub command: Option<&'a str>,
+    /// Also run the command if the run failed.
+    pub always: bool,
+    /// Fail the run if the command fails.
+    pub strict: bool,
+}
+
+/// How the post-run command went, recorded in `meta.ron`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PostRun {
+    pub command: String,
+    /// The exit code, `None` if the command was killed by a signal or could not be run.
+    pub status: Option<i32>,
+    /// Why the command failed, if it did.
+    pub error: Option<String>,
+    /// How long the command ran in seconds.
+    pub duration: f64,
+}
+
+impl PostRun {
+    pub fn succeeded(&self) -> bool {
+        self.error.is_none()
+    }
+}
+
+/// Run `command` with `sh -c` on the controller, with the output directory as `OUT_DIR` and as
+/// the last argument. Its output is forwarded to the log as it comes in.
+pub async fn run(command: &str, out_path: &Path) -> PostRun {
+    info!("Running post-run command `{command}`");
+    let start = Instant::now();
+    let mut sh = Command::new("sh");
+    // `"$@"` passes the output directory as the last argument, without quoting it into the command.
+    sh.arg("-c")
+        .arg(format!("{command} \"$@\""))
+        .arg("post-run")
+        .arg(out_path)
+        .env("OUT_DIR", out_path);
+    let on_line = |kind, line: &str| match kind {
+        Line::Stdout => info!(target: "post-run", "{line}"),
+        Line::Stderr => warn!(target: "post-run", "{line}"),
+    };
+    let result = run_local_streaming(sh, &on_line).await;
+
+    let (status, error) = match result {
+        Ok(output) if output.status.success() => (output.status.code(), None),
+        Ok(output) => (
+            output.status.code(),
+            Some(format!("exited with {}", output.status)),
+        ),
+        Err(err) => (None, Some(format!("could not run the command: {err}"))),
+    };
+    let post_run = PostRun {
+        command: command.to_string(),
+        status,
+        error,
+        duration: start.elapsed().as_secs_f64(),
+    };
+    match &post_run.error {
+        None => info!("Post-run command completed"),
+        Some(err) => warn!("Post-run command failed: {err}"),
+    }
+    post_run
+}
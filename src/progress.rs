@@ -0,0 +1,237 @@
+//! Reporting which phase a running script is in, so long runs do not look stuck.
+//!
+//! While a reporter is active, every phase transition is logged along with how long the previous
+//! phase took. With `--progress` the current phase and its elapsed time are also shown on a
+//! status line that updates in place if stderr is a terminal, or logged periodically otherwise.
+//!
+//! Entries of a plan that run at the same time each report their own phase, see [scope].
+
+use std::{
+    collections::BTreeMap,
+    fmt,
+    future::Future,
+    io::{IsTerminal, Write},
+    sync::{Arc, Mutex, RwLock},
+    time::{Duration, Instant},
+};
+
+use tokio::task::JoinHandle;
+use tracing::info;
+
+use crate::metrics;
+
+/// The reporter phases are currently reported to, if any.
+static ACTIVE: RwLock<Option<Arc<Progress>>> = RwLock::new(None);
+
+tokio::task_local! {
+    /// The plan entry the current task runs, whose phase is reported separately.
+    static ENTRY: String;
+}
+
+/// How often the status line is redrawn on a terminal.
+const STATUS_LINE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How often the current phase is logged when stderr is not a terminal.
+const STATUS_LOG_INTERVAL: Duration = Duration::from_secs(30);
+
+/// A phase of a script.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Phase {
+    /// Associating the clients to the network.
+    Connecting,
+    /// Preparing the hosts, like setting the MCS or starting servers.
+    Provisioning,
+    /// Associating the clients while capturing their association IDs.
+    AidDiscovery,
+    /// The monitors are capturing, for the given duration.
+    Capturing(Duration),
+    /// Traffic is generated without capturing it, for the given duration.
+    Running(Duration),
+    /// Gathering and writing the results.
+    Collecting,
+}
+
+impl Phase {
+    /// How long the phase is expected to take, if known.
+    fn duration(&self) -> Option<Duration> {
+        match self {
+            Phase::Capturing(duration) | Phase::Running(duration) => Some(*duration),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Phase {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Phase::Connecting => "connecting",
+            Phase::Provisioning => "provisioning",
+            Phase::AidDiscovery => "AID discovery",
+            Phase::Capturing(_) => "capturing",
+            Phase::Running(_) => "running",
+            Phase::Collecting => "collecting results",
+        })
+    }
+}
+
+/// Reports the phases of a script, see the module documentation.
+pub struct Progress {
+    /// The current phase of every plan entry that is running, `None` outside of a plan.
+    current: Mutex<BTreeMap<Option<String>, (Phase, Instant)>>,
+    /// Redraws the status line or logs the phase periodically, with `--progress`.
+    status: Mutex<Option<JoinHandle<()>>>,
+    tty: bool,
+}
+
+impl Progress {
+    /// Start reporting phases, showing a status line if `status` is set.
+    pub fn start(status: bool) -> Arc<Self> {
+        let progress = Arc::new(Progress {
+            current: Mutex::new(BTreeMap::new()),
+            status: Mutex::new(None),
+            tty: std::io::stderr().is_terminal(),
+        });
+        if status {
+            let reporter = progress.clone();
+            *progress.status.lock().expect("progress lock is poisoned") =
+                Some(tokio::spawn(async move { reporter.show_status().await }));
+        }
+        *ACTIVE.write().expect("progress lock is poisoned") = Some(progress.clone());
+        progress
+    }
+
+    /// Stop reporting phases, logging how long the last phase took.
+    pub fn finish(&self) {
+        ACTIVE.write().expect("progress lock is poisoned").take();
+        if let Some(task) = self
+            .status
+            .lock()
+            .expect("progress lock is poisoned")
+            .take()
+        {
+            task.abort();
+            if self.tty {
+                eprint!("\x1b[2K");
+            }
+        }
+        let current = std::mem::take(&mut *self.current.lock().expect("progress lock is poisoned"));
+        for entry in current.into_keys() {
+            self.leave(entry.as_deref());
+        }
+    }
+
+    /// Log how long the last phase of `entry` took, and stop reporting it.
+    fn leave(&self, entry: Option<&str>) {
+        let mut current = self.current.lock().expect("progress lock is poisoned");
+        if let Some((phase, start)) = current.remove(&entry.map(str::to_string)) {
+            info!(
+                "Finished {phase} after {:.1}s",
+                start.elapsed().as_secs_f64()
+            );
+        }
+    }
+
+    fn enter(&self, phase: Phase) {
+        let entry = ENTRY.try_with(String::clone).ok();
+        let mut current = self.current.lock().expect("progress lock is poisoned");
+        match current.insert(entry.clone(), (phase, Instant::now())) {
+            // Hosts that report the same phase concurrently do not restart it.
+            Some((previous, start)) if previous == phase => {
+                current.insert(entry, (previous, start));
+            }
+            Some((previous, start)) => info!(
+                "Phase: {phase} (finished {previous} after {:.1}s)",
+                start.elapsed().as_secs_f64()
+            ),
+            None => info!("Phase: {phase}"),
+        }
+    }
+
+    /// The current phase with its elapsed time, like `capturing 34/60s`, prefixed with the name
+    /// of the entry for every running plan entry.
+    fn status(&self) -> Option<String> {
+        let current = self.current.lock().expect("progress lock is poisoned");
+        let phases: Vec<_> = current
+            .iter()
+            .map(|(entry, (phase, start))| {
+                let elapsed = start.elapsed().as_secs();
+                let status = match phase.duration() {
+                    Some(total) => format!("{phase} {elapsed}/{}s", total.as_secs()),
+                    None => format!("{phase} {elapsed}s"),
+                };
+                match entry {
+                    Some(entry) => format!("{entry}: {status}"),
+                    None => status,
+                }
+            })
+            .collect();
+        (!phases.is_empty()).then(|| phases.join(", "))
+    }
+
+    async fn show_status(&self) {
+        let interval = if self.tty {
+            STATUS_LINE_INTERVAL
+        } else {
+            STATUS_LOG_INTERVAL
+        };
+        let mut ticks = tokio::time::interval(interval);
+        ticks.tick().await;
+        loop {
+            ticks.tick().await;
+            let Some(status) = self.status() else {
+                continue;
+            };
+            if self.tty {
+                // End at the start of the line, so the next log line overwrites the status.
+                let mut stderr = std::io::stderr().lock();
+                _ = write!(stderr, "\x1b[2K[{status}]\r");
+                _ = stderr.flush();
+            } else {
+                info!("Still {status}");
+            }
+        }
+    }
+}
+
+/// Report that the script entered `phase`, if a reporter is active.
+pub fn enter(phase: Phase) {
+    metrics::set_phase(phase);
+    let Some(progress) = ACTIVE.read().expect("progress lock is poisoned").clone() else {
+        return;
+    };
+    progress.enter(phase);
+}
+
+/// Run `future` as the plan entry `entry`, reporting its phases separately from those of the
+/// entries that run at the same time.
+pub async fn scope<F: Future>(entry: &str, future: F) -> F::Output {
+    let output = ENTRY.scope(entry.to_string(), future).await;
+    let progress = ACTIVE.read().expect("progress lock is poisoned").clone();
+    if let Some(progress) = progress {
+        progress.leave(Some(entry));
+    }
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn entries_report_their_own_phase() {
+        let progress = Progress::start(false);
+        scope("a", async { enter(Phase::Provisioning) }).await;
+        let b = scope("b", async {
+            enter(Phase::Running(Duration::from_secs(10)));
+            progress.status()
+        })
+        .await;
+        // An entry that finished is no longer shown.
+        assert_eq!(b.as_deref(), Some("b: running 0/10s"));
+        assert_eq!(progress.status(), None);
+
+        enter(Phase::Collecting);
+        assert_eq!(progress.status().as_deref(), Some("collecting results 0s"));
+        progress.finish();
+    }
+}
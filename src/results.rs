@@ -0,0 +1,280 @@
+//! The files a run writes to its results directory, with the version of their format, and loading
+//! them back for tools like `compare`.
+//!
+//! Every artifact records the version of its schema in a `schema_version` field. The minor version
+//! goes up when fields are added, which older controllers ignore. The major version goes up when
+//! fields are removed or change meaning, after which older controllers refuse to load the file.
+//! Artifacts from before versioning have version 0.0 and are migrated when they are loaded.
+
+use std::{collections::BTreeMap, fmt, io::ErrorKind, path::Path};
+
+use anyhow::Context;
+use ron::ser::{to_string_pretty, PrettyConfig};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use crate::{
+    hosts::HostId,
+    monitor::MonitorMetadata,
+    scripts::{
+        iperf::{IperfResult, RunStatus, RunSummary},
+        meta::Meta,
+    },
+    timing::Timings,
+};
+
+/// The version of the format of an artifact.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct SchemaVersion {
+    pub major: u32,
+    pub minor: u32,
+}
+
+impl SchemaVersion {
+    pub const fn new(major: u32, minor: u32) -> Self {
+        Self { major, minor }
+    }
+}
+
+impl fmt::Display for SchemaVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{}", self.major, self.minor)
+    }
+}
+
+/// A file in the results directory of a run.
+pub trait Artifact: Serialize + DeserializeOwned {
+    /// The name of the file in the results directory.
+    const FILE: &'static str;
+    /// The version of the format this controller writes.
+    const SCHEMA_VERSION: SchemaVersion;
+
+    /// Parse an artifact written with an older major version than [Artifact::SCHEMA_VERSION].
+    /// By default it is parsed as if it were current, which works as long as the fields that were
+    /// added since have defaults.
+    fn migrate(raw: &str, _version: SchemaVersion) -> ron::error::SpannedResult<Self> {
+        ron::from_str(raw)
+    }
+}
+
+impl Artifact for Meta {
+    const FILE: &'static str = "meta.ron";
+    const SCHEMA_VERSION: SchemaVersion = SchemaVersion::new(1, 1);
+}
+
+impl Artifact for RunSummary {
+    const FILE: &'static str = "summary.ron";
+    const SCHEMA_VERSION: SchemaVersion = SchemaVersion::new(2, 0);
+}
+
+impl Artifact for RunStatus {
+    const FILE: &'static str = "status.ron";
+    const SCHEMA_VERSION: SchemaVersion = SchemaVersion::new(1, 0);
+}
+
+impl Artifact for Timings {
+    const FILE: &'static str = "timings.ron";
+    const SCHEMA_VERSION: SchemaVersion = SchemaVersion::new(1, 0);
+}
+
+impl Artifact for MonitorMetadata {
+    const FILE: &'static str = "monitor.ron";
+    const SCHEMA_VERSION: SchemaVersion = SchemaVersion::new(1, 1);
+}
+
+/// The parsed iperf output of every client, written to `results.ron` with `--json`.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct IperfResults {
+    #[serde(default)]
+    pub schema_version: SchemaVersion,
+    pub clients: BTreeMap<HostId, IperfResult>,
+}
+
+impl IperfResults {
+    pub fn new(clients: BTreeMap<HostId, IperfResult>) -> Self {
+        Self {
+            schema_version: Self::SCHEMA_VERSION,
+            clients,
+        }
+    }
+}
+
+impl Artifact for IperfResults {
+    const FILE: &'static str = "results.ron";
+    const SCHEMA_VERSION: SchemaVersion = SchemaVersion::new(1, 0);
+
+    /// Before versioning the results were written as a bare map of the clients.
+    fn migrate(raw: &str, version: SchemaVersion) -> ron::error::SpannedResult<Self> {
+        Ok(Self {
+            schema_version: version,
+            clients: ron::from_str(raw)?,
+        })
+    }
+}
+
+/// The artifacts of a run that could be loaded. Artifacts the run did not write are `None`.
+#[derive(Debug, Clone, Default)]
+pub struct RunArtifacts {
+    pub meta: Option<Meta>,
+    pub summary: Option<RunSummary>,
+    pub results: Option<IperfResults>,
+    pub monitor: Option<MonitorMetadata>,
+}
+
+/// Load the artifacts of the run in `dir`. Fails if an artifact can not be parsed or has a newer
+/// major version than this controller reads.
+pub fn load_run(dir: &Path) -> anyhow::Result<RunArtifacts> {
+    Ok(RunArtifacts {
+        meta: load_artifact(dir)?,
+        summary: load_artifact(dir)?,
+        results: load_artifact(dir)?,
+        monitor: load_artifact(dir)?,
+    })
+}
+
+/// Load artifact `T` from `dir`, `None` if the run did not write it.
+pub fn load_artifact<T: Artifact>(dir: &Path) -> anyhow::Result<Option<T>> {
+    let path = dir.join(T::FILE);
+    let raw = match std::fs::read_to_string(&path) {
+        Ok(raw) => raw,
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(err).with_context(|| format!("could not read {}", path.display())),
+    };
+    parse_artifact::<T>(&raw)
+        .map(Some)
+        .with_context(|| format!("could not load {}", path.display()))
+}
+
+/// Parse artifact `T`, migrating it if it has an older major version.
+pub fn parse_artifact<T: Artifact>(raw: &str) -> anyhow::Result<T> {
+    let version = schema_version(raw);
+    let current = T::SCHEMA_VERSION;
+    if version.major > current.major {
+        anyhow::bail!(
+            "it has schema version {version}, but this controller only reads {} up to {}.x, \
+             update the controller to load it",
+            T::FILE,
+            current.major
+        );
+    }
+    let artifact = if version.major < current.major {
+        T::migrate(raw, version)
+    } else {
+        ron::from_str(raw)
+    };
+    artifact.with_context(|| format!("it does not match schema version {version}"))
+}
+
+/// Write artifact `T` to `dir`.
+pub async fn write_artifact<T: Artifact>(dir: &Path, artifact: &T) -> anyhow::Result<()> {
+    let dump = to_string_pretty(artifact, PrettyConfig::new())
+        .with_context(|| format!("failed to serialize {}", T::FILE))?;
+    tokio::fs::write(dir.join(T::FILE), dump)
+        .await
+        .with_context(|| format!("failed to save {}", T::FILE))
+}
+
+/// The schema version of an artifact, 0.0 if it has none because it is from before versioning.
+fn schema_version(raw: &str) -> SchemaVersion {
+    #[derive(Deserialize)]
+    struct Versioned {
+        #[serde(default)]
+        schema_version: SchemaVersion,
+    }
+    ron::from_str::<Versioned>(raw).map_or(SchemaVersion::default(), |v| v.schema_version)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scripts::iperf::Saved;
+
+    fn iperf_result() -> IperfResult {
+        IperfResult {
+            protocol: Some("TCP".to_string()),
+            tos: None,
+            directions: Vec::new(),
+            error: Some("interrupted".to_string()),
+        }
+    }
+
+    #[tokio::test]
+    async fn artifacts_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let results = IperfResults::new(BTreeMap::from([("sta1".to_string(), iperf_result())]));
+        write_artifact(dir.path(), &results).await.unwrap();
+        let status = RunStatus {
+            schema_version: RunStatus::SCHEMA_VERSION,
+            failures: vec!["client sta1 failed".to_string()],
+            clients: BTreeMap::from([("sta1".to_string(), Saved::Yes)]),
+            captures: Some(Saved::Failed("monitor unreachable".to_string())),
+            errors: Vec::new(),
+        };
+        write_artifact(dir.path(), &status).await.unwrap();
+
+        let run = load_run(dir.path()).unwrap();
+        let loaded = run.results.unwrap();
+        assert_eq!(loaded.schema_version, IperfResults::SCHEMA_VERSION);
+        assert_eq!(loaded.clients["sta1"].error.as_deref(), Some("interrupted"));
+        assert!(run.meta.is_none());
+        assert!(run.summary.is_none());
+        assert!(run.monitor.is_none());
+
+        let loaded: RunStatus = load_artifact(dir.path()).unwrap().unwrap();
+        assert_eq!(loaded.failures, status.failures);
+        assert_eq!(loaded.captures, status.captures);
+    }
+
+    #[test]
+    fn results_from_before_versioning_are_migrated() {
+        let raw = r#"{"sta1": (protocol: Some("UDP"), tos: None, directions: [], error: None)}"#;
+        let results = parse_artifact::<IperfResults>(raw).unwrap();
+        assert_eq!(results.schema_version, SchemaVersion::new(0, 0));
+        assert_eq!(results.clients["sta1"].protocol.as_deref(), Some("UDP"));
+    }
+
+    #[test]
+    fn newer_minor_versions_are_read() {
+        let raw = r#"(
+            schema_version: (major: 1, minor: 7),
+            clients: {},
+            added_later: "ignored",
+        )"#;
+        let results = parse_artifact::<IperfResults>(raw).unwrap();
+        assert_eq!(results.schema_version, SchemaVersion::new(1, 7));
+    }
+
+    #[test]
+    fn newer_major_versions_are_rejected() {
+        let raw = r#"(schema_version: (major: 2, minor: 0), clients: {})"#;
+        let err = parse_artifact::<IperfResults>(raw).unwrap_err();
+        let message = format!("{err:#}");
+        assert!(message.contains("schema version 2.0"), "{message}");
+        assert!(message.contains("results.ron up to 1.x"), "{message}");
+    }
+
+    #[test]
+    fn invalid_artifacts_name_their_version() {
+        let raw = r#"(schema_version: (major: 1, minor: 0), clients: 3)"#;
+        let err = parse_artifact::<IperfResults>(raw).unwrap_err();
+        assert!(
+            format!("{err:#}").contains("does not match schema version 1.0"),
+            "{err:#}"
+        );
+    }
+
+    #[test]
+    fn invalid_artifacts_in_a_run_name_their_file() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("results.ron"), "not ron").unwrap();
+        let err = load_run(dir.path()).unwrap_err();
+        assert!(format!("{err:#}").contains("results.ron"), "{err:#}");
+    }
+
+    #[test]
+    fn versions_are_ordered_by_major_first() {
+        assert!(SchemaVersion::new(1, 9) < SchemaVersion::new(2, 0));
+        assert!(SchemaVersion::new(1, 0) < SchemaVersion::new(1, 1));
+        assert_eq!(schema_version("not ron"), SchemaVersion::default());
+        assert_eq!(SchemaVersion::new(2, 1).to_string(), "2.1");
+    }
+}
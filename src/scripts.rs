@@ -1,19 +1,703 @@
-use std::path::Path;
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    ops::Range,
+    path::Path,
+    time::{Duration, Instant, SystemTime},
+};
 
+use anyhow::Context;
 use clap::Parser;
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
 
-use crate::hosts::Hosts;
+use self::{
+    iterations::Status,
+    meta::Meta,
+    roles::{dedup_hosts, HostRoles},
+};
+use crate::{
+    cancel::{Aborted, CancellationToken, Reason, TimedOut},
+    command_log::{self, CommandLog, HostErrors},
+    hosts::{HostId, Hosts},
+    lock::{self, LockOptions},
+    notify::{self, Notification, NotifyOptions},
+    output::{self, IndexEntry},
+    post_run::{self, PostRunOptions},
+    progress::Progress,
+    results::write_artifact,
+    sqlite::{self, RunLabels},
+    timing::{self, TimedPhase, Timing},
+    utils::check_channel,
+};
 
+pub mod anonymize;
+pub mod ap_setup;
+pub mod assoc_storm;
+pub mod atten_sweep;
+pub mod baseline;
+pub mod burst;
+pub mod capture;
+pub mod cleanup;
+pub mod compare;
+pub mod config;
+pub mod fairness;
+pub mod host_info;
+pub mod interference;
 pub mod iperf;
+pub mod iterations;
+pub mod latency;
+pub mod list_hosts;
+pub mod loaded_latency;
+pub mod meta;
+pub mod mixed;
+pub mod monitoring;
+pub mod multicast;
+pub mod plan;
+pub mod power_save;
+pub mod reparse;
+pub mod replay;
+pub mod report;
+pub mod roam;
+pub mod roles;
+pub mod saturate;
+pub mod soak;
+pub mod survey;
+pub mod transfer;
+pub mod verify;
 
-#[derive(Parser, Debug, Clone)]
+/// The scripts, as subcommands. In config files the name of the script is given as `script`
+/// and its arguments as `args`.
+#[derive(Parser, Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "script", content = "args", rename_all = "kebab-case")]
 pub enum Script {
     /// Run an IPerf stress test with multiple nodes.
     Iperf(iperf::IperfArgs),
+    /// Measure the round trip time from clients to the access point with ping.
+    Latency(latency::LatencyArgs),
+    /// Measure the latency under load by pinging while running an IPerf stress test.
+    LoadedLatency(loaded_latency::LoadedLatencyArgs),
+    /// Survey how busy a list of channels is.
+    Survey(survey::SurveyArgs),
+    /// Measure how long it takes for clients to join the network at the same time.
+    AssocStorm(assoc_storm::AssocStormArgs),
+    /// Make a client roam between two access points and measure the interruption.
+    Roam(roam::RoamArgs),
+    /// Run an IPerf stress test at every step of an external attenuator.
+    AttenSweep(atten_sweep::AttenSweepArgs),
+    /// Measure airtime fairness with one client pinned to a low MCS.
+    Fairness(fairness::FairnessArgs),
+    /// Send ON/OFF bursts of UDP traffic from multiple nodes.
+    Burst(burst::BurstArgs),
+    /// Search for the highest UDP load that is sustained without too much loss.
+    Saturate(saturate::SaturateArgs),
+    /// Capture a channel without generating any traffic.
+    Baseline(baseline::BaselineArgs),
+    /// Run TCP and UDP IPerf clients at the same time.
+    Mixed(mixed::MixedArgs),
+    /// Send a multicast stream to clients and compare the received and captured rates.
+    Multicast(multicast::MulticastArgs),
+    /// Capture a channel on one or more monitors without running an experiment.
+    Capture(capture::CaptureArgs),
+    /// Configure the radio of an access point.
+    ApSetup(ap_setup::ApSetupArgs),
+    /// Kill leftover processes and reset the hosts to a known state.
+    Cleanup(cleanup::CleanupArgs),
+    /// Print the system, driver and connection state of the hosts.
+    HostInfo(host_info::HostInfoArgs),
+    /// Copy a file from the controller to hosts.
+    Push(transfer::PushArgs),
+    /// Copy a file from hosts to the controller.
+    Fetch(transfer::FetchArgs),
+    /// Run an IPerf stress test while a second network generates background traffic.
+    Interference(interference::InterferenceArgs),
+    /// Run IPerf for a long time while capturing short samples on a schedule.
+    Soak(soak::SoakArgs),
+    /// Compare the latency and loss of clients with power save off and on.
+    PowerSave(power_save::PowerSaveArgs),
+    /// Run every preparatory step of an IPerf stress test without any traffic, and report which
+    /// passed.
+    Verify(iperf::IperfArgs),
+    /// Run an IPerf stress test again with the saved arguments of an earlier run.
+    Replay(replay::ReplayArgs),
+    /// Run the experiments of a plan file one after the other.
+    Plan(plan::PlanArgs),
+    /// List the hosts of the hosts file and check whether they can be connected to.
+    ListHosts(list_hosts::ListHostsArgs),
+    /// Parse the text output of the iperf clients in an old results directory into the
+    /// `results.ron` and `summary.ron` files of runs with `--json`.
+    Reparse(reparse::ReparseArgs),
+    /// Compare the results of two iperf runs and write the changes to `comparison.ron`.
+    Compare(compare::CompareArgs),
+    /// Replace the MAC addresses in the captures of a results directory with pseudonyms, writing
+    /// anonymized copies next to them.
+    Anonymize(anonymize::AnonymizeArgs),
+    /// Collect the summaries of the iterations of a sweep into `campaign.csv` and `campaign.ron`,
+    /// with the mean and standard deviation over the repeats of every configuration.
+    Report(report::ReportArgs),
 }
 
-pub async fn run(args: Script, hosts: Hosts, out_path: &Path) -> anyhow::Result<()> {
+impl Script {
+    /// Whether the script continues an earlier run in the same output directory.
+    pub fn resumes(&self) -> bool {
+        matches!(self, Script::Plan(args) if args.resume)
+    }
+
+    /// Check that the frequency of the script is part of a channel of its bandwidth. A single
+    /// argument can not be checked against the other while parsing it.
+    pub fn check_channels(&self) -> Result<(), String> {
+        match self {
+            Script::Iperf(args) | Script::Verify(args) => args.network.check_channel(),
+            Script::LoadedLatency(loaded_latency::LoadedLatencyArgs { iperf, .. })
+            | Script::AttenSweep(atten_sweep::AttenSweepArgs { iperf, .. })
+            | Script::Fairness(fairness::FairnessArgs { iperf, .. })
+            | Script::Saturate(saturate::SaturateArgs { iperf, .. })
+            | Script::Mixed(mixed::MixedArgs { iperf, .. })
+            | Script::Interference(interference::InterferenceArgs { iperf, .. }) => {
+                iperf.network.check_channel()
+            }
+            Script::Latency(args) => args.network.check_channel(),
+            Script::AssocStorm(args) => args.network.check_channel(),
+            Script::Burst(args) => args.network.check_channel(),
+            Script::Multicast(args) => args.network.check_channel(),
+            Script::Soak(args) => args.network.check_channel(),
+            Script::PowerSave(args) => args.network.check_channel(),
+            Script::Baseline(args) => check_channel(args.frequency, args.bandwidth),
+            Script::Capture(args) => check_channel(args.frequency, args.bandwidth),
+            Script::ApSetup(args) => check_channel(args.frequency, args.bandwidth),
+            Script::Survey(args) => args
+                .frequencies
+                .iter()
+                .try_for_each(|&frequency| check_channel(frequency, args.bandwidth)),
+            _ => Ok(()),
+        }
+    }
+
+    /// Check that no host is given roles that conflict, like a client that is also the access
+    /// point, listing every conflict in the error. Hosts listed more than once in a role are
+    /// used once, with a warning.
+    pub fn check_hosts(&mut self) -> Result<(), String> {
+        self.dedup_hosts();
+        let violations = self.roles().map(|roles| roles.violations());
+        match violations {
+            Some(violations) if !violations.is_empty() => Err(format!(
+                "the hosts are given conflicting roles:\n  {}",
+                violations.join("\n  ")
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    /// The hosts the script uses, `None` if it may use any host of the hosts file.
+    pub fn hosts(&self) -> Option<BTreeSet<&str>> {
+        self.roles().map(|roles| roles.ids())
+    }
+
+    /// The roles the script gives its hosts, `None` if it does not name the hosts it uses.
+    pub fn roles(&self) -> Option<HostRoles<'_>> {
+        let roles = match self {
+            Script::Iperf(args) | Script::Verify(args) => iperf_roles(args),
+            Script::AttenSweep(atten_sweep::AttenSweepArgs { iperf, .. })
+            | Script::Saturate(saturate::SaturateArgs { iperf, .. }) => iperf_roles(iperf),
+            Script::LoadedLatency(args) => iperf_roles(&args.iperf).other(&args.latency_clients),
+            Script::Fairness(args) => iperf_roles(&args.iperf).other([&args.slow_client]),
+            Script::Mixed(args) => {
+                iperf_roles(&args.iperf).clients(args.tcp_clients.iter().chain(&args.udp_clients))
+            }
+            Script::Interference(args) => iperf_roles(&args.iperf)
+                .endpoint("interfering access point", args.interferer_ap.as_str())
+                .endpoint("interfering client", args.interferer_client.as_str()),
+            Script::Latency(args) => HostRoles::new()
+                .endpoint("access point", args.ap.as_str())
+                .clients(&args.clients)
+                .network(&args.network),
+            Script::AssocStorm(args) => HostRoles::new()
+                .endpoint("access point", args.ap.as_str())
+                .clients(&args.clients)
+                .network(&args.network),
+            Script::Burst(args) => HostRoles::new()
+                .endpoint("access point", args.ap.as_str())
+                .endpoint("server", args.server.as_deref())
+                .clients(&args.clients)
+                .network(&args.network),
+            Script::Multicast(args) => HostRoles::new()
+                .endpoint("access point", args.ap.as_str())
+                .endpoint("server", args.server.as_deref())
+                .clients(&args.clients)
+                .network(&args.network),
+            Script::Soak(args) => HostRoles::new()
+                .endpoint("access point", args.ap.as_str())
+                .endpoint("server", args.server.as_deref())
+                .clients(&args.clients)
+                .network(&args.network),
+            Script::PowerSave(args) => HostRoles::new()
+                .endpoint("access point", args.ap.as_str())
+                .endpoint("server", args.server.as_deref())
+                .clients(&args.clients)
+                .network(&args.network),
+            Script::Roam(args) => HostRoles::new()
+                .endpoint("first access point", args.from_ap.as_str())
+                .endpoint("second access point", args.to_ap.as_str())
+                .endpoint("server", args.server.as_str())
+                .clients([&args.client])
+                .monitors(args.from_monitors.iter().chain(&args.to_monitors), false),
+            Script::ApSetup(args) => HostRoles::new()
+                .endpoint("access point", args.ap.as_str())
+                .other(&args.verify_client),
+            Script::Baseline(baseline::BaselineArgs { monitors, .. })
+            | Script::Capture(capture::CaptureArgs { monitors, .. })
+            | Script::Survey(survey::SurveyArgs { monitors, .. }) => {
+                HostRoles::new().monitors(monitors, false)
+            }
+            Script::Push(transfer::PushArgs { hosts, .. })
+            | Script::Fetch(transfer::FetchArgs { hosts, .. }) => HostRoles::new().other(hosts),
+            // Without hosts these default to all of them.
+            Script::Cleanup(cleanup::CleanupArgs { hosts, .. })
+            | Script::HostInfo(host_info::HostInfoArgs { hosts, .. })
+                if !hosts.is_empty() =>
+            {
+                HostRoles::new().other(hosts)
+            }
+            _ => return None,
+        };
+        Some(roles)
+    }
+
+    /// Use the hosts that are listed more than once in a role once.
+    fn dedup_hosts(&mut self) {
+        match self {
+            Script::Iperf(args) | Script::Verify(args) => dedup_iperf_hosts(args),
+            Script::LoadedLatency(loaded_latency::LoadedLatencyArgs { iperf, .. })
+            | Script::AttenSweep(atten_sweep::AttenSweepArgs { iperf, .. })
+            | Script::Fairness(fairness::FairnessArgs { iperf, .. })
+            | Script::Saturate(saturate::SaturateArgs { iperf, .. })
+            | Script::Interference(interference::InterferenceArgs { iperf, .. }) => {
+                dedup_iperf_hosts(iperf)
+            }
+            Script::Mixed(args) => {
+                dedup_iperf_hosts(&mut args.iperf);
+                dedup_hosts("TCP clients", &mut args.tcp_clients);
+                dedup_hosts("UDP clients", &mut args.udp_clients);
+            }
+            Script::Latency(latency::LatencyArgs {
+                clients, network, ..
+            })
+            | Script::AssocStorm(assoc_storm::AssocStormArgs {
+                clients, network, ..
+            })
+            | Script::Burst(burst::BurstArgs {
+                clients, network, ..
+            })
+            | Script::Multicast(multicast::MulticastArgs {
+                clients, network, ..
+            })
+            | Script::Soak(soak::SoakArgs {
+                clients, network, ..
+            })
+            | Script::PowerSave(power_save::PowerSaveArgs {
+                clients, network, ..
+            }) => {
+                dedup_hosts("clients", clients);
+                dedup_hosts("monitors", &mut network.monitors);
+            }
+            Script::Roam(args) => {
+                dedup_hosts(
+                    "monitors of the first access point",
+                    &mut args.from_monitors,
+                );
+                dedup_hosts("monitors of the second access point", &mut args.to_monitors);
+            }
+            Script::Baseline(baseline::BaselineArgs { monitors, .. })
+            | Script::Capture(capture::CaptureArgs { monitors, .. })
+            | Script::Survey(survey::SurveyArgs { monitors, .. }) => {
+                dedup_hosts("monitors", monitors)
+            }
+            _ => {}
+        }
+    }
+}
+
+/// The roles of the hosts of an iperf experiment.
+fn iperf_roles(args: &iperf::IperfArgs) -> HostRoles<'_> {
+    HostRoles::new()
+        .endpoint("access point", args.ap.as_deref())
+        .endpoint("server", args.server.as_deref())
+        .clients(&args.clients)
+        .network(&args.network)
+}
+
+fn dedup_iperf_hosts(args: &mut iperf::IperfArgs) {
+    dedup_hosts("clients", &mut args.clients);
+    dedup_hosts("monitors", &mut args.network.monitors);
+}
+
+/// Mark an output directory as containing the results of a failed run by writing a `FAILED` file
+/// containing the reason.
+pub async fn mark_failed(out_path: &Path, reason: &str) -> anyhow::Result<()> {
+    tokio::fs::write(out_path.join("FAILED"), format!("{reason}\n"))
+        .await
+        .context("failed to write FAILED marker")
+}
+
+/// Mark an output directory as containing the partial results of a run that was cancelled, by
+/// writing an `ABORTED` file if the user stopped it or a `TIMED_OUT` file if it exceeded
+/// `--max-runtime`.
+pub async fn mark_aborted(out_path: &Path, reason: Reason) -> anyhow::Result<()> {
+    tokio::fs::create_dir_all(out_path)
+        .await
+        .context("could not create output folder")?;
+    let marker = match reason {
+        Reason::User => "ABORTED",
+        Reason::Timeout => "TIMED_OUT",
+    };
+    tokio::fs::write(out_path.join(marker), format!("{reason}\n"))
+        .await
+        .with_context(|| format!("failed to write {marker} marker"))
+}
+
+/// How [run] records a run, from the global command line arguments.
+#[derive(Debug, Clone)]
+pub struct RunOptions<'a> {
+    /// The name of the script, as given on the command line.
+    pub script_name: &'a str,
+    pub label: Option<&'a str>,
+    pub hosts_file: &'a Path,
+    /// Record the commands run on the hosts to `commands.jsonl`.
+    pub log_commands: bool,
+    /// Only print what the script would do, see [dry_run].
+    pub dry_run: bool,
+    /// Show a status line with the current phase, see [Progress].
+    pub progress: bool,
+    /// The directory with the `latest` link and the `index.csv` of all runs, `None` to not
+    /// update them.
+    pub index_dir: Option<&'a Path>,
+    /// Where to send a notification when the run finishes.
+    pub notify: NotifyOptions<'a>,
+    /// How the hosts are locked for the run.
+    pub lock: LockOptions,
+    /// The command to run on the controller after the run.
+    pub post_run: PostRunOptions<'a>,
+    /// The SQLite database to export the results to after the run.
+    pub export_sqlite: Option<&'a Path>,
+    /// When the hosts were connected to, recorded as the first phase in `timings.ron`.
+    pub connect_time: Option<Range<Instant>>,
+}
+
+/// Key numbers of the results of a run by name, like `total_throughput`, for scripts that provide
+/// them.
+pub type KeyNumbers = BTreeMap<String, f64>;
+
+/// How a run went, returned by [run].
+#[derive(Debug)]
+pub struct RunReport {
+    pub result: anyhow::Result<()>,
+    pub status: Status,
+    pub duration: Duration,
+    /// The commands that failed on each host.
+    pub host_errors: BTreeMap<HostId, HostErrors>,
+    pub numbers: KeyNumbers,
+}
+
+/// Run a script, recording how it was started to `meta.ron`, how long its phases took to
+/// `timings.ron` and the commands it runs to `commands.jsonl` in the output directory if
+/// `log_commands` is set.
+///
+/// The hosts the script uses are locked while it runs, so other controllers do not use them at the same
+/// time. When `cancel` is cancelled the script is stopped, the hosts are cleaned up and [Aborted] is
+/// returned, or [TimedOut] if it was cancelled because of `--max-runtime`. Afterwards the run is
+/// added to the `index.csv` of the index directory, its `latest` link is pointed at the output
+/// directory if the run succeeded, and the notifications in `options` are sent.
+///
+/// The post-run command in `options` runs after the results are written. If it fails the run
+/// only fails as well if it is strict.
+pub async fn run(
+    args: Script,
+    hosts: Hosts,
+    out_path: &Path,
+    options: &RunOptions<'_>,
+    cancel: &CancellationToken,
+) -> RunReport {
+    let start = Instant::now();
+    command_log::start_tally();
+    let mut numbers = KeyNumbers::new();
+    let result = run_recorded(args, hosts, out_path, options, cancel, &mut numbers).await;
+    RunReport {
+        status: Status::of(&result),
+        result,
+        duration: start.elapsed(),
+        host_errors: command_log::finish_tally(),
+        numbers,
+    }
+}
+
+/// The part of [run] that does not count the errors.
+async fn run_recorded(
+    args: Script,
+    hosts: Hosts,
+    out_path: &Path,
+    options: &RunOptions<'_>,
+    cancel: &CancellationToken,
+    numbers: &mut KeyNumbers,
+) -> anyhow::Result<()> {
+    if options.dry_run {
+        return self::dry_run(args, &hosts, out_path).await;
+    }
+
+    // Only the hosts taking part are locked, so runs on other hosts are not blocked. The locks
+    // are taken before anything is written, so a run that can not get them leaves no results
+    // behind. From here on every path has to release the locks.
+    let used = args.hosts();
+    let run_hosts = hosts
+        .iter()
+        .filter(|host| {
+            used.as_ref()
+                .is_none_or(|ids| ids.contains(host.id.as_str()))
+        })
+        .cloned()
+        .collect::<Vec<_>>();
+    let locks = lock::acquire(run_hosts.clone(), options.lock).await?;
+    let start = SystemTime::now();
+    let prepared = async {
+        tokio::fs::create_dir_all(out_path)
+            .await
+            .context("could not create output folder")?;
+        let mut meta = Meta::new(options.hosts_file).await?;
+        meta.record_file_stems(hosts.iter().map(|host| host.id.as_str()));
+        meta.write(out_path).await?;
+        let log = options
+            .log_commands
+            .then(|| CommandLog::start(out_path))
+            .transpose()?;
+        anyhow::Ok((meta, log))
+    };
+    let (mut meta, log) = match prepared.await {
+        Ok(prepared) => prepared,
+        Err(err) => {
+            locks.release().await;
+            return Err(err);
+        }
+    };
+
+    let timing = Timing::new();
+    if let Some(connect_time) = options.connect_time.clone() {
+        timing.record(TimedPhase::Connect, connect_time);
+    }
+    let progress = Progress::start(options.progress);
+    let mut result = timing::scope(&timing, run_with(args, &hosts, out_path, cancel))
+        .await
+        .map(|key_numbers| *numbers = key_numbers);
+    progress.finish();
+    let timings = timing.finish();
+    timings.log_summary();
+    if let Err(err) = write_artifact(out_path, &timings).await {
+        warn!("Could not save the phase timings: {err:#}");
+    }
+    let _finishing = cancel.finishing();
+    if matches!(&result, Err(err) if err.is::<Aborted>()) {
+        let reason = cancel.reason().unwrap_or(Reason::User);
+        // Scripts that did not stop by themselves left their daemons running.
+        info!("Cleaning up the hosts after aborting");
+        if let Err(err) = mark_aborted(out_path, reason).await {
+            warn!("Could not mark the results as aborted: {err:?}");
+        }
+        let report = cleanup::clean_up_after_abort(run_hosts).await;
+        if let Err(err) = cleanup::write_report(out_path, &report).await {
+            warn!("Could not save the cleanup report: {err:?}");
+        }
+        if reason == Reason::Timeout {
+            result = Err(TimedOut.into());
+        }
+    }
+    locks.release().await;
+
+    if let Some(log) = log {
+        if let Err(err) = log.finish() {
+            warn!("Could not save the command log: {err:?}");
+        }
+    }
+    meta.finish(&result);
+    if let Err(err) = meta.write(out_path).await {
+        warn!("Could not save the metadata: {err:?}");
+    }
+
+    if let Some(command) = options.post_run.command {
+        if result.is_ok() || options.post_run.always {
+            let post_run = post_run::run(command, out_path).await;
+            if !post_run.succeeded() && options.post_run.strict && result.is_ok() {
+                result = Err(anyhow::anyhow!(
+                    "post-run command failed: {}",
+                    post_run.error.as_deref().unwrap_or_default()
+                ));
+                meta.finish(&result);
+            }
+            meta.post_run = Some(post_run);
+            if let Err(err) = meta.write(out_path).await {
+                warn!("Could not save the metadata: {err:?}");
+            }
+        }
+    }
+
+    if let Some(db) = options.export_sqlite {
+        let labels = RunLabels {
+            script: Some(options.script_name),
+            label: options.label,
+        };
+        match sqlite::export(db, out_path, labels).await {
+            Ok(runs) => info!("Exported {runs} runs to {}", db.display()),
+            Err(err) => warn!("Could not export the results: {err:#}"),
+        }
+    }
+
+    if let Some(index_dir) = options.index_dir {
+        let status = meta.status.as_ref().map_or("unknown", Status::name);
+        let entry = IndexEntry {
+            start,
+            script: options.script_name,
+            label: options.label,
+            out_path,
+            status,
+        };
+        if let Err(err) = output::append_index(index_dir, &entry) {
+            warn!("Could not add the run to the index: {err:#}");
+        }
+        if result.is_ok() {
+            if let Err(err) = output::link_latest(index_dir, out_path) {
+                warn!("Could not link the results as the latest: {err:#}");
+            }
+        }
+    }
+
+    let notification = Notification {
+        out_dir: out_path,
+        status: meta.status.as_ref().map_or("unknown", Status::name),
+        duration: start.elapsed().unwrap_or_default().as_secs_f64(),
+        script: options.script_name,
+    };
+    notify::notify(&options.notify, &notification).await;
+    result
+}
+
+/// Validate the arguments of a script and print which hosts it uses, the commands it runs and the
+/// files it writes, without running anything on the hosts.
+pub async fn dry_run(args: Script, hosts: &Hosts, out_path: &Path) -> anyhow::Result<()> {
+    match args {
+        Script::Iperf(args) => iperf::dry_run(args, hosts, out_path),
+        Script::Mixed(args) => mixed::dry_run(args, hosts, out_path),
+        Script::Replay(args) => replay::dry_run(args, hosts, out_path).await,
+        _ => anyhow::bail!("--dry-run is not supported by this script"),
+    }
+}
+
+/// Run a script with already connected hosts, so multiple scripts can share the connections.
+///
+/// Scripts that do not handle `cancel` themselves are dropped when it is cancelled, which stops
+/// them at their next await point. Returns the key numbers of the results, for the scripts that
+/// provide them.
+pub async fn run_with(
+    args: Script,
+    hosts: &Hosts,
+    out_path: &Path,
+    cancel: &CancellationToken,
+) -> anyhow::Result<KeyNumbers> {
     match args {
-        Script::Iperf(args) => iperf::run(args, hosts, out_path).await,
+        Script::Iperf(args) => return iperf::run(args, hosts, out_path, cancel).await,
+        Script::Latency(args) => latency::run(args, hosts, out_path, cancel).await,
+        Script::LoadedLatency(args) => loaded_latency::run(args, hosts, out_path, cancel).await,
+        Script::Survey(args) => survey::run(args, hosts, out_path, cancel).await,
+        Script::AssocStorm(args) => assoc_storm::run(args, hosts, out_path, cancel).await,
+        Script::Roam(args) => roam::run(args, hosts, out_path, cancel).await,
+        Script::AttenSweep(args) => atten_sweep::run(args, hosts, out_path, cancel).await,
+        Script::Fairness(args) => fairness::run(args, hosts, out_path, cancel).await,
+        Script::Burst(args) => burst::run(args, hosts, out_path, cancel).await,
+        Script::Saturate(args) => saturate::run(args, hosts, out_path, cancel).await,
+        Script::Baseline(args) => baseline::run(args, hosts, out_path, cancel).await,
+        Script::Mixed(args) => return mixed::run(args, hosts, out_path, cancel).await,
+        Script::Multicast(args) => multicast::run(args, hosts, out_path, cancel).await,
+        Script::Capture(args) => capture::run(args, hosts, out_path, cancel).await,
+        Script::ApSetup(args) => cancel.run(ap_setup::run(args, hosts, out_path)).await,
+        Script::Cleanup(args) => cancel.run(cleanup::run(args, hosts, out_path)).await,
+        Script::HostInfo(args) => cancel.run(host_info::run(args, hosts, out_path)).await,
+        Script::Push(args) => cancel.run(transfer::push(args, hosts, out_path)).await,
+        Script::Fetch(args) => cancel.run(transfer::fetch(args, hosts, out_path)).await,
+        Script::Interference(args) => {
+            return interference::run(args, hosts, out_path, cancel).await
+        }
+        Script::Soak(args) => soak::run(args, hosts, out_path, cancel).await,
+        Script::PowerSave(args) => power_save::run(args, hosts, out_path, cancel).await,
+        Script::Verify(args) => cancel.run(verify::run(args, hosts, out_path)).await,
+        Script::Replay(args) => return replay::run(args, hosts, out_path, cancel).await,
+        Script::Plan(args) => plan::run(args, hosts, out_path, cancel).await,
+        Script::ListHosts(_) => anyhow::bail!("list-hosts can only be run on its own"),
+        Script::Reparse(_) => anyhow::bail!("reparse can only be run on its own"),
+        Script::Compare(_) => anyhow::bail!("compare can only be run on its own"),
+        Script::Anonymize(_) => anyhow::bail!("anonymize can only be run on its own"),
+        Script::Report(_) => anyhow::bail!("report can only be run on its own"),
+    }?;
+    Ok(KeyNumbers::new())
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::{CommandFactory, FromArgMatches};
+
+    use super::*;
+
+    fn parse(args: &str) -> Script {
+        let argv = ["controller"].into_iter().chain(args.split_whitespace());
+        Script::from_arg_matches(&Script::command().get_matches_from(argv)).unwrap()
+    }
+
+    #[test]
+    fn hosts_of_iperf_are_its_roles() {
+        let script = parse(
+            "iperf --ap ap --server srv --clients a,b --monitors m -F 5180 -B 20 --ssid s \
+             -U true -T 10M",
+        );
+        let hosts = script.hosts().unwrap();
+        assert_eq!(
+            hosts.into_iter().collect::<Vec<_>>(),
+            ["a", "ap", "b", "m", "srv"]
+        );
+    }
+
+    #[test]
+    fn hosts_of_cleanup_default_to_all() {
+        assert_eq!(parse("cleanup").hosts(), None);
+        let script = parse("cleanup --hosts a,b");
+        assert_eq!(script.hosts().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn arguments_dump_keeps_hostile_ssids() {
+        for ssid in [
+            "Bob's Wi-Fi",
+            "\"quoted\" $HOME; reboot",
+            "caf\u{e9} \u{1f4f6}",
+            r"a\b",
+        ] {
+            let argv = [
+                "controller",
+                "latency",
+                "--ap",
+                "ap",
+                "--clients",
+                "a",
+                "--no-monitor",
+                "-F",
+                "5180",
+                "-B",
+                "20",
+                "--ssid",
+                ssid,
+            ];
+            let script =
+                Script::from_arg_matches(&Script::command().get_matches_from(argv)).unwrap();
+            let Script::Latency(args) = &script else {
+                panic!("parsed {script:?}");
+            };
+            // Written like `arguments.ron`, and read back like a replay.
+            let dump = ron::ser::to_string_pretty(args, ron::ser::PrettyConfig::new()).unwrap();
+            let read: latency::LatencyArgs = ron::from_str(&dump).unwrap();
+            assert_eq!(read.network.ssid, ssid, "{dump}");
+        }
     }
 }
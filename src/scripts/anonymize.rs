@@ -0,0 +1,175 @@
+//! Anonymize the captures in a results directory before sharing it, writing an
+//! `<name>-anon.pcapng` next to every capture and the pseudonyms of the addresses to a separate
+//! mapping file.
+
+use std::{
+    collections::BTreeMap,
+    fs::File,
+    io::BufWriter,
+    path::{Path, PathBuf},
+};
+
+use anyhow::Context;
+use clap::Parser;
+use ron::ser::{to_string_pretty, PrettyConfig};
+use serde::{Deserialize, Serialize};
+use tracing::info;
+
+use crate::capture::{anonymize, AnonymizePolicy, AnonymizeStats};
+
+/// The suffix of the anonymized captures, which are skipped when anonymizing again.
+const SUFFIX: &str = "-anon.pcapng";
+
+#[derive(Parser, Debug, Clone, Serialize, Deserialize)]
+pub struct AnonymizeArgs {
+    /// The results directory with the captures to anonymize, including those in its
+    /// subdirectories.
+    #[clap(long)]
+    pub dir: PathBuf,
+    /// The secret the pseudonyms are derived from. Use the same key for all captures that should
+    /// get the same pseudonyms, and keep it private.
+    #[clap(long, env = "ANONYMIZE_KEY", hide_env_values = true)]
+    #[serde(skip_serializing, default)]
+    pub key: String,
+    /// Where to write the address of every pseudonym, which has to be outside of `--dir`.
+    #[clap(long)]
+    pub mapping: PathBuf,
+    /// Keep the first three bytes of the addresses, which identify the vendor of the device.
+    #[clap(long)]
+    #[serde(default)]
+    pub keep_oui: bool,
+}
+
+/// Anonymize every capture below `args.dir`. Returns how many captures were anonymized.
+pub fn run(args: &AnonymizeArgs) -> anyhow::Result<usize> {
+    if !args.dir.is_dir() {
+        anyhow::bail!("{} is not a directory", args.dir.display());
+    }
+    if args.key.is_empty() {
+        anyhow::bail!("the key can not be empty");
+    }
+    let dir = args
+        .dir
+        .canonicalize()
+        .with_context(|| format!("could not resolve {}", args.dir.display()))?;
+    let mapping_dir = match args.mapping.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent.to_owned(),
+        _ => PathBuf::from("."),
+    };
+    let mapping_dir = mapping_dir
+        .canonicalize()
+        .with_context(|| format!("could not resolve {}", mapping_dir.display()))?;
+    if mapping_dir.starts_with(&dir) {
+        anyhow::bail!(
+            "the mapping would be shared with the captures, write it outside of {}",
+            args.dir.display()
+        );
+    }
+
+    let policy = AnonymizePolicy {
+        key: args.key.as_bytes().to_vec(),
+        keep_oui: args.keep_oui,
+    };
+    let mut addresses = BTreeMap::new();
+    let mut anonymized = 0;
+    let mut pending = vec![dir];
+    while let Some(dir) = pending.pop() {
+        let entries =
+            std::fs::read_dir(&dir).with_context(|| format!("could not read {}", dir.display()))?;
+        for entry in entries {
+            let entry = entry.with_context(|| format!("could not read {}", dir.display()))?;
+            let path = entry.path();
+            let file_type = entry
+                .file_type()
+                .with_context(|| format!("could not read {}", path.display()))?;
+            // Links are not followed, like `latest` in the index directory, which points at a run
+            // that is anonymized already and could point at a parent.
+            if file_type.is_symlink() {
+                continue;
+            } else if file_type.is_dir() {
+                pending.push(path);
+            } else if let Some(output) = anonymized_path(&path) {
+                let stats = anonymize_file(&path, &output, &policy)?;
+                info!(
+                    "Anonymized {} with {} packets, {} kept as they are",
+                    path.display(),
+                    stats.packets,
+                    stats.skipped
+                );
+                addresses.extend(stats.addresses);
+                anonymized += 1;
+            }
+        }
+    }
+
+    let dump = to_string_pretty(&addresses, PrettyConfig::new())
+        .context("failed to serialize address mapping")?;
+    std::fs::write(&args.mapping, dump)
+        .with_context(|| format!("could not write {}", args.mapping.display()))?;
+    info!(
+        "Anonymized {anonymized} captures with {} addresses, wrote the mapping to {}",
+        addresses.len(),
+        args.mapping.display()
+    );
+    Ok(anonymized)
+}
+
+/// Where the anonymized copy of the capture at `path` is written, `None` if it is not a capture
+/// or is anonymized already.
+fn anonymized_path(path: &Path) -> Option<PathBuf> {
+    let name = path.file_name()?.to_str()?;
+    let stem = name.strip_suffix(".pcapng")?;
+    (!name.ends_with(SUFFIX)).then(|| path.with_file_name(format!("{stem}{SUFFIX}")))
+}
+
+fn anonymize_file(
+    path: &Path,
+    output: &Path,
+    policy: &AnonymizePolicy,
+) -> anyhow::Result<AnonymizeStats> {
+    let reader = File::open(path).with_context(|| format!("could not open {}", path.display()))?;
+    let writer =
+        File::create(output).with_context(|| format!("could not create {}", output.display()))?;
+    anonymize(reader, BufWriter::new(writer), policy)
+        .with_context(|| format!("could not anonymize {}", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::symlink;
+
+    use super::*;
+
+    #[test]
+    fn links_are_not_followed() {
+        let dir = tempfile::tempdir().unwrap();
+        let results = dir.path().join("results");
+        let run_dir = results.join("run");
+        std::fs::create_dir_all(&run_dir).unwrap();
+        // An empty capture, which has no packets to anonymize.
+        std::fs::write(run_dir.join("monitor.pcapng"), "").unwrap();
+        symlink(&run_dir, results.join("latest")).unwrap();
+        symlink(&results, run_dir.join("loop")).unwrap();
+
+        let args = AnonymizeArgs {
+            dir: results.clone(),
+            key: "secret".to_string(),
+            mapping: dir.path().join("mapping.ron"),
+            keep_oui: false,
+        };
+        assert_eq!(run(&args).unwrap(), 1);
+        assert!(run_dir.join("monitor-anon.pcapng").exists());
+        // Anonymized captures are skipped when anonymizing again.
+        assert_eq!(run(&args).unwrap(), 1);
+    }
+
+    #[test]
+    fn anonymized_paths() {
+        assert_eq!(
+            anonymized_path(Path::new("run/ap.pcapng")),
+            Some(PathBuf::from("run/ap-anon.pcapng"))
+        );
+        assert_eq!(anonymized_path(Path::new("run/ap-anon.pcapng")), None);
+        assert_eq!(anonymized_path(Path::new("run/ap.txt")), None);
+    }
+}
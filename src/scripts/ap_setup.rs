@@ -0,0 +1,287 @@
+//! Configure the radio of an access point, so the network of an experiment can be reproduced.
+
+use std::{
+    path::Path,
+    time::{Duration, Instant},
+};
+
+use anyhow::Context;
+use clap::Parser;
+use ron::ser::{to_string_pretty, PrettyConfig};
+use serde::{Deserialize, Serialize};
+use tokio::time::sleep;
+use tracing::{debug, info, warn};
+
+use crate::{
+    driver::{
+        ap::{self, ApConfig, ApPlatform, Security},
+        wifi,
+    },
+    hosts::{Host, HostId, Hosts},
+    mac::MacAddr,
+    utils::{parse_bandwidth, parse_frequency},
+};
+
+#[derive(Parser, Debug, Clone, Serialize, Deserialize)]
+pub struct ApSetupArgs {
+    /// The host id of the access point.
+    #[clap(long)]
+    pub ap: String,
+    /// The SSID of the network.
+    #[clap(long)]
+    pub ssid: String,
+    /// The frequency of the primary channel in MHz.
+    #[clap(short = 'F', long, value_parser = parse_frequency)]
+    pub frequency: u32,
+    /// The width of the channel in MHz.
+    #[clap(short = 'B', long, value_parser = parse_bandwidth)]
+    pub bandwidth: u32,
+    /// The security of the network.
+    #[clap(long, default_value = "open")]
+    #[serde(default = "default_security")]
+    pub security: Security,
+    /// The passphrase of the network, required unless the network is open.
+    #[clap(long)]
+    #[serde(default)]
+    pub passphrase: Option<String>,
+    /// Enable 802.11ax (HE).
+    #[clap(long)]
+    #[serde(default)]
+    pub he: bool,
+    /// The country code to configure, for example `NL`.
+    #[clap(long)]
+    #[serde(default)]
+    pub country: Option<String>,
+    /// How to configure the access point. Detected from the access point if not set.
+    #[clap(long)]
+    #[serde(default)]
+    pub platform: Option<ApPlatform>,
+    /// The uci section of the radio to configure on OpenWrt.
+    #[clap(long, default_value = "radio0")]
+    #[serde(default = "default_radio")]
+    pub radio: String,
+    /// The uci section of the wireless interface on OpenWrt. Defaults to `default_<radio>`.
+    #[clap(long)]
+    #[serde(default)]
+    pub uci_iface: Option<String>,
+    /// Where to write the hostapd configuration on generic Linux.
+    #[clap(long, default_value = "/etc/hostapd/hostapd.conf")]
+    #[serde(default = "default_hostapd_conf")]
+    pub hostapd_conf: String,
+    /// The host id of a client that scans for the network to verify the configuration.
+    #[clap(long)]
+    #[serde(default)]
+    pub verify_client: Option<String>,
+    /// How long to wait for the network to come up in seconds.
+    #[clap(long, default_value = "30")]
+    #[serde(default = "default_timeout")]
+    pub timeout: u64,
+    /// Print the configuration changes without applying them.
+    #[clap(long)]
+    #[serde(default)]
+    pub dry_run: bool,
+}
+
+// The defaults of the arguments when they are read from a config file. They match the
+// defaults of the command line.
+fn default_security() -> Security {
+    Security::Open
+}
+
+fn default_radio() -> String {
+    "radio0".to_string()
+}
+
+fn default_hostapd_conf() -> String {
+    "/etc/hostapd/hostapd.conf".to_string()
+}
+
+fn default_timeout() -> u64 {
+    30
+}
+
+/// The configured network, written to `ap.ron`.
+#[derive(Debug, Clone, Serialize)]
+pub struct ApSetup {
+    pub platform: ApPlatform,
+    pub bssid: MacAddr,
+    /// The client that found the network in a scan, and the signal strength it saw in dBm.
+    pub verified_by: Option<(HostId, Option<f64>)>,
+}
+
+pub async fn run(args: ApSetupArgs, hosts: &Hosts, out_path: &Path) -> anyhow::Result<()> {
+    let config = ApConfig {
+        ssid: args.ssid.clone(),
+        frequency: args.frequency,
+        bandwidth: args.bandwidth,
+        security: args.security,
+        passphrase: args.passphrase.clone(),
+        he: args.he,
+        country: args.country.clone(),
+    };
+    config.validate().context("invalid configuration")?;
+
+    tokio::fs::create_dir_all(out_path)
+        .await
+        .context("could not create output folder")?;
+    // The passphrase is left out of the saved arguments.
+    let saved = ApSetupArgs {
+        passphrase: args.passphrase.as_ref().map(|_| "<hidden>".to_string()),
+        ..args.clone()
+    };
+    let args_dump =
+        to_string_pretty(&saved, PrettyConfig::new()).context("failed to serialize args info")?;
+    tokio::fs::write(out_path.join("arguments.ron"), args_dump)
+        .await
+        .context("failed to save arguments")?;
+
+    let access_point = hosts.get(&args.ap).context("access point id not found")?;
+    let ifname = access_point.extra_data.interface_name();
+    let platform = match args.platform {
+        Some(platform) => platform,
+        None => ap::detect_platform(access_point).await?,
+    };
+    let changes = match platform {
+        ApPlatform::Openwrt => {
+            let iface = args
+                .uci_iface
+                .clone()
+                .unwrap_or_else(|| format!("default_{}", args.radio));
+            config.uci_batch(&args.radio, &iface)?
+        }
+        ApPlatform::Hostapd => {
+            let ifname = ifname.with_context(|| {
+                format!(
+                    "access point `{}` has no interface name configured for hostapd",
+                    access_point.id
+                )
+            })?;
+            config.hostapd_conf(ifname)?
+        }
+    };
+
+    if args.dry_run {
+        info!("Dry run, the following {platform:?} configuration is not applied:\n{changes}");
+        return Ok(());
+    }
+
+    info!(
+        host = access_point.id,
+        "Applying {platform:?} configuration"
+    );
+    debug!("Configuration:\n{changes}");
+    match platform {
+        ApPlatform::Openwrt => ap::apply_uci(access_point, &changes).await?,
+        ApPlatform::Hostapd => {
+            ap::apply_hostapd(access_point, &args.hostapd_conf, &changes).await?
+        }
+    }
+
+    let ifname = ifname.with_context(|| {
+        format!(
+            "access point `{}` has no interface name configured to check the network",
+            access_point.id
+        )
+    })?;
+    let bssid = wait_for_bss(access_point, ifname, &args).await?;
+    info!("Network `{}` is up with BSSID {bssid}", args.ssid);
+
+    let verified_by = match &args.verify_client {
+        Some(client) => {
+            let client = hosts.get(client).context("verify client id not found")?;
+            let signal = verify_scan(client, bssid, &args).await?;
+            Some((client.id.clone(), signal))
+        }
+        None => {
+            warn!("No --verify-client given, the network is not verified by a scan");
+            None
+        }
+    };
+
+    let setup = ApSetup {
+        platform,
+        bssid,
+        verified_by,
+    };
+    let dump =
+        to_string_pretty(&setup, PrettyConfig::new()).context("failed to serialize setup")?;
+    tokio::fs::write(out_path.join("ap.ron"), dump)
+        .await
+        .context("failed to save setup")
+}
+
+/// Wait until the interface of the access point operates on the configured channel, returning
+/// its BSSID.
+async fn wait_for_bss(ap: &Host, ifname: &str, args: &ApSetupArgs) -> anyhow::Result<MacAddr> {
+    let start = Instant::now();
+    loop {
+        // The interface may briefly not exist while the configuration is reloaded.
+        match wifi::interface_info(ap, ifname).await {
+            Ok(info)
+                if info.frequency == Some(args.frequency) && info.width == Some(args.bandwidth) =>
+            {
+                if let Some(addr) = info.addr {
+                    return Ok(addr);
+                }
+            }
+            Ok(info) => debug!(?info, "Network is not up yet"),
+            Err(err) => debug!("Could not query the access point: {err:?}"),
+        }
+        if start.elapsed() > Duration::from_secs(args.timeout) {
+            anyhow::bail!(
+                "the network did not come up on {} MHz within {}s",
+                args.frequency,
+                args.timeout
+            );
+        }
+        sleep(Duration::from_secs(1)).await;
+    }
+}
+
+/// Scan from `client` until the network is found, returning the signal strength.
+async fn verify_scan(
+    client: &Host,
+    bssid: MacAddr,
+    args: &ApSetupArgs,
+) -> anyhow::Result<Option<f64>> {
+    let ifname = client.extra_data.interface_name().with_context(|| {
+        format!(
+            "client `{}` has no interface name configured to scan",
+            client.id
+        )
+    })?;
+    // A single scan can miss a network, especially right after it came up.
+    const ATTEMPTS: u32 = 3;
+    for attempt in 1..=ATTEMPTS {
+        match wifi::scan(client, ifname).await {
+            Ok(results) => {
+                let found = results.iter().find(|r| r.bssid.parse() == Ok(bssid));
+                match found {
+                    Some(found) if found.ssid.as_deref() == Some(args.ssid.as_str()) => {
+                        info!(
+                            host = client.id,
+                            "Found `{}` on {} MHz",
+                            args.ssid,
+                            found
+                                .frequency
+                                .map_or("unknown".to_string(), |f| f.to_string())
+                        );
+                        return Ok(found.signal);
+                    }
+                    Some(found) => anyhow::bail!(
+                        "{bssid} is advertising SSID {:?} instead of `{}`",
+                        found.ssid,
+                        args.ssid
+                    ),
+                    None => debug!(attempt, "Network not found in scan"),
+                }
+            }
+            Err(err) => warn!(host = client.id, "Scan failed: {err:?}"),
+        }
+        sleep(Duration::from_secs(2)).await;
+    }
+    anyhow::bail!(
+        "`{}` did not find {bssid} after {ATTEMPTS} scans",
+        client.id
+    )
+}
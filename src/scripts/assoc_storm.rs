@@ -0,0 +1,372 @@
+//! Measure how long association and DHCP take when many clients join at the same time.
+
+use std::{
+    fmt::Write,
+    path::Path,
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+use anyhow::{anyhow, Context};
+use clap::Parser;
+use ron::ser::{to_string_pretty, PrettyConfig};
+use serde::{Deserialize, Serialize};
+use tokio::{
+    task::JoinSet,
+    time::{sleep, timeout, Instant},
+};
+use tracing::{debug, info, warn};
+
+use crate::{
+    cancel::CancellationToken,
+    hosts::{Host, HostId, Hosts},
+    scripts::monitoring::{run_captured, MonitorArgs},
+    utils::{for_each_sequential, unix_time, OnError},
+};
+
+#[derive(Parser, Debug, Clone, Serialize, Deserialize)]
+pub struct AssocStormArgs {
+    /// The host id of the access point.
+    #[clap(long)]
+    pub ap: String,
+    /// The host ids of the clients that will join the network.
+    #[clap(long, required = true, value_delimiter = ',', num_args = 1..)]
+    pub clients: Vec<String>,
+    /// How many times all clients disconnect and join again.
+    #[clap(long, default_value = "5")]
+    #[serde(default = "default_rounds")]
+    pub rounds: u32,
+    /// The time between starting two consecutive clients in seconds. By default all clients
+    /// join simultaneously.
+    #[clap(long, default_value = "0", value_parser = parse_stagger)]
+    #[serde(default)]
+    pub stagger: f64,
+    /// Let the clients join one at a time. Each client starts `--stagger` seconds after the
+    /// previous one joined or failed to.
+    #[clap(long)]
+    #[serde(default)]
+    pub sequential: bool,
+    /// How long a client gets to associate and obtain an address in seconds, after which it is
+    /// recorded as failed.
+    #[clap(long, default_value = "30")]
+    #[serde(default = "default_timeout")]
+    pub timeout: u64,
+    /// How long to wait after disconnecting the clients before they join again in seconds.
+    #[clap(long, default_value = "2")]
+    #[serde(default = "default_pause")]
+    pub pause: u64,
+    #[command(flatten)]
+    pub network: MonitorArgs,
+}
+
+// The defaults of the arguments when they are read from a config file. They match the
+// defaults of the command line.
+fn default_rounds() -> u32 {
+    5
+}
+
+fn default_timeout() -> u64 {
+    30
+}
+
+fn default_pause() -> u64 {
+    2
+}
+
+/// Parse the stagger, which must be a number of seconds that is not negative.
+fn parse_stagger(s: &str) -> Result<f64, String> {
+    let stagger: f64 = s
+        .trim()
+        .parse()
+        .map_err(|_| format!("invalid stagger `{s}`, expected a number of seconds"))?;
+    if Duration::try_from_secs_f64(stagger).is_err() {
+        return Err(format!(
+            "invalid stagger `{s}`, must be a finite number of seconds that is not negative"
+        ));
+    }
+    Ok(stagger)
+}
+
+/// How a single client joined the network in a round.
+#[derive(Debug, Clone, Serialize)]
+pub struct JoinTiming {
+    pub round: u32,
+    pub host: HostId,
+    /// When the client started joining, in seconds since the unix epoch.
+    pub start: f64,
+    /// The time from starting until the client was associated in seconds.
+    pub association: Option<f64>,
+    /// The time from being associated until the client had an IP address in seconds.
+    pub dhcp: Option<f64>,
+    /// Why the client did not join, if it failed.
+    pub error: Option<String>,
+}
+
+impl JoinTiming {
+    pub fn success(&self) -> bool {
+        self.error.is_none()
+    }
+}
+
+pub async fn run(
+    args: AssocStormArgs,
+    hosts: &Hosts,
+    out_path: &Path,
+    cancel: &CancellationToken,
+) -> anyhow::Result<()> {
+    tokio::fs::create_dir_all(out_path)
+        .await
+        .context("could not create output folder")?;
+    let args_dump =
+        to_string_pretty(&args, PrettyConfig::new()).context("failed to serialize args info")?;
+    tokio::fs::write(out_path.join("arguments.ron"), args_dump)
+        .await
+        .context("failed to save arguments")?;
+
+    let clients: Vec<_> = hosts
+        .get_many(&args.clients)
+        .map_err(|missing| anyhow!("no host with id {missing}"))?
+        .cloned()
+        .collect();
+    if let Some(client) = clients
+        .iter()
+        .find(|c| c.extra_data.interface_name().is_none())
+    {
+        anyhow::bail!(
+            "client `{}` needs an interface name to measure its connection",
+            client.id
+        );
+    }
+    let access_point = hosts.get(&args.ap).context("access point id not found")?;
+    let bssid = args.network.check_access_point(access_point).await?;
+
+    let join_timeout = Duration::from_secs(args.timeout);
+    // The capture covers the pause, the stagger of the last client and its timeout. It is stopped
+    // as soon as the round completes.
+    let stagger = Duration::from_secs_f64(args.stagger);
+    let last_start = if args.sequential {
+        (join_timeout + stagger) * clients.len().saturating_sub(1) as u32
+    } else {
+        stagger * clients.len().saturating_sub(1) as u32
+    };
+    let capture_duration = Duration::from_secs(args.pause + args.timeout) + last_start;
+
+    let mut timings = Vec::new();
+    for round in 1..=args.rounds {
+        cancel.check()?;
+        info!("Starting round {round}/{}", args.rounds);
+        let round_path = out_path.join(format!("round-{round}"));
+        tokio::fs::create_dir_all(&round_path)
+            .await
+            .context("could not create round folder")?;
+
+        let mut monitor = args
+            .network
+            .capture(hosts, bssid, capture_duration, &round_path)
+            .await?;
+
+        let joins = join_round(&args, &clients, round, stagger, join_timeout);
+        let mut round_timings =
+            run_captured(&mut monitor, cancel, async { Ok(joins.await) }).await?;
+        round_timings.sort_by(|a, b| a.host.cmp(&b.host));
+
+        if let Some(monitor) = monitor {
+            if let Err(err) = monitor.stop_and_collect("round complete").await {
+                warn!("Could not collect the captures of round {round}: {err:?}");
+            }
+        }
+
+        let failed: Vec<_> = round_timings.iter().filter(|t| !t.success()).collect();
+        info!(
+            "Round {round}: {} of {} clients joined",
+            round_timings.len() - failed.len(),
+            round_timings.len()
+        );
+        for timing in &failed {
+            warn!(
+                host = timing.host,
+                "Failed to join: {}",
+                timing.error.as_deref().unwrap_or_default()
+            );
+            let Some(client) = hosts.get(&timing.host) else {
+                continue;
+            };
+            if let Err(err) = client.reset_interface().await {
+                warn!(host = client.id, "Could not reset interface: {err:?}");
+            }
+        }
+
+        timings.extend(round_timings);
+        // Written after every round, so the completed rounds are kept if a later one fails.
+        write_timings(out_path, &timings).await?;
+    }
+
+    info!("Join times per client:\n{}", timing_table(&timings));
+    Ok(())
+}
+
+/// Disconnect all clients and time how long each of them takes to join again.
+async fn join_round(
+    args: &AssocStormArgs,
+    clients: &[Arc<Host>],
+    round: u32,
+    stagger: Duration,
+    join_timeout: Duration,
+) -> Vec<JoinTiming> {
+    let mut disconnects = JoinSet::new();
+
+    for client in clients.iter().cloned() {
+        disconnects.spawn(async move { (client.disconnect().await, client) });
+    }
+    for (result, client) in disconnects.join_all().await {
+        // Disconnecting fails if the client was not connected, which is fine.
+        if let Err(err) = result {
+            debug!(host = client.id, "Could not disconnect: {err:#}");
+        }
+    }
+    sleep(Duration::from_secs(args.pause)).await;
+
+    if args.sequential {
+        let ssid = &args.network.ssid;
+        for_each_sequential(clients, stagger, OnError::Continue, |client| async move {
+            anyhow::Ok(time_join(round, &client, ssid, join_timeout).await)
+        })
+        .await
+        .into_iter()
+        // Failed joins are recorded in the timing, so there are no errors.
+        .filter_map(|joined| joined.result.ok())
+        .collect()
+    } else {
+        let mut joins = JoinSet::new();
+        for (i, client) in clients.iter().cloned().enumerate() {
+            let delay = stagger * i as u32;
+            let ssid = args.network.ssid.clone();
+            joins.spawn(async move {
+                sleep(delay).await;
+                time_join(round, &client, &ssid, join_timeout).await
+            });
+        }
+        joins.join_all().await
+    }
+}
+
+/// Associate a client to `ssid` and time how long it takes to associate and to get an address.
+async fn time_join(round: u32, client: &Host, ssid: &str, join_timeout: Duration) -> JoinTiming {
+    let mut timing = JoinTiming {
+        round,
+        host: client.id.clone(),
+        start: unix_time(SystemTime::now()),
+        association: None,
+        dhcp: None,
+        error: None,
+    };
+    let start = Instant::now();
+    let mut associated = None;
+
+    // nmcli only returns once the connection is fully set up, so the association and the address
+    // are watched separately while it runs.
+    let phases = async {
+        let watch = async {
+            client.wait_for_association().await?;
+            associated = Some(start.elapsed());
+            client.wait_for_link().await?;
+            anyhow::Ok(start.elapsed())
+        };
+        tokio::try_join!(client.associate(ssid, None), watch)
+    };
+    let result = timeout(join_timeout, phases).await;
+
+    timing.association = associated.map(|d| d.as_secs_f64());
+    match result {
+        Ok(Ok((_, linked))) => {
+            let associated = associated.unwrap_or(linked);
+            timing.dhcp = Some((linked - associated).as_secs_f64());
+        }
+        Ok(Err(err)) => timing.error = Some(format!("{err:#}")),
+        Err(_) => {
+            timing.error = Some(match associated {
+                Some(_) => format!("no address within {}s", join_timeout.as_secs()),
+                None => format!("not associated within {}s", join_timeout.as_secs()),
+            })
+        }
+    }
+    timing
+}
+
+/// Write the timings to `timings.ron` and `timings.csv`.
+async fn write_timings(out_path: &Path, timings: &[JoinTiming]) -> anyhow::Result<()> {
+    let dump =
+        to_string_pretty(timings, PrettyConfig::new()).context("failed to serialize timings")?;
+    tokio::fs::write(out_path.join("timings.ron"), dump)
+        .await
+        .context("failed to save timings")?;
+    tokio::fs::write(out_path.join("timings.csv"), timings_csv(timings))
+        .await
+        .context("failed to save timings")
+}
+
+/// A CSV table with a row for every client in every round.
+pub fn timings_csv(timings: &[JoinTiming]) -> String {
+    let opt = |v: Option<f64>| v.map(|v| format!("{v:.6}")).unwrap_or_default();
+    let mut csv = "round,host,start,association,dhcp,success\n".to_string();
+    for t in timings {
+        _ = writeln!(
+            csv,
+            "{},{},{:.6},{},{},{}",
+            t.round,
+            t.host,
+            t.start,
+            opt(t.association),
+            opt(t.dhcp),
+            t.success()
+        );
+    }
+    csv
+}
+
+/// A table with the number of successful joins and the mean durations of every client.
+fn timing_table(timings: &[JoinTiming]) -> String {
+    let mut hosts: Vec<&HostId> = timings.iter().map(|t| &t.host).collect();
+    hosts.sort();
+    hosts.dedup();
+
+    let mean = |values: Vec<f64>| {
+        if values.is_empty() {
+            "-".to_string()
+        } else {
+            format!("{:.3}s", values.iter().sum::<f64>() / values.len() as f64)
+        }
+    };
+    let mut table = String::new();
+    for host in hosts {
+        let own: Vec<_> = timings.iter().filter(|t| &t.host == host).collect();
+        let ok: Vec<_> = own.iter().filter(|t| t.success()).collect();
+        _ = writeln!(
+            table,
+            "{host}: {}/{} joined, association {}, dhcp {}",
+            ok.len(),
+            own.len(),
+            mean(ok.iter().filter_map(|t| t.association).collect()),
+            mean(ok.iter().filter_map(|t| t.dhcp).collect()),
+        );
+    }
+    table
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn valid_staggers() {
+        assert_eq!(parse_stagger("0"), Ok(0.0));
+        assert_eq!(parse_stagger("1.5"), Ok(1.5));
+    }
+
+    #[test]
+    fn invalid_staggers() {
+        for value in ["", "fast", "-1", "nan", "inf", "1e30"] {
+            assert!(parse_stagger(value).is_err(), "{value}");
+        }
+    }
+}
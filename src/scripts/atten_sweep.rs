@@ -0,0 +1,196 @@
+//! Sweep over attenuation values set by an external attenuator, running iperf at every step.
+
+use std::{collections::BTreeMap, fmt::Write, path::Path};
+
+use anyhow::Context;
+use clap::Parser;
+use ron::ser::{to_string_pretty, PrettyConfig};
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+
+use crate::{
+    cancel::CancellationToken,
+    driver::wifi,
+    hosts::{HostId, Hosts},
+    scripts::{
+        iperf::{self, IperfArgs, IperfResult},
+        iterations::{fingerprint, run_iterations, Iteration, IterationArgs, StopIterations},
+    },
+    utils::run_local,
+};
+
+#[derive(Parser, Debug, Clone, Serialize, Deserialize)]
+pub struct AttenSweepArgs {
+    #[command(flatten)]
+    pub iperf: IperfArgs,
+    /// The attenuation values to sweep over in dB, for example `0,5,10`.
+    #[clap(long, required = true, value_delimiter = ',', num_args = 1..)]
+    pub steps: Vec<f64>,
+    /// The command that sets the attenuation, run on the controller before every step. `{value}`
+    /// is replaced by the attenuation, for example `atten-ctl set {value}`.
+    #[clap(long)]
+    pub step_command: String,
+}
+
+/// The results of a single attenuation step.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StepOutput {
+    pub attenuation: f64,
+    /// The signal strength every client reported before the traffic started, in dBm.
+    pub rssi: BTreeMap<HostId, Option<i32>>,
+    /// The parsed client results of every run of the step.
+    pub results: Vec<BTreeMap<HostId, IperfResult>>,
+}
+
+pub async fn run(
+    args: AttenSweepArgs,
+    hosts: &Hosts,
+    out_path: &Path,
+    cancel: &CancellationToken,
+) -> anyhow::Result<()> {
+    args.iperf.validate().context("invalid arguments")?;
+    if !args.step_command.contains("{value}") {
+        warn!("The step command does not contain `{{value}}`, so every step runs the same command");
+    }
+    if !args.iperf.json {
+        warn!("Throughput in atten.csv requires --json");
+    }
+    tokio::fs::create_dir_all(out_path)
+        .await
+        .context("could not create output folder")?;
+    let args_dump =
+        to_string_pretty(&args, PrettyConfig::new()).context("failed to serialize args info")?;
+    tokio::fs::write(out_path.join("sweep-arguments.ron"), args_dump)
+        .await
+        .context("failed to save arguments")?;
+
+    let iterations = args
+        .steps
+        .iter()
+        .map(|&value| Iteration {
+            name: format!("atten-{value}"),
+            data: value,
+        })
+        .collect();
+    // Every step runs the full iperf experiment, including its own repetitions.
+    let sweep = IterationArgs {
+        repeat: 1,
+        repeat_cooldown: 0,
+        fail_fast: args.iperf.iterations.fail_fast,
+    };
+
+    // Steps can be added when resuming, as every step has its own directory.
+    let fingerprint = fingerprint(&AttenSweepArgs {
+        steps: Vec::new(),
+        ..args.clone()
+    })?;
+    let mut outputs = Vec::new();
+    let statuses = run_iterations(
+        &sweep,
+        out_path,
+        &fingerprint,
+        iterations,
+        &mut outputs,
+        |iteration, step_path| {
+            let args = args.clone();
+            async move {
+                let value = iteration.data;
+                tokio::fs::create_dir_all(&step_path)
+                    .await
+                    .context("could not create step folder")?;
+
+                let command = args.step_command.replace("{value}", &value.to_string());
+                info!("Setting attenuation to {value} dB");
+                let output = run_local(&command).await.map_err(|err| {
+                    anyhow::Error::new(StopIterations(format!(
+                        "step command for {value} dB failed: {err:#}"
+                    )))
+                })?;
+                let mut raw = output.stdout;
+                raw.extend_from_slice(&output.stderr);
+                tokio::fs::write(step_path.join("step-command.txt"), raw)
+                    .await
+                    .context("failed to save step command output")?;
+
+                let rssi = client_rssi(&args.iperf.clients, hosts).await;
+                let results =
+                    iperf::run_with_pings(args.iperf, hosts, &step_path, None, cancel).await?;
+                Ok(StepOutput {
+                    attenuation: value,
+                    rssi,
+                    results,
+                })
+            }
+        },
+    )
+    .await;
+
+    // The completed steps are written even if the sweep was stopped.
+    let csv = atten_csv(&outputs);
+    tokio::fs::write(out_path.join("atten.csv"), csv)
+        .await
+        .context("failed to write sweep results")?;
+
+    let statuses = statuses?;
+    let failed = statuses.iter().filter(|s| !s.status.is_completed()).count();
+    if failed > 0 {
+        anyhow::bail!("{failed} of {} steps failed", statuses.len());
+    }
+    Ok(())
+}
+
+/// The signal strength of every client that has an interface name configured.
+async fn client_rssi(clients: &[String], hosts: &Hosts) -> BTreeMap<HostId, Option<i32>> {
+    let mut rssi = BTreeMap::new();
+    for client in clients.iter().filter_map(|id| hosts.get(id)) {
+        let signal = match client.extra_data.interface_name() {
+            Some(ifname) => match wifi::link_info(client, ifname).await {
+                Ok(link) => link.signal,
+                Err(err) => {
+                    warn!(
+                        host = client.id,
+                        "Could not get the signal strength: {err:?}"
+                    );
+                    None
+                }
+            },
+            None => None,
+        };
+        rssi.insert(client.id.clone(), signal);
+    }
+    rssi
+}
+
+/// Combine the steps into a CSV table with one row per client per direction for every run.
+pub fn atten_csv(steps: &[StepOutput]) -> String {
+    let mut csv = "attenuation,run,client,rssi,direction,goodput\n".to_string();
+    for step in steps {
+        for (run, results) in step.results.iter().enumerate() {
+            for (client, rssi) in &step.rssi {
+                let rssi = rssi.map(|v| v.to_string()).unwrap_or_default();
+                let directions = results
+                    .get(client)
+                    .map(|r| r.directions.as_slice())
+                    .unwrap_or_default();
+                if directions.is_empty() {
+                    _ = writeln!(csv, "{},{},{client},{rssi},,", step.attenuation, run + 1);
+                }
+                for direction in directions {
+                    let goodput = direction
+                        .summary
+                        .as_ref()
+                        .map(|s| format!("{:.0}", s.bits_per_second))
+                        .unwrap_or_default();
+                    _ = writeln!(
+                        csv,
+                        "{},{},{client},{rssi},{:?},{goodput}",
+                        step.attenuation,
+                        run + 1,
+                        direction.direction
+                    );
+                }
+            }
+        }
+    }
+    csv
+}
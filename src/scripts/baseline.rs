@@ -0,0 +1,236 @@
+//! Capture a channel without generating any traffic, to characterize the interference on it.
+
+use std::{collections::BTreeMap, path::Path, time::Duration};
+
+use anyhow::Context;
+use clap::Parser;
+use ron::ser::{to_string_pretty, PrettyConfig};
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+
+use crate::{
+    cancel::CancellationToken,
+    capture::{
+        analysis::{self, AirtimeStats},
+        csv,
+    },
+    hosts::{HostId, Hosts},
+    mac::MacAddr,
+    monitor::MonitorConfig,
+    utils::{host_file, parse_bandwidth, parse_frequency},
+};
+
+#[derive(Parser, Debug, Clone, Serialize, Deserialize)]
+pub struct BaselineArgs {
+    /// The host id(s) of the hosts that will capture the wireless traffic.
+    #[clap(long, required = true, value_delimiter = ',', num_args = 1..)]
+    pub monitors: Vec<String>,
+    /// The frequency of the channel to capture in MHz.
+    #[clap(short = 'F', long, value_parser = parse_frequency)]
+    pub frequency: u32,
+    /// The bandwidth to capture with in MHz.
+    #[clap(short = 'B', long, value_parser = parse_bandwidth)]
+    pub bandwidth: u32,
+    /// How long to capture in seconds.
+    #[clap(short = 'd', long)]
+    pub duration: u64,
+    /// Also analyze only the frames of this BSS, for example the access point of a later
+    /// experiment.
+    #[clap(long)]
+    #[serde(default)]
+    pub bssid: Option<MacAddr>,
+    /// Export every capture to `<host>.frames.csv.gz` once the monitors are done, with a row per
+    /// frame. Requires tshark on the controller.
+    #[clap(long)]
+    #[serde(default)]
+    pub export_csv: bool,
+}
+
+impl BaselineArgs {
+    /// Validate combinations of arguments that can not be expressed through clap.
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if self.duration == 0 {
+            anyhow::bail!("the duration must be larger than zero");
+        }
+        Ok(())
+    }
+}
+
+/// The analysis of the capture of a single monitor, written to `baseline.ron`.
+#[derive(Debug, Clone, Serialize)]
+pub struct BaselineAnalysis {
+    /// All frames on the channel.
+    pub channel: AirtimeStats,
+    /// The fraction of the time the channel was busy.
+    pub utilization: f64,
+    /// Only the frames of `--bssid`, if it was set.
+    pub bss: Option<AirtimeStats>,
+}
+
+pub async fn run(
+    args: BaselineArgs,
+    hosts: &Hosts,
+    out_path: &Path,
+    cancel: &CancellationToken,
+) -> anyhow::Result<()> {
+    args.validate()?;
+    tokio::fs::create_dir_all(out_path)
+        .await
+        .context("could not create output folder")?;
+    let args_dump =
+        to_string_pretty(&args, PrettyConfig::new()).context("failed to serialize args info")?;
+    tokio::fs::write(out_path.join("arguments.ron"), args_dump)
+        .await
+        .context("failed to save arguments")?;
+
+    info!(
+        "Capturing {} MHz for {}s without traffic",
+        args.frequency, args.duration
+    );
+    let monitor = MonitorConfig {
+        // The network is only used to discover association IDs, which is disabled.
+        ssid: String::new(),
+        bssid: args.bssid,
+        frequency: args.frequency,
+        bandwidth: args.bandwidth,
+        monitors: args.monitors.clone(),
+        targets: Vec::new(),
+        duration: Duration::from_secs(args.duration),
+        // There is no traffic to lead into, the whole capture is measured.
+        lead_in: Duration::ZERO,
+        lead_out: Duration::ZERO,
+        output_path: Some(out_path.to_owned()),
+        set_aids: false,
+        known_aids: None,
+        filter: None,
+        merge_captures: false,
+        dedup_window: None,
+    }
+    .start(hosts)
+    .await
+    .context("failed to start capture")?;
+    let output = monitor.wait_or_stop(cancel).await?;
+    if args.export_csv {
+        let hosts: Vec<HostId> = output
+            .captures
+            .iter()
+            .map(|(host, _)| host.clone())
+            .collect();
+        csv::export_captures(out_path, &hosts).await;
+    }
+
+    let mut analyses = BTreeMap::<HostId, BaselineAnalysis>::new();
+    for (host, _) in output.captures {
+        let capture = host_file(out_path, &host, "pcapng");
+        let channel = match analysis::airtime(&capture, None).await {
+            Ok(stats) => stats,
+            Err(err) => {
+                warn!(host, "Could not analyze the capture: {err:?}");
+                continue;
+            }
+        };
+        let bss = match args.bssid {
+            Some(bssid) => Some(analysis::airtime(&capture, Some(bssid)).await?),
+            None => None,
+        };
+        let utilization = channel.utilization(args.duration as f64);
+        info!(
+            host,
+            "{} frames from {} BSSs, {:.1}% utilization, {} with a bad FCS",
+            channel.frames,
+            channel.bss_count,
+            utilization * 100.0,
+            channel.bad_fcs
+        );
+        analyses.insert(
+            host,
+            BaselineAnalysis {
+                channel,
+                utilization,
+                bss,
+            },
+        );
+    }
+
+    let dump =
+        to_string_pretty(&analyses, PrettyConfig::new()).context("failed to serialize analysis")?;
+    tokio::fs::write(out_path.join("baseline.ron"), dump)
+        .await
+        .context("failed to save analysis")
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::{CommandFactory, FromArgMatches};
+
+    use super::*;
+    use crate::scripts::Script;
+
+    fn parse(args: &str) -> Result<Script, clap::Error> {
+        let argv = ["controller", "baseline"]
+            .into_iter()
+            .chain(args.split_whitespace());
+        Script::from_arg_matches(&Script::command().try_get_matches_from(argv)?)
+    }
+
+    fn baseline(args: &str) -> BaselineArgs {
+        match parse(args).unwrap() {
+            Script::Baseline(args) => args,
+            script => panic!("parsed {script:?}"),
+        }
+    }
+
+    #[test]
+    fn only_monitors_and_the_channel_are_required() {
+        let args = baseline("--monitors m1,m2 -F 5180 -B 80 -d 600");
+        args.validate().unwrap();
+        assert_eq!(args.monitors, ["m1", "m2"]);
+        assert_eq!((args.frequency, args.bandwidth), (5180, 80));
+        assert_eq!(args.bssid, None);
+
+        let script = parse("--monitors m1,m2 -F 5180 -B 80 -d 600").unwrap();
+        assert_eq!(
+            script.hosts().unwrap().into_iter().collect::<Vec<_>>(),
+            ["m1", "m2"]
+        );
+    }
+
+    #[test]
+    fn traffic_arguments_are_rejected() {
+        for extra in ["--server srv", "--clients a,b", "--ssid net", "--ap ap"] {
+            assert!(
+                parse(&format!("--monitors m -F 5180 -B 20 -d 60 {extra}")).is_err(),
+                "{extra}"
+            );
+        }
+    }
+
+    #[test]
+    fn missing_arguments_are_rejected() {
+        for args in [
+            "-F 5180 -B 20 -d 60",
+            "--monitors m -B 20 -d 60",
+            "--monitors m -F 5180 -d 60",
+            "--monitors m -F 5180 -B 20",
+        ] {
+            assert!(parse(args).is_err(), "{args}");
+        }
+    }
+
+    #[test]
+    fn invalid_arguments_are_rejected() {
+        assert!(parse("--monitors m -F 5181 -B 20 -d 60").is_err());
+        assert!(parse("--monitors m -F 5180 -B 30 -d 60").is_err());
+        assert!(parse("--monitors m -F 5180 -B 20 -d 60 --bssid nope").is_err());
+
+        let err = baseline("--monitors m -F 5180 -B 20 -d 0")
+            .validate()
+            .unwrap_err();
+        assert_eq!(err.to_string(), "the duration must be larger than zero");
+        let script = parse("--monitors m -F 2437 -B 80 -d 60").unwrap();
+        assert_eq!(
+            script.check_channels().unwrap_err(),
+            "channel 6 (2437 MHz) is not part of a 80 MHz wide channel"
+        );
+    }
+}
@@ -0,0 +1,327 @@
+//! Generate ON/OFF UDP traffic, with bursts at a fixed rate separated by idle gaps.
+
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    path::Path,
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+use anyhow::{anyhow, Context};
+use clap::Parser;
+use ron::ser::{to_string_pretty, PrettyConfig};
+use serde::{Deserialize, Serialize};
+use tracing::{error, info, warn};
+
+use crate::{
+    cancel::{Aborted, CancellationToken, Reason},
+    daemon::stop_all,
+    hosts::{Host, HostId, Hosts},
+    scripts::{
+        iperf::{kill_stale_iperfs, server_ports, spawn_servers},
+        mark_failed,
+        monitoring::MonitorArgs,
+    },
+    utils::{barrier_start, host_file, parse_bitrate},
+};
+
+/// How long after the servers are up the clients start sending, which leaves time to measure the
+/// clocks of the clients.
+const START_LEAD: Duration = Duration::from_secs(2);
+
+#[derive(Parser, Debug, Clone, Serialize, Deserialize)]
+pub struct BurstArgs {
+    /// The host id of the access point.
+    #[clap(long)]
+    pub ap: String,
+    /// The host id of where the iperf servers are running. Defaults to the access point.
+    #[clap(long)]
+    #[serde(default)]
+    pub server: Option<String>,
+    /// The host ids that will send bursts.
+    #[clap(long, required = true, value_delimiter = ',', num_args = 1..)]
+    pub clients: Vec<String>,
+    /// How long every burst lasts in milliseconds.
+    #[clap(long, default_value = "100")]
+    #[serde(default = "default_burst")]
+    pub burst: u64,
+    /// How long to stay idle between two bursts in milliseconds.
+    #[clap(long, default_value = "400")]
+    #[serde(default = "default_gap")]
+    pub gap: u64,
+    /// The rate of every client during a burst in bits per second, for example `50M`.
+    #[clap(long, default_value = "50M", value_parser = parse_bitrate)]
+    #[serde(default = "default_rate")]
+    pub rate: u64,
+    /// How long to keep sending bursts in seconds.
+    #[clap(short = 'd', long, default_value = "10")]
+    #[serde(default = "default_duration")]
+    pub duration: u64,
+    /// Send the bursts from the server to the clients instead.
+    #[clap(long)]
+    #[serde(default)]
+    pub downlink: bool,
+    #[command(flatten)]
+    pub network: MonitorArgs,
+}
+
+// The defaults of the arguments when they are read from a config file. They match the
+// defaults of the command line.
+fn default_burst() -> u64 {
+    100
+}
+
+fn default_gap() -> u64 {
+    400
+}
+
+fn default_rate() -> u64 {
+    50_000_000
+}
+
+fn default_duration() -> u64 {
+    10
+}
+
+impl BurstArgs {
+    /// The number of bytes sent in a single burst.
+    fn burst_bytes(&self) -> u64 {
+        (self.rate * self.burst / 8_000).max(1)
+    }
+}
+
+/// A single burst of a client, written to `bursts.csv`.
+///
+/// All times are in seconds since the unix epoch, on the clock of the client.
+#[derive(Debug, Clone, Serialize)]
+pub struct Burst {
+    pub index: u32,
+    /// When the burst should have started according to the schedule.
+    pub intended_start: f64,
+    /// When the burst was started.
+    pub start: f64,
+    /// When the burst completed.
+    pub end: f64,
+    /// The exit code of iperf for this burst.
+    pub exit_code: i32,
+}
+
+pub async fn run(
+    args: BurstArgs,
+    hosts: &Hosts,
+    out_path: &Path,
+    cancel: &CancellationToken,
+) -> anyhow::Result<()> {
+    tokio::fs::create_dir_all(out_path)
+        .await
+        .context("could not create output folder")?;
+    let args_dump =
+        to_string_pretty(&args, PrettyConfig::new()).context("failed to serialize args info")?;
+    tokio::fs::write(out_path.join("arguments.ron"), args_dump)
+        .await
+        .context("failed to save arguments")?;
+    if args.burst == 0 {
+        anyhow::bail!("the burst duration must be larger than zero");
+    }
+
+    let clients: Vec<_> = hosts
+        .get_many(&args.clients)
+        .map_err(|missing| anyhow!("no host with id {missing}"))?
+        .collect();
+    let access_point = hosts.get(&args.ap).context("access point id not found")?;
+    let server = hosts
+        .get(args.server.as_deref().unwrap_or(&args.ap))
+        .context("server id not found")?;
+    let server_ifname = server.extra_data.interface_name();
+    let server_ip = server
+        .ip_address()
+        .await
+        .context("failed to get IP address of server")?;
+    let ports = server_ports(clients.len());
+
+    let bssid = args.network.check_access_point(access_point).await?;
+    let mut cleanup_hosts: Vec<Arc<Host>> = clients.iter().map(|&c| c.clone()).collect();
+    cleanup_hosts.push(server.clone());
+    kill_stale_iperfs(&cleanup_hosts, ports.clone()).await?;
+
+    cancel.check()?;
+    let monitor = args
+        .network
+        .start(
+            hosts,
+            &clients,
+            bssid,
+            Duration::from_secs(args.duration) + START_LEAD,
+            out_path,
+            None,
+        )
+        .await?;
+
+    // The servers handle all bursts of a client, so they are stopped explicitly afterwards.
+    let outputs = match spawn_servers(server, server_ifname, &server_ip, ports.clone(), false).await
+    {
+        Ok(servers) => {
+            info!(
+                "Sending {} ms bursts every {} ms from {} clients",
+                args.burst,
+                args.burst + args.gap,
+                clients.len()
+            );
+            // The clients start at the same instant, so their bursts line up.
+            let mut client_ports = ports.clone();
+            let outputs = cancel
+                .run(barrier_start(
+                    clients.iter().copied(),
+                    SystemTime::now() + START_LEAD,
+                    |h| {
+                        let port = client_ports
+                            .next()
+                            .expect("there is a port for every client");
+                        burst_command(&args, &server_ip, port, h.extra_data.interface_name())
+                    },
+                ))
+                .await;
+            stop_all(servers).await;
+            outputs
+        }
+        Err(err) => Err(err),
+    };
+
+    let outputs = match outputs {
+        Ok(outputs) => outputs,
+        Err(err) => {
+            let reason = if err.is::<Aborted>() {
+                cancel.reason().unwrap_or(Reason::User).to_string()
+            } else {
+                "bursts failed".to_string()
+            };
+            if let Some(monitor) = monitor {
+                if let Err(err) = monitor.stop_and_collect(reason).await {
+                    warn!("Could not collect the captures: {err:?}");
+                }
+            }
+            return Err(err);
+        }
+    };
+
+    let mut schedules = BTreeMap::new();
+    let mut failures = Vec::new();
+    for (host, output) in outputs {
+        tokio::fs::write(host_file(out_path, &host.id, "bursts.txt"), &output.stdout)
+            .await
+            .context("failed to save burst log")?;
+        let bursts = parse_burst_log(&String::from_utf8_lossy(&output.stdout));
+        let failed = bursts.iter().filter(|b| b.exit_code != 0).count();
+        if failed > 0 {
+            error!(host = host.id, "{failed} of {} bursts failed", bursts.len());
+            failures.push(format!("{failed} bursts of `{}` failed", host.id));
+        } else if bursts.is_empty() {
+            failures.push(format!("`{}` did not send any bursts", host.id));
+        } else {
+            info!(
+                host = host.id,
+                "Sent {} bursts, started {:.1} ms late on average",
+                bursts.len(),
+                mean_lateness(&bursts) * 1000.0
+            );
+        }
+        schedules.insert(host.id.clone(), bursts);
+    }
+    tokio::fs::write(out_path.join("bursts.csv"), bursts_csv(&schedules))
+        .await
+        .context("failed to save bursts")?;
+
+    if let Some(monitor) = monitor {
+        info!("Waiting for capture to finish");
+        if let Err(err) = monitor.wait_or_stop(cancel).await {
+            if err.is::<Aborted>() {
+                return Err(err);
+            }
+            error!("Monitor failed: {err:?}");
+            failures.push(format!("monitor failed: {err:#}"));
+        }
+    }
+
+    if !failures.is_empty() {
+        let reason = failures.join("; ");
+        mark_failed(out_path, &reason).await?;
+        anyhow::bail!("{reason}");
+    }
+    Ok(())
+}
+
+/// Build the shell loop that runs a short iperf test for every burst.
+///
+/// Every burst sends a fixed number of bytes at the burst rate. The loop waits for the intended
+/// start of the next burst rather than for the gap, so a late burst does not shift the rest of
+/// the schedule. Every burst is logged as `burst <index> <intended> <start> <end> <exit code>`.
+fn burst_command(args: &BurstArgs, server_ip: &str, port: u16, bind_dev: Option<&str>) -> String {
+    let mut iperf = format!(
+        "iperf3 -c {server_ip} -p {port} -u -b {} -n {}",
+        args.rate,
+        args.burst_bytes()
+    );
+    if let Some(ifname) = bind_dev {
+        _ = write!(iperf, " --bind-dev {ifname}");
+    }
+    if args.downlink {
+        iperf.push_str(" -R");
+    }
+    let period = (args.burst + args.gap) as f64 / 1000.0;
+    format!(
+        "now() {{ date +%s.%N; }}; \
+         first=$(now); i=0; \
+         while awk -v n=$(now) -v f=$first 'BEGIN {{ exit !(n - f < {duration}) }}'; do \
+         due=$(awk -v f=$first -v i=$i 'BEGIN {{ printf \"%.6f\", f + i * {period} }}'); \
+         sleep $(awk -v n=$due -v t=$(now) 'BEGIN {{ d = n - t; printf \"%.6f\", (d > 0 ? d : 0) }}'); \
+         start=$(now); {iperf} >/dev/null 2>&1; code=$?; \
+         echo \"burst $i $due $start $(now) $code\"; \
+         i=$((i + 1)); \
+         done",
+        duration = args.duration,
+    )
+}
+
+/// Parse the lines logged by the burst loop, see [burst_command].
+pub fn parse_burst_log(log: &str) -> Vec<Burst> {
+    log.lines()
+        .filter_map(|line| {
+            let mut fields = line.strip_prefix("burst ")?.split_whitespace();
+            Some(Burst {
+                index: fields.next()?.parse().ok()?,
+                intended_start: fields.next()?.parse().ok()?,
+                start: fields.next()?.parse().ok()?,
+                end: fields.next()?.parse().ok()?,
+                exit_code: fields.next()?.parse().ok()?,
+            })
+        })
+        .collect()
+}
+
+/// How late the bursts started on average compared to the schedule, in seconds.
+fn mean_lateness(bursts: &[Burst]) -> f64 {
+    if bursts.is_empty() {
+        return 0.0;
+    }
+    bursts
+        .iter()
+        .map(|b| b.start - b.intended_start)
+        .sum::<f64>()
+        / bursts.len() as f64
+}
+
+/// A CSV table with the intended and actual schedule of every burst.
+pub fn bursts_csv(schedules: &BTreeMap<HostId, Vec<Burst>>) -> String {
+    let mut csv = "host,burst,intended_start,start,end,exit_code\n".to_string();
+    for (host, bursts) in schedules {
+        for b in bursts {
+            _ = writeln!(
+                csv,
+                "{host},{},{:.6},{:.6},{:.6},{}",
+                b.index, b.intended_start, b.start, b.end, b.exit_code
+            );
+        }
+    }
+    csv
+}
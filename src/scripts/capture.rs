@@ -0,0 +1,118 @@
+//! Capture a channel on one or more monitors and collect the captures, without running an
+//! experiment.
+
+use std::{path::Path, time::Duration};
+
+use anyhow::Context;
+use clap::Parser;
+use ron::ser::{to_string_pretty, PrettyConfig};
+use serde::{Deserialize, Serialize};
+use tracing::{error, info};
+
+use crate::{
+    cancel::{Aborted, CancellationToken},
+    hosts::Hosts,
+    monitor::MonitorConfig,
+    scripts::mark_failed,
+    utils::{parse_bandwidth, parse_frequency},
+};
+
+#[derive(Parser, Debug, Clone, Serialize, Deserialize)]
+pub struct CaptureArgs {
+    /// The host id(s) of the hosts that will capture the wireless traffic.
+    #[clap(long, required = true, value_delimiter = ',', num_args = 1..)]
+    pub monitors: Vec<String>,
+    /// The frequency of the channel to capture in MHz.
+    #[clap(short = 'F', long, value_parser = parse_frequency)]
+    pub frequency: u32,
+    /// The bandwidth to capture with in MHz.
+    #[clap(short = 'B', long, value_parser = parse_bandwidth)]
+    pub bandwidth: u32,
+    /// How long to capture in seconds.
+    #[clap(short = 'd', long)]
+    pub duration: u64,
+    /// Only capture frames matching this capture filter, for example `wlan host <mac>`.
+    #[clap(long)]
+    #[serde(default)]
+    pub filter: Option<String>,
+    /// Merge the captures of the monitors into `merged.pcapng`, ordered by time and corrected for
+    /// the clock offsets of the monitor hosts.
+    #[clap(long)]
+    #[serde(default)]
+    pub merge_captures: bool,
+    /// When merging, drop frames another monitor captured at most this many milliseconds before.
+    #[clap(long, requires = "merge_captures")]
+    #[serde(default)]
+    pub dedup_window: Option<u64>,
+}
+
+pub async fn run(
+    args: CaptureArgs,
+    hosts: &Hosts,
+    out_path: &Path,
+    cancel: &CancellationToken,
+) -> anyhow::Result<()> {
+    if args.duration == 0 {
+        anyhow::bail!("the duration must be larger than zero");
+    }
+    tokio::fs::create_dir_all(out_path)
+        .await
+        .context("could not create output folder")?;
+    let args_dump =
+        to_string_pretty(&args, PrettyConfig::new()).context("failed to serialize args info")?;
+    tokio::fs::write(out_path.join("arguments.ron"), args_dump)
+        .await
+        .context("failed to save arguments")?;
+
+    info!(
+        "Capturing {} MHz on {} monitors for {}s",
+        args.frequency,
+        args.monitors.len(),
+        args.duration
+    );
+    let monitor = MonitorConfig {
+        // The network is only used to discover association IDs, which is disabled.
+        ssid: String::new(),
+        bssid: None,
+        frequency: args.frequency,
+        bandwidth: args.bandwidth,
+        monitors: args.monitors.clone(),
+        targets: Vec::new(),
+        duration: Duration::from_secs(args.duration),
+        // There is no traffic to lead into, the whole capture is measured.
+        lead_in: Duration::ZERO,
+        lead_out: Duration::ZERO,
+        output_path: Some(out_path.to_owned()),
+        set_aids: false,
+        known_aids: None,
+        filter: args.filter.clone(),
+        merge_captures: args.merge_captures,
+        dedup_window: args.dedup_window.map(Duration::from_millis),
+    }
+    .start(hosts)
+    .await
+    .context("failed to start capture")?;
+
+    let output = match monitor.wait_or_stop(cancel).await {
+        Ok(output) => output,
+        Err(err) if err.is::<Aborted>() => return Err(err),
+        Err(err) => {
+            error!("Capture failed: {err:?}");
+            mark_failed(out_path, &format!("capture failed: {err:#}")).await?;
+            return Err(err);
+        }
+    };
+    for capture in &output.metadata.captures {
+        if let Some(stats) = &capture.stats {
+            info!(
+                host = capture.host,
+                "Captured {} bytes, {} packets",
+                stats.bytes,
+                stats
+                    .packets
+                    .map_or("unknown".to_string(), |p| p.to_string())
+            );
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,289 @@
+//! Restore the hosts of the testbed to a known state, for example after a crashed run.
+
+use std::{collections::BTreeMap, path::Path, sync::Arc};
+
+use anyhow::{anyhow, Context};
+use clap::Parser;
+use openssh::Stdio;
+use ron::ser::{to_string_pretty, PrettyConfig};
+use serde::{Deserialize, Serialize};
+use tokio::task::JoinSet;
+use tracing::{info, warn};
+
+use crate::{
+    daemon,
+    driver::wifi::iwlwifi,
+    hosts::{Host, HostId, Hosts},
+    lock,
+};
+
+/// The interface the monitors capture on.
+const MONITOR_INTERFACE: &str = "mon0";
+
+#[derive(Parser, Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct CleanupArgs {
+    /// The host ids of the hosts to clean up. Defaults to all hosts.
+    #[clap(long, value_delimiter = ',', num_args = 1..)]
+    pub hosts: Vec<String>,
+    /// Also delete the monitor interface on hosts that have one.
+    #[clap(long)]
+    pub delete_monitors: bool,
+    /// Forget the NetworkManager profiles of these SSIDs.
+    #[clap(long, value_delimiter = ',', num_args = 1..)]
+    pub forget_ssids: Vec<String>,
+    /// Only list what would be cleaned up, without changing anything.
+    #[clap(long)]
+    pub dry_run: bool,
+}
+
+/// A single cleanup step on a host.
+#[derive(Debug, Clone, Serialize)]
+pub enum Action {
+    /// Kill the processes whose command line matches the `pgrep` pattern.
+    Kill { name: String, pattern: String },
+    /// Reset the association ID the monitor interface follows.
+    ClearAid,
+    /// Delete a wireless interface.
+    DeleteInterface(String),
+    /// Delete the NetworkManager profile of a network.
+    ForgetProfile(String),
+    /// Remove the lock of the controller that last used the host, see [lock].
+    RemoveLock,
+    /// Stop a daemon the controller started, see [daemon::terminate].
+    StopDaemon { pid: u32, command: String },
+}
+
+/// What a cleanup step did, written to `cleanup.ron`.
+#[derive(Debug, Clone, Serialize)]
+pub enum StepOutcome {
+    /// Something was cleaned up.
+    Cleaned(String),
+    /// There was nothing to clean up.
+    Nothing,
+    /// The step was only listed because of `--dry-run`.
+    Planned,
+    Failed(String),
+}
+
+impl Action {
+    fn describe(&self) -> String {
+        match self {
+            Action::Kill { name, .. } => format!("kill {name} processes"),
+            Action::ClearAid => "clear the AID filter".to_string(),
+            Action::DeleteInterface(ifname) => format!("delete interface {ifname}"),
+            Action::ForgetProfile(ssid) => format!("forget the profile of `{ssid}`"),
+            Action::RemoveLock => "remove the controller lock".to_string(),
+            Action::StopDaemon { pid, command } => format!("stop `{command}` ({pid})"),
+        }
+    }
+
+    /// Perform the step, returning what was cleaned up if anything.
+    async fn apply(&self, host: &Host) -> anyhow::Result<Option<String>> {
+        match self {
+            Action::Kill { pattern, .. } => {
+                let output = shell(
+                    host,
+                    &format!("pgrep -af '{pattern}' && sudo pkill -f '{pattern}' || true"),
+                )
+                .await?;
+                let killed: Vec<_> = output.lines().filter(|l| !l.trim().is_empty()).collect();
+                Ok((!killed.is_empty()).then(|| format!("killed {}", killed.join("; "))))
+            }
+            Action::ClearAid => {
+                iwlwifi::clear_association_id(host).await?;
+                Ok(Some("reset the AID filter".to_string()))
+            }
+            Action::DeleteInterface(ifname) => {
+                let output = shell(
+                    host,
+                    &format!(
+                        "if [ -e /sys/class/net/{ifname} ]; then sudo iw dev {ifname} del && echo deleted; fi"
+                    ),
+                )
+                .await?;
+                Ok((!output.trim().is_empty()).then(|| format!("deleted {ifname}")))
+            }
+            Action::ForgetProfile(ssid) => {
+                let status = host
+                    .session
+                    .command("sudo")
+                    .args(["nmcli", "connection", "delete", "id", ssid])
+                    .stdin(Stdio::null())
+                    .stdout(Stdio::null())
+                    .stderr(Stdio::null())
+                    .status()
+                    .await
+                    .context("failed to delete profile")?;
+                // nmcli exits with 10 if the profile does not exist.
+                match status.code() {
+                    Some(0) => Ok(Some(format!("forgot `{ssid}`"))),
+                    Some(10) => Ok(None),
+                    _ => anyhow::bail!("deleting the profile exited with status code {status}"),
+                }
+            }
+            Action::RemoveLock => {
+                let removed = lock::remove(host).await?;
+                Ok(removed.then(|| "removed the controller lock".to_string()))
+            }
+            Action::StopDaemon { pid, command } => {
+                let stopped = daemon::terminate(host, *pid).await?;
+                Ok(stopped.then(|| format!("stopped `{command}` ({pid})")))
+            }
+        }
+    }
+}
+
+/// The cleanup steps for `host`.
+pub fn actions(host: &Host, args: &CleanupArgs) -> Vec<Action> {
+    // The bracket expressions prevent the patterns from matching the shell running `pgrep`.
+    let mut actions = vec![
+        Action::Kill {
+            name: "iperf3".to_string(),
+            pattern: "[i]perf3 -[sc] ".to_string(),
+        },
+        Action::Kill {
+            name: "tshark".to_string(),
+            pattern: format!("[t]shark .*--interface {MONITOR_INTERFACE}"),
+        },
+        Action::Kill {
+            name: "dumpcap".to_string(),
+            pattern: format!("[d]umpcap .*{MONITOR_INTERFACE}"),
+        },
+    ];
+    if host.extra_data.wifi_driver.as_deref() == Some("iwlwifi") {
+        actions.push(Action::ClearAid);
+    }
+    if args.delete_monitors {
+        actions.push(Action::DeleteInterface(MONITOR_INTERFACE.to_string()));
+    }
+    actions.extend(args.forget_ssids.iter().cloned().map(Action::ForgetProfile));
+    actions.push(Action::RemoveLock);
+    actions
+}
+
+pub async fn run(args: CleanupArgs, hosts: &Hosts, out_path: &Path) -> anyhow::Result<()> {
+    tokio::fs::create_dir_all(out_path)
+        .await
+        .context("could not create output folder")?;
+    let args_dump =
+        to_string_pretty(&args, PrettyConfig::new()).context("failed to serialize args info")?;
+    tokio::fs::write(out_path.join("arguments.ron"), args_dump)
+        .await
+        .context("failed to save arguments")?;
+
+    let targets: Vec<Arc<Host>> = if args.hosts.is_empty() {
+        hosts.iter().cloned().collect()
+    } else {
+        hosts
+            .get_many(&args.hosts)
+            .map_err(|missing| anyhow!("no host with id {missing}"))?
+            .cloned()
+            .collect()
+    };
+
+    let report = clean_up(targets, &args).await;
+    write_report(out_path, &report).await?;
+
+    let failed = report
+        .values()
+        .flatten()
+        .filter(|(_, outcome)| matches!(outcome, StepOutcome::Failed(_)))
+        .count();
+    if failed > 0 {
+        anyhow::bail!("{failed} cleanup steps failed");
+    }
+    Ok(())
+}
+
+/// What was done on every host, written to `cleanup.ron`.
+pub type Report = BTreeMap<HostId, Vec<(Action, StepOutcome)>>;
+
+/// The cleanup steps for `host` after a run using it was aborted. Only the daemons the
+/// controller started are stopped, as the other processes may belong to someone else.
+pub fn abort_actions(host: &Host) -> Vec<Action> {
+    let mut actions: Vec<_> = daemon::started_on(&host.id)
+        .into_iter()
+        .map(|(pid, command)| Action::StopDaemon { pid, command })
+        .collect();
+    if host.extra_data.wifi_driver.as_deref() == Some("iwlwifi") {
+        actions.push(Action::ClearAid);
+    }
+    actions
+}
+
+/// Perform the cleanup steps on all `targets` at the same time.
+pub async fn clean_up(targets: impl IntoIterator<Item = Arc<Host>>, args: &CleanupArgs) -> Report {
+    let dry_run = args.dry_run;
+    perform(targets, dry_run, |host| actions(host, args)).await
+}
+
+/// Clean up the `targets` of a run that was aborted, see [abort_actions].
+pub async fn clean_up_after_abort(targets: impl IntoIterator<Item = Arc<Host>>) -> Report {
+    perform(targets, false, abort_actions).await
+}
+
+async fn perform(
+    targets: impl IntoIterator<Item = Arc<Host>>,
+    dry_run: bool,
+    actions: impl Fn(&Host) -> Vec<Action>,
+) -> Report {
+    let mut tasks = JoinSet::new();
+    for host in targets {
+        let actions = actions(&host);
+        tasks.spawn(async move {
+            let mut steps = Vec::new();
+            for action in actions {
+                let outcome = if dry_run {
+                    info!(host = host.id, "Would {}", action.describe());
+                    StepOutcome::Planned
+                } else {
+                    match action.apply(&host).await {
+                        Ok(Some(cleaned)) => {
+                            info!(host = host.id, "{cleaned}");
+                            StepOutcome::Cleaned(cleaned)
+                        }
+                        Ok(None) => {
+                            info!(host = host.id, "Nothing to {}", action.describe());
+                            StepOutcome::Nothing
+                        }
+                        Err(err) => {
+                            warn!(host = host.id, "Could not {}: {err:?}", action.describe());
+                            StepOutcome::Failed(format!("{err:#}"))
+                        }
+                    }
+                };
+                steps.push((action, outcome));
+            }
+            (host.id.clone(), steps)
+        });
+    }
+    tasks.join_all().await.into_iter().collect()
+}
+
+/// Write the report to `cleanup.ron` in `out_path`.
+pub async fn write_report(out_path: &Path, report: &Report) -> anyhow::Result<()> {
+    let dump =
+        to_string_pretty(report, PrettyConfig::new()).context("failed to serialize report")?;
+    tokio::fs::write(out_path.join("cleanup.ron"), dump)
+        .await
+        .context("failed to save report")
+}
+
+/// Run `command` on `host` and return its output.
+async fn shell(host: &Host, command: &str) -> anyhow::Result<String> {
+    let output = host
+        .session
+        .shell(command)
+        .output()
+        .await
+        .context("failed to run cleanup command")?;
+    if !output.status.success() {
+        anyhow::bail!(
+            "cleanup command exited with error code {}: {}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
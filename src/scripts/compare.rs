@@ -0,0 +1,584 @@
+//! Compare the results of two iperf runs that differ in a few arguments, printing the changes in
+//! throughput, retransmissions, loss and capture statistics and writing them to
+//! `comparison.ron`.
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt::Write,
+    io::ErrorKind,
+    path::{Path, PathBuf},
+};
+
+use anyhow::Context;
+use clap::Parser;
+use ron::ser::{to_string_pretty, PrettyConfig};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use tracing::{info, warn};
+
+use crate::{
+    capture::CaptureStats,
+    hosts::HostId,
+    results::{load_run, Artifact, IperfResults},
+    scripts::iperf::{DirectionSummary, IperfResult, RunSummary, TrafficDirection},
+};
+
+#[derive(Parser, Debug, Clone, Serialize, Deserialize)]
+pub struct CompareArgs {
+    /// The results directory of the first run, the baseline of the comparison.
+    #[clap(long)]
+    pub a: PathBuf,
+    /// The results directory of the second run.
+    #[clap(long)]
+    pub b: PathBuf,
+    /// The arguments the runs are expected to differ in, like `mcs` or `network.bandwidth`. The
+    /// comparison fails if the runs differ in any other argument.
+    #[clap(long, value_delimiter = ',', num_args = 1..)]
+    #[serde(default)]
+    pub expect_diff: Vec<String>,
+    /// Highlight changes of at least this many percent.
+    #[clap(long, default_value = "5")]
+    #[serde(default = "default_threshold")]
+    pub threshold: f64,
+    /// Where to write the comparison. Defaults to `comparison.ron` in the directory of `--b`.
+    #[clap(long)]
+    #[serde(default)]
+    pub output: Option<PathBuf>,
+}
+
+fn default_threshold() -> f64 {
+    5.0
+}
+
+/// Arguments that follow from other arguments, like the load of every client from the total
+/// throughput. Their changes are shown, but do not need to be passed to `--expect-diff`.
+const DERIVED_ARGUMENTS: &[&str] = &["client_loads"];
+
+/// The arguments that differ by their path, with their value in both runs. Missing arguments are
+/// `None`.
+pub type ArgumentDifferences = BTreeMap<String, (Option<String>, Option<String>)>;
+
+/// The comparison of two runs, written to `comparison.ron`.
+#[derive(Debug, Clone, Serialize)]
+pub struct Comparison {
+    pub a: PathBuf,
+    pub b: PathBuf,
+    /// The threshold in percent above which a change is significant.
+    pub threshold: f64,
+    pub arguments: ArgumentDifferences,
+    pub clients: BTreeMap<HostId, Vec<DirectionComparison>>,
+    /// The totals over all clients.
+    pub totals: Vec<DirectionComparison>,
+    pub monitors: BTreeMap<HostId, CaptureComparison>,
+}
+
+/// The change of the traffic in one direction, for a single client or all of them.
+#[derive(Debug, Clone, Serialize)]
+pub struct DirectionComparison {
+    pub direction: TrafficDirection,
+    /// The throughput in bits per second.
+    pub goodput: Change,
+    /// TCP retransmissions per second, from the `results.ron` of the runs.
+    pub retransmit_rate: Change,
+    /// The percentage of UDP packets that was lost.
+    pub loss_percent: Change,
+}
+
+/// The change of the capture of a single monitor.
+#[derive(Debug, Clone, Serialize)]
+pub struct CaptureComparison {
+    pub bytes: Change,
+    pub packets: Change,
+    pub dropped: Change,
+}
+
+/// The value of a number in both runs, `None` in a run that does not have it.
+#[derive(Debug, Clone, Serialize)]
+pub struct Change {
+    pub a: Option<f64>,
+    pub b: Option<f64>,
+    /// The change relative to `a` in percent, if both have a value and `a` is not zero.
+    pub percent: Option<f64>,
+    /// Whether the change is at least the threshold.
+    pub significant: bool,
+}
+
+impl Change {
+    fn new(a: Option<f64>, b: Option<f64>, threshold: f64) -> Self {
+        let percent = match (a, b) {
+            (Some(a), Some(b)) if a != 0.0 => Some((b - a) / a.abs() * 100.0),
+            _ => None,
+        };
+        Change {
+            a,
+            b,
+            percent,
+            significant: percent.is_some_and(|p| p.abs() >= threshold),
+        }
+    }
+}
+
+/// The results of one of the runs.
+struct Run {
+    summary: RunSummary,
+    /// The parsed iperf output per client, empty if the run has no `results.ron`.
+    results: BTreeMap<HostId, IperfResult>,
+}
+
+/// Compare the runs and write the comparison. Returns the comparison that was written.
+pub fn run(args: &CompareArgs) -> anyhow::Result<Comparison> {
+    let arguments = argument_differences(&args.a, &args.b)?;
+    let expected: Vec<String> = args
+        .expect_diff
+        .iter()
+        .map(|f| f.replace('-', "_"))
+        .collect();
+    let unexpected = unexpected_differences(&arguments, &expected);
+    if !unexpected.is_empty() {
+        anyhow::bail!(
+            "the runs also differ in {}, pass them to --expect-diff if that is intended",
+            unexpected
+                .iter()
+                .map(|f| format!("`{f}`"))
+                .collect::<Vec<_>>()
+                .join(", ")
+        );
+    }
+    for field in &expected {
+        if !arguments.keys().any(|f| is_within(f, field)) {
+            warn!("The runs do not differ in `{field}`");
+        }
+    }
+
+    let a = read_run(&args.a)?;
+    let b = read_run(&args.b)?;
+    let comparison = compare(args, arguments, &a, &b);
+    info!("Comparison:\n{}", comparison.table());
+
+    let output = args
+        .output
+        .clone()
+        .unwrap_or_else(|| args.b.join("comparison.ron"));
+    let dump = to_string_pretty(&comparison, PrettyConfig::new())
+        .context("failed to serialize comparison")?;
+    std::fs::write(&output, dump)
+        .with_context(|| format!("could not write {}", output.display()))?;
+    info!("Wrote comparison to {}", output.display());
+    Ok(comparison)
+}
+
+/// The arguments that differ but are not `expected` to, nor derived from other arguments.
+fn unexpected_differences<'a>(
+    arguments: &'a ArgumentDifferences,
+    expected: &[String],
+) -> Vec<&'a String> {
+    arguments
+        .keys()
+        .filter(|field| {
+            !expected
+                .iter()
+                .map(String::as_str)
+                .chain(DERIVED_ARGUMENTS.iter().copied())
+                .any(|e| is_within(field, e))
+        })
+        .collect()
+}
+
+/// Whether the argument at path `field` is `parent` or one of its fields.
+fn is_within(field: &str, parent: &str) -> bool {
+    field == parent
+        || field
+            .strip_prefix(parent)
+            .is_some_and(|rest| rest.starts_with('.'))
+}
+
+/// Read and parse a results file, naming the file in the errors.
+fn read_ron<T: DeserializeOwned>(path: &Path) -> anyhow::Result<Option<T>> {
+    let raw = match std::fs::read_to_string(path) {
+        Ok(raw) => raw,
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(err).with_context(|| format!("could not read {}", path.display())),
+    };
+    ron::from_str(&raw).map(Some).with_context(|| {
+        format!(
+            "{} does not match the format of this version of the controller",
+            path.display()
+        )
+    })
+}
+
+fn read_run(dir: &Path) -> anyhow::Result<Run> {
+    let artifacts = load_run(dir)?;
+    let Some(summary) = artifacts.summary else {
+        anyhow::bail!(
+            "{} does not exist, is {} the results directory of an iperf run?",
+            dir.join(RunSummary::FILE).display(),
+            dir.display()
+        );
+    };
+    let results = match artifacts.results {
+        Some(results) => results.clients,
+        None => {
+            warn!(
+                "{} does not exist, retransmit rates are not compared",
+                dir.join(IperfResults::FILE).display()
+            );
+            BTreeMap::new()
+        }
+    };
+    Ok(Run { summary, results })
+}
+
+/// The arguments that differ between the runs in directories `a` and `b`, by their path.
+fn argument_differences(a: &Path, b: &Path) -> anyhow::Result<ArgumentDifferences> {
+    let load = |dir: &Path| -> anyhow::Result<BTreeMap<String, String>> {
+        let path = dir.join("arguments.ron");
+        let Some(value) = read_ron::<ron::Value>(&path)? else {
+            anyhow::bail!("{} does not exist", path.display());
+        };
+        let mut fields = BTreeMap::new();
+        flatten(&value, "", &mut fields);
+        Ok(fields)
+    };
+    let (a, b) = (load(a)?, load(b)?);
+    let keys: BTreeSet<&String> = a.keys().chain(b.keys()).collect();
+    Ok(keys
+        .into_iter()
+        .filter(|key| a.get(*key) != b.get(*key))
+        .map(|key| (key.clone(), (a.get(key).cloned(), b.get(key).cloned())))
+        .collect())
+}
+
+/// Collect the values of all fields of the arguments by their path, like `network.ssid`.
+fn flatten(value: &ron::Value, prefix: &str, fields: &mut BTreeMap<String, String>) {
+    match value {
+        ron::Value::Map(map) => {
+            for (key, value) in map.iter() {
+                let key = match key {
+                    ron::Value::String(key) => key.clone(),
+                    other => ron::to_string(other).unwrap_or_default(),
+                };
+                flatten(value, &format!("{prefix}{key}."), fields);
+            }
+        }
+        // A field without a value is the same as a field that is not there.
+        ron::Value::Option(None) => {}
+        ron::Value::Option(Some(value)) => flatten(value, prefix, fields),
+        other => {
+            let path = prefix.strip_suffix('.').unwrap_or(prefix).to_string();
+            fields.insert(path, ron::to_string(other).unwrap_or_default());
+        }
+    }
+}
+
+fn compare(args: &CompareArgs, arguments: ArgumentDifferences, a: &Run, b: &Run) -> Comparison {
+    let threshold = args.threshold;
+    let hosts: BTreeSet<&HostId> = a
+        .summary
+        .clients
+        .keys()
+        .chain(b.summary.clients.keys())
+        .collect();
+    let clients = hosts
+        .into_iter()
+        .map(|host| {
+            let directions = |run: &Run| {
+                run.summary
+                    .clients
+                    .get(host)
+                    .map(|c| c.directions.clone())
+                    .unwrap_or_default()
+            };
+            let rate = |run: &Run, direction| retransmit_rate(run, Some(host), direction);
+            let compared = compare_directions(
+                &directions(a),
+                &directions(b),
+                |d| (rate(a, d), rate(b, d)),
+                threshold,
+            );
+            (host.clone(), compared)
+        })
+        .collect();
+    let totals = compare_directions(
+        &a.summary.totals,
+        &b.summary.totals,
+        |d| (retransmit_rate(a, None, d), retransmit_rate(b, None, d)),
+        threshold,
+    );
+
+    let hosts: BTreeSet<&HostId> = a
+        .summary
+        .monitors
+        .keys()
+        .chain(b.summary.monitors.keys())
+        .collect();
+    let monitors = hosts
+        .into_iter()
+        .map(|host| {
+            let stats = |run: &Run| run.summary.monitors.get(host).cloned().flatten();
+            let (sa, sb) = (stats(a), stats(b));
+            let change = |f: fn(&CaptureStats) -> Option<u64>| {
+                Change::new(
+                    sa.as_ref().and_then(f).map(|v| v as f64),
+                    sb.as_ref().and_then(f).map(|v| v as f64),
+                    threshold,
+                )
+            };
+            let compared = CaptureComparison {
+                bytes: change(|s| Some(s.bytes)),
+                packets: change(|s| s.packets),
+                dropped: change(|s| s.dropped),
+            };
+            (host.clone(), compared)
+        })
+        .collect();
+
+    Comparison {
+        a: args.a.clone(),
+        b: args.b.clone(),
+        threshold,
+        arguments,
+        clients,
+        totals,
+        monitors,
+    }
+}
+
+/// Compare the traffic in every direction either run has.
+fn compare_directions(
+    a: &[DirectionSummary],
+    b: &[DirectionSummary],
+    retransmit_rates: impl Fn(TrafficDirection) -> (Option<f64>, Option<f64>),
+    threshold: f64,
+) -> Vec<DirectionComparison> {
+    [TrafficDirection::Uplink, TrafficDirection::Downlink]
+        .into_iter()
+        .filter_map(|direction| {
+            let find =
+                |s: &[DirectionSummary]| s.iter().find(|d| d.direction == direction).cloned();
+            let (da, db) = (find(a), find(b));
+            if da.is_none() && db.is_none() {
+                return None;
+            }
+            let (ra, rb) = retransmit_rates(direction);
+            Some(DirectionComparison {
+                direction,
+                goodput: Change::new(
+                    da.as_ref().map(|d| d.goodput),
+                    db.as_ref().map(|d| d.goodput),
+                    threshold,
+                ),
+                retransmit_rate: Change::new(ra, rb, threshold),
+                loss_percent: Change::new(
+                    da.and_then(|d| d.lost_percent),
+                    db.and_then(|d| d.lost_percent),
+                    threshold,
+                ),
+            })
+        })
+        .collect()
+}
+
+/// The TCP retransmissions per second of `host`, or summed over all clients if `None`.
+fn retransmit_rate(run: &Run, host: Option<&HostId>, direction: TrafficDirection) -> Option<f64> {
+    run.results
+        .iter()
+        .filter(|(h, _)| host.is_none_or(|host| *h == host))
+        .filter_map(|(_, result)| {
+            let summary = result.direction(direction)?.summary.as_ref()?;
+            let retransmits = summary.retransmits?;
+            (summary.seconds > 0.0).then(|| retransmits as f64 / summary.seconds)
+        })
+        .reduce(|a, b| a + b)
+}
+
+impl Comparison {
+    /// Format the comparison as a human-readable table. Significant changes are marked with `*`.
+    pub fn table(&self) -> String {
+        let mut out = String::new();
+        for (field, (a, b)) in &self.arguments {
+            let show = |v: &Option<String>| v.clone().unwrap_or_else(|| "-".to_string());
+            _ = writeln!(out, "argument {field}: {} -> {}", show(a), show(b));
+        }
+
+        _ = writeln!(
+            out,
+            "{:<16} {:<9} {:<14} {:>14} {:>14} {:>10}",
+            "name", "direction", "metric", "a", "b", "change"
+        );
+        let rows = self
+            .clients
+            .iter()
+            .map(|(host, d)| (host.as_str(), d))
+            .chain(std::iter::once(("total", &self.totals)));
+        for (name, directions) in rows {
+            for d in directions {
+                let direction = format!("{:?}", d.direction);
+                let metrics = [
+                    ("Mbit/s", &d.goodput, 1_000_000.0),
+                    ("retrans/s", &d.retransmit_rate, 1.0),
+                    ("loss %", &d.loss_percent, 1.0),
+                ];
+                for (metric, change, scale) in metrics {
+                    row(&mut out, name, &direction, metric, change, scale);
+                }
+            }
+        }
+        for (host, c) in &self.monitors {
+            let name = format!("monitor {host}");
+            for (metric, change) in [
+                ("bytes", &c.bytes),
+                ("packets", &c.packets),
+                ("dropped", &c.dropped),
+            ] {
+                row(&mut out, &name, "", metric, change, 1.0);
+            }
+        }
+        out
+    }
+}
+
+/// Write a row of the table, skipping numbers neither run has.
+fn row(out: &mut String, name: &str, direction: &str, metric: &str, change: &Change, scale: f64) {
+    if change.a.is_none() && change.b.is_none() {
+        return;
+    }
+    let value = |v: Option<f64>| v.map_or("-".to_string(), |v| format!("{:.2}", v / scale));
+    let percent = change
+        .percent
+        .map_or("-".to_string(), |p| format!("{p:+.1}%"));
+    _ = writeln!(
+        out,
+        "{:<16} {:<9} {:<14} {:>14} {:>14} {:>10}{}",
+        name,
+        direction,
+        metric,
+        value(change.a),
+        value(change.b),
+        percent,
+        if change.significant { " *" } else { "" },
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn differences(fields: &[&str]) -> ArgumentDifferences {
+        fields
+            .iter()
+            .map(|f| {
+                (
+                    f.to_string(),
+                    (Some("1".to_string()), Some("2".to_string())),
+                )
+            })
+            .collect()
+    }
+
+    fn expected(fields: &[&str]) -> Vec<String> {
+        fields.iter().map(|f| f.to_string()).collect()
+    }
+
+    #[test]
+    fn change_relative_to_the_baseline() {
+        let change = Change::new(Some(200.0), Some(150.0), 5.0);
+        assert_eq!(change.percent, Some(-25.0));
+        assert!(change.significant);
+        // A negative baseline is compared by its size, so an increase is positive.
+        let change = Change::new(Some(-10.0), Some(-5.0), 5.0);
+        assert_eq!(change.percent, Some(50.0));
+        assert!(change.significant);
+    }
+
+    #[test]
+    fn change_without_a_percentage() {
+        for (a, b) in [
+            (Some(0.0), Some(10.0)),
+            (Some(0.0), Some(0.0)),
+            (None, Some(10.0)),
+            (Some(10.0), None),
+            (None, None),
+        ] {
+            let change = Change::new(a, b, 5.0);
+            assert_eq!(change.percent, None, "{a:?} {b:?}");
+            assert!(!change.significant, "{a:?} {b:?}");
+        }
+    }
+
+    #[test]
+    fn change_at_the_threshold() {
+        assert!(Change::new(Some(100.0), Some(105.0), 5.0).significant);
+        assert!(Change::new(Some(100.0), Some(95.0), 5.0).significant);
+        assert!(!Change::new(Some(100.0), Some(104.9), 5.0).significant);
+        assert!(!Change::new(Some(100.0), Some(100.0), 5.0).significant);
+        // Any change is significant with a threshold of 0.
+        assert!(Change::new(Some(100.0), Some(100.0), 0.0).significant);
+    }
+
+    #[test]
+    fn nested_arguments_are_flattened() {
+        let value: ron::Value = ron::from_str(
+            "(mcs: Some(7), clients: [\"a\"], network: (bandwidth: 80, bssid: None))",
+        )
+        .unwrap();
+        let mut fields = BTreeMap::new();
+        flatten(&value, "", &mut fields);
+        assert_eq!(fields["mcs"], "7");
+        assert_eq!(fields["network.bandwidth"], "80");
+        assert!(!fields.contains_key("network.bssid"));
+        assert!(fields.contains_key("clients"));
+    }
+
+    #[test]
+    fn expected_differences_match_nested_paths() {
+        let arguments = differences(&["mcs", "network.bandwidth", "network.frequency"]);
+        assert_eq!(
+            unexpected_differences(&arguments, &expected(&["mcs", "network.bandwidth"])),
+            ["network.frequency"]
+        );
+        assert!(unexpected_differences(&arguments, &expected(&["mcs", "network"])).is_empty());
+        // A prefix of a name is not its parent.
+        assert_eq!(
+            unexpected_differences(&arguments, &expected(&["mcs", "net"])),
+            ["network.bandwidth", "network.frequency"]
+        );
+        assert_eq!(
+            unexpected_differences(&arguments, &expected(&["network.bandwidth.x"])),
+            ["mcs", "network.bandwidth", "network.frequency"]
+        );
+    }
+
+    #[test]
+    fn derived_arguments_are_exempt() {
+        let arguments = differences(&["client_loads.a", "client_loads.b", "throughput"]);
+        assert!(unexpected_differences(&arguments, &expected(&["throughput"])).is_empty());
+        assert_eq!(unexpected_differences(&arguments, &[]), ["throughput"]);
+    }
+
+    #[test]
+    fn differences_between_argument_files() {
+        let dir = tempfile::tempdir().unwrap();
+        for (name, arguments) in [
+            ("a", "(mcs: None, network: (bandwidth: 80, ssid: \"net\"))"),
+            (
+                "b",
+                "(mcs: Some(7), network: (bandwidth: 40, ssid: \"net\"))",
+            ),
+        ] {
+            std::fs::create_dir(dir.path().join(name)).unwrap();
+            std::fs::write(dir.path().join(name).join("arguments.ron"), arguments).unwrap();
+        }
+        let differences =
+            argument_differences(&dir.path().join("a"), &dir.path().join("b")).unwrap();
+        assert_eq!(
+            differences,
+            BTreeMap::from([
+                ("mcs".to_string(), (None, Some("7".to_string()))),
+                (
+                    "network.bandwidth".to_string(),
+                    (Some("80".to_string()), Some("40".to_string()))
+                ),
+            ])
+        );
+    }
+}
@@ -0,0 +1,283 @@
+//! Reading the script to run and its arguments from a config file, so long invocations can be
+//! kept under version control.
+
+use std::path::Path;
+
+use anyhow::{anyhow, Context};
+use clap::{parser::ValueSource, ArgMatches, Command, CommandFactory, FromArgMatches};
+use serde::{de::DeserializeOwned, Deserializer};
+use tracing::{info, warn};
+
+use crate::scripts::Script;
+
+/// Read the script and its arguments from the config file at `path`, in TOML or RON depending on
+/// its extension. The arguments use the names of the fields of the arguments of the script, with
+/// the flattened arguments in nested tables.
+///
+/// ```toml
+/// script = "iperf"
+///
+/// [args]
+/// ap = "ap1"
+/// clients = ["nuc1", "nuc2"]
+/// udp = true
+/// total_throughput = 100000000
+///
+/// [args.network]
+/// monitors = ["mon1"]
+/// frequency = 5180
+/// bandwidth = 80
+/// ssid = "testnet"
+/// ```
+///
+/// In RON the name of the script is an identifier, written like `r#loaded-latency` if it
+/// contains a dash: `(script: iperf, args: (ap: Some("ap1"), ...))`.
+///
+/// The arguments passed on the command line in `matches` override those in the file. Returns the
+/// name of the script and its arguments.
+pub fn load(path: &Path, matches: &ArgMatches) -> anyhow::Result<(String, Script)> {
+    let raw = std::fs::read_to_string(path)
+        .with_context(|| format!("could not read {}", path.display()))?;
+    let script: Script = match path.extension().and_then(|e| e.to_str()) {
+        Some("toml") => deserialize(toml::Deserializer::new(&raw)),
+        Some("ron") => {
+            let mut de = ron::Deserializer::from_str(&raw)?;
+            deserialize(&mut de).and_then(|script| {
+                de.end()
+                    .map(|()| script)
+                    .map_err(|e| de.span_error(e).into())
+            })
+        }
+        _ => Err(anyhow!(
+            "config files must be TOML or RON, ending in .toml or .ron"
+        )),
+    }
+    .with_context(|| format!("invalid config file {}", path.display()))?;
+
+    apply_overrides(script, matches)
+}
+
+/// Deserialize `T`, naming the field in the error if a field is invalid and rejecting fields that
+/// do not exist, so typos are not silently ignored.
+fn deserialize<'de, T, D>(deserializer: D) -> anyhow::Result<T>
+where
+    T: DeserializeOwned,
+    D: Deserializer<'de>,
+    D::Error: Send + Sync + 'static,
+{
+    let mut unknown = Vec::new();
+    let mut track = |path: serde_ignored::Path| unknown.push(path.to_string());
+    let deserializer = serde_ignored::Deserializer::new(deserializer, &mut track);
+    let value = serde_path_to_error::deserialize(deserializer).map_err(|err| {
+        let path = err.path().to_string();
+        anyhow!("{path}: {}", err.into_inner())
+    })?;
+    if !unknown.is_empty() {
+        anyhow::bail!("unknown fields: {}", unknown.join(", "));
+    }
+    Ok(value)
+}
+
+/// Override the arguments of `script` with those passed on the command line in `matches`.
+fn apply_overrides(script: Script, matches: &ArgMatches) -> anyhow::Result<(String, Script)> {
+    let mut merged = serde_json::to_value(&script).context("failed to serialize arguments")?;
+    let name = merged["script"]
+        .as_str()
+        .expect("scripts are tagged with their name")
+        .to_string();
+    let Some((given, args)) = matches.subcommand() else {
+        return Ok((name, script));
+    };
+    if given != name {
+        anyhow::bail!("the config file is for the {name} script, not {given}");
+    }
+
+    // Updating also resets the arguments that were not passed to their defaults, so only the
+    // arguments that were passed are taken over.
+    let mut updated = script.clone();
+    updated
+        .update_from_arg_matches(matches)
+        .context("invalid arguments")?;
+    let updated = serde_json::to_value(&updated).context("failed to serialize arguments")?;
+    let command = Script::command();
+    let command = command
+        .find_subcommand(given)
+        .expect("the matches are of a script");
+    copy_overrides(&mut merged["args"], &updated["args"], command, args);
+    let script = serde_json::from_value(merged).context("could not apply the arguments")?;
+    Ok((name, script))
+}
+
+/// Copy the arguments of `command` that were passed on the command line in `matches` from
+/// `updated` to `merged`, both serialized to JSON.
+pub fn copy_overrides(
+    merged: &mut serde_json::Value,
+    updated: &serde_json::Value,
+    command: &Command,
+    matches: &ArgMatches,
+) {
+    for arg in command.get_arguments() {
+        let id = arg.get_id().as_str();
+        if matches.value_source(id) != Some(ValueSource::CommandLine) {
+            continue;
+        }
+        if copy_field(merged, updated, id) {
+            info!("Overriding {id}");
+        } else {
+            warn!("Could not override {id}");
+        }
+    }
+}
+
+/// Copy the field named `key` from `source` to `target`, also looking in nested structs for the
+/// arguments that are flattened on the command line. Returns false if there is no such field.
+fn copy_field(target: &mut serde_json::Value, source: &serde_json::Value, key: &str) -> bool {
+    let (serde_json::Value::Object(target), serde_json::Value::Object(source)) = (target, source)
+    else {
+        return false;
+    };
+    if let Some(value) = source.get(key) {
+        target.insert(key.to_string(), value.clone());
+        return true;
+    }
+    target.iter_mut().any(|(name, nested)| {
+        source
+            .get(name)
+            .is_some_and(|source| copy_field(nested, source, key))
+    })
+}
+
+/// Make every argument of the scripts optional, so the arguments that are in a config file do not
+/// have to be passed on the command line as well.
+pub fn relax(command: Command) -> Command {
+    let names: Vec<_> = command
+        .get_subcommands()
+        .map(|s| s.get_name().to_string())
+        .collect();
+    names
+        .iter()
+        .fold(command.subcommand_required(false), |command, name| {
+            command.mut_subcommand(name, relax_args)
+        })
+}
+
+/// Make every argument and group of arguments of `command` optional.
+pub fn relax_args(command: Command) -> Command {
+    let groups: Vec<_> = command
+        .get_groups()
+        .map(|g| g.get_id().to_string())
+        .collect();
+    groups.iter().fold(
+        command.mut_args(|arg| arg.required(false)),
+        |command, group| command.mut_group(group, |g| g.required(false)),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The example of [load].
+    const IPERF_TOML: &str = r#"
+script = "iperf"
+
+[args]
+ap = "ap1"
+clients = ["nuc1", "nuc2"]
+udp = true
+total_throughput = 100000000
+
+[args.network]
+monitors = ["mon1"]
+frequency = 5180
+bandwidth = 80
+ssid = "testnet"
+"#;
+
+    /// The same arguments on the command line.
+    const IPERF_ARGS: &str = "iperf --ap ap1 --clients nuc1,nuc2 --udp true \
+                              --throughput 100000000 --monitors mon1 --frequency 5180 \
+                              --bandwidth 80 --ssid testnet";
+
+    fn write(dir: &Path, name: &str, contents: &str) -> std::path::PathBuf {
+        let path = dir.join(name);
+        std::fs::write(&path, contents).unwrap();
+        path
+    }
+
+    fn matches(args: &str) -> ArgMatches {
+        let argv = ["controller"].into_iter().chain(args.split_whitespace());
+        // Unlike the command line of the controller, that of the scripts alone shows its help
+        // without a script.
+        relax(Script::command().arg_required_else_help(false)).get_matches_from(argv)
+    }
+
+    fn json(script: &Script) -> serde_json::Value {
+        serde_json::to_value(script).unwrap()
+    }
+
+    #[test]
+    fn arguments_in_a_file_match_the_command_line() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = write(dir.path(), "iperf.toml", IPERF_TOML);
+        let (name, script) = load(&path, &matches("")).unwrap();
+        assert_eq!(name, "iperf");
+
+        let parsed = Script::from_arg_matches(
+            &Script::command().get_matches_from(
+                ["controller"]
+                    .into_iter()
+                    .chain(IPERF_ARGS.split_whitespace()),
+            ),
+        )
+        .unwrap();
+        assert_eq!(json(&script), json(&parsed));
+    }
+
+    #[test]
+    fn arguments_round_trip_through_ron() {
+        let dir = tempfile::tempdir().unwrap();
+        let toml = write(dir.path(), "iperf.toml", IPERF_TOML);
+        let (_, script) = load(&toml, &matches("")).unwrap();
+
+        let dump = ron::ser::to_string_pretty(&script, ron::ser::PrettyConfig::new()).unwrap();
+        let ron = write(dir.path(), "iperf.ron", &dump);
+        let (name, loaded) = load(&ron, &matches("")).unwrap();
+        assert_eq!(name, "iperf");
+        assert_eq!(json(&loaded), json(&script));
+    }
+
+    #[test]
+    fn the_command_line_overrides_the_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = write(dir.path(), "iperf.toml", IPERF_TOML);
+        let (_, script) = load(&path, &matches("iperf --ssid other --clients nuc3")).unwrap();
+
+        let script = json(&script);
+        assert_eq!(script["args"]["network"]["ssid"], "other");
+        assert_eq!(script["args"]["clients"], serde_json::json!(["nuc3"]));
+        // Arguments that were not passed keep the value in the file.
+        assert_eq!(script["args"]["ap"], "ap1");
+        assert_eq!(script["args"]["network"]["frequency"], 5180);
+    }
+
+    #[test]
+    fn invalid_files_are_rejected() {
+        let dir = tempfile::tempdir().unwrap();
+        let err = |name: &str, contents: &str, args: &str| {
+            let path = write(dir.path(), name, contents);
+            format!("{:#}", load(&path, &matches(args)).unwrap_err())
+        };
+
+        let typo = IPERF_TOML.replace("udp = true", "upd = true");
+        assert!(err("typo.toml", &typo, "").contains("unknown fields: args.upd"));
+
+        let invalid = IPERF_TOML.replace("frequency = 5180", "frequency = \"high\"");
+        assert!(err("invalid.toml", &invalid, "").contains("args.network.frequency"));
+
+        assert!(err("iperf.toml", IPERF_TOML, "latency")
+            .contains("the config file is for the iperf script, not latency"));
+        assert!(err("iperf.yaml", IPERF_TOML, "").contains("must be TOML or RON"));
+        assert!(err("trailing.ron", "(script: cleanup, args: ()) ()", "").contains("trailing.ron"));
+    }
+}
@@ -0,0 +1,219 @@
+//! Measure airtime fairness by pinning one client to a low MCS while the others use automatic
+//! rate control.
+
+use std::{collections::BTreeMap, fmt::Write, path::Path};
+
+use anyhow::{anyhow, Context};
+use clap::Parser;
+use ron::ser::{to_string_pretty, PrettyConfig};
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+
+use crate::{
+    cancel::CancellationToken,
+    capture::analysis,
+    driver::wifi,
+    hosts::{HostId, Hosts},
+    mac::MacAddr,
+    scripts::iperf::{self, IperfArgs},
+    utils::host_file,
+};
+
+#[derive(Parser, Debug, Clone, Serialize, Deserialize)]
+pub struct FairnessArgs {
+    #[command(flatten)]
+    pub iperf: IperfArgs,
+    /// The host id of the client that is pinned to a low MCS. Must be one of the clients.
+    #[clap(long)]
+    pub slow_client: String,
+    /// The bitrate mask for the slow client, in the format of `iw dev <if> set bitrates`. For
+    /// example: `vht-mcs-5 1:0`.
+    ///
+    /// The mask restricts what the slow client transmits, so it affects the uplink and the
+    /// acknowledgements of the downlink.
+    #[clap(long)]
+    pub slow_mcs: String,
+}
+
+/// The share of a client in the throughput and the airtime, written to `fairness.ron`.
+#[derive(Debug, Clone, Serialize)]
+pub struct ClientFairness {
+    /// The MAC address of the client.
+    pub station: MacAddr,
+    /// Whether this is the client pinned to a low MCS.
+    pub slow: bool,
+    /// The throughput of the client over all directions in bits per second.
+    pub goodput: Option<f64>,
+    /// The airtime used by frames to and from the client in seconds.
+    pub airtime: Option<f64>,
+    /// The fraction of the airtime of all clients used by this client.
+    pub airtime_share: Option<f64>,
+}
+
+/// The results of the fairness experiment, written to `fairness.ron`.
+#[derive(Debug, Clone, Serialize)]
+pub struct FairnessResult {
+    pub clients: BTreeMap<HostId, ClientFairness>,
+    /// Jain's fairness index over the throughput of the clients, from `1 / n` to 1 when all
+    /// clients get the same throughput.
+    pub throughput_index: Option<f64>,
+}
+
+pub async fn run(
+    args: FairnessArgs,
+    hosts: &Hosts,
+    out_path: &Path,
+    cancel: &CancellationToken,
+) -> anyhow::Result<()> {
+    let mut iperf_args = args.iperf.clone();
+    // The per-client throughput is taken from the JSON results.
+    iperf_args.json = true;
+    iperf_args.validate().context("invalid arguments")?;
+    if iperf_args.iterations.repeat > 1
+        || iperf_args.throughput_sweep.is_some()
+        || iperf_args.client_sweep
+    {
+        anyhow::bail!(
+            "the fairness experiment runs once, without --repeat, --throughput-sweep or --client-sweep"
+        );
+    }
+    if !iperf_args.clients.contains(&args.slow_client) {
+        anyhow::bail!("the slow client must be one of the clients");
+    }
+    wifi::validate_bitrate_mask(&args.slow_mcs).context("invalid --slow-mcs")?;
+
+    tokio::fs::create_dir_all(out_path)
+        .await
+        .context("could not create output folder")?;
+    let args_dump =
+        to_string_pretty(&args, PrettyConfig::new()).context("failed to serialize args info")?;
+    tokio::fs::write(out_path.join("fairness-arguments.ron"), args_dump)
+        .await
+        .context("failed to save arguments")?;
+
+    let mut stations = BTreeMap::new();
+    for client in hosts
+        .get_many(&iperf_args.clients)
+        .map_err(|missing| anyhow!("no host with id {missing}"))?
+    {
+        let ifname = client
+            .extra_data
+            .interface_name()
+            .with_context(|| format!("client `{}` has no interface name configured", client.id))?;
+        let addr = wifi::interface_info(client, ifname)
+            .await?
+            .addr
+            .with_context(|| format!("could not determine the address of `{}`", client.id))?;
+        stations.insert(client.id.clone(), addr);
+    }
+
+    let slow = hosts
+        .get(&args.slow_client)
+        .context("slow client id not found")?;
+    // SAFETY: the interface name of every client was checked above.
+    let slow_ifname = slow
+        .extra_data
+        .interface_name()
+        .expect("client has an interface name");
+    info!(host = slow.id, "Pinning bitrates to `{}`", args.slow_mcs);
+    wifi::set_station_bitrates(slow, slow_ifname, Some(&args.slow_mcs))
+        .await
+        .context("failed to pin the bitrate of the slow client")?;
+
+    let results = iperf::run_with_pings(iperf_args.clone(), hosts, out_path, None, cancel).await;
+
+    if let Err(err) = wifi::set_station_bitrates(slow, slow_ifname, None).await {
+        warn!(host = slow.id, "Could not restore the bitrates: {err:?}");
+    }
+    let results = results?.into_iter().next().unwrap_or_default();
+
+    // Every monitor may have missed frames the others captured, so the largest airtime of a
+    // station over all monitors is used.
+    let mut airtime: BTreeMap<MacAddr, f64> = BTreeMap::new();
+    let network = &iperf_args.network;
+    if network.no_monitor {
+        warn!("Airtime shares require monitoring");
+    }
+    for monitor in network.monitors.iter().filter(|_| !network.no_monitor) {
+        let capture = host_file(out_path, monitor, "pcapng");
+        let addresses: Vec<MacAddr> = stations.values().copied().collect();
+        match analysis::station_airtime(&capture, &addresses).await {
+            Ok(found) => {
+                for (station, time) in found {
+                    let entry = airtime.entry(station).or_default();
+                    *entry = entry.max(time);
+                }
+            }
+            Err(err) => warn!("Could not analyze {}: {err:?}", capture.display()),
+        }
+    }
+    let total_airtime: f64 = airtime.values().sum();
+
+    let clients: BTreeMap<_, _> = stations
+        .into_iter()
+        .map(|(host, station)| {
+            let goodput = results.get(&host).map(|r| {
+                r.directions
+                    .iter()
+                    .filter_map(|d| d.summary.as_ref())
+                    .map(|s| s.bits_per_second)
+                    .sum()
+            });
+            let airtime = airtime.get(&station).copied();
+            let fairness = ClientFairness {
+                slow: host == args.slow_client,
+                station,
+                goodput,
+                airtime,
+                airtime_share: airtime
+                    .filter(|_| total_airtime > 0.0)
+                    .map(|a| a / total_airtime),
+            };
+            (host, fairness)
+        })
+        .collect();
+    let result = FairnessResult {
+        throughput_index: jain_index(clients.values().filter_map(|c| c.goodput)),
+        clients,
+    };
+
+    let dump =
+        to_string_pretty(&result, PrettyConfig::new()).context("failed to serialize results")?;
+    tokio::fs::write(out_path.join("fairness.ron"), dump)
+        .await
+        .context("failed to save results")?;
+    info!("Fairness per client:\n{}", fairness_table(&result));
+    Ok(())
+}
+
+/// Jain's fairness index `(Σx)² / (n · Σx²)`, or `None` without any values.
+pub fn jain_index(values: impl IntoIterator<Item = f64>) -> Option<f64> {
+    let (mut n, mut sum, mut squares) = (0.0, 0.0, 0.0);
+    for value in values {
+        n += 1.0;
+        sum += value;
+        squares += value * value;
+    }
+    (squares > 0.0).then(|| sum * sum / (n * squares))
+}
+
+fn fairness_table(result: &FairnessResult) -> String {
+    let mut table = String::new();
+    for (host, client) in &result.clients {
+        let goodput = client
+            .goodput
+            .map_or("-".to_string(), |g| format!("{:.1} Mbit/s", g / 1e6));
+        let share = client
+            .airtime_share
+            .map_or("-".to_string(), |s| format!("{:.1}%", s * 100.0));
+        _ = writeln!(
+            table,
+            "{host}{}: {goodput}, {share} of the airtime",
+            if client.slow { " (slow)" } else { "" }
+        );
+    }
+    if let Some(index) = result.throughput_index {
+        _ = writeln!(table, "Throughput fairness index: {index:.3}");
+    }
+    table
+}
@@ -0,0 +1,233 @@
+//! Gather the system, driver and connection state of every host, for debugging the testbed.
+
+use std::{collections::BTreeMap, fmt::Write, future::Future, path::Path};
+
+use anyhow::{anyhow, Context};
+use clap::Parser;
+use ron::ser::{to_string_pretty, PrettyConfig};
+use serde::{Deserialize, Serialize};
+use tokio::task::JoinSet;
+use tracing::info;
+
+use crate::{
+    driver::wifi::{self, DriverInfo, LinkInfo},
+    hosts::{Host, HostId, Hosts},
+};
+
+/// The tools whose versions are reported, with the command that prints the version.
+const TOOLS: &[(&str, &str)] = &[
+    ("iperf3", "iperf3 --version"),
+    ("tshark", "tshark --version"),
+    ("iw", "iw --version"),
+    ("nmcli", "nmcli --version"),
+    ("ping", "ping -V"),
+];
+
+#[derive(Parser, Debug, Clone, Serialize, Deserialize)]
+pub struct HostInfoArgs {
+    /// The host ids of the hosts to query. Defaults to all hosts.
+    #[clap(long, value_delimiter = ',', num_args = 1..)]
+    #[serde(default)]
+    pub hosts: Vec<String>,
+}
+
+/// Everything that was found out about a host, written to `host-info.ron`.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct HostInfo {
+    pub os: Option<String>,
+    pub kernel: Option<String>,
+    /// The driver of the main wireless interface.
+    pub driver: Option<DriverInfo>,
+    pub interfaces: Vec<InterfaceState>,
+    /// The SSID the host is connected to, if any.
+    pub ssid: Option<String>,
+    /// The connection of the main wireless interface.
+    pub link: Option<LinkInfo>,
+    /// The first line of the version output of every tool, `None` if it is not installed.
+    pub tools: BTreeMap<String, Option<String>>,
+    /// Where the interface and driver in the hosts file do not match the host.
+    pub config_mismatches: Vec<String>,
+    /// The probes that failed, so the information above is incomplete.
+    pub failures: Vec<String>,
+}
+
+/// A network interface, as reported by `ip -br addr`.
+#[derive(Debug, Clone, Serialize)]
+pub struct InterfaceState {
+    pub name: String,
+    pub state: String,
+    pub addresses: Vec<String>,
+}
+
+pub async fn run(args: HostInfoArgs, hosts: &Hosts, out_path: &Path) -> anyhow::Result<()> {
+    tokio::fs::create_dir_all(out_path)
+        .await
+        .context("could not create output folder")?;
+
+    let targets: Vec<_> = if args.hosts.is_empty() {
+        hosts.iter().cloned().collect()
+    } else {
+        hosts
+            .get_many(&args.hosts)
+            .map_err(|missing| anyhow!("no host with id {missing}"))?
+            .cloned()
+            .collect()
+    };
+    let mut tasks = JoinSet::new();
+    for host in targets {
+        tasks.spawn(async move { (host.id.clone(), host_info(&host).await) });
+    }
+    let infos: BTreeMap<HostId, HostInfo> = tasks.join_all().await.into_iter().collect();
+
+    info!("Hosts:\n{}", info_table(&infos));
+    let dump =
+        to_string_pretty(&infos, PrettyConfig::new()).context("failed to serialize host info")?;
+    tokio::fs::write(out_path.join("host-info.ron"), dump)
+        .await
+        .context("failed to save host info")
+}
+
+/// Run all probes on `host`. Failing probes are recorded instead of stopping the others.
+async fn host_info(host: &Host) -> HostInfo {
+    let mut info = HostInfo {
+        config_mismatches: host.config_mismatches.clone(),
+        ..Default::default()
+    };
+    let failures = &mut info.failures;
+
+    info.os = probe(failures, "os", async {
+        let os = shell(host, ". /etc/os-release && echo \"$PRETTY_NAME\"").await?;
+        Ok(os.trim().to_string())
+    })
+    .await;
+    info.kernel = probe(failures, "kernel", async {
+        Ok(shell(host, "uname -r").await?.trim().to_string())
+    })
+    .await;
+    info.interfaces = probe(failures, "interfaces", async {
+        Ok(parse_ip_brief(&shell(host, "ip -br addr").await?))
+    })
+    .await
+    .unwrap_or_default();
+    info.ssid = probe(failures, "ssid", host.connected_ssid())
+        .await
+        .flatten();
+
+    match host.extra_data.interface_name() {
+        Some(ifname) => {
+            info.driver = probe(failures, "driver", wifi::driver_info(host, ifname)).await;
+            info.link = probe(failures, "link", wifi::link_info(host, ifname)).await;
+        }
+        None => failures.push("no interface name configured".to_string()),
+    }
+
+    for (tool, command) in TOOLS {
+        // A tool that can not be run is reported as not installed rather than as a failure.
+        let version = shell(host, &format!("{command} 2>&1"))
+            .await
+            .ok()
+            .and_then(|out| out.lines().next().map(|l| l.trim().to_string()));
+        info.tools.insert(tool.to_string(), version);
+    }
+    info
+}
+
+/// Await a probe, recording its error in `failures` if it fails.
+async fn probe<T>(
+    failures: &mut Vec<String>,
+    name: &str,
+    probe: impl Future<Output = anyhow::Result<T>>,
+) -> Option<T> {
+    match probe.await {
+        Ok(value) => Some(value),
+        Err(err) => {
+            failures.push(format!("{name}: {err:#}"));
+            None
+        }
+    }
+}
+
+/// Run `command` on `host` and return its output, failing if it exits with an error.
+async fn shell(host: &Host, command: &str) -> anyhow::Result<String> {
+    let output = host
+        .session
+        .shell(command)
+        .output()
+        .await
+        .context("failed to run command")?;
+    if !output.status.success() {
+        anyhow::bail!("`{command}` exited with error code {}", output.status);
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+/// Parse the output of `ip -br addr`, which has lines like `wlp1s0 UP 10.0.0.2/24 fe80::1/64`.
+pub fn parse_ip_brief(output: &str) -> Vec<InterfaceState> {
+    output
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split_whitespace();
+            Some(InterfaceState {
+                // Virtual interfaces are shown as `name@parent`.
+                name: fields.next()?.split('@').next()?.to_string(),
+                state: fields.next()?.to_string(),
+                addresses: fields.map(str::to_string).collect(),
+            })
+        })
+        .collect()
+}
+
+fn info_table(infos: &BTreeMap<HostId, HostInfo>) -> String {
+    let mut out = String::new();
+    let opt = |v: Option<&str>| v.unwrap_or("-").to_string();
+    for (host, info) in infos {
+        _ = writeln!(
+            out,
+            "{host}: {} (kernel {})",
+            opt(info.os.as_deref()),
+            opt(info.kernel.as_deref())
+        );
+        if let Some(driver) = &info.driver {
+            _ = writeln!(
+                out,
+                "  driver: {} {}, firmware {}",
+                opt(driver.driver.as_deref()),
+                opt(driver.version.as_deref()),
+                opt(driver.firmware.as_deref())
+            );
+        }
+        for interface in &info.interfaces {
+            _ = writeln!(
+                out,
+                "  {:<16} {:<8} {}",
+                interface.name,
+                interface.state,
+                interface.addresses.join(" ")
+            );
+        }
+        match (&info.ssid, &info.link) {
+            (Some(ssid), Some(link)) => {
+                _ = writeln!(
+                    out,
+                    "  connected to `{ssid}` ({}), {} dBm, {}",
+                    opt(link.bssid.as_deref()),
+                    opt(link.signal.map(|s| s.to_string()).as_deref()),
+                    opt(link.tx_bitrate.as_deref())
+                )
+            }
+            (Some(ssid), None) => _ = writeln!(out, "  connected to `{ssid}`"),
+            (None, _) => _ = writeln!(out, "  not connected"),
+        }
+        for (tool, version) in &info.tools {
+            _ = writeln!(
+                out,
+                "  {tool}: {}",
+                version.as_deref().unwrap_or("not installed")
+            );
+        }
+        for failure in &info.failures {
+            _ = writeln!(out, "  failed: {failure}");
+        }
+    }
+    out
+}
@@ -0,0 +1,434 @@
+//! Run an iperf experiment while a second access point and client generate background traffic,
+//! to study how networks on the same or an adjacent channel share the medium.
+
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+    process::Output,
+    time::{Duration, SystemTime},
+};
+
+use anyhow::{anyhow, Context};
+use clap::Parser;
+use ron::ser::{to_string_pretty, PrettyConfig};
+use serde::{Deserialize, Serialize};
+use tokio::{select, task::JoinHandle, time::sleep};
+use tracing::{error, info, warn};
+
+use crate::{
+    cancel::{Aborted, CancellationToken},
+    capture::analysis::{self, AirtimeStats},
+    driver::wifi,
+    hosts::{Host, Hosts},
+    mac::MacAddr,
+    scripts::{
+        iperf::{self, Direction, IperfArgs, IperfResult},
+        mark_failed, KeyNumbers,
+    },
+    utils::{host_file, parse_bitrate, unix_time},
+};
+
+/// The port of the iperf server of the interferer, outside the range used by the experiment.
+const INTERFERER_PORT: u16 = 5900;
+
+/// The duration passed to the interferer client. It is stopped explicitly once the experiment is
+/// done, this only limits how long a client left behind by a crashed controller keeps sending.
+const INTERFERER_MAX_DURATION: u64 = 24 * 60 * 60;
+
+/// How long to wait for the interferer client to report its results after stopping it.
+const STOP_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Parser, Debug, Clone, Serialize, Deserialize)]
+pub struct InterferenceArgs {
+    #[command(flatten)]
+    pub iperf: IperfArgs,
+    /// The host id of the access point of the interfering network. It also runs the iperf
+    /// server of the interferer.
+    #[clap(long)]
+    pub interferer_ap: String,
+    /// The host id of the client connected to the interfering access point.
+    #[clap(long)]
+    pub interferer_client: String,
+    /// The offered load of the interferer in bits per second, 0 for unlimited TCP.
+    #[clap(long, value_parser = parse_bitrate)]
+    pub interferer_load: u64,
+    /// Let the interferer use TCP instead of UDP.
+    #[clap(long)]
+    #[serde(default)]
+    pub interferer_tcp: bool,
+    /// In which direction the interferer sends its traffic.
+    #[clap(long, default_value = "downlink")]
+    #[serde(default = "default_interferer_direction")]
+    pub interferer_direction: Direction,
+    /// How long the interferer runs in seconds before the experiment starts, so it has reached
+    /// a steady state.
+    #[clap(long, default_value = "3")]
+    #[serde(default = "default_interferer_lead")]
+    pub interferer_lead: u64,
+}
+
+// The defaults of the arguments when they are read from a config file. They match the
+// defaults of the command line.
+fn default_interferer_direction() -> Direction {
+    Direction::Downlink
+}
+
+fn default_interferer_lead() -> u64 {
+    3
+}
+
+/// The interferer and how it behaved, written to `interference.ron`.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct InterferenceReport {
+    /// The BSSID of the interfering network, used to tell its frames apart in the captures.
+    pub interferer_bssid: Option<MacAddr>,
+    /// The BSSID of the network under test.
+    pub primary_bssid: Option<MacAddr>,
+    /// The command run on the interferer client.
+    pub command: String,
+    /// When the interferer was started and stopped, in seconds since the unix epoch.
+    pub start: f64,
+    pub end: f64,
+    /// The throughput the interferer achieved in bits per second, per direction.
+    pub achieved: BTreeMap<String, f64>,
+    /// Why the interferer did not keep running for the whole experiment, if it did not.
+    pub failure: Option<String>,
+    /// The airtime of both networks in every capture of the experiment, by capture file.
+    pub airtime: BTreeMap<PathBuf, CaptureAirtime>,
+}
+
+/// The airtime in a capture split by network.
+#[derive(Debug, Clone, Serialize)]
+pub struct CaptureAirtime {
+    pub channel: AirtimeStats,
+    pub primary: Option<AirtimeStats>,
+    pub interferer: Option<AirtimeStats>,
+}
+
+pub async fn run(
+    args: InterferenceArgs,
+    hosts: &Hosts,
+    out_path: &Path,
+    cancel: &CancellationToken,
+) -> anyhow::Result<KeyNumbers> {
+    tokio::fs::create_dir_all(out_path)
+        .await
+        .context("could not create output folder")?;
+    // The iperf script writes its own arguments, so these are written separately.
+    let args_dump =
+        to_string_pretty(&args, PrettyConfig::new()).context("failed to serialize args info")?;
+    tokio::fs::write(out_path.join("interference-arguments.ron"), args_dump)
+        .await
+        .context("failed to save arguments")?;
+
+    let result = run_with_interferer(&args, hosts, out_path, cancel).await;
+    match &result {
+        Err(err) if !err.is::<Aborted>() => mark_failed(out_path, &format!("{err:#}")).await?,
+        _ => {}
+    }
+    result
+}
+
+async fn run_with_interferer(
+    args: &InterferenceArgs,
+    hosts: &Hosts,
+    out_path: &Path,
+    cancel: &CancellationToken,
+) -> anyhow::Result<KeyNumbers> {
+    let interferers = [&args.interferer_ap, &args.interferer_client];
+    let mut primary = args
+        .iperf
+        .clients
+        .iter()
+        .chain(&args.iperf.network.monitors);
+    if let Some(host) = primary.find(|h| interferers.contains(h)) {
+        anyhow::bail!("`{host}` can not be part of both the experiment and the interferer");
+    }
+    if [&args.iperf.ap, &args.iperf.server]
+        .into_iter()
+        .flatten()
+        .any(|h| interferers.contains(&h))
+    {
+        anyhow::bail!("the interferer needs its own access point and server");
+    }
+    if !args.interferer_tcp && args.interferer_load == 0 {
+        anyhow::bail!("a UDP interferer needs --interferer-load");
+    }
+    let ap = hosts
+        .get(&args.interferer_ap)
+        .ok_or_else(|| anyhow!("no host with id {}", args.interferer_ap))?
+        .clone();
+    let client = hosts
+        .get(&args.interferer_client)
+        .ok_or_else(|| anyhow!("no host with id {}", args.interferer_client))?
+        .clone();
+
+    let mut report = InterferenceReport {
+        interferer_bssid: bssid(&ap).await,
+        primary_bssid: match args.iperf.network.bssid {
+            Some(bssid) => Some(bssid),
+            None => match hosts.get(args.iperf.ap_id()) {
+                Some(primary_ap) => bssid(primary_ap).await,
+                None => None,
+            },
+        },
+        ..Default::default()
+    };
+    if report.interferer_bssid.is_none() {
+        warn!("The interferer can not be told apart in the captures without its BSSID");
+    }
+
+    // The interferer is started first, so the experiment runs against a steady background load.
+    let ports = INTERFERER_PORT..INTERFERER_PORT + 1;
+    iperf::kill_stale_iperfs(&[ap.clone(), client.clone()], ports.clone())
+        .await
+        .context("failed to clean up stale interferer processes")?;
+    let server_ip = ap
+        .ip_address()
+        .await
+        .context("failed to get IP address of the interferer access point")?;
+    let server = tokio::spawn({
+        let ap = ap.clone();
+        let command = iperf::server_command(
+            ap.extra_data.interface_name(),
+            &server_ip,
+            INTERFERER_PORT,
+            true,
+        );
+        async move { command.build(&ap.session).output().await }
+    });
+    if let Err(err) = iperf::wait_for_servers(&ap, ports.clone(), iperf::SERVER_START_TIMEOUT).await
+    {
+        server.abort();
+        return Err(err).context("the interferer server did not start");
+    }
+
+    report.command = client_command(args, &client, &server_ip);
+    info!(host = client.id, "Starting interferer: {}", report.command);
+    report.start = unix_time(SystemTime::now());
+    let mut interferer: JoinHandle<Result<Output, openssh::Error>> = tokio::spawn({
+        let client = client.clone();
+        let command = report.command.clone();
+        async move { client.session.shell(command).output().await }
+    });
+
+    // An interferer that can not connect exits right away, so check it is still running before
+    // starting the experiment.
+    sleep(Duration::from_secs(args.interferer_lead)).await;
+    let experiment = if interferer.is_finished() {
+        let output = (&mut interferer).await;
+        report.failure = Some(exit_reason(output));
+        Err(anyhow!(
+            "the interferer stopped before the experiment started"
+        ))
+    } else {
+        info!("Starting the experiment");
+        let experiment = iperf::run(args.iperf.clone(), hosts, out_path, cancel);
+        tokio::pin!(experiment);
+        select! {
+            result = &mut experiment => result,
+            output = &mut interferer => {
+                // The experiment keeps running, the results are marked instead.
+                let reason = exit_reason(output);
+                error!(host = client.id, "The interferer stopped during the experiment: {reason}");
+                report.failure = Some(reason);
+                experiment.await
+            }
+        }
+    };
+
+    let output = if report.failure.is_none() {
+        stop_interferer(&client, &mut interferer).await
+    } else {
+        None
+    };
+    report.end = unix_time(SystemTime::now());
+    server.abort();
+    if let Err(err) = iperf::kill_stale_iperfs(std::slice::from_ref(&ap), ports).await {
+        warn!(
+            host = ap.id,
+            "Could not stop the interferer server: {err:?}"
+        );
+    }
+
+    if let Some(output) = output {
+        tokio::fs::write(out_path.join("interferer.json"), &output.stdout)
+            .await
+            .context("failed to save interferer output")?;
+        match iperf::parse_json(&output.stdout, Duration::ZERO) {
+            Ok(result) => report.achieved = achieved(&result),
+            Err(err) => warn!("Could not parse the interferer output: {err:?}"),
+        }
+        for (direction, bitrate) in &report.achieved {
+            info!(
+                "Interferer achieved {:.1} Mbit/s {direction}",
+                bitrate / 1e6
+            );
+        }
+    }
+
+    if !args.iperf.network.no_monitor {
+        for capture in captures(out_path, &args.iperf.network.monitors).await {
+            match capture_airtime(&capture, &report).await {
+                Ok(airtime) => {
+                    report.airtime.insert(capture, airtime);
+                }
+                Err(err) => warn!("Could not analyze {}: {err:?}", capture.display()),
+            }
+        }
+    }
+
+    let dump = to_string_pretty(&report, PrettyConfig::new())
+        .context("failed to serialize interference report")?;
+    tokio::fs::write(out_path.join("interference.ron"), dump)
+        .await
+        .context("failed to save interference report")?;
+
+    let numbers = experiment?;
+    if let Some(failure) = report.failure {
+        anyhow::bail!("the interferer did not run for the whole experiment: {failure}");
+    }
+    Ok(numbers)
+}
+
+/// Build the command of the interferer client. JSON output is used, as iperf still reports the
+/// results of an interrupted test in it.
+fn client_command(args: &InterferenceArgs, client: &Host, server_ip: &str) -> String {
+    let mut cmd = format!(
+        "iperf3 -c {server_ip} -p {INTERFERER_PORT} -t {INTERFERER_MAX_DURATION} -b {} -J",
+        args.interferer_load
+    );
+    if let Some(ifname) = client.extra_data.interface_name() {
+        cmd.push_str(&format!(" --bind-dev {ifname}"));
+    } else if let Some(ip) = client.extra_data.interface_ip() {
+        cmd.push_str(&format!(" -B {ip}"));
+    }
+    if !args.interferer_tcp {
+        cmd.push_str(" -u");
+    }
+    match args.interferer_direction {
+        Direction::Uplink => {}
+        Direction::Downlink => cmd.push_str(" -R"),
+        Direction::Bidir => cmd.push_str(" --bidir"),
+    }
+    cmd
+}
+
+/// Interrupt the interferer client, which makes iperf print the results so far, and wait for its
+/// output.
+async fn stop_interferer(
+    client: &Host,
+    interferer: &mut JoinHandle<Result<Output, openssh::Error>>,
+) -> Option<Output> {
+    info!(host = client.id, "Stopping interferer");
+    let pattern = format!("[i]perf3 -c .*-p {INTERFERER_PORT} ");
+    if let Err(err) = client
+        .session
+        .shell(format!("pkill -INT -f '{pattern}'"))
+        .status()
+        .await
+    {
+        warn!(host = client.id, "Could not stop the interferer: {err:?}");
+    }
+    match tokio::time::timeout(STOP_TIMEOUT, &mut *interferer).await {
+        Ok(Ok(Ok(output))) => Some(output),
+        Ok(output) => {
+            warn!(
+                host = client.id,
+                "Could not get the interferer output: {}",
+                exit_reason(output)
+            );
+            None
+        }
+        Err(_) => {
+            interferer.abort();
+            warn!(host = client.id, "The interferer did not stop in time");
+            None
+        }
+    }
+}
+
+/// Describe why the interferer client exited.
+fn exit_reason(output: Result<Result<Output, openssh::Error>, tokio::task::JoinError>) -> String {
+    match output {
+        Ok(Ok(output)) => {
+            // iperf reports its errors in the JSON output.
+            let error = iperf::parse_json(&output.stdout, Duration::ZERO)
+                .ok()
+                .and_then(|r| r.error);
+            match error {
+                Some(error) => format!("exited with {}: {error}", output.status),
+                None => format!("exited with {}", output.status),
+            }
+        }
+        Ok(Err(err)) => format!("failed to run: {err}"),
+        Err(err) => format!("task failed: {err}"),
+    }
+}
+
+/// The average throughput of every direction of the interferer.
+fn achieved(result: &IperfResult) -> BTreeMap<String, f64> {
+    result
+        .directions
+        .iter()
+        .filter_map(|d| {
+            Some((
+                format!("{:?}", d.direction),
+                d.summary.as_ref()?.bits_per_second,
+            ))
+        })
+        .collect()
+}
+
+/// The address of the main wireless interface of an access point, which is its BSSID.
+async fn bssid(ap: &Host) -> Option<MacAddr> {
+    let ifname = ap.extra_data.interface_name()?;
+    match wifi::interface_info(ap, ifname).await {
+        Ok(info) => info.addr,
+        Err(err) => {
+            warn!(host = ap.id, "Could not get the BSSID: {err:?}");
+            None
+        }
+    }
+}
+
+/// The captures of the monitors in the output directory, including those of every iteration.
+async fn captures(out_path: &Path, monitors: &[String]) -> Vec<PathBuf> {
+    let mut found = Vec::new();
+    let mut dirs = vec![out_path.to_owned()];
+    while let Some(dir) = dirs.pop() {
+        for monitor in monitors {
+            let capture = host_file(&dir, monitor, "pcapng");
+            if capture.exists() {
+                found.push(capture);
+            }
+        }
+        let Ok(mut entries) = tokio::fs::read_dir(&dir).await else {
+            continue;
+        };
+        while let Ok(Some(entry)) = entries.next_entry().await {
+            if entry.file_type().await.is_ok_and(|t| t.is_dir()) {
+                dirs.push(entry.path());
+            }
+        }
+    }
+    found.sort();
+    found
+}
+
+async fn capture_airtime(
+    capture: &Path,
+    report: &InterferenceReport,
+) -> anyhow::Result<CaptureAirtime> {
+    let per_bss = |bssid: Option<MacAddr>| async move {
+        match bssid {
+            Some(bssid) => analysis::airtime(capture, Some(bssid)).await.map(Some),
+            None => Ok(None),
+        }
+    };
+    Ok(CaptureAirtime {
+        channel: analysis::airtime(capture, None).await?,
+        primary: per_bss(report.primary_bssid).await?,
+        interferer: per_bss(report.interferer_bssid).await?,
+    })
+}
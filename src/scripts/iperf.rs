@@ -1,275 +1,2383 @@
-use std::{path::Path, time::Duration};
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    fmt::Write as _,
+    ops::Range,
+    path::Path,
+    process::Output,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime},
+};
 
 use anyhow::{anyhow, Context};
-use clap::{Parser, ValueEnum};
+use clap::{ArgGroup, Parser, ValueEnum};
 use ron::ser::{to_string_pretty, PrettyConfig};
-use serde::Serialize;
-use tokio::{fs::File, io::AsyncWriteExt, select, time::sleep};
+use serde::{Deserialize, Serialize};
+use tokio::{select, task::JoinSet, time::sleep};
 use tracing::{debug, error, info, warn};
 
-use crate::{hosts::Hosts, monitor::MonitorConfig, utils::run_all};
+use crate::{
+    cancel::{Aborted, CancellationToken, Reason},
+    capture::{csv, trim},
+    daemon::{stop_all, RemoteDaemon},
+    driver::wifi::{self, Aid, StationBitrate},
+    hosts::{ExtraData, Host, HostId, Hosts},
+    metrics,
+    progress::{self, Phase},
+    results::{write_artifact, IperfResults},
+    scripts::iterations::{fingerprint, run_iterations, Iteration, IterationArgs},
+    scripts::latency::{
+        collect_pings, loaded_rtt, ping_command, write_results as write_latency, PingPlan,
+    },
+    scripts::monitoring::MonitorArgs,
+    scripts::{mark_aborted, mark_failed, KeyNumbers},
+    timing::{self, TimedPhase},
+    utils::{
+        format_bitrate, host_file, log_text, parse_bitrate, run_all, run_all_with, spawn_one,
+        unix_time, Line, LineHandler, RemoteCmd, RetryPolicy, RunOptions,
+    },
+};
 
-#[derive(Parser, Debug, Clone, Serialize)]
+mod clients;
+mod dscp;
+mod goodput_check;
+mod link_samples;
+mod parse;
+mod rc_trace;
+mod run_plan;
+mod status;
+mod summary;
+mod timeline;
+
+pub use clients::{write_clients, Attempt, ClientRecord};
+pub use dscp::{ClientDscp, Dscp};
+pub use goodput_check::{check_goodput, DirectionCheck, GoodputCheck};
+pub use link_samples::{LinkSample, LinkSampler};
+pub use parse::{
+    parse_interval_line, parse_json, parse_text, DirectionResult, Interval, IperfResult,
+    IperfTextResult, ReportedTotal, Side, Summary, TrafficDirection,
+};
+pub use rc_trace::{RcTrace, RcTraceHosts};
+pub use run_plan::{PlannedClient, PlannedMonitor, RunPlan};
+pub use status::{save_output, RunStatus, Saved};
+pub use summary::{
+    summarize, BitrateCheck, ClientSummary, DirectionSummary, GroupSummary, Outcome, RunSummary,
+    SummaryInput,
+};
+pub use timeline::{write_timeline, ClientSecond, Timeline, TimelineInput, TimelineRow};
+
+#[derive(Parser, Debug, Clone, Serialize, Deserialize)]
+#[command(group(ArgGroup::new("offered_load").args(["total_throughput", "throughput_sweep"])))]
+#[command(group(ArgGroup::new("endpoints").args(["ap", "server"]).required(true).multiple(true)))]
 pub struct IperfArgs {
-    /// The host id of where the iperf servers are running.
-    #[clap(long = "server")]
-    pub server: String,
+    /// The host id of the access point.
+    ///
+    /// The access point is used for configuring the MCS and is the server for the iperf tests if
+    /// no separate server is set.
+    #[clap(long)]
+    #[serde(default)]
+    pub ap: Option<String>,
+    /// The host id of where the iperf servers are running, for example a wired host behind the
+    /// access point. Defaults to the access point.
+    ///
+    /// DEPRECATED: when `--ap` is not set, this host is also used as the access point.
+    #[clap(long, alias = "server-host")]
+    #[serde(default)]
+    pub server: Option<String>,
     /// The host ids that will run iperf clients.
     #[clap(long, required = true, value_delimiter = ',', num_args = 1..)]
+    #[serde(default)]
     pub clients: Vec<String>,
-    /// The host id(s) of the hosts that will capture the wireless traffic.
-    #[clap(long, required = true, value_delimiter = ',', num_args = 1..)]
-    pub monitors: Vec<String>,
     /// In which direction to perform the IPerf tests.
     #[clap(short = 'D', long, default_value = "downlink")]
+    #[serde(default = "default_direction")]
     pub direction: Direction,
-    /// How the capture should last in seconds.
+    /// How long the iperf test should last in seconds.
     #[clap(short = 'd', long, default_value = "10")]
+    #[serde(default = "default_duration")]
     pub duration: u64,
+    /// Warm-up period in seconds that is excluded from the reported results.
+    ///
+    /// For TCP this is passed to iperf using `-O`, which extends the test by the warm-up period.
+    /// For UDP the first intervals are dropped when parsing the results instead. Must be smaller
+    /// than the duration.
+    #[clap(long, default_value = "0")]
+    #[serde(default)]
+    pub omit: u64,
+    /// The UDP datagram size in bytes, passed to iperf with `-l`. Only valid with UDP.
+    #[clap(long)]
+    #[serde(default)]
+    pub packet_size: Option<u32>,
+    /// The TCP maximum segment size in bytes, passed to iperf with `-M`. Only valid with TCP.
+    #[clap(long)]
+    #[serde(default)]
+    pub mss: Option<u32>,
+    /// Mark the traffic of all clients with this DSCP value, passed to iperf with `--dscp`.
+    ///
+    /// Accepts a value from 0 to 63 or a name like `EF`, `CS6` or `AF41`. The access category
+    /// used by Wi-Fi (WMM) is derived from the top three bits of the DSCP by default:
+    /// `CS1`, `AF1x`, `CS2` and `AF2x` map to background, `CS0`, `CS3` and `AF3x` to best effort,
+    /// `CS4`, `AF4x` and `CS5` to video and `CS6` and `CS7` to voice. Newer kernels follow RFC
+    /// 8325 instead, which for instance maps `EF` to voice.
+    #[clap(long)]
+    #[serde(default)]
+    pub dscp: Option<Dscp>,
+    /// Override the DSCP value for specific clients, in the form `<host>=<dscp>`.
+    #[clap(long, value_delimiter = ',', num_args = 1..)]
+    #[serde(default)]
+    pub client_dscp: Vec<ClientDscp>,
+    /// Do not count failing iperf clients as a failure of the run.
+    ///
+    /// The failures are still recorded in the summary. Useful for deliberately lossy experiments.
+    #[clap(long)]
+    #[serde(default)]
+    pub tolerate_client_failures: bool,
+    /// How often to retry an iperf client that fails within the first seconds, for example
+    /// because it could not connect.
+    ///
+    /// A retried client measures a later window than the others, which is marked in the results.
+    #[clap(long, default_value = "0")]
+    #[serde(default)]
+    pub client_retries: u32,
+    /// Do not kill iperf processes left over from earlier runs before starting.
+    ///
+    /// Only processes using the ports of this experiment are killed, but this can be used when
+    /// other experiments share the hosts.
+    #[clap(long)]
+    #[serde(default)]
+    pub no_precleanup: bool,
+    /// How long to wait in seconds after configuring the access point before starting the
+    /// experiment, so rate control can adapt to the new configuration.
+    #[clap(long, default_value = "2")]
+    #[serde(default = "default_settle")]
+    pub settle: u64,
+    /// Send a second of unlimited traffic while settling, so rate control converges faster. The
+    /// results of this traffic are discarded.
+    #[clap(long)]
+    #[serde(default)]
+    pub prime: bool,
+    /// Let the clients output JSON and parse it into a `results.ron` file.
+    #[clap(long)]
+    #[serde(default)]
+    pub json: bool,
+    /// Do not forward the output of the clients to the log while they are running.
+    ///
+    /// Live output is only available without `--json`.
+    #[clap(long)]
+    #[serde(default)]
+    pub quiet: bool,
     /// Whether to use UDP.
     #[clap(
         short = 'U',
         long = "udp",
         required = true,
-        requires_if("true", "total_throughput")
+        requires_if("true", "offered_load")
     )]
     pub udp: Option<bool>,
     /// The total throughput that the clients should use together in bits per second.
     ///
-    /// This will be divided equally over each client. Use 0 for unlimited throughput.
-    #[clap(short = 'T', long = "throughput", default_value = "0")]
+    /// This will be divided equally over each client, with the remainder going to the first
+    /// clients so the loads add up to the total. Use 0 for unlimited throughput. Accepts
+    /// `K`, `M` and `G` suffixes, for example `100M`.
+    #[clap(short = 'T', long = "throughput", default_value = "0", value_parser = parse_bitrate)]
+    #[serde(default)]
     pub total_throughput: u64,
+    /// Run the experiment once for each of these total throughputs, for example `50M,100M,200M`.
+    ///
+    /// Each offered load is written to its own `load-<throughput>` subdirectory and the parsed
+    /// results of all of them are combined in `sweep.csv`.
+    #[clap(long, value_delimiter = ',', num_args = 1.., value_parser = parse_bitrate)]
+    #[serde(default)]
+    pub throughput_sweep: Option<Vec<u64>>,
+    /// Run the experiment with only the first client, then the first two and so on until all
+    /// clients are used.
+    ///
+    /// Each client count is written to its own `clients-<n>` subdirectory and the parsed results
+    /// are combined in `client-sweep.csv`. Clients that are added in a step associate again, so
+    /// the monitors only have to discover their association IDs. Every monitor needs its own
+    /// association ID, so the sweep starts at the number of monitors.
+    #[clap(long)]
+    #[serde(default)]
+    pub client_sweep: bool,
     /// Configure the MCS.
     ///
     /// Follows the format of `iw dev <if> set bitrates <mcs...>`. For example: `he-mcs-5 1:11`.
-    /// Set tp auto to use automatic MCS. Not providing a value will not set anything.
+    /// Set to auto to use automatic MCS. Not providing a value will not set anything.
+    ///
+    /// The MCS is verified after the clients ran and cleared again at the end of the run.
     #[clap(long)]
+    #[serde(default)]
     pub mcs: Option<String>,
-    /// The frequency the access point is using in MHz.
-    #[clap(short = 'F', long)]
-    pub frequency: u32,
-    /// The bandwidth used by the AP in MHz.
-    #[clap(short = 'B', long)]
-    pub bandwidth: u32,
-    /// The SSID (display name) of the access point.
+    /// Fail the run if a configured parameter could not be verified, instead of only warning.
+    #[clap(long)]
+    #[serde(default)]
+    pub strict_params: bool,
+    /// Sample the rate control statistics of these hosts from debugfs while the traffic runs.
+    ///
+    /// The snapshots are written to `rc-trace/<host>.txt`. Only iwlwifi and mt76 are supported,
+    /// other hosts are skipped with a warning.
+    #[clap(long)]
+    #[serde(default)]
+    pub rc_trace: Option<RcTraceHosts>,
+    /// The time between rate control samples in seconds.
+    #[clap(long, default_value = "1")]
+    #[serde(default = "default_rc_trace_interval")]
+    pub rc_trace_interval: f64,
+    /// Export every capture to `<host>.frames.csv.gz` once the monitors are done, with a row per
+    /// frame. Requires tshark on the controller.
     #[clap(long)]
-    pub ssid: String,
-    /// The BSSID of the access point, often the MAC address.
+    #[serde(default)]
+    pub export_csv: bool,
+    /// Trim every capture to the time the clients ran once the monitors are done, writing it to
+    /// `<host>.trimmed.pcapng`. This leaves out the padding and association traffic around the
+    /// traffic, which skew statistics over the whole capture.
     #[clap(long)]
-    pub bssid: String,
+    #[serde(default)]
+    pub trim_captures: bool,
+    /// Replace the captures with their trimmed version instead of keeping both.
+    #[clap(long, requires = "trim_captures")]
+    #[serde(default)]
+    pub trim_in_place: bool,
+    /// Compare the goodput every client reported with the data frames the monitors captured for
+    /// it, flagging clients where they differ by more than `--analyze-tolerance`. Requires tshark
+    /// on the controller.
+    #[clap(long, requires = "json")]
+    #[serde(default)]
+    pub analyze: bool,
+    /// How much the captured and reported goodput of a client may differ in percent before it is
+    /// flagged.
+    #[clap(long, default_value = "20")]
+    #[serde(default = "default_analyze_tolerance")]
+    pub analyze_tolerance: f64,
+    /// Write `timeline.csv` with a row per second of the traffic, with the goodput and
+    /// retransmits of every client and the frames and retry ratio every monitor captured.
+    #[clap(long, requires = "json")]
+    #[serde(default)]
+    pub timeline: bool,
+    /// Add the signal strength of the clients to the timeline, sampling it every this many
+    /// seconds while the traffic runs.
+    #[clap(long, requires = "timeline")]
+    #[serde(default)]
+    pub sample_links: Option<f64>,
+    #[command(flatten)]
+    pub network: MonitorArgs,
+    #[command(flatten)]
+    #[serde(default)]
+    pub iterations: IterationArgs,
+    /// Split the clients into groups with their own protocol and offered load. Without groups,
+    /// all clients form a single group using `--udp` and `--throughput`.
+    #[clap(skip)]
+    #[serde(default)]
+    pub groups: Vec<TrafficGroup>,
+    /// The offered load every client ran with in bits per second, 0 if unlimited. Only filled in
+    /// when the arguments are written to `arguments.ron`, to record how the load was split.
+    #[clap(skip)]
+    #[serde(default)]
+    pub client_loads: BTreeMap<HostId, u64>,
+}
+
+/// A set of clients sharing a protocol and an offered load.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TrafficGroup {
+    pub name: String,
+    pub clients: Vec<HostId>,
+    pub udp: bool,
+    /// The total throughput of the clients of this group in bits per second, 0 if unlimited.
+    pub total_throughput: u64,
 }
 
-#[derive(ValueEnum, Debug, Clone, Copy, Serialize)]
+impl TrafficGroup {
+    /// The offered load of `client` in the group, see [split_load].
+    pub fn client_load(&self, client: &str) -> u64 {
+        let loads = split_load(self.total_throughput, self.clients.len());
+        self.clients
+            .iter()
+            .position(|c| c == client)
+            .map_or(0, |i| loads[i])
+    }
+}
+
+/// The lowest UDP offered load of a single client in bits per second. Below it iperf sends so few
+/// datagrams per interval that a single lost one dominates the loss.
+pub const MIN_UDP_CLIENT_LOAD: u64 = 100_000;
+
+/// Divide `total` bits per second over `clients` clients as equally as possible. The remainder of
+/// the division goes to the first clients, one bit per second each, so the loads add up to
+/// `total`.
+pub fn split_load(total: u64, clients: usize) -> Vec<u64> {
+    let Some(count) = u64::try_from(clients).ok().filter(|&c| c > 0) else {
+        return Vec::new();
+    };
+    let (base, remainder) = (total / count, total % count);
+    (0..count)
+        .map(|i| base + u64::from(i < remainder))
+        .collect()
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy, Serialize, Deserialize)]
 pub enum Direction {
     Uplink,
     Downlink,
     Bidir,
 }
 
-pub async fn run(args: IperfArgs, hosts: Hosts, out_path: &Path) -> anyhow::Result<()> {
-    let args_dump = {
-        let config = PrettyConfig::new()
-            .depth_limit(2)
-            .separate_tuple_members(true)
-            .enumerate_arrays(true);
-        to_string_pretty(&args, config).context("failed to serialize args info")?
+// The defaults of arguments that were added later, used when reading the arguments of earlier
+// runs and config files. They match the defaults of the command line.
+fn default_direction() -> Direction {
+    Direction::Downlink
+}
+
+fn default_duration() -> u64 {
+    10
+}
+
+fn default_settle() -> u64 {
+    2
+}
+
+fn default_rc_trace_interval() -> f64 {
+    1.0
+}
+
+fn default_analyze_tolerance() -> f64 {
+    20.0
+}
+
+/// Whether `seconds` is a period that is larger than 0 and fits a [Duration], which rules out
+/// `NaN` and infinity.
+fn is_period(seconds: f64) -> bool {
+    Duration::try_from_secs_f64(seconds).is_ok_and(|period| !period.is_zero())
+}
+
+impl IperfArgs {
+    /// Validate combinations of arguments that can not be expressed through clap.
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if self.clients.is_empty() {
+            anyhow::bail!("at least one client is required");
+        }
+        if self.omit >= self.duration {
+            anyhow::bail!(
+                "omit ({}s) must be smaller than the duration ({}s)",
+                self.omit,
+                self.duration
+            );
+        }
+        if self.rc_trace.is_some() && !is_period(self.rc_trace_interval) {
+            anyhow::bail!("--rc-trace-interval must be larger than 0");
+        }
+        if self.analyze && self.network.no_monitor {
+            anyhow::bail!("--analyze needs the captures of the monitors");
+        }
+        if self.analyze_tolerance < 0.0 {
+            anyhow::bail!("--analyze-tolerance can not be negative");
+        }
+        if self.sample_links.is_some_and(|period| !is_period(period)) {
+            anyhow::bail!("--sample-links must be larger than 0");
+        }
+        if let Some(Some(mask)) = self.mcs_mask() {
+            wifi::validate_bitrate_mask(mask).context("invalid --mcs")?;
+        }
+        let groups = self.traffic_groups();
+        if !self.groups.is_empty() {
+            self.validate_groups()?;
+        }
+        if let Some(size) = self.packet_size {
+            if !groups.iter().any(|g| g.udp) {
+                anyhow::bail!("--packet-size can only be used with UDP");
+            }
+            if !(16..=65507).contains(&size) {
+                anyhow::bail!("packet size must be between 16 and 65507 bytes, got {size}");
+            }
+        }
+        if let Some(unknown) = self
+            .client_dscp
+            .iter()
+            .find(|c| !self.clients.contains(&c.host))
+        {
+            anyhow::bail!(
+                "DSCP override for `{}`, which is not a client",
+                unknown.host
+            );
+        }
+        for group in groups.iter().filter(|g| g.udp) {
+            let loads = match &self.throughput_sweep {
+                Some(loads) if self.groups.is_empty() => loads.clone(),
+                _ => vec![group.total_throughput],
+            };
+            // With --client-sweep the load is split over the most clients in the last iteration.
+            let clients = group.clients.len() as u64;
+            if let Some(load) = loads
+                .into_iter()
+                .find(|&load| load > 0 && load / clients < MIN_UDP_CLIENT_LOAD)
+            {
+                anyhow::bail!(
+                    "an offered load of {} over {clients} UDP clients is below the minimum of {} \
+                     per client",
+                    format_bitrate(load),
+                    format_bitrate(MIN_UDP_CLIENT_LOAD)
+                );
+            }
+        }
+        if let Some(mss) = self.mss {
+            if groups.iter().all(|g| g.udp) {
+                anyhow::bail!("--mss can only be used with TCP");
+            }
+            if !(88..=9216).contains(&mss) {
+                anyhow::bail!("MSS must be between 88 and 9216 bytes, got {mss}");
+            }
+        }
+        Ok(())
+    }
+
+    /// Check that every client is in exactly one of the explicit groups.
+    fn validate_groups(&self) -> anyhow::Result<()> {
+        if self.throughput_sweep.is_some() {
+            anyhow::bail!("--throughput-sweep can not be used with traffic groups");
+        }
+        if self.client_sweep {
+            anyhow::bail!("--client-sweep can not be used with traffic groups");
+        }
+        let mut seen = HashSet::new();
+        for group in &self.groups {
+            if group.clients.is_empty() {
+                anyhow::bail!("the {} group has no clients", group.name);
+            }
+            if group.udp && group.total_throughput == 0 {
+                anyhow::bail!(
+                    "the {} group uses UDP and needs an offered load",
+                    group.name
+                );
+            }
+            if let Some(client) = group.clients.iter().find(|c| !seen.insert(*c)) {
+                anyhow::bail!("`{client}` is in more than one group");
+            }
+        }
+        if let Some(client) = self.clients.iter().find(|c| !seen.contains(c)) {
+            anyhow::bail!("`{client}` is not in any group");
+        }
+        if seen.len() != self.clients.len() {
+            anyhow::bail!("every client of a group must be one of the clients");
+        }
+        Ok(())
+    }
+
+    /// The groups the clients are split into. Without explicit groups all clients form a single
+    /// group.
+    pub fn traffic_groups(&self) -> Vec<TrafficGroup> {
+        if !self.groups.is_empty() {
+            return self.groups.clone();
+        }
+        let udp = self.is_udp();
+        vec![TrafficGroup {
+            name: if udp { "udp" } else { "tcp" }.to_string(),
+            clients: self.clients.clone(),
+            udp,
+            total_throughput: self.total_throughput,
+        }]
+    }
+
+    /// Whether the client uses UDP, following its group.
+    fn client_is_udp(&self, host: &str) -> bool {
+        self.groups
+            .iter()
+            .find(|g| g.clients.iter().any(|c| c == host))
+            .map_or(self.is_udp(), |g| g.udp)
+    }
+
+    /// The host id of the access point.
+    pub fn ap_id(&self) -> &str {
+        self.ap
+            .as_deref()
+            .or(self.server.as_deref())
+            .expect("either an AP or server is required by clap")
+    }
+
+    /// The host id of the host running the iperf servers.
+    pub fn server_id(&self) -> &str {
+        self.server
+            .as_deref()
+            .or(self.ap.as_deref())
+            .expect("either an AP or server is required by clap")
+    }
+
+    /// Whether the experiment runs more than once, because it is repeated or swept.
+    fn is_repeated(&self) -> bool {
+        self.iterations.repeat > 1 || self.throughput_sweep.is_some() || self.client_sweep
+    }
+
+    /// The DSCP value used for the traffic of a client.
+    fn dscp_for(&self, host: &str) -> Option<Dscp> {
+        self.client_dscp
+            .iter()
+            .find(|c| c.host == host)
+            .map(|c| c.dscp)
+            .or(self.dscp)
+    }
+
+    /// Whether the interval output of the clients should be forwarded to the log as it comes in.
+    fn live_output(&self) -> bool {
+        // JSON output is only printed once the test completes.
+        !self.quiet && !self.json
+    }
+
+    /// The bitrate mask to apply to the access point, if any. `Some(None)` clears the mask so
+    /// the MCS is picked automatically.
+    pub fn mcs_mask(&self) -> Option<Option<&str>> {
+        let mcs = self.mcs.as_deref()?;
+        Some((!mcs.eq_ignore_ascii_case("auto")).then_some(mcs))
+    }
+
+    /// Whether a failing client should stop the run right away.
+    fn stop_on_client_failure(&self) -> bool {
+        self.iterations.fail_fast && !self.tolerate_client_failures
+    }
+
+    /// Whether the test uses UDP, which is the default.
+    fn is_udp(&self) -> bool {
+        self.udp.unwrap_or(true)
+    }
+
+    /// The longest warm-up period passed to iperf with `-O`. This is only used for TCP, UDP
+    /// results are trimmed afterwards instead.
+    fn iperf_omit(&self) -> u64 {
+        if self.traffic_groups().iter().all(|g| g.udp) {
+            0
+        } else {
+            self.omit
+        }
+    }
+}
+
+/// The port of the first iperf server. Every next client uses the next port.
+const FIRST_PORT: u16 = 5001;
+
+/// The ports of the iperf servers of `clients` clients, each with its own server.
+pub fn server_ports(clients: usize) -> Range<u16> {
+    FIRST_PORT..FIRST_PORT + clients as u16
+}
+
+/// How long to wait for the iperf servers to start listening.
+pub const SERVER_START_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The least time the iperf servers get to exit after their clients finished.
+const SERVER_EXIT_MIN: Duration = Duration::from_secs(5);
+
+/// How long the iperf servers of a test of length `test` may take to exit after their clients
+/// finished, before they are stopped. The servers exchange the results with their clients before
+/// exiting, which takes longer after longer tests.
+pub fn server_exit_timeout(test: Duration) -> Duration {
+    SERVER_EXIT_MIN.max(test / 10)
+}
+
+/// How soon after starting a failing client may be retried. Later failures are not caused by
+/// connection problems and are not retried.
+const CLIENT_RETRY_WINDOW: Duration = Duration::from_secs(5);
+
+/// The TCP ports that are being listened on by the server.
+pub async fn server_listening_ports(server: &Host) -> anyhow::Result<HashSet<u16>> {
+    let output = server
+        .shell_checked("ss -tln 2>/dev/null || netstat -tln")
+        .await
+        .context("failed to list listening ports on the server")?;
+    Ok(listening_ports(&String::from_utf8_lossy(&output.stdout)))
+}
+
+/// Make sure an iperf server is listening on `port`, starting a new one if the old one already
+/// exited. Returns the new server, if one was started.
+async fn ensure_server(
+    server: &Arc<Host>,
+    server_ifname: Option<&str>,
+    server_ip: &str,
+    port: u16,
+) -> anyhow::Result<Option<RemoteDaemon>> {
+    if server_listening_ports(server).await?.contains(&port) {
+        return Ok(None);
+    }
+
+    debug!(port, "Restarting iperf server");
+    let command = server_command(server_ifname, server_ip, port, true);
+    let daemon = server.spawn_daemon(command).await?;
+    if let Err(err) = wait_for_servers(server, port..port + 1, SERVER_START_TIMEOUT).await {
+        stop_all([daemon]).await;
+        return Err(err);
+    }
+    Ok(Some(daemon))
+}
+
+/// Wait until iperf servers are listening on all `ports` of the server.
+///
+/// iperf3 servers always listen over TCP for the control connection, also when testing with UDP,
+/// so only TCP ports are checked.
+pub async fn wait_for_servers(
+    server: &Host,
+    ports: Range<u16>,
+    timeout: Duration,
+) -> anyhow::Result<()> {
+    let start = Instant::now();
+    loop {
+        let listening = server_listening_ports(server).await?;
+
+        let missing: Vec<_> = ports.clone().filter(|p| !listening.contains(p)).collect();
+        if missing.is_empty() {
+            info!("iperf servers ready after {:.2?}", start.elapsed());
+            return Ok(());
+        }
+        if start.elapsed() > timeout {
+            for port in &missing {
+                error!(
+                    host = server.id,
+                    "No iperf server is listening on port {port}"
+                );
+            }
+            anyhow::bail!(
+                "iperf servers did not start listening within {timeout:?} on ports {missing:?}"
+            );
+        }
+        sleep(Duration::from_millis(100)).await;
+    }
+}
+
+/// Parse the local ports from the output of `ss -tln` or `netstat -tln`.
+///
+/// Both print the local address as the fourth column. Lines that can not be parsed, like headers,
+/// are skipped.
+fn listening_ports(output: &str) -> HashSet<u16> {
+    output
+        .lines()
+        .filter_map(|line| line.split_whitespace().nth(3))
+        .filter_map(|addr| addr.rsplit_once(':'))
+        .filter_map(|(_, port)| port.parse().ok())
+        .collect()
+}
+
+/// Kill iperf servers and clients left over from earlier runs that use one of the ports in
+/// `ports`, so new clients can not connect to them. Other iperf processes are left alone.
+pub async fn kill_stale_iperfs(hosts: &[Arc<Host>], ports: Range<u16>) -> anyhow::Result<()> {
+    let pattern = stale_iperf_pattern(ports);
+    // Killing is idempotent, so transient connection failures can be retried.
+    let outputs = run_all_with(
+        hosts,
+        |_| format!("pgrep -af '{pattern}' && pkill -f '{pattern}' || true"),
+        RunOptions {
+            retry: Some(RetryPolicy::SSH),
+            ..Default::default()
+        },
+    )
+    .await?;
+
+    for (host, output) in outputs {
+        let killed = log_text(&output.stdout);
+        if killed.trim().is_empty() {
+            debug!(host = host.id, "No stale iperf processes found");
+        }
+        for line in killed.lines() {
+            info!(host = host.id, "Killed stale iperf process: {line}");
+        }
+    }
+    Ok(())
+}
+
+/// A `pgrep`/`pkill` pattern matching iperf servers and clients using one of the ports.
+///
+/// The option is written as a bracket expression, so the pattern does not match the command line
+/// of the shell running `pgrep` itself.
+fn stale_iperf_pattern(ports: Range<u16>) -> String {
+    let ports = ports.map(|p| p.to_string()).collect::<Vec<_>>().join("|");
+    format!("iperf3 -[sc] .*-p ({ports})( |$)")
+}
+
+/// Build the command for an iperf server on `port`. With `single_test` it exits after handling a
+/// single test, otherwise it keeps running until it is stopped.
+pub fn server_command(
+    bind_dev: Option<&str>,
+    server_ip: &str,
+    port: u16,
+    single_test: bool,
+) -> RemoteCmd {
+    let command = RemoteCmd::new("iperf3").arg("-s");
+    let command = match bind_dev {
+        Some(ifname) => command.arg("--bind-dev").arg(ifname),
+        None => command.arg("-B").arg(server_ip),
     };
+    let command = command.arg("-p").arg(port);
+    match single_test {
+        true => command.arg("-1"),
+        false => command,
+    }
+}
 
-    let total_bandwidth = args.total_throughput;
-    let udp = args.udp.unwrap_or(true);
+/// Start an iperf server on `server` for every port in `ports` and wait until they are listening.
+/// If not all of them start, those that did are stopped again.
+pub async fn spawn_servers(
+    server: &Arc<Host>,
+    bind_dev: Option<&str>,
+    server_ip: &str,
+    ports: Range<u16>,
+    single_test: bool,
+) -> anyhow::Result<Vec<RemoteDaemon>> {
+    info!("Starting iperf servers");
+    let mut starting = JoinSet::new();
+    for port in ports.clone() {
+        let server = server.clone();
+        let command = server_command(bind_dev, server_ip, port, single_test);
+        starting.spawn(async move { server.spawn_daemon(command).await });
+    }
+    let mut servers = Vec::with_capacity(ports.len());
+    while let Some(joined) = starting.join_next().await {
+        // Servers that did start are killed when dropped.
+        servers.push(joined.context("iperf server task failed")??);
+    }
+    if let Err(err) = wait_for_servers(server, ports, SERVER_START_TIMEOUT).await {
+        stop_all(servers).await;
+        return Err(err);
+    }
+    Ok(servers)
+}
 
-    let senders: Vec<_> = hosts
-        .get_many(&args.clients)
-        .map_err(|missing| anyhow!("no host with id {missing}"))?
+/// The hosts taking part in an experiment, resolved from the arguments.
+pub struct Endpoints {
+    pub senders: Vec<Arc<Host>>,
+    pub access_point: Arc<Host>,
+    pub server: Arc<Host>,
+    /// The address the servers listen on.
+    pub server_ip: String,
+    /// Every client connects to its own server, each listening on a different port.
+    pub ports: Range<u16>,
+}
+
+impl Endpoints {
+    /// Look up the hosts of the experiment and the address of the server.
+    pub async fn resolve(args: &IperfArgs, hosts: &Hosts) -> anyhow::Result<Self> {
+        let mut endpoints = Self::lookup(args, hosts)?;
+        endpoints.server_ip = endpoints
+            .server
+            .ip_address()
+            .await
+            .context("failed to get IP address of server")?;
+        Ok(endpoints)
+    }
+
+    /// Look up the hosts of the experiment without running anything on them. The address of the
+    /// server is a placeholder unless it is set in the hosts file.
+    pub fn lookup(args: &IperfArgs, hosts: &Hosts) -> anyhow::Result<Self> {
+        let senders: Vec<_> = hosts
+            .get_many(&args.clients)
+            .map_err(|missing| anyhow!("no host with id {missing}"))?
+            .cloned()
+            .collect();
+        let access_point = hosts
+            .get(args.ap_id())
+            .context("access point id not found")?
+            .clone();
+        let server = hosts
+            .get(args.server_id())
+            .context("server id not found")?
+            .clone();
+        let server_ip = server
+            .extra_data
+            .interface_ip()
+            .map_or_else(|| format!("<address of {}>", server.id), str::to_string);
+        let ports = server_ports(senders.len());
+        Ok(Self {
+            senders,
+            access_point,
+            server,
+            server_ip,
+            ports,
+        })
+    }
+
+    pub fn server_ifname(&self) -> Option<&str> {
+        self.server.extra_data.interface_name()
+    }
+
+    /// The hosts running iperf: the clients and the server.
+    pub fn participants(&self) -> Vec<Arc<Host>> {
+        let mut participants = self.senders.clone();
+        if !participants.iter().any(|h| h.id == self.server.id) {
+            participants.push(self.server.clone());
+        }
+        participants
+    }
+
+    /// Start a server for every client and wait until they are listening. Each server exits
+    /// after a single test.
+    pub async fn spawn_servers(&self) -> anyhow::Result<Vec<RemoteDaemon>> {
+        spawn_servers(
+            &self.server,
+            self.server_ifname(),
+            &self.server_ip,
+            self.ports.clone(),
+            true,
+        )
+        .await
+    }
+}
+
+/// The MCS set on the access point for a run, which is restored to the mask that was in use
+/// before once the run is over.
+pub struct PinnedMcs {
+    access_point: Arc<Host>,
+    ifname: String,
+    /// The mask in use before the MCS was set, `None` if rate control could pick any bitrate.
+    previous: Option<String>,
+}
+
+impl PinnedMcs {
+    pub fn previous(&self) -> Option<&str> {
+        self.previous.as_deref()
+    }
+
+    /// Set the mask that was in use before the MCS was set again.
+    pub async fn restore(self) -> anyhow::Result<()> {
+        debug!("Restoring MCS");
+        wifi::set_bitrates(&self.access_point, &self.ifname, self.previous.as_deref())
+            .await
+            .context("failed to restore the MCS")
+    }
+}
+
+/// Configure the MCS on the access point, if the arguments restrict it. The returned MCS must be
+/// restored when the run is over, also when it fails.
+pub async fn set_mcs(
+    args: &IperfArgs,
+    access_point: &Arc<Host>,
+) -> anyhow::Result<Option<PinnedMcs>> {
+    let Some(mask) = args.mcs_mask() else {
+        return Ok(None);
+    };
+    let Some(ap_ifname) = access_point.extra_data.interface_name() else {
+        anyhow::bail!("Access point should have an interface name configured to set the MCS");
+    };
+    let previous = wifi::bitrate_mask(access_point, ap_ifname)
+        .await
+        .context("failed to get the MCS before setting it")?;
+    debug!("Setting MCS");
+    wifi::set_bitrates(access_point, ap_ifname, mask)
+        .await
+        .context("failed to set MCS")?;
+    Ok(Some(PinnedMcs {
+        access_point: access_point.clone(),
+        ifname: ap_ifname.to_string(),
+        previous,
+    }))
+}
+
+/// Build the command for an iperf client with the host data `client` connecting to the server
+/// at `server_ip:port`.
+fn client_command(
+    args: &IperfArgs,
+    client: &ExtraData,
+    server_ip: &str,
+    port: u16,
+    udp: bool,
+    bitrate: u64,
+    dscp: Option<Dscp>,
+) -> RemoteCmd {
+    let mut cmd = RemoteCmd::new("iperf3")
+        .args(["-c", server_ip])
+        .arg("-p")
+        .arg(port)
+        .arg("-t")
+        .arg(args.duration);
+    if let Some(ifname) = client.interface_name() {
+        cmd = cmd.args(["--bind-dev", ifname]);
+    } else if let Some(ip) = client.interface_ip() {
+        cmd = cmd.arg("-B").arg(ip);
+    }
+    cmd = cmd.arg("-b").arg(bitrate);
+    if udp {
+        cmd = cmd.arg("-u");
+    }
+    match args.direction {
+        Direction::Uplink => {}
+        Direction::Downlink => cmd = cmd.arg("-R"),
+        Direction::Bidir => cmd = cmd.arg("--bidir"),
+    }
+    if !udp && args.omit > 0 {
+        cmd = cmd.arg("-O").arg(args.omit);
+    }
+    match (udp, args.packet_size, args.mss) {
+        (true, Some(size), _) => cmd = cmd.arg("-l").arg(size),
+        (false, _, Some(mss)) => cmd = cmd.arg("-M").arg(mss),
+        _ => {}
+    }
+    if let Some(dscp) = dscp {
+        cmd = cmd.arg("--dscp").arg(dscp);
+    }
+    if args.json {
+        cmd.arg("-J")
+    } else {
+        // Make sure every interval is printed right away, so it can be followed live.
+        cmd.arg("--forceflush")
+    }
+}
+
+pub async fn run(
+    args: IperfArgs,
+    hosts: &Hosts,
+    out_path: &Path,
+    cancel: &CancellationToken,
+) -> anyhow::Result<KeyNumbers> {
+    let results = run_with_pings(args, hosts, out_path, None, cancel).await?;
+    Ok(key_numbers(&results))
+}
+
+/// The key numbers of the results of every repetition: `total_throughput` is the throughput of
+/// all clients in all directions together in bits per second, averaged over the repetitions.
+fn key_numbers(results: &[BTreeMap<HostId, IperfResult>]) -> KeyNumbers {
+    let totals: Vec<f64> = results
+        .iter()
+        .map(|run| {
+            run.values()
+                .flat_map(|result| &result.directions)
+                .filter_map(|direction| direction.summary.as_ref())
+                .map(|summary| summary.bits_per_second)
+                .sum()
+        })
         .collect();
+    let mut numbers = KeyNumbers::new();
+    if !totals.is_empty() {
+        let mean = totals.iter().sum::<f64>() / totals.len() as f64;
+        numbers.insert("total_throughput".to_string(), mean);
+    }
+    numbers
+}
 
-    let access_point = hosts
-        .get(&args.server)
-        .context("access point id not found")?
-        .clone();
+/// Validate the arguments and warn about those that are discouraged.
+fn check(args: &IperfArgs) -> anyhow::Result<()> {
+    args.validate().context("invalid arguments")?;
+    if matches!(args.direction, Direction::Bidir) && !args.json {
+        warn!("Bidirectional results are only split into uplink and downlink with --json");
+    }
+    if args.ap.is_none() {
+        warn!(
+            "Using --server without --ap is deprecated, pass the access point using --ap instead"
+        );
+    }
+    Ok(())
+}
 
-    let Some(access_point_ifname) = access_point.extra_data.interface.clone() else {
-        anyhow::bail!("Access point should have a wireless interface IP configured");
+/// The iterations of a repeated or swept experiment, with the offered load and number of clients
+/// of each.
+fn iterations(args: &IperfArgs) -> Vec<Iteration<(u64, usize)>> {
+    // Every client count and offered load is repeated the configured number of times.
+    let loads = args
+        .throughput_sweep
+        .clone()
+        .unwrap_or_else(|| vec![args.total_throughput]);
+    let counts = if args.client_sweep {
+        let first = if args.network.no_monitor {
+            1
+        } else {
+            args.network.monitors.len().clamp(1, args.clients.len())
+        };
+        if first > 1 {
+            info!("Starting the client sweep at {first} clients, one for every monitor");
+        }
+        (first..=args.clients.len()).collect()
+    } else {
+        vec![args.clients.len()]
     };
+    let mut iterations = Vec::new();
+    for &count in &counts {
+        for &load in &loads {
+            for repetition in args.iterations.repetitions() {
+                let mut parts = Vec::new();
+                if args.client_sweep {
+                    parts.push(format!("clients-{count}"));
+                }
+                if args.throughput_sweep.is_some() {
+                    parts.push(format!("load-{}", format_bitrate(load)));
+                }
+                if args.iterations.repeat > 1 {
+                    parts.push(repetition.name);
+                }
+                iterations.push(Iteration {
+                    name: parts.join("/"),
+                    data: (load, count),
+                });
+            }
+        }
+    }
+    iterations
+}
 
-    let server_ip = {
-        debug!("Getting server ip");
-        let output = access_point
-            .session
-            .shell(format!(
-                "ip -4 a show {} | awk '/inet/ {{print $2}}' | cut -d/ -f1",
-                access_point_ifname
-            ))
-            .output()
+/// Print what the experiment would do, without running anything on the hosts.
+pub fn dry_run(args: IperfArgs, hosts: &Hosts, out_path: &Path) -> anyhow::Result<()> {
+    check(&args)?;
+    let mut out = format!("Output directory: {}\n", out_path.display());
+    if !args.is_repeated() {
+        let plan = RunPlan::new(
+            &args,
+            &Endpoints::lookup(&args, hosts)?,
+            &TrafficSchedule::new(&args, Duration::ZERO),
+        );
+        _ = writeln!(out, "{plan}");
+        println!("{out}");
+        return Ok(());
+    }
+
+    // Repetitions of the same configuration run the same commands, so they are only shown once.
+    let mut configurations: Vec<((u64, usize), Vec<String>)> = Vec::new();
+    for iteration in iterations(&args) {
+        match configurations
+            .iter_mut()
+            .find(|(c, _)| *c == iteration.data)
+        {
+            Some((_, names)) => names.push(iteration.name),
+            None => configurations.push((iteration.data, vec![iteration.name])),
+        }
+    }
+    for ((load, count), names) in configurations {
+        let mut args = args.clone();
+        args.total_throughput = load;
+        args.clients.truncate(count);
+        let plan = RunPlan::new(
+            &args,
+            &Endpoints::lookup(&args, hosts)?,
+            &TrafficSchedule::new(&args, Duration::ZERO),
+        );
+        _ = writeln!(out, "\nIn {}:\n{plan}", names.join(", "));
+    }
+    _ = writeln!(
+        out,
+        "\nThe status of every iteration is written to runs.ron"
+    );
+    println!("{out}");
+    Ok(())
+}
+
+/// Run the iperf experiment while pinging alongside the traffic, see [PingPlan].
+///
+/// Returns the parsed client results of every iteration, which are only available with `--json`.
+pub async fn run_with_pings(
+    args: IperfArgs,
+    hosts: &Hosts,
+    out_path: &Path,
+    pings: Option<&PingPlan>,
+    cancel: &CancellationToken,
+) -> anyhow::Result<Vec<BTreeMap<HostId, IperfResult>>> {
+    check(&args)?;
+    if !args.is_repeated() {
+        let output = run_once(&args, hosts, out_path, None, pings, cancel).await?;
+        return Ok(vec![output.results]);
+    }
+
+    let iterations = iterations(&args);
+
+    // The AIDs found in the first iteration are reused as long as the clients stay associated,
+    // together with the clients they were discovered for.
+    let aids = Arc::new(Mutex::new(None::<KnownAids>));
+    // How the iterations are repeated does not change their results, so more repetitions can be
+    // added when resuming.
+    let fingerprint = fingerprint(&IperfArgs {
+        iterations: IterationArgs::default(),
+        ..args.clone()
+    })?;
+    let mut outputs = Vec::new();
+    let statuses = run_iterations(
+        &args.iterations,
+        out_path,
+        &fingerprint,
+        iterations,
+        &mut outputs,
+        |iteration, run_path| {
+            let mut args = args.clone();
+            let (load, count) = iteration.data;
+            args.total_throughput = load;
+            args.clients.truncate(count);
+            let aids = aids.clone();
+            async move {
+                let known_aids = aids.lock().expect("lock poisoned").take();
+                let known_aids = match known_aids {
+                    Some((known, previous))
+                        if args.clients.starts_with(&previous)
+                            && args
+                                .network
+                                .clients_associated(hosts.get_many(&previous).into_iter().flatten())
+                                .await =>
+                    {
+                        // Clients added since then associate again, so the monitors only
+                        // discover the association IDs of those.
+                        if !args.network.no_monitor {
+                            for added in hosts
+                                .get_many(&args.clients[previous.len()..])
+                                .into_iter()
+                                .flatten()
+                            {
+                                added
+                                    .disconnect()
+                                    .await
+                                    .context("failed to disconnect added client")?;
+                            }
+                        }
+                        Some(known)
+                    }
+                    _ => None,
+                };
+
+                let output = run_once(&args, hosts, &run_path, known_aids, pings, cancel).await?;
+                *aids.lock().expect("lock poisoned") = Some((output.aids, args.clients.clone()));
+                Ok(IterationOutput {
+                    name: iteration.name,
+                    load,
+                    clients: count,
+                    results: output.results,
+                })
+            }
+        },
+    )
+    .await?;
+
+    if args.throughput_sweep.is_some() {
+        if !args.json {
+            warn!("Per-client results in sweep.csv require --json");
+        }
+        let csv = sweep_csv(&outputs);
+        tokio::fs::write(out_path.join("sweep.csv"), csv)
             .await
-            .context("failed to get IP address of server")?;
-        if !output.status.success() {
-            anyhow::bail!(
-                "failed to get IP address of server: returned with exit code {}",
-                output.status
-            );
+            .context("failed to write sweep results")?;
+    }
+    if args.client_sweep {
+        if !args.json {
+            warn!("Results in client-sweep.csv require --json");
+        }
+        let csv = client_sweep_csv(&outputs);
+        tokio::fs::write(out_path.join("client-sweep.csv"), csv)
+            .await
+            .context("failed to write client sweep results")?;
+    }
+
+    let failed = statuses.iter().filter(|s| !s.status.is_completed()).count();
+    if failed > 0 {
+        anyhow::bail!("{failed} of {} iterations failed", statuses.len());
+    }
+    Ok(outputs.into_iter().map(|o| o.results).collect())
+}
+
+/// The association IDs discovered in an earlier iteration and the clients they belong to.
+type KnownAids = (Vec<Aid>, Vec<HostId>);
+
+/// The parsed results of a single iteration of a sweep or repetition.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct IterationOutput {
+    name: String,
+    /// The total offered load in bits per second.
+    load: u64,
+    /// The number of clients that were used.
+    clients: usize,
+    results: BTreeMap<HostId, IperfResult>,
+}
+
+/// Combine the parsed results of the iterations of a sweep into a CSV table with one row per
+/// client per iteration.
+fn sweep_csv(outputs: &[IterationOutput]) -> String {
+    let mut csv = "iteration,load,client,direction,goodput,lost_percent,retransmits\n".to_string();
+    for IterationOutput {
+        name: iteration,
+        load,
+        results,
+        ..
+    } in outputs
+    {
+        for (client, direction) in results
+            .iter()
+            .flat_map(|(client, result)| result.directions.iter().map(move |d| (client, d)))
+        {
+            let summary = direction.summary.as_ref();
+            let field = |v: Option<String>| v.unwrap_or_default();
+            csv.push_str(&format!(
+                "{iteration},{load},{client},{:?},{},{},{}\n",
+                direction.direction,
+                field(summary.map(|s| format!("{:.0}", s.bits_per_second))),
+                field(
+                    summary
+                        .and_then(|s| s.lost_percent)
+                        .map(|v| format!("{v:.3}"))
+                ),
+                field(summary.and_then(|s| s.retransmits).map(|v| v.to_string())),
+            ));
+        }
+    }
+    csv
+}
+
+/// Combine the parsed results of a client sweep into a CSV table with the goodput of every client
+/// and the total of every direction per iteration. The totals use `total` as the client.
+fn client_sweep_csv(outputs: &[IterationOutput]) -> String {
+    let mut csv = "clients,iteration,client,direction,goodput\n".to_string();
+    for output in outputs {
+        let mut totals: BTreeMap<String, f64> = BTreeMap::new();
+        for (client, result) in &output.results {
+            for direction in &result.directions {
+                let Some(summary) = &direction.summary else {
+                    continue;
+                };
+                let name = format!("{:?}", direction.direction);
+                *totals.entry(name.clone()).or_default() += summary.bits_per_second;
+                csv.push_str(&format!(
+                    "{},{},{client},{name},{:.0}\n",
+                    output.clients, output.name, summary.bits_per_second
+                ));
+            }
+        }
+        for (direction, total) in totals {
+            csv.push_str(&format!(
+                "{},{},total,{direction},{total:.0}\n",
+                output.clients, output.name
+            ));
+        }
+    }
+    csv
+}
+
+/// Run a second of unlimited traffic from every client in the direction of the experiment, and
+/// discard the results.
+async fn prime(
+    args: &IperfArgs,
+    server: &Arc<Host>,
+    server_ifname: Option<&str>,
+    server_ip: &str,
+    senders: &[&Arc<Host>],
+    ports: Range<u16>,
+) -> anyhow::Result<()> {
+    let mut prime_args = args.clone();
+    prime_args.duration = 1;
+    prime_args.omit = 0;
+    prime_args.json = false;
+
+    let servers = spawn_servers(server, server_ifname, server_ip, ports.clone(), true).await?;
+    let mut client_ports = ports.clone();
+    let clients = run_all(senders.iter().copied(), |h| {
+        client_command(
+            &prime_args,
+            &h.extra_data,
+            server_ip,
+            client_ports
+                .next()
+                .expect("there is a port for every client"),
+            prime_args.client_is_udp(&h.id),
+            0,
+            None,
+        )
+    })
+    .await;
+    if let Err(err) = clients {
+        stop_all(servers).await;
+        return Err(err);
+    }
+    // The servers exit by themselves after their single test, those that do not are stopped.
+    let exit_timeout = server_exit_timeout(Duration::from_secs(prime_args.duration));
+    let deadline = Instant::now() + exit_timeout;
+    let mut stuck = 0;
+    for daemon in servers {
+        let limit = deadline.saturating_duration_since(Instant::now());
+        match daemon.wait_timeout(limit).await {
+            Some(result) => _ = result?,
+            None => stuck += 1,
         }
+    }
+    if stuck > 0 {
+        anyhow::bail!("{stuck} iperf servers did not exit within {exit_timeout:?}");
+    }
+    Ok(())
+}
 
-        let s = String::from_utf8_lossy(&output.stdout).trim().to_string();
-        if s.is_empty() {
-            anyhow::bail!("failed to get IP address of server: empty output");
+/// Verify that the access point transmits with an MCS allowed by `mask`.
+async fn check_bitrates(
+    access_point: &Host,
+    mask: Option<&str>,
+    previous: Option<String>,
+) -> BitrateCheck {
+    // SAFETY: The interface name was needed to set the MCS.
+    let ifname = access_point
+        .extra_data
+        .interface_name()
+        .expect("access point has an interface name");
+
+    let (stations, error) = match wifi::station_bitrates(access_point, ifname).await {
+        Ok(stations) => {
+            let error = verify_mcs(mask, &stations).err();
+            (stations, error)
         }
-        debug!("Found server ip: {s}");
-        s
+        Err(err) => (Vec::new(), Some(format!("{err:#}"))),
     };
+    if let Some(station) = stations.first() {
+        info!("Access point transmits at {}", station.tx_bitrate);
+    }
+
+    BitrateCheck {
+        requested: mask.map(str::to_string),
+        previous,
+        stations,
+        error,
+    }
+}
+
+/// Check that all stations use an MCS allowed by the bitrate mask. Masks that do not restrict the
+/// MCS cannot be verified and are always accepted.
+fn verify_mcs(mask: Option<&str>, stations: &[StationBitrate]) -> Result<(), String> {
+    let Some(allowed) = mask.and_then(wifi::mask_mcs) else {
+        return Ok(());
+    };
+    if stations.is_empty() {
+        return Err("no stations are associated with the access point".to_string());
+    }
+    for station in stations {
+        match station.mcs {
+            Some(mcs) if allowed.contains(&mcs) => {}
+            Some(mcs) => {
+                return Err(format!(
+                    "station {} uses MCS {mcs}, expected one of {allowed:?}",
+                    station.station
+                ))
+            }
+            None => {
+                return Err(format!(
+                    "station {} uses legacy bitrate {}",
+                    station.station, station.tx_bitrate
+                ))
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Save the output of a failed attempt of a client that is retried to `<host>.attempt-<n>.txt`.
+async fn save_failed_attempt(
+    out_path: &Path,
+    host: &str,
+    attempt: usize,
+    output: &Output,
+) -> anyhow::Result<()> {
+    let mut contents = output.stdout.clone();
+    contents.extend_from_slice(&output.stderr);
+    tokio::fs::write(
+        host_file(out_path, host, &format!("attempt-{attempt}.txt")),
+        contents,
+    )
+    .await
+    .context("failed to save output of failed attempt")
+}
+
+/// The output of a single run of the experiment.
+struct RunOutput {
+    /// The association IDs that were assigned to the monitors, so later runs can reuse them.
+    aids: Vec<Aid>,
+    /// The parsed client results, if JSON output was enabled.
+    results: BTreeMap<HostId, IperfResult>,
+}
+
+/// When the traffic of a run is sent, which determines the window the monitors capture around.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TrafficSchedule {
+    /// How long after the first client every client starts.
+    pub client_offsets: Vec<Duration>,
+    /// How long every client sends, including the warm-up left out of the results.
+    pub traffic: Duration,
+    /// How much later the last client may finish when clients are retried.
+    pub retry_margin: Duration,
+    /// The idle time measured before and after the traffic.
+    pub baseline: Duration,
+}
+
+impl TrafficSchedule {
+    /// The schedule of a run, with `baseline` the idle time before and after the load.
+    pub fn new(args: &IperfArgs, baseline: Duration) -> Self {
+        TrafficSchedule {
+            // The clients are started together.
+            client_offsets: vec![Duration::ZERO; args.clients.len()],
+            traffic: Duration::from_secs(args.duration + args.iperf_omit()),
+            // A client is retried within the retry window of its start, and restarting its server
+            // takes about as long again.
+            retry_margin: CLIENT_RETRY_WINDOW * 2 * args.client_retries,
+            baseline,
+        }
+    }
+
+    /// How long the measured traffic lasts, from the start of the idle baseline until the
+    /// latest client may finish and the idle baseline after it.
+    pub fn window(&self) -> Duration {
+        let last_start = self
+            .client_offsets
+            .iter()
+            .max()
+            .copied()
+            .unwrap_or_default();
+        self.baseline * 2 + last_start + self.traffic + self.retry_margin
+    }
+}
+
+/// Run the experiment a single time, writing the results to `out_path`.
+async fn run_once(
+    args: &IperfArgs,
+    hosts: &Hosts,
+    out_path: &Path,
+    known_aids: Option<Vec<Aid>>,
+    pings: Option<&PingPlan>,
+    cancel: &CancellationToken,
+) -> anyhow::Result<RunOutput> {
+    let mut mcs = None;
+    let result = run_pinned(args, hosts, out_path, known_aids, pings, cancel, &mut mcs).await;
+    // The access point is not left pinned to the MCS of the run, whichever way the run ended.
+    if let Some(mcs) = mcs {
+        if let Err(err) = mcs.restore().await {
+            warn!("Could not restore the MCS on the access point: {err:?}");
+        }
+    }
+    result
+}
+
+/// Run the experiment, keeping the MCS that is set on the access point in `mcs`.
+async fn run_pinned(
+    args: &IperfArgs,
+    hosts: &Hosts,
+    out_path: &Path,
+    known_aids: Option<Vec<Aid>>,
+    pings: Option<&PingPlan>,
+    cancel: &CancellationToken,
+    mcs: &mut Option<PinnedMcs>,
+) -> anyhow::Result<RunOutput> {
+    cancel.check()?;
+    progress::enter(Phase::Provisioning);
+    let provision = timing::phase(TimedPhase::Provision);
+
+    let endpoints = Endpoints::resolve(args, hosts).await?;
+    let baseline = pings.map_or(Duration::ZERO, |p| p.baseline);
+    let schedule = TrafficSchedule::new(args, baseline);
+    debug!(
+        ?schedule,
+        "Scheduled {:.1}s of traffic",
+        schedule.window().as_secs_f64()
+    );
+    let plan = RunPlan::new(args, &endpoints, &schedule);
+    let args_dump = {
+        let args = IperfArgs {
+            client_loads: plan
+                .clients
+                .iter()
+                .map(|(host, client)| (host.clone(), client.offered_load))
+                .collect(),
+            ..args.clone()
+        };
+        let config = PrettyConfig::new()
+            .depth_limit(2)
+            .separate_tuple_members(true)
+            .enumerate_arrays(true);
+        to_string_pretty(&args, config).context("failed to serialize args info")?
+    };
+    let senders: Vec<_> = endpoints.senders.iter().collect();
+    let access_point = endpoints.access_point.clone();
+    let server = endpoints.server.clone();
+    let server_ifname = endpoints.server_ifname().map(str::to_string);
+    let server_ip = endpoints.server_ip.clone();
+    let ports = endpoints.ports.clone();
 
     tokio::fs::create_dir_all(&out_path)
         .await
-        .expect("could not create output folder");
+        .context("could not create output folder")?;
     // Write the arguments out in a file so they can be found later.
     tokio::fs::write(&out_path.join("arguments.ron"), &args_dump)
         .await
         .context("failed to save arguments")?;
 
-    // Configure the MCS on the access point.
-    // TODO: maybe make more general and also fix that this actually happens on the AP.
-    if let Some(mcs) = args.mcs {
-        debug!("Setting MCS");
-        let output = access_point
-            .session
-            .shell(format!(
-                "iw dev phy1-ap0 set bitrates {}",
-                if &mcs.to_lowercase() == "auto" {
-                    ""
-                } else {
-                    &mcs
-                }
-            ))
-            .output()
+    let bssid = args.network.check_access_point(&access_point).await?;
+    *mcs = set_mcs(args, &access_point).await?;
+
+    let participants = endpoints.participants();
+    if args.no_precleanup {
+        debug!("Skipping cleanup of stale iperf processes");
+    } else {
+        kill_stale_iperfs(&participants, ports.clone())
             .await
-            .context("failed to set MCS")?;
+            .context("failed to clean up stale iperf processes")?;
+    }
 
-        if !output.status.success() {
-            debug!(
-                stdout = %String::from_utf8_lossy(&output.stdout),
-                stderr = %String::from_utf8_lossy(&output.stderr),
-                "Failed to set MCS"
-            );
-            anyhow::bail!("setting MCS exited with error code {}", output.status);
+    // Give rate control time to adapt to the configuration of the access point.
+    let settle_start = Instant::now();
+    if args.prime {
+        info!("Priming rate control");
+        if let Err(err) = prime(
+            args,
+            &server,
+            server_ifname.as_deref(),
+            &server_ip,
+            &senders,
+            ports.clone(),
+        )
+        .await
+        {
+            warn!("Could not prime rate control: {err:?}");
+            kill_stale_iperfs(&participants, ports.clone())
+                .await
+                .context("failed to clean up iperf processes used for priming")?;
         }
     }
+    let remaining = Duration::from_secs(args.settle).saturating_sub(settle_start.elapsed());
+    if !remaining.is_zero() {
+        debug!("Settling for {:.1}s", remaining.as_secs_f64());
+        sleep(remaining).await;
+    }
+
+    let (ping_hosts, ping_target) = match pings {
+        Some(plan) => {
+            let ping_hosts: Vec<_> = hosts
+                .get_many(&plan.hosts)
+                .map_err(|missing| anyhow!("no host with id {missing}"))?
+                .cloned()
+                .collect();
+            let target = match &plan.target {
+                Some(target) => target.clone(),
+                None => access_point
+                    .ip_address()
+                    .await
+                    .context("failed to get IP address of access point")?,
+            };
+            (ping_hosts, Some(target))
+        }
+        None => (Vec::new(), None),
+    };
+
+    provision.end();
 
     // Configure and start the monitoring.
-    let monitor = MonitorConfig {
-        ssid: args.ssid,
-        bssid: args.bssid,
-        monitors: args.monitors.clone(),
-        targets: senders.iter().map(|v| v.id.clone()).collect(),
-        // Give some extra leeway to ensure the monitor captures everything.
-        duration: Duration::from_secs(args.duration + 4),
-        output_path: Some(out_path.to_owned()),
-        // TODO: how can this be automated in OpenWRT?
-        frequency: args.frequency,
-        bandwidth: args.bandwidth,
-        set_aids: true,
-    }
-    .start(&hosts)
-    .await
-    .context("failed to start capture")?;
-
-    let mut start_port = 5000;
-    let iperf_client_num = senders.len();
-
-    // Start the iperf servers on the access point.
-    let access_point_ifname2 = access_point_ifname.clone();
-    let aps = tokio::spawn(async move {
-        info!("Starting iperf servers");
-        let mut n = start_port;
-        run_all(vec![&access_point; iperf_client_num], |_| {
-            n += 1;
-            format!("iperf3 -s --bind-dev {access_point_ifname2} -p {n} -1")
-        })
-        .await
-        .unwrap();
-    });
+    let mut targets = senders.clone();
+    for host in &ping_hosts {
+        if !targets.iter().any(|t| t.id == host.id) {
+            targets.push(host);
+        }
+    }
+    let monitor = args
+        .network
+        .start(
+            hosts,
+            &targets,
+            bssid,
+            schedule.window(),
+            out_path,
+            known_aids,
+        )
+        .await?;
 
-    // Ensure all iperf servers have been started before starting the clients. This is slightly
-    // hackty but the simplest way.
-    sleep(Duration::from_secs(1)).await;
+    // Start the iperf servers, which must be listening before the clients start.
+    let provision = timing::phase(TimedPhase::Provision);
+    let servers = endpoints.spawn_servers().await;
+    provision.end();
+    let servers = match servers {
+        Ok(servers) => servers,
+        Err(err) => {
+            if let Some(monitor) = monitor {
+                if let Err(err) = monitor
+                    .stop_and_collect("iperf servers did not start")
+                    .await
+                {
+                    warn!("Could not collect the captures: {err:?}");
+                }
+            }
+            return Err(err);
+        }
+    };
 
     // Run iperf clients on each NUC.
     info!("Starting iperf clients");
-    let mut ip_num = 0;
-    let iperfs = run_all(senders.clone(), |h| {
-        if h.extra_data.interface.is_none() {
+    if monitor.is_none() {
+        // With a monitor the capture covers the traffic, and is reported by the monitor.
+        let duration = Duration::from_secs(args.duration + args.iperf_omit());
+        progress::enter(Phase::Running(duration));
+    }
+    for h in &senders {
+        if h.extra_data.interface_name().is_none() && h.extra_data.interface_ip().is_none() {
             warn!(
                 host = h.id,
                 "Host does not have an interface set in the hosts file"
             );
         }
+    }
+    let mut records: BTreeMap<_, _> = plan
+        .clients
+        .into_iter()
+        .map(|(host, client)| {
+            let record = ClientRecord::new(client.port, client.offered_load, client.command);
+            (host, record)
+        })
+        .collect();
+    // From here on a file that can not be written does not stop the run, so the captures are
+    // still collected. What could not be saved is reported when the run ends.
+    let mut status = RunStatus::new();
+    status.record(write_clients(out_path, &records).await);
 
-        start_port += 1;
-        let s = format!(
-            "iperf3 -c {server_ip} -p {start_port} {0} -b {1} {2} {3}",
-            // 0 - Bind interface
-            h.extra_data
-                .interface
-                .as_ref()
-                .map(|ifname| format!("--bind-dev {ifname}"))
-                .unwrap_or_else(|| "".to_string()),
-            // 1 - Bandwidth
-            total_bandwidth / senders.len() as u64,
-            // 2 - Use UDP or not
-            if udp { "-u" } else { "" },
-            // 3 - Which direction to test
-            match args.direction {
-                Direction::Uplink => "",
-                Direction::Downlink => "-R",
-                Direction::Bidir => "--bidir",
-            },
+    // Start pinging before the load, so the idle round trip time is measured as well.
+    let ping_task = ping_target.map(|target| {
+        // SAFETY: There is only a target if there is a plan.
+        let plan = pings.expect("pings are planned");
+        let duration = (baseline * 2).as_secs() + args.duration + args.iperf_omit();
+        let interval = plan.interval;
+        let ping_hosts = ping_hosts.clone();
+        tokio::spawn(async move {
+            info!("Starting pings");
+            run_all(ping_hosts.iter(), |h| {
+                ping_command(&target, interval, duration, h.extra_data.interface_name())
+            })
+            .await
+        })
+    });
+    if ping_task.is_some() {
+        debug!("Measuring idle latency for {baseline:?}");
+        sleep(baseline).await;
+    }
+
+    let rc_trace = match args.rc_trace {
+        Some(traced) => {
+            let mut traced_hosts = Vec::new();
+            if matches!(traced, RcTraceHosts::Ap | RcTraceHosts::All) {
+                traced_hosts.push(access_point.clone());
+            }
+            if matches!(traced, RcTraceHosts::Clients | RcTraceHosts::All) {
+                traced_hosts.extend(senders.iter().map(|&h| h.clone()));
+            }
+            let period = Duration::from_secs_f64(args.rc_trace_interval);
+            status.record(
+                RcTrace::start(&traced_hosts, period, out_path)
+                    .await
+                    .context("failed to start the rate control trace"),
+            )
+        }
+        None => None,
+    };
+    let link_sampler = args
+        .sample_links
+        .map(|period| LinkSampler::start(&endpoints.senders, Duration::from_secs_f64(period)));
+
+    let load_start = SystemTime::now();
+    let live_output = args.live_output();
+    let on_line = (live_output || metrics::enabled()).then(|| {
+        Arc::new(move |host: &Host, kind: Line, line: &str| {
+            if live_output {
+                info!(host = host.id, "{line}");
+            }
+            if let Some((_, bitrate)) = parse_interval_line(line).filter(|_| kind == Line::Stdout) {
+                metrics::set_throughput(&host.id, bitrate);
+            }
+        }) as LineHandler
+    });
+    let traffic = timing::phase(TimedPhase::Traffic);
+    let mut clients = JoinSet::new();
+    let mut attempt_starts = HashMap::new();
+    // The traffic of a client lasts until its last attempt finishes.
+    let mut client_phases = HashMap::new();
+    for h in &senders {
+        let record = records.get_mut(&h.id).expect("every client has a record");
+        record.started();
+        attempt_starts.insert(h.id.clone(), Instant::now());
+        client_phases.insert(h.id.clone(), timing::host_phase(TimedPhase::Traffic, &h.id));
+        spawn_one(
+            &mut clients,
+            (*h).clone(),
+            record.command.clone(),
+            on_line.clone(),
         );
-        ip_num += 1;
-        s
-    })
-    .await
-    .unwrap();
+    }
+
+    // Process the clients as they complete, so a failing client can be acted upon immediately.
+    let mut iperfs = Vec::with_capacity(senders.len());
+    let mut client_failures = Vec::new();
+    let mut retry_servers = Vec::new();
+    let mut aborted = false;
+    // An error while the clients run stops the run like a failed client, so the processes that
+    // are still running are cleaned up.
+    let mut client_error = None;
+    loop {
+        let joined = select! {
+            joined = clients.join_next() => joined,
+            () = cancel.cancelled() => {
+                aborted = true;
+                break;
+            }
+        };
+        let Some(joined) = joined else {
+            break;
+        };
+        let joined = joined
+            .context("iperf client task failed")
+            .and_then(|(host, output)| {
+                let output = output
+                    .with_context(|| format!("failed to run iperf client on `{}`", host.id))?;
+                Ok((host, output))
+            });
+        let (host, output) = match joined {
+            Ok(joined) => joined,
+            Err(err) => {
+                client_error = Some(err);
+                break;
+            }
+        };
+        let record = records
+            .get_mut(&host.id)
+            .expect("every client has a record");
+        record.finished(output.status);
+
+        let elapsed = attempt_starts[&host.id].elapsed();
+        let can_retry = record.failed_attempts.len() < args.client_retries as usize
+            && elapsed < CLIENT_RETRY_WINDOW;
+        if !output.status.success() && can_retry {
+            let attempt = record.failed_attempts.len() + 1;
+            warn!(
+                host = host.id,
+                "Iperf failed after {elapsed:.1?}, retrying ({attempt}/{})", args.client_retries
+            );
+            if let Err(err) = save_failed_attempt(out_path, &host.id, attempt, &output).await {
+                client_error = Some(err);
+                break;
+            }
+
+            match ensure_server(&server, server_ifname.as_deref(), &server_ip, record.port).await {
+                Ok(restarted) => {
+                    retry_servers.extend(restarted);
+                    record.retry();
+                    attempt_starts.insert(host.id.clone(), Instant::now());
+                    spawn_one(
+                        &mut clients,
+                        host.clone(),
+                        record.command.clone(),
+                        on_line.clone(),
+                    );
+                    continue;
+                }
+                Err(err) => error!(host = host.id, "Could not retry the client: {err:?}"),
+            }
+        }
+
+        client_phases.remove(&host.id);
+        if !output.status.success() {
+            error!(host = host.id, "Iperf failed");
+            client_failures.push(format!(
+                "iperf client on `{}` exited with {}",
+                host.id, output.status
+            ));
+        }
+        iperfs.push((host, output));
+
+        if args.stop_on_client_failure() && !client_failures.is_empty() {
+            break;
+        }
+    }
+    drop(client_phases);
+    traffic.end();
+    if let Some(trace) = rc_trace {
+        trace.stop().await;
+    }
+    let links = match link_sampler {
+        Some(sampler) => sampler.stop().await,
+        None => BTreeMap::new(),
+    };
+    status.record(write_clients(out_path, &records).await);
+    let load_end = SystemTime::now();
 
     // Write all the iperf outputs to files.
+    let mut results = BTreeMap::new();
     for (host, iperf) in iperfs.into_iter() {
-        if !iperf.status.success() {
-            error!(host = host.id, "Iperf failed");
+        status.client(&host.id, save_output(out_path, &host.id, &iperf).await);
+
+        if args.json {
+            match parse_json(&iperf.stdout, Duration::from_secs(args.omit)) {
+                Ok(result) => {
+                    for direction in &result.directions {
+                        if let Some(summary) = &direction.summary {
+                            info!(
+                                host = host.id,
+                                "Measured {:?} {:.2} Mbit/s over {:.1}s",
+                                direction.direction,
+                                summary.bits_per_second / 1_000_000.0,
+                                summary.seconds
+                            );
+                        }
+                    }
+                    results.insert(host.id.clone(), result);
+                }
+                Err(err) => error!(host = host.id, "Could not parse iperf output: {err:?}"),
+            }
         }
+    }
 
-        let mut f = File::create_new(out_path.join(&format!("{}.txt", host.id)))
-            .await
-            .unwrap();
-        f.write_all(&iperf.stdout).await.unwrap();
+    if args.json {
+        status.record(write_artifact(out_path, &IperfResults::new(results.clone())).await);
+    }
 
-        // Also write error output if it exists.
-        if !iperf.stderr.is_empty() {
-            let mut f = File::create_new(out_path.join(&format!("{}.stderr.txt", host.id)))
-                .await
-                .unwrap();
-            f.write_all(&iperf.stderr).await.unwrap();
+    let bitrates = match (args.mcs_mask(), mcs.as_ref()) {
+        (Some(mask), Some(pinned)) => {
+            let previous = pinned.previous().map(str::to_string);
+            Some(check_bitrates(&access_point, mask, previous).await)
+        }
+        _ => None,
+    };
+
+    let abort_reason = cancel.reason().unwrap_or(Reason::User);
+    let stop_reason = if aborted {
+        warn!("Stopping the run because it was aborted");
+        Some(abort_reason.to_string())
+    } else if let Some(err) = &client_error {
+        error!("Stopping the run because of an error: {err:#}");
+        Some(format!("{err:#}"))
+    } else if args.stop_on_client_failure() && !client_failures.is_empty() {
+        error!("Stopping the run because a client failed");
+        Some(client_failures.join("; "))
+    } else {
+        None
+    };
+    if let Some(reason) = stop_reason {
+        // Stop everything that is still running, keeping whatever was captured so far. Only the
+        // servers this run started are stopped, the clients exit once their server is gone.
+        clients.abort_all();
+        stop_all(servers.into_iter().chain(retry_servers)).await;
+        if let Some(task) = &ping_task {
+            task.abort();
+        }
+        if let Some(monitor) = monitor {
+            let collected = monitor.stop_and_collect(reason.clone()).await;
+            if let Err(err) = &collected {
+                warn!("Could not collect the captures: {err:?}");
+            }
+            status.captures(collected);
         }
+
+        status.failures.push(reason);
+        return Err(fail_run(out_path, &status, aborted.then_some(abort_reason)).await);
     }
 
-    info!("Waiting for capture to finish");
-    monitor.wait().await.expect("monitor task crashed");
+    // The traffic is done, what remains is collecting and writing the results.
+    let _finishing = cancel.finishing();
+    let aids = monitor
+        .as_ref()
+        .map(|m| m.aids().to_vec())
+        .unwrap_or_default();
+    let mut outcome = Outcome::of_clients(client_failures, args.tolerate_client_failures);
 
-    debug!("Waiting for AP to finish");
-    select! {
-        _ = tokio::time::sleep(Duration::from_secs(1)) => {
-            // Close the remaining iperf sessions.
-            _ = hosts
-                .get(&args.server)
-                .expect("access point was used earlier")
-                .session
-                .shell("killall iperf3")
-                .output()
-                .await;
+    let mut latency = None;
+    if let Some(task) = ping_task {
+        info!("Waiting for pings to finish");
+        match task.await {
+            Ok(Ok(outputs)) => {
+                let collected = status.record(collect_pings(out_path, outputs).await);
+                let (results, failures) = collected.unwrap_or_default();
+                status.record(write_latency(out_path, &results).await);
+                outcome.failures.extend(failures);
+                let (start, end) = (unix_time(load_start), unix_time(load_end));
+                latency = Some(
+                    results
+                        .iter()
+                        .map(|(host, result)| (host.clone(), loaded_rtt(result, start, end)))
+                        .collect(),
+                );
+            }
+            Ok(Err(err)) => outcome.failures.push(format!("ping failed: {err:#}")),
+            Err(err) => outcome.failures.push(format!("ping task failed: {err}")),
+        }
+    }
+    if let Some(error) = bitrates.as_ref().and_then(|b| b.error.as_ref()) {
+        if args.strict_params {
+            outcome
+                .failures
+                .push(format!("MCS verification failed: {error}"));
+        } else {
+            warn!("MCS verification failed: {error}");
+        }
+    }
 
-            anyhow::bail!("AP iperf servers did not close correctly; remaining sessions killed");
-        },
-        result = aps => {
-            _ = result.context("iperf on AP failed")?;
-        },
+    let monitor_output = match monitor {
+        Some(monitor) => {
+            info!("Waiting for capture to finish");
+            Some(monitor.wait().await)
+        }
+        None => None,
+    };
+    progress::enter(Phase::Collecting);
+    let _analysis = timing::phase(TimedPhase::Analysis);
+    let monitor_output = monitor_output.and_then(|output| {
+        if let Err(err) = &output {
+            error!("Monitor failed: {err:?}");
+            outcome.failures.push(format!("monitor failed: {err:#}"));
+        }
+        status.captures(output)
+    });
+    if let Some(output) = &monitor_output {
+        outcome.check_captures(&output.metadata);
+    }
+    let traffic_start = records.values().filter_map(|r| r.start).reduce(f64::min);
+    let traffic_end = records.values().filter_map(|r| r.end).reduce(f64::max);
+    if let Some(output) = monitor_output.as_ref().filter(|_| args.trim_captures) {
+        match traffic_start.zip(traffic_end) {
+            Some((start, end)) => {
+                let mut captures = Vec::new();
+                for (host, _) in &output.captures {
+                    let offset = output.metadata.clock_offset(hosts, host).await;
+                    captures.push((host.clone(), offset));
+                }
+                trim::trim_captures(out_path, &captures, start, end, args.trim_in_place).await;
+            }
+            None => warn!("No client ran, not trimming the captures"),
+        }
+    }
+    if let Some(output) = monitor_output.as_ref().filter(|_| args.export_csv) {
+        let hosts: Vec<HostId> = output
+            .captures
+            .iter()
+            .map(|(host, _)| host.clone())
+            .collect();
+        csv::export_captures(out_path, &hosts).await;
     }
 
-    Ok(())
+    // The servers exit by themselves after their single test, those that do not are stopped.
+    let exit_timeout = server_exit_timeout(Duration::from_secs(args.duration + args.iperf_omit()));
+    debug!("Waiting up to {exit_timeout:?} for servers to finish");
+    let deadline = Instant::now() + exit_timeout;
+    let mut stuck = 0;
+    for daemon in servers {
+        let limit = deadline.saturating_duration_since(Instant::now());
+        match daemon.wait_timeout(limit).await {
+            Some(Ok(_)) => {}
+            Some(Err(err)) => outcome
+                .failures
+                .push(format!("iperf on server failed: {err:#}")),
+            None => stuck += 1,
+        }
+    }
+    if stuck > 0 {
+        error!("{stuck} iperf servers did not exit within {exit_timeout:?} and were stopped");
+        outcome
+            .failures
+            .push("iperf servers did not close correctly".to_string());
+    }
+    // Servers restarted for retried clients served their test already or are no longer needed.
+    stop_all(retry_servers).await;
+
+    let goodput_check = match monitor_output.as_ref().filter(|_| args.analyze) {
+        Some(output) => Some(
+            check_goodput(
+                &endpoints.senders,
+                hosts,
+                &results,
+                &records,
+                &output.metadata,
+                out_path,
+                args.analyze_tolerance / 100.0,
+            )
+            .await,
+        ),
+        None => None,
+    };
+
+    if args.timeline {
+        let monitor = monitor_output.as_ref().map(|o| &o.metadata);
+        if let Err(err) = write_timeline(out_path, hosts, &results, &records, monitor, &links).await
+        {
+            warn!("Could not write the timeline: {err:#}");
+        }
+    }
+
+    let summary = summarize(SummaryInput {
+        offered_load: args.total_throughput,
+        groups: &args.groups,
+        packet_size: args.packet_size,
+        mss: args.mss,
+        results: &results,
+        clients: &records,
+        monitor: monitor_output.as_ref().map(|o| &o.metadata),
+        bitrates,
+        latency,
+        goodput_check,
+        outcome,
+    });
+    info!("Run summary:\n{}", summary.table());
+    status.record(write_artifact(out_path, &summary).await);
+
+    if !summary.outcome.is_success() || !status.is_complete() {
+        status.failures = summary.outcome.failures;
+        return Err(fail_run(out_path, &status, aborted.then_some(abort_reason)).await);
+    }
+
+    Ok(RunOutput { aids, results })
+}
+
+/// Write what a failed run saved to `status.ron` and mark its output directory as failed, or as
+/// aborted if it was cancelled. Returns the error the run ends with.
+async fn fail_run(out_path: &Path, status: &RunStatus, aborted: Option<Reason>) -> anyhow::Error {
+    if let Err(err) = write_artifact(out_path, status).await {
+        error!("Could not save the status of the run: {err:#}");
+    }
+    if let Some(reason) = aborted {
+        return match mark_aborted(out_path, reason).await {
+            Ok(()) => Aborted.into(),
+            Err(err) => err,
+        };
+    }
+    let err = status.error();
+    match mark_failed(out_path, &err.to_string()).await {
+        Ok(()) => err,
+        Err(mark_err) => mark_err,
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    #[test]
+    fn server_exit_timeout_grows_with_the_test() {
+        assert_eq!(server_exit_timeout(Duration::ZERO), SERVER_EXIT_MIN);
+        assert_eq!(
+            server_exit_timeout(Duration::from_secs(10)),
+            SERVER_EXIT_MIN
+        );
+        assert_eq!(
+            server_exit_timeout(Duration::from_secs(50)),
+            SERVER_EXIT_MIN
+        );
+        assert_eq!(
+            server_exit_timeout(Duration::from_secs(120)),
+            Duration::from_secs(12)
+        );
+        assert_eq!(
+            server_exit_timeout(Duration::from_secs(3600)),
+            Duration::from_secs(360)
+        );
+    }
+
+    #[test]
+    fn server_command_binds_to_the_interface() {
+        let command = server_command(Some("wlan0"), "10.0.0.1", 5001, true);
+        assert_eq!(command.to_string(), "iperf3 -s --bind-dev wlan0 -p 5001 -1");
+    }
+
+    #[test]
+    fn server_command_binds_to_the_address() {
+        let command = server_command(None, "10.0.0.1", 5002, true);
+        assert_eq!(command.to_string(), "iperf3 -s -B 10.0.0.1 -p 5002 -1");
+    }
+
+    #[test]
+    fn persistent_server_command_handles_every_test() {
+        let command = server_command(None, "10.0.0.1", 5003, false);
+        assert_eq!(command.to_string(), "iperf3 -s -B 10.0.0.1 -p 5003");
+        assert_eq!(command.exec_line(), command.to_string());
+    }
+
+    #[test]
+    fn server_ports_start_at_the_first_port() {
+        assert_eq!(server_ports(3), 5001..5004);
+        assert!(server_ports(0).is_empty());
+    }
+
+    /// Whether `pattern` matches `command_line` the way `pgrep -f` matches, as an extended
+    /// regular expression anywhere in the command line.
+    fn matches(pattern: &str, command_line: &str) -> bool {
+        std::process::Command::new("sh")
+            .args(["-c", r#"printf '%s\n' "$2" | grep -qE "$1""#, "sh"])
+            .args([pattern, command_line])
+            .status()
+            .unwrap()
+            .success()
+    }
+
+    #[test]
+    fn stale_iperf_pattern_is_scoped_to_the_ports() {
+        let pattern = stale_iperf_pattern(5001..5003);
+        assert_eq!(pattern, "iperf3 -[sc] .*-p (5001|5002)( |$)");
+
+        assert!(matches(&pattern, "iperf3 -s -B 10.0.0.1 -p 5001 -1"));
+        assert!(matches(&pattern, "iperf3 -s --bind-dev wlan0 -p 5002"));
+        assert!(matches(
+            &pattern,
+            "iperf3 -c 10.0.0.1 -p 5002 -t 10 -i 1 --json"
+        ));
+        // Other ports, including those that start with one of the ports.
+        assert!(!matches(&pattern, "iperf3 -s -B 10.0.0.1 -p 5003"));
+        assert!(!matches(&pattern, "iperf3 -s -B 10.0.0.1 -p 50010"));
+        assert!(!matches(&pattern, "iperf3 -c 10.0.0.1 -p 15001 -t 10"));
+        // The shell running the kill, and iperf 2.
+        assert!(!matches(
+            &pattern,
+            &format!("sh -c pgrep -af '{pattern}' && pkill -f '{pattern}' || true")
+        ));
+        assert!(!matches(&pattern, "iperf -s -u -B 239.0.0.1 -p 5001"));
+    }
+
+    #[test]
+    fn load_is_split_equally() {
+        assert_eq!(split_load(300_000_000, 3), [100_000_000; 3]);
+        assert_eq!(split_load(100_000_000, 1), [100_000_000]);
+    }
+
+    #[test]
+    fn load_remainder_goes_to_the_first_clients() {
+        assert_eq!(split_load(10, 4), [3, 3, 2, 2]);
+        assert_eq!(split_load(100_000_001, 2), [50_000_001, 50_000_000]);
+        for (total, clients) in [(1_000_003, 7), (5, 8), (u64::MAX, 3)] {
+            let loads = split_load(total, clients);
+            assert_eq!(loads.len(), clients);
+            assert_eq!(loads.iter().sum::<u64>(), total);
+            assert!(loads.first().unwrap() - loads.last().unwrap() <= 1);
+        }
+    }
+
+    #[test]
+    fn load_smaller_than_the_clients() {
+        assert_eq!(split_load(2, 4), [1, 1, 0, 0]);
+        assert_eq!(split_load(0, 2), [0, 0]);
+    }
+
+    #[test]
+    fn load_without_clients() {
+        assert!(split_load(100_000_000, 0).is_empty());
+    }
+
+    pub(crate) fn iperf_args(args: &[&str]) -> IperfArgs {
+        let command = [
+            "iperf",
+            "--ap",
+            "ap",
+            "--clients",
+            "a,b,c",
+            "--ssid",
+            "net",
+            "--frequency",
+            "5180",
+            "--bandwidth",
+            "80",
+            "--no-monitor",
+        ];
+        IperfArgs::try_parse_from(command.iter().chain(args)).unwrap()
+    }
+
+    #[test]
+    fn schedule_of_simultaneous_clients() {
+        let args = iperf_args(&["--udp", "false", "--duration", "10", "--omit", "2"]);
+        let schedule = TrafficSchedule::new(&args, Duration::from_secs(3));
+        assert_eq!(schedule.client_offsets, [Duration::ZERO; 3]);
+        // TCP clients are extended by the warm-up.
+        assert_eq!(schedule.traffic, Duration::from_secs(12));
+        assert_eq!(schedule.retry_margin, Duration::ZERO);
+        assert_eq!(schedule.window(), Duration::from_secs(3 + 12 + 3));
+    }
+
+    #[test]
+    fn schedule_of_udp_clients_ignores_the_warm_up() {
+        let args = iperf_args(&["--udp", "true", "--throughput", "10M", "--omit", "2"]);
+        let schedule = TrafficSchedule::new(&args, Duration::ZERO);
+        assert_eq!(schedule.window(), Duration::from_secs(10));
+    }
+
+    #[test]
+    fn schedule_of_retried_clients() {
+        let args = iperf_args(&["--udp", "false", "--client-retries", "2"]);
+        let schedule = TrafficSchedule::new(&args, Duration::from_secs(1));
+        // Every retry may start a retry window later, plus the restart of the server.
+        assert_eq!(schedule.retry_margin, CLIENT_RETRY_WINDOW * 4);
+        assert_eq!(
+            schedule.window(),
+            Duration::from_secs(1 + 10 + 1) + CLIENT_RETRY_WINDOW * 4
+        );
+    }
+
+    #[test]
+    fn schedule_of_staggered_clients() {
+        let schedule = TrafficSchedule {
+            client_offsets: vec![
+                Duration::ZERO,
+                Duration::from_millis(2500),
+                Duration::from_secs(1),
+            ],
+            traffic: Duration::from_secs(10),
+            retry_margin: Duration::ZERO,
+            baseline: Duration::from_secs(2),
+        };
+        // The window lasts until the client that starts last is done.
+        assert_eq!(
+            schedule.window(),
+            Duration::from_millis(2000 + 2500 + 10_000 + 2000)
+        );
+
+        let retried = TrafficSchedule {
+            retry_margin: Duration::from_secs(5),
+            ..schedule
+        };
+        assert_eq!(
+            retried.window(),
+            Duration::from_millis(2000 + 2500 + 10_000 + 5000 + 2000)
+        );
+    }
+
+    #[test]
+    fn schedule_without_clients() {
+        let schedule = TrafficSchedule {
+            client_offsets: Vec::new(),
+            traffic: Duration::from_secs(10),
+            retry_margin: Duration::ZERO,
+            baseline: Duration::from_secs(2),
+        };
+        assert_eq!(schedule.window(), Duration::from_secs(14));
+    }
+
+    fn client_data(interface_name: Option<&str>, interface_ip: Option<&str>) -> ExtraData {
+        ExtraData {
+            wifi_driver: None,
+            interface_name: interface_name.map(String::from),
+            interface_ip: interface_ip.map(String::from),
+            interface: None,
+        }
+    }
+
+    fn command_line(args: &IperfArgs, udp: bool) -> String {
+        let client = client_data(Some("wlan0"), None);
+        client_command(args, &client, "10.0.0.1", 5201, udp, 1_000_000, None).to_string()
+    }
+
+    #[test]
+    fn udp_client_command_sets_the_packet_size() {
+        let args = iperf_args(&[
+            "--udp",
+            "true",
+            "--throughput",
+            "30M",
+            "--packet-size",
+            "200",
+            "--duration",
+            "10",
+        ]);
+        args.validate().unwrap();
+        assert_eq!(
+            command_line(&args, true),
+            "iperf3 -c 10.0.0.1 -p 5201 -t 10 --bind-dev wlan0 -b 1000000 -u -R -l 200 --forceflush"
+        );
+    }
+
+    #[test]
+    fn tcp_client_command_sets_the_mss() {
+        let args = iperf_args(&["--udp", "false", "--mss", "1400", "--duration", "10"]);
+        args.validate().unwrap();
+        assert_eq!(
+            command_line(&args, false),
+            "iperf3 -c 10.0.0.1 -p 5201 -t 10 --bind-dev wlan0 -b 1000000 -R -M 1400 --forceflush"
+        );
+    }
+
+    #[test]
+    fn client_command_without_sizes() {
+        let args = iperf_args(&[
+            "--udp",
+            "false",
+            "--duration",
+            "10",
+            "--omit",
+            "2",
+            "--json",
+        ]);
+        let client = client_data(None, Some("10.0.0.2/24"));
+        let command = client_command(&args, &client, "10.0.0.1", 5202, false, 0, None);
+        assert_eq!(
+            command.to_string(),
+            "iperf3 -c 10.0.0.1 -p 5202 -t 10 -B 10.0.0.2 -b 0 -R -O 2 -J"
+        );
+    }
+
+    #[test]
+    fn mixed_groups_get_the_size_of_their_protocol() {
+        let mut args = iperf_args(&["--udp", "false", "--packet-size", "1200", "--mss", "1000"]);
+        args.groups = vec![
+            TrafficGroup {
+                name: "tcp".to_string(),
+                clients: vec!["a".to_string(), "b".to_string()],
+                udp: false,
+                total_throughput: 0,
+            },
+            TrafficGroup {
+                name: "udp".to_string(),
+                clients: vec!["c".to_string()],
+                udp: true,
+                total_throughput: 10_000_000,
+            },
+        ];
+        args.validate().unwrap();
+        let udp = command_line(&args, true);
+        assert!(udp.contains(" -l 1200 "), "{udp}");
+        assert!(!udp.contains("-M"), "{udp}");
+        let tcp = command_line(&args, false);
+        assert!(tcp.contains(" -M 1000 "), "{tcp}");
+        assert!(!tcp.contains("-l"), "{tcp}");
+    }
+
+    #[test]
+    fn sizes_must_match_the_protocol() {
+        let packet_size = iperf_args(&["--udp", "false", "--packet-size", "1200"]);
+        let err = packet_size.validate().unwrap_err();
+        assert_eq!(err.to_string(), "--packet-size can only be used with UDP");
+
+        let mss = iperf_args(&["--udp", "true", "--throughput", "30M", "--mss", "1400"]);
+        let err = mss.validate().unwrap_err();
+        assert_eq!(err.to_string(), "--mss can only be used with TCP");
+    }
+
+    #[test]
+    fn sizes_must_be_in_range() {
+        for (size, valid) in [
+            ("15", false),
+            ("16", true),
+            ("65507", true),
+            ("65508", false),
+        ] {
+            let args = iperf_args(&[
+                "--udp",
+                "true",
+                "--throughput",
+                "30M",
+                "--packet-size",
+                size,
+            ]);
+            assert_eq!(args.validate().is_ok(), valid, "packet size {size}");
+        }
+        for (mss, valid) in [("87", false), ("88", true), ("9216", true), ("9217", false)] {
+            let args = iperf_args(&["--udp", "false", "--mss", mss]);
+            assert_eq!(args.validate().is_ok(), valid, "mss {mss}");
+        }
+    }
+
+    #[test]
+    fn rc_trace_interval_must_be_a_period() {
+        for (interval, valid) in [
+            ("0.5", true),
+            ("0", false),
+            ("-1", false),
+            ("NaN", false),
+            ("inf", false),
+            ("1e300", false),
+        ] {
+            let args = iperf_args(&[
+                "--udp",
+                "false",
+                "--rc-trace",
+                "ap",
+                &format!("--rc-trace-interval={interval}"),
+            ]);
+            assert_eq!(args.validate().is_ok(), valid, "{interval}");
+        }
+    }
+
+    #[test]
+    fn link_sample_period_must_be_a_period() {
+        for (period, valid) in [
+            ("2", true),
+            ("0", false),
+            ("-1", false),
+            ("NaN", false),
+            ("inf", false),
+        ] {
+            let args = iperf_args(&[
+                "--udp",
+                "false",
+                "--json",
+                "--timeline",
+                &format!("--sample-links={period}"),
+            ]);
+            assert_eq!(args.validate().is_ok(), valid, "{period}");
+        }
+    }
 }
@@ -0,0 +1,185 @@
+//! Bookkeeping of the iperf clients of a run, so captures can be matched to a client.
+
+use std::{collections::BTreeMap, path::Path, process::ExitStatus, time::SystemTime};
+
+use anyhow::Context;
+use ron::ser::{to_string_pretty, PrettyConfig};
+use serde::Serialize;
+
+use crate::{
+    hosts::HostId,
+    utils::{unix_time, RemoteCmd},
+};
+
+/// How a single iperf client was run, written to `clients.ron`.
+#[derive(Debug, Clone, Serialize)]
+pub struct ClientRecord {
+    /// The port of the server the client connected to.
+    pub port: u16,
+    /// The offered load of the client in bits per second, 0 if unlimited.
+    pub offered_load: u64,
+    /// The command line the client was started with.
+    pub command: RemoteCmd,
+    /// When the controller started the client, in seconds since the unix epoch.
+    pub start: Option<f64>,
+    /// When the client finished, in seconds since the unix epoch.
+    pub end: Option<f64>,
+    /// The exit code of the client, if it finished normally.
+    pub exit_status: Option<i32>,
+    /// Earlier attempts that failed and were retried. If there are any, the client measured a
+    /// later window than the clients that were not retried.
+    pub failed_attempts: Vec<Attempt>,
+}
+
+/// A single attempt of running an iperf client.
+#[derive(Debug, Clone, Serialize)]
+pub struct Attempt {
+    pub start: Option<f64>,
+    pub end: Option<f64>,
+    pub exit_status: Option<i32>,
+}
+
+impl ClientRecord {
+    pub fn new(port: u16, offered_load: u64, command: RemoteCmd) -> Self {
+        ClientRecord {
+            port,
+            offered_load,
+            command,
+            start: None,
+            end: None,
+            exit_status: None,
+            failed_attempts: Vec::new(),
+        }
+    }
+
+    /// Mark the client as started now.
+    pub fn started(&mut self) {
+        self.start = Some(unix_time(SystemTime::now()));
+    }
+
+    /// Mark the client as finished now with the given status.
+    pub fn finished(&mut self, status: ExitStatus) {
+        self.end = Some(unix_time(SystemTime::now()));
+        self.exit_status = status.code();
+    }
+
+    /// Record the current attempt as failed and mark the client as started again.
+    pub fn retry(&mut self) {
+        self.failed_attempts.push(Attempt {
+            start: self.start,
+            end: self.end.take(),
+            exit_status: self.exit_status.take(),
+        });
+        self.started();
+    }
+}
+
+/// Write the records of all clients to `clients.ron`, replacing an earlier version.
+pub async fn write_clients(
+    out_path: &Path,
+    clients: &BTreeMap<HostId, ClientRecord>,
+) -> anyhow::Result<()> {
+    let dump =
+        to_string_pretty(clients, PrettyConfig::new()).context("failed to serialize clients")?;
+    tokio::fs::write(out_path.join("clients.ron"), dump)
+        .await
+        .context("failed to save clients")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::process::ExitStatusExt;
+
+    use super::*;
+
+    fn record() -> ClientRecord {
+        let command = RemoteCmd::new("iperf3")
+            .arg("-c")
+            .arg("10.0.0.1")
+            .arg("-p")
+            .arg(5202);
+        ClientRecord::new(5202, 25_000_000, command)
+    }
+
+    /// The status of a process that exited with `code`.
+    fn exited(code: i32) -> ExitStatus {
+        ExitStatus::from_raw(code << 8)
+    }
+
+    #[test]
+    fn records_are_filled_in_as_the_client_runs() {
+        let before = unix_time(SystemTime::now());
+        let mut record = record();
+        assert_eq!(
+            (record.start, record.end, record.exit_status),
+            (None, None, None)
+        );
+
+        record.started();
+        record.finished(exited(0));
+        let (start, end) = (record.start.unwrap(), record.end.unwrap());
+        assert!(before <= start && start <= end, "{before} {start} {end}");
+        assert_eq!(record.exit_status, Some(0));
+        assert!(record.failed_attempts.is_empty());
+    }
+
+    #[test]
+    fn retries_keep_the_failed_attempts() {
+        let mut record = record();
+        record.started();
+        record.finished(exited(1));
+        let first_start = record.start;
+        record.retry();
+
+        let [attempt] = record.failed_attempts.as_slice() else {
+            panic!("expected one attempt, got {:?}", record.failed_attempts);
+        };
+        assert_eq!(attempt.start, first_start);
+        assert!(attempt.end.is_some());
+        assert_eq!(attempt.exit_status, Some(1));
+        // The retry is running.
+        assert!(record.start.unwrap() >= first_start.unwrap());
+        assert_eq!((record.end, record.exit_status), (None, None));
+
+        record.finished(exited(0));
+        assert_eq!(record.exit_status, Some(0));
+    }
+
+    #[test]
+    fn killed_clients_have_no_exit_code() {
+        let mut record = record();
+        record.started();
+        record.finished(ExitStatus::from_raw(9));
+        assert!(record.end.is_some());
+        assert_eq!(record.exit_status, None);
+    }
+
+    #[tokio::test]
+    async fn records_are_written_to_clients_ron() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut clients = BTreeMap::from([("sta1".to_string(), record())]);
+        write_clients(dir.path(), &clients).await.unwrap();
+        let first = std::fs::read_to_string(dir.path().join("clients.ron")).unwrap();
+        assert!(first.contains("\"sta1\""), "{first}");
+        assert!(first.contains("port: 5202"), "{first}");
+        assert!(first.contains("offered_load: 25000000"), "{first}");
+        assert!(
+            first.contains("command: \"iperf3 -c 10.0.0.1 -p 5202\""),
+            "{first}"
+        );
+        assert!(first.contains("start: None"), "{first}");
+
+        // Written again once the client finished.
+        let sta1 = clients.get_mut("sta1").unwrap();
+        sta1.started();
+        sta1.finished(exited(0));
+        write_clients(dir.path(), &clients).await.unwrap();
+        let updated = std::fs::read_to_string(dir.path().join("clients.ron")).unwrap();
+        assert!(updated.contains("exit_status: Some(0)"), "{updated}");
+        let value: ron::Value = ron::from_str(&updated).unwrap();
+        let ron::Value::Map(map) = value else {
+            panic!("expected a map, got {updated}");
+        };
+        assert_eq!(map.len(), 1);
+    }
+}
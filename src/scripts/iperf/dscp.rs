@@ -0,0 +1,82 @@
+//! Parsing of DSCP values used to mark iperf traffic.
+
+use std::{fmt::Display, str::FromStr};
+
+use serde::{Deserialize, Serialize};
+
+/// A Differentiated Services Code Point, a value between 0 and 63.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct Dscp(u8);
+
+impl Dscp {
+    pub fn value(self) -> u8 {
+        self.0
+    }
+}
+
+impl FromStr for Dscp {
+    type Err = String;
+
+    /// Parses either a numeric value or a name like `EF`, `CS6` or `AF41`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let upper = s.trim().to_ascii_uppercase();
+        let value = if let Ok(v) = upper.parse::<u8>() {
+            v
+        } else if upper == "EF" {
+            46
+        } else if let Some(class) = upper.strip_prefix("CS") {
+            match class.parse::<u8>() {
+                Ok(class @ 0..=7) => class << 3,
+                _ => return Err(format!("invalid class selector `{s}`, expected CS0 to CS7")),
+            }
+        } else if let Some(af) = upper.strip_prefix("AF") {
+            // Assured forwarding names are `AF<class><drop precedence>`.
+            let digits: Vec<_> = af.chars().filter_map(|c| c.to_digit(10)).collect();
+            match digits[..] {
+                [class @ 1..=4, drop @ 1..=3] if af.len() == 2 => (class * 8 + drop * 2) as u8,
+                _ => {
+                    return Err(format!(
+                        "invalid assured forwarding name `{s}`, expected AF11 to AF43"
+                    ))
+                }
+            }
+        } else {
+            return Err(format!(
+                "invalid DSCP `{s}`, expected a number or a name like EF or AF41"
+            ));
+        };
+
+        if value > 63 {
+            return Err(format!("DSCP must be between 0 and 63, got {value}"));
+        }
+        Ok(Dscp(value))
+    }
+}
+
+impl Display for Dscp {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// A DSCP value for a specific client, in the form `<host>=<dscp>`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ClientDscp {
+    pub host: String,
+    pub dscp: Dscp,
+}
+
+impl FromStr for ClientDscp {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (host, dscp) = s
+            .split_once('=')
+            .ok_or_else(|| format!("expected `<host>=<dscp>`, got `{s}`"))?;
+        Ok(ClientDscp {
+            host: host.to_string(),
+            dscp: dscp.parse()?,
+        })
+    }
+}
@@ -0,0 +1,286 @@
+//! A check of the goodput the clients reported against the data frames the monitors captured for
+//! them, with `--analyze`. A large difference usually means a monitor missed traffic or a client
+//! sent its traffic over another interface.
+
+use std::{collections::BTreeMap, path::Path, sync::Arc};
+
+use serde::{Deserialize, Serialize};
+use tracing::{debug, warn};
+
+use crate::{
+    capture::analysis::{self, StationBytes},
+    driver::wifi,
+    hosts::{Host, HostId, Hosts},
+    mac::MacAddr,
+    monitor::MonitorMetadata,
+    scripts::iperf::{ClientRecord, IperfResult, TrafficDirection},
+    utils::host_file,
+};
+
+/// The reported and observed goodput of every client.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GoodputCheck {
+    /// The largest difference between the observed and reported goodput, as a fraction of the
+    /// reported goodput, that is not flagged.
+    pub tolerance: f64,
+    pub clients: BTreeMap<HostId, Vec<DirectionCheck>>,
+}
+
+/// The goodput of a client in one direction.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DirectionCheck {
+    pub direction: TrafficDirection,
+    /// The goodput iperf reported in bits per second.
+    pub reported: f64,
+    /// The data frames the monitors captured in bits per second, including their 802.11 headers.
+    /// The monitor that captured the most is used.
+    pub observed: Option<f64>,
+    /// The monitor the observed goodput is from.
+    pub monitor: Option<HostId>,
+    /// The observed goodput divided by the reported goodput. It is a bit above 1 when the monitor
+    /// captured everything, because of the headers.
+    pub ratio: Option<f64>,
+    /// Whether the ratio differs from 1 by more than the tolerance.
+    pub flagged: bool,
+}
+
+/// Compare the goodput the `clients` reported with the data frames in the captures of the
+/// monitor, over the window in which each client measured.
+pub async fn check_goodput(
+    clients: &[Arc<Host>],
+    hosts: &Hosts,
+    results: &BTreeMap<HostId, IperfResult>,
+    records: &BTreeMap<HostId, ClientRecord>,
+    monitor: &MonitorMetadata,
+    out_path: &Path,
+    tolerance: f64,
+) -> GoodputCheck {
+    // The window of every station by the controller clock. The measured intervals are the last
+    // ones before the client finished.
+    let mut stations = BTreeMap::new();
+    for client in clients {
+        let Some(window) = measured_window(results.get(&client.id), records.get(&client.id)) else {
+            debug!(host = client.id, "Client has no measured window to check");
+            continue;
+        };
+        match station_address(client).await {
+            Ok(address) => _ = stations.insert(client.id.clone(), (address, window)),
+            Err(err) => warn!(host = client.id, "Can not check the goodput: {err:#}"),
+        }
+    }
+
+    // The largest number of bytes any monitor captured for every station.
+    let mut observed: BTreeMap<HostId, (HostId, StationBytes)> = BTreeMap::new();
+    for capture in monitor.captures.iter().filter(|c| c.stats.is_some()) {
+        let offset = monitor.clock_offset(hosts, &capture.host).await;
+        let windows = stations
+            .values()
+            .map(|(address, (start, end))| (*address, (start + offset, end + offset)))
+            .collect();
+        let path = host_file(out_path, &capture.host, "pcapng");
+        let bytes = match analysis::station_data_bytes(&path, &windows).await {
+            Ok(bytes) => bytes,
+            Err(err) => {
+                warn!(
+                    host = capture.host,
+                    "Could not analyze the capture: {err:#}"
+                );
+                continue;
+            }
+        };
+        for (client, (address, _)) in &stations {
+            let Some(found) = bytes.get(address) else {
+                continue;
+            };
+            let best = observed.get(client).map_or(0, |(_, b)| b.sent + b.received);
+            if found.sent + found.received >= best {
+                observed.insert(client.clone(), (capture.host.clone(), found.clone()));
+            }
+        }
+    }
+
+    let clients = stations
+        .iter()
+        .filter_map(|(client, (_, (start, end)))| {
+            let result = results.get(client)?;
+            let seen = observed.get(client);
+            let checks = result
+                .directions
+                .iter()
+                .filter_map(|d| {
+                    let reported = d.summary.as_ref()?.bits_per_second;
+                    let observed = seen.map(|(_, bytes)| {
+                        let bytes = match d.direction {
+                            TrafficDirection::Uplink => bytes.sent,
+                            TrafficDirection::Downlink => bytes.received,
+                        };
+                        bytes as f64 * 8.0 / (end - start)
+                    });
+                    let (ratio, flagged) = compare(reported, observed, tolerance);
+                    if flagged {
+                        warn!(
+                            host = client,
+                            "The monitors observed {:.2} Mbit/s {:?}, but iperf reported {:.2} Mbit/s",
+                            observed.unwrap_or_default() / 1_000_000.0,
+                            d.direction,
+                            reported / 1_000_000.0
+                        );
+                    }
+                    Some(DirectionCheck {
+                        direction: d.direction,
+                        reported,
+                        observed,
+                        monitor: seen.map(|(monitor, _)| monitor.clone()),
+                        ratio,
+                        flagged,
+                    })
+                })
+                .collect();
+            Some((client.clone(), checks))
+        })
+        .collect();
+    GoodputCheck { tolerance, clients }
+}
+
+/// The ratio of the `observed` to the `reported` goodput, and whether it differs from 1 by more
+/// than `tolerance`.
+fn compare(reported: f64, observed: Option<f64>, tolerance: f64) -> (Option<f64>, bool) {
+    let ratio = observed.filter(|_| reported > 0.0).map(|o| o / reported);
+    let flagged = ratio.is_some_and(|r| (r - 1.0).abs() > tolerance);
+    (ratio, flagged)
+}
+
+/// The window in which a client measured the goodput it reported, in seconds since the unix
+/// epoch.
+fn measured_window(
+    result: Option<&IperfResult>,
+    record: Option<&ClientRecord>,
+) -> Option<(f64, f64)> {
+    let record = record?;
+    let end = record.end?;
+    let seconds = result?
+        .directions
+        .iter()
+        .filter_map(|d| d.summary.as_ref().map(|s| s.seconds))
+        .reduce(f64::max)?;
+    let start = record.start.map_or(end - seconds, |s| s.max(end - seconds));
+    (end > start).then_some((start, end))
+}
+
+/// The MAC address of the wireless interface of a client.
+async fn station_address(client: &Host) -> anyhow::Result<MacAddr> {
+    let Some(ifname) = client.extra_data.interface_name() else {
+        anyhow::bail!("client has no interface name configured");
+    };
+    wifi::interface_info(client, ifname)
+        .await?
+        .addr
+        .ok_or_else(|| anyhow::anyhow!("could not determine the address of the client"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        scripts::iperf::{
+            summary::tests::{measured, result},
+            Summary,
+        },
+        utils::RemoteCmd,
+    };
+
+    fn record(start: Option<f64>, end: Option<f64>) -> ClientRecord {
+        ClientRecord {
+            start,
+            end,
+            ..ClientRecord::new(5201, 0, RemoteCmd::new("iperf3"))
+        }
+    }
+
+    /// A result of which the directions measured `seconds`.
+    fn measured_for(seconds: &[f64]) -> IperfResult {
+        let directions = [TrafficDirection::Uplink, TrafficDirection::Downlink];
+        let summaries = seconds
+            .iter()
+            .zip(directions)
+            .map(|(&seconds, direction)| {
+                (
+                    direction,
+                    Some(Summary {
+                        seconds,
+                        ..measured(10.0)
+                    }),
+                )
+            })
+            .collect();
+        result("TCP", summaries)
+    }
+
+    #[test]
+    fn window_ends_when_the_client_finished() {
+        let iperf = measured_for(&[10.0]);
+        let window = measured_window(Some(&iperf), Some(&record(Some(100.0), Some(112.0))));
+        assert_eq!(window, Some((102.0, 112.0)));
+        // The longest direction decides the window.
+        let iperf = measured_for(&[8.0, 10.0]);
+        let window = measured_window(Some(&iperf), Some(&record(Some(100.0), Some(112.0))));
+        assert_eq!(window, Some((102.0, 112.0)));
+    }
+
+    #[test]
+    fn window_is_clamped_to_the_start() {
+        let iperf = measured_for(&[10.0]);
+        let window = measured_window(Some(&iperf), Some(&record(Some(105.0), Some(112.0))));
+        assert_eq!(window, Some((105.0, 112.0)));
+        let window = measured_window(Some(&iperf), Some(&record(None, Some(112.0))));
+        assert_eq!(window, Some((102.0, 112.0)));
+    }
+
+    #[test]
+    fn window_without_measurements() {
+        let iperf = measured_for(&[10.0]);
+        assert_eq!(measured_window(Some(&iperf), None), None);
+        assert_eq!(
+            measured_window(None, Some(&record(Some(100.0), Some(112.0)))),
+            None
+        );
+        // The client did not finish.
+        assert_eq!(
+            measured_window(Some(&iperf), Some(&record(Some(100.0), None))),
+            None
+        );
+        // No direction has a summary.
+        let empty = result("TCP", vec![(TrafficDirection::Uplink, None)]);
+        assert_eq!(
+            measured_window(Some(&empty), Some(&record(None, Some(112.0)))),
+            None
+        );
+        // The client finished when it started.
+        assert_eq!(
+            measured_window(Some(&iperf), Some(&record(Some(112.0), Some(112.0)))),
+            None
+        );
+    }
+
+    #[test]
+    fn goodput_within_the_tolerance() {
+        assert_eq!(compare(100.0, Some(104.0), 0.05), (Some(1.04), false));
+        assert_eq!(compare(100.0, Some(96.0), 0.05), (Some(0.96), false));
+        // Exactly at the tolerance is not flagged, the ratio is exact for these values.
+        assert_eq!(compare(100.0, Some(150.0), 0.5), (Some(1.5), false));
+        assert_eq!(compare(100.0, Some(50.0), 0.5), (Some(0.5), false));
+    }
+
+    #[test]
+    fn goodput_beyond_the_tolerance() {
+        assert_eq!(compare(100.0, Some(151.0), 0.5), (Some(1.51), true));
+        assert_eq!(compare(100.0, Some(49.0), 0.5), (Some(0.49), true));
+        assert_eq!(compare(100.0, Some(0.0), 0.2), (Some(0.0), true));
+    }
+
+    #[test]
+    fn goodput_without_a_ratio() {
+        assert_eq!(compare(100.0, None, 0.2), (None, false));
+        assert_eq!(compare(0.0, Some(10.0), 0.2), (None, false));
+    }
+}
@@ -0,0 +1,102 @@
+//! Sampling of the link of the clients while the traffic runs, for the timeline.
+
+use std::{
+    collections::BTreeMap,
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+use tokio::{
+    select,
+    sync::watch,
+    task::JoinSet,
+    time::{interval, MissedTickBehavior},
+};
+use tracing::{debug, warn};
+
+use crate::{
+    driver::wifi,
+    hosts::{Host, HostId},
+    utils::unix_time,
+};
+
+/// The signal strength of a client at one moment.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LinkSample {
+    /// When the link was sampled, in seconds since the unix epoch by the controller clock.
+    pub time: f64,
+    /// The signal strength of the access point in dBm, `None` if the client was not connected.
+    pub signal: Option<i32>,
+}
+
+/// The samplers of every client. Dropping it stops sampling right away.
+pub struct LinkSampler {
+    stop: watch::Sender<bool>,
+    samplers: JoinSet<(HostId, Vec<LinkSample>)>,
+}
+
+impl LinkSampler {
+    /// Start sampling the link of every client every `period`. Clients without an interface name
+    /// are skipped.
+    pub fn start(clients: &[Arc<Host>], period: Duration) -> Self {
+        let (stop, stopped) = watch::channel(false);
+        let mut samplers = JoinSet::new();
+        for client in clients {
+            let Some(ifname) = client.extra_data.interface_name() else {
+                warn!(
+                    host = client.id,
+                    "No interface name configured, not sampling the link"
+                );
+                continue;
+            };
+            let (client, ifname) = (client.clone(), ifname.to_string());
+            let stopped = stopped.clone();
+            samplers.spawn(async move {
+                let samples = sample(&client, &ifname, period, stopped).await;
+                (client.id.clone(), samples)
+            });
+        }
+        Self { stop, samplers }
+    }
+
+    /// Stop sampling, letting a sample that is being taken finish, and return the samples of
+    /// every client.
+    pub async fn stop(mut self) -> BTreeMap<HostId, Vec<LinkSample>> {
+        _ = self.stop.send(true);
+        let mut samples = BTreeMap::new();
+        while let Some(result) = self.samplers.join_next().await {
+            match result {
+                Ok((host, host_samples)) => _ = samples.insert(host, host_samples),
+                Err(err) => warn!("Link sampler failed: {err}"),
+            }
+        }
+        samples
+    }
+}
+
+async fn sample(
+    host: &Host,
+    ifname: &str,
+    period: Duration,
+    mut stopped: watch::Receiver<bool>,
+) -> Vec<LinkSample> {
+    let mut ticks = interval(period);
+    ticks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    let mut samples = Vec::new();
+    loop {
+        select! {
+            _ = stopped.changed() => break,
+            _ = ticks.tick() => {}
+        }
+        let time = unix_time(SystemTime::now());
+        match wifi::link_info(host, ifname).await {
+            Ok(link) => samples.push(LinkSample {
+                time,
+                signal: link.signal,
+            }),
+            Err(err) => warn!(host = host.id, "Could not sample the link: {err:?}"),
+        }
+    }
+    debug!(host = host.id, "Took {} link samples", samples.len());
+    samples
+}
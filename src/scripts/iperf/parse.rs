@@ -0,0 +1,629 @@
+//! Parsing of iperf3 client output into structured results.
+
+use std::{io::BufRead, time::Duration};
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+
+/// The parsed result of a single iperf3 client run.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct IperfResult {
+    /// The protocol reported by iperf, for example `TCP` or `UDP`.
+    pub protocol: Option<String>,
+    /// The type of service byte iperf marked the traffic with. The DSCP is the upper six bits.
+    pub tos: Option<u8>,
+    /// The results for every direction traffic was sent in. Bidirectional tests have both an
+    /// uplink and a downlink entry, other tests only have one.
+    pub directions: Vec<DirectionResult>,
+    /// The error reported by iperf, if the test did not complete.
+    pub error: Option<String>,
+}
+
+/// The direction of traffic, as seen from the client.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum TrafficDirection {
+    /// From the client to the server.
+    Uplink,
+    /// From the server to the client.
+    Downlink,
+}
+
+/// The results of the traffic in a single direction.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DirectionResult {
+    pub direction: TrafficDirection,
+    /// Per-interval measurements, in the order iperf reported them.
+    pub intervals: Vec<Interval>,
+    /// Totals computed over all intervals that were not omitted. Absent if there were none.
+    pub summary: Option<Summary>,
+}
+
+impl IperfResult {
+    /// The result of the traffic in a specific direction, if any was sent in that direction.
+    pub fn direction(&self, direction: TrafficDirection) -> Option<&DirectionResult> {
+        self.directions.iter().find(|d| d.direction == direction)
+    }
+}
+
+/// A single reporting interval of an iperf run.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Interval {
+    /// Start of the interval in seconds since the start of the test.
+    pub start: f64,
+    /// End of the interval in seconds since the start of the test.
+    pub end: f64,
+    pub bytes: u64,
+    pub bits_per_second: f64,
+    /// True if this interval falls in the warm-up period and is excluded from the summary.
+    pub omitted: bool,
+    /// TCP retransmits, only reported on the sending side.
+    pub retransmits: Option<u64>,
+    /// UDP jitter, only reported on the receiving side.
+    pub jitter_ms: Option<f64>,
+    pub lost_packets: Option<u64>,
+    pub packets: Option<u64>,
+}
+
+/// Aggregated statistics over the measured (non-omitted) intervals of a run.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Summary {
+    pub seconds: f64,
+    pub bytes: u64,
+    pub bits_per_second: f64,
+    pub retransmits: Option<u64>,
+    /// Mean jitter over the measured intervals.
+    pub jitter_ms: Option<f64>,
+    pub lost_packets: Option<u64>,
+    pub packets: Option<u64>,
+    pub lost_percent: Option<f64>,
+}
+
+/// The subset of the iperf3 `-J` output that is used.
+#[derive(Debug, Deserialize)]
+struct RawOutput {
+    #[serde(default)]
+    start: Option<RawStart>,
+    #[serde(default)]
+    intervals: Vec<RawInterval>,
+    #[serde(default)]
+    error: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct RawStart {
+    test_start: Option<RawTestStart>,
+}
+
+#[derive(Debug, Deserialize)]
+struct RawTestStart {
+    protocol: Option<String>,
+    tos: Option<u8>,
+    #[serde(default)]
+    reverse: u8,
+    #[serde(default)]
+    bidir: u8,
+}
+
+#[derive(Debug, Deserialize)]
+struct RawInterval {
+    /// The traffic sent by the client, or received by it if the test is reversed.
+    sum: RawSum,
+    /// The traffic received by the client in a bidirectional test.
+    sum_bidir_reverse: Option<RawSum>,
+}
+
+#[derive(Debug, Deserialize)]
+struct RawSum {
+    start: f64,
+    end: f64,
+    bytes: u64,
+    bits_per_second: f64,
+    #[serde(default)]
+    omitted: bool,
+    retransmits: Option<u64>,
+    jitter_ms: Option<f64>,
+    lost_packets: Option<u64>,
+    packets: Option<u64>,
+}
+
+impl From<RawSum> for Interval {
+    fn from(sum: RawSum) -> Self {
+        Interval {
+            start: sum.start,
+            end: sum.end,
+            bytes: sum.bytes,
+            bits_per_second: sum.bits_per_second,
+            omitted: sum.omitted,
+            retransmits: sum.retransmits,
+            jitter_ms: sum.jitter_ms,
+            lost_packets: sum.lost_packets,
+            packets: sum.packets,
+        }
+    }
+}
+
+/// Parse the JSON output (`-J`) of an iperf3 client.
+///
+/// Intervals that iperf itself marked as omitted (through `-O`) are excluded from the summary. If
+/// iperf did not omit anything, all intervals starting within the `omit` period are marked as
+/// omitted instead. This gives UDP runs, for which `-O` is not passed, the same warm-up behavior.
+pub fn parse_json(data: &[u8], omit: Duration) -> anyhow::Result<IperfResult> {
+    let raw: RawOutput = serde_json::from_slice(data).context("invalid iperf JSON output")?;
+    let test_start = raw.start.and_then(|s| s.test_start);
+
+    let (reverse, bidir) = test_start
+        .as_ref()
+        .map(|t| (t.reverse != 0, t.bidir != 0))
+        .unwrap_or_default();
+    let forward_direction = if reverse {
+        TrafficDirection::Downlink
+    } else {
+        TrafficDirection::Uplink
+    };
+
+    let mut forward = Vec::with_capacity(raw.intervals.len());
+    let mut backward = Vec::new();
+    for interval in raw.intervals {
+        forward.push(interval.sum.into());
+        if let Some(sum) = interval.sum_bidir_reverse {
+            backward.push(sum.into());
+        }
+    }
+
+    let mut directions = vec![direction_result(forward_direction, forward, omit)];
+    if bidir {
+        directions.push(direction_result(TrafficDirection::Downlink, backward, omit));
+    }
+
+    Ok(IperfResult {
+        protocol: test_start.as_ref().and_then(|t| t.protocol.clone()),
+        tos: test_start.and_then(|t| t.tos),
+        directions,
+        error: raw.error,
+    })
+}
+
+/// Mark the warm-up intervals and summarize the traffic in a single direction.
+fn direction_result(
+    direction: TrafficDirection,
+    mut intervals: Vec<Interval>,
+    omit: Duration,
+) -> DirectionResult {
+    if !intervals.iter().any(|i| i.omitted) {
+        let omit = omit.as_secs_f64();
+        intervals
+            .iter_mut()
+            .filter(|i| i.start < omit)
+            .for_each(|i| i.omitted = true);
+    }
+
+    DirectionResult {
+        direction,
+        summary: summarize(&intervals),
+        intervals,
+    }
+}
+
+/// Aggregate all intervals that are not omitted.
+fn summarize(intervals: &[Interval]) -> Option<Summary> {
+    let measured: Vec<_> = intervals.iter().filter(|i| !i.omitted).collect();
+    if measured.is_empty() {
+        return None;
+    }
+
+    // Sums an optional field, which is only present if any of the intervals reported it.
+    fn sum_opt<T: std::iter::Sum<T> + Copy>(
+        measured: &[&Interval],
+        f: impl Fn(&Interval) -> Option<T>,
+    ) -> Option<T> {
+        let values: Vec<T> = measured.iter().filter_map(|i| f(i)).collect();
+        (!values.is_empty()).then(|| values.into_iter().sum())
+    }
+
+    let seconds: f64 = measured.iter().map(|i| i.end - i.start).sum();
+    let bytes: u64 = measured.iter().map(|i| i.bytes).sum();
+    let lost_packets = sum_opt(&measured, |i| i.lost_packets);
+    let packets = sum_opt(&measured, |i| i.packets);
+    let jitters: Vec<f64> = measured.iter().filter_map(|i| i.jitter_ms).collect();
+
+    Some(Summary {
+        seconds,
+        bytes,
+        bits_per_second: if seconds > 0.0 {
+            bytes as f64 * 8.0 / seconds
+        } else {
+            0.0
+        },
+        retransmits: sum_opt(&measured, |i| i.retransmits),
+        jitter_ms: (!jitters.is_empty())
+            .then(|| jitters.iter().sum::<f64>() / jitters.len() as f64),
+        lost_percent: match (lost_packets, packets) {
+            (Some(lost), Some(total)) if total > 0 => Some(lost as f64 / total as f64 * 100.0),
+            _ => None,
+        },
+        lost_packets,
+        packets,
+    })
+}
+
+/// Parse an interval line of the human readable output of an iperf3 client, like
+/// `[  5]   1.00-2.00   sec  1.12 MBytes  9.38 Mbits/sec`, into the end of the interval in seconds
+/// and the bitrate in bits per second.
+///
+/// The summary lines at the end of a test, which end in `sender` or `receiver`, are skipped.
+pub fn parse_interval_line(line: &str) -> Option<(f64, f64)> {
+    let line = line.trim();
+    if !line.starts_with('[') || line.ends_with("sender") || line.ends_with("receiver") {
+        return None;
+    }
+    let fields: Vec<&str> = line.split(']').nth(1)?.split_whitespace().collect();
+    let (_, end) = fields.first()?.split_once('-')?;
+    let end = end.parse().ok()?;
+    let unit = fields.iter().position(|f| f.ends_with("bits/sec"))?;
+    let value: f64 = fields.get(unit.checked_sub(1)?)?.parse().ok()?;
+    Some((end, value * bitrate_scale(fields[unit])?))
+}
+
+/// The factor to get bits per second from a bitrate in `unit`, like `Mbits/sec`.
+fn bitrate_scale(unit: &str) -> Option<f64> {
+    Some(match unit.strip_suffix("bits/sec")? {
+        "" => 1.0,
+        "K" => 1e3,
+        "M" => 1e6,
+        "G" => 1e9,
+        _ => return None,
+    })
+}
+
+/// The factor to get bytes from an amount in `unit`, like `MBytes`. iperf uses powers of 1024 for
+/// amounts of data.
+fn bytes_scale(unit: &str) -> Option<f64> {
+    Some(match unit.strip_suffix("Bytes")? {
+        "" => 1.0,
+        "K" => 1024.0,
+        "M" => 1024.0 * 1024.0,
+        "G" => 1024.0 * 1024.0 * 1024.0,
+        "T" => 1024.0 * 1024.0 * 1024.0 * 1024.0,
+        _ => return None,
+    })
+}
+
+/// The parsed human readable output of an iperf3 client, as written by runs without `--json`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct IperfTextResult {
+    /// The intervals and their summary, in the same shape as [parse_json] gives them. The text
+    /// output does not include the type of service, so that is always `None`.
+    pub result: IperfResult,
+    /// The totals iperf printed at the end of the test.
+    pub totals: Vec<ReportedTotal>,
+}
+
+/// Which end of the test measured a total.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Side {
+    Sender,
+    Receiver,
+}
+
+/// A total printed at the end of a test, on the lines ending in `sender` and `receiver`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ReportedTotal {
+    pub direction: TrafficDirection,
+    pub side: Side,
+    pub total: Interval,
+}
+
+/// A row of the text output with an interval or a total.
+struct TextRow {
+    /// Whether the row is the sum over all parallel streams.
+    sum: bool,
+    /// The direction of a bidirectional test, from the `[TX-C]` or `[RX-C]` tag.
+    direction: Option<TrafficDirection>,
+    interval: Interval,
+    /// The number after the bitrate, retransmits for TCP and datagrams for UDP.
+    count: Option<u64>,
+    /// `None` for an interval, or which end measured a total.
+    side: Option<Side>,
+}
+
+/// Parse the human readable output of an iperf3 client, like the `<client>.txt` files of runs
+/// without `--json`.
+///
+/// TCP and UDP tests, reversed (`-R`) and bidirectional tests and parallel streams are supported.
+/// With parallel streams only the `[SUM]` rows are used. A test that failed has the error iperf
+/// printed and no intervals.
+pub fn parse_text(reader: impl BufRead) -> anyhow::Result<IperfTextResult> {
+    let mut reverse = false;
+    let mut udp = false;
+    let mut header = false;
+    let mut found = false;
+    let mut error = None;
+    let mut rows = Vec::new();
+    for line in reader.lines() {
+        let line = line.context("could not read iperf output")?;
+        let line = line.trim();
+        if line.starts_with("Connecting to host") {
+            found = true;
+        } else if line.starts_with("Reverse mode") {
+            reverse = true;
+        } else if let Some(err) = line.strip_prefix("iperf3: error - ") {
+            found = true;
+            error = Some(err.to_string());
+        } else if line.starts_with("[ ID]") {
+            found = true;
+            header = true;
+            udp |= line.contains("Datagrams");
+        } else if let Some(row) = parse_text_row(line) {
+            rows.push(row);
+        }
+    }
+    if !found && rows.is_empty() {
+        anyhow::bail!("not iperf output");
+    }
+
+    // With parallel streams every stream has its own rows, which are summed in the `[SUM]` rows.
+    let parallel = rows.iter().any(|row| row.sum);
+    let forward_direction = if reverse {
+        TrafficDirection::Downlink
+    } else {
+        TrafficDirection::Uplink
+    };
+    let mut forward = Vec::new();
+    let mut backward = Vec::new();
+    let mut totals = Vec::new();
+    for mut row in rows.into_iter().filter(|row| row.sum == parallel) {
+        if udp {
+            row.interval.packets = row.interval.packets.or(row.count);
+        } else {
+            row.interval.retransmits = row.count;
+        }
+        let direction = row.direction.unwrap_or(forward_direction);
+        match row.side {
+            Some(side) => totals.push(ReportedTotal {
+                direction,
+                side,
+                total: row.interval,
+            }),
+            None if direction == forward_direction => forward.push(row.interval),
+            None => backward.push(row.interval),
+        }
+    }
+
+    let mut directions = vec![direction_result(forward_direction, forward, Duration::ZERO)];
+    if !backward.is_empty() {
+        let backward_direction = match forward_direction {
+            TrafficDirection::Uplink => TrafficDirection::Downlink,
+            TrafficDirection::Downlink => TrafficDirection::Uplink,
+        };
+        directions.push(direction_result(
+            backward_direction,
+            backward,
+            Duration::ZERO,
+        ));
+    }
+    Ok(IperfTextResult {
+        result: IperfResult {
+            // A test that failed before it started has no table to tell the protocol from.
+            protocol: header.then(|| if udp { "UDP" } else { "TCP" }.to_string()),
+            tos: None,
+            directions,
+            error,
+        },
+        totals,
+    })
+}
+
+/// Parse a row of the text output with an interval or a total, like
+/// `[  5]   0.00-1.00   sec  11.2 MBytes  94.1 Mbits/sec    0    375 KBytes`. Any other line is
+/// `None`.
+fn parse_text_row(line: &str) -> Option<TextRow> {
+    // The tags are the stream ID or `SUM`, and in bidirectional tests `TX-C` or `RX-C`.
+    let mut rest = line;
+    let mut tags = Vec::new();
+    while let Some(tagged) = rest.strip_prefix('[') {
+        let (tag, after) = tagged.split_once(']')?;
+        tags.push(tag.trim());
+        rest = after;
+    }
+    let mut fields: Vec<&str> = rest.split_whitespace().collect();
+    let side = match fields.last() {
+        Some(&"sender") => Some(Side::Sender),
+        Some(&"receiver") => Some(Side::Receiver),
+        _ => None,
+    };
+    if side.is_some() {
+        fields.pop();
+    }
+    let omitted = fields.last() == Some(&"(omitted)");
+    if omitted {
+        fields.pop();
+    }
+    let [range, "sec", amount, amount_unit, rate, rate_unit, extra @ ..] = fields.as_slice() else {
+        return None;
+    };
+    let (start, end) = range.split_once('-')?;
+
+    let mut row = TextRow {
+        sum: tags.first() == Some(&"SUM"),
+        direction: tags.iter().find_map(|tag| match *tag {
+            "TX-C" => Some(TrafficDirection::Uplink),
+            "RX-C" => Some(TrafficDirection::Downlink),
+            _ => None,
+        }),
+        interval: Interval {
+            start: start.parse().ok()?,
+            end: end.parse().ok()?,
+            bytes: (amount.parse::<f64>().ok()? * bytes_scale(amount_unit)?) as u64,
+            bits_per_second: rate.parse::<f64>().ok()? * bitrate_scale(rate_unit)?,
+            omitted,
+            retransmits: None,
+            jitter_ms: None,
+            lost_packets: None,
+            packets: None,
+        },
+        count: None,
+        side,
+    };
+    // The receiving end of UDP reports `0.012 ms  3/822 (0.36%)`.
+    if let Some(ms) = extra.iter().position(|f| *f == "ms") {
+        row.interval.jitter_ms = extra.get(ms.checked_sub(1)?)?.parse().ok();
+        if let Some((lost, total)) = extra.get(ms + 1).and_then(|f| f.split_once('/')) {
+            row.interval.lost_packets = lost.parse().ok();
+            row.interval.packets = total.parse().ok();
+        }
+    } else {
+        row.count = extra.first().and_then(|f| f.parse().ok());
+    }
+    Some(row)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TCP_OUTPUT: &str = "\
+Connecting to host 10.0.0.1, port 5001
+[  5] local 10.0.0.2 port 43210 connected to 10.0.0.1 port 5001
+[ ID] Interval           Transfer     Bitrate         Retr  Cwnd
+[  5]   0.00-1.00   sec  10.5 MBytes  88.1 Mbits/sec    3    212 KBytes       (omitted)
+[  5]   0.00-1.00   sec  11.2 MBytes  94.1 Mbits/sec    0    375 KBytes       
+[  5]   1.00-2.00   sec  11.1 MBytes  93.3 Mbits/sec    2    372 KBytes       
+- - - - - - - - - - - - - - - - - - - - - - - - -
+[ ID] Interval           Transfer     Bitrate         Retr
+[  5]   0.00-2.00   sec  22.3 MBytes  93.7 Mbits/sec    2             sender
+[  5]   0.00-2.01   sec  22.1 MBytes  92.4 Mbits/sec                  receiver
+
+iperf Done.
+";
+
+    const UDP_REVERSE_OUTPUT: &str = "\
+Connecting to host 10.0.0.1, port 5002
+Reverse mode, remote host 10.0.0.1 is sending
+[  5] local 10.0.0.3 port 51000 connected to 10.0.0.1 port 5002
+[ ID] Interval           Transfer     Bitrate         Jitter    Lost/Total Datagrams
+[  5]   0.00-1.00   sec  1.19 MBytes  10.0 Mbits/sec  0.012 ms  0/863 (0%)  
+[  5]   1.00-2.00   sec  1.18 MBytes  9.90 Mbits/sec  0.020 ms  8/863 (0.93%)  
+- - - - - - - - - - - - - - - - - - - - - - - - -
+[ ID] Interval           Transfer     Bitrate         Jitter    Lost/Total Datagrams
+[  5]   0.00-2.04   sec  2.39 MBytes  9.83 Mbits/sec  0.000 ms  0/1726 (0%)  sender
+[  5]   0.00-2.00   sec  2.37 MBytes  9.95 Mbits/sec  0.020 ms  8/1726 (0.46%)  receiver
+
+iperf Done.
+";
+
+    const FAILED_OUTPUT: &str = "\
+Connecting to host 10.0.0.1, port 5003
+iperf3: error - unable to connect to server - server may not be running: Connection refused
+";
+
+    fn close(a: f64, b: f64) -> bool {
+        (a - b).abs() < 1e-6 * b.abs().max(1.0)
+    }
+
+    #[test]
+    fn text_output_of_a_tcp_test() {
+        let parsed = parse_text(TCP_OUTPUT.as_bytes()).unwrap();
+        let result = &parsed.result;
+        assert_eq!(result.protocol.as_deref(), Some("TCP"));
+        assert_eq!(result.error, None);
+        assert_eq!(result.directions.len(), 1);
+
+        let uplink = result.direction(TrafficDirection::Uplink).unwrap();
+        let intervals = &uplink.intervals;
+        assert_eq!(intervals.len(), 3);
+        assert!(intervals[0].omitted);
+        assert_eq!(intervals[0].retransmits, Some(3));
+        assert!(!intervals[1].omitted);
+        assert_eq!(intervals[2].start, 1.0);
+        assert_eq!(intervals[2].end, 2.0);
+        assert_eq!(intervals[2].bytes, (11.1 * 1024.0 * 1024.0) as u64);
+        assert!(close(intervals[2].bits_per_second, 93.3e6));
+        assert_eq!(intervals[2].retransmits, Some(2));
+        assert_eq!(intervals[2].packets, None);
+
+        // The omitted interval is left out of the summary.
+        let summary = uplink.summary.as_ref().unwrap();
+        assert_eq!(summary.seconds, 2.0);
+        assert_eq!(summary.bytes, intervals[1].bytes + intervals[2].bytes);
+        assert_eq!(summary.retransmits, Some(2));
+        assert_eq!(summary.lost_percent, None);
+
+        let [sender, receiver] = parsed.totals.as_slice() else {
+            panic!("expected two totals, got {:?}", parsed.totals);
+        };
+        assert_eq!(sender.side, Side::Sender);
+        assert_eq!(sender.direction, TrafficDirection::Uplink);
+        assert_eq!(sender.total.retransmits, Some(2));
+        assert_eq!(receiver.side, Side::Receiver);
+        assert_eq!(receiver.total.end, 2.01);
+        assert!(close(receiver.total.bits_per_second, 92.4e6));
+        assert_eq!(receiver.total.retransmits, None);
+    }
+
+    #[test]
+    fn text_output_of_a_reversed_udp_test() {
+        let parsed = parse_text(UDP_REVERSE_OUTPUT.as_bytes()).unwrap();
+        let result = &parsed.result;
+        assert_eq!(result.protocol.as_deref(), Some("UDP"));
+        assert!(result.direction(TrafficDirection::Uplink).is_none());
+
+        let downlink = result.direction(TrafficDirection::Downlink).unwrap();
+        let second = &downlink.intervals[1];
+        assert_eq!(second.jitter_ms, Some(0.020));
+        assert_eq!(second.lost_packets, Some(8));
+        assert_eq!(second.packets, Some(863));
+        assert_eq!(second.retransmits, None);
+
+        let summary = downlink.summary.as_ref().unwrap();
+        assert_eq!(summary.lost_packets, Some(8));
+        assert_eq!(summary.packets, Some(1726));
+        assert!(close(summary.lost_percent.unwrap(), 8.0 / 1726.0 * 100.0));
+        assert!(close(summary.jitter_ms.unwrap(), 0.016));
+
+        let receiver = parsed
+            .totals
+            .iter()
+            .find(|t| t.side == Side::Receiver)
+            .unwrap();
+        assert_eq!(receiver.direction, TrafficDirection::Downlink);
+        assert_eq!(receiver.total.lost_packets, Some(8));
+        assert_eq!(receiver.total.packets, Some(1726));
+    }
+
+    #[test]
+    fn text_output_of_a_failed_test() {
+        let parsed = parse_text(FAILED_OUTPUT.as_bytes()).unwrap();
+        let result = &parsed.result;
+        assert_eq!(
+            result.error.as_deref(),
+            Some("unable to connect to server - server may not be running: Connection refused")
+        );
+        // Nothing tells the protocol before the table is printed.
+        assert_eq!(result.protocol, None);
+        assert!(result.directions[0].intervals.is_empty());
+        assert!(result.directions[0].summary.is_none());
+        assert!(parsed.totals.is_empty());
+    }
+
+    #[test]
+    fn text_output_that_is_not_from_iperf() {
+        assert!(parse_text("PING 10.0.0.1 56(84) bytes of data.\n".as_bytes()).is_err());
+        assert!(parse_text("".as_bytes()).is_err());
+    }
+
+    #[test]
+    fn interval_lines_of_live_output() {
+        let (end, bitrate) =
+            parse_interval_line("[  5]   1.00-2.00   sec  11.1 MBytes  93.3 Mbits/sec    2")
+                .unwrap();
+        assert_eq!(end, 2.0);
+        assert!(close(bitrate, 93.3e6));
+        assert_eq!(
+            parse_interval_line(
+                "[  5]   0.00-2.00   sec  22.3 MBytes  93.7 Mbits/sec    2  sender"
+            ),
+            None
+        );
+        assert_eq!(parse_interval_line("iperf Done."), None);
+    }
+}
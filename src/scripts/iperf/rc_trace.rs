@@ -0,0 +1,138 @@
+//! Sampling of the rate control statistics of the hosts while the traffic runs.
+
+use std::{
+    path::Path,
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+use anyhow::Context;
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use tokio::{
+    fs::OpenOptions,
+    io::AsyncWriteExt,
+    select,
+    sync::watch,
+    task::JoinSet,
+    time::{interval, MissedTickBehavior},
+};
+use tracing::{debug, warn};
+
+use crate::{
+    driver::wifi,
+    hosts::Host,
+    utils::{host_file, unix_time},
+};
+
+/// Which hosts to sample the rate control statistics of.
+#[derive(ValueEnum, Debug, Clone, Copy, Serialize, Deserialize)]
+pub enum RcTraceHosts {
+    /// Only the access point, which controls the rate of the downlink.
+    Ap,
+    /// Only the clients, which control the rate of the uplink.
+    Clients,
+    All,
+}
+
+/// The samplers of every traced host. Dropping it stops sampling right away.
+pub struct RcTrace {
+    stop: watch::Sender<bool>,
+    samplers: JoinSet<()>,
+}
+
+impl RcTrace {
+    /// Start writing a snapshot of the rate control statistics of every host to
+    /// `rc-trace/<host>.txt` every `period`. Hosts whose driver is not supported are skipped.
+    pub async fn start(
+        hosts: &[Arc<Host>],
+        period: Duration,
+        out_path: &Path,
+    ) -> anyhow::Result<Self> {
+        let trace_path = out_path.join("rc-trace");
+        tokio::fs::create_dir_all(&trace_path)
+            .await
+            .context("could not create rate control trace folder")?;
+
+        let (stop, stopped) = watch::channel(false);
+        let mut samplers = JoinSet::new();
+        for host in hosts {
+            let driver = host.extra_data.wifi_driver.as_deref();
+            let Some(files) = wifi::rate_control_files(driver) else {
+                warn!(
+                    host = host.id,
+                    "Rate control traces are not supported for driver {}",
+                    driver.unwrap_or("unknown")
+                );
+                continue;
+            };
+            let host = host.clone();
+            let path = host_file(&trace_path, &host.id, "txt");
+            let stopped = stopped.clone();
+            samplers.spawn(async move { sample(&host, files, period, &path, stopped).await });
+        }
+        Ok(Self { stop, samplers })
+    }
+
+    /// Stop sampling, letting a snapshot that is being taken finish.
+    pub async fn stop(mut self) {
+        _ = self.stop.send(true);
+        while self.samplers.join_next().await.is_some() {}
+    }
+}
+
+async fn sample(
+    host: &Host,
+    files: &[&str],
+    period: Duration,
+    path: &Path,
+    mut stopped: watch::Receiver<bool>,
+) {
+    let mut ticks = interval(period);
+    ticks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    let mut snapshots = 0;
+    loop {
+        select! {
+            _ = stopped.changed() => break,
+            _ = ticks.tick() => {}
+        }
+        let time = unix_time(SystemTime::now());
+        let snapshot = match wifi::rate_control_snapshot(host, files).await {
+            Ok(Some(snapshot)) => snapshot,
+            Ok(None) if snapshots == 0 => {
+                warn!(
+                    host = host.id,
+                    "No rate control statistics found in debugfs, not tracing"
+                );
+                return;
+            }
+            Ok(None) => continue,
+            Err(err) => {
+                warn!(host = host.id, "Could not sample rate control: {err:?}");
+                continue;
+            }
+        };
+        if let Err(err) = append(path, time, &snapshot).await {
+            warn!(host = host.id, "Could not save rate control trace: {err:?}");
+            return;
+        }
+        snapshots += 1;
+    }
+    debug!(host = host.id, "Took {snapshots} rate control snapshots");
+}
+
+/// Append a snapshot, preceded by a `# <time>` line with the time in seconds since the unix
+/// epoch.
+async fn append(path: &Path, time: f64, snapshot: &[u8]) -> anyhow::Result<()> {
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .await
+        .context("failed to open trace")?;
+    let mut entry = format!("# {time:.3}\n").into_bytes();
+    entry.extend_from_slice(snapshot);
+    file.write_all(&entry)
+        .await
+        .context("failed to write trace")
+}
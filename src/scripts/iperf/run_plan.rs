@@ -0,0 +1,170 @@
+//! What a single run of the experiment does, built before anything is changed on the hosts so it
+//! can also be printed with `--dry-run`.
+
+use std::{collections::BTreeMap, fmt};
+
+use serde::Serialize;
+
+use crate::{
+    hosts::HostId,
+    mac::MacAddr,
+    utils::{sanitize_file_stem, RemoteCmd},
+};
+
+use super::{client_command, server_command, Endpoints, IperfArgs, TrafficSchedule};
+
+/// The roles of the hosts and the commands they run in a single run.
+#[derive(Debug, Clone, Serialize)]
+pub struct RunPlan {
+    pub access_point: HostId,
+    pub server: HostId,
+    /// The address the servers listen on and the clients connect to.
+    pub server_ip: String,
+    /// The command of every server, one per client.
+    pub servers: Vec<RemoteCmd>,
+    pub clients: BTreeMap<HostId, PlannedClient>,
+    /// How the traffic is captured, `None` with `--no-monitor`.
+    pub monitor: Option<PlannedMonitor>,
+    /// The files written to the output directory of the run.
+    pub outputs: Vec<String>,
+}
+
+/// How a client takes part in the run.
+#[derive(Debug, Clone, Serialize)]
+pub struct PlannedClient {
+    /// The name of the traffic group of the client.
+    pub group: String,
+    /// The port of the server the client connects to.
+    pub port: u16,
+    /// The offered load of the client in bits per second, 0 if unlimited.
+    pub offered_load: u64,
+    pub command: RemoteCmd,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct PlannedMonitor {
+    pub hosts: Vec<HostId>,
+    pub frequency: u32,
+    pub bandwidth: u32,
+    /// The BSSID to follow, `None` if it is taken from the access point.
+    pub bssid: Option<MacAddr>,
+    /// How long the monitors capture, in seconds.
+    pub duration: f64,
+    /// How long the measured traffic lasts within the capture, in seconds.
+    pub window: f64,
+}
+
+impl RunPlan {
+    /// Plan a run with the resolved `endpoints`. Nothing is run on the hosts.
+    pub fn new(args: &IperfArgs, endpoints: &Endpoints, schedule: &TrafficSchedule) -> Self {
+        let groups = args.traffic_groups();
+        let mut clients = BTreeMap::new();
+        for (host, port) in endpoints.senders.iter().zip(endpoints.ports.clone()) {
+            let group = groups
+                .iter()
+                .find(|g| g.clients.contains(&host.id))
+                .expect("every client is in a group");
+            let offered_load = group.client_load(&host.id);
+            let command = client_command(
+                args,
+                &host.extra_data,
+                &endpoints.server_ip,
+                port,
+                group.udp,
+                offered_load,
+                args.dscp_for(&host.id),
+            );
+            clients.insert(
+                host.id.clone(),
+                PlannedClient {
+                    group: group.name.clone(),
+                    port,
+                    offered_load,
+                    command,
+                },
+            );
+        }
+
+        let servers = endpoints
+            .ports
+            .clone()
+            .map(|port| server_command(endpoints.server_ifname(), &endpoints.server_ip, port, true))
+            .collect();
+
+        let network = &args.network;
+        let monitor = (!network.no_monitor).then(|| PlannedMonitor {
+            hosts: network.monitors.clone(),
+            frequency: network.frequency,
+            bandwidth: network.bandwidth,
+            bssid: network.bssid,
+            duration: network.capture_duration(schedule.window()).as_secs_f64(),
+            window: schedule.window().as_secs_f64(),
+        });
+
+        let mut outputs = vec!["arguments.ron".to_string(), "clients.ron".to_string()];
+        for client in clients.keys() {
+            outputs.push(format!("{}.txt", sanitize_file_stem(client)));
+        }
+        if args.json {
+            outputs.push("results.ron".to_string());
+        }
+        outputs.push("summary.ron".to_string());
+        if let Some(monitor) = &monitor {
+            outputs.push("monitor.ron".to_string());
+            outputs.extend(
+                monitor
+                    .hosts
+                    .iter()
+                    .map(|h| format!("{}.pcapng", sanitize_file_stem(h))),
+            );
+        }
+        if args.rc_trace.is_some() {
+            outputs.push("rc-trace/".to_string());
+        }
+
+        Self {
+            access_point: endpoints.access_point.id.clone(),
+            server: endpoints.server.id.clone(),
+            server_ip: endpoints.server_ip.clone(),
+            servers,
+            clients,
+            monitor,
+            outputs,
+        }
+    }
+}
+
+impl fmt::Display for RunPlan {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "access point: {}", self.access_point)?;
+        writeln!(f, "server: {} listening on {}", self.server, self.server_ip)?;
+        for command in &self.servers {
+            writeln!(f, "  {command}")?;
+        }
+        writeln!(f, "clients:")?;
+        for (host, client) in &self.clients {
+            writeln!(
+                f,
+                "  {host} ({} group, port {}): {}",
+                client.group, client.port, client.command
+            )?;
+        }
+        match &self.monitor {
+            Some(monitor) => writeln!(
+                f,
+                "monitors: {} on {} MHz with a width of {} MHz following {} for {:.0}s ({:.0}s of traffic)",
+                monitor.hosts.join(", "),
+                monitor.frequency,
+                monitor.bandwidth,
+                monitor.bssid.map_or(
+                    "the BSSID of the access point".to_string(),
+                    |bssid| bssid.to_string()
+                ),
+                monitor.duration,
+                monitor.window
+            )?,
+            None => writeln!(f, "monitors: none")?,
+        }
+        write!(f, "outputs: {}", self.outputs.join(", "))
+    }
+}
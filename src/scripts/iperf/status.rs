@@ -0,0 +1,273 @@
+//! What a failed run managed to save, so the partial results in its directory can be used.
+
+use std::{collections::BTreeMap, path::Path, process::Output};
+
+use anyhow::{anyhow, Context};
+use serde::{Deserialize, Serialize};
+use tokio::io::AsyncWriteExt;
+use tracing::error;
+
+use crate::{
+    hosts::HostId,
+    results::{Artifact, SchemaVersion},
+    utils::host_file,
+};
+
+/// What a run saved and what it could not, written to `status.ron` when the run fails.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct RunStatus {
+    #[serde(default)]
+    pub schema_version: SchemaVersion,
+    /// Why the run failed.
+    pub failures: Vec<String>,
+    /// Whether the output of every client that finished was saved.
+    pub clients: BTreeMap<HostId, Saved>,
+    /// Whether the captures were collected, `None` without a monitor.
+    pub captures: Option<Saved>,
+    /// The other files of the run that could not be written.
+    pub errors: Vec<String>,
+}
+
+/// Whether part of the results of a run was saved.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Saved {
+    Yes,
+    /// It was not saved, with why.
+    Failed(String),
+}
+
+impl<T> From<&anyhow::Result<T>> for Saved {
+    fn from(result: &anyhow::Result<T>) -> Self {
+        match result {
+            Ok(_) => Saved::Yes,
+            Err(err) => Saved::Failed(format!("{err:#}")),
+        }
+    }
+}
+
+impl RunStatus {
+    pub fn new() -> Self {
+        Self {
+            schema_version: Self::SCHEMA_VERSION,
+            ..Default::default()
+        }
+    }
+
+    /// Record whether the output of the client on `host` was saved.
+    pub fn client(&mut self, host: &HostId, result: anyhow::Result<()>) {
+        if let Err(err) = &result {
+            error!(host, "Could not save the iperf output: {err:#}");
+        }
+        self.clients.insert(host.clone(), Saved::from(&result));
+    }
+
+    /// Record whether the captures were collected, returning them if they were.
+    pub fn captures<T>(&mut self, result: anyhow::Result<T>) -> Option<T> {
+        self.captures = Some(Saved::from(&result));
+        result.ok()
+    }
+
+    /// Record the result of writing any other file, returning its value if it succeeded.
+    pub fn record<T>(&mut self, result: anyhow::Result<T>) -> Option<T> {
+        match result {
+            Ok(value) => Some(value),
+            Err(err) => {
+                error!("{err:#}");
+                self.errors.push(format!("{err:#}"));
+                None
+            }
+        }
+    }
+
+    /// What could not be saved, excluding the captures: a monitor that failed is a failure of
+    /// the run itself.
+    pub fn unsaved(&self) -> Vec<String> {
+        let clients = self.clients.iter().filter_map(|(host, saved)| match saved {
+            Saved::Yes => None,
+            Saved::Failed(err) => Some(format!("failed to save the output of `{host}`: {err}")),
+        });
+        clients.chain(self.errors.iter().cloned()).collect()
+    }
+
+    /// Whether everything the run produced was saved.
+    pub fn is_complete(&self) -> bool {
+        self.unsaved().is_empty()
+    }
+
+    /// The error the run ends with: why it failed and what could not be saved.
+    pub fn error(&self) -> anyhow::Error {
+        let unsaved = self.unsaved();
+        let mut reasons = self.failures.clone();
+        if !unsaved.is_empty() {
+            reasons.push(format!("not everything was saved: {}", unsaved.join(", ")));
+        }
+        anyhow!("{}", reasons.join("; "))
+    }
+}
+
+/// Write the output of the client on `host` to `<host>.txt`, and its error output to
+/// `<host>.stderr.txt` if there is any.
+pub async fn save_output(out_path: &Path, host: &str, output: &Output) -> anyhow::Result<()> {
+    let path = host_file(out_path, host, "txt");
+    write_new(&path, &output.stdout).await?;
+    if !output.stderr.is_empty() {
+        let path = host_file(out_path, host, "stderr.txt");
+        write_new(&path, &output.stderr).await?;
+    }
+    Ok(())
+}
+
+/// Write `contents` to `path`, which must not exist yet.
+async fn write_new(path: &Path, contents: &[u8]) -> anyhow::Result<()> {
+    let mut file = tokio::fs::File::create_new(path)
+        .await
+        .with_context(|| format!("failed to create {}", path.display()))?;
+    file.write_all(contents)
+        .await
+        .with_context(|| format!("failed to write {}", path.display()))?;
+    // The write may still be in progress after `write_all`, flushing waits for it to finish.
+    file.flush()
+        .await
+        .with_context(|| format!("failed to write {}", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::process::ExitStatusExt;
+
+    use super::*;
+
+    fn output(stdout: &str, stderr: &str) -> Output {
+        Output {
+            status: std::process::ExitStatus::from_raw(0),
+            stdout: stdout.as_bytes().to_vec(),
+            stderr: stderr.as_bytes().to_vec(),
+        }
+    }
+
+    #[test]
+    fn everything_saved() {
+        let mut status = RunStatus::new();
+        status.client(&"a".to_string(), Ok(()));
+        assert_eq!(status.record(Ok(3)), Some(3));
+        assert_eq!(status.captures(Ok("captures")), Some("captures"));
+        assert!(status.is_complete());
+        assert_eq!(status.captures, Some(Saved::Yes));
+        assert_eq!(status.clients["a"], Saved::Yes);
+    }
+
+    #[test]
+    fn unsaved_files_are_accumulated() {
+        let mut status = RunStatus::new();
+        status.client(&"a".to_string(), Ok(()));
+        status.client(
+            &"b".to_string(),
+            Err(anyhow!("disk full").context("failed to write b.txt")),
+        );
+        assert_eq!(
+            status.record::<()>(Err(anyhow!("failed to save clients"))),
+            None
+        );
+        assert_eq!(status.record(Ok(())), Some(()));
+        assert!(!status.is_complete());
+        assert_eq!(
+            status.unsaved(),
+            [
+                "failed to save the output of `b`: failed to write b.txt: disk full",
+                "failed to save clients",
+            ]
+        );
+    }
+
+    #[test]
+    fn failed_captures_are_not_unsaved() {
+        let mut status = RunStatus::new();
+        assert_eq!(
+            status.captures::<()>(Err(anyhow!("monitor `m` failed"))),
+            None
+        );
+        assert_eq!(
+            status.captures,
+            Some(Saved::Failed("monitor `m` failed".to_string()))
+        );
+        assert!(status.is_complete());
+    }
+
+    #[test]
+    fn error_lists_the_failures_and_what_was_not_saved() {
+        let mut status = RunStatus::new();
+        status
+            .failures
+            .push("iperf client on `a` exited with 1".to_string());
+        assert_eq!(
+            status.error().to_string(),
+            "iperf client on `a` exited with 1"
+        );
+
+        status.client(&"a".to_string(), Err(anyhow!("permission denied")));
+        status.record::<()>(Err(anyhow!("failed to save latency")));
+        assert_eq!(
+            status.error().to_string(),
+            "iperf client on `a` exited with 1; not everything was saved: failed to save the \
+             output of `a`: permission denied, failed to save latency"
+        );
+
+        let mut status = RunStatus::new();
+        status.record::<()>(Err(anyhow!("failed to save summary")));
+        assert_eq!(
+            status.error().to_string(),
+            "not everything was saved: failed to save summary"
+        );
+    }
+
+    #[tokio::test]
+    async fn output_is_saved_with_its_errors() {
+        let dir = tempfile::tempdir().unwrap();
+        save_output(dir.path(), "a", &output("out", ""))
+            .await
+            .unwrap();
+        save_output(dir.path(), "b", &output("out", "err"))
+            .await
+            .unwrap();
+        let read = |name: &str| std::fs::read_to_string(dir.path().join(name)).unwrap();
+        assert_eq!(read("a.txt"), "out");
+        assert!(!dir.path().join("a.stderr.txt").exists());
+        assert_eq!(read("b.txt"), "out");
+        assert_eq!(read("b.stderr.txt"), "err");
+    }
+
+    #[tokio::test]
+    async fn output_does_not_overwrite() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("a.txt"), "earlier").unwrap();
+        let err = save_output(dir.path(), "a", &output("out", ""))
+            .await
+            .unwrap_err();
+        assert!(
+            format!("{err:#}").starts_with("failed to create "),
+            "{err:#}"
+        );
+        assert_eq!(
+            std::fs::read_to_string(dir.path().join("a.txt")).unwrap(),
+            "earlier"
+        );
+
+        // Recorded like the run does, the failure ends up in the error of the run.
+        let mut status = RunStatus::new();
+        status.client(&"a".to_string(), Err(err));
+        assert!(!status.is_complete());
+        assert!(status
+            .error()
+            .to_string()
+            .contains("failed to save the output of `a`"));
+    }
+
+    #[tokio::test]
+    async fn output_into_a_missing_directory() {
+        let dir = tempfile::tempdir().unwrap();
+        let missing = dir.path().join("missing");
+        assert!(save_output(&missing, "a", &output("out", ""))
+            .await
+            .is_err());
+    }
+}
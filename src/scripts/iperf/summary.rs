@@ -0,0 +1,811 @@
+//! Aggregation of the results of a run into a summary.
+
+use std::{collections::BTreeMap, fmt::Write};
+
+use serde::{de::IgnoredAny, Deserialize, Deserializer, Serialize};
+use tracing::error;
+
+use crate::{
+    capture::CaptureStats,
+    driver::wifi::StationBitrate,
+    hosts::HostId,
+    monitor::MonitorMetadata,
+    results::{Artifact, SchemaVersion},
+    scripts::{
+        iperf::{ClientRecord, GoodputCheck, IperfResult, TrafficDirection, TrafficGroup},
+        latency::LoadedRtt,
+    },
+};
+
+/// A summary of a single run, written to `summary.ron`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RunSummary {
+    #[serde(default)]
+    pub schema_version: SchemaVersion,
+    /// The total offered load in bits per second, 0 if unlimited.
+    pub offered_load: u64,
+    /// The UDP datagram size in bytes, if set.
+    pub packet_size: Option<u32>,
+    /// The TCP maximum segment size in bytes, if set.
+    pub mss: Option<u32>,
+    pub clients: BTreeMap<HostId, ClientSummary>,
+    /// The totals over all clients, per direction.
+    pub totals: Vec<DirectionSummary>,
+    /// The totals per traffic group, if the clients were split into groups.
+    pub groups: BTreeMap<String, GroupSummary>,
+    /// Statistics of the capture of every monitor.
+    pub monitors: BTreeMap<HostId, Option<CaptureStats>>,
+    /// The MCS configured on the access point and whether it was verified.
+    pub bitrates: Option<BitrateCheck>,
+    /// The round trip times while idle and under load, if pinged alongside the traffic.
+    pub latency: Option<BTreeMap<HostId, LoadedRtt>>,
+    /// The goodput of the clients compared with the captures, with `--analyze`.
+    pub goodput_check: Option<GoodputCheck>,
+    pub outcome: Outcome,
+}
+
+/// The result of verifying the MCS that was set on the access point.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BitrateCheck {
+    /// The requested bitrate mask, `None` for automatic MCS.
+    pub requested: Option<String>,
+    /// The mask in use before the requested one was set, which is restored after the run.
+    /// `None` if rate control could pick any bitrate.
+    #[serde(default, deserialize_with = "previous_mask")]
+    pub previous: Option<String>,
+    /// The bitrates of the stations after the clients ran.
+    pub stations: Vec<StationBitrate>,
+    /// Why the MCS could not be verified, if it could not.
+    pub error: Option<String>,
+}
+
+/// Summaries before version 2.0 held the bitrates of the stations as the previous MCS, which
+/// are not a mask and are dropped.
+fn previous_mask<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<String>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Previous {
+        Mask(Option<String>),
+        Bitrates(IgnoredAny),
+    }
+    Ok(match Previous::deserialize(deserializer)? {
+        Previous::Mask(mask) => mask,
+        Previous::Bitrates(_) => None,
+    })
+}
+
+/// Whether a run succeeded, and why not.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Outcome {
+    /// Problems that make the run count as failed.
+    pub failures: Vec<String>,
+    /// Client failures that were ignored because of `--tolerate-client-failures`.
+    pub tolerated: Vec<String>,
+}
+
+impl Outcome {
+    /// Start from the failures of the clients, which are only tolerated with
+    /// `--tolerate-client-failures`.
+    pub fn of_clients(failures: Vec<String>, tolerate: bool) -> Self {
+        if tolerate {
+            Self {
+                failures: Vec::new(),
+                tolerated: failures,
+            }
+        } else {
+            Self {
+                failures,
+                tolerated: Vec::new(),
+            }
+        }
+    }
+
+    /// Fail the run for every capture of `monitor` that completed without any packets.
+    pub fn check_captures(&mut self, monitor: &MonitorMetadata) {
+        let empty = monitor.captures.iter().filter(|c| {
+            c.stats
+                .as_ref()
+                .is_some_and(|s| s.bytes == 0 || s.packets == Some(0))
+        });
+        for capture in empty {
+            error!(host = capture.host, "Capture is empty");
+            self.failures
+                .push(format!("capture on `{}` is empty", capture.host));
+        }
+    }
+
+    pub fn is_success(&self) -> bool {
+        self.failures.is_empty()
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ClientSummary {
+    /// The offered load of this client in bits per second, 0 if unlimited.
+    pub offered_load: u64,
+    /// How often the client was retried. Retried clients measured a later window than the others.
+    pub retries: usize,
+    pub directions: Vec<DirectionSummary>,
+}
+
+/// The traffic of the clients of a single group.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GroupSummary {
+    pub udp: bool,
+    /// The total offered load of the group in bits per second, 0 if unlimited.
+    pub offered_load: u64,
+    /// The totals over the clients of the group, per direction.
+    pub totals: Vec<DirectionSummary>,
+}
+
+/// The measured traffic in one direction, for a single client or all of them.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DirectionSummary {
+    pub direction: TrafficDirection,
+    /// The achieved throughput in bits per second.
+    pub goodput: f64,
+    pub retransmits: Option<u64>,
+    pub lost_packets: Option<u64>,
+    pub packets: Option<u64>,
+    pub lost_percent: Option<f64>,
+    pub jitter_ms: Option<f64>,
+}
+
+/// Settings of the run that are included in the summary.
+pub struct SummaryInput<'a> {
+    pub offered_load: u64,
+    /// The explicit traffic groups of the run, empty if all clients form a single group.
+    pub groups: &'a [TrafficGroup],
+    pub packet_size: Option<u32>,
+    pub mss: Option<u32>,
+    pub results: &'a BTreeMap<HostId, IperfResult>,
+    pub clients: &'a BTreeMap<HostId, ClientRecord>,
+    pub monitor: Option<&'a MonitorMetadata>,
+    pub bitrates: Option<BitrateCheck>,
+    pub latency: Option<BTreeMap<HostId, LoadedRtt>>,
+    pub goodput_check: Option<GoodputCheck>,
+    pub outcome: Outcome,
+}
+
+/// Aggregate the parsed client results and capture statistics into a summary.
+pub fn summarize(input: SummaryInput<'_>) -> RunSummary {
+    let clients: BTreeMap<_, _> = input
+        .results
+        .iter()
+        .map(|(host, result)| {
+            let directions = result
+                .directions
+                .iter()
+                .filter_map(|d| {
+                    let s = d.summary.as_ref()?;
+                    Some(DirectionSummary {
+                        direction: d.direction,
+                        goodput: s.bits_per_second,
+                        retransmits: s.retransmits,
+                        lost_packets: s.lost_packets,
+                        packets: s.packets,
+                        lost_percent: s.lost_percent,
+                        jitter_ms: s.jitter_ms,
+                    })
+                })
+                .collect();
+            let record = input.clients.get(host);
+            let summary = ClientSummary {
+                offered_load: record.map_or(0, |c| c.offered_load),
+                retries: record.map_or(0, |c| c.failed_attempts.len()),
+                directions,
+            };
+            (host.clone(), summary)
+        })
+        .collect();
+
+    let totals = direction_totals(clients.values());
+    let groups = input
+        .groups
+        .iter()
+        .map(|group| {
+            let summary = GroupSummary {
+                udp: group.udp,
+                offered_load: group.total_throughput,
+                totals: direction_totals(
+                    clients
+                        .iter()
+                        .filter(|(host, _)| group.clients.contains(host))
+                        .map(|(_, c)| c),
+                ),
+            };
+            (group.name.clone(), summary)
+        })
+        .collect();
+
+    let monitors = input
+        .monitor
+        .map(|m| {
+            m.captures
+                .iter()
+                .map(|c| (c.host.clone(), c.stats.clone()))
+                .collect()
+        })
+        .unwrap_or_default();
+
+    RunSummary {
+        schema_version: RunSummary::SCHEMA_VERSION,
+        offered_load: input.offered_load,
+        packet_size: input.packet_size,
+        mss: input.mss,
+        clients,
+        totals,
+        groups,
+        monitors,
+        bitrates: input.bitrates,
+        latency: input.latency,
+        goodput_check: input.goodput_check,
+        outcome: input.outcome,
+    }
+}
+
+/// Sum the traffic of the clients in every direction any of them sent traffic in.
+fn direction_totals<'a>(
+    clients: impl Iterator<Item = &'a ClientSummary> + Clone,
+) -> Vec<DirectionSummary> {
+    [TrafficDirection::Uplink, TrafficDirection::Downlink]
+        .into_iter()
+        .filter_map(|direction| total(direction, clients.clone()))
+        .collect()
+}
+
+/// Sum the traffic of all clients in one direction. Returns `None` if no client sent traffic in
+/// that direction.
+fn total<'a>(
+    direction: TrafficDirection,
+    clients: impl Iterator<Item = &'a ClientSummary>,
+) -> Option<DirectionSummary> {
+    let entries: Vec<_> = clients
+        .flat_map(|c| c.directions.iter())
+        .filter(|d| d.direction == direction)
+        .collect();
+    if entries.is_empty() {
+        return None;
+    }
+
+    // Sums an optional field, which is only present if any of the clients reported it.
+    fn sum_opt(
+        entries: &[&DirectionSummary],
+        f: impl Fn(&DirectionSummary) -> Option<u64>,
+    ) -> Option<u64> {
+        entries.iter().filter_map(|e| f(e)).reduce(|a, b| a + b)
+    }
+
+    let lost_packets = sum_opt(&entries, |e| e.lost_packets);
+    let packets = sum_opt(&entries, |e| e.packets);
+    let jitters: Vec<_> = entries.iter().filter_map(|e| e.jitter_ms).collect();
+    Some(DirectionSummary {
+        direction,
+        goodput: entries.iter().map(|e| e.goodput).sum(),
+        retransmits: sum_opt(&entries, |e| e.retransmits),
+        lost_percent: match (lost_packets, packets) {
+            (Some(lost), Some(total)) if total > 0 => Some(lost as f64 / total as f64 * 100.0),
+            _ => None,
+        },
+        lost_packets,
+        packets,
+        jitter_ms: (!jitters.is_empty())
+            .then(|| jitters.iter().sum::<f64>() / jitters.len() as f64),
+    })
+}
+
+impl RunSummary {
+    /// Format the summary as a human-readable table.
+    pub fn table(&self) -> String {
+        let mut out = String::new();
+        let opt = |v: Option<String>| v.unwrap_or_else(|| "-".to_string());
+
+        _ = writeln!(
+            out,
+            "{:<16} {:<9} {:>12} {:>12} {:>8} {:>8} {:>10}",
+            "client", "direction", "offered", "goodput", "retrans", "loss %", "jitter ms"
+        );
+        let rows = self
+            .clients
+            .iter()
+            .flat_map(|(host, c)| {
+                c.directions
+                    .iter()
+                    .map(move |d| (host.as_str(), c.offered_load, d))
+            })
+            .chain(self.groups.iter().flat_map(|(name, g)| {
+                g.totals
+                    .iter()
+                    .map(move |d| (name.as_str(), g.offered_load, d))
+            }))
+            .chain(self.totals.iter().map(|d| ("total", self.offered_load, d)));
+        for (name, offered, d) in rows {
+            _ = writeln!(
+                out,
+                "{:<16} {:<9} {:>12} {:>12} {:>8} {:>8} {:>10}",
+                name,
+                format!("{:?}", d.direction),
+                if offered == 0 {
+                    "unlimited".to_string()
+                } else {
+                    format!("{:.2} Mbit/s", offered as f64 / 1_000_000.0)
+                },
+                format!("{:.2} Mbit/s", d.goodput / 1_000_000.0),
+                opt(d.retransmits.map(|v| v.to_string())),
+                opt(d.lost_percent.map(|v| format!("{v:.2}"))),
+                opt(d.jitter_ms.map(|v| format!("{v:.3}"))),
+            );
+        }
+
+        for (host, client) in &self.clients {
+            if client.retries > 0 {
+                _ = writeln!(out, "client {host}: retried {} time(s)", client.retries);
+            }
+        }
+
+        for (host, rtt) in self.latency.iter().flatten() {
+            _ = writeln!(
+                out,
+                "latency {host}: p95 idle {} ms, loaded {} ms",
+                opt(rtt.idle_p95.map(|v| format!("{v:.3}"))),
+                opt(rtt.loaded_p95.map(|v| format!("{v:.3}"))),
+            );
+        }
+
+        if let Some(check) = &self.goodput_check {
+            for (host, directions) in &check.clients {
+                for d in directions {
+                    _ = writeln!(
+                        out,
+                        "captured {host} {:?}: {} Mbit/s, ratio {}{}",
+                        d.direction,
+                        opt(d.observed.map(|v| format!("{:.2}", v / 1_000_000.0))),
+                        opt(d.ratio.map(|v| format!("{v:.2}"))),
+                        if d.flagged { " (flagged)" } else { "" },
+                    );
+                }
+            }
+        }
+
+        for (host, stats) in &self.monitors {
+            match stats {
+                Some(stats) => {
+                    _ = writeln!(
+                        out,
+                        "monitor {host}: {} bytes, {} packets, {} dropped",
+                        stats.bytes,
+                        opt(stats.packets.map(|v| v.to_string())),
+                        opt(stats.dropped.map(|v| v.to_string())),
+                    )
+                }
+                None => _ = writeln!(out, "monitor {host}: capture failed"),
+            }
+        }
+
+        for failure in &self.outcome.failures {
+            _ = writeln!(out, "failure: {failure}");
+        }
+        for tolerated in &self.outcome.tolerated {
+            _ = writeln!(out, "tolerated: {tolerated}");
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::{
+        monitor::CaptureMetadata,
+        scripts::iperf::{DirectionResult, Summary},
+        utils::RemoteCmd,
+    };
+
+    /// The summary of a direction with a goodput in Mbit/s.
+    pub(crate) fn measured(mbits: f64) -> Summary {
+        Summary {
+            seconds: 10.0,
+            bytes: (mbits * 1e6 * 10.0 / 8.0) as u64,
+            bits_per_second: mbits * 1e6,
+            retransmits: None,
+            jitter_ms: None,
+            lost_packets: None,
+            packets: None,
+            lost_percent: None,
+        }
+    }
+
+    fn tcp(mbits: f64, retransmits: u64) -> Summary {
+        Summary {
+            retransmits: Some(retransmits),
+            ..measured(mbits)
+        }
+    }
+
+    fn udp(mbits: f64, lost: u64, packets: u64, jitter_ms: f64) -> Summary {
+        Summary {
+            lost_packets: Some(lost),
+            packets: Some(packets),
+            lost_percent: Some(lost as f64 / packets as f64 * 100.0),
+            jitter_ms: Some(jitter_ms),
+            ..measured(mbits)
+        }
+    }
+
+    pub(crate) fn result(
+        protocol: &str,
+        directions: Vec<(TrafficDirection, Option<Summary>)>,
+    ) -> IperfResult {
+        IperfResult {
+            protocol: Some(protocol.to_string()),
+            tos: None,
+            directions: directions
+                .into_iter()
+                .map(|(direction, summary)| DirectionResult {
+                    direction,
+                    intervals: Vec::new(),
+                    summary,
+                })
+                .collect(),
+            error: None,
+        }
+    }
+
+    fn record(offered_load: u64) -> ClientRecord {
+        ClientRecord::new(5201, offered_load, RemoteCmd::new("iperf3"))
+    }
+
+    fn input<'a>(
+        results: &'a BTreeMap<HostId, IperfResult>,
+        clients: &'a BTreeMap<HostId, ClientRecord>,
+    ) -> SummaryInput<'a> {
+        SummaryInput {
+            offered_load: 0,
+            groups: &[],
+            packet_size: None,
+            mss: None,
+            results,
+            clients,
+            monitor: None,
+            bitrates: None,
+            latency: None,
+            goodput_check: None,
+            outcome: Outcome::default(),
+        }
+    }
+
+    fn close(a: f64, b: f64) -> bool {
+        (a - b).abs() < 1e-9 * b.abs().max(1.0)
+    }
+
+    #[test]
+    fn tcp_clients_are_summed_per_direction() {
+        let results = BTreeMap::from([
+            (
+                "a".to_string(),
+                result(
+                    "TCP",
+                    vec![
+                        (TrafficDirection::Uplink, Some(tcp(40.0, 3))),
+                        (TrafficDirection::Downlink, Some(tcp(60.0, 1))),
+                    ],
+                ),
+            ),
+            (
+                "b".to_string(),
+                result("TCP", vec![(TrafficDirection::Uplink, Some(tcp(35.5, 0)))]),
+            ),
+        ]);
+        let mut retried = record(50_000_000);
+        retried.retry();
+        let clients = BTreeMap::from([
+            ("a".to_string(), record(50_000_000)),
+            ("b".to_string(), retried),
+        ]);
+        let summary = summarize(SummaryInput {
+            offered_load: 100_000_000,
+            mss: Some(1400),
+            ..input(&results, &clients)
+        });
+
+        assert_eq!(summary.offered_load, 100_000_000);
+        assert_eq!(summary.mss, Some(1400));
+        assert_eq!(summary.clients["a"].directions.len(), 2);
+        assert_eq!(summary.clients["a"].offered_load, 50_000_000);
+        assert_eq!(summary.clients["a"].retries, 0);
+        assert_eq!(summary.clients["b"].retries, 1);
+
+        let [uplink, downlink] = summary.totals.as_slice() else {
+            panic!("expected two directions, got {:?}", summary.totals);
+        };
+        assert_eq!(uplink.direction, TrafficDirection::Uplink);
+        assert!(close(uplink.goodput, 75.5e6));
+        assert_eq!(uplink.retransmits, Some(3));
+        assert_eq!(downlink.direction, TrafficDirection::Downlink);
+        assert!(close(downlink.goodput, 60e6));
+        assert_eq!(downlink.retransmits, Some(1));
+        assert_eq!(uplink.lost_percent, None);
+        assert_eq!(uplink.jitter_ms, None);
+    }
+
+    #[test]
+    fn udp_loss_is_taken_over_all_packets() {
+        let results = BTreeMap::from([
+            (
+                "a".to_string(),
+                result(
+                    "UDP",
+                    vec![(TrafficDirection::Downlink, Some(udp(10.0, 10, 1000, 0.2)))],
+                ),
+            ),
+            (
+                "b".to_string(),
+                result(
+                    "UDP",
+                    vec![(TrafficDirection::Downlink, Some(udp(9.0, 90, 3000, 0.4)))],
+                ),
+            ),
+        ]);
+        let summary = summarize(input(&results, &BTreeMap::new()));
+
+        let [total] = summary.totals.as_slice() else {
+            panic!("expected one direction, got {:?}", summary.totals);
+        };
+        assert_eq!(total.lost_packets, Some(100));
+        assert_eq!(total.packets, Some(4000));
+        // Not the mean of 1% and 3%, as the clients sent a different number of packets.
+        assert!(close(total.lost_percent.unwrap(), 2.5));
+        assert!(close(total.jitter_ms.unwrap(), 0.3));
+        assert_eq!(total.retransmits, None);
+        // Clients without a record offered an unlimited load.
+        assert_eq!(summary.clients["a"].offered_load, 0);
+    }
+
+    #[test]
+    fn directions_without_measurements_are_left_out() {
+        // A client whose intervals were all omitted, and one that failed before measuring.
+        let results = BTreeMap::from([
+            (
+                "a".to_string(),
+                result("TCP", vec![(TrafficDirection::Uplink, None)]),
+            ),
+            ("b".to_string(), result("TCP", Vec::new())),
+        ]);
+        let summary = summarize(input(&results, &BTreeMap::new()));
+        assert!(summary.clients["a"].directions.is_empty());
+        assert!(summary.clients["b"].directions.is_empty());
+        assert!(summary.totals.is_empty());
+    }
+
+    #[test]
+    fn groups_only_sum_their_own_clients() {
+        let results = BTreeMap::from([
+            (
+                "tcp1".to_string(),
+                result("TCP", vec![(TrafficDirection::Uplink, Some(tcp(30.0, 2)))]),
+            ),
+            (
+                "udp1".to_string(),
+                result(
+                    "UDP",
+                    vec![(TrafficDirection::Uplink, Some(udp(5.0, 1, 100, 0.1)))],
+                ),
+            ),
+        ]);
+        let groups = [
+            TrafficGroup {
+                name: "bulk".to_string(),
+                clients: vec!["tcp1".to_string()],
+                udp: false,
+                total_throughput: 0,
+            },
+            TrafficGroup {
+                name: "voice".to_string(),
+                clients: vec!["udp1".to_string(), "absent".to_string()],
+                udp: true,
+                total_throughput: 6_000_000,
+            },
+        ];
+        let summary = summarize(SummaryInput {
+            groups: &groups,
+            ..input(&results, &BTreeMap::new())
+        });
+
+        let bulk = &summary.groups["bulk"];
+        assert!(!bulk.udp);
+        assert!(close(bulk.totals[0].goodput, 30e6));
+        assert_eq!(bulk.totals[0].lost_packets, None);
+        let voice = &summary.groups["voice"];
+        assert!(voice.udp);
+        assert_eq!(voice.offered_load, 6_000_000);
+        assert!(close(voice.totals[0].goodput, 5e6));
+        assert_eq!(voice.totals[0].retransmits, None);
+        assert!(close(summary.totals[0].goodput, 35e6));
+    }
+
+    #[test]
+    fn capture_statistics_of_every_monitor() {
+        let monitor = captures(vec![
+            (
+                "mon1",
+                Some(CaptureStats {
+                    bytes: 4096,
+                    packets: Some(12),
+                    dropped: Some(0),
+                }),
+            ),
+            ("mon2", None),
+        ]);
+        let summary = summarize(SummaryInput {
+            monitor: Some(&monitor),
+            ..input(&BTreeMap::new(), &BTreeMap::new())
+        });
+
+        assert_eq!(summary.monitors["mon1"].as_ref().unwrap().packets, Some(12));
+        assert!(summary.monitors["mon2"].is_none());
+        let table = summary.table();
+        assert!(table.contains("monitor mon1: 4096 bytes, 12 packets, 0 dropped"));
+        assert!(table.contains("monitor mon2: capture failed"));
+    }
+
+    #[test]
+    fn table_has_a_row_per_client_direction_and_total() {
+        let results = BTreeMap::from([(
+            "a".to_string(),
+            result(
+                "UDP",
+                vec![(TrafficDirection::Downlink, Some(udp(10.0, 10, 1000, 0.25)))],
+            ),
+        )]);
+        let clients = BTreeMap::from([("a".to_string(), record(10_000_000))]);
+        let summary = summarize(input(&results, &clients));
+
+        let table = summary.table();
+        let lines = table.lines().collect::<Vec<_>>();
+        assert_eq!(lines.len(), 3, "{table}");
+        assert!(lines[1].starts_with("a "), "{table}");
+        assert!(lines[1].contains("10.00 Mbit/s"), "{table}");
+        assert!(lines[1].contains("1.00"), "{table}");
+        assert!(lines[1].contains("0.250"), "{table}");
+        assert!(lines[2].starts_with("total "), "{table}");
+        assert!(lines[2].contains("unlimited"), "{table}");
+    }
+
+    #[test]
+    fn previous_mask_round_trips() {
+        let check = BitrateCheck {
+            requested: Some("he-mcs-5 1:11".to_string()),
+            previous: Some("he-mcs-2 1:7".to_string()),
+            stations: Vec::new(),
+            error: None,
+        };
+        let parsed: BitrateCheck = ron::from_str(&ron::to_string(&check).unwrap()).unwrap();
+        assert_eq!(parsed.previous, check.previous);
+
+        let raw = r#"(requested: None, previous: None, stations: [], error: None)"#;
+        let parsed: BitrateCheck = ron::from_str(raw).unwrap();
+        assert_eq!(parsed.previous, None);
+    }
+
+    #[test]
+    fn previous_bitrates_of_old_summaries_are_dropped() {
+        let raw = r#"(
+            requested: Some("he-mcs-5 1:11"),
+            previous: [(station: "02:00:00:00:00:01", tx_bitrate: "100.0 MBit/s", mcs: Some(5))],
+            stations: [],
+            error: None,
+        )"#;
+        let parsed: BitrateCheck = ron::from_str(raw).unwrap();
+        assert_eq!(parsed.previous, None);
+        assert_eq!(parsed.requested.as_deref(), Some("he-mcs-5 1:11"));
+    }
+
+    fn captures(stats: Vec<(&str, Option<CaptureStats>)>) -> MonitorMetadata {
+        MonitorMetadata {
+            schema_version: MonitorMetadata::SCHEMA_VERSION,
+            frequency: 5180,
+            bandwidth: 80,
+            duration: 10.0,
+            window: None,
+            aids: Vec::new(),
+            captures: stats
+                .into_iter()
+                .map(|(host, stats)| CaptureMetadata {
+                    host: host.to_string(),
+                    start: 0.0,
+                    end: 10.0,
+                    stats,
+                    error: None,
+                })
+                .collect(),
+            partial: None,
+            clock_offsets: BTreeMap::new(),
+            merged: None,
+        }
+    }
+
+    fn stats(bytes: u64, packets: Option<u64>) -> Option<CaptureStats> {
+        Some(CaptureStats {
+            bytes,
+            packets,
+            dropped: None,
+        })
+    }
+
+    #[test]
+    fn client_failures_fail_the_run_unless_tolerated() {
+        let failures = vec!["client `a` exited with 1".to_string()];
+        let outcome = Outcome::of_clients(failures.clone(), false);
+        assert!(!outcome.is_success());
+        assert_eq!(outcome.failures, failures);
+        assert!(outcome.tolerated.is_empty());
+
+        let outcome = Outcome::of_clients(failures.clone(), true);
+        assert!(outcome.is_success());
+        assert_eq!(outcome.tolerated, failures);
+
+        assert!(Outcome::of_clients(Vec::new(), false).is_success());
+    }
+
+    #[test]
+    fn empty_captures_fail_the_run() {
+        let monitor = captures(vec![
+            ("full", stats(4096, Some(12))),
+            ("no-bytes", stats(0, None)),
+            ("no-packets", stats(1024, Some(0))),
+            // A capture that failed is reported by the monitor itself.
+            ("failed", None),
+        ]);
+        // Even with tolerated client failures.
+        let mut outcome = Outcome::of_clients(vec!["client `a` failed".to_string()], true);
+        outcome.check_captures(&monitor);
+        assert_eq!(
+            outcome.failures,
+            [
+                "capture on `no-bytes` is empty",
+                "capture on `no-packets` is empty"
+            ]
+        );
+        assert!(!outcome.is_success());
+    }
+
+    #[test]
+    fn outcome_is_written_to_the_summary_and_the_status() {
+        let mut outcome = Outcome::of_clients(vec!["client `b` failed".to_string()], false);
+        outcome.check_captures(&captures(vec![("mon", stats(0, None))]));
+        let summary = summarize(SummaryInput {
+            outcome,
+            ..input(&BTreeMap::new(), &BTreeMap::new())
+        });
+
+        let dump = ron::to_string(&summary).unwrap();
+        let parsed: RunSummary = ron::from_str(&dump).unwrap();
+        assert_eq!(parsed.outcome.failures.len(), 2);
+        let table = summary.table();
+        assert!(table.contains("failure: client `b` failed"), "{table}");
+        assert!(
+            table.contains("failure: capture on `mon` is empty"),
+            "{table}"
+        );
+
+        // A run that did not succeed ends with its failures as the error.
+        let mut status = crate::scripts::iperf::RunStatus::new();
+        status.failures = summary.outcome.failures;
+        assert_eq!(
+            status.error().to_string(),
+            "client `b` failed; capture on `mon` is empty"
+        );
+    }
+
+    #[test]
+    fn tolerated_failures_are_listed_apart() {
+        let summary = summarize(SummaryInput {
+            outcome: Outcome::of_clients(vec!["client `c` failed".to_string()], true),
+            ..input(&BTreeMap::new(), &BTreeMap::new())
+        });
+        assert!(summary.outcome.is_success());
+        let table = summary.table();
+        assert!(table.contains("tolerated: client `c` failed"), "{table}");
+        assert!(!table.contains("failure:"), "{table}");
+    }
+}
@@ -0,0 +1,462 @@
+//! A timeline of a run with a row per second, combining the iperf intervals of the clients, the
+//! frames the monitors captured and the signal strength of the clients, written to `timeline.csv`
+//! with `--timeline`.
+//!
+//! Everything is aligned on the clock of the controller. The intervals of a client are relative to
+//! when the controller started it, the captures are shifted by the clock offset of their monitor
+//! and the link samples are taken by the controller.
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt::Write,
+    path::Path,
+};
+
+use anyhow::Context;
+use tracing::warn;
+
+use crate::{
+    capture::analysis::{self, FrameCounts},
+    hosts::{HostId, Hosts},
+    monitor::MonitorMetadata,
+    scripts::iperf::{ClientRecord, IperfResult, LinkSample},
+    utils::host_file,
+};
+
+/// The inputs of a timeline.
+pub struct TimelineInput<'a> {
+    pub results: &'a BTreeMap<HostId, IperfResult>,
+    pub clients: &'a BTreeMap<HostId, ClientRecord>,
+    /// The frames every monitor captured per second by the controller clock.
+    pub frames: &'a BTreeMap<HostId, BTreeMap<i64, FrameCounts>>,
+    pub links: &'a BTreeMap<HostId, Vec<LinkSample>>,
+}
+
+/// The seconds from when the first client started until the last client finished.
+#[derive(Debug, Clone, Default)]
+pub struct Timeline {
+    pub clients: Vec<HostId>,
+    pub monitors: Vec<HostId>,
+    pub rows: Vec<TimelineRow>,
+}
+
+/// A single second of the timeline. Values are `None` if nothing was measured in that second.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TimelineRow {
+    /// The start of the second in seconds since the unix epoch.
+    pub time: i64,
+    /// The second of every client, in the same order as [Timeline::clients].
+    pub clients: Vec<ClientSecond>,
+    /// The frames every monitor captured, in the same order as [Timeline::monitors].
+    pub monitors: Vec<Option<FrameCounts>>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ClientSecond {
+    /// The goodput of the intervals in the second in bits per second, summed over the directions.
+    pub goodput: Option<f64>,
+    pub retransmits: Option<u64>,
+    /// The mean signal strength of the samples in the second in dBm.
+    pub rssi: Option<f64>,
+}
+
+impl Timeline {
+    /// Align the inputs on the seconds of the controller clock. An interval belongs to the second
+    /// its middle falls in. Intervals of the same client that fall in the same second, like those
+    /// of both directions, have their goodput and retransmits added up.
+    pub fn build(input: &TimelineInput<'_>) -> Self {
+        let windows: BTreeMap<&HostId, (f64, f64)> = input
+            .clients
+            .iter()
+            .filter_map(|(host, record)| Some((host, (record.start?, record.end?))))
+            .collect();
+        let Some(first) = windows.values().map(|w| w.0).reduce(f64::min) else {
+            return Self::default();
+        };
+        let last = windows.values().map(|w| w.1).fold(first, f64::max);
+        let (first, last) = (first.floor() as i64, last.ceil() as i64);
+
+        let clients: Vec<HostId> = input
+            .results
+            .keys()
+            .chain(input.links.keys())
+            .filter(|host| windows.contains_key(host))
+            .cloned()
+            .collect::<BTreeSet<_>>()
+            .into_iter()
+            .collect();
+        let mut seconds: Vec<BTreeMap<i64, ClientSecond>> = Vec::new();
+        for client in &clients {
+            let mut second: BTreeMap<i64, ClientSecond> = BTreeMap::new();
+            let (start, _) = windows[client];
+            let intervals = input
+                .results
+                .get(client)
+                .into_iter()
+                .flat_map(|r| r.directions.iter())
+                .flat_map(|d| d.intervals.iter());
+            for interval in intervals {
+                let middle = start + (interval.start + interval.end) / 2.0;
+                let entry = second.entry(middle.floor() as i64).or_default();
+                entry.goodput = Some(entry.goodput.unwrap_or_default() + interval.bits_per_second);
+                if let Some(retransmits) = interval.retransmits {
+                    entry.retransmits = Some(entry.retransmits.unwrap_or_default() + retransmits);
+                }
+            }
+            let mut signals: BTreeMap<i64, Vec<i32>> = BTreeMap::new();
+            for sample in input.links.get(client).into_iter().flatten() {
+                if let Some(signal) = sample.signal {
+                    signals
+                        .entry(sample.time.floor() as i64)
+                        .or_default()
+                        .push(signal);
+                }
+            }
+            for (time, signals) in signals {
+                let mean = signals.iter().sum::<i32>() as f64 / signals.len() as f64;
+                second.entry(time).or_default().rssi = Some(mean);
+            }
+            seconds.push(second);
+        }
+
+        let monitors: Vec<HostId> = input.frames.keys().cloned().collect();
+        let rows = (first..last)
+            .map(|time| TimelineRow {
+                time,
+                clients: seconds
+                    .iter()
+                    .map(|s| s.get(&time).cloned().unwrap_or_default())
+                    .collect(),
+                monitors: monitors
+                    .iter()
+                    .map(|m| input.frames[m].get(&time).copied())
+                    .collect(),
+            })
+            .collect();
+        Self {
+            clients,
+            monitors,
+            rows,
+        }
+    }
+
+    /// Format the timeline as CSV, with the columns of every client followed by those of every
+    /// monitor. Missing values are left empty.
+    pub fn csv(&self) -> String {
+        let mut csv = "time,second".to_string();
+        for client in &self.clients {
+            _ = write!(csv, ",{client}_goodput,{client}_retransmits,{client}_rssi");
+        }
+        for monitor in &self.monitors {
+            _ = write!(csv, ",{monitor}_frames,{monitor}_retry_ratio");
+        }
+        csv.push('\n');
+
+        let start = self.rows.first().map_or(0, |r| r.time);
+        let field = |v: Option<String>| v.unwrap_or_default();
+        for row in &self.rows {
+            _ = write!(csv, "{},{}", row.time, row.time - start);
+            for client in &row.clients {
+                _ = write!(
+                    csv,
+                    ",{},{},{}",
+                    field(client.goodput.map(|v| format!("{v:.0}"))),
+                    field(client.retransmits.map(|v| v.to_string())),
+                    field(client.rssi.map(|v| format!("{v:.1}"))),
+                );
+            }
+            for frames in &row.monitors {
+                _ = write!(
+                    csv,
+                    ",{},{}",
+                    field(frames.map(|f| f.frames.to_string())),
+                    field(
+                        frames
+                            .and_then(|f| f.retry_ratio())
+                            .map(|v| format!("{v:.3}"))
+                    ),
+                );
+            }
+            csv.push('\n');
+        }
+        csv
+    }
+}
+
+/// Count the frames in the capture of every monitor per second and write the timeline of the run
+/// to `timeline.csv`. Monitors whose capture can not be read are left out.
+pub async fn write_timeline(
+    out_path: &Path,
+    hosts: &Hosts,
+    results: &BTreeMap<HostId, IperfResult>,
+    clients: &BTreeMap<HostId, ClientRecord>,
+    monitor: Option<&MonitorMetadata>,
+    links: &BTreeMap<HostId, Vec<LinkSample>>,
+) -> anyhow::Result<()> {
+    let mut frames = BTreeMap::new();
+    if let Some(monitor) = monitor {
+        for capture in monitor.captures.iter().filter(|c| c.stats.is_some()) {
+            let offset = monitor.clock_offset(hosts, &capture.host).await;
+            let path = host_file(out_path, &capture.host, "pcapng");
+            match analysis::frame_timeline(&path, offset).await {
+                Ok(timeline) => _ = frames.insert(capture.host.clone(), timeline),
+                Err(err) => warn!(
+                    host = capture.host,
+                    "Could not analyze the capture: {err:#}"
+                ),
+            }
+        }
+    }
+
+    let timeline = Timeline::build(&TimelineInput {
+        results,
+        clients,
+        frames: &frames,
+        links,
+    });
+    tokio::fs::write(out_path.join("timeline.csv"), timeline.csv())
+        .await
+        .context("failed to write timeline")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        scripts::iperf::{DirectionResult, Interval, TrafficDirection},
+        utils::RemoteCmd,
+    };
+
+    fn record(start: f64, end: f64) -> ClientRecord {
+        ClientRecord {
+            port: 5201,
+            offered_load: 0,
+            command: RemoteCmd::new("iperf3"),
+            start: Some(start),
+            end: Some(end),
+            exit_status: Some(0),
+            failed_attempts: Vec::new(),
+        }
+    }
+
+    fn interval(start: f64, end: f64, bits_per_second: f64, retransmits: Option<u64>) -> Interval {
+        Interval {
+            start,
+            end,
+            bytes: (bits_per_second * (end - start) / 8.0) as u64,
+            bits_per_second,
+            omitted: false,
+            retransmits,
+            jitter_ms: None,
+            lost_packets: None,
+            packets: None,
+        }
+    }
+
+    fn result(directions: Vec<(TrafficDirection, Vec<Interval>)>) -> IperfResult {
+        IperfResult {
+            protocol: Some("TCP".to_string()),
+            tos: None,
+            directions: directions
+                .into_iter()
+                .map(|(direction, intervals)| DirectionResult {
+                    direction,
+                    intervals,
+                    summary: None,
+                })
+                .collect(),
+            error: None,
+        }
+    }
+
+    fn sample(time: f64, signal: Option<i32>) -> LinkSample {
+        LinkSample { time, signal }
+    }
+
+    fn build(
+        results: &BTreeMap<HostId, IperfResult>,
+        clients: &BTreeMap<HostId, ClientRecord>,
+        frames: &BTreeMap<HostId, BTreeMap<i64, FrameCounts>>,
+        links: &BTreeMap<HostId, Vec<LinkSample>>,
+    ) -> Timeline {
+        Timeline::build(&TimelineInput {
+            results,
+            clients,
+            frames,
+            links,
+        })
+    }
+
+    fn goodput(timeline: &Timeline, client: usize) -> Vec<Option<f64>> {
+        timeline
+            .rows
+            .iter()
+            .map(|row| row.clients[client].goodput)
+            .collect()
+    }
+
+    #[test]
+    fn intervals_are_aligned_on_the_start_of_their_client() {
+        let results = BTreeMap::from([
+            (
+                "a".to_string(),
+                result(vec![(
+                    TrafficDirection::Uplink,
+                    vec![
+                        interval(0.0, 1.0, 10.0, None),
+                        interval(1.0, 2.0, 20.0, None),
+                        interval(2.0, 3.0, 30.0, None),
+                    ],
+                )]),
+            ),
+            (
+                "b".to_string(),
+                result(vec![(
+                    TrafficDirection::Uplink,
+                    vec![interval(0.0, 1.0, 40.0, None)],
+                )]),
+            ),
+        ]);
+        // The middle of the first interval of `a` is at 100.8 and that of `b` at 102.1.
+        let clients = BTreeMap::from([
+            ("a".to_string(), record(100.3, 103.3)),
+            ("b".to_string(), record(101.6, 102.6)),
+        ]);
+        let timeline = build(&results, &clients, &BTreeMap::new(), &BTreeMap::new());
+
+        assert_eq!(timeline.clients, ["a", "b"]);
+        let times = timeline.rows.iter().map(|r| r.time).collect::<Vec<_>>();
+        assert_eq!(times, [100, 101, 102, 103]);
+        assert_eq!(
+            goodput(&timeline, 0),
+            [Some(10.0), Some(20.0), Some(30.0), None]
+        );
+        assert_eq!(goodput(&timeline, 1), [None, None, Some(40.0), None]);
+    }
+
+    #[test]
+    fn intervals_in_the_same_second_are_added_up() {
+        let results = BTreeMap::from([(
+            "a".to_string(),
+            result(vec![
+                (
+                    TrafficDirection::Uplink,
+                    vec![interval(0.0, 1.0, 10.0, Some(2))],
+                ),
+                (
+                    TrafficDirection::Downlink,
+                    vec![interval(0.0, 1.0, 5.0, Some(1))],
+                ),
+            ]),
+        )]);
+        let clients = BTreeMap::from([("a".to_string(), record(50.0, 51.0))]);
+        let timeline = build(&results, &clients, &BTreeMap::new(), &BTreeMap::new());
+
+        assert_eq!(timeline.rows.len(), 1);
+        assert_eq!(
+            timeline.rows[0].clients[0],
+            ClientSecond {
+                goodput: Some(15.0),
+                retransmits: Some(3),
+                rssi: None,
+            }
+        );
+    }
+
+    #[test]
+    fn signal_samples_are_averaged_per_second() {
+        let clients = BTreeMap::from([("a".to_string(), record(10.5, 12.5))]);
+        let links = BTreeMap::from([(
+            "a".to_string(),
+            vec![
+                sample(10.6, Some(-50)),
+                sample(10.9, Some(-53)),
+                sample(11.2, None),
+                sample(12.1, Some(-60)),
+            ],
+        )]);
+        let timeline = build(&BTreeMap::new(), &clients, &BTreeMap::new(), &links);
+
+        // A client with only link samples is still on the timeline.
+        assert_eq!(timeline.clients, ["a"]);
+        let rssi = timeline
+            .rows
+            .iter()
+            .map(|row| row.clients[0].rssi)
+            .collect::<Vec<_>>();
+        assert_eq!(rssi, [Some(-51.5), None, Some(-60.0)]);
+    }
+
+    #[test]
+    fn frames_of_the_monitors_are_matched_by_second() {
+        let clients = BTreeMap::from([("a".to_string(), record(20.0, 22.0))]);
+        let counts = FrameCounts {
+            frames: 100,
+            retries: 25,
+        };
+        // Frames outside of the traffic are not on the timeline.
+        let frames = BTreeMap::from([(
+            "mon".to_string(),
+            BTreeMap::from([(19, counts), (21, counts), (22, counts)]),
+        )]);
+        let timeline = build(&BTreeMap::new(), &clients, &frames, &BTreeMap::new());
+
+        assert_eq!(timeline.monitors, ["mon"]);
+        let monitors = timeline
+            .rows
+            .iter()
+            .map(|row| row.monitors.clone())
+            .collect::<Vec<_>>();
+        assert_eq!(monitors, [vec![None], vec![Some(counts)]]);
+        // The client has no results or samples, so it has no columns.
+        assert!(timeline.clients.is_empty());
+    }
+
+    #[test]
+    fn clients_that_did_not_run_are_left_out() {
+        let results = BTreeMap::from([(
+            "a".to_string(),
+            result(vec![(
+                TrafficDirection::Uplink,
+                vec![interval(0.0, 1.0, 10.0, None)],
+            )]),
+        )]);
+        let mut unstarted = record(0.0, 0.0);
+        unstarted.start = None;
+        let clients = BTreeMap::from([("a".to_string(), unstarted)]);
+        let timeline = build(&results, &clients, &BTreeMap::new(), &BTreeMap::new());
+        assert!(timeline.clients.is_empty());
+        assert!(timeline.rows.is_empty());
+    }
+
+    #[test]
+    fn csv_with_missing_values() {
+        let results = BTreeMap::from([(
+            "a".to_string(),
+            result(vec![(
+                TrafficDirection::Uplink,
+                vec![interval(0.0, 1.0, 1234.4, Some(0))],
+            )]),
+        )]);
+        let clients = BTreeMap::from([("a".to_string(), record(7.0, 9.0))]);
+        let frames = BTreeMap::from([(
+            "mon".to_string(),
+            BTreeMap::from([(
+                8,
+                FrameCounts {
+                    frames: 8,
+                    retries: 1,
+                },
+            )]),
+        )]);
+        let links = BTreeMap::from([("a".to_string(), vec![sample(8.5, Some(-42))])]);
+        let timeline = build(&results, &clients, &frames, &links);
+
+        assert_eq!(
+            timeline.csv(),
+            "time,second,a_goodput,a_retransmits,a_rssi,mon_frames,mon_retry_ratio\n\
+             7,0,1234,0,,,\n\
+             8,1,,,-42.0,8,0.125\n"
+        );
+    }
+}
@@ -0,0 +1,383 @@
+//! Shared machinery for scripts that run the same experiment multiple times.
+
+use std::{
+    future::Future,
+    io::ErrorKind,
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+
+use anyhow::Context;
+use clap::Args;
+use ron::ser::{to_string_pretty, PrettyConfig};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tokio::time::sleep;
+use tracing::{error, info};
+
+use crate::cancel::{Aborted, TimedOut};
+
+/// Arguments controlling how often an experiment is repeated.
+#[derive(Args, Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct IterationArgs {
+    /// How many times to repeat the experiment.
+    ///
+    /// Each repetition is written to its own `run-<n>` subdirectory of the output path.
+    #[clap(long, default_value = "1")]
+    pub repeat: u32,
+    /// How long to wait between iterations in seconds.
+    #[clap(long, default_value = "0")]
+    pub repeat_cooldown: u64,
+    /// Stop at the first failure instead of continuing with the next iteration.
+    ///
+    /// Scripts also use this to stop an iteration as soon as a part of it fails, for example an
+    /// iperf client.
+    #[clap(long)]
+    pub fail_fast: bool,
+}
+
+impl Default for IterationArgs {
+    /// Run once, like the defaults of the command line.
+    fn default() -> Self {
+        Self {
+            repeat: 1,
+            repeat_cooldown: 0,
+            fail_fast: false,
+        }
+    }
+}
+
+/// A single iteration of an experiment.
+#[derive(Debug, Clone)]
+pub struct Iteration<T> {
+    /// Name of the iteration, also used as the name of its output directory.
+    pub name: String,
+    /// Data specific to this iteration, for example the parameters that are swept over.
+    pub data: T,
+}
+
+/// The recorded outcome of an iteration, written to the `runs.ron` index.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct IterationStatus {
+    pub name: String,
+    pub status: Status,
+    /// How long the iteration took in seconds.
+    pub duration: f64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum Status {
+    Completed,
+    Failed(String),
+    /// The run was stopped by the user.
+    Aborted,
+    /// The run exceeded `--max-runtime`.
+    TimedOut,
+}
+
+/// An error an iteration can return to stop the remaining iterations, even without
+/// `--fail-fast`.
+#[derive(Debug)]
+pub struct StopIterations(pub String);
+
+impl std::fmt::Display for StopIterations {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for StopIterations {}
+
+impl Status {
+    /// How a run with `result` finished.
+    pub fn of<T>(result: &anyhow::Result<T>) -> Self {
+        match result {
+            Ok(_) => Status::Completed,
+            Err(err) if err.is::<Aborted>() => Status::Aborted,
+            Err(err) if err.is::<TimedOut>() => Status::TimedOut,
+            Err(err) => Status::Failed(format!("{err:#}")),
+        }
+    }
+
+    pub fn is_completed(&self) -> bool {
+        matches!(self, Status::Completed)
+    }
+
+    /// The name of the status without its details, like `failed`.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Status::Completed => "completed",
+            Status::Failed(_) => "failed",
+            Status::Aborted => "aborted",
+            Status::TimedOut => "timed-out",
+        }
+    }
+}
+
+impl IterationArgs {
+    /// The iterations for the configured number of repetitions, named `run-01`, `run-02`, ...
+    pub fn repetitions(&self) -> Vec<Iteration<u32>> {
+        let width = self.repeat.to_string().len().max(2);
+        (1..=self.repeat)
+            .map(|n| Iteration {
+                name: format!("run-{n:0width$}"),
+                data: n,
+            })
+            .collect()
+    }
+}
+
+/// The marker written to `done.ron` in the output directory of every finished iteration, so a
+/// later run with `--resume` can skip the iterations that completed.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Marker<R> {
+    pub status: Status,
+    /// How long the iteration took in seconds.
+    pub duration: f64,
+    /// The [fingerprint] of the arguments the iteration ran with.
+    pub fingerprint: String,
+    /// What the iteration returned, used to combine the results of all iterations.
+    pub summary: Option<R>,
+}
+
+/// Only the fingerprint of a [Marker], to check it without knowing the type of the summary.
+#[derive(Deserialize)]
+struct MarkerFingerprint {
+    fingerprint: String,
+}
+
+/// Hash the arguments of an experiment, so iterations are only resumed with the same arguments.
+pub fn fingerprint(params: &impl Serialize) -> anyhow::Result<String> {
+    let params = ron::to_string(params).context("failed to serialize arguments")?;
+    Ok(Sha256::digest(params.as_bytes())
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect())
+}
+
+/// Run `func` for every iteration, each with its own output directory inside `out_path`, and add
+/// what each iteration returned to `outputs` in the order of the iterations.
+///
+/// After every iteration the `runs.ron` index in `out_path` is updated and a [Marker] is written
+/// to `done.ron` in the directory of the iteration, so the progress can be found even if the
+/// controller is stopped halfway. Iterations that already have a marker of a completed run are
+/// skipped, using the summary in the marker, so an earlier run in `out_path` can be resumed. This
+/// is refused if those iterations ran with a different `fingerprint`. The directories of the other
+/// iterations are deleted before they run again.
+///
+/// A failed iteration is recorded and skipped unless `fail_fast` is set or the iteration returned
+/// [StopIterations], [Aborted] or [TimedOut], in which case the error is returned.
+pub async fn run_iterations<T, R, F, Fut>(
+    args: &IterationArgs,
+    out_path: &Path,
+    fingerprint: &str,
+    iterations: Vec<Iteration<T>>,
+    outputs: &mut Vec<R>,
+    mut func: F,
+) -> anyhow::Result<Vec<IterationStatus>>
+where
+    R: Serialize + DeserializeOwned,
+    F: FnMut(Iteration<T>, PathBuf) -> Fut,
+    Fut: Future<Output = anyhow::Result<R>>,
+{
+    tokio::fs::create_dir_all(out_path)
+        .await
+        .context("could not create output folder")?;
+
+    // Check all earlier iterations before running anything.
+    for iteration in &iterations {
+        let path = out_path.join(&iteration.name).join("done.ron");
+        let Some(earlier) = read_marker::<MarkerFingerprint>(&path).await? else {
+            continue;
+        };
+        if earlier.fingerprint != fingerprint {
+            anyhow::bail!(
+                "iteration {} in {} ran with different arguments, refusing to resume it",
+                iteration.name,
+                out_path.display()
+            );
+        }
+    }
+
+    let total = iterations.len();
+    let mut statuses = Vec::with_capacity(total);
+    let mut ran = false;
+    for (i, iteration) in iterations.into_iter().enumerate() {
+        let name = iteration.name.clone();
+        let run_path = out_path.join(&name);
+        let marker_path = run_path.join("done.ron");
+        if let Some(marker) = read_marker::<Marker<R>>(&marker_path).await? {
+            if let (Status::Completed, Some(summary)) = (&marker.status, marker.summary) {
+                info!(
+                    "Skipping iteration {name} ({}/{total}), it already completed",
+                    i + 1
+                );
+                outputs.push(summary);
+                statuses.push(IterationStatus {
+                    name,
+                    status: marker.status,
+                    duration: marker.duration,
+                });
+                continue;
+            }
+        }
+        // Start the iteration over without the files of an earlier attempt, which has no marker
+        // if the controller was killed during it.
+        match tokio::fs::remove_dir_all(&run_path).await {
+            Ok(()) => info!("Running iteration {name} again, it did not complete before"),
+            Err(err) if err.kind() == ErrorKind::NotFound => {}
+            Err(err) => {
+                return Err(err).with_context(|| format!("could not delete {}", run_path.display()))
+            }
+        }
+
+        if ran && args.repeat_cooldown > 0 {
+            info!("Cooling down for {}s", args.repeat_cooldown);
+            sleep(Duration::from_secs(args.repeat_cooldown)).await;
+        }
+        ran = true;
+
+        info!("Starting iteration {name} ({}/{total})", i + 1);
+        let start = Instant::now();
+        let result = func(iteration, run_path.clone()).await;
+
+        let status = Status::of(&result);
+        if let (Status::Failed(_), Err(err)) = (&status, &result) {
+            error!("Iteration {name} failed: {err:?}");
+        }
+        let duration = start.elapsed().as_secs_f64();
+        let (result, summary) = match result {
+            Ok(summary) => (Ok(()), Some(summary)),
+            Err(err) => (Err(err), None),
+        };
+        let marker = Marker {
+            status: status.clone(),
+            duration,
+            fingerprint: fingerprint.to_string(),
+            summary,
+        };
+        write_marker(&marker_path, &marker).await?;
+        outputs.extend(marker.summary);
+        statuses.push(IterationStatus {
+            name: name.clone(),
+            status,
+            duration,
+        });
+        write_index(out_path, &statuses).await?;
+
+        let stop = matches!(&result, Err(err) if err.is::<StopIterations>() || err.is::<Aborted>() || err.is::<TimedOut>());
+        if args.fail_fast || stop {
+            result.with_context(|| format!("iteration {name} failed"))?;
+        }
+    }
+    // The skipped iterations are only written here if no iteration had to run.
+    write_index(out_path, &statuses).await?;
+
+    let failed = statuses.iter().filter(|s| !s.status.is_completed()).count();
+    info!("Completed {} of {total} iterations", total - failed);
+    Ok(statuses)
+}
+
+/// Read the marker at `path`, `None` if the iteration has not finished before.
+async fn read_marker<M: DeserializeOwned>(path: &Path) -> anyhow::Result<Option<M>> {
+    match tokio::fs::read_to_string(path).await {
+        Ok(raw) => ron::from_str(&raw)
+            .with_context(|| format!("could not parse {}", path.display()))
+            .map(Some),
+        Err(err) if err.kind() == ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(err).with_context(|| format!("could not read {}", path.display())),
+    }
+}
+
+/// Write the marker of a finished iteration to `path`, creating its directory if the iteration
+/// did not.
+async fn write_marker<R: Serialize>(path: &Path, marker: &Marker<R>) -> anyhow::Result<()> {
+    if let Some(dir) = path.parent() {
+        tokio::fs::create_dir_all(dir)
+            .await
+            .context("could not create iteration folder")?;
+    }
+    let dump = to_string_pretty(marker, PrettyConfig::new())
+        .context("failed to serialize iteration marker")?;
+    tokio::fs::write(path, dump)
+        .await
+        .context("failed to write iteration marker")
+}
+
+/// Write the `runs.ron` index containing the status of every iteration so far.
+async fn write_index(out_path: &Path, statuses: &[IterationStatus]) -> anyhow::Result<()> {
+    let index = to_string_pretty(statuses, PrettyConfig::new())
+        .context("failed to serialize iteration index")?;
+    tokio::fs::write(out_path.join("runs.ron"), index)
+        .await
+        .context("failed to write iteration index")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(repeat: u32) -> IterationArgs {
+        IterationArgs {
+            repeat,
+            ..Default::default()
+        }
+    }
+
+    /// Run the iterations, creating a file that must not exist yet in each.
+    async fn run(out_path: &Path, repeat: u32) -> (anyhow::Result<Vec<IterationStatus>>, u32) {
+        let mut outputs = Vec::new();
+        let mut ran = 0;
+        let result = run_iterations(
+            &args(repeat),
+            out_path,
+            "fingerprint",
+            args(repeat).repetitions(),
+            &mut outputs,
+            |iteration, path| {
+                ran += 1;
+                async move {
+                    tokio::fs::create_dir_all(&path).await?;
+                    tokio::fs::File::create_new(path.join("output.txt")).await?;
+                    Ok(iteration.data)
+                }
+            },
+        )
+        .await;
+        (result, ran)
+    }
+
+    #[tokio::test]
+    async fn reruns_iteration_killed_without_marker() {
+        let dir = tempfile::tempdir().unwrap();
+        let leftover = dir.path().join("run-01");
+        std::fs::create_dir_all(&leftover).unwrap();
+        std::fs::write(leftover.join("output.txt"), "partial").unwrap();
+
+        let (result, ran) = run(dir.path(), 1).await;
+        assert!(result.unwrap()[0].status.is_completed());
+        assert_eq!(ran, 1);
+        assert_eq!(std::fs::read(leftover.join("output.txt")).unwrap(), b"");
+    }
+
+    #[tokio::test]
+    async fn skips_completed_iterations() {
+        let dir = tempfile::tempdir().unwrap();
+        let (result, ran) = run(dir.path(), 2).await;
+        assert_eq!(result.unwrap().len(), 2);
+        assert_eq!(ran, 2);
+
+        let (result, ran) = run(dir.path(), 2).await;
+        assert_eq!(result.unwrap().len(), 2);
+        assert_eq!(ran, 0);
+    }
+
+    #[test]
+    fn status_of_timed_out_run() {
+        let result: anyhow::Result<()> = Err(TimedOut.into());
+        assert!(matches!(Status::of(&result), Status::TimedOut));
+        let result: anyhow::Result<()> = Err(anyhow::anyhow!("broken"));
+        assert!(matches!(Status::of(&result), Status::Failed(err) if err == "broken"));
+    }
+}
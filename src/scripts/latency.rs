@@ -0,0 +1,279 @@
+//! Measure the round trip time from clients to the access point with ping.
+
+use std::{
+    collections::BTreeMap, fmt::Write, path::Path, process::Output, sync::Arc, time::Duration,
+};
+
+use anyhow::{anyhow, Context};
+use clap::Parser;
+use ron::ser::{to_string_pretty, PrettyConfig};
+use serde::{Deserialize, Serialize};
+use tracing::{error, info, warn};
+
+use crate::{
+    cancel::{Aborted, CancellationToken},
+    hosts::{Host, HostId, Hosts},
+    scripts::{
+        mark_failed,
+        monitoring::{run_captured, MonitorArgs},
+    },
+    utils::{host_file, run_all},
+};
+
+mod ping;
+
+pub use ping::{parse_ping, PingResult, PingSample, RttStats};
+
+#[derive(Parser, Debug, Clone, Serialize, Deserialize)]
+pub struct LatencyArgs {
+    /// The host id of the access point. Its IP address is pinged unless `--target` is set.
+    #[clap(long)]
+    pub ap: String,
+    /// The IP address to ping instead of the access point.
+    #[clap(long)]
+    #[serde(default)]
+    pub target: Option<String>,
+    /// The host ids that will run ping.
+    #[clap(long, required = true, value_delimiter = ',', num_args = 1..)]
+    pub clients: Vec<String>,
+    /// How long to ping in seconds.
+    #[clap(short = 'd', long, default_value = "10")]
+    #[serde(default = "default_duration")]
+    pub duration: u64,
+    /// The time between pings in seconds. Most systems only allow intervals below 0.2 seconds
+    /// for root.
+    #[clap(short = 'i', long, default_value = "0.2")]
+    #[serde(default = "default_interval")]
+    pub interval: f64,
+    #[command(flatten)]
+    pub network: MonitorArgs,
+}
+
+// The defaults of the arguments when they are read from a config file. They match the
+// defaults of the command line.
+fn default_duration() -> u64 {
+    10
+}
+
+fn default_interval() -> f64 {
+    0.2
+}
+
+pub async fn run(
+    args: LatencyArgs,
+    hosts: &Hosts,
+    out_path: &Path,
+    cancel: &CancellationToken,
+) -> anyhow::Result<()> {
+    tokio::fs::create_dir_all(out_path)
+        .await
+        .context("could not create output folder")?;
+    let args_dump =
+        to_string_pretty(&args, PrettyConfig::new()).context("failed to serialize args info")?;
+    tokio::fs::write(out_path.join("arguments.ron"), args_dump)
+        .await
+        .context("failed to save arguments")?;
+
+    let clients: Vec<_> = hosts
+        .get_many(&args.clients)
+        .map_err(|missing| anyhow!("no host with id {missing}"))?
+        .collect();
+    let access_point = hosts.get(&args.ap).context("access point id not found")?;
+
+    let bssid = args.network.check_access_point(access_point).await?;
+    let target = match &args.target {
+        Some(target) => target.clone(),
+        None => access_point
+            .ip_address()
+            .await
+            .context("failed to get IP address of access point")?,
+    };
+
+    cancel.check()?;
+    let mut monitor = args
+        .network
+        .start(
+            hosts,
+            &clients,
+            bssid,
+            Duration::from_secs(args.duration),
+            out_path,
+            None,
+        )
+        .await?;
+
+    info!("Pinging {target} from {} clients", clients.len());
+    let pings = run_all(clients.iter().copied(), |h| {
+        ping_command(
+            &target,
+            args.interval,
+            args.duration,
+            h.extra_data.interface_name(),
+        )
+    });
+    let outputs = match run_captured(&mut monitor, cancel, pings).await {
+        Ok(outputs) => outputs,
+        Err(err) => {
+            if let Some(monitor) = monitor {
+                if let Err(err) = monitor.stop_and_collect("ping failed").await {
+                    warn!("Could not collect the captures: {err:?}");
+                }
+            }
+            return Err(err);
+        }
+    };
+
+    let (results, mut failures) = collect_pings(out_path, outputs).await?;
+    write_results(out_path, &results).await?;
+
+    if let Some(monitor) = monitor {
+        info!("Waiting for capture to finish");
+        match monitor.wait_or_stop(cancel).await {
+            Ok(_) => {}
+            Err(err) if err.is::<Aborted>() => return Err(err),
+            Err(err) => {
+                error!("Monitor failed: {err:?}");
+                failures.push(format!("monitor failed: {err:#}"));
+            }
+        }
+    }
+
+    if !failures.is_empty() {
+        let reason = failures.join("; ");
+        mark_failed(out_path, &reason).await?;
+        anyhow::bail!("{reason}");
+    }
+    Ok(())
+}
+
+/// Pings that run alongside other traffic, to measure the latency under load.
+#[derive(Debug, Clone, Serialize)]
+pub struct PingPlan {
+    /// The hosts that run ping.
+    pub hosts: Vec<HostId>,
+    /// The IP address to ping. Defaults to the access point.
+    pub target: Option<String>,
+    /// The time between pings in seconds.
+    pub interval: f64,
+    /// How long to ping before the load starts and after it stops.
+    pub baseline: Duration,
+}
+
+/// Round trip times of a host while idle and while under load, in milliseconds.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LoadedRtt {
+    pub idle_samples: usize,
+    pub loaded_samples: usize,
+    pub idle_median: Option<f64>,
+    pub idle_p95: Option<f64>,
+    pub loaded_median: Option<f64>,
+    pub loaded_p95: Option<f64>,
+}
+
+/// Split the replies into those received while the load ran between `load_start` and `load_end`
+/// (in seconds since the unix epoch), and the idle ones received before or after.
+pub fn loaded_rtt(result: &PingResult, load_start: f64, load_end: f64) -> LoadedRtt {
+    let (loaded, idle): (Vec<&PingSample>, Vec<_>) = result
+        .samples
+        .iter()
+        .partition(|s| (load_start..=load_end).contains(&s.timestamp));
+    let mut loaded: Vec<f64> = loaded.iter().map(|s| s.rtt_ms).collect();
+    let mut idle: Vec<f64> = idle.iter().map(|s| s.rtt_ms).collect();
+    LoadedRtt {
+        idle_samples: idle.len(),
+        loaded_samples: loaded.len(),
+        idle_median: percentile(&mut idle, 50.0),
+        idle_p95: percentile(&mut idle, 95.0),
+        loaded_median: percentile(&mut loaded, 50.0),
+        loaded_p95: percentile(&mut loaded, 95.0),
+    }
+}
+
+/// The nearest-rank percentile of the values.
+pub fn percentile(values: &mut [f64], p: f64) -> Option<f64> {
+    if values.is_empty() {
+        return None;
+    }
+    values.sort_unstable_by(f64::total_cmp);
+    let rank = (p / 100.0 * values.len() as f64).ceil() as usize;
+    Some(values[rank.clamp(1, values.len()) - 1])
+}
+
+/// Build the command to ping `target` every `interval` seconds for `duration` seconds.
+pub fn ping_command(target: &str, interval: f64, duration: u64, ifname: Option<&str>) -> String {
+    let mut cmd = format!("ping -D -n -i {interval} -w {duration}");
+    if let Some(ifname) = ifname {
+        _ = write!(cmd, " -I {ifname}");
+    }
+    _ = write!(cmd, " {target}");
+    cmd
+}
+
+/// Save the raw output of every ping to `<host>.ping.txt` and parse it.
+///
+/// Returns the parsed results and the reasons of the clients that did not get any reply.
+pub async fn collect_pings(
+    out_path: &Path,
+    outputs: Vec<(Arc<Host>, Output)>,
+) -> anyhow::Result<(BTreeMap<HostId, PingResult>, Vec<String>)> {
+    let mut results = BTreeMap::new();
+    let mut failures = Vec::new();
+    for (host, output) in outputs {
+        let mut raw = output.stdout.clone();
+        raw.extend_from_slice(&output.stderr);
+        tokio::fs::write(host_file(out_path, &host.id, "ping.txt"), raw)
+            .await
+            .context("failed to save ping output")?;
+
+        let result = parse_ping(&String::from_utf8_lossy(&output.stdout));
+        match &result.rtt {
+            Some(rtt) => info!(
+                host = host.id,
+                "RTT min/avg/max {:.3}/{:.3}/{:.3} ms, {}% packet loss",
+                rtt.min,
+                rtt.avg,
+                rtt.max,
+                result.loss_percent.unwrap_or_default()
+            ),
+            None => error!(host = host.id, "No replies received"),
+        }
+        if result.samples.is_empty() {
+            failures.push(format!(
+                "ping on `{}` got no replies and exited with {}",
+                host.id, output.status
+            ));
+        }
+        results.insert(host.id.clone(), result);
+    }
+    Ok((results, failures))
+}
+
+/// Write the results to `latency.ron`, and the individual round trip times to `latency.csv`.
+pub async fn write_results(
+    out_path: &Path,
+    results: &BTreeMap<HostId, PingResult>,
+) -> anyhow::Result<()> {
+    let dump = to_string_pretty(results, PrettyConfig::new())
+        .context("failed to serialize ping results")?;
+    tokio::fs::write(out_path.join("latency.ron"), dump)
+        .await
+        .context("failed to save ping results")?;
+    tokio::fs::write(out_path.join("latency.csv"), latency_csv(results))
+        .await
+        .context("failed to save ping results")
+}
+
+/// A CSV table with a row for every reply.
+pub fn latency_csv(results: &BTreeMap<HostId, PingResult>) -> String {
+    let mut csv = "host,timestamp,seq,rtt_ms\n".to_string();
+    for (host, result) in results {
+        for sample in &result.samples {
+            _ = writeln!(
+                csv,
+                "{host},{:.6},{},{}",
+                sample.timestamp, sample.seq, sample.rtt_ms
+            );
+        }
+    }
+    csv
+}
@@ -0,0 +1,94 @@
+//! Parsing of `ping -D` output into round trip time series.
+
+use serde::Serialize;
+
+/// The parsed output of a single ping run.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct PingResult {
+    /// Every reply that was received, in order.
+    pub samples: Vec<PingSample>,
+    pub transmitted: Option<u32>,
+    pub received: Option<u32>,
+    pub loss_percent: Option<f64>,
+    /// The statistics reported by ping at the end of the run.
+    pub rtt: Option<RttStats>,
+}
+
+/// A single echo reply.
+#[derive(Debug, Clone, Serialize)]
+pub struct PingSample {
+    /// When the reply was received, in seconds since the unix epoch.
+    pub timestamp: f64,
+    pub seq: u32,
+    pub rtt_ms: f64,
+}
+
+/// Round trip time statistics in milliseconds.
+#[derive(Debug, Clone, Serialize)]
+pub struct RttStats {
+    pub min: f64,
+    pub avg: f64,
+    pub max: f64,
+    /// The standard deviation. Not reported by all ping implementations.
+    pub mdev: Option<f64>,
+}
+
+/// Parse the output of `ping -D`.
+///
+/// Replies look like `[1700000000.123456] 64 bytes from 10.0.0.1: icmp_seq=1 ttl=64 time=1.23 ms`
+/// and the run ends with a packet loss line and an RTT statistics line.
+pub fn parse_ping(output: &str) -> PingResult {
+    let mut result = PingResult::default();
+    for line in output.lines() {
+        let line = line.trim();
+        if let Some(sample) = parse_reply(line) {
+            result.samples.push(sample);
+        } else if line.contains("packets transmitted") {
+            // For example `10 packets transmitted, 9 received, 10% packet loss, time 9011ms`.
+            for part in line.split(',') {
+                let value = part.split_whitespace().next().unwrap_or_default();
+                if part.contains("transmitted") {
+                    result.transmitted = value.parse().ok();
+                } else if part.contains("received") {
+                    result.received = value.parse().ok();
+                } else if part.contains("packet loss") {
+                    result.loss_percent = value.trim_end_matches('%').parse().ok();
+                }
+            }
+        } else if line.contains("min/avg/max") {
+            let Some((_, values)) = line.split_once(" = ") else {
+                continue;
+            };
+            let values: Vec<f64> = values
+                .split_whitespace()
+                .next()
+                .unwrap_or_default()
+                .split('/')
+                .filter_map(|v| v.parse().ok())
+                .collect();
+            if let [min, avg, max, ..] = values[..] {
+                result.rtt = Some(RttStats {
+                    min,
+                    avg,
+                    max,
+                    mdev: values.get(3).copied(),
+                });
+            }
+        }
+    }
+    result
+}
+
+fn parse_reply(line: &str) -> Option<PingSample> {
+    let (timestamp, rest) = line.strip_prefix('[')?.split_once(']')?;
+    let field = |name: &str| {
+        rest.split_whitespace()
+            .find_map(|word| word.strip_prefix(name))
+            .map(str::to_string)
+    };
+    Some(PingSample {
+        timestamp: timestamp.parse().ok()?,
+        seq: field("icmp_seq=")?.parse().ok()?,
+        rtt_ms: field("time=")?.parse().ok()?,
+    })
+}
@@ -0,0 +1,165 @@
+//! List the hosts of the hosts file and whether they can be connected to. This runs before the
+//! hosts are connected to, so it also works when some of them are unreachable.
+
+use std::{fmt::Write, time::Duration};
+
+use anyhow::Context;
+use clap::{Parser, ValueEnum};
+use serde::{Deserialize, Serialize};
+
+use crate::hosts::{HostConfig, HostId, HostsConfig};
+
+#[derive(Parser, Debug, Clone, Serialize, Deserialize)]
+pub struct ListHostsArgs {
+    /// Only list the configuration of the hosts, without connecting to them.
+    #[clap(long)]
+    #[serde(default)]
+    pub offline: bool,
+    /// How long to try to connect to a host before reporting it as unreachable, in seconds.
+    #[clap(long, default_value_t = 5)]
+    #[serde(default = "default_connect_timeout")]
+    pub connect_timeout: u64,
+    /// How the hosts are printed.
+    #[clap(long, value_enum, default_value = "table")]
+    #[serde(default)]
+    pub format: ListFormat,
+}
+
+fn default_connect_timeout() -> u64 {
+    5
+}
+
+/// The output formats of `list-hosts`.
+#[derive(ValueEnum, Debug, Clone, Copy, Default, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ListFormat {
+    /// A table for reading.
+    #[default]
+    Table,
+    /// A JSON array with an object per host, for scripting.
+    Json,
+}
+
+/// A host from the hosts file, with its connection status if it was checked.
+#[derive(Debug, Clone, Serialize)]
+pub struct HostEntry {
+    pub id: HostId,
+    pub url: String,
+    /// The relays to jump through to reach the host, the first is connected to first.
+    pub relays: Vec<String>,
+    pub wifi_driver: Option<String>,
+    pub interface_name: Option<String>,
+    /// `None` with `--offline`.
+    pub status: Option<Status>,
+}
+
+/// Whether a host could be connected to.
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "state", rename_all = "kebab-case")]
+pub enum Status {
+    Connected { os: String },
+    Unreachable { error: String },
+}
+
+impl HostEntry {
+    fn new(host: &HostConfig) -> Self {
+        Self {
+            id: host.id.clone(),
+            url: host.url.clone(),
+            relays: host.relays.clone(),
+            wifi_driver: host.extra_data.wifi_driver.clone(),
+            interface_name: host.extra_data.interface_name().map(str::to_string),
+            status: None,
+        }
+    }
+
+    /// The url of the host, followed by how it is reached if it is behind relays.
+    fn url_summary(&self) -> String {
+        match self.relays.as_slice() {
+            [] => self.url.clone(),
+            [relay] => format!("{} (via {relay})", self.url),
+            [first, rest @ ..] => format!("{} (via {first} +{})", self.url, rest.len()),
+        }
+    }
+}
+
+/// List the hosts in `config`, connecting to them unless `--offline` is set, and return the
+/// listing in the requested format. Hosts that can not be connected to are listed with the error.
+pub async fn run(args: &ListHostsArgs, config: &HostsConfig) -> anyhow::Result<String> {
+    let mut entries: Vec<_> = config.hosts.iter().map(HostEntry::new).collect();
+    if !args.offline {
+        let timeout = Duration::from_secs(args.connect_timeout);
+        let results = config.connect_each(timeout).await;
+        for (entry, (_, result)) in entries.iter_mut().zip(results) {
+            entry.status = Some(match result {
+                Ok(host) => Status::Connected {
+                    os: host.os_info.to_string(),
+                },
+                Err(err) => Status::Unreachable {
+                    error: format!("{err:#}"),
+                },
+            });
+        }
+    }
+
+    match args.format {
+        ListFormat::Table => Ok(table(&entries, args.offline)),
+        ListFormat::Json => {
+            serde_json::to_string_pretty(&entries).context("failed to serialize the hosts")
+        }
+    }
+}
+
+/// Format the hosts as a table, with a row per host and the connection status unless `offline`.
+fn table(entries: &[HostEntry], offline: bool) -> String {
+    let mut header = vec!["ID", "URL", "DRIVER", "INTERFACE"];
+    if !offline {
+        header.extend(["STATUS", "OS / ERROR"]);
+    }
+    let rows: Vec<Vec<String>> = entries
+        .iter()
+        .map(|entry| {
+            let mut row = vec![
+                entry.id.clone(),
+                entry.url_summary(),
+                entry.wifi_driver.clone().unwrap_or_else(|| "-".to_string()),
+                entry
+                    .interface_name
+                    .clone()
+                    .unwrap_or_else(|| "-".to_string()),
+            ];
+            match &entry.status {
+                Some(Status::Connected { os }) => row.extend(["connected".to_string(), os.clone()]),
+                Some(Status::Unreachable { error }) => {
+                    // Errors from ssh can span multiple lines, which would break the table.
+                    let error = error.lines().collect::<Vec<_>>().join("; ");
+                    row.extend(["unreachable".to_string(), error])
+                }
+                None => {}
+            }
+            row
+        })
+        .collect();
+
+    let mut widths: Vec<_> = header.iter().map(|h| h.len()).collect();
+    for row in &rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.chars().count());
+        }
+    }
+
+    let mut out = String::new();
+    let mut write_row = |cells: &mut dyn Iterator<Item = &str>| {
+        let line = cells
+            .zip(&widths)
+            .map(|(cell, &width)| format!("{cell:<width$}"))
+            .collect::<Vec<_>>()
+            .join("  ");
+        _ = writeln!(out, "{}", line.trim_end());
+    };
+    write_row(&mut header.iter().copied());
+    for row in &rows {
+        write_row(&mut row.iter().map(String::as_str));
+    }
+    out
+}
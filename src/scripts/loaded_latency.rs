@@ -0,0 +1,74 @@
+//! Measure the latency under load, by pinging while iperf generates traffic.
+
+use std::{path::Path, time::Duration};
+
+use anyhow::Context;
+use clap::Parser;
+use ron::ser::{to_string_pretty, PrettyConfig};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    cancel::CancellationToken,
+    hosts::Hosts,
+    scripts::{
+        iperf::{self, IperfArgs},
+        latency::PingPlan,
+    },
+};
+
+#[derive(Parser, Debug, Clone, Serialize, Deserialize)]
+pub struct LoadedLatencyArgs {
+    #[command(flatten)]
+    pub iperf: IperfArgs,
+    /// The host ids that will run ping. These can also be iperf clients.
+    #[clap(long, required = true, value_delimiter = ',', num_args = 1..)]
+    pub latency_clients: Vec<String>,
+    /// The IP address to ping instead of the access point.
+    #[clap(long)]
+    #[serde(default)]
+    pub ping_target: Option<String>,
+    /// The time between pings in seconds.
+    #[clap(long, default_value = "0.2")]
+    #[serde(default = "default_ping_interval")]
+    pub ping_interval: f64,
+    /// How long to ping in seconds before the load starts and after it stops, to measure the
+    /// idle round trip time.
+    #[clap(long, default_value = "3")]
+    #[serde(default = "default_baseline")]
+    pub baseline: u64,
+}
+
+// The defaults of the arguments when they are read from a config file. They match the
+// defaults of the command line.
+fn default_ping_interval() -> f64 {
+    0.2
+}
+
+fn default_baseline() -> u64 {
+    3
+}
+
+pub async fn run(
+    args: LoadedLatencyArgs,
+    hosts: &Hosts,
+    out_path: &Path,
+    cancel: &CancellationToken,
+) -> anyhow::Result<()> {
+    tokio::fs::create_dir_all(out_path)
+        .await
+        .context("could not create output folder")?;
+    // The iperf script writes its own arguments, so only the latency arguments are written here.
+    let plan = PingPlan {
+        hosts: args.latency_clients,
+        target: args.ping_target,
+        interval: args.ping_interval,
+        baseline: Duration::from_secs(args.baseline),
+    };
+    let dump = to_string_pretty(&plan, PrettyConfig::new()).context("failed to serialize pings")?;
+    tokio::fs::write(out_path.join("pings.ron"), dump)
+        .await
+        .context("failed to save pings")?;
+
+    iperf::run_with_pings(args.iperf, hosts, out_path, Some(&plan), cancel).await?;
+    Ok(())
+}
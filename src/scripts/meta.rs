@@ -0,0 +1,105 @@
+//! The `meta.ron` file of a run, recording which controller ran it and how, so results can be
+//! traced back to the version and configuration that produced them.
+
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+use anyhow::Context;
+use ron::ser::{to_string_pretty, PrettyConfig};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::{
+    hosts::HostId,
+    post_run::PostRun,
+    results::{Artifact, SchemaVersion},
+    scripts::iterations::Status,
+    utils::{controller_name, sanitize_file_stem, unix_time},
+};
+
+/// How and by which controller a run was started, written to `meta.ron`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Meta {
+    #[serde(default)]
+    pub schema_version: SchemaVersion,
+    /// The version of the controller.
+    pub version: String,
+    /// The git commit the controller was built from.
+    pub git_hash: String,
+    /// The command line the controller was started with.
+    pub invocation: Vec<String>,
+    pub hosts_file: PathBuf,
+    /// The SHA-256 hash of the hosts file.
+    pub hosts_hash: String,
+    /// The user and machine running the controller, as `user@hostname`.
+    pub controller: String,
+    /// When the run started, in seconds since the unix epoch.
+    pub start: f64,
+    /// When the run finished, in seconds since the unix epoch.
+    pub end: Option<f64>,
+    /// How the run finished, `None` while it is running.
+    pub status: Option<Status>,
+    /// How the `--post-run` command went, if it was run.
+    pub post_run: Option<PostRun>,
+    /// The names the files of hosts are written under, for the hosts whose id could not be used
+    /// as a file name as is. See [sanitize_file_stem].
+    #[serde(default)]
+    pub file_stems: BTreeMap<HostId, String>,
+}
+
+impl Meta {
+    /// Describe a run that starts now with the hosts from `hosts_file`.
+    pub async fn new(hosts_file: &Path) -> anyhow::Result<Self> {
+        let hosts = tokio::fs::read(hosts_file)
+            .await
+            .with_context(|| format!("could not read {}", hosts_file.display()))?;
+        let hosts_hash = Sha256::digest(&hosts)
+            .iter()
+            .map(|b| format!("{b:02x}"))
+            .collect();
+
+        Ok(Self {
+            schema_version: Self::SCHEMA_VERSION,
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            git_hash: option_env!("GIT_HASH").unwrap_or("unknown").to_string(),
+            invocation: std::env::args().collect(),
+            hosts_file: hosts_file.to_path_buf(),
+            hosts_hash,
+            controller: controller_name().await,
+            start: unix_time(SystemTime::now()),
+            end: None,
+            status: None,
+            post_run: None,
+            file_stems: BTreeMap::new(),
+        })
+    }
+
+    /// Record the file names of `hosts` that differ from their id.
+    pub fn record_file_stems<'a>(&mut self, hosts: impl IntoIterator<Item = &'a str>) {
+        self.file_stems = hosts
+            .into_iter()
+            .filter_map(|id| {
+                let stem = sanitize_file_stem(id);
+                (stem != id).then(|| (id.to_string(), stem))
+            })
+            .collect();
+    }
+
+    /// Record how the run finished.
+    pub fn finish(&mut self, result: &anyhow::Result<()>) {
+        self.end = Some(unix_time(SystemTime::now()));
+        self.status = Some(Status::of(result));
+    }
+
+    /// Write the metadata to `meta.ron` in `out_path`, which must exist.
+    pub async fn write(&self, out_path: &Path) -> anyhow::Result<()> {
+        let dump =
+            to_string_pretty(self, PrettyConfig::new()).context("failed to serialize metadata")?;
+        tokio::fs::write(out_path.join("meta.ron"), dump)
+            .await
+            .context("failed to write metadata")
+    }
+}
@@ -0,0 +1,107 @@
+//! Run TCP and UDP clients at the same time, each group with its own offered load.
+
+use std::path::Path;
+
+use anyhow::Context;
+use clap::Parser;
+use ron::ser::{to_string_pretty, PrettyConfig};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    cancel::CancellationToken,
+    hosts::Hosts,
+    scripts::{
+        iperf::{self, IperfArgs, TrafficGroup},
+        KeyNumbers,
+    },
+    utils::parse_bitrate,
+};
+
+#[derive(Parser, Debug, Clone, Serialize, Deserialize)]
+#[command(mut_arg("clients", |a| a.required(false).hide(true)))]
+#[command(mut_arg("udp", |a| a.required(false).hide(true)))]
+#[command(mut_arg("total_throughput", |a| a.hide(true)))]
+pub struct MixedArgs {
+    /// The iperf experiment to run. The clients, protocol and offered load are set per group, so
+    /// `--clients`, `--udp` and `--throughput` are not used.
+    #[command(flatten)]
+    pub iperf: IperfArgs,
+    /// The host ids that will run TCP clients.
+    #[clap(long, required = true, value_delimiter = ',', num_args = 1..)]
+    pub tcp_clients: Vec<String>,
+    /// The host ids that will run UDP clients.
+    #[clap(long, required = true, value_delimiter = ',', num_args = 1..)]
+    pub udp_clients: Vec<String>,
+    /// The total throughput of the TCP clients together in bits per second, 0 for unlimited.
+    #[clap(long, default_value = "0", value_parser = parse_bitrate)]
+    #[serde(default)]
+    pub tcp_throughput: u64,
+    /// The total throughput of the UDP clients together in bits per second, for example `50M`.
+    #[clap(long, value_parser = parse_bitrate)]
+    pub udp_throughput: u64,
+}
+
+pub async fn run(
+    args: MixedArgs,
+    hosts: &Hosts,
+    out_path: &Path,
+    cancel: &CancellationToken,
+) -> anyhow::Result<KeyNumbers> {
+    let iperf_args = iperf_args(&args)?;
+
+    tokio::fs::create_dir_all(out_path)
+        .await
+        .context("could not create output folder")?;
+    // The iperf script writes its own arguments, including the groups.
+    let args_dump =
+        to_string_pretty(&args, PrettyConfig::new()).context("failed to serialize args info")?;
+    tokio::fs::write(out_path.join("mixed-arguments.ron"), args_dump)
+        .await
+        .context("failed to save arguments")?;
+
+    iperf::run(iperf_args, hosts, out_path, cancel).await
+}
+
+/// The arguments of the iperf experiment, with a traffic group for the TCP and UDP clients.
+fn iperf_args(args: &MixedArgs) -> anyhow::Result<IperfArgs> {
+    if !args.iperf.clients.is_empty() || args.iperf.udp.is_some() {
+        anyhow::bail!("use --tcp-clients and --udp-clients instead of --clients and --udp");
+    }
+    if let Some(host) = args
+        .tcp_clients
+        .iter()
+        .find(|c| args.udp_clients.contains(c))
+    {
+        anyhow::bail!("`{host}` can not be both a TCP and a UDP client");
+    }
+
+    let mut iperf_args = args.iperf.clone();
+    iperf_args.clients = args
+        .tcp_clients
+        .iter()
+        .chain(&args.udp_clients)
+        .cloned()
+        .collect();
+    iperf_args.total_throughput = args.tcp_throughput + args.udp_throughput;
+    iperf_args.groups = vec![
+        TrafficGroup {
+            name: "tcp".to_string(),
+            clients: args.tcp_clients.clone(),
+            udp: false,
+            total_throughput: args.tcp_throughput,
+        },
+        TrafficGroup {
+            name: "udp".to_string(),
+            clients: args.udp_clients.clone(),
+            udp: true,
+            total_throughput: args.udp_throughput,
+        },
+    ];
+    iperf_args.validate().context("invalid arguments")?;
+    Ok(iperf_args)
+}
+
+/// Print what the experiment would do, see [iperf::dry_run].
+pub fn dry_run(args: MixedArgs, hosts: &Hosts, out_path: &Path) -> anyhow::Result<()> {
+    iperf::dry_run(iperf_args(&args)?, hosts, out_path)
+}
@@ -0,0 +1,285 @@
+//! Options for the network under test and for monitoring it, shared by the scripts.
+
+use std::{future::Future, path::Path, sync::Arc, time::Duration};
+
+use anyhow::Context;
+use clap::{ArgGroup, Args};
+use serde::{Deserialize, Serialize};
+use tracing::{debug, info, warn};
+
+use crate::{
+    cancel::{Aborted, CancellationToken, Reason},
+    driver::wifi::{self, Aid},
+    hosts::{Host, Hosts},
+    mac::MacAddr,
+    monitor::{Monitor, MonitorConfig},
+    progress::{self, Phase},
+    timing::{self, TimedPhase},
+    utils::{check_channel, parse_bandwidth, parse_frequency},
+};
+
+#[derive(Args, Debug, Clone, Serialize, Deserialize)]
+#[command(group(ArgGroup::new("capture").args(["monitors", "no_monitor"]).required(true)))]
+pub struct MonitorArgs {
+    /// The host id(s) of the hosts that will capture the wireless traffic.
+    #[clap(long, value_delimiter = ',', num_args = 1..)]
+    #[serde(default)]
+    pub monitors: Vec<String>,
+    /// Run without capturing any traffic.
+    ///
+    /// The clients are not associated again to discover their association IDs; only clients that
+    /// are not connected to the SSID yet are associated.
+    #[clap(long)]
+    #[serde(default)]
+    pub no_monitor: bool,
+    /// Allow a host to be both a client and a monitor, for setups that capture on the clients.
+    #[clap(long)]
+    #[serde(default)]
+    pub allow_monitor_client_overlap: bool,
+    /// The frequency the access point is using in MHz.
+    #[clap(short = 'F', long, value_parser = parse_frequency)]
+    pub frequency: u32,
+    /// The bandwidth used by the AP in MHz.
+    #[clap(short = 'B', long, value_parser = parse_bandwidth)]
+    pub bandwidth: u32,
+    /// The SSID (display name) of the access point.
+    #[clap(long)]
+    pub ssid: String,
+    /// The BSSID of the access point, often the MAC address.
+    ///
+    /// Defaults to the address of the interface of the access point.
+    #[clap(long)]
+    #[serde(default)]
+    pub bssid: Option<MacAddr>,
+    /// Do not check the frequency and bandwidth against the access point.
+    ///
+    /// Use this for access points the controller cannot query. `--bssid` is then required to
+    /// monitor.
+    #[clap(long)]
+    #[serde(default)]
+    pub trust_args: bool,
+    /// Merge the captures of the monitors into `merged.pcapng`, ordered by time and corrected for
+    /// the clock offsets of the monitor hosts.
+    #[clap(long)]
+    #[serde(default)]
+    pub merge_captures: bool,
+    /// When merging, drop frames another monitor captured at most this many milliseconds before.
+    #[clap(long, requires = "merge_captures")]
+    #[serde(default)]
+    pub dedup_window: Option<u64>,
+    /// How many seconds the captures start before the measured traffic, to cover starting it.
+    #[clap(long, default_value = "2")]
+    #[serde(default = "default_lead")]
+    pub lead_in: u64,
+    /// How many seconds the captures continue after the measured traffic, to cover stopping it.
+    #[clap(long, default_value = "2")]
+    #[serde(default = "default_lead")]
+    pub lead_out: u64,
+}
+
+// The default of the lead-in and lead-out when they are read from a config file, matching the
+// default of the command line.
+fn default_lead() -> u64 {
+    2
+}
+
+impl MonitorArgs {
+    /// Check that the frequency is part of a channel of the bandwidth.
+    pub fn check_channel(&self) -> Result<(), String> {
+        check_channel(self.frequency, self.bandwidth)
+    }
+
+    /// How long a capture of `duration` of measured traffic runs, including the lead-in and
+    /// lead-out.
+    pub fn capture_duration(&self, duration: Duration) -> Duration {
+        Duration::from_secs(self.lead_in) + duration + Duration::from_secs(self.lead_out)
+    }
+
+    /// Check the channel arguments against the channel the access point is actually on, unless
+    /// `--trust-args` is set. Returns the BSSID, taken from the access point if it was not given.
+    pub async fn check_access_point(&self, access_point: &Host) -> anyhow::Result<Option<MacAddr>> {
+        if self.trust_args {
+            return Ok(self.bssid);
+        }
+
+        let Some(ifname) = access_point.extra_data.interface_name() else {
+            anyhow::bail!(
+                "access point `{}` has no interface name configured to check the channel, pass --trust-args to skip the check",
+                access_point.id
+            );
+        };
+        let info = wifi::interface_info(access_point, ifname)
+            .await
+            .context("failed to query the access point, pass --trust-args to skip the check")?;
+        debug!(?info, "Queried access point");
+
+        if info.frequency != Some(self.frequency) || info.width != Some(self.bandwidth) {
+            let show = |v: Option<u32>| v.map_or("unknown".to_string(), |v| format!("{v} MHz"));
+            anyhow::bail!(
+                "access point is on {} with a width of {}, but --frequency {} --bandwidth {} was given",
+                show(info.frequency),
+                show(info.width),
+                self.frequency,
+                self.bandwidth,
+            );
+        }
+
+        match (self.bssid, info.addr) {
+            (Some(bssid), _) => Ok(Some(bssid)),
+            (None, Some(addr)) => {
+                info!("Using BSSID {addr} of the access point");
+                Ok(Some(addr))
+            }
+            (None, None) => anyhow::bail!("could not determine the BSSID of the access point"),
+        }
+    }
+
+    /// Start monitoring the traffic of `targets`, which lasts `duration`, unless `--no-monitor` is
+    /// set. The capture starts `--lead-in` before the traffic and ends `--lead-out` after it.
+    ///
+    /// Without monitoring, only the targets that are not connected yet are associated. Otherwise
+    /// the monitor associates all targets to discover their association IDs, unless `known_aids`
+    /// from an earlier run are given.
+    pub async fn start(
+        &self,
+        hosts: &Hosts,
+        targets: &[&Arc<Host>],
+        bssid: Option<MacAddr>,
+        duration: Duration,
+        out_path: &Path,
+        known_aids: Option<Vec<Aid>>,
+    ) -> anyhow::Result<Option<Monitor>> {
+        if self.no_monitor {
+            debug!("Skipping monitoring");
+            progress::enter(Phase::Connecting);
+            let _timing = timing::phase(TimedPhase::Associate);
+            for target in targets {
+                let _timing = timing::host_phase(TimedPhase::Associate, &target.id);
+                target
+                    .ensure_associated(&self.ssid)
+                    .await
+                    .context("failed to associate clients")?;
+            }
+            return Ok(None);
+        }
+
+        let Some(bssid) = bssid else {
+            anyhow::bail!("--bssid is required to monitor with --trust-args");
+        };
+        let monitor = self
+            .monitor_config(targets, bssid, duration, out_path, known_aids)
+            .start(hosts)
+            .await
+            .context("failed to start capture")?;
+        Ok(Some(monitor))
+    }
+
+    /// The configuration of a monitor following `targets`, which associates them to discover
+    /// their association IDs unless `known_aids` are given.
+    pub fn monitor_config(
+        &self,
+        targets: &[&Arc<Host>],
+        bssid: MacAddr,
+        duration: Duration,
+        out_path: &Path,
+        known_aids: Option<Vec<Aid>>,
+    ) -> MonitorConfig {
+        MonitorConfig {
+            ssid: self.ssid.clone(),
+            bssid: Some(bssid),
+            monitors: self.monitors.clone(),
+            targets: targets.iter().map(|v| v.id.clone()).collect(),
+            duration,
+            lead_in: Duration::from_secs(self.lead_in),
+            lead_out: Duration::from_secs(self.lead_out),
+            output_path: Some(out_path.to_owned()),
+            // The access point is expected to be on this channel already, see the ap-setup script.
+            frequency: self.frequency,
+            bandwidth: self.bandwidth,
+            set_aids: true,
+            known_aids,
+            filter: None,
+            merge_captures: self.merge_captures,
+            dedup_window: self.dedup_window.map(Duration::from_millis),
+        }
+    }
+
+    /// Start capturing all traffic of the network around `duration` of measured traffic, unless
+    /// `--no-monitor` is set. Unlike [MonitorArgs::start], no clients are associated and no
+    /// association IDs are set.
+    pub async fn capture(
+        &self,
+        hosts: &Hosts,
+        bssid: Option<MacAddr>,
+        duration: Duration,
+        out_path: &Path,
+    ) -> anyhow::Result<Option<Monitor>> {
+        if self.no_monitor {
+            return Ok(None);
+        }
+        let Some(bssid) = bssid else {
+            anyhow::bail!("--bssid is required to monitor with --trust-args");
+        };
+        let monitor = MonitorConfig {
+            ssid: self.ssid.clone(),
+            bssid: Some(bssid),
+            monitors: self.monitors.clone(),
+            targets: Vec::new(),
+            duration,
+            lead_in: Duration::from_secs(self.lead_in),
+            lead_out: Duration::from_secs(self.lead_out),
+            output_path: Some(out_path.to_owned()),
+            frequency: self.frequency,
+            bandwidth: self.bandwidth,
+            set_aids: false,
+            known_aids: None,
+            filter: None,
+            merge_captures: self.merge_captures,
+            dedup_window: self.dedup_window.map(Duration::from_millis),
+        }
+        .start(hosts)
+        .await
+        .context("failed to start capture")?;
+        Ok(Some(monitor))
+    }
+
+    /// Returns true if all the clients are still associated to the network under test.
+    pub async fn clients_associated<'a>(
+        &self,
+        clients: impl IntoIterator<Item = &'a Arc<Host>>,
+    ) -> bool {
+        for client in clients {
+            match client.connected_ssid().await {
+                Ok(Some(ssid)) if ssid == self.ssid => {}
+                Ok(_) => {
+                    debug!(host = client.id, "Client is no longer associated");
+                    return false;
+                }
+                Err(err) => {
+                    warn!(host = client.id, "Could not check association: {err:?}");
+                    return false;
+                }
+            }
+        }
+        true
+    }
+}
+
+/// Run `phase` of a script while `monitor` captures it. If `cancel` is cancelled first the phase
+/// is dropped, and what was captured so far is collected before [Aborted] is returned.
+pub async fn run_captured<T>(
+    monitor: &mut Option<Monitor>,
+    cancel: &CancellationToken,
+    phase: impl Future<Output = anyhow::Result<T>>,
+) -> anyhow::Result<T> {
+    let result = cancel.run(phase).await;
+    if matches!(&result, Err(err) if err.is::<Aborted>()) {
+        if let Some(monitor) = monitor.take() {
+            let reason = cancel.reason().unwrap_or(Reason::User);
+            if let Err(err) = monitor.stop_and_collect(reason.to_string()).await {
+                warn!("Could not collect the captures: {err:?}");
+            }
+        }
+    }
+    result
+}
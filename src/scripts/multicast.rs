@@ -0,0 +1,417 @@
+//! Send a multicast UDP stream to clients that joined the group, and compare the configured,
+//! received and captured rates.
+//!
+//! iperf3 does not support multicast, so iperf 2 is used instead.
+
+use std::{
+    collections::BTreeMap, fmt::Write as _, net::Ipv4Addr, path::Path, sync::Arc, time::Duration,
+};
+
+use anyhow::{anyhow, Context};
+use clap::Parser;
+use ron::ser::{to_string_pretty, PrettyConfig};
+use serde::{Deserialize, Serialize};
+use tokio::{task::JoinSet, time::sleep};
+use tracing::{error, info, warn};
+
+use crate::{
+    cancel::{Aborted, CancellationToken},
+    capture::analysis::{self, MulticastFrames},
+    hosts::{Host, HostId, Hosts},
+    mac::MacAddr,
+    package::Package,
+    scripts::mark_failed,
+    scripts::monitoring::{run_captured, MonitorArgs},
+    utils::{format_bitrate, host_file, parse_bitrate, run_all, spawn_all},
+};
+
+/// How long the receivers are started before the sender, so they joined the group.
+const JOIN_TIME: Duration = Duration::from_secs(2);
+
+#[derive(Parser, Debug, Clone, Serialize, Deserialize)]
+pub struct MulticastArgs {
+    /// The host id of the access point.
+    #[clap(long)]
+    pub ap: String,
+    /// The host id of the host sending the stream, for example a wired host behind the access
+    /// point. Defaults to the access point.
+    #[clap(long)]
+    #[serde(default)]
+    pub server: Option<String>,
+    /// The host ids of the clients that join the group and receive the stream.
+    #[clap(long, required = true, value_delimiter = ',', num_args = 1..)]
+    pub clients: Vec<String>,
+    /// The multicast group to send to.
+    #[clap(long, default_value = "239.255.1.1")]
+    #[serde(default = "default_group")]
+    pub group: Ipv4Addr,
+    /// The UDP port to send to.
+    #[clap(long, default_value = "5001")]
+    #[serde(default = "default_port")]
+    pub port: u16,
+    /// The rate of the stream in bits per second.
+    #[clap(long, default_value = "10M", value_parser = parse_bitrate)]
+    #[serde(default = "default_rate")]
+    pub rate: u64,
+    /// The TTL of the multicast packets. Must be larger than one if the sender is routed to the
+    /// wireless network.
+    #[clap(long, default_value = "1")]
+    #[serde(default = "default_ttl")]
+    pub ttl: u8,
+    /// How long to send in seconds.
+    #[clap(short = 'd', long, default_value = "10")]
+    #[serde(default = "default_duration")]
+    pub duration: u64,
+    #[command(flatten)]
+    pub network: MonitorArgs,
+}
+
+// The defaults of the arguments when they are read from a config file. They match the
+// defaults of the command line.
+fn default_group() -> Ipv4Addr {
+    Ipv4Addr::new(239, 255, 1, 1)
+}
+
+fn default_port() -> u16 {
+    5001
+}
+
+fn default_rate() -> u64 {
+    10_000_000
+}
+
+fn default_ttl() -> u8 {
+    1
+}
+
+fn default_duration() -> u64 {
+    10
+}
+
+/// A report printed by iperf 2 for an interval or a whole stream.
+#[derive(Debug, Clone, Serialize)]
+pub struct StreamReport {
+    pub seconds: f64,
+    pub bits_per_second: f64,
+    /// Only reported by receivers.
+    pub jitter_ms: Option<f64>,
+    pub lost: Option<u64>,
+    pub total: Option<u64>,
+}
+
+impl StreamReport {
+    pub fn loss_percent(&self) -> Option<f64> {
+        match (self.lost, self.total) {
+            (Some(lost), Some(total)) if total > 0 => Some(lost as f64 / total as f64 * 100.0),
+            _ => None,
+        }
+    }
+}
+
+/// The comparison of the rates, written to `multicast.ron`.
+#[derive(Debug, Clone, Serialize)]
+pub struct MulticastSummary {
+    /// The configured rate in bits per second.
+    pub configured: u64,
+    /// What the sender reported sending.
+    pub sent: Option<StreamReport>,
+    /// What every client received, `None` if it did not receive anything.
+    pub received: BTreeMap<HostId, Option<StreamReport>>,
+    /// The multicast frames every monitor captured.
+    pub captured: BTreeMap<HostId, MulticastFrames>,
+}
+
+pub async fn run(
+    args: MulticastArgs,
+    hosts: &Hosts,
+    out_path: &Path,
+    cancel: &CancellationToken,
+) -> anyhow::Result<()> {
+    tokio::fs::create_dir_all(out_path)
+        .await
+        .context("could not create output folder")?;
+    let args_dump =
+        to_string_pretty(&args, PrettyConfig::new()).context("failed to serialize args info")?;
+    tokio::fs::write(out_path.join("arguments.ron"), args_dump)
+        .await
+        .context("failed to save arguments")?;
+    if !args.group.is_multicast() {
+        anyhow::bail!("{} is not a multicast address", args.group);
+    }
+
+    let clients: Vec<Arc<Host>> = hosts
+        .get_many(&args.clients)
+        .map_err(|missing| anyhow!("no host with id {missing}"))?
+        .cloned()
+        .collect();
+    let access_point = hosts.get(&args.ap).context("access point id not found")?;
+    let server = hosts
+        .get(args.server.as_deref().unwrap_or(&args.ap))
+        .context("server id not found")?
+        .clone();
+
+    // Every host needs iperf 2, which is not installed alongside iperf3.
+    let mut preflight = JoinSet::new();
+    for host in clients.iter().chain([&server]).cloned() {
+        preflight.spawn(async move {
+            let result = host.ensure_package(Package::Iperf2).await;
+            (host, result)
+        });
+    }
+    for (host, result) in preflight.join_all().await {
+        result.with_context(|| format!("iperf 2 is not available on `{}`", host.id))?;
+    }
+
+    let bssid = args.network.check_access_point(access_point).await?;
+    let group = args.group;
+    let stale = format!("[i]perf -[sc] .*{group}");
+    run_all(clients.iter().chain([&server]), |_| {
+        format!("pkill -f '{stale}' || true")
+    })
+    .await
+    .context("failed to clean up stale iperf processes")?;
+
+    // Multicast frames are sent to the group address, so the monitors capture everything in the
+    // BSS rather than following clients.
+    cancel.check()?;
+    let mut monitor = args
+        .network
+        .capture(
+            hosts,
+            bssid,
+            Duration::from_secs(args.duration) + JOIN_TIME,
+            out_path,
+        )
+        .await?;
+
+    info!("Joining {} clients to {group}", clients.len());
+    // The receivers stop by themselves a little after the sender is done.
+    let receive_time = JOIN_TIME.as_secs() + args.duration + 3;
+    let receivers = spawn_all(&clients, |h| {
+        let bind = match h.extra_data.interface_name() {
+            Some(ifname) => format!("{group}%{ifname}"),
+            None => group.to_string(),
+        };
+        format!(
+            "timeout {receive_time} iperf -s -u -B {bind} -p {} -i 1",
+            args.port
+        )
+    });
+    let send = async {
+        sleep(JOIN_TIME).await;
+        info!(
+            host = server.id,
+            "Sending {} to {group} for {}s",
+            format_bitrate(args.rate),
+            args.duration
+        );
+        let sender = server
+            .session
+            .shell(sender_command(&args, &server))
+            .output()
+            .await
+            .context("failed to run the sender");
+        anyhow::Ok(sender)
+    };
+    let sender = run_captured(&mut monitor, cancel, send).await?;
+    let mut failures = Vec::new();
+    let sent = match &sender {
+        Ok(output) => {
+            tokio::fs::write(out_path.join("sender.txt"), &output.stdout)
+                .await
+                .context("failed to save sender output")?;
+            if !output.status.success() {
+                failures.push(format!("the sender exited with {}", output.status));
+            }
+            parse_report(&String::from_utf8_lossy(&output.stdout))
+        }
+        Err(err) => {
+            failures.push(format!("{err:#}"));
+            None
+        }
+    };
+
+    let mut received = BTreeMap::new();
+    for (host, output) in receivers.join_all().await {
+        let output = match output {
+            Ok(output) => output,
+            Err(err) => {
+                error!(host = host.id, "Receiver failed: {err:?}");
+                failures.push(format!("the receiver on `{}` failed", host.id));
+                received.insert(host.id.clone(), None);
+                continue;
+            }
+        };
+        tokio::fs::write(host_file(out_path, &host.id, "txt"), &output.stdout)
+            .await
+            .context("failed to save receiver output")?;
+        let report = parse_report(&String::from_utf8_lossy(&output.stdout));
+        if report.is_none() {
+            error!(host = host.id, "Did not receive the stream");
+            failures.push(format!("`{}` did not receive the stream", host.id));
+        }
+        received.insert(host.id.clone(), report);
+    }
+
+    let mut captured = BTreeMap::new();
+    if let Some(monitor) = monitor {
+        info!("Waiting for capture to finish");
+        match monitor.wait_or_stop(cancel).await {
+            Ok(output) => {
+                let destination = group_mac(group);
+                for (host, _) in output.captures {
+                    let capture = host_file(out_path, &host, "pcapng");
+                    match analysis::multicast_frames(&capture, destination).await {
+                        Ok(frames) => {
+                            captured.insert(host, frames);
+                        }
+                        Err(err) => warn!("Could not analyze {}: {err:?}", capture.display()),
+                    }
+                }
+            }
+            Err(err) if err.is::<Aborted>() => return Err(err),
+            Err(err) => {
+                error!("Monitor failed: {err:?}");
+                failures.push(format!("monitor failed: {err:#}"));
+            }
+        }
+    }
+
+    let summary = MulticastSummary {
+        configured: args.rate,
+        sent,
+        received,
+        captured,
+    };
+    info!(
+        "Multicast rates:\n{}",
+        summary_table(&summary, args.duration)
+    );
+    let dump =
+        to_string_pretty(&summary, PrettyConfig::new()).context("failed to serialize summary")?;
+    tokio::fs::write(out_path.join("multicast.ron"), dump)
+        .await
+        .context("failed to save summary")?;
+
+    if !failures.is_empty() {
+        let reason = failures.join("; ");
+        mark_failed(out_path, &reason).await?;
+        anyhow::bail!("{reason}");
+    }
+    Ok(())
+}
+
+fn sender_command(args: &MulticastArgs, server: &Host) -> String {
+    let mut cmd = format!(
+        "iperf -c {} -u -p {} -b {} -t {} -T {} -i 1",
+        args.group, args.port, args.rate, args.duration, args.ttl
+    );
+    // The source address selects the interface the stream is sent on.
+    if let Some(ip) = server.extra_data.interface_ip() {
+        _ = write!(cmd, " -B {ip}");
+    }
+    cmd
+}
+
+/// The MAC address an IPv4 multicast group is sent to, which contains the lower 23 bits of the
+/// group address.
+pub fn group_mac(group: Ipv4Addr) -> MacAddr {
+    let [_, b, c, d] = group.octets();
+    MacAddr::new([0x01, 0x00, 0x5e, b & 0x7f, c, d])
+}
+
+/// Parse the report of the whole stream from the output of an iperf 2 sender or receiver.
+///
+/// Reports look like `[  3]  0.0-10.0 sec  1.25 MBytes  1.05 Mbits/sec   0.015 ms    0/  893 (0%)`,
+/// where receivers add the jitter and loss. The report with the longest interval is the one of
+/// the whole stream.
+pub fn parse_report(output: &str) -> Option<StreamReport> {
+    output.lines().filter_map(parse_report_line).fold(
+        None,
+        |longest: Option<StreamReport>, report| match longest {
+            Some(longest) if longest.seconds > report.seconds => Some(longest),
+            _ => Some(report),
+        },
+    )
+}
+
+fn parse_report_line(line: &str) -> Option<StreamReport> {
+    let (_, rest) = line.trim().strip_prefix('[')?.split_once(']')?;
+    let (interval, rest) = rest.split_once("sec")?;
+    // The interval is padded, as in `0.0- 1.0`.
+    let interval: String = interval.split_whitespace().collect();
+    let (start, end) = interval.split_once('-')?;
+    let seconds = end.parse::<f64>().ok()? - start.parse::<f64>().ok()?;
+
+    let fields: Vec<&str> = rest.split_whitespace().collect();
+    let unit = fields.iter().position(|f| f.ends_with("bits/sec"))?;
+    let value: f64 = fields.get(unit.checked_sub(1)?)?.parse().ok()?;
+    let scale = match fields[unit].strip_suffix("bits/sec")? {
+        "" => 1.0,
+        "K" => 1e3,
+        "M" => 1e6,
+        "G" => 1e9,
+        _ => return None,
+    };
+
+    let mut report = StreamReport {
+        seconds,
+        bits_per_second: value * scale,
+        jitter_ms: None,
+        lost: None,
+        total: None,
+    };
+    if let Some(ms) = fields.iter().position(|f| *f == "ms") {
+        report.jitter_ms = fields.get(ms.checked_sub(1)?)?.parse().ok();
+        // The datagrams are padded, as in `0/  893 (0%)`.
+        let datagrams: String = fields[ms + 1..].concat();
+        if let Some((lost, rest)) = datagrams.split_once('/') {
+            report.lost = lost.parse().ok();
+            report.total = rest.split('(').next().and_then(|t| t.parse().ok());
+        }
+    }
+    Some(report)
+}
+
+fn summary_table(summary: &MulticastSummary, duration: u64) -> String {
+    let rate = |bps: f64| format!("{:.2} Mbit/s", bps / 1e6);
+    let mut out = format!("configured: {}\n", rate(summary.configured as f64));
+    _ = writeln!(
+        out,
+        "sent: {}",
+        summary
+            .sent
+            .as_ref()
+            .map_or("-".to_string(), |s| rate(s.bits_per_second))
+    );
+    for (host, report) in &summary.received {
+        match report {
+            Some(report) => {
+                _ = writeln!(
+                    out,
+                    "received by {host}: {}, {} loss",
+                    rate(report.bits_per_second),
+                    report
+                        .loss_percent()
+                        .map_or("unknown".to_string(), |l| format!("{l:.2}%"))
+                )
+            }
+            None => _ = writeln!(out, "received by {host}: nothing"),
+        }
+    }
+    for (host, frames) in &summary.captured {
+        let rates: Vec<String> = frames
+            .data_rates
+            .iter()
+            .map(|(rate, count)| format!("{count} at {rate} Mbit/s"))
+            .collect();
+        _ = writeln!(
+            out,
+            "captured by {host}: {} frames, {} including headers ({})",
+            frames.frames,
+            rate(frames.bytes as f64 * 8.0 / duration as f64),
+            rates.join(", ")
+        );
+    }
+    out
+}
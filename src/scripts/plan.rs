@@ -0,0 +1,607 @@
+//! Run a list of experiments from a plan file one after the other, sharing the connections to the
+//! hosts. With `--parallel` entries that use different hosts run at the same time.
+
+use std::{
+    collections::{BTreeSet, HashSet},
+    future::Future,
+    path::{Path, PathBuf},
+    pin::Pin,
+    time::{Duration, Instant, SystemTime},
+};
+
+use anyhow::Context;
+use clap::{CommandFactory, FromArgMatches, Parser};
+use ron::ser::{to_string_pretty, PrettyConfig};
+use serde::{Deserialize, Serialize};
+use tokio::{task::JoinSet, time::sleep};
+use tracing::{error, info, info_span, warn, Instrument};
+
+use crate::{
+    cancel::{Aborted, CancellationToken, Reason},
+    hosts::Hosts,
+    progress,
+    results::write_artifact,
+    scripts::{
+        self,
+        iterations::{fingerprint, run_iterations, IterationArgs, Status},
+        Script,
+    },
+    timing::{self, Timing},
+    utils::unix_time,
+};
+
+#[derive(Parser, Debug, Clone, Serialize, Deserialize)]
+pub struct PlanArgs {
+    /// The plan file describing the experiments to run.
+    #[clap(long)]
+    pub file: PathBuf,
+    /// Skip the entries that already completed in an earlier run of the plan. The output path of
+    /// that run has to be passed with `--out`.
+    #[clap(long)]
+    #[serde(default)]
+    pub resume: bool,
+    /// How many entries may run at the same time. Entries only run at the same time if they do
+    /// not share any hosts, entries that do run in the order of the plan.
+    #[clap(long, default_value_t = 1)]
+    #[serde(default = "default_parallel")]
+    pub parallel: usize,
+}
+
+fn default_parallel() -> usize {
+    1
+}
+
+/// A plan file, in TOML.
+///
+/// ```toml
+/// fail-fast = false
+///
+/// [[entry]]
+/// name = "udp-100m"
+/// script = "iperf"
+/// args = ["--ap", "ap1", "--clients", "nuc1,nuc2", "-U", "true", "-T", "100M"]
+/// repeat = 3
+/// cooldown = 10
+/// ```
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct Plan {
+    /// Stop the plan at the first entry that fails.
+    #[serde(default)]
+    pub fail_fast: bool,
+    #[serde(rename = "entry")]
+    pub entries: Vec<PlanEntry>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct PlanEntry {
+    /// The name of the entry, also used as the name of its output directory.
+    pub name: String,
+    /// The script to run, as it is named on the command line.
+    pub script: String,
+    /// The command line arguments of the script.
+    #[serde(default)]
+    pub args: Vec<String>,
+    /// How many times to run the entry. Every run gets its own `run-<n>` directory if larger
+    /// than one.
+    #[serde(default = "default_repeat")]
+    pub repeat: u32,
+    /// How long to wait in seconds after every run of this entry.
+    #[serde(default)]
+    pub cooldown: u64,
+}
+
+fn default_repeat() -> u32 {
+    1
+}
+
+/// The status of an entry, written to `plan-status.ron`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EntryStatus {
+    pub name: String,
+    /// `None` while the entry has not run yet.
+    pub status: Option<Status>,
+    /// When the entry started and ended, in seconds since the unix epoch.
+    pub start: Option<f64>,
+    pub end: Option<f64>,
+}
+
+impl PlanEntry {
+    /// Parse the arguments of the entry into a script.
+    fn parse(&self) -> anyhow::Result<(Script, clap::ArgMatches)> {
+        let argv = ["plan".to_string(), self.script.clone()]
+            .into_iter()
+            .chain(self.args.iter().cloned());
+        let matches = Script::command()
+            .try_get_matches_from(argv)
+            .map_err(|err| anyhow::anyhow!("{}", err.render()))?;
+        let script = Script::from_arg_matches(&matches)?;
+        Ok((script, matches))
+    }
+}
+
+impl PlanEntry {
+    /// Parse the script of the entry and check its arguments, like the command line would.
+    fn check(&self) -> anyhow::Result<Script> {
+        let (mut script, _) = self
+            .parse()
+            .with_context(|| format!("invalid arguments for entry `{}`", self.name))?;
+        if matches!(script, Script::Plan(_)) {
+            anyhow::bail!("entry `{}` can not run another plan", self.name);
+        }
+        script
+            .check_channels()
+            .and_then(|()| script.check_hosts())
+            .map_err(|err| anyhow::anyhow!("invalid arguments for entry `{}`: {err}", self.name))?;
+        Ok(script)
+    }
+}
+
+impl Plan {
+    /// Check that every entry can be parsed, gives its hosts roles that do not conflict and only
+    /// refers to known hosts.
+    pub fn validate(&self, hosts: &Hosts) -> anyhow::Result<()> {
+        if self.entries.is_empty() {
+            anyhow::bail!("the plan has no entries");
+        }
+        let mut names = HashSet::new();
+        for entry in &self.entries {
+            if entry.name.is_empty() || entry.name.contains(['/', '\\']) || entry.name == ".." {
+                anyhow::bail!("`{}` can not be used as the name of an entry", entry.name);
+            }
+            if !names.insert(&entry.name) {
+                anyhow::bail!("duplicate entry name `{}`", entry.name);
+            }
+            if entry.repeat == 0 {
+                anyhow::bail!("entry `{}` must run at least once", entry.name);
+            }
+
+            let script = entry.check()?;
+            for host in script.hosts().into_iter().flatten() {
+                if hosts.get(host).is_none() {
+                    anyhow::bail!("entry `{}` uses unknown host `{host}`", entry.name);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+pub async fn run(
+    args: PlanArgs,
+    hosts: &Hosts,
+    out_path: &Path,
+    cancel: &CancellationToken,
+) -> anyhow::Result<()> {
+    let raw = tokio::fs::read_to_string(&args.file)
+        .await
+        .with_context(|| format!("could not read {}", args.file.display()))?;
+    let plan: Plan = toml::from_str(&raw).context("could not parse plan")?;
+    plan.validate(hosts).context("invalid plan")?;
+
+    tokio::fs::create_dir_all(out_path)
+        .await
+        .context("could not create output folder")?;
+    let status_path = out_path.join("plan-status.ron");
+    let mut statuses = if args.resume {
+        resume_statuses(&plan, &status_path).await?
+    } else {
+        if status_path.exists() {
+            anyhow::bail!(
+                "{} already contains a plan, pass --resume to continue it",
+                out_path.display()
+            );
+        }
+        plan.entries
+            .iter()
+            .map(|e| EntryStatus {
+                name: e.name.clone(),
+                status: None,
+                start: None,
+                end: None,
+            })
+            .collect()
+    };
+    tokio::fs::write(out_path.join("plan.toml"), &raw)
+        .await
+        .context("failed to save plan")?;
+    write_statuses(&status_path, &statuses).await?;
+
+    let total = plan.entries.len();
+    let host_sets: Vec<_> = plan.entries.iter().map(PlanEntry::host_set).collect();
+    let mut pending = Vec::new();
+    for (i, entry) in plan.entries.iter().enumerate() {
+        if statuses[i]
+            .status
+            .as_ref()
+            .is_some_and(Status::is_completed)
+        {
+            info!(
+                "Skipping entry {} ({}/{total}), already completed",
+                entry.name,
+                i + 1
+            );
+        } else {
+            pending.push(i);
+        }
+    }
+
+    let parallel = args.parallel.max(1);
+    let mut running = JoinSet::new();
+    let mut running_entries = Vec::new();
+    let mut stop = None;
+    loop {
+        // An error here stops starting entries, but the running ones are waited for so they
+        // clean up after themselves.
+        while stop.is_none() && running.len() < parallel {
+            let Some(next) = next_entry(&pending, &running_entries, &host_sets) else {
+                break;
+            };
+            let i = pending.remove(next);
+            let entry = plan.entries[i].clone();
+            let entry_path = out_path.join(&entry.name);
+            if let Err(err) = move_earlier_output(out_path, &entry.name).await {
+                stop = Some(err);
+                break;
+            }
+
+            info!("Starting entry {} ({}/{total})", entry.name, i + 1);
+            statuses[i].start = Some(unix_time(SystemTime::now()));
+            statuses[i].end = None;
+            if let Err(err) = write_statuses(&status_path, &statuses).await {
+                statuses[i].start = None;
+                stop = Some(err);
+                break;
+            }
+            running_entries.push(i);
+            let hosts = hosts.clone();
+            let cancel = cancel.clone();
+            let fail_fast = plan.fail_fast;
+            let span = info_span!("entry", entry = %entry.name);
+            running.spawn(
+                async move {
+                    let start = Instant::now();
+                    let result = progress::scope(
+                        &entry.name,
+                        run_entry(&entry, &hosts, &entry_path, &cancel),
+                    )
+                    .await;
+                    info!(
+                        "Entry {} finished after {:.0}s",
+                        entry.name,
+                        start.elapsed().as_secs_f64()
+                    );
+                    // The hosts of the entry cool down before another entry can use them.
+                    let stopping = result.is_err() && (fail_fast || cancel.is_cancelled());
+                    if entry.cooldown > 0 && i + 1 < total && !stopping {
+                        info!("Cooling down for {}s", entry.cooldown);
+                        sleep(Duration::from_secs(entry.cooldown)).await;
+                    }
+                    (i, result)
+                }
+                .instrument(span),
+            );
+        }
+
+        let Some(joined) = running.join_next().await else {
+            break;
+        };
+        let (i, result) = match joined {
+            Ok(joined) => joined,
+            Err(err) => {
+                // The entry is not known, but no new entries start after this.
+                error!("A plan entry panicked: {err}");
+                stop.get_or_insert(anyhow::Error::new(err).context("plan entry panicked"));
+                continue;
+            }
+        };
+        running_entries.retain(|&running| running != i);
+        let entry = &plan.entries[i];
+        statuses[i].end = Some(unix_time(SystemTime::now()));
+        statuses[i].status = Some(match &result {
+            Ok(()) => Status::Completed,
+            Err(err) if err.is::<Aborted>() && cancel.reason() == Some(Reason::Timeout) => {
+                Status::TimedOut
+            }
+            Err(err) if err.is::<Aborted>() => Status::Aborted,
+            Err(err) => {
+                error!("Entry {} failed: {err:?}", entry.name);
+                Status::Failed(format!("{err:#}"))
+            }
+        });
+        if let Err(err) = write_statuses(&status_path, &statuses).await {
+            error!("{err:#}");
+            stop.get_or_insert(err);
+        }
+
+        // No new entries are started, but the running ones can finish.
+        if stop.is_none() && (plan.fail_fast || cancel.is_cancelled()) {
+            if let Err(err) = result {
+                stop = Some(err.context(format!("entry {} failed", entry.name)));
+            }
+        }
+    }
+    if let Some(err) = stop {
+        return Err(err);
+    }
+
+    let failed = statuses
+        .iter()
+        .filter(|s| !s.status.as_ref().is_some_and(Status::is_completed))
+        .count();
+    if failed > 0 {
+        anyhow::bail!("{failed} of {total} entries failed");
+    }
+    Ok(())
+}
+
+/// The hosts an entry uses, to decide which entries can run at the same time.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HostSet {
+    /// The entry does not name its hosts, so it can use any of them.
+    All,
+    Only(BTreeSet<String>),
+}
+
+impl HostSet {
+    /// Whether an entry using `self` can not run at the same time as one using `other`.
+    pub fn overlaps(&self, other: &HostSet) -> bool {
+        match (self, other) {
+            (HostSet::Only(a), HostSet::Only(b)) => !a.is_disjoint(b),
+            _ => true,
+        }
+    }
+}
+
+impl PlanEntry {
+    /// The hosts the script of the entry gives a role.
+    pub fn host_set(&self) -> HostSet {
+        let Ok((script, _)) = self.parse() else {
+            return HostSet::All;
+        };
+        match script.hosts() {
+            Some(hosts) if !hosts.is_empty() => {
+                HostSet::Only(hosts.into_iter().map(str::to_string).collect())
+            }
+            _ => HostSet::All,
+        }
+    }
+}
+
+/// The position in `pending` of the next entry that can start with the entries in `running`
+/// running. Entries start in the order of the plan, as long as they do not share hosts with a
+/// running entry or with an earlier entry that is still waiting.
+pub fn next_entry(pending: &[usize], running: &[usize], host_sets: &[HostSet]) -> Option<usize> {
+    let mut blocked: Vec<&HostSet> = running.iter().map(|&i| &host_sets[i]).collect();
+    for (next, &i) in pending.iter().enumerate() {
+        if !blocked.iter().any(|set| set.overlaps(&host_sets[i])) {
+            return Some(next);
+        }
+        blocked.push(&host_sets[i]);
+    }
+    None
+}
+
+/// Move the output of an earlier attempt at the entry `name` aside, as scripts do not overwrite
+/// existing files.
+async fn move_earlier_output(out_path: &Path, name: &str) -> anyhow::Result<()> {
+    let entry_path = out_path.join(name);
+    if !entry_path.exists() {
+        return Ok(());
+    }
+    let old = out_path.join(format!(
+        "{name}.failed-{}",
+        unix_time(SystemTime::now()) as u64
+    ));
+    warn!("Moving earlier output of {name} to {}", old.display());
+    tokio::fs::rename(&entry_path, &old)
+        .await
+        .context("could not move earlier output")
+}
+
+/// Run all repetitions of an entry.
+async fn run_entry(
+    entry: &PlanEntry,
+    hosts: &Hosts,
+    out_path: &Path,
+    cancel: &CancellationToken,
+) -> anyhow::Result<()> {
+    if entry.repeat == 1 {
+        let (script, _) = entry.parse()?;
+        return run_script(script, hosts, out_path, cancel).await;
+    }
+
+    let iterations = IterationArgs {
+        repeat: entry.repeat,
+        repeat_cooldown: entry.cooldown,
+        fail_fast: false,
+    };
+    let fingerprint = fingerprint(&entry.parse()?.0)?;
+    let statuses = run_iterations(
+        &iterations,
+        out_path,
+        &fingerprint,
+        iterations.repetitions(),
+        &mut Vec::new(),
+        |_, run_path| async move {
+            let (script, _) = entry.parse()?;
+            run_script(script, hosts, &run_path, cancel).await
+        },
+    )
+    .await?;
+    let failed = statuses.iter().filter(|s| !s.status.is_completed()).count();
+    if failed > 0 {
+        anyhow::bail!("{failed} of {} runs failed", statuses.len());
+    }
+    Ok(())
+}
+
+/// Run the script of an entry. Scripts can not run plans, but the future is boxed to break the
+/// recursion of the types, which also lets entries run on their own tasks.
+fn run_script<'a>(
+    mut script: Script,
+    hosts: &'a Hosts,
+    out_path: &'a Path,
+    cancel: &'a CancellationToken,
+) -> Pin<Box<dyn Future<Output = anyhow::Result<()>> + Send + 'a>> {
+    Box::pin(async move {
+        // Checked when the plan was validated, this uses the hosts listed twice once.
+        script.check_hosts().map_err(anyhow::Error::msg)?;
+        // Entries that run at the same time each time their own phases.
+        let timing = Timing::new();
+        let result =
+            timing::scope(&timing, scripts::run_with(script, hosts, out_path, cancel)).await;
+        if let Err(err) = write_artifact(out_path, &timing.finish()).await {
+            warn!("Could not save the phase timings: {err:#}");
+        }
+        result?;
+        Ok(())
+    })
+}
+
+/// Read the statuses of an earlier run of the plan. Entries are matched by name, so entries can
+/// be added to the plan before resuming it.
+async fn resume_statuses(plan: &Plan, status_path: &Path) -> anyhow::Result<Vec<EntryStatus>> {
+    let earlier: Vec<EntryStatus> = match tokio::fs::read_to_string(status_path).await {
+        Ok(raw) => ron::from_str(&raw).context("could not parse the earlier plan status")?,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+            warn!("Nothing to resume, starting the plan from the beginning");
+            Vec::new()
+        }
+        Err(err) => return Err(err).context("could not read the earlier plan status"),
+    };
+    Ok(plan
+        .entries
+        .iter()
+        .map(|entry| {
+            earlier
+                .iter()
+                .find(|s| s.name == entry.name)
+                .cloned()
+                .unwrap_or_else(|| EntryStatus {
+                    name: entry.name.clone(),
+                    status: None,
+                    start: None,
+                    end: None,
+                })
+        })
+        .collect())
+}
+
+async fn write_statuses(status_path: &Path, statuses: &[EntryStatus]) -> anyhow::Result<()> {
+    let dump =
+        to_string_pretty(statuses, PrettyConfig::new()).context("failed to serialize statuses")?;
+    tokio::fs::write(status_path, dump)
+        .await
+        .context("failed to write plan status")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NETWORK: &str = r#""-F", "5180", "-B", "20", "--ssid", "s""#;
+
+    fn plan(entries: &[(&str, &str)]) -> Plan {
+        let entries: String = entries
+            .iter()
+            .map(|(script, args)| {
+                format!(
+                    "[[entry]]\nname = \"{script}-{}\"\nscript = \"{script}\"\nargs = [{args}, {NETWORK}]\n",
+                    args.len()
+                )
+            })
+            .collect();
+        toml::from_str(&entries).unwrap()
+    }
+
+    fn iperf(ap: &str, clients: &str) -> String {
+        format!(
+            r#""--ap", "{ap}", "--clients", "{clients}", "--no-monitor", "-U", "true", "-T", "10M""#
+        )
+    }
+
+    fn only(hosts: &[&str]) -> HostSet {
+        HostSet::Only(hosts.iter().map(|h| h.to_string()).collect())
+    }
+
+    #[test]
+    fn host_sets_overlap() {
+        assert!(only(&["a", "b"]).overlaps(&only(&["b", "c"])));
+        assert!(!only(&["a"]).overlaps(&only(&["b"])));
+        assert!(HostSet::All.overlaps(&only(&["b"])));
+        assert!(only(&["a"]).overlaps(&HostSet::All));
+    }
+
+    #[test]
+    fn host_set_of_entry_is_its_roles() {
+        let plan = plan(&[(
+            "interference",
+            &format!(
+                r#"{}, "--interferer-ap", "iap", "--interferer-client", "ic", "--interferer-load", "1M""#,
+                iperf("ap", "a,b")
+            ),
+        )]);
+        assert_eq!(
+            plan.entries[0].host_set(),
+            only(&["a", "ap", "b", "iap", "ic"])
+        );
+    }
+
+    #[test]
+    fn entries_without_hosts_use_all() {
+        let entry = PlanEntry {
+            name: "cleanup".to_string(),
+            script: "cleanup".to_string(),
+            args: Vec::new(),
+            repeat: 1,
+            cooldown: 0,
+        };
+        assert_eq!(entry.host_set(), HostSet::All);
+    }
+
+    #[test]
+    fn entries_with_conflicting_roles_are_rejected() {
+        let conflicting = plan(&[("iperf", &iperf("ap", "a,ap"))]);
+        let err = conflicting.entries[0].check().unwrap_err().to_string();
+        assert!(
+            err.starts_with("invalid arguments for entry `iperf-"),
+            "{err}"
+        );
+        assert!(
+            err.contains("`ap` is the access point and also a client"),
+            "{err}"
+        );
+
+        let deduped = plan(&[("iperf", &iperf("ap", "a,b,a"))]);
+        let Script::Iperf(args) = deduped.entries[0].check().unwrap() else {
+            panic!("not an iperf script");
+        };
+        assert_eq!(args.clients, ["a", "b"]);
+    }
+
+    #[test]
+    fn disjoint_entries_start_together() {
+        let host_sets = [
+            only(&["ap1", "a"]),
+            only(&["ap2", "b"]),
+            only(&["ap1", "c"]),
+        ];
+        assert_eq!(next_entry(&[0, 1, 2], &[], &host_sets), Some(0));
+        assert_eq!(next_entry(&[1, 2], &[0], &host_sets), Some(0));
+        assert_eq!(next_entry(&[2], &[0, 1], &host_sets), None);
+        assert_eq!(next_entry(&[2], &[1], &host_sets), Some(0));
+    }
+
+    #[test]
+    fn waiting_entries_keep_their_order() {
+        // The third entry does not overlap the running one, but it does overlap the second,
+        // which was waiting first.
+        let host_sets = [only(&["a"]), only(&["a", "b"]), only(&["b"])];
+        assert_eq!(next_entry(&[1, 2], &[0], &host_sets), None);
+        let host_sets = [only(&["a"]), HostSet::All, only(&["b"])];
+        assert_eq!(next_entry(&[1, 2], &[0], &host_sets), None);
+        assert_eq!(next_entry(&[1, 2], &[], &host_sets), Some(0));
+    }
+}
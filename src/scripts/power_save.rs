@@ -0,0 +1,458 @@
+//! Compare the latency and loss of clients with power save disabled and enabled.
+
+use std::{collections::BTreeMap, fmt::Write as _, path::Path, sync::Arc, time::Duration};
+
+use anyhow::{anyhow, Context};
+use clap::Parser;
+use ron::ser::{to_string_pretty, PrettyConfig};
+use serde::{Deserialize, Serialize};
+use tracing::{error, info, warn};
+
+use crate::{
+    cancel::{Aborted, CancellationToken},
+    capture::analysis::{self, PowerSaveFrames},
+    daemon::stop_all,
+    driver::wifi,
+    hosts::{Host, HostId, Hosts},
+    mac::MacAddr,
+    scripts::{
+        iperf::{kill_stale_iperfs, parse_json, server_ports, spawn_servers},
+        latency::{collect_pings, percentile, ping_command, write_results, PingResult},
+        mark_failed,
+        monitoring::{run_captured, MonitorArgs},
+    },
+    utils::{host_file, parse_bitrate, run_all},
+};
+
+#[derive(Parser, Debug, Clone, Serialize, Deserialize)]
+pub struct PowerSaveArgs {
+    /// The host id of the access point. Its IP address is pinged.
+    #[clap(long)]
+    pub ap: String,
+    /// The host id of where the iperf servers are running. Defaults to the access point.
+    #[clap(long)]
+    #[serde(default)]
+    pub server: Option<String>,
+    /// The host ids of the clients to toggle power save on.
+    #[clap(long, required = true, value_delimiter = ',', num_args = 1..)]
+    pub clients: Vec<String>,
+    /// How long to measure in each state in seconds.
+    #[clap(short = 'd', long, default_value = "30")]
+    #[serde(default = "default_duration")]
+    pub duration: u64,
+    /// The time between pings in seconds.
+    #[clap(short = 'i', long, default_value = "0.2")]
+    #[serde(default = "default_interval")]
+    pub interval: f64,
+    /// The UDP load of every client in bits per second, 0 to only ping.
+    #[clap(long, default_value = "1M", value_parser = parse_bitrate)]
+    #[serde(default = "default_udp_rate")]
+    pub udp_rate: u64,
+    /// Send the UDP traffic from the clients instead of to them. Traffic to the clients is
+    /// buffered by the access point while they sleep.
+    #[clap(long)]
+    #[serde(default)]
+    pub uplink: bool,
+    #[command(flatten)]
+    pub network: MonitorArgs,
+}
+
+// The defaults of the arguments when they are read from a config file. They match the
+// defaults of the command line.
+fn default_duration() -> u64 {
+    30
+}
+
+fn default_interval() -> f64 {
+    0.2
+}
+
+fn default_udp_rate() -> u64 {
+    1_000_000
+}
+
+/// The results of a client in a single state.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ClientPhase {
+    /// Round trip time percentiles in milliseconds.
+    pub rtt_median: Option<f64>,
+    pub rtt_p95: Option<f64>,
+    pub rtt_p99: Option<f64>,
+    pub ping_loss_percent: Option<f64>,
+    pub udp_loss_percent: Option<f64>,
+    /// The power save frames the client sent, the most any monitor captured.
+    pub frames: Option<PowerSaveFrames>,
+}
+
+/// The comparison of both states, written to `power-save.ron`.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct PowerSaveSummary {
+    /// Whether power save was enabled on every client before the experiment.
+    pub original: BTreeMap<HostId, bool>,
+    pub off: BTreeMap<HostId, ClientPhase>,
+    pub on: BTreeMap<HostId, ClientPhase>,
+    pub failures: Vec<String>,
+}
+
+pub async fn run(
+    args: PowerSaveArgs,
+    hosts: &Hosts,
+    out_path: &Path,
+    cancel: &CancellationToken,
+) -> anyhow::Result<()> {
+    tokio::fs::create_dir_all(out_path)
+        .await
+        .context("could not create output folder")?;
+    let args_dump =
+        to_string_pretty(&args, PrettyConfig::new()).context("failed to serialize args info")?;
+    tokio::fs::write(out_path.join("arguments.ron"), args_dump)
+        .await
+        .context("failed to save arguments")?;
+
+    let clients: Vec<Arc<Host>> = hosts
+        .get_many(&args.clients)
+        .map_err(|missing| anyhow!("no host with id {missing}"))?
+        .cloned()
+        .collect();
+    if let Some(client) = clients
+        .iter()
+        .find(|c| c.extra_data.interface_name().is_none())
+    {
+        anyhow::bail!(
+            "client `{}` needs an interface name to toggle power save",
+            client.id
+        );
+    }
+
+    let mut summary = PowerSaveSummary::default();
+    for client in &clients {
+        // SAFETY: the interface name of every client was checked above.
+        let ifname = client.extra_data.interface_name().expect("interface name");
+        let enabled = wifi::power_save(client, ifname)
+            .await
+            .with_context(|| format!("could not read the power save setting of `{}`", client.id))?;
+        summary.original.insert(client.id.clone(), enabled);
+    }
+
+    let result = async {
+        for enabled in [false, true] {
+            let phase_path = out_path.join(if enabled { "ps-on" } else { "ps-off" });
+            let phase = run_phase(&args, hosts, &clients, enabled, &phase_path, cancel).await;
+            let (results, failures) = phase.with_context(|| {
+                format!("power save {} failed", if enabled { "on" } else { "off" })
+            })?;
+            summary.failures.extend(failures);
+            if enabled {
+                summary.on = results;
+            } else {
+                summary.off = results;
+            }
+        }
+        anyhow::Ok(())
+    }
+    .await;
+
+    // The original setting is restored even if the experiment failed.
+    for client in &clients {
+        let ifname = client.extra_data.interface_name().expect("interface name");
+        let original = summary.original[&client.id];
+        if let Err(err) = wifi::set_power_save(client, ifname, original).await {
+            error!(host = client.id, "Could not restore power save: {err:?}");
+            summary
+                .failures
+                .push(format!("could not restore power save on `{}`", client.id));
+        }
+    }
+    if let Err(err) = result {
+        if !err.is::<Aborted>() {
+            mark_failed(out_path, &format!("{err:#}")).await?;
+        }
+        return Err(err);
+    }
+
+    info!("Power save off vs on:\n{}", comparison_table(&summary));
+    let dump =
+        to_string_pretty(&summary, PrettyConfig::new()).context("failed to serialize summary")?;
+    tokio::fs::write(out_path.join("power-save.ron"), dump)
+        .await
+        .context("failed to save summary")?;
+
+    if !summary.failures.is_empty() {
+        let reason = summary.failures.join("; ");
+        mark_failed(out_path, &reason).await?;
+        anyhow::bail!("{reason}");
+    }
+    Ok(())
+}
+
+/// Measure with power save set to `enabled` on all clients. Returns the results of every client
+/// and the reasons the measurement was incomplete.
+async fn run_phase(
+    args: &PowerSaveArgs,
+    hosts: &Hosts,
+    clients: &[Arc<Host>],
+    enabled: bool,
+    out_path: &Path,
+    cancel: &CancellationToken,
+) -> anyhow::Result<(BTreeMap<HostId, ClientPhase>, Vec<String>)> {
+    tokio::fs::create_dir_all(out_path)
+        .await
+        .context("could not create output folder")?;
+    info!(
+        "Setting power save {} on {} clients",
+        if enabled { "on" } else { "off" },
+        clients.len()
+    );
+    for client in clients {
+        let ifname = client.extra_data.interface_name().expect("interface name");
+        wifi::set_power_save(client, ifname, enabled)
+            .await
+            .with_context(|| format!("could not set power save on `{}`", client.id))?;
+    }
+
+    let access_point = hosts.get(&args.ap).context("access point id not found")?;
+    let server = hosts
+        .get(args.server.as_deref().unwrap_or(&args.ap))
+        .context("server id not found")?;
+    let target = access_point
+        .ip_address()
+        .await
+        .context("failed to get IP address of access point")?;
+    let server_ip = server
+        .ip_address()
+        .await
+        .context("failed to get IP address of server")?;
+    let bssid = args.network.check_access_point(access_point).await?;
+
+    // Light traffic is run next to the pings, so there is something to buffer while sleeping.
+    let ports = server_ports(clients.len());
+    let traffic = args.udp_rate > 0;
+    if traffic {
+        let mut cleanup_hosts = clients.to_vec();
+        cleanup_hosts.push(server.clone());
+        kill_stale_iperfs(&cleanup_hosts, ports.clone()).await?;
+    }
+
+    // The monitors capture everything in the BSS rather than following clients, as sleeping
+    // clients send few frames of their own.
+    cancel.check()?;
+    let mut monitor = args
+        .network
+        .capture(hosts, bssid, Duration::from_secs(args.duration), out_path)
+        .await?;
+
+    let servers = match traffic {
+        true => {
+            let server_ifname = server.extra_data.interface_name();
+            match spawn_servers(server, server_ifname, &server_ip, ports.clone(), true).await {
+                Ok(servers) => servers,
+                Err(err) => {
+                    if let Some(monitor) = monitor {
+                        if let Err(err) = monitor
+                            .stop_and_collect("iperf servers did not start")
+                            .await
+                        {
+                            warn!("Could not collect the captures: {err:?}");
+                        }
+                    }
+                    return Err(err);
+                }
+            }
+        }
+        false => Vec::new(),
+    };
+
+    info!("Pinging {target} for {}s", args.duration);
+    let pings = run_all(clients, |h| {
+        ping_command(
+            &target,
+            args.interval,
+            args.duration,
+            h.extra_data.interface_name(),
+        )
+    });
+    let mut client_ports = ports.clone();
+    let udp = async {
+        if !traffic {
+            return Ok(Vec::new());
+        }
+        run_all(clients, |h| {
+            let port = client_ports
+                .next()
+                .expect("there is a port for every client");
+            udp_command(args, &server_ip, port, h.extra_data.interface_name())
+        })
+        .await
+    };
+    let traffic = async { anyhow::Ok(tokio::join!(pings, udp)) };
+    let traffic = run_captured(&mut monitor, cancel, traffic).await;
+    // The servers exited after their test, unless their client failed to connect.
+    stop_all(servers).await;
+    let (pings, udp) = traffic?;
+    // The capture is collected before anything can fail, so it is not left running.
+    let captured = match monitor {
+        Some(monitor) => {
+            info!("Waiting for capture to finish");
+            Some(monitor.wait_or_stop(cancel).await)
+        }
+        None => None,
+    };
+
+    let mut failures = Vec::new();
+    let ping_results = match pings {
+        Ok(outputs) => {
+            let (results, ping_failures) = collect_pings(out_path, outputs).await?;
+            write_results(out_path, &results).await?;
+            failures.extend(ping_failures);
+            results
+        }
+        Err(err) => {
+            failures.push(format!("ping failed: {err:#}"));
+            BTreeMap::new()
+        }
+    };
+    let mut udp_loss = BTreeMap::new();
+    match udp {
+        Ok(outputs) => {
+            for (host, output) in outputs {
+                tokio::fs::write(host_file(out_path, &host.id, "json"), &output.stdout)
+                    .await
+                    .context("failed to save iperf output")?;
+                match parse_json(&output.stdout, Duration::ZERO) {
+                    Ok(result) => {
+                        let loss = result
+                            .directions
+                            .first()
+                            .and_then(|d| d.summary.as_ref())
+                            .and_then(|s| s.lost_percent);
+                        udp_loss.insert(host.id.clone(), loss);
+                    }
+                    Err(err) => {
+                        warn!(host = host.id, "Could not parse iperf output: {err:?}");
+                        failures.push(format!("iperf on `{}` failed", host.id));
+                    }
+                }
+            }
+        }
+        Err(err) => failures.push(format!("iperf failed: {err:#}")),
+    }
+
+    let mut frames: BTreeMap<MacAddr, PowerSaveFrames> = BTreeMap::new();
+    let mut stations = BTreeMap::new();
+    if let Some(captured) = captured {
+        match captured {
+            Ok(output) => {
+                for client in clients {
+                    let ifname = client.extra_data.interface_name().expect("interface name");
+                    match wifi::interface_info(client, ifname).await.map(|i| i.addr) {
+                        Ok(Some(addr)) => {
+                            stations.insert(client.id.clone(), addr);
+                        }
+                        Ok(None) | Err(_) => {
+                            warn!(host = client.id, "Could not determine the MAC address")
+                        }
+                    }
+                }
+                let addresses: Vec<MacAddr> = stations.values().copied().collect();
+                for (host, _) in output.captures {
+                    let capture = host_file(out_path, &host, "pcapng");
+                    match analysis::power_save_frames(&capture, &addresses).await {
+                        Ok(found) => {
+                            for (station, counts) in found {
+                                let entry = frames.entry(station).or_default();
+                                entry.ps_poll = entry.ps_poll.max(counts.ps_poll);
+                                entry.null = entry.null.max(counts.null);
+                                entry.qos_null = entry.qos_null.max(counts.qos_null);
+                                entry.power_management =
+                                    entry.power_management.max(counts.power_management);
+                            }
+                        }
+                        Err(err) => warn!("Could not analyze {}: {err:?}", capture.display()),
+                    }
+                }
+            }
+            Err(err) if err.is::<Aborted>() => return Err(err),
+            Err(err) => {
+                error!("Monitor failed: {err:?}");
+                failures.push(format!("monitor failed: {err:#}"));
+            }
+        }
+    }
+
+    let results = clients
+        .iter()
+        .map(|client| {
+            let mut phase = ping_results
+                .get(&client.id)
+                .map(rtt_percentiles)
+                .unwrap_or_default();
+            phase.udp_loss_percent = udp_loss.get(&client.id).copied().flatten();
+            phase.frames = stations
+                .get(&client.id)
+                .and_then(|station| frames.get(station))
+                .cloned();
+            (client.id.clone(), phase)
+        })
+        .collect();
+    Ok((results, failures))
+}
+
+/// Build the command of a UDP iperf client sending `--udp-rate` for the duration of a state.
+fn udp_command(args: &PowerSaveArgs, server_ip: &str, port: u16, bind_dev: Option<&str>) -> String {
+    let mut cmd = format!(
+        "iperf3 -c {server_ip} -p {port} -t {} -u -b {} -J",
+        args.duration, args.udp_rate
+    );
+    if let Some(ifname) = bind_dev {
+        _ = write!(cmd, " --bind-dev {ifname}");
+    }
+    if !args.uplink {
+        cmd.push_str(" -R");
+    }
+    cmd
+}
+
+fn rtt_percentiles(result: &PingResult) -> ClientPhase {
+    let mut rtts: Vec<f64> = result.samples.iter().map(|s| s.rtt_ms).collect();
+    ClientPhase {
+        rtt_median: percentile(&mut rtts, 50.0),
+        rtt_p95: percentile(&mut rtts, 95.0),
+        rtt_p99: percentile(&mut rtts, 99.0),
+        ping_loss_percent: result.loss_percent,
+        ..Default::default()
+    }
+}
+
+fn comparison_table(summary: &PowerSaveSummary) -> String {
+    let mut out = format!(
+        "{:<12} {:>5} {:>10} {:>10} {:>10} {:>10} {:>8} {:>8}\n",
+        "client", "ps", "median", "p95", "ping loss", "udp loss", "ps-poll", "pm bit"
+    );
+    let opt = |v: Option<f64>, unit: &str| v.map_or("-".to_string(), |v| format!("{v:.2}{unit}"));
+    for (host, original) in &summary.original {
+        for (state, phases) in [("off", &summary.off), ("on", &summary.on)] {
+            let Some(phase) = phases.get(host) else {
+                continue;
+            };
+            let frames = phase.frames.as_ref();
+            _ = writeln!(
+                out,
+                "{:<12} {state:>5} {:>10} {:>10} {:>10} {:>10} {:>8} {:>8}",
+                host,
+                opt(phase.rtt_median, "ms"),
+                opt(phase.rtt_p95, "ms"),
+                opt(phase.ping_loss_percent, "%"),
+                opt(phase.udp_loss_percent, "%"),
+                frames.map_or("-".to_string(), |f| f.ps_poll.to_string()),
+                frames.map_or("-".to_string(), |f| f.power_management.to_string()),
+            );
+        }
+        _ = writeln!(
+            out,
+            "{:<12} restored power save {}",
+            host,
+            if *original { "on" } else { "off" }
+        );
+    }
+    out
+}
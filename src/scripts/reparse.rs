@@ -0,0 +1,163 @@
+//! Parse the text output of iperf clients in results directories from before `--json` existed,
+//! writing the `results.ron` and `summary.ron` a run with `--json` writes.
+
+use std::{
+    collections::BTreeMap,
+    fs::File,
+    io::BufReader,
+    path::{Path, PathBuf},
+};
+
+use anyhow::Context;
+use clap::Parser;
+use ron::ser::{to_string_pretty, PrettyConfig};
+use serde::{Deserialize, Serialize};
+use tracing::{debug, info, warn};
+
+use crate::{
+    hosts::HostId,
+    results::{load_artifact, IperfResults},
+    scripts::{
+        iperf::{parse_text, summarize, IperfResult, Outcome, SummaryInput},
+        meta::Meta,
+    },
+};
+
+#[derive(Parser, Debug, Clone, Serialize, Deserialize)]
+pub struct ReparseArgs {
+    /// The results directory to parse. Every directory in it with `<client>.txt` iperf outputs
+    /// gets a `results.ron` and `summary.ron`.
+    #[clap(long)]
+    pub dir: PathBuf,
+    /// Replace the `results.ron` and `summary.ron` of directories that already have them. Without
+    /// this a `summary.ron` is kept, as it can contain more than the iperf results.
+    #[clap(long)]
+    #[serde(default)]
+    pub force: bool,
+}
+
+/// Parse the iperf outputs in every directory below `args.dir`. Returns how many directories got
+/// results.
+pub fn run(args: &ReparseArgs) -> anyhow::Result<usize> {
+    if !args.dir.is_dir() {
+        anyhow::bail!("{} is not a directory", args.dir.display());
+    }
+    let mut parsed = 0;
+    // The hosts whose files are named differently from their id, by file name. They are recorded
+    // in the `meta.ron` of a run and apply to the directories of its iterations as well.
+    let mut pending = vec![(args.dir.clone(), BTreeMap::new())];
+    while let Some((dir, mut hosts)) = pending.pop() {
+        if let Some(meta) = load_artifact::<Meta>(&dir)? {
+            hosts = meta
+                .file_stems
+                .into_iter()
+                .map(|(host, stem)| (stem, host))
+                .collect();
+        }
+        let mut reports = Vec::new();
+        let entries =
+            std::fs::read_dir(&dir).with_context(|| format!("could not read {}", dir.display()))?;
+        for entry in entries {
+            let path = entry
+                .with_context(|| format!("could not read {}", dir.display()))?
+                .path();
+            if path.is_dir() {
+                pending.push((path, hosts.clone()));
+            } else if let Some(stem) = report_host(&path) {
+                let host = hosts.get(&stem).cloned().unwrap_or(stem);
+                reports.push((host, path));
+            }
+        }
+        if !reports.is_empty() && reparse_dir(&dir, reports, args.force)? {
+            parsed += 1;
+        }
+    }
+    info!("Wrote results for {parsed} directories");
+    Ok(parsed)
+}
+
+/// The file stem of the client a file could be the iperf output of, from a name like `nuc3.txt`.
+/// The other text files of a run, like `nuc3.stderr.txt` and `nuc3.attempt-1.txt`, have a dot in
+/// their stem.
+fn report_host(path: &Path) -> Option<String> {
+    if path.extension()? != "txt" {
+        return None;
+    }
+    let stem = path.file_stem()?.to_str()?;
+    (!stem.contains('.')).then(|| stem.to_string())
+}
+
+/// Parse the iperf outputs of a single run and write its results. Returns whether anything was
+/// written, files that are not iperf output are skipped.
+fn reparse_dir(dir: &Path, reports: Vec<(HostId, PathBuf)>, force: bool) -> anyhow::Result<bool> {
+    let results_path = dir.join("results.ron");
+    if results_path.exists() && !force {
+        info!(
+            "Skipping {}, it already has results, pass --force to replace them",
+            dir.display()
+        );
+        return Ok(false);
+    }
+
+    let mut results: BTreeMap<HostId, IperfResult> = BTreeMap::new();
+    let mut outcome = Outcome::default();
+    for (host, path) in reports {
+        let file =
+            File::open(&path).with_context(|| format!("could not open {}", path.display()))?;
+        let parsed = match parse_text(BufReader::new(file)) {
+            Ok(v) => v,
+            Err(err) => {
+                debug!("Skipping {}: {err:#}", path.display());
+                continue;
+            }
+        };
+        if let Some(err) = &parsed.result.error {
+            warn!(host, "Iperf failed in {}: {err}", dir.display());
+            outcome
+                .failures
+                .push(format!("iperf client on `{host}` failed: {err}"));
+        }
+        results.insert(host, parsed.result);
+    }
+    if results.is_empty() {
+        return Ok(false);
+    }
+
+    let results = IperfResults::new(results);
+    let dump = to_string_pretty(&results, PrettyConfig::new())
+        .context("failed to serialize iperf results")?;
+    std::fs::write(&results_path, dump).context("failed to save iperf results")?;
+    // Runs without `--json` wrote a summary without the clients, but with the monitors.
+    let summary_path = dir.join("summary.ron");
+    if summary_path.exists() && !force {
+        info!(
+            "Parsed {} iperf outputs in {}, keeping its summary.ron",
+            results.clients.len(),
+            dir.display()
+        );
+        return Ok(true);
+    }
+    let summary = summarize(SummaryInput {
+        offered_load: 0,
+        groups: &[],
+        packet_size: None,
+        mss: None,
+        results: &results.clients,
+        clients: &BTreeMap::new(),
+        monitor: None,
+        bitrates: None,
+        latency: None,
+        goodput_check: None,
+        outcome,
+    });
+    let dump = to_string_pretty(&summary, PrettyConfig::new())
+        .context("failed to serialize run summary")?;
+    std::fs::write(summary_path, dump).context("failed to save run summary")?;
+    info!(
+        "Parsed {} iperf outputs in {}:\n{}",
+        results.clients.len(),
+        dir.display(),
+        summary.table()
+    );
+    Ok(true)
+}
@@ -0,0 +1,231 @@
+//! Run an iperf experiment again using the `arguments.ron` saved by an earlier run.
+
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Context};
+use clap::{CommandFactory, FromArgMatches, Parser};
+use ron::ser::{to_string_pretty, PrettyConfig};
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+
+use crate::{
+    cancel::CancellationToken,
+    hosts::Hosts,
+    scripts::{
+        config,
+        iperf::{self, IperfArgs},
+        KeyNumbers,
+    },
+};
+
+#[derive(Parser, Debug, Clone, Serialize, Deserialize)]
+pub struct ReplayArgs {
+    /// The `arguments.ron` of the run to replay.
+    #[clap(long)]
+    pub from_args: PathBuf,
+    /// Iperf arguments overriding those of the earlier run, passed after `--`. For example:
+    /// `-- --duration 30 --clients nuc1,nuc2`.
+    #[clap(last = true)]
+    #[serde(default)]
+    pub overrides: Vec<String>,
+}
+
+pub async fn run(
+    args: ReplayArgs,
+    hosts: &Hosts,
+    out_path: &Path,
+    cancel: &CancellationToken,
+) -> anyhow::Result<KeyNumbers> {
+    let iperf_args = iperf_args(&args).await?;
+
+    tokio::fs::create_dir_all(out_path)
+        .await
+        .context("could not create output folder")?;
+    // The iperf script writes the arguments it ran with, these only record where they came from.
+    let args_dump =
+        to_string_pretty(&args, PrettyConfig::new()).context("failed to serialize args info")?;
+    tokio::fs::write(out_path.join("replay-arguments.ron"), args_dump)
+        .await
+        .context("failed to save arguments")?;
+
+    info!("Replaying {}", args.from_args.display());
+    iperf::run(iperf_args, hosts, out_path, cancel).await
+}
+
+/// Print what the replayed experiment would do, see [iperf::dry_run].
+pub async fn dry_run(args: ReplayArgs, hosts: &Hosts, out_path: &Path) -> anyhow::Result<()> {
+    iperf::dry_run(iperf_args(&args).await?, hosts, out_path)
+}
+
+/// The saved arguments of the earlier run with the overrides applied.
+async fn iperf_args(args: &ReplayArgs) -> anyhow::Result<IperfArgs> {
+    let raw = tokio::fs::read_to_string(&args.from_args)
+        .await
+        .with_context(|| format!("could not read {}", args.from_args.display()))?;
+    let mut iperf_args = load_args(&raw)?;
+    if !args.overrides.is_empty() {
+        iperf_args = apply_overrides(iperf_args, &args.overrides)?;
+    }
+    Ok(iperf_args)
+}
+
+/// Parse the saved arguments of an earlier run.
+///
+/// Arguments that were added after that run get their default value and arguments that no
+/// longer exist are ignored, both with a warning.
+pub fn load_args(raw: &str) -> anyhow::Result<IperfArgs> {
+    let args: IperfArgs = ron::from_str(raw).context("could not parse the saved arguments")?;
+
+    let saved: ron::Value = ron::from_str(raw).context("could not parse the saved arguments")?;
+    // JSON keeps the names of enum variants, which a RON value does not.
+    let parsed = serde_json::to_value(&args).context("failed to serialize arguments")?;
+    let mut defaulted = Vec::new();
+    let mut unknown = Vec::new();
+    compare_fields(&saved, &parsed, "", &mut defaulted, &mut unknown);
+    if !defaulted.is_empty() {
+        warn!(
+            "The saved arguments do not contain {}, using the defaults",
+            defaulted.join(", ")
+        );
+    }
+    if !unknown.is_empty() {
+        warn!(
+            "Ignoring saved arguments that no longer exist: {}",
+            unknown.join(", ")
+        );
+    }
+    Ok(args)
+}
+
+/// Collect the fields that are only in the parsed arguments, which were defaulted, and those that
+/// are only in the saved arguments, which were ignored.
+fn compare_fields(
+    saved: &ron::Value,
+    parsed: &serde_json::Value,
+    prefix: &str,
+    defaulted: &mut Vec<String>,
+    unknown: &mut Vec<String>,
+) {
+    let (ron::Value::Map(saved), serde_json::Value::Object(parsed)) = (saved, parsed) else {
+        return;
+    };
+    for (key, value) in parsed {
+        let path = format!("{prefix}{key}");
+        match saved.get(&ron::Value::String(key.clone())) {
+            Some(saved) => compare_fields(saved, value, &format!("{path}."), defaulted, unknown),
+            None => defaulted.push(path),
+        }
+    }
+    for key in saved.keys() {
+        if let ron::Value::String(key) = key {
+            if !parsed.contains_key(key) {
+                unknown.push(format!("{prefix}{key}"));
+            }
+        }
+    }
+}
+
+/// Override the saved arguments with those given on the command line, which use the syntax of
+/// the iperf script.
+pub fn apply_overrides(args: IperfArgs, overrides: &[String]) -> anyhow::Result<IperfArgs> {
+    // Only the overridden arguments are passed, so nothing is required.
+    let mut command = config::relax_args(IperfArgs::command().no_binary_name(true));
+    let matches = command
+        .try_get_matches_from_mut(overrides)
+        .map_err(|err| anyhow!("invalid overrides: {}", err.render()))?;
+
+    // Updating also resets the arguments that were not passed to their defaults, so only the
+    // arguments that were passed are taken over.
+    let mut updated = args.clone();
+    updated
+        .update_from_arg_matches(&matches)
+        .context("invalid overrides")?;
+    let updated = serde_json::to_value(&updated).context("failed to serialize arguments")?;
+    let mut merged = serde_json::to_value(&args).context("failed to serialize arguments")?;
+    config::copy_overrides(&mut merged, &updated, &command, &matches);
+    serde_json::from_value(merged).context("could not apply overrides")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scripts::iperf::tests::iperf_args as cli_args;
+
+    fn json(args: &IperfArgs) -> serde_json::Value {
+        serde_json::to_value(args).unwrap()
+    }
+
+    /// The arguments as an earlier run saved them to `arguments.ron`.
+    fn saved(args: &IperfArgs) -> String {
+        to_string_pretty(args, PrettyConfig::new()).unwrap()
+    }
+
+    fn fields(raw: &str) -> (Vec<String>, Vec<String>) {
+        let args = load_args(raw).unwrap();
+        let (mut defaulted, mut unknown) = (Vec::new(), Vec::new());
+        compare_fields(
+            &ron::from_str(raw).unwrap(),
+            &json(&args),
+            "",
+            &mut defaulted,
+            &mut unknown,
+        );
+        (defaulted, unknown)
+    }
+
+    #[test]
+    fn saved_arguments_are_replayed_unchanged() {
+        let args = cli_args(&["--udp", "true", "--throughput", "50M", "--duration", "30"]);
+        let raw = saved(&args);
+        assert_eq!(json(&load_args(&raw).unwrap()), json(&args));
+        assert_eq!(fields(&raw), (Vec::new(), Vec::new()));
+    }
+
+    #[test]
+    fn arguments_added_after_the_run_are_defaulted() {
+        let args = cli_args(&["--udp", "false", "--omit", "3"]);
+        let raw = saved(&args);
+        let old = raw
+            .lines()
+            .filter(|line| !line.trim_start().starts_with("omit:"))
+            .collect::<Vec<_>>()
+            .join("\n");
+        assert_ne!(old, raw);
+
+        assert_eq!(load_args(&old).unwrap().omit, 0);
+        assert_eq!(fields(&old), (vec!["omit".to_string()], Vec::new()));
+    }
+
+    #[test]
+    fn arguments_that_no_longer_exist_are_ignored() {
+        let args = cli_args(&["--udp", "false"]);
+        let raw = saved(&args).replacen('(', "(\n    removed_option: 3,", 1);
+        assert_eq!(json(&load_args(&raw).unwrap()), json(&args));
+        assert_eq!(
+            fields(&raw),
+            (Vec::new(), vec!["removed_option".to_string()])
+        );
+    }
+
+    #[test]
+    fn overrides_replace_only_the_given_arguments() {
+        let args = cli_args(&["--udp", "false", "--duration", "10", "--omit", "2"]);
+        let overrides = ["--duration", "30", "--clients", "x,y"].map(String::from);
+        let replayed = apply_overrides(load_args(&saved(&args)).unwrap(), &overrides).unwrap();
+
+        assert_eq!(replayed.duration, 30);
+        assert_eq!(replayed.clients, ["x", "y"]);
+        assert_eq!(replayed.omit, 2);
+        assert_eq!(replayed.network.ssid, args.network.ssid);
+
+        let invalid = ["--duration", "soon"].map(String::from);
+        let err = apply_overrides(args, &invalid).unwrap_err();
+        assert!(err.to_string().starts_with("invalid overrides"), "{err}");
+    }
+
+    #[test]
+    fn invalid_saved_arguments_are_rejected() {
+        assert!(load_args("(clients: 3)").is_err());
+        assert!(load_args("not ron").is_err());
+    }
+}
@@ -0,0 +1,601 @@
+//! Collect the summaries of the iterations of a sweep or repeated run into a single campaign
+//! report, written to `campaign.ron` and `campaign.csv` in the directory of the sweep.
+//!
+//! Iterations are found through the `runs.ron` index and the `done.ron` markers the iterations
+//! write. Their parameters are read from their names, like `clients-3/load-100M/run-02`, where the
+//! `run-<n>` part is the repetition. Iterations with the same parameters are repeats of the same
+//! configuration, which are aggregated.
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt::Write,
+    io::ErrorKind,
+    path::{Path, PathBuf},
+};
+
+use anyhow::Context;
+use clap::Parser;
+use ron::ser::{to_string_pretty, PrettyConfig};
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+
+use crate::{
+    hosts::HostId,
+    output::csv_field,
+    results::{load_run, RunArtifacts},
+    scripts::iterations::{IterationStatus, Status},
+};
+
+#[derive(Parser, Debug, Clone, Serialize, Deserialize)]
+pub struct ReportArgs {
+    /// The output directory of the sweep or repeated run.
+    #[clap(long)]
+    pub dir: PathBuf,
+}
+
+/// The report of a sweep, written to `campaign.ron`.
+#[derive(Debug, Clone, Serialize)]
+pub struct CampaignReport {
+    pub dir: PathBuf,
+    /// Every iteration in the order they ran, including those that failed or did not finish.
+    pub iterations: Vec<IterationReport>,
+    /// The aggregates of the repeats of every configuration, in the order they first ran.
+    pub configurations: Vec<ConfigurationReport>,
+}
+
+/// A single iteration of the sweep. The measurements are `None` if the iteration did not write
+/// them.
+#[derive(Debug, Clone, Serialize)]
+pub struct IterationReport {
+    /// The name of the iteration, the path of its directory relative to the sweep.
+    pub name: String,
+    /// The swept parameters by name, like `load` with `100M`.
+    pub parameters: BTreeMap<String, String>,
+    /// The number of the repetition, if the configuration was repeated.
+    pub repetition: Option<u32>,
+    /// How the iteration finished like `completed` or `failed`, or `incomplete` if it did not.
+    pub status: String,
+    /// Why the iteration failed or could not be read.
+    pub error: Option<String>,
+    /// The total throughput in bits per second, summed over the directions.
+    pub goodput: Option<f64>,
+    /// The throughput of every client in bits per second, summed over the directions.
+    pub clients: BTreeMap<HostId, f64>,
+    /// The percentage of UDP packets that was lost.
+    pub loss_percent: Option<f64>,
+    /// TCP retransmissions per second over all clients, from `results.ron`.
+    pub retransmit_rate: Option<f64>,
+    /// The fraction of packets the monitors dropped while capturing.
+    pub drop_ratio: Option<f64>,
+}
+
+/// The repeats of a configuration. Only completed iterations count towards the aggregates.
+#[derive(Debug, Clone, Serialize)]
+pub struct ConfigurationReport {
+    pub parameters: BTreeMap<String, String>,
+    /// The number of iterations of the configuration.
+    pub iterations: usize,
+    /// The number of those that completed.
+    pub completed: usize,
+    pub goodput: Option<Aggregate>,
+    pub loss_percent: Option<Aggregate>,
+    pub retransmit_rate: Option<Aggregate>,
+    pub drop_ratio: Option<Aggregate>,
+}
+
+/// The mean and sample standard deviation of a value over the repeats of a configuration.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct Aggregate {
+    pub mean: f64,
+    /// `None` with fewer than two values.
+    pub stddev: Option<f64>,
+    /// The number of repeats that have the value.
+    pub count: usize,
+}
+
+impl Aggregate {
+    /// Aggregate `values`, `None` if there are none.
+    pub fn of(values: &[f64]) -> Option<Self> {
+        if values.is_empty() {
+            return None;
+        }
+        let count = values.len();
+        let mean = values.iter().sum::<f64>() / count as f64;
+        let stddev = (count > 1).then(|| {
+            let squares: f64 = values.iter().map(|v| (v - mean).powi(2)).sum();
+            (squares / (count - 1) as f64).sqrt()
+        });
+        Some(Self {
+            mean,
+            stddev,
+            count,
+        })
+    }
+}
+
+/// Only the status of a `done.ron` marker, to read it without knowing the type of the summary.
+#[derive(Deserialize)]
+struct MarkerStatus {
+    status: Status,
+}
+
+/// Write the report of the sweep in `--dir`. Returns the report that was written.
+pub fn run(args: &ReportArgs) -> anyhow::Result<CampaignReport> {
+    let found = discover(&args.dir)?;
+    if found.is_empty() {
+        anyhow::bail!(
+            "no iterations found in {}, is it the output directory of a sweep or a run with \
+             --repeat?",
+            args.dir.display()
+        );
+    }
+
+    let iterations: Vec<IterationReport> = found
+        .into_iter()
+        .map(|(name, status)| {
+            let path = args.dir.join(&name);
+            let mut report = IterationReport::new(name, status);
+            match load_run(&path) {
+                Ok(artifacts) => report.measure(&artifacts),
+                Err(err) => {
+                    warn!("Could not load iteration {}: {err:#}", report.name);
+                    report.error.get_or_insert_with(|| format!("{err:#}"));
+                }
+            }
+            report
+        })
+        .collect();
+    let report = CampaignReport {
+        dir: args.dir.clone(),
+        configurations: group(&iterations),
+        iterations,
+    };
+    info!("Campaign:\n{}", report.table());
+
+    let dump = to_string_pretty(&report, PrettyConfig::new())
+        .context("failed to serialize campaign report")?;
+    std::fs::write(args.dir.join("campaign.ron"), dump).context("failed to write campaign.ron")?;
+    std::fs::write(args.dir.join("campaign.csv"), report.csv())
+        .context("failed to write campaign.csv")?;
+    info!(
+        "Wrote the report of {} iterations to {}",
+        report.iterations.len(),
+        args.dir.display()
+    );
+    Ok(report)
+}
+
+/// Find the iterations in `dir` with their status, `None` if they did not finish.
+///
+/// The iterations of the `runs.ron` index come first, in the order they ran, followed by any
+/// other directory with a `done.ron` marker or a `summary.ron`, like an iteration that was running
+/// when the controller stopped. An iteration with its own `runs.ron`, like a step of an
+/// attenuation sweep with repetitions, is replaced by its iterations.
+fn discover(dir: &Path) -> anyhow::Result<Vec<(String, Option<Status>)>> {
+    let mut found = Vec::new();
+    discover_in(dir, "", &mut found)?;
+    Ok(found)
+}
+
+fn discover_in(
+    dir: &Path,
+    prefix: &str,
+    found: &mut Vec<(String, Option<Status>)>,
+) -> anyhow::Result<()> {
+    let join = |name: &str| {
+        if prefix.is_empty() {
+            name.to_string()
+        } else {
+            format!("{prefix}/{name}")
+        }
+    };
+    let mut seen = BTreeSet::new();
+    for entry in read_index(dir)? {
+        seen.insert(entry.name.clone());
+        if dir.join(&entry.name).join("runs.ron").exists() {
+            discover_in(&dir.join(&entry.name), &join(&entry.name), found)?;
+        } else {
+            found.push((join(&entry.name), Some(entry.status)));
+        }
+    }
+
+    // Iterations missing from the index, nested in directories like `clients-3/load-100M`.
+    let mut pending = vec![(dir.to_path_buf(), String::new())];
+    while let Some((path, name)) = pending.pop() {
+        let mut children = Vec::new();
+        let entries = std::fs::read_dir(&path)
+            .with_context(|| format!("could not read {}", path.display()))?;
+        for entry in entries {
+            let entry = entry.with_context(|| format!("could not read {}", path.display()))?;
+            if entry.path().is_dir() {
+                children.push(entry.file_name().to_string_lossy().into_owned());
+            }
+        }
+        children.sort();
+        for child in children.into_iter().rev() {
+            let child_name = if name.is_empty() {
+                child.clone()
+            } else {
+                format!("{name}/{child}")
+            };
+            let child_path = path.join(&child);
+            if seen.contains(&child_name) {
+                continue;
+            }
+            if child_path.join("runs.ron").exists() {
+                discover_in(&child_path, &join(&child_name), found)?;
+            } else if child_path.join("done.ron").exists() {
+                found.push((join(&child_name), read_marker_status(&child_path)));
+            } else if child_path.join("summary.ron").exists() {
+                found.push((join(&child_name), None));
+            } else {
+                pending.push((child_path, child_name));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Read the `runs.ron` index in `dir`, empty if there is none.
+fn read_index(dir: &Path) -> anyhow::Result<Vec<IterationStatus>> {
+    let path = dir.join("runs.ron");
+    match std::fs::read_to_string(&path) {
+        Ok(raw) => {
+            ron::from_str(&raw).with_context(|| format!("could not parse {}", path.display()))
+        }
+        Err(err) if err.kind() == ErrorKind::NotFound => Ok(Vec::new()),
+        Err(err) => Err(err).with_context(|| format!("could not read {}", path.display())),
+    }
+}
+
+/// The status in the `done.ron` marker in `dir`, `None` with a warning if it can not be read.
+pub fn read_marker_status(dir: &Path) -> Option<Status> {
+    let path = dir.join("done.ron");
+    let marker = std::fs::read_to_string(&path)
+        .context("could not read it")
+        .and_then(|raw| ron::from_str::<MarkerStatus>(&raw).context("could not parse it"));
+    match marker {
+        Ok(marker) => Some(marker.status),
+        Err(err) => {
+            warn!("Ignoring {}: {err:#}", path.display());
+            None
+        }
+    }
+}
+
+/// The swept parameters and the repetition in the name of an iteration. Every part of the name
+/// is a parameter with its value after the first `-`, except `run-<n>`, the repetition. Parts
+/// without a value are kept by their position, like `step-2` for the second part.
+pub fn parse_name(name: &str) -> (BTreeMap<String, String>, Option<u32>) {
+    let mut parameters = BTreeMap::new();
+    let mut repetition = None;
+    for (i, part) in name.split('/').enumerate() {
+        match part.split_once('-') {
+            Some(("run", n)) if n.parse::<u32>().is_ok() => repetition = n.parse().ok(),
+            Some((key, value)) => _ = parameters.insert(key.to_string(), value.to_string()),
+            None => _ = parameters.insert(format!("step-{}", i + 1), part.to_string()),
+        }
+    }
+    (parameters, repetition)
+}
+
+impl IterationReport {
+    fn new(name: String, status: Option<Status>) -> Self {
+        let (parameters, repetition) = parse_name(&name);
+        let error = match &status {
+            Some(Status::Failed(err)) => Some(err.clone()),
+            _ => None,
+        };
+        Self {
+            name,
+            parameters,
+            repetition,
+            status: status
+                .as_ref()
+                .map_or("incomplete", Status::name)
+                .to_string(),
+            error,
+            goodput: None,
+            clients: BTreeMap::new(),
+            loss_percent: None,
+            retransmit_rate: None,
+            drop_ratio: None,
+        }
+    }
+
+    fn is_completed(&self) -> bool {
+        self.status == Status::Completed.name()
+    }
+
+    /// Fill in the measurements from the artifacts of the iteration.
+    fn measure(&mut self, artifacts: &RunArtifacts) {
+        if let Some(summary) = &artifacts.summary {
+            self.goodput = Some(summary.totals.iter().map(|d| d.goodput).sum());
+            self.clients = summary
+                .clients
+                .iter()
+                .map(|(host, c)| (host.clone(), c.directions.iter().map(|d| d.goodput).sum()))
+                .collect();
+            let (lost, packets) = summary
+                .totals
+                .iter()
+                .filter_map(|d| Some((d.lost_packets?, d.packets?)))
+                .fold((0, 0), |(l, p), (lost, packets)| (l + lost, p + packets));
+            self.loss_percent = (packets > 0).then(|| lost as f64 / packets as f64 * 100.0);
+            let (dropped, total) = summary
+                .monitors
+                .values()
+                .flatten()
+                .filter_map(|s| Some((s.dropped?, s.packets? + s.dropped?)))
+                .fold((0, 0), |(d, t), (dropped, total)| (d + dropped, t + total));
+            self.drop_ratio = (total > 0).then(|| dropped as f64 / total as f64);
+        }
+        if let Some(results) = &artifacts.results {
+            self.retransmit_rate = results
+                .clients
+                .values()
+                .flat_map(|r| &r.directions)
+                .filter_map(|d| {
+                    let summary = d.summary.as_ref()?;
+                    let retransmits = summary.retransmits?;
+                    (summary.seconds > 0.0).then(|| retransmits as f64 / summary.seconds)
+                })
+                .reduce(|a, b| a + b);
+        }
+    }
+}
+
+/// Group the iterations by their parameters, in the order the configurations first ran.
+pub fn group(iterations: &[IterationReport]) -> Vec<ConfigurationReport> {
+    let mut configurations: Vec<(&BTreeMap<String, String>, Vec<&IterationReport>)> = Vec::new();
+    for iteration in iterations {
+        match configurations
+            .iter_mut()
+            .find(|(parameters, _)| **parameters == iteration.parameters)
+        {
+            Some((_, repeats)) => repeats.push(iteration),
+            None => configurations.push((&iteration.parameters, vec![iteration])),
+        }
+    }
+
+    configurations
+        .into_iter()
+        .map(|(parameters, repeats)| {
+            let completed: Vec<&IterationReport> = repeats
+                .iter()
+                .copied()
+                .filter(|i| i.is_completed())
+                .collect();
+            let aggregate = |value: fn(&IterationReport) -> Option<f64>| {
+                Aggregate::of(
+                    &completed
+                        .iter()
+                        .filter_map(|i| value(i))
+                        .collect::<Vec<_>>(),
+                )
+            };
+            ConfigurationReport {
+                parameters: parameters.clone(),
+                iterations: repeats.len(),
+                completed: completed.len(),
+                goodput: aggregate(|i| i.goodput),
+                loss_percent: aggregate(|i| i.loss_percent),
+                retransmit_rate: aggregate(|i| i.retransmit_rate),
+                drop_ratio: aggregate(|i| i.drop_ratio),
+            }
+        })
+        .collect()
+}
+
+impl CampaignReport {
+    /// Format the report as CSV with a row per iteration. The parameters and clients of all
+    /// iterations get a column each, which is empty for the iterations without them.
+    pub fn csv(&self) -> String {
+        let parameters: BTreeSet<&String> = self
+            .iterations
+            .iter()
+            .flat_map(|i| i.parameters.keys())
+            .collect();
+        let clients: BTreeSet<&HostId> = self
+            .iterations
+            .iter()
+            .flat_map(|i| i.clients.keys())
+            .collect();
+
+        let mut csv = "iteration".to_string();
+        for parameter in &parameters {
+            _ = write!(csv, ",{}", csv_field(parameter));
+        }
+        csv.push_str(",repetition,status,goodput,loss_percent,retransmit_rate,drop_ratio");
+        for client in &clients {
+            _ = write!(csv, ",{}", csv_field(&format!("{client}_goodput")));
+        }
+        csv.push_str(",error\n");
+
+        let field = |v: Option<String>| v.unwrap_or_default();
+        for iteration in &self.iterations {
+            csv.push_str(&csv_field(&iteration.name));
+            for parameter in &parameters {
+                _ = write!(
+                    csv,
+                    ",{}",
+                    field(iteration.parameters.get(*parameter).map(|v| csv_field(v)))
+                );
+            }
+            _ = write!(
+                csv,
+                ",{},{},{},{},{},{}",
+                field(iteration.repetition.map(|v| v.to_string())),
+                iteration.status,
+                field(iteration.goodput.map(|v| format!("{v:.0}"))),
+                field(iteration.loss_percent.map(|v| format!("{v:.3}"))),
+                field(iteration.retransmit_rate.map(|v| format!("{v:.2}"))),
+                field(iteration.drop_ratio.map(|v| format!("{v:.5}"))),
+            );
+            for client in &clients {
+                _ = write!(
+                    csv,
+                    ",{}",
+                    field(iteration.clients.get(*client).map(|v| format!("{v:.0}")))
+                );
+            }
+            _ = writeln!(csv, ",{}", field(iteration.error.as_deref().map(csv_field)));
+        }
+        csv
+    }
+
+    /// Format the configurations as a human-readable table, with the mean and standard deviation
+    /// of the throughput over their completed repeats.
+    pub fn table(&self) -> String {
+        let mut out = String::new();
+        _ = writeln!(
+            out,
+            "{:<32} {:>9} {:>14} {:>14} {:>9}",
+            "configuration", "completed", "goodput", "stddev", "loss %"
+        );
+        for configuration in &self.configurations {
+            let name = configuration
+                .parameters
+                .iter()
+                .map(|(key, value)| format!("{key}={value}"))
+                .collect::<Vec<_>>()
+                .join(" ");
+            let show = |v: Option<f64>, precision: usize| {
+                v.map_or("-".to_string(), |v| format!("{v:.precision$}"))
+            };
+            _ = writeln!(
+                out,
+                "{:<32} {:>9} {:>14} {:>14} {:>9}",
+                if name.is_empty() { "-" } else { &name },
+                format!("{}/{}", configuration.completed, configuration.iterations),
+                show(configuration.goodput.map(|a| a.mean), 0),
+                show(configuration.goodput.and_then(|a| a.stddev), 0),
+                show(configuration.loss_percent.map(|a| a.mean), 3),
+            );
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn iteration(name: &str, status: Option<Status>, goodput: Option<f64>) -> IterationReport {
+        let mut report = IterationReport::new(name.to_string(), status);
+        report.goodput = goodput;
+        report
+    }
+
+    #[test]
+    fn repeats_are_grouped_by_their_parameters() {
+        let iterations = [
+            iteration("load-100M/run-1", Some(Status::Completed), Some(90.0)),
+            iteration("load-200M/run-1", Some(Status::Completed), Some(150.0)),
+            iteration("load-100M/run-2", Some(Status::Completed), Some(94.0)),
+            iteration("load-200M/run-2", Some(Status::Completed), Some(170.0)),
+            iteration("load-100M/run-3", Some(Status::Completed), Some(92.0)),
+        ];
+        let configurations = group(&iterations);
+
+        // In the order the configurations first ran.
+        let loads = configurations
+            .iter()
+            .map(|c| c.parameters["load"].as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(loads, ["100M", "200M"]);
+
+        let first = &configurations[0];
+        assert_eq!((first.iterations, first.completed), (3, 3));
+        assert_eq!(
+            first.goodput,
+            Some(Aggregate {
+                mean: 92.0,
+                stddev: Some(2.0),
+                count: 3,
+            })
+        );
+        assert_eq!(configurations[1].goodput.unwrap().mean, 160.0);
+    }
+
+    #[test]
+    fn only_completed_iterations_are_aggregated() {
+        let iterations = [
+            iteration("clients-3/run-1", Some(Status::Completed), Some(80.0)),
+            iteration(
+                "clients-3/run-2",
+                Some(Status::Failed("client sta2 failed".to_string())),
+                Some(10.0),
+            ),
+            iteration("clients-3/run-3", Some(Status::Aborted), Some(20.0)),
+            iteration("clients-3/run-4", None, None),
+        ];
+        let configurations = group(&iterations);
+
+        assert_eq!(configurations.len(), 1);
+        let configuration = &configurations[0];
+        assert_eq!((configuration.iterations, configuration.completed), (4, 1));
+        assert_eq!(
+            configuration.goodput,
+            Some(Aggregate {
+                mean: 80.0,
+                stddev: None,
+                count: 1,
+            })
+        );
+        assert_eq!(configuration.loss_percent, None);
+    }
+
+    #[test]
+    fn iterations_differing_in_any_parameter_are_separate() {
+        let iterations = [
+            iteration("clients-3/load-100M", Some(Status::Completed), Some(1.0)),
+            iteration("clients-4/load-100M", Some(Status::Completed), Some(2.0)),
+            iteration(
+                "clients-3/load-100M/run-2",
+                Some(Status::Completed),
+                Some(3.0),
+            ),
+        ];
+        let configurations = group(&iterations);
+        let counts = configurations
+            .iter()
+            .map(|c| c.iterations)
+            .collect::<Vec<_>>();
+        assert_eq!(counts, [2, 1]);
+    }
+
+    #[test]
+    fn configurations_without_a_value_have_no_aggregate() {
+        let iterations = [
+            iteration("step-1/run-1", Some(Status::Completed), None),
+            iteration("step-1/run-2", Some(Status::Completed), Some(5.0)),
+        ];
+        let configuration = &group(&iterations)[0];
+        assert_eq!(configuration.goodput.unwrap().count, 1);
+        assert!(group(&[]).is_empty());
+    }
+
+    #[test]
+    fn names_are_split_into_parameters_and_the_repetition() {
+        let (parameters, repetition) = parse_name("clients-3/load-100M/run-02");
+        assert_eq!(
+            parameters,
+            BTreeMap::from([
+                ("clients".to_string(), "3".to_string()),
+                ("load".to_string(), "100M".to_string()),
+            ])
+        );
+        assert_eq!(repetition, Some(2));
+
+        let (parameters, repetition) = parse_name("baseline/run-x");
+        assert_eq!(
+            parameters,
+            BTreeMap::from([
+                ("step-1".to_string(), "baseline".to_string()),
+                ("run".to_string(), "x".to_string()),
+            ])
+        );
+        assert_eq!(repetition, None);
+    }
+}
@@ -0,0 +1,475 @@
+//! Force a client to roam between two access points and measure the interruption of its traffic.
+
+use std::{
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+use anyhow::{anyhow, Context};
+use clap::{Parser, ValueEnum};
+use ron::ser::{to_string_pretty, PrettyConfig};
+use serde::{Deserialize, Serialize};
+use tokio::time::sleep;
+use tracing::{info, warn};
+
+use crate::{
+    cancel::{Aborted, CancellationToken, Reason},
+    capture::analysis,
+    driver::wifi,
+    hosts::{Host, Hosts},
+    mac::MacAddr,
+    monitor::{Monitor, MonitorConfig, DEFAULT_LEAD_IN, DEFAULT_LEAD_OUT},
+    progress::{self, Phase},
+    scripts::{
+        iperf::{
+            parse_json, server_command, wait_for_servers, Interval, TrafficDirection,
+            SERVER_START_TIMEOUT,
+        },
+        latency::{parse_ping, ping_command, PingSample},
+        mark_failed,
+    },
+    utils::{host_file, unix_time},
+};
+
+#[derive(Parser, Debug, Clone, Serialize, Deserialize)]
+pub struct RoamArgs {
+    /// The host id of the access point the client starts on.
+    #[clap(long)]
+    pub from_ap: String,
+    /// The host id of the access point the client should roam to.
+    #[clap(long)]
+    pub to_ap: String,
+    /// The host id of the client that is made to roam.
+    #[clap(long)]
+    pub client: String,
+    /// The host id of the host the traffic is sent to. It must be reachable through both access
+    /// points, for example a wired host behind them.
+    #[clap(long)]
+    pub server: String,
+    /// The SSID both access points use.
+    #[clap(long)]
+    pub ssid: String,
+    /// The host ids of the hosts that capture on the channel of the first access point.
+    #[clap(long, required = true, value_delimiter = ',', num_args = 1..)]
+    pub from_monitors: Vec<String>,
+    /// The host ids of the hosts that capture on the channel of the second access point.
+    #[clap(long, required = true, value_delimiter = ',', num_args = 1..)]
+    pub to_monitors: Vec<String>,
+    /// How the roam is triggered.
+    #[clap(long, default_value = "txpower")]
+    #[serde(default = "default_trigger")]
+    pub trigger: RoamTrigger,
+    /// The transmit power the first access point drops to in dBm, with `--trigger txpower`.
+    #[clap(long, default_value = "0", allow_negative_numbers = true)]
+    #[serde(default)]
+    pub txpower: i32,
+    /// The traffic sent by the client to measure the interruption.
+    #[clap(long, default_value = "ping")]
+    #[serde(default = "default_traffic")]
+    pub traffic: RoamTraffic,
+    /// How long the traffic runs in seconds.
+    #[clap(short = 'd', long, default_value = "20")]
+    #[serde(default = "default_duration")]
+    pub duration: u64,
+    /// How long after the traffic started to trigger the roam in seconds.
+    #[clap(long, default_value = "5")]
+    #[serde(default = "default_trigger_after")]
+    pub trigger_after: u64,
+    /// The port of the iperf server.
+    #[clap(long, default_value = "5201")]
+    #[serde(default = "default_port")]
+    pub port: u16,
+}
+
+// The defaults of the arguments when they are read from a config file. They match the
+// defaults of the command line.
+fn default_trigger() -> RoamTrigger {
+    RoamTrigger::Txpower
+}
+
+fn default_traffic() -> RoamTraffic {
+    RoamTraffic::Ping
+}
+
+fn default_duration() -> u64 {
+    20
+}
+
+fn default_trigger_after() -> u64 {
+    5
+}
+
+fn default_port() -> u16 {
+    5201
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+pub enum RoamTrigger {
+    /// Drop the transmit power of the first access point, so the client loses it.
+    Txpower,
+    /// Send an 802.11v BSS transition request from the first access point.
+    BssTransition,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+pub enum RoamTraffic {
+    /// Ping the server every 50 ms.
+    Ping,
+    /// Run an iperf TCP stream from the client to the server.
+    Iperf,
+}
+
+/// The results of a roam, written to `roam.ron`.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct RoamResult {
+    /// When the roam was triggered, in seconds since the unix epoch.
+    pub trigger: f64,
+    /// When the first (re)association response to the client was captured on the channel of the
+    /// second access point after the trigger, in seconds since the unix epoch.
+    pub reassociated: Option<f64>,
+    /// The time from the trigger until the reassociation response in seconds.
+    pub reassociation_time: Option<f64>,
+    /// The longest interruption of the traffic around the trigger in seconds.
+    pub gap: Option<f64>,
+    /// When the longest interruption started, in seconds since the unix epoch.
+    pub gap_start: Option<f64>,
+}
+
+pub async fn run(
+    args: RoamArgs,
+    hosts: &Hosts,
+    out_path: &Path,
+    cancel: &CancellationToken,
+) -> anyhow::Result<()> {
+    tokio::fs::create_dir_all(out_path)
+        .await
+        .context("could not create output folder")?;
+    let args_dump =
+        to_string_pretty(&args, PrettyConfig::new()).context("failed to serialize args info")?;
+    tokio::fs::write(out_path.join("arguments.ron"), args_dump)
+        .await
+        .context("failed to save arguments")?;
+    if args.trigger_after >= args.duration {
+        anyhow::bail!("--trigger-after must be smaller than the duration");
+    }
+
+    let from_ap = hosts
+        .get(&args.from_ap)
+        .context("access point id not found")?;
+    let to_ap = hosts
+        .get(&args.to_ap)
+        .context("access point id not found")?;
+    let client = hosts.get(&args.client).context("client id not found")?;
+    let server = hosts.get(&args.server).context("server id not found")?;
+    let interface = |host: &Host| {
+        host.extra_data
+            .interface_name()
+            .map(str::to_string)
+            .ok_or_else(|| anyhow!("host `{}` has no interface name configured", host.id))
+    };
+    let (from_if, to_if, client_if) = (interface(from_ap)?, interface(to_ap)?, interface(client)?);
+
+    let from_info = wifi::interface_info(from_ap, &from_if).await?;
+    let to_info = wifi::interface_info(to_ap, &to_if).await?;
+    let client_mac = wifi::interface_info(client, &client_if)
+        .await?
+        .addr
+        .context("could not determine the address of the client")?;
+    let (Some(from_bssid), Some(to_bssid)) = (from_info.addr, to_info.addr) else {
+        anyhow::bail!("could not determine the BSSIDs of the access points");
+    };
+    info!("Roaming {client_mac} from {from_bssid} to {to_bssid}");
+
+    progress::enter(Phase::Connecting);
+    client
+        .ensure_associated(&args.ssid)
+        .await
+        .context("failed to associate client")?;
+    let server_ip = server
+        .ip_address()
+        .await
+        .context("failed to get IP address of server")?;
+
+    cancel.check()?;
+    let mut monitors = Vec::new();
+    for (name, bssid, channel, ids) in [
+        (
+            "from",
+            from_bssid,
+            (from_info.frequency, from_info.width),
+            &args.from_monitors,
+        ),
+        (
+            "to",
+            to_bssid,
+            (to_info.frequency, to_info.width),
+            &args.to_monitors,
+        ),
+    ] {
+        let (Some(frequency), Some(bandwidth)) = channel else {
+            anyhow::bail!("could not determine the channel of the `{name}` access point");
+        };
+        let monitor = MonitorConfig {
+            ssid: args.ssid.clone(),
+            bssid: Some(bssid),
+            frequency,
+            bandwidth,
+            monitors: ids.clone(),
+            targets: Vec::new(),
+            duration: Duration::from_secs(args.duration),
+            lead_in: DEFAULT_LEAD_IN,
+            lead_out: DEFAULT_LEAD_OUT,
+            output_path: Some(out_path.join(name)),
+            set_aids: false,
+            known_aids: None,
+            filter: None,
+            merge_captures: false,
+            dedup_window: None,
+        }
+        .start(hosts)
+        .await
+        .with_context(|| format!("failed to start `{name}` capture"))?;
+        monitors.push((name, monitor));
+    }
+
+    let traffic = start_traffic(&args, client, &client_if, server, &server_ip).await;
+    let traffic = match traffic {
+        Ok(traffic) => traffic,
+        Err(err) => {
+            stop_monitors(monitors, "traffic failed to start").await;
+            return Err(err);
+        }
+    };
+
+    let stop_reason = || cancel.reason().unwrap_or(Reason::User).to_string();
+    let waited = cancel.run(async {
+        sleep(Duration::from_secs(args.trigger_after)).await;
+        anyhow::Ok(())
+    });
+    if let Err(err) = waited.await {
+        traffic.abort();
+        stop_monitors(monitors, &stop_reason()).await;
+        return Err(err);
+    }
+    let mut result = RoamResult {
+        trigger: unix_time(SystemTime::now()),
+        ..Default::default()
+    };
+    info!("Triggering roam with {:?}", args.trigger);
+    let triggered = match args.trigger {
+        RoamTrigger::Txpower => wifi::set_txpower(from_ap, &from_if, Some(args.txpower)).await,
+        RoamTrigger::BssTransition => {
+            wifi::request_bss_transition(from_ap, &from_if, client_mac, to_bssid).await
+        }
+    };
+
+    let output = cancel
+        .run(async { traffic.await.context("traffic task panicked") })
+        .await;
+    if args.trigger == RoamTrigger::Txpower {
+        if let Err(err) = wifi::set_txpower(from_ap, &from_if, None).await {
+            warn!(
+                host = from_ap.id,
+                "Could not restore transmit power: {err:?}"
+            );
+        }
+    }
+
+    let output = match output {
+        Ok(output) => output,
+        Err(err) => {
+            stop_monitors(monitors, &stop_reason()).await;
+            return Err(err);
+        }
+    };
+
+    let mut failures = Vec::new();
+    if let Err(err) = &triggered {
+        failures.push(format!("triggering the roam failed: {err:#}"));
+    }
+    match output {
+        Ok(output) => {
+            let file = match args.traffic {
+                RoamTraffic::Ping => "client.ping.txt",
+                RoamTraffic::Iperf => "client.json",
+            };
+            tokio::fs::write(out_path.join(file), &output.stdout)
+                .await
+                .context("failed to save traffic output")?;
+            match traffic_gap(&args, &output.stdout, result.trigger) {
+                Ok(Some((start, gap))) => {
+                    result.gap_start = Some(start);
+                    result.gap = Some(gap);
+                }
+                Ok(None) => warn!("No interruption of the traffic found"),
+                Err(err) => failures.push(format!("could not parse the traffic: {err:#}")),
+            }
+        }
+        Err(err) => failures.push(format!("traffic failed: {err:#}")),
+    }
+
+    let mut to_captures = Vec::new();
+    let mut monitors = monitors.into_iter();
+    while let Some((name, monitor)) = monitors.next() {
+        match monitor.wait_or_stop(cancel).await {
+            Ok(output) if name == "to" => {
+                to_captures.extend(
+                    output
+                        .captures
+                        .into_iter()
+                        .map(|(host, _)| host_file(&out_path.join(name), &host, "pcapng")),
+                );
+            }
+            Ok(_) => {}
+            Err(err) if err.is::<Aborted>() => {
+                stop_monitors(monitors.collect(), &stop_reason()).await;
+                return Err(err);
+            }
+            Err(err) => failures.push(format!("`{name}` monitor failed: {err:#}")),
+        }
+    }
+    result.reassociated = reassociation(&to_captures, client_mac, result.trigger).await;
+    result.reassociation_time = result.reassociated.map(|t| t - result.trigger);
+
+    let dump =
+        to_string_pretty(&result, PrettyConfig::new()).context("failed to serialize results")?;
+    tokio::fs::write(out_path.join("roam.ron"), dump)
+        .await
+        .context("failed to save results")?;
+    let show = |v: Option<f64>| v.map_or("unknown".to_string(), |v| format!("{v:.3}s"));
+    info!(
+        "Reassociation took {}, traffic was interrupted for {}",
+        show(result.reassociation_time),
+        show(result.gap)
+    );
+
+    if !failures.is_empty() {
+        let reason = failures.join("; ");
+        mark_failed(out_path, &reason).await?;
+        anyhow::bail!("{reason}");
+    }
+    Ok(())
+}
+
+type TrafficTask = tokio::task::JoinHandle<anyhow::Result<std::process::Output>>;
+
+/// Start the traffic from the client to the server, starting an iperf server if needed.
+async fn start_traffic(
+    args: &RoamArgs,
+    client: &Arc<Host>,
+    client_if: &str,
+    server: &Arc<Host>,
+    server_ip: &str,
+) -> anyhow::Result<TrafficTask> {
+    let command = match args.traffic {
+        RoamTraffic::Ping => ping_command(server_ip, 0.05, args.duration, Some(client_if)),
+        RoamTraffic::Iperf => {
+            let server_if = server.extra_data.interface_name().map(str::to_string);
+            let command = server_command(server_if.as_deref(), server_ip, args.port, true);
+            // The server exits by itself after the test.
+            tokio::spawn({
+                let server = server.clone();
+                async move { command.build(&server.session).output().await }
+            });
+            wait_for_servers(server, args.port..args.port + 1, SERVER_START_TIMEOUT).await?;
+            format!(
+                "iperf3 -c {server_ip} -p {} -t {} -i 0.1 --bind-dev {client_if} --json",
+                args.port, args.duration
+            )
+        }
+    };
+    let client = client.clone();
+    Ok(tokio::spawn(async move {
+        Ok(client.session.shell(command).output().await?)
+    }))
+}
+
+/// Stop the monitors early, keeping what they captured.
+async fn stop_monitors(monitors: Vec<(&str, Monitor)>, reason: &str) {
+    for (name, monitor) in monitors {
+        if let Err(err) = monitor.stop_and_collect(reason).await {
+            warn!("Could not collect the `{name}` captures: {err:?}");
+        }
+    }
+}
+
+/// Find the longest interruption of the traffic in its output. Returns when it started in seconds
+/// since the unix epoch and how long it lasted.
+fn traffic_gap(args: &RoamArgs, output: &[u8], trigger: f64) -> anyhow::Result<Option<(f64, f64)>> {
+    match args.traffic {
+        RoamTraffic::Ping => {
+            let result = parse_ping(&String::from_utf8_lossy(output));
+            Ok(reply_gap(&result.samples))
+        }
+        RoamTraffic::Iperf => {
+            let result = parse_json(output, Duration::ZERO)?;
+            let intervals = &result
+                .direction(TrafficDirection::Uplink)
+                .context("iperf did not report any uplink traffic")?
+                .intervals;
+            // The intervals are relative to the start of the test, which was the trigger delay
+            // before the trigger.
+            let test_start = trigger - args.trigger_after as f64;
+            Ok(throughput_gap(intervals, args.trigger_after as f64)
+                .map(|(start, gap)| (test_start + start, gap)))
+        }
+    }
+}
+
+/// The longest time between two consecutive ping replies, and when it started.
+pub fn reply_gap(samples: &[PingSample]) -> Option<(f64, f64)> {
+    samples
+        .windows(2)
+        .map(|pair| (pair[0].timestamp, pair[1].timestamp - pair[0].timestamp))
+        .max_by(|a, b| a.1.total_cmp(&b.1))
+}
+
+/// The longest run of iperf intervals in which the throughput was below a tenth of the median
+/// throughput before `baseline_end`, and when it started. Both are in seconds since the start of
+/// the test.
+pub fn throughput_gap(intervals: &[Interval], baseline_end: f64) -> Option<(f64, f64)> {
+    let mut baseline: Vec<f64> = intervals
+        .iter()
+        .filter(|i| i.end <= baseline_end)
+        .map(|i| i.bits_per_second)
+        .collect();
+    if baseline.is_empty() {
+        return None;
+    }
+    baseline.sort_unstable_by(f64::total_cmp);
+    let threshold = baseline[baseline.len() / 2] / 10.0;
+
+    let mut longest: Option<(f64, f64)> = None;
+    let mut current: Option<(f64, f64)> = None;
+    for interval in intervals {
+        if interval.bits_per_second < threshold {
+            let start = current.map_or(interval.start, |(start, _)| start);
+            current = Some((start, interval.end - start));
+        } else {
+            current = None;
+        }
+        if let Some(run) = current {
+            if longest.is_none_or(|longest| run.1 > longest.1) {
+                longest = Some(run);
+            }
+        }
+    }
+    longest
+}
+
+/// The first (re)association response to `station` after `trigger` in any of the captures.
+async fn reassociation(captures: &[PathBuf], station: MacAddr, trigger: f64) -> Option<f64> {
+    let mut first = None::<f64>;
+    for capture in captures {
+        match analysis::association_responses(capture, station).await {
+            Ok(times) => {
+                if let Some(time) = times.into_iter().find(|&t| t >= trigger) {
+                    first = Some(first.map_or(time, |first| first.min(time)));
+                }
+            }
+            Err(err) => warn!("Could not analyze {}: {err:?}", capture.display()),
+        }
+    }
+    first
+}
@@ -0,0 +1,221 @@
+//! Checking the roles the hosts of a script are given, before connecting to them.
+//!
+//! A host given two roles makes for results that look fine but are meaningless, like a client
+//! that is also the access point sending its iperf traffic over loopback.
+
+use std::collections::BTreeSet;
+
+use tracing::warn;
+
+use crate::scripts::monitoring::MonitorArgs;
+
+/// The hosts a script uses in each role.
+#[derive(Debug, Default)]
+pub struct HostRoles<'a> {
+    /// The hosts with a single role of their own, like the access point and the server.
+    endpoints: Vec<(&'static str, &'a str)>,
+    clients: Vec<&'a str>,
+    monitors: Vec<&'a str>,
+    /// The hosts in roles that may overlap with the others, like the ping clients of
+    /// `loaded-latency` that can also run iperf.
+    others: Vec<&'a str>,
+    allow_monitor_client_overlap: bool,
+}
+
+impl<'a> HostRoles<'a> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add the host with the role `role`, like `access point`, if it is set. Endpoints may be the
+    /// same host, like a server that defaults to the access point.
+    pub fn endpoint(mut self, role: &'static str, id: impl Into<Option<&'a str>>) -> Self {
+        if let Some(id) = id.into() {
+            self.endpoints.push((role, id));
+        }
+        self
+    }
+
+    pub fn clients(mut self, ids: impl IntoIterator<Item = &'a String>) -> Self {
+        self.clients.extend(ids.into_iter().map(String::as_str));
+        self
+    }
+
+    /// Add the monitors, which may also be clients if `allow_overlap` is set.
+    pub fn monitors(
+        mut self,
+        ids: impl IntoIterator<Item = &'a String>,
+        allow_overlap: bool,
+    ) -> Self {
+        self.monitors.extend(ids.into_iter().map(String::as_str));
+        self.allow_monitor_client_overlap |= allow_overlap;
+        self
+    }
+
+    /// Add hosts in a role that may overlap with the others.
+    pub fn other(mut self, ids: impl IntoIterator<Item = &'a String>) -> Self {
+        self.others.extend(ids.into_iter().map(String::as_str));
+        self
+    }
+
+    /// Add the monitors of `network`, none with `--no-monitor`.
+    pub fn network(self, network: &'a MonitorArgs) -> Self {
+        let monitors = if network.no_monitor {
+            [].iter()
+        } else {
+            network.monitors.iter()
+        };
+        self.monitors(monitors, network.allow_monitor_client_overlap)
+    }
+
+    /// Every host that is given a role.
+    pub fn ids(&self) -> BTreeSet<&'a str> {
+        let endpoints = self.endpoints.iter().map(|&(_, id)| id);
+        let listed = [&self.clients, &self.monitors, &self.others];
+        endpoints
+            .chain(listed.into_iter().flatten().copied())
+            .collect()
+    }
+
+    /// Every host that is given roles that conflict: an endpoint that is also a client or a
+    /// monitor, and a client that is also a monitor unless that is allowed.
+    pub fn violations(&self) -> Vec<String> {
+        let mut violations = Vec::new();
+        for &(role, id) in &self.endpoints {
+            if self.clients.contains(&id) {
+                violations.push(format!("`{id}` is the {role} and also a client"));
+            }
+            if self.monitors.contains(&id) {
+                violations.push(format!("`{id}` is the {role} and also a monitor"));
+            }
+        }
+        if !self.allow_monitor_client_overlap {
+            // An endpoint that is both is reported as such already.
+            let overlap = self.clients.iter().filter(|id| {
+                self.monitors.contains(id) && !self.endpoints.iter().any(|(_, e)| e == *id)
+            });
+            for id in overlap {
+                violations.push(format!(
+                    "`{id}` is both a client and a monitor, pass --allow-monitor-client-overlap \
+                     if that is intended"
+                ));
+            }
+        }
+        violations
+    }
+}
+
+/// Remove the hosts that are listed more than once from `ids`, keeping the first, with a warning
+/// for each. `role` names the list, like `clients`.
+pub fn dedup_hosts(role: &str, ids: &mut Vec<String>) {
+    let mut seen = Vec::with_capacity(ids.len());
+    ids.retain(|id| {
+        if seen.contains(id) {
+            warn!("`{id}` is listed more than once in the {role}, using it once");
+            false
+        } else {
+            seen.push(id.clone());
+            true
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ids(ids: &[&str]) -> Vec<String> {
+        ids.iter().map(|id| id.to_string()).collect()
+    }
+
+    fn violations(clients: &[&str], monitors: &[&str], allow_overlap: bool) -> Vec<String> {
+        let (clients, monitors) = (ids(clients), ids(monitors));
+        HostRoles::new()
+            .endpoint("access point", "ap")
+            .endpoint("server", Some("srv"))
+            .clients(&clients)
+            .monitors(&monitors, allow_overlap)
+            .violations()
+    }
+
+    #[test]
+    fn distinct_roles_are_fine() {
+        assert!(violations(&["a", "b"], &["m"], false).is_empty());
+        assert!(violations(&[], &[], false).is_empty());
+    }
+
+    #[test]
+    fn access_point_as_client() {
+        assert_eq!(
+            violations(&["a", "ap"], &["m"], false),
+            ["`ap` is the access point and also a client"]
+        );
+    }
+
+    #[test]
+    fn server_as_monitor() {
+        assert_eq!(
+            violations(&["a"], &["srv"], false),
+            ["`srv` is the server and also a monitor"]
+        );
+    }
+
+    #[test]
+    fn monitor_as_client() {
+        let conflict = violations(&["a", "m"], &["m"], false);
+        assert_eq!(conflict.len(), 1);
+        assert!(conflict[0].starts_with("`m` is both a client and a monitor"));
+        assert!(violations(&["a", "m"], &["m"], true).is_empty());
+    }
+
+    #[test]
+    fn endpoint_in_both_lists_is_reported_once_per_list() {
+        assert_eq!(
+            violations(&["ap"], &["ap"], false),
+            [
+                "`ap` is the access point and also a client",
+                "`ap` is the access point and also a monitor",
+            ]
+        );
+    }
+
+    #[test]
+    fn every_violation_is_listed() {
+        assert_eq!(
+            violations(&["ap", "srv", "m"], &["m", "ap"], false).len(),
+            4
+        );
+    }
+
+    #[test]
+    fn server_may_be_the_access_point() {
+        let clients = ids(&["a"]);
+        let roles = HostRoles::new()
+            .endpoint("access point", "ap")
+            .endpoint("server", "ap")
+            .clients(&clients);
+        assert!(roles.violations().is_empty());
+        assert_eq!(roles.ids().into_iter().collect::<Vec<_>>(), ["a", "ap"]);
+    }
+
+    #[test]
+    fn other_roles_may_overlap() {
+        let (clients, others) = (ids(&["a"]), ids(&["a", "ap"]));
+        let roles = HostRoles::new()
+            .endpoint("access point", "ap")
+            .clients(&clients)
+            .other(&others);
+        assert!(roles.violations().is_empty());
+    }
+
+    #[test]
+    fn duplicates_are_removed_in_order() {
+        let mut clients = ids(&["b", "a", "b", "c", "a"]);
+        dedup_hosts("clients", &mut clients);
+        assert_eq!(clients, ["b", "a", "c"]);
+
+        let mut clients = ids(&["a", "b"]);
+        dedup_hosts("clients", &mut clients);
+        assert_eq!(clients, ["a", "b"]);
+    }
+}
@@ -0,0 +1,290 @@
+//! Search for the highest UDP load the network sustains without exceeding a loss rate.
+
+use std::{collections::BTreeMap, fmt::Write, path::Path};
+
+use anyhow::Context;
+use clap::Parser;
+use ron::ser::{to_string_pretty, PrettyConfig};
+use serde::{Deserialize, Serialize};
+use tracing::info;
+
+use crate::{
+    cancel::CancellationToken,
+    hosts::{HostId, Hosts},
+    scripts::iperf::{self, IperfArgs, IperfResult},
+    utils::{format_bitrate, parse_bitrate},
+};
+
+#[derive(Parser, Debug, Clone, Serialize, Deserialize)]
+pub struct SaturateArgs {
+    /// The iperf experiment to run. The offered load is set by the search, so `--throughput`
+    /// is ignored. `--duration` applies to the confirmation runs.
+    #[command(flatten)]
+    pub iperf: IperfArgs,
+    /// The lowest total load to search from, for example `10M`.
+    #[clap(long, value_parser = parse_bitrate)]
+    pub min_load: u64,
+    /// The highest total load to search up to, for example `500M`.
+    #[clap(long, value_parser = parse_bitrate)]
+    pub max_load: u64,
+    /// The loss rate in percent above which a load is not sustainable.
+    #[clap(long, default_value = "1")]
+    #[serde(default = "default_loss_threshold")]
+    pub loss_threshold: f64,
+    /// How long every probe runs in seconds.
+    #[clap(long, default_value = "3")]
+    #[serde(default = "default_probe_duration")]
+    pub probe_duration: u64,
+    /// How often every load is probed. The loss rates of the probes are averaged, which smooths
+    /// out the variance between runs.
+    #[clap(long, default_value = "1")]
+    #[serde(default = "default_probe_repeats")]
+    pub probe_repeats: u32,
+    /// How often to run the full experiment at the load that was found.
+    #[clap(long, default_value = "3")]
+    #[serde(default = "default_confirmations")]
+    pub confirmations: u32,
+    /// Stop searching once the bounds are this close together, for example `5M`.
+    #[clap(long, default_value = "5M", value_parser = parse_bitrate)]
+    #[serde(default = "default_tolerance")]
+    pub tolerance: u64,
+}
+
+// The defaults of the arguments when they are read from a config file. They match the
+// defaults of the command line.
+fn default_loss_threshold() -> f64 {
+    1.0
+}
+
+fn default_probe_duration() -> u64 {
+    3
+}
+
+fn default_probe_repeats() -> u32 {
+    1
+}
+
+fn default_confirmations() -> u32 {
+    3
+}
+
+fn default_tolerance() -> u64 {
+    5_000_000
+}
+
+/// A single load that was probed, written to `search.csv`.
+#[derive(Debug, Clone, Serialize)]
+pub struct ProbePoint {
+    /// The total offered load in bits per second.
+    pub load: u64,
+    /// The mean loss rate over the probes in percent, if any packets were reported.
+    pub loss_percent: Option<f64>,
+    /// The mean total goodput over the probes in bits per second.
+    pub goodput: f64,
+    pub sustainable: bool,
+}
+
+/// The result of the search, written to `saturation.ron`.
+#[derive(Debug, Clone, Serialize)]
+pub struct Saturation {
+    /// The highest sustainable total load that was found in bits per second.
+    pub load: u64,
+    /// Whether the maximum load was sustainable, so the real saturation point may be higher.
+    pub above_max: bool,
+    /// The runs at the found load, with full captures.
+    pub confirmations: Vec<ProbePoint>,
+}
+
+pub async fn run(
+    args: SaturateArgs,
+    hosts: &Hosts,
+    out_path: &Path,
+    cancel: &CancellationToken,
+) -> anyhow::Result<()> {
+    if args.iperf.udp == Some(false) {
+        anyhow::bail!("the saturation search only works with UDP");
+    }
+    if args.min_load == 0 || args.min_load >= args.max_load {
+        anyhow::bail!("--min-load must be larger than zero and smaller than --max-load");
+    }
+    let probe_args = probe_args(&args);
+    probe_args
+        .validate()
+        .context("invalid arguments for probes")?;
+
+    tokio::fs::create_dir_all(out_path)
+        .await
+        .context("could not create output folder")?;
+    let args_dump =
+        to_string_pretty(&args, PrettyConfig::new()).context("failed to serialize args info")?;
+    tokio::fs::write(out_path.join("saturate-arguments.ron"), args_dump)
+        .await
+        .context("failed to save arguments")?;
+
+    let mut trace = Vec::new();
+    let probe = |n: usize, load: u64| {
+        let probe_args = probe_args.clone();
+        let path = out_path
+            .join("probes")
+            .join(format!("probe-{:02}-{}", n, format_bitrate(load)));
+        async move {
+            info!("Probing {}", format_bitrate(load));
+            let results = iperf::run_with_pings(
+                IperfArgs {
+                    total_throughput: load,
+                    ..probe_args
+                },
+                hosts,
+                &path,
+                None,
+                cancel,
+            )
+            .await?;
+            anyhow::Ok(probe_point(load, &results, args.loss_threshold))
+        }
+    };
+
+    let max = probe(trace.len() + 1, args.max_load).await?;
+    let sustainable_max = max.sustainable;
+    trace.push(max);
+    write_trace(out_path, &trace).await?;
+
+    let (mut low, mut high) = (args.min_load, args.max_load);
+    if !sustainable_max {
+        let min = probe(trace.len() + 1, args.min_load).await?;
+        let sustainable_min = min.sustainable;
+        trace.push(min);
+        write_trace(out_path, &trace).await?;
+        if !sustainable_min {
+            anyhow::bail!(
+                "the loss rate already exceeds {}% at the minimum load",
+                args.loss_threshold
+            );
+        }
+
+        while high - low > args.tolerance {
+            let mid = low + (high - low) / 2;
+            let point = probe(trace.len() + 1, mid).await?;
+            if point.sustainable {
+                low = mid;
+            } else {
+                high = mid;
+            }
+            trace.push(point);
+            write_trace(out_path, &trace).await?;
+        }
+    } else {
+        low = args.max_load;
+    }
+    info!("Saturation found at {}", format_bitrate(low));
+
+    // The confirmation runs use the full experiment, including monitoring.
+    let mut confirm_args = IperfArgs {
+        total_throughput: low,
+        json: true,
+        client_sweep: false,
+        ..args.iperf.clone()
+    };
+    confirm_args.iterations.repeat = args.confirmations;
+    let results =
+        iperf::run_with_pings(confirm_args, hosts, &out_path.join("confirm"), None, cancel)
+            .await
+            .context("confirmation runs failed")?;
+    let confirmations = results
+        .iter()
+        .map(|r| probe_point(low, std::slice::from_ref(r), args.loss_threshold))
+        .collect();
+
+    let saturation = Saturation {
+        load: low,
+        above_max: sustainable_max,
+        confirmations,
+    };
+    let dump = to_string_pretty(&saturation, PrettyConfig::new())
+        .context("failed to serialize saturation")?;
+    tokio::fs::write(out_path.join("saturation.ron"), dump)
+        .await
+        .context("failed to save saturation")?;
+    for point in &saturation.confirmations {
+        info!(
+            "Confirmation at {}: {} loss, {:.1} Mbit/s",
+            format_bitrate(low),
+            point
+                .loss_percent
+                .map_or("unknown".to_string(), |l| format!("{l:.2}%")),
+            point.goodput / 1e6
+        );
+    }
+    Ok(())
+}
+
+/// The arguments of a probe: a short run with JSON output and without monitoring, repeated the
+/// configured number of times.
+fn probe_args(args: &SaturateArgs) -> IperfArgs {
+    let mut probe = args.iperf.clone();
+    probe.duration = args.probe_duration;
+    probe.json = true;
+    probe.throughput_sweep = None;
+    probe.client_sweep = false;
+    probe.network.no_monitor = true;
+    probe.network.monitors.clear();
+    probe.iterations.repeat = args.probe_repeats;
+    probe
+}
+
+/// Combine the results of the runs of a probe into a single point, averaging the loss rate and
+/// the goodput over the runs.
+pub fn probe_point(
+    load: u64,
+    runs: &[BTreeMap<HostId, IperfResult>],
+    loss_threshold: f64,
+) -> ProbePoint {
+    let mut losses = Vec::new();
+    let mut goodput = 0.0;
+    for run in runs {
+        let (mut lost, mut packets) = (0, 0);
+        for summary in run
+            .values()
+            .flat_map(|r| &r.directions)
+            .filter_map(|d| d.summary.as_ref())
+        {
+            lost += summary.lost_packets.unwrap_or_default();
+            packets += summary.packets.unwrap_or_default();
+            goodput += summary.bits_per_second;
+        }
+        if packets > 0 {
+            losses.push(lost as f64 / packets as f64 * 100.0);
+        }
+    }
+    let loss_percent =
+        (!losses.is_empty()).then(|| losses.iter().sum::<f64>() / losses.len() as f64);
+    ProbePoint {
+        load,
+        loss_percent,
+        goodput: goodput / runs.len().max(1) as f64,
+        // Without any reported packets the load can not be shown to be sustainable.
+        sustainable: loss_percent.is_some_and(|loss| loss <= loss_threshold),
+    }
+}
+
+/// Write every probe so far to `search.csv`.
+async fn write_trace(out_path: &Path, trace: &[ProbePoint]) -> anyhow::Result<()> {
+    let mut csv = "probe,load,loss_percent,goodput,sustainable\n".to_string();
+    for (i, point) in trace.iter().enumerate() {
+        _ = writeln!(
+            csv,
+            "{},{},{},{:.0},{}",
+            i + 1,
+            point.load,
+            point
+                .loss_percent
+                .map(|l| format!("{l:.3}"))
+                .unwrap_or_default(),
+            point.goodput,
+            point.sustainable
+        );
+    }
+    tokio::fs::write(out_path.join("search.csv"), csv)
+        .await
+        .context("failed to save search trace")
+}
@@ -0,0 +1,474 @@
+//! Run iperf for hours while only capturing short samples on a schedule, to test the stability of
+//! a network without filling the disk.
+
+use std::{
+    collections::BTreeMap,
+    fmt::Write as _,
+    path::Path,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime},
+};
+
+use anyhow::{anyhow, Context};
+use clap::Parser;
+use ron::ser::{to_string_pretty, PrettyConfig};
+use serde::{Deserialize, Serialize};
+use tokio::{
+    fs::OpenOptions,
+    io::AsyncWriteExt,
+    select,
+    task::JoinSet,
+    time::{interval_at, MissedTickBehavior},
+};
+use tracing::{error, info, warn};
+
+use crate::{
+    cancel::CancellationToken,
+    daemon::stop_all,
+    driver::wifi::{self, LinkInfo},
+    hosts::{Host, HostId, Hosts},
+    mac::MacAddr,
+    metrics,
+    monitor::{Monitor, MonitorConfig},
+    scripts::{
+        iperf::{
+            kill_stale_iperfs, parse_interval_line, server_ports, spawn_servers, split_load,
+            MIN_UDP_CLIENT_LOAD,
+        },
+        mark_failed,
+        monitoring::MonitorArgs,
+    },
+    utils::{format_bitrate, host_file, parse_bitrate, spawn_one, unix_time, Line, LineHandler},
+};
+
+/// How long after the clients should have finished to give up on them.
+const CLIENT_GRACE: Duration = Duration::from_secs(30);
+
+#[derive(Parser, Debug, Clone, Serialize, Deserialize)]
+pub struct SoakArgs {
+    /// The host id of the access point.
+    #[clap(long)]
+    pub ap: String,
+    /// The host id of where the iperf servers are running. Defaults to the access point.
+    #[clap(long)]
+    #[serde(default)]
+    pub server: Option<String>,
+    /// The host ids that will run iperf clients.
+    #[clap(long, required = true, value_delimiter = ',', num_args = 1..)]
+    pub clients: Vec<String>,
+    /// Use UDP instead of TCP.
+    #[clap(long)]
+    #[serde(default)]
+    pub udp: bool,
+    /// The total throughput that the clients should use together in bits per second, 0 for
+    /// unlimited. Required with UDP.
+    #[clap(short = 'T', long = "throughput", default_value = "0", value_parser = parse_bitrate)]
+    #[serde(default)]
+    pub total_throughput: u64,
+    /// Send the traffic from the server to the clients instead.
+    #[clap(long)]
+    #[serde(default)]
+    pub downlink: bool,
+    /// How long to keep the traffic running in seconds.
+    #[clap(long)]
+    pub total_duration: u64,
+    /// How often to capture a sample of the channel in seconds.
+    #[clap(long, default_value = "600")]
+    #[serde(default = "default_sample_every")]
+    pub sample_every: u64,
+    /// How long every sample lasts in seconds.
+    #[clap(long, default_value = "30")]
+    #[serde(default = "default_sample_length")]
+    pub sample_length: u64,
+    /// How often to record the throughput and signal of the clients in seconds.
+    #[clap(long, default_value = "60")]
+    #[serde(default = "default_stats_every")]
+    pub stats_every: u64,
+    #[command(flatten)]
+    pub network: MonitorArgs,
+}
+
+// The defaults of the arguments when they are read from a config file. They match the
+// defaults of the command line.
+fn default_sample_every() -> u64 {
+    600
+}
+
+fn default_sample_length() -> u64 {
+    30
+}
+
+fn default_stats_every() -> u64 {
+    60
+}
+
+/// A capture taken during the soak test, written to `samples.ron`.
+#[derive(Debug, Clone, Serialize)]
+pub struct Sample {
+    pub name: String,
+    /// When the sample was started, in seconds since the unix epoch.
+    pub start: f64,
+    pub error: Option<String>,
+}
+
+/// The bitrates every client reported since the last time the statistics were recorded.
+type Bitrates = Arc<Mutex<BTreeMap<HostId, Vec<f64>>>>;
+
+impl SoakArgs {
+    fn validate(&self) -> anyhow::Result<()> {
+        if self.clients.is_empty() {
+            anyhow::bail!("at least one client is required");
+        }
+        if self.udp && self.total_throughput == 0 {
+            anyhow::bail!("UDP needs an offered load, set it with --throughput");
+        }
+        if self.udp && self.total_throughput / (self.clients.len() as u64) < MIN_UDP_CLIENT_LOAD {
+            anyhow::bail!(
+                "an offered load of {} over {} UDP clients is below the minimum of {} per client",
+                format_bitrate(self.total_throughput),
+                self.clients.len(),
+                format_bitrate(MIN_UDP_CLIENT_LOAD)
+            );
+        }
+        if self.sample_length == 0 || self.sample_length >= self.sample_every {
+            anyhow::bail!("the sample length must be between zero and the sample interval");
+        }
+        if self.stats_every == 0 {
+            anyhow::bail!("--stats-every must be larger than zero");
+        }
+        Ok(())
+    }
+
+    fn client_command(
+        &self,
+        server_ip: &str,
+        port: u16,
+        load: u64,
+        bind_dev: Option<&str>,
+    ) -> String {
+        let mut cmd = format!(
+            "iperf3 -c {server_ip} -p {port} -t {} -b {load} -i 1 --forceflush",
+            self.total_duration
+        );
+        if let Some(ifname) = bind_dev {
+            _ = write!(cmd, " --bind-dev {ifname}");
+        }
+        if self.udp {
+            cmd.push_str(" -u");
+        }
+        if self.downlink {
+            cmd.push_str(" -R");
+        }
+        cmd
+    }
+}
+
+pub async fn run(
+    args: SoakArgs,
+    hosts: &Hosts,
+    out_path: &Path,
+    cancel: &CancellationToken,
+) -> anyhow::Result<()> {
+    tokio::fs::create_dir_all(out_path)
+        .await
+        .context("could not create output folder")?;
+    let args_dump =
+        to_string_pretty(&args, PrettyConfig::new()).context("failed to serialize args info")?;
+    tokio::fs::write(out_path.join("arguments.ron"), args_dump)
+        .await
+        .context("failed to save arguments")?;
+    args.validate().context("invalid arguments")?;
+
+    let clients: Vec<Arc<Host>> = hosts
+        .get_many(&args.clients)
+        .map_err(|missing| anyhow!("no host with id {missing}"))?
+        .cloned()
+        .collect();
+    let access_point = hosts.get(&args.ap).context("access point id not found")?;
+    let server = hosts
+        .get(args.server.as_deref().unwrap_or(&args.ap))
+        .context("server id not found")?;
+    let server_ifname = server.extra_data.interface_name();
+    let server_ip = server
+        .ip_address()
+        .await
+        .context("failed to get IP address of server")?;
+    let ports = server_ports(clients.len());
+
+    let bssid = args.network.check_access_point(access_point).await?;
+    let mut cleanup_hosts = clients.clone();
+    cleanup_hosts.push(server.clone());
+    kill_stale_iperfs(&cleanup_hosts, ports.clone()).await?;
+    cancel.check()?;
+
+    // The servers are not limited to a single test, so a client that is restarted by hand can
+    // connect again. They are stopped explicitly at the end, or when dropped on an error.
+    let servers = spawn_servers(server, server_ifname, &server_ip, ports.clone(), false).await?;
+
+    info!(
+        "Running {} clients for {}s, sampling {}s every {}s",
+        clients.len(),
+        args.total_duration,
+        args.sample_length,
+        args.sample_every
+    );
+    let bitrates: Bitrates = Arc::default();
+    let on_line: LineHandler = {
+        let bitrates = bitrates.clone();
+        Arc::new(move |host: &Host, kind: Line, line: &str| {
+            if kind != Line::Stdout {
+                return;
+            }
+            if let Some((_, bitrate)) = parse_interval_line(line) {
+                metrics::set_throughput(&host.id, bitrate);
+                let mut bitrates = bitrates.lock().expect("lock poisoned");
+                bitrates.entry(host.id.clone()).or_default().push(bitrate);
+            }
+        })
+    };
+    let mut running = JoinSet::new();
+    let loads = split_load(args.total_throughput, clients.len());
+    for ((client, port), load) in clients.iter().zip(ports.clone()).zip(loads) {
+        let command =
+            args.client_command(&server_ip, port, load, client.extra_data.interface_name());
+        spawn_one(&mut running, client.clone(), command, Some(on_line.clone()));
+    }
+
+    let stats_path = out_path.join("stats.csv");
+    tokio::fs::write(
+        &stats_path,
+        "time,client,throughput,signal,tx_bitrate,bssid\n",
+    )
+    .await
+    .context("failed to create statistics file")?;
+
+    let start = Instant::now();
+    let deadline = tokio::time::sleep(Duration::from_secs(args.total_duration) + CLIENT_GRACE);
+    tokio::pin!(deadline);
+    let mut stats_timer = interval_at(
+        (start + Duration::from_secs(args.stats_every)).into(),
+        Duration::from_secs(args.stats_every),
+    );
+    let mut sample_timer = interval_at(start.into(), Duration::from_secs(args.sample_every));
+    // A slow sample should not cause a burst of samples to catch up.
+    stats_timer.set_missed_tick_behavior(MissedTickBehavior::Skip);
+    sample_timer.set_missed_tick_behavior(MissedTickBehavior::Skip);
+
+    let mut failures = Vec::new();
+    let mut samples: Vec<Sample> = Vec::new();
+    let mut captures: JoinSet<(usize, anyhow::Result<()>)> = JoinSet::new();
+    let mut outputs = Vec::new();
+    loop {
+        select! {
+            joined = running.join_next() => {
+                let Some(joined) = joined else {
+                    break;
+                };
+                let (host, output) = joined.context("iperf client task failed")?;
+                match output {
+                    Ok(output) => {
+                        if !output.status.success() {
+                            error!(host = host.id, "Iperf exited with {}", output.status);
+                            failures.push(format!("iperf client on `{}` exited with {}", host.id, output.status));
+                        } else if start.elapsed() < Duration::from_secs(args.total_duration) {
+                            warn!(host = host.id, "Iperf stopped early");
+                        }
+                        outputs.push((host, output));
+                    }
+                    Err(err) => {
+                        error!(host = host.id, "Running iperf failed: {err:?}");
+                        failures.push(format!("failed to run iperf on `{}`: {err}", host.id));
+                    }
+                }
+            }
+            _ = stats_timer.tick() => {
+                if let Err(err) = record_stats(&clients, &bitrates, &stats_path).await {
+                    warn!("Could not record statistics: {err:?}");
+                }
+            }
+            _ = sample_timer.tick(), if !args.network.no_monitor => {
+                // Do not start a sample that would not finish before the traffic stops.
+                let remaining = Duration::from_secs(args.total_duration).saturating_sub(start.elapsed());
+                if remaining < Duration::from_secs(args.sample_length) {
+                    continue;
+                }
+                let name = format!("sample-{:03}", samples.len() + 1);
+                info!("Capturing {name}");
+                samples.push(Sample {
+                    name: name.clone(),
+                    start: unix_time(SystemTime::now()),
+                    error: None,
+                });
+                let index = samples.len() - 1;
+                match start_sample(&args, hosts, bssid, &out_path.join(&name)).await {
+                    Ok(monitor) => {
+                        let cancel = cancel.clone();
+                        captures.spawn(async move {
+                            (index, monitor.wait_or_stop(&cancel).await.map(|_| ()))
+                        });
+                    }
+                    Err(err) => {
+                        warn!("Could not start {name}: {err:?}");
+                        samples[index].error = Some(format!("{err:#}"));
+                    }
+                }
+            }
+            Some(joined) = captures.join_next() => {
+                let (index, result) = joined.context("capture task failed")?;
+                if let Err(err) = result {
+                    warn!("Sample {} failed: {err:?}", samples[index].name);
+                    samples[index].error = Some(format!("{err:#}"));
+                }
+            }
+            _ = &mut deadline => {
+                error!("The clients did not stop in time");
+                failures.push("the clients did not stop in time".to_string());
+                running.abort_all();
+                break;
+            }
+            () = cancel.cancelled() => {
+                // The samples stop their captures themselves, and are collected below.
+                running.abort_all();
+                break;
+            }
+        }
+    }
+    if let Err(err) = record_stats(&clients, &bitrates, &stats_path).await {
+        warn!("Could not record statistics: {err:?}");
+    }
+
+    // Clients that did not stop in time exit once their servers are gone.
+    stop_all(servers).await;
+    for (index, result) in captures.join_all().await {
+        if let Err(err) = result {
+            warn!("Sample {} failed: {err:?}", samples[index].name);
+            samples[index].error = Some(format!("{err:#}"));
+        }
+    }
+    cancel.check()?;
+
+    for (host, output) in outputs {
+        tokio::fs::write(host_file(out_path, &host.id, "txt"), &output.stdout)
+            .await
+            .context("failed to save iperf output")?;
+        if !output.stderr.is_empty() {
+            tokio::fs::write(host_file(out_path, &host.id, "stderr.txt"), &output.stderr)
+                .await
+                .context("failed to save iperf output")?;
+        }
+    }
+    let failed_samples = samples.iter().filter(|s| s.error.is_some()).count();
+    info!(
+        "Captured {} of {} samples",
+        samples.len() - failed_samples,
+        samples.len()
+    );
+    let dump =
+        to_string_pretty(&samples, PrettyConfig::new()).context("failed to serialize samples")?;
+    tokio::fs::write(out_path.join("samples.ron"), dump)
+        .await
+        .context("failed to save samples")?;
+
+    // Failed samples are only reported, as the traffic itself is what is being tested.
+    if !failures.is_empty() {
+        let reason = failures.join("; ");
+        mark_failed(out_path, &reason).await?;
+        anyhow::bail!("{reason}");
+    }
+    Ok(())
+}
+
+/// Start a capture of a single sample into `out_path`.
+///
+/// The association IDs of the clients are not discovered, as that would require associating them
+/// again during the test.
+async fn start_sample(
+    args: &SoakArgs,
+    hosts: &Hosts,
+    bssid: Option<MacAddr>,
+    out_path: &Path,
+) -> anyhow::Result<Monitor> {
+    MonitorConfig {
+        ssid: args.network.ssid.clone(),
+        bssid,
+        frequency: args.network.frequency,
+        bandwidth: args.network.bandwidth,
+        monitors: args.network.monitors.clone(),
+        targets: Vec::new(),
+        duration: Duration::from_secs(args.sample_length),
+        // The traffic runs throughout the test, the whole sample is measured.
+        lead_in: Duration::ZERO,
+        lead_out: Duration::ZERO,
+        output_path: Some(out_path.to_owned()),
+        set_aids: false,
+        known_aids: None,
+        filter: None,
+        merge_captures: false,
+        dedup_window: None,
+    }
+    .start(hosts)
+    .await
+}
+
+/// Append the mean throughput since the last call and the current link of every client to the
+/// statistics file.
+async fn record_stats(
+    clients: &[Arc<Host>],
+    bitrates: &Bitrates,
+    stats_path: &Path,
+) -> anyhow::Result<()> {
+    let mut links = JoinSet::new();
+    for client in clients {
+        let client = client.clone();
+        links.spawn(async move {
+            let link = match client.extra_data.interface_name() {
+                Some(ifname) => wifi::link_info(&client, ifname).await,
+                None => Err(anyhow!("no interface name configured")),
+            };
+            (client.id.clone(), link)
+        });
+    }
+    let links: BTreeMap<HostId, anyhow::Result<LinkInfo>> =
+        links.join_all().await.into_iter().collect();
+    let bitrates = std::mem::take(&mut *bitrates.lock().expect("lock poisoned"));
+
+    let time = unix_time(SystemTime::now());
+    let mut rows = String::new();
+    for (client, link) in &links {
+        let throughput = bitrates
+            .get(client)
+            .filter(|b| !b.is_empty())
+            .map(|b| b.iter().sum::<f64>() / b.len() as f64);
+        let link = match link {
+            Ok(link) => Some(link),
+            Err(err) => {
+                warn!(host = client, "Could not get the link: {err:?}");
+                None
+            }
+        };
+        let field = |v: Option<String>| v.unwrap_or_default();
+        _ = writeln!(
+            rows,
+            "{time:.3},{client},{},{},{},{}",
+            field(throughput.map(|t| format!("{t:.0}"))),
+            field(link.and_then(|l| l.signal).map(|s| s.to_string())),
+            field(link.and_then(|l| l.tx_bitrate.clone())).replace(',', " "),
+            field(link.and_then(|l| l.bssid.clone())),
+        );
+        info!(
+            host = client,
+            "{:.1} Mbit/s, {} dBm",
+            throughput.unwrap_or_default() / 1e6,
+            field(link.and_then(|l| l.signal).map(|s| s.to_string()))
+        );
+    }
+
+    let mut file = OpenOptions::new()
+        .append(true)
+        .open(stats_path)
+        .await
+        .context("failed to open statistics file")?;
+    file.write_all(rows.as_bytes())
+        .await
+        .context("failed to write statistics")
+}
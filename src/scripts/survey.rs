@@ -0,0 +1,185 @@
+//! Survey how busy channels are, to pick a channel for an experiment.
+
+use std::{fmt::Write, path::Path, time::Duration};
+
+use anyhow::{anyhow, Context};
+use clap::Parser;
+use ron::ser::{to_string_pretty, PrettyConfig};
+use serde::{Deserialize, Serialize};
+use tokio::task::JoinSet;
+use tracing::{error, info};
+
+use crate::{
+    cancel::CancellationToken,
+    capture::{analysis, CaptureConfig, StopCondition},
+    driver::wifi,
+    hosts::{HostId, Hosts},
+    utils::{parse_bandwidth, parse_frequency, sanitize_file_stem},
+};
+
+#[derive(Parser, Debug, Clone, Serialize, Deserialize)]
+pub struct SurveyArgs {
+    /// The host id(s) of the hosts that will capture the wireless traffic.
+    #[clap(long, required = true, value_delimiter = ',', num_args = 1..)]
+    pub monitors: Vec<String>,
+    /// The frequencies of the channels to survey in MHz, for example `5180,5200,5220`.
+    #[clap(long, required = true, value_delimiter = ',', num_args = 1.., value_parser = parse_frequency)]
+    pub frequencies: Vec<u32>,
+    /// The bandwidth to capture with in MHz.
+    #[clap(short = 'B', long, default_value = "20", value_parser = parse_bandwidth)]
+    #[serde(default = "default_bandwidth")]
+    pub bandwidth: u32,
+    /// How long to capture on every channel in seconds.
+    #[clap(long, default_value = "5")]
+    #[serde(default = "default_dwell")]
+    pub dwell: u64,
+}
+
+// The defaults of the arguments when they are read from a config file. They match the
+// defaults of the command line.
+fn default_bandwidth() -> u32 {
+    20
+}
+
+fn default_dwell() -> u64 {
+    5
+}
+
+/// The survey of a single channel by a single monitor.
+#[derive(Debug, Clone, Serialize)]
+pub struct ChannelSurvey {
+    pub frequency: u32,
+    pub host: HostId,
+    pub frames: u64,
+    pub bss_count: usize,
+    /// The estimated time the medium was busy in seconds.
+    pub airtime: f64,
+    /// The fraction of the dwell time the medium was busy.
+    pub utilization: f64,
+}
+
+pub async fn run(
+    args: SurveyArgs,
+    hosts: &Hosts,
+    out_path: &Path,
+    cancel: &CancellationToken,
+) -> anyhow::Result<()> {
+    tokio::fs::create_dir_all(out_path)
+        .await
+        .context("could not create output folder")?;
+    let args_dump =
+        to_string_pretty(&args, PrettyConfig::new()).context("failed to serialize args info")?;
+    tokio::fs::write(out_path.join("arguments.ron"), args_dump)
+        .await
+        .context("failed to save arguments")?;
+
+    let monitors: Vec<_> = hosts
+        .get_many(&args.monitors)
+        .map_err(|missing| anyhow!("no host with id {missing}"))?
+        .cloned()
+        .collect();
+
+    let mut surveys = Vec::new();
+    for (i, &frequency) in args.frequencies.iter().enumerate() {
+        // A channel that is being surveyed is finished, its captures stop by themselves.
+        cancel.check()?;
+        info!(
+            "Surveying {frequency} MHz ({}/{})",
+            i + 1,
+            args.frequencies.len()
+        );
+
+        let mut captures = JoinSet::new();
+        for monitor in monitors.iter().cloned() {
+            let path = out_path.join(format!(
+                "channel-{frequency}-{}.pcapng",
+                sanitize_file_stem(&monitor.id)
+            ));
+            let (bandwidth, dwell) = (args.bandwidth, args.dwell);
+            captures.spawn(async move {
+                let result = async {
+                    wifi::set_monitor_channel(&monitor, "mon0", frequency, bandwidth).await?;
+                    monitor
+                        .capture(&CaptureConfig {
+                            interface: "mon0".to_string(),
+                            stop_condition: StopCondition::Duration(Duration::from_secs(dwell)),
+                            output_path: Some(path.clone()),
+                            filter: None,
+                        })
+                        .await?;
+                    analysis::airtime(&path, None).await
+                }
+                .await;
+                (monitor, result)
+            });
+        }
+
+        for (monitor, result) in captures.join_all().await {
+            let stats = match result {
+                Ok(stats) => stats,
+                Err(err) => {
+                    error!(
+                        host = monitor.id,
+                        "Could not survey {frequency} MHz: {err:?}"
+                    );
+                    continue;
+                }
+            };
+            surveys.push(ChannelSurvey {
+                frequency,
+                host: monitor.id.clone(),
+                frames: stats.frames,
+                bss_count: stats.bss_count,
+                airtime: stats.airtime,
+                utilization: stats.utilization(args.dwell as f64),
+            });
+        }
+    }
+
+    tokio::fs::write(out_path.join("survey.csv"), survey_csv(&surveys))
+        .await
+        .context("failed to save survey")?;
+
+    let ranking = rank_channels(&surveys);
+    if ranking.is_empty() {
+        anyhow::bail!("no channel could be surveyed");
+    }
+    let mut table = String::new();
+    for (rank, (frequency, utilization)) in ranking.iter().enumerate() {
+        _ = writeln!(
+            table,
+            "{:>2}. {frequency} MHz: {:.1}% utilization",
+            rank + 1,
+            utilization * 100.0
+        );
+    }
+    info!("Channels from least to most busy:\n{table}");
+    Ok(())
+}
+
+/// A CSV table with a row for every channel and monitor.
+pub fn survey_csv(surveys: &[ChannelSurvey]) -> String {
+    let mut csv = "frequency,host,frames,bss_count,airtime,utilization\n".to_string();
+    for s in surveys {
+        _ = writeln!(
+            csv,
+            "{},{},{},{},{:.6},{:.6}",
+            s.frequency, s.host, s.frames, s.bss_count, s.airtime, s.utilization
+        );
+    }
+    csv
+}
+
+/// Order the channels from the least to the most utilized. If multiple monitors surveyed a
+/// channel, the highest utilization is used.
+pub fn rank_channels(surveys: &[ChannelSurvey]) -> Vec<(u32, f64)> {
+    let mut channels: Vec<(u32, f64)> = Vec::new();
+    for s in surveys {
+        match channels.iter_mut().find(|(f, _)| *f == s.frequency) {
+            Some((_, utilization)) => *utilization = utilization.max(s.utilization),
+            None => channels.push((s.frequency, s.utilization)),
+        }
+    }
+    channels.sort_by(|a, b| a.1.total_cmp(&b.1));
+    channels
+}
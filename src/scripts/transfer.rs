@@ -0,0 +1,157 @@
+//! Copy files between the controller and multiple hosts.
+
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use anyhow::{anyhow, Context};
+use clap::Parser;
+use serde::{Deserialize, Serialize};
+use tokio::{sync::Semaphore, task::JoinSet};
+use tracing::{error, info};
+
+use crate::{
+    hosts::{Host, HostId, Hosts},
+    utils::sanitize_file_stem,
+};
+
+/// The default number of transfers running at the same time. Relays limit the number of sessions
+/// that can be open at once.
+const DEFAULT_CONCURRENCY: usize = 8;
+
+#[derive(Parser, Debug, Clone, Serialize, Deserialize)]
+pub struct PushArgs {
+    /// The host ids of the hosts to copy the file to.
+    #[clap(long, required = true, value_delimiter = ',', num_args = 1..)]
+    pub hosts: Vec<String>,
+    /// The file on the controller to copy.
+    #[clap(long)]
+    pub local: PathBuf,
+    /// Where to write the file on the hosts. Relative paths are relative to the home directory.
+    #[clap(long)]
+    pub remote: String,
+    /// How many hosts to copy to at the same time.
+    #[clap(long, default_value_t = DEFAULT_CONCURRENCY)]
+    #[serde(default = "default_max_concurrency")]
+    pub max_concurrency: usize,
+}
+
+#[derive(Parser, Debug, Clone, Serialize, Deserialize)]
+pub struct FetchArgs {
+    /// The host ids of the hosts to copy the file from.
+    #[clap(long, required = true, value_delimiter = ',', num_args = 1..)]
+    pub hosts: Vec<String>,
+    /// The file on the hosts to copy. Relative paths are relative to the home directory.
+    #[clap(long)]
+    pub remote: String,
+    /// The directory to copy the files into, in a subdirectory per host. Defaults to the output
+    /// directory.
+    #[clap(long)]
+    #[serde(default)]
+    pub local_dir: Option<PathBuf>,
+    /// How many hosts to copy from at the same time.
+    #[clap(long, default_value_t = DEFAULT_CONCURRENCY)]
+    #[serde(default = "default_max_concurrency")]
+    pub max_concurrency: usize,
+}
+
+// The defaults of the arguments when they are read from a config file. They match the
+// defaults of the command line.
+fn default_max_concurrency() -> usize {
+    DEFAULT_CONCURRENCY
+}
+
+pub async fn push(args: PushArgs, hosts: &Hosts, _out_path: &Path) -> anyhow::Result<()> {
+    if !args.local.is_file() {
+        anyhow::bail!("{} is not a file", args.local.display());
+    }
+    let targets = resolve(hosts, &args.hosts)?;
+    info!(
+        "Copying {} to {} on {} hosts",
+        args.local.display(),
+        args.remote,
+        targets.len()
+    );
+    let permits = Arc::new(Semaphore::new(args.max_concurrency.max(1)));
+    let mut tasks = JoinSet::new();
+    for host in targets {
+        let args = args.clone();
+        let permits = permits.clone();
+        tasks.spawn(async move {
+            // SAFETY: The semaphore is never closed.
+            let _permit = permits.acquire().await.expect("semaphore is closed");
+            let result = host.upload(&args.local, &args.remote).await;
+            (host.id.clone(), result)
+        });
+    }
+    summarize(tasks.join_all().await.into_iter().collect())
+}
+
+pub async fn fetch(args: FetchArgs, hosts: &Hosts, out_path: &Path) -> anyhow::Result<()> {
+    let targets = resolve(hosts, &args.hosts)?;
+    let local_dir = args
+        .local_dir
+        .clone()
+        .unwrap_or_else(|| out_path.to_owned());
+    let file_name = Path::new(&args.remote)
+        .file_name()
+        .with_context(|| format!("{} does not name a file", args.remote))?
+        .to_owned();
+    info!(
+        "Copying {} from {} hosts into {}",
+        args.remote,
+        targets.len(),
+        local_dir.display()
+    );
+
+    let permits = Arc::new(Semaphore::new(args.max_concurrency.max(1)));
+    let mut tasks = JoinSet::new();
+    for host in targets {
+        // Every host gets its own directory, as the files have the same name.
+        let dir = local_dir.join(sanitize_file_stem(&host.id));
+        let local = dir.join(&file_name);
+        let remote = args.remote.clone();
+        let permits = permits.clone();
+        tasks.spawn(async move {
+            // SAFETY: The semaphore is never closed.
+            let _permit = permits.acquire().await.expect("semaphore is closed");
+            let result = async {
+                tokio::fs::create_dir_all(&dir)
+                    .await
+                    .with_context(|| format!("could not create {}", dir.display()))?;
+                host.download(&remote, &local).await
+            }
+            .await;
+            (host.id.clone(), result)
+        });
+    }
+    summarize(tasks.join_all().await.into_iter().collect())
+}
+
+fn resolve(hosts: &Hosts, ids: &[String]) -> anyhow::Result<Vec<Arc<Host>>> {
+    Ok(hosts
+        .get_many(ids)
+        .map_err(|missing| anyhow!("no host with id {missing}"))?
+        .cloned()
+        .collect())
+}
+
+/// Log the outcome of the transfer of every host, failing if any of them failed.
+fn summarize(results: BTreeMap<HostId, anyhow::Result<u64>>) -> anyhow::Result<()> {
+    let mut failed = 0;
+    for (host, result) in &results {
+        match result {
+            Ok(bytes) => info!(host, "Copied {:.1} MB", *bytes as f64 / 1e6),
+            Err(err) => {
+                error!(host, "Transfer failed: {err:?}");
+                failed += 1;
+            }
+        }
+    }
+    if failed > 0 {
+        anyhow::bail!("{failed} of {} transfers failed", results.len());
+    }
+    Ok(())
+}
@@ -0,0 +1,368 @@
+//! Run every preparatory step of an iperf experiment without generating any traffic, to find
+//! problems with the testbed before leaving a campaign running unattended.
+
+use std::{fmt::Write as _, path::Path, sync::Arc, time::Duration};
+
+use anyhow::{anyhow, Context};
+use ron::ser::{to_string_pretty, PrettyConfig};
+use serde::Serialize;
+use tokio::task::JoinSet;
+use tracing::{error, info};
+
+use crate::{
+    capture::{CaptureConfig, StopCondition},
+    daemon::stop_all,
+    hosts::{Host, HostId, Hosts},
+    package::Package,
+    scripts::{
+        iperf::{kill_stale_iperfs, server_listening_ports, set_mcs, Endpoints, IperfArgs},
+        mark_failed,
+    },
+    utils::host_file,
+};
+
+/// How long the monitors capture to check they receive frames.
+const CAPTURE_DURATION: Duration = Duration::from_secs(2);
+
+/// A preparatory step of an experiment.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Step {
+    /// The SSH connection is still alive.
+    Connect,
+    /// The tools the host needs are installed.
+    Tools,
+    /// The access point is on the configured channel.
+    AccessPoint,
+    /// The MCS can be set on the access point.
+    Mcs,
+    /// The iperf servers can listen on their ports.
+    Ports,
+    /// The client is associated to the network.
+    Associate,
+    /// The association IDs of the clients were found.
+    AidDiscovery,
+    /// The monitor interface was set to the association ID and channel.
+    MonitorTuning,
+    /// The monitor captured frames.
+    Capture,
+}
+
+/// The outcome of a step on a host, written to `checklist.ron`.
+#[derive(Debug, Clone, Serialize)]
+pub struct Check {
+    pub step: Step,
+    pub host: HostId,
+    /// Why the step failed, `None` if it passed.
+    pub error: Option<String>,
+}
+
+impl Check {
+    pub fn passed(&self) -> bool {
+        self.error.is_none()
+    }
+}
+
+#[derive(Default)]
+struct Checklist(Vec<Check>);
+
+impl Checklist {
+    /// Record the outcome of a step.
+    fn record(&mut self, step: Step, host: &Host, result: anyhow::Result<()>) {
+        let error = match result {
+            Ok(()) => {
+                info!(host = host.id, "{step:?} passed");
+                None
+            }
+            Err(err) => {
+                error!(host = host.id, "{step:?} failed: {err:?}");
+                Some(format!("{err:#}"))
+            }
+        };
+        self.0.push(Check {
+            step,
+            host: host.id.clone(),
+            error,
+        });
+    }
+
+    /// Run a step on every host at the same time.
+    async fn record_all<F, Fut>(&mut self, step: Step, hosts: &[Arc<Host>], check: F)
+    where
+        F: Fn(Arc<Host>) -> Fut,
+        Fut: std::future::Future<Output = anyhow::Result<()>> + Send + 'static,
+    {
+        let mut tasks = JoinSet::new();
+        for host in hosts {
+            let check = check(host.clone());
+            let host = host.clone();
+            tasks.spawn(async move { (host, check.await) });
+        }
+        for (host, result) in tasks.join_all().await {
+            self.record(step, &host, result);
+        }
+    }
+}
+
+pub async fn run(args: IperfArgs, hosts: &Hosts, out_path: &Path) -> anyhow::Result<()> {
+    args.validate().context("invalid arguments")?;
+    tokio::fs::create_dir_all(out_path)
+        .await
+        .context("could not create output folder")?;
+    let args_dump =
+        to_string_pretty(&args, PrettyConfig::new()).context("failed to serialize args info")?;
+    tokio::fs::write(out_path.join("arguments.ron"), args_dump)
+        .await
+        .context("failed to save arguments")?;
+
+    let mut checklist = Checklist::default();
+    verify(&args, hosts, out_path, &mut checklist).await?;
+
+    info!("Checklist:\n{}", checklist_table(&checklist.0));
+    let dump = to_string_pretty(&checklist.0, PrettyConfig::new())
+        .context("failed to serialize checklist")?;
+    tokio::fs::write(out_path.join("checklist.ron"), dump)
+        .await
+        .context("failed to save checklist")?;
+
+    let failed = checklist.0.iter().filter(|c| !c.passed()).count();
+    if failed > 0 {
+        let reason = format!("{failed} of {} checks failed", checklist.0.len());
+        mark_failed(out_path, &reason).await?;
+        anyhow::bail!("{reason}");
+    }
+    info!("All {} checks passed", checklist.0.len());
+    Ok(())
+}
+
+/// Run the steps in the order of an experiment. Steps that depend on an earlier step that failed
+/// are still attempted, so every problem shows up in a single run.
+async fn verify(
+    args: &IperfArgs,
+    hosts: &Hosts,
+    out_path: &Path,
+    checklist: &mut Checklist,
+) -> anyhow::Result<()> {
+    let clients: Vec<Arc<Host>> = hosts
+        .get_many(&args.clients)
+        .map_err(|missing| anyhow!("no host with id {missing}"))?
+        .cloned()
+        .collect();
+    let server = hosts
+        .get(args.server_id())
+        .context("server id not found")?
+        .clone();
+    let access_point = hosts
+        .get(args.ap_id())
+        .context("access point id not found")?
+        .clone();
+    let monitors: Vec<Arc<Host>> = if args.network.no_monitor {
+        Vec::new()
+    } else {
+        hosts
+            .get_many(&args.network.monitors)
+            .map_err(|missing| anyhow!("no host with id {missing}"))?
+            .cloned()
+            .collect()
+    };
+
+    let mut involved: Vec<Arc<Host>> = Vec::new();
+    for host in clients
+        .iter()
+        .chain([&access_point, &server])
+        .chain(&monitors)
+    {
+        if !involved.iter().any(|h| h.id == host.id) {
+            involved.push(host.clone());
+        }
+    }
+    checklist
+        .record_all(Step::Connect, &involved, |host| async move {
+            host.session.check().await.context("connection is broken")
+        })
+        .await;
+
+    let mut iperf_hosts = clients.clone();
+    if !clients.iter().any(|h| h.id == server.id) {
+        iperf_hosts.push(server.clone());
+    }
+    checklist
+        .record_all(Step::Tools, &iperf_hosts, |host| async move {
+            require(&host, Package::Iperf3).await
+        })
+        .await;
+    checklist
+        .record_all(Step::Tools, &monitors, |host| async move {
+            require(&host, Package::Wireshark).await
+        })
+        .await;
+
+    let bssid = args.network.check_access_point(&access_point).await;
+    let bssid = match bssid {
+        Ok(bssid) => {
+            checklist.record(Step::AccessPoint, &access_point, Ok(()));
+            bssid
+        }
+        Err(err) => {
+            checklist.record(Step::AccessPoint, &access_point, Err(err));
+            args.network.bssid
+        }
+    };
+
+    let mcs = match set_mcs(args, &access_point).await {
+        // The MCS is restored again right away, as no traffic follows.
+        Ok(Some(pinned)) => pinned.restore().await,
+        Ok(None) => Ok(()),
+        Err(err) => Err(err),
+    };
+    if args.mcs_mask().is_some() {
+        checklist.record(Step::Mcs, &access_point, mcs);
+    }
+
+    let ports = check_ports(args, hosts).await;
+    checklist.record(Step::Ports, &server, ports);
+
+    if args.network.no_monitor {
+        checklist
+            .record_all(Step::Associate, &clients, |host| {
+                let ssid = args.network.ssid.clone();
+                async move { host.ensure_associated(&ssid).await }
+            })
+            .await;
+        return Ok(());
+    }
+    let Some(first_monitor) = monitors.first() else {
+        return Ok(());
+    };
+    let Some(bssid) = bssid else {
+        checklist.record(
+            Step::AidDiscovery,
+            first_monitor,
+            Err(anyhow!("--bssid is required to monitor with --trust-args")),
+        );
+        return Ok(());
+    };
+
+    // The monitor associates the clients while listening for their association IDs, just like
+    // at the start of an experiment.
+    let targets: Vec<_> = clients.iter().collect();
+    let config = args
+        .network
+        .monitor_config(&targets, bssid, CAPTURE_DURATION, out_path, None);
+    let aids = match config.discover_aids(&monitors, clients.clone()).await {
+        Ok(aids) if aids.len() < monitors.len() => {
+            let error = anyhow!(
+                "expected at least {} aids, got {}",
+                monitors.len(),
+                aids.len()
+            );
+            checklist.record(Step::AidDiscovery, first_monitor, Err(error));
+            aids
+        }
+        Ok(aids) => {
+            info!("Found aids {aids:?}");
+            checklist.record(Step::AidDiscovery, first_monitor, Ok(()));
+            aids
+        }
+        Err(err) => {
+            checklist.record(Step::AidDiscovery, first_monitor, Err(err));
+            Vec::new()
+        }
+    };
+    checklist
+        .record_all(Step::Associate, &clients, |host| {
+            let ssid = args.network.ssid.clone();
+            async move {
+                match host.connected_ssid().await? {
+                    Some(connected) if connected == ssid => Ok(()),
+                    Some(connected) => anyhow::bail!("connected to `{connected}` instead"),
+                    None => anyhow::bail!("not connected"),
+                }
+            }
+        })
+        .await;
+
+    let tuning = Arc::new(config.tuning());
+    let mut tasks = JoinSet::new();
+    for (i, host) in monitors.iter().cloned().enumerate() {
+        let aid = aids.get(i).copied();
+        let tuning = tuning.clone();
+        tasks.spawn(async move {
+            let result = tuning.tune(&host, aid).await;
+            (host, result)
+        });
+    }
+    for (host, result) in tasks.join_all().await {
+        checklist.record(Step::MonitorTuning, &host, result);
+    }
+
+    checklist
+        .record_all(Step::Capture, &monitors, |host| {
+            let output_path = host_file(out_path, &host.id, "pcapng");
+            async move {
+                let (_, stats) = host
+                    .capture(&CaptureConfig {
+                        interface: "mon0".to_string(),
+                        stop_condition: StopCondition::Duration(CAPTURE_DURATION),
+                        output_path: Some(output_path),
+                        filter: None,
+                    })
+                    .await?;
+                if stats.bytes == 0 || stats.packets == Some(0) {
+                    anyhow::bail!("capture is empty");
+                }
+                Ok(())
+            }
+        })
+        .await;
+    Ok(())
+}
+
+async fn require(host: &Host, pkg: Package) -> anyhow::Result<()> {
+    if !host.has_package(pkg).await? {
+        anyhow::bail!("{} is not installed", pkg.binary());
+    }
+    Ok(())
+}
+
+/// Start a server on every port the experiment uses, and stop them again once they listen.
+async fn check_ports(args: &IperfArgs, hosts: &Hosts) -> anyhow::Result<()> {
+    let endpoints = Endpoints::resolve(args, hosts).await?;
+    let participants = endpoints.participants();
+    if !args.no_precleanup {
+        kill_stale_iperfs(&participants, endpoints.ports.clone())
+            .await
+            .context("failed to clean up stale iperf processes")?;
+    }
+    let listening = server_listening_ports(&endpoints.server).await?;
+    let taken: Vec<_> = endpoints
+        .ports
+        .clone()
+        .filter(|p| listening.contains(p))
+        .collect();
+    if !taken.is_empty() {
+        anyhow::bail!("ports {taken:?} are already in use");
+    }
+
+    let servers = endpoints.spawn_servers().await?;
+    // The servers wait for a client forever, so they are stopped explicitly.
+    stop_all(servers).await;
+    Ok(())
+}
+
+fn checklist_table(checks: &[Check]) -> String {
+    let mut out = format!("{:<16} {:<12} {}\n", "step", "host", "result");
+    for check in checks {
+        _ = writeln!(
+            out,
+            "{:<16} {:<12} {}",
+            format!("{:?}", check.step),
+            check.host,
+            check
+                .error
+                .as_deref()
+                .map_or("ok".to_string(), |e| format!("FAILED: {e}"))
+        );
+    }
+    out
+}
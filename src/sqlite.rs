@@ -0,0 +1,291 @@
+//! Export of the results of runs to an SQLite database with `--export-sqlite`, to query long
+//! measurement campaigns. Requires the `sqlite` feature.
+//!
+//! The schema is created if the database does not have it yet:
+//!
+//! - `runs`: a row per exported run, by its results directory `dir`. The script and label are
+//!   only known for runs exported when they finish. The controller, version, git hash, start and
+//!   finish times come from `meta.ron`, the status from the `done.ron` marker of an iteration or
+//!   else `meta.ron`, the offered load from `summary.ron`.
+//! - `clients`: the totals of every client per direction from `summary.ron`.
+//! - `monitors`: the capture statistics of every monitor.
+//! - `intervals`: the iperf intervals of every client per direction from `results.ron`, in seconds
+//!   since the client started.
+//!
+//! Every run is inserted in its own transaction. Exporting a run again replaces its earlier rows.
+
+use std::path::Path;
+
+/// The tables of the database, created if absent.
+pub const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS runs (
+    id INTEGER PRIMARY KEY,
+    dir TEXT NOT NULL UNIQUE,
+    script TEXT,
+    label TEXT,
+    controller TEXT,
+    version TEXT,
+    git_hash TEXT,
+    started REAL,
+    finished REAL,
+    status TEXT,
+    offered_load INTEGER,
+    exported REAL NOT NULL
+);
+CREATE TABLE IF NOT EXISTS clients (
+    run_id INTEGER NOT NULL REFERENCES runs (id),
+    host TEXT NOT NULL,
+    direction TEXT NOT NULL,
+    goodput REAL NOT NULL,
+    retransmits INTEGER,
+    lost_packets INTEGER,
+    packets INTEGER,
+    lost_percent REAL,
+    jitter_ms REAL
+);
+CREATE TABLE IF NOT EXISTS monitors (
+    run_id INTEGER NOT NULL REFERENCES runs (id),
+    host TEXT NOT NULL,
+    bytes INTEGER,
+    packets INTEGER,
+    dropped INTEGER
+);
+CREATE TABLE IF NOT EXISTS intervals (
+    run_id INTEGER NOT NULL REFERENCES runs (id),
+    host TEXT NOT NULL,
+    direction TEXT NOT NULL,
+    interval_start REAL NOT NULL,
+    interval_end REAL NOT NULL,
+    bits_per_second REAL NOT NULL,
+    omitted INTEGER NOT NULL,
+    retransmits INTEGER,
+    lost_packets INTEGER,
+    packets INTEGER,
+    jitter_ms REAL
+);
+";
+
+/// The script and label of a run that is exported when it finishes.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RunLabels<'a> {
+    pub script: Option<&'a str>,
+    pub label: Option<&'a str>,
+}
+
+/// Export every run in `dir` to the database at `db`, also the iterations of a sweep. A run is a
+/// directory with a `summary.ron` or `results.ron`. Returns how many runs were exported.
+#[cfg(feature = "sqlite")]
+pub async fn export(db: &Path, dir: &Path, labels: RunLabels<'_>) -> anyhow::Result<usize> {
+    database::export(db, dir, labels).await
+}
+
+/// Without the `sqlite` feature there is no database to export to.
+#[cfg(not(feature = "sqlite"))]
+pub async fn export(_db: &Path, _dir: &Path, _labels: RunLabels<'_>) -> anyhow::Result<usize> {
+    anyhow::bail!("the controller was built without the `sqlite` feature")
+}
+
+#[cfg(feature = "sqlite")]
+mod database {
+    use std::{
+        path::{Path, PathBuf},
+        time::SystemTime,
+    };
+
+    use anyhow::Context;
+    use rusqlite::params;
+
+    use super::{RunLabels, SCHEMA};
+    use crate::{
+        results::{load_artifact, load_run, Artifact, RunArtifacts},
+        scripts::{iperf::RunSummary, meta::Meta, report::read_marker_status},
+        utils::unix_time,
+    };
+
+    /// A run found in the directory that is exported.
+    struct ExportedRun {
+        dir: PathBuf,
+        artifacts: RunArtifacts,
+        /// The metadata of the run, or of the sweep it is an iteration of.
+        meta: Option<Meta>,
+    }
+
+    pub async fn export(db: &Path, dir: &Path, labels: RunLabels<'_>) -> anyhow::Result<usize> {
+        let (db, dir) = (db.to_owned(), dir.to_owned());
+        let labels = (
+            labels.script.map(str::to_string),
+            labels.label.map(str::to_string),
+        );
+        tokio::task::spawn_blocking(move || {
+            let runs = find_runs(&dir)?;
+            let labels = RunLabels {
+                script: labels.0.as_deref(),
+                label: labels.1.as_deref(),
+            };
+            insert_runs(&db, &runs, labels)
+                .with_context(|| format!("could not export to {}", db.display()))?;
+            Ok(runs.len())
+        })
+        .await
+        .context("export task panicked")?
+    }
+
+    /// Load the runs in `dir` and below it.
+    fn find_runs(dir: &Path) -> anyhow::Result<Vec<ExportedRun>> {
+        let mut runs = Vec::new();
+        let mut pending = vec![(dir.to_path_buf(), None::<Meta>)];
+        while let Some((path, meta)) = pending.pop() {
+            let meta = load_artifact::<Meta>(&path)?.or(meta);
+            if path.join(RunSummary::FILE).exists() || path.join("results.ron").exists() {
+                runs.push(ExportedRun {
+                    dir: std::path::absolute(&path).unwrap_or_else(|_| path.clone()),
+                    artifacts: load_run(&path)?,
+                    meta: meta.clone(),
+                });
+            }
+            let entries = std::fs::read_dir(&path)
+                .with_context(|| format!("could not read {}", path.display()))?;
+            for entry in entries {
+                let entry = entry.with_context(|| format!("could not read {}", path.display()))?;
+                if entry.path().is_dir() {
+                    pending.push((entry.path(), meta.clone()));
+                }
+            }
+        }
+        runs.sort_by(|a, b| a.dir.cmp(&b.dir));
+        Ok(runs)
+    }
+
+    fn insert_runs(db: &Path, runs: &[ExportedRun], labels: RunLabels<'_>) -> anyhow::Result<()> {
+        let mut connection =
+            rusqlite::Connection::open(db).context("could not open the database")?;
+        connection
+            .execute_batch(SCHEMA)
+            .context("could not create the schema")?;
+        for run in runs {
+            let transaction = connection.transaction()?;
+            insert_run(&transaction, run, labels)
+                .with_context(|| format!("could not insert {}", run.dir.display()))?;
+            transaction.commit()?;
+        }
+        Ok(())
+    }
+
+    fn insert_run(
+        transaction: &rusqlite::Transaction<'_>,
+        run: &ExportedRun,
+        labels: RunLabels<'_>,
+    ) -> rusqlite::Result<()> {
+        let dir = run.dir.to_string_lossy();
+        for table in ["clients", "monitors", "intervals"] {
+            transaction.execute(
+                &format!(
+                    "DELETE FROM {table} WHERE run_id IN (SELECT id FROM runs WHERE dir = ?1)"
+                ),
+                [&dir],
+            )?;
+        }
+        transaction.execute("DELETE FROM runs WHERE dir = ?1", [&dir])?;
+
+        let meta = run.meta.as_ref();
+        // Only iterations have a marker, other runs have their status in their metadata.
+        let status = if run.dir.join("done.ron").exists() {
+            read_marker_status(&run.dir)
+        } else {
+            meta.and_then(|m| m.status.clone())
+        };
+        let summary = run.artifacts.summary.as_ref();
+        transaction.execute(
+            "INSERT INTO runs (dir, script, label, controller, version, git_hash, started, finished, \
+             status, offered_load, exported) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11)",
+            params![
+                dir,
+                labels.script,
+                labels.label,
+                meta.map(|m| &m.controller),
+                meta.map(|m| &m.version),
+                meta.map(|m| &m.git_hash),
+                meta.map(|m| m.start),
+                meta.and_then(|m| m.end),
+                status.as_ref().map(|s| s.name()),
+                summary.map(|s| s.offered_load),
+                unix_time(SystemTime::now()),
+            ],
+        )?;
+        let run_id = transaction.last_insert_rowid();
+
+        if let Some(summary) = summary {
+            let mut insert = transaction.prepare(
+                "INSERT INTO clients (run_id, host, direction, goodput, retransmits, lost_packets, \
+                 packets, lost_percent, jitter_ms) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+            )?;
+            for (host, client) in &summary.clients {
+                for d in &client.directions {
+                    insert.execute(params![
+                        run_id,
+                        host,
+                        format!("{:?}", d.direction),
+                        d.goodput,
+                        d.retransmits,
+                        d.lost_packets,
+                        d.packets,
+                        d.lost_percent,
+                        d.jitter_ms,
+                    ])?;
+                }
+            }
+        }
+
+        // The summary has the statistics of every monitor, also those whose capture failed.
+        let monitors: Vec<_> = match (summary, &run.artifacts.monitor) {
+            (Some(summary), _) => summary.monitors.iter().collect(),
+            (None, Some(monitor)) => monitor
+                .captures
+                .iter()
+                .map(|c| (&c.host, &c.stats))
+                .collect(),
+            (None, None) => Vec::new(),
+        };
+        let mut insert = transaction.prepare(
+            "INSERT INTO monitors (run_id, host, bytes, packets, dropped) VALUES (?1, ?2, ?3, ?4, ?5)",
+        )?;
+        for (host, stats) in monitors {
+            let stats = stats.as_ref();
+            insert.execute(params![
+                run_id,
+                host,
+                stats.map(|s| s.bytes),
+                stats.and_then(|s| s.packets),
+                stats.and_then(|s| s.dropped),
+            ])?;
+        }
+
+        if let Some(results) = &run.artifacts.results {
+            let mut insert = transaction.prepare(
+                "INSERT INTO intervals (run_id, host, direction, interval_start, interval_end, \
+                 bits_per_second, omitted, retransmits, lost_packets, packets, jitter_ms) \
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11)",
+            )?;
+            for (host, result) in &results.clients {
+                for d in &result.directions {
+                    for i in &d.intervals {
+                        insert.execute(params![
+                            run_id,
+                            host,
+                            format!("{:?}", d.direction),
+                            i.start,
+                            i.end,
+                            i.bits_per_second,
+                            i.omitted,
+                            i.retransmits,
+                            i.lost_packets,
+                            i.packets,
+                            i.jitter_ms,
+                        ])?;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
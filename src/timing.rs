@@ -0,0 +1,498 @@
+//! Timing the phases of a run, so it is clear where the time of an experiment goes.
+//!
+//! Within [scope], [phase] and [host_phase] time a phase with the recorder of the scope until the
+//! guard they return is dropped. Every run has its own recorder, so plan entries that run at the
+//! same time do not mix their phases, and tasks spawned by a script record with the recorder of
+//! the script if they are wrapped in [inherit]. A phase started while another phase of the run is
+//! open is nested in it, and the phases of a single host are nested in the innermost phase of the
+//! run that is open. Only [Instant]s are taken while the script runs, the tree is built once when
+//! the recorder finishes and written to `timings.ron`.
+
+use std::{
+    collections::BTreeMap,
+    fmt,
+    future::Future,
+    ops::Range,
+    str::FromStr,
+    sync::{Arc, Mutex},
+    time::Instant,
+};
+
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use tracing::info;
+
+use crate::{
+    hosts::HostId,
+    results::{Artifact, SchemaVersion},
+};
+
+tokio::task_local! {
+    /// The recorder of the run the current task belongs to.
+    static RECORDER: Arc<Timing>;
+}
+
+/// A timed phase of a run. It is written to `timings.ron` by its name, which is stable so the
+/// timings can be compared between runs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum TimedPhase {
+    /// Connecting to the hosts over SSH.
+    Connect,
+    /// Preparing the hosts, like setting the MCS or starting servers.
+    Provision,
+    /// Associating a client to the network.
+    Associate,
+    /// Capturing the association IDs of the clients.
+    AidDiscovery,
+    /// Configuring the monitors and starting the captures.
+    CaptureStart,
+    /// Generating the measured traffic.
+    Traffic,
+    /// Stopping the captures and gathering them.
+    CaptureCollect,
+    /// Parsing the output and writing the results.
+    Analysis,
+}
+
+impl TimedPhase {
+    pub const ALL: [TimedPhase; 8] = [
+        TimedPhase::Connect,
+        TimedPhase::Provision,
+        TimedPhase::Associate,
+        TimedPhase::AidDiscovery,
+        TimedPhase::CaptureStart,
+        TimedPhase::Traffic,
+        TimedPhase::CaptureCollect,
+        TimedPhase::Analysis,
+    ];
+
+    /// The name of the phase, as written to `timings.ron`.
+    pub fn name(self) -> &'static str {
+        match self {
+            TimedPhase::Connect => "connect",
+            TimedPhase::Provision => "provision",
+            TimedPhase::Associate => "associate",
+            TimedPhase::AidDiscovery => "aid-discovery",
+            TimedPhase::CaptureStart => "capture-start",
+            TimedPhase::Traffic => "traffic",
+            TimedPhase::CaptureCollect => "capture-collect",
+            TimedPhase::Analysis => "analysis",
+        }
+    }
+}
+
+impl fmt::Display for TimedPhase {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for TimedPhase {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::ALL
+            .into_iter()
+            .find(|phase| phase.name() == s)
+            .ok_or_else(|| format!("unknown phase `{s}`"))
+    }
+}
+
+impl Serialize for TimedPhase {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.name())
+    }
+}
+
+impl<'de> Deserialize<'de> for TimedPhase {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(de::Error::custom)
+    }
+}
+
+/// A phase as it is recorded while the script runs.
+struct Span {
+    phase: TimedPhase,
+    host: Option<HostId>,
+    parent: Option<usize>,
+    start: Instant,
+    end: Option<Instant>,
+}
+
+#[derive(Default)]
+struct Spans {
+    spans: Vec<Span>,
+    /// The phases of the run that are open, innermost last.
+    open: Vec<usize>,
+}
+
+/// Records the phases of a run, see the module documentation.
+pub struct Timing {
+    spans: Mutex<Spans>,
+}
+
+impl Timing {
+    /// A recorder without any phases, which records the phases of the futures run in [scope].
+    pub fn new() -> Arc<Self> {
+        Arc::new(Timing {
+            spans: Mutex::new(Spans::default()),
+        })
+    }
+
+    /// Record a phase of the run that was timed before the recorder started, like connecting to
+    /// the hosts.
+    pub fn record(&self, phase: TimedPhase, time: Range<Instant>) {
+        let mut spans = self.spans.lock().expect("timing lock is poisoned");
+        spans.spans.push(Span {
+            phase,
+            host: None,
+            parent: None,
+            start: time.start,
+            end: Some(time.end),
+        });
+    }
+
+    /// Stop timing phases, returning the tree of phases. Phases that are still open are ended
+    /// now and marked as unfinished.
+    pub fn finish(&self) -> Timings {
+        let spans = std::mem::take(&mut *self.spans.lock().expect("timing lock is poisoned"));
+        Timings::build(spans.spans, Instant::now())
+    }
+
+    fn open(&self, phase: TimedPhase, host: Option<&str>) -> usize {
+        let mut spans = self.spans.lock().expect("timing lock is poisoned");
+        let id = spans.spans.len();
+        let parent = spans.open.last().copied();
+        spans.spans.push(Span {
+            phase,
+            host: host.map(str::to_string),
+            parent,
+            start: Instant::now(),
+            end: None,
+        });
+        if host.is_none() {
+            spans.open.push(id);
+        }
+        id
+    }
+
+    fn close(&self, id: usize) {
+        let mut spans = self.spans.lock().expect("timing lock is poisoned");
+        // Phases are not always ended in the order they started when hosts run concurrently.
+        spans.open.retain(|&open| open != id);
+        if let Some(span) = spans.spans.get_mut(id) {
+            span.end = Some(Instant::now());
+        }
+    }
+}
+
+/// Times a phase until it is dropped.
+#[must_use = "the phase ends when the guard is dropped"]
+pub struct PhaseGuard {
+    span: Option<(Arc<Timing>, usize)>,
+}
+
+impl PhaseGuard {
+    /// End the phase, the same as dropping the guard.
+    pub fn end(self) {}
+}
+
+impl Drop for PhaseGuard {
+    fn drop(&mut self) {
+        if let Some((timing, id)) = self.span.take() {
+            timing.close(id);
+        }
+    }
+}
+
+/// Run `future`, recording the phases it times with `timing`.
+pub async fn scope<F: Future>(timing: &Arc<Timing>, future: F) -> F::Output {
+    RECORDER.scope(timing.clone(), future).await
+}
+
+/// Let `future` record its phases with the recorder of the current task, if any, for futures
+/// that are spawned on a task of their own.
+pub fn inherit<F: Future>(future: F) -> impl Future<Output = F::Output> {
+    let timing = RECORDER.try_with(Arc::clone).ok();
+    async move {
+        match timing {
+            Some(timing) => RECORDER.scope(timing, future).await,
+            None => future.await,
+        }
+    }
+}
+
+/// Time a phase of the run until the returned guard is dropped, if it runs in a [scope].
+pub fn phase(phase: TimedPhase) -> PhaseGuard {
+    open(phase, None)
+}
+
+/// Time a phase of `host` until the returned guard is dropped, if it runs in a [scope].
+pub fn host_phase(phase: TimedPhase, host: &str) -> PhaseGuard {
+    open(phase, Some(host))
+}
+
+fn open(phase: TimedPhase, host: Option<&str>) -> PhaseGuard {
+    let timing = RECORDER.try_with(Arc::clone).ok();
+    PhaseGuard {
+        span: timing.map(|timing| {
+            let id = timing.open(phase, host);
+            (timing, id)
+        }),
+    }
+}
+
+/// The timed phases of a run, written to `timings.ron`.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Timings {
+    #[serde(default)]
+    pub schema_version: SchemaVersion,
+    /// The phases that were not nested in another, in the order they started.
+    pub phases: Vec<PhaseTiming>,
+}
+
+/// A timed phase, with the phases nested in it.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PhaseTiming {
+    pub phase: TimedPhase,
+    /// The host the phase is of, `None` for a phase of the whole run.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub host: Option<HostId>,
+    /// When the phase started in seconds, since the first phase of the run started.
+    pub start: f64,
+    /// How long the phase took in seconds.
+    pub duration: f64,
+    /// Whether the phase was still open when the run ended, because it failed or was aborted.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub unfinished: bool,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub phases: Vec<PhaseTiming>,
+}
+
+impl Timings {
+    /// Build the tree of `spans`, ending the open ones at `now`.
+    fn build(spans: Vec<Span>, now: Instant) -> Self {
+        let origin = spans.iter().map(|span| span.start).min().unwrap_or(now);
+        let mut children: BTreeMap<Option<usize>, Vec<usize>> = BTreeMap::new();
+        for (id, span) in spans.iter().enumerate() {
+            children.entry(span.parent).or_default().push(id);
+        }
+        let mut timings = Timings {
+            schema_version: Self::SCHEMA_VERSION,
+            phases: Self::children(&spans, &children, None, origin, now),
+        };
+        timings.phases.sort_by(|a, b| a.start.total_cmp(&b.start));
+        timings
+    }
+
+    fn children(
+        spans: &[Span],
+        children: &BTreeMap<Option<usize>, Vec<usize>>,
+        parent: Option<usize>,
+        origin: Instant,
+        now: Instant,
+    ) -> Vec<PhaseTiming> {
+        let Some(ids) = children.get(&parent) else {
+            return Vec::new();
+        };
+        ids.iter()
+            .map(|&id| {
+                let span = &spans[id];
+                let end = span.end.unwrap_or(now);
+                PhaseTiming {
+                    phase: span.phase,
+                    host: span.host.clone(),
+                    start: span.start.duration_since(origin).as_secs_f64(),
+                    duration: end.duration_since(span.start).as_secs_f64(),
+                    unfinished: span.end.is_none(),
+                    phases: Self::children(spans, children, Some(id), origin, now),
+                }
+            })
+            .collect()
+    }
+
+    /// Every phase in the tree, depth first.
+    pub fn flatten(&self) -> Vec<&PhaseTiming> {
+        fn visit<'a>(phases: &'a [PhaseTiming], all: &mut Vec<&'a PhaseTiming>) {
+            for phase in phases {
+                all.push(phase);
+                visit(&phase.phases, all);
+            }
+        }
+        let mut all = Vec::new();
+        visit(&self.phases, &mut all);
+        all
+    }
+
+    /// Log how long each phase took in total, and which host was the slowest in the phases of
+    /// single hosts.
+    pub fn log_summary(&self) {
+        let mut run: BTreeMap<TimedPhase, (f64, usize)> = BTreeMap::new();
+        let mut slowest: BTreeMap<TimedPhase, (f64, &str)> = BTreeMap::new();
+        for phase in self.flatten() {
+            match &phase.host {
+                None => {
+                    let (total, count) = run.entry(phase.phase).or_default();
+                    *total += phase.duration;
+                    *count += 1;
+                }
+                Some(host) => {
+                    let entry = slowest.entry(phase.phase).or_insert((0.0, host));
+                    if phase.duration >= entry.0 {
+                        *entry = (phase.duration, host);
+                    }
+                }
+            }
+        }
+        if run.is_empty() && slowest.is_empty() {
+            return;
+        }
+        info!("Phase durations:");
+        for (phase, (total, count)) in run {
+            match count {
+                1 => info!("  {phase}: {total:.1}s"),
+                _ => info!("  {phase}: {total:.1}s ({count} times)"),
+            }
+        }
+        for (phase, (duration, host)) in slowest {
+            info!("  {phase}: slowest host `{host}` took {duration:.1}s");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    fn span(
+        origin: Instant,
+        phase: TimedPhase,
+        host: Option<&str>,
+        parent: Option<usize>,
+        start: u64,
+        end: Option<u64>,
+    ) -> Span {
+        Span {
+            phase,
+            host: host.map(str::to_string),
+            parent,
+            start: origin + Duration::from_secs(start),
+            end: end.map(|end| origin + Duration::from_secs(end)),
+        }
+    }
+
+    #[test]
+    fn build_nests_phases() {
+        let origin = Instant::now();
+        let spans = vec![
+            span(origin, TimedPhase::Connect, None, None, 0, Some(1)),
+            span(origin, TimedPhase::Traffic, None, None, 3, None),
+            span(origin, TimedPhase::Provision, None, None, 1, Some(3)),
+            span(
+                origin,
+                TimedPhase::Associate,
+                Some("a"),
+                Some(2),
+                1,
+                Some(2),
+            ),
+            span(origin, TimedPhase::Traffic, Some("a"), Some(1), 3, Some(8)),
+        ];
+        let timings = Timings::build(spans, origin + Duration::from_secs(10));
+
+        let top: Vec<_> = timings.phases.iter().map(|p| p.phase).collect();
+        assert_eq!(
+            top,
+            [
+                TimedPhase::Connect,
+                TimedPhase::Provision,
+                TimedPhase::Traffic
+            ]
+        );
+        let provision = &timings.phases[1];
+        assert_eq!(provision.start, 1.0);
+        assert_eq!(provision.duration, 2.0);
+        assert_eq!(provision.phases[0].host.as_deref(), Some("a"));
+        assert_eq!(provision.phases[0].phase, TimedPhase::Associate);
+
+        // The traffic was still running when the run ended.
+        let traffic = &timings.phases[2];
+        assert!(traffic.unfinished);
+        assert_eq!(traffic.duration, 7.0);
+        assert!(!traffic.phases[0].unfinished);
+        assert_eq!(timings.flatten().len(), 5);
+    }
+
+    #[test]
+    fn build_without_phases() {
+        assert!(Timings::build(Vec::new(), Instant::now()).phases.is_empty());
+    }
+
+    #[test]
+    fn phases_serialize_by_name() {
+        let timings = Timings {
+            schema_version: Timings::SCHEMA_VERSION,
+            phases: vec![PhaseTiming {
+                phase: TimedPhase::AidDiscovery,
+                host: None,
+                start: 0.5,
+                duration: 2.0,
+                unfinished: false,
+                phases: vec![PhaseTiming {
+                    phase: TimedPhase::Associate,
+                    host: Some("client-1".to_string()),
+                    start: 0.5,
+                    duration: 1.0,
+                    unfinished: true,
+                    phases: Vec::new(),
+                }],
+            }],
+        };
+        let raw = ron::to_string(&timings).unwrap();
+        assert!(raw.contains("phase:\"aid-discovery\""), "{raw}");
+        assert!(raw.contains("unfinished:true"), "{raw}");
+        // Defaults are left out.
+        assert_eq!(raw.matches("unfinished").count(), 1, "{raw}");
+
+        let parsed: Timings = ron::from_str(&raw).unwrap();
+        assert_eq!(parsed.phases, timings.phases);
+        assert!(ron::from_str::<TimedPhase>("\"warmup\"").is_err());
+    }
+
+    #[test]
+    fn phases_outside_a_scope_are_not_timed() {
+        let guard = phase(TimedPhase::Traffic);
+        assert!(guard.span.is_none());
+    }
+
+    #[tokio::test]
+    async fn scopes_record_their_own_phases() {
+        let (first, second) = (Timing::new(), Timing::new());
+        let run = |host: &'static str, phase_of_run| async move {
+            let _run = phase(phase_of_run);
+            tokio::spawn(inherit(async move {
+                let _host = host_phase(TimedPhase::Associate, host);
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }))
+            .await
+            .unwrap();
+        };
+        tokio::join!(
+            scope(&first, run("a", TimedPhase::Provision)),
+            scope(&second, run("b", TimedPhase::Traffic)),
+        );
+
+        for (timing, phase, host) in [
+            (first, TimedPhase::Provision, "a"),
+            (second, TimedPhase::Traffic, "b"),
+        ] {
+            let timings = timing.finish();
+            assert_eq!(timings.phases.len(), 1);
+            assert_eq!(timings.phases[0].phase, phase);
+            assert_eq!(timings.phases[0].phases.len(), 1);
+            assert_eq!(timings.phases[0].phases[0].host.as_deref(), Some(host));
+        }
+    }
+}
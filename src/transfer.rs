@@ -0,0 +1,316 @@
+//! Copying files between the controller and the hosts over the existing SSH sessions, so relays
+//! are used as configured.
+//!
+//! Files are streamed through `cat` on the host rather than SFTP, as some OpenWrt builds do not
+//! ship an SFTP server.
+
+use std::{
+    path::Path,
+    time::{Duration, Instant},
+};
+
+use anyhow::Context;
+use openssh::Stdio;
+use tokio::{
+    fs::File,
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+};
+use tracing::info;
+
+use crate::hosts::Host;
+
+/// How often to log the progress of a transfer.
+const PROGRESS_INTERVAL: Duration = Duration::from_secs(5);
+/// Files smaller than this are copied without logging the progress.
+const PROGRESS_THRESHOLD: u64 = 16 * 1024 * 1024;
+
+impl Host {
+    /// Copy the local file at `local` to `remote` on the host, replacing it if it exists.
+    /// Relative remote paths are relative to the home directory of the user. Returns the number
+    /// of bytes copied.
+    pub async fn upload(&self, local: impl AsRef<Path>, remote: &str) -> anyhow::Result<u64> {
+        let local = local.as_ref();
+        self.write_remote(local, remote).await.with_context(|| {
+            format!(
+                "failed to upload {} to {remote} on `{}`",
+                local.display(),
+                self.id
+            )
+        })
+    }
+
+    /// Copy the file at `remote` on the host to `local`, replacing it if it exists. Returns the
+    /// number of bytes copied.
+    pub async fn download(&self, remote: &str, local: impl AsRef<Path>) -> anyhow::Result<u64> {
+        let local = local.as_ref();
+        self.read_remote(remote, local).await.with_context(|| {
+            format!(
+                "failed to download {remote} on `{}` to {}",
+                self.id,
+                local.display()
+            )
+        })
+    }
+
+    async fn write_remote(&self, local: &Path, remote: &str) -> anyhow::Result<u64> {
+        let mut file = File::open(local)
+            .await
+            .with_context(|| format!("could not open {}", local.display()))?;
+        let size = file.metadata().await.ok().map(|m| m.len());
+
+        let (program, args) = write_command(remote);
+        let mut child = self
+            .session
+            .command(program)
+            .args(args)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .stderr(Stdio::piped())
+            .spawn()
+            .await
+            .context("failed to start remote write")?;
+        // SAFETY: `Stdio::piped()` is used above for the stdin, so it should be present.
+        let mut stdin = child.stdin().take().expect("missing stdin handle");
+        let bytes = copy_with_progress(&self.id, &mut file, &mut stdin, size).await?;
+        stdin
+            .shutdown()
+            .await
+            .context("failed to close remote write")?;
+        drop(stdin);
+
+        let output = child
+            .wait_with_output()
+            .await
+            .context("remote write failed")?;
+        if !output.status.success() {
+            anyhow::bail!(
+                "writing {remote} exited with error code {}: {}",
+                output.status,
+                String::from_utf8_lossy(&output.stderr).trim()
+            );
+        }
+        Ok(bytes)
+    }
+
+    async fn read_remote(&self, remote: &str, local: &Path) -> anyhow::Result<u64> {
+        // The size is only used to report progress.
+        let size = self
+            .session
+            .command("stat")
+            .args(["-c", "%s", "--", remote])
+            .output()
+            .await
+            .ok()
+            .and_then(|out| String::from_utf8_lossy(&out.stdout).trim().parse().ok());
+
+        let (program, args) = read_command(remote);
+        let mut child = self
+            .session
+            .command(program)
+            .args(args)
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .await
+            .context("failed to start remote read")?;
+        let mut file = File::create(local)
+            .await
+            .with_context(|| format!("could not create {}", local.display()))?;
+        // SAFETY: `Stdio::piped()` is used above for the stdout, so it should be present.
+        let mut stdout = child.stdout().take().expect("missing stdout handle");
+        let bytes = copy_with_progress(&self.id, &mut stdout, &mut file, size).await?;
+        file.flush().await.context("failed to write file")?;
+        drop(stdout);
+
+        let output = child
+            .wait_with_output()
+            .await
+            .context("remote read failed")?;
+        if !output.status.success() {
+            anyhow::bail!(
+                "reading {remote} exited with error code {}: {}",
+                output.status,
+                String::from_utf8_lossy(&output.stderr).trim()
+            );
+        }
+        Ok(bytes)
+    }
+}
+
+/// The command on the host that writes its stdin to `remote`. The path is passed to the shell as
+/// `$0` rather than in the command line, so it is never split or expanded.
+fn write_command(remote: &str) -> (&'static str, [&str; 3]) {
+    ("sh", ["-c", "cat > \"$0\"", remote])
+}
+
+/// The command on the host that writes `remote` to its stdout. The `--` keeps paths starting with
+/// a `-` from being taken as options.
+fn read_command(remote: &str) -> (&'static str, [&str; 2]) {
+    ("cat", ["--", remote])
+}
+
+/// Copy `reader` to `writer` in fixed size chunks, logging the progress every few seconds unless
+/// the transfer is known to be small. `size` is the expected number of bytes, if known.
+async fn copy_with_progress(
+    host: &str,
+    reader: &mut (impl AsyncRead + Unpin),
+    writer: &mut (impl AsyncWrite + Unpin),
+    size: Option<u64>,
+) -> anyhow::Result<u64> {
+    let mut buf = vec![0; 1 << 20];
+    let mut copied = 0u64;
+    let start = Instant::now();
+    let mut last_report = start;
+    let report = size.is_none_or(|size| size >= PROGRESS_THRESHOLD);
+    loop {
+        let n = reader.read(&mut buf).await.context("failed to read")?;
+        if n == 0 {
+            break;
+        }
+        writer
+            .write_all(&buf[..n])
+            .await
+            .context("failed to write")?;
+        copied += n as u64;
+
+        if report && last_report.elapsed() >= PROGRESS_INTERVAL {
+            last_report = Instant::now();
+            let rate = copied as f64 / start.elapsed().as_secs_f64() / 1e6;
+            match size.filter(|s| *s > 0) {
+                Some(size) => info!(
+                    host,
+                    "{:.1}% of {:.1} MB, {rate:.1} MB/s",
+                    copied as f64 / size as f64 * 100.0,
+                    size as f64 / 1e6
+                ),
+                None => info!(host, "{:.1} MB, {rate:.1} MB/s", copied as f64 / 1e6),
+            }
+        }
+    }
+    Ok(copied)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::process::Stdio;
+
+    use tokio::process::Command;
+
+    use super::*;
+
+    /// Paths a shell would split, expand or take as an option if they were passed as is.
+    const HOSTILE: &[&str] = &["my file", "-n", "$HOME", "it's", "a\"b", "`id`"];
+
+    /// Upload `local` to `remote` like [Host::upload], with the command run on the controller.
+    async fn upload(local: &Path, remote: &Path) -> anyhow::Result<u64> {
+        let mut file = File::open(local).await?;
+        let (program, args) = write_command(remote.to_str().unwrap());
+        let mut child = Command::new(program)
+            .args(args)
+            .stdin(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()?;
+        let mut stdin = child.stdin.take().unwrap();
+        let bytes = copy_with_progress("local", &mut file, &mut stdin, None).await?;
+        stdin.shutdown().await?;
+        drop(stdin);
+        let output = child.wait_with_output().await?;
+        anyhow::ensure!(
+            output.status.success(),
+            "write exited with {}",
+            output.status
+        );
+        Ok(bytes)
+    }
+
+    /// Download `remote` to `local` like [Host::download], with the command run on the
+    /// controller.
+    async fn download(remote: &Path, local: &Path) -> anyhow::Result<u64> {
+        let (program, args) = read_command(remote.to_str().unwrap());
+        let mut child = Command::new(program)
+            .args(args)
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()?;
+        let mut file = File::create(local).await?;
+        let mut stdout = child.stdout.take().unwrap();
+        let bytes = copy_with_progress("local", &mut stdout, &mut file, None).await?;
+        file.flush().await?;
+        drop(stdout);
+        let output = child.wait_with_output().await?;
+        anyhow::ensure!(
+            output.status.success(),
+            "read exited with {}",
+            output.status
+        );
+        Ok(bytes)
+    }
+
+    /// Contents larger than the buffer, so they are copied in several chunks.
+    fn contents() -> Vec<u8> {
+        (0..(3 << 20) + 17).map(|i| (i % 251) as u8).collect()
+    }
+
+    #[tokio::test]
+    async fn files_round_trip_through_the_commands() {
+        let dir = tempfile::tempdir().unwrap();
+        let local = dir.path().join("local");
+        std::fs::write(&local, contents()).unwrap();
+
+        for name in HOSTILE {
+            let remote = dir.path().join(name);
+            let copied = upload(&local, &remote).await.unwrap();
+            assert_eq!(copied, contents().len() as u64, "{name}");
+            assert_eq!(std::fs::read(&remote).unwrap(), contents(), "{name}");
+
+            let back = dir.path().join("back");
+            download(&remote, &back).await.unwrap();
+            assert_eq!(std::fs::read(&back).unwrap(), contents(), "{name}");
+        }
+    }
+
+    #[tokio::test]
+    async fn relative_paths_starting_with_a_dash_are_not_options() {
+        let dir = tempfile::tempdir().unwrap();
+        // Relative paths are resolved from the working directory, the home directory over SSH.
+        let (program, args) = read_command("-n");
+        let output = Command::new(program)
+            .args(args)
+            .current_dir(dir.path())
+            .output()
+            .await
+            .unwrap();
+        assert!(!output.status.success());
+
+        std::fs::write(dir.path().join("-n"), b"dashed").unwrap();
+        let output = Command::new(program)
+            .args(args)
+            .current_dir(dir.path())
+            .output()
+            .await
+            .unwrap();
+        assert_eq!(output.stdout, b"dashed");
+    }
+
+    #[tokio::test]
+    async fn failed_commands_are_errors() {
+        let dir = tempfile::tempdir().unwrap();
+        let local = dir.path().join("local");
+        std::fs::write(&local, b"contents").unwrap();
+
+        let missing = dir.path().join("missing/file");
+        assert!(upload(&local, &missing).await.is_err());
+        assert!(download(&missing, &dir.path().join("back")).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn empty_files_are_copied() {
+        let mut writer = Vec::new();
+        let copied = copy_with_progress("local", &mut &b""[..], &mut writer, Some(0))
+            .await
+            .unwrap();
+        assert_eq!(copied, 0);
+        assert!(writer.is_empty());
+    }
+}
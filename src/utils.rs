@@ -1,28 +1,744 @@
-use std::{process::Output, sync::Arc};
+use std::{
+    borrow::Cow,
+    fmt,
+    future::Future,
+    net::Ipv4Addr,
+    path::{Path, PathBuf},
+    process::Output,
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
 
 use anyhow::Context;
-use tokio::task::JoinSet;
-use tracing::error;
+use openssh::{OwningCommand, Session, Stdio};
+use serde::{Serialize, Serializer};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, BufReader},
+    sync::Semaphore,
+    task::JoinSet,
+    time::{sleep, Instant},
+};
+use tracing::{debug, error, info, warn};
 
-use crate::hosts::Host;
+use crate::{command_log, driver::ap, hosts::Host, metrics};
 
-pub async fn run_all<F>(
-    hosts: impl IntoIterator<Item = &Arc<Host>>,
+/// A command to run on a host.
+///
+/// Commands built from a program and arguments are passed to the host with every argument
+/// escaped, so values such as an SSID containing spaces or quotes arrive unchanged. Use
+/// [RemoteCmd::shell] only when shell features such as pipes are needed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RemoteCmd {
+    Exec {
+        program: String,
+        args: Vec<String>,
+    },
+    /// A command line interpreted by the shell of the host as is.
+    Shell(String),
+}
+
+impl RemoteCmd {
+    pub fn new(program: impl Into<String>) -> Self {
+        RemoteCmd::Exec {
+            program: program.into(),
+            args: Vec::new(),
+        }
+    }
+
+    /// A command line for the shell of the host. Quote any values in it with [shell_quote].
+    pub fn shell(command: impl Into<String>) -> Self {
+        RemoteCmd::Shell(command.into())
+    }
+
+    /// Add an argument. For a shell command line the argument is quoted and appended.
+    pub fn arg(mut self, arg: impl ToString) -> Self {
+        match &mut self {
+            RemoteCmd::Exec { args, .. } => args.push(arg.to_string()),
+            RemoteCmd::Shell(command) => {
+                command.push(' ');
+                command.push_str(&shell_quote(&arg.to_string()));
+            }
+        }
+        self
+    }
+
+    pub fn args<I>(self, args: I) -> Self
+    where
+        I: IntoIterator,
+        I::Item: ToString,
+    {
+        args.into_iter().fold(self, RemoteCmd::arg)
+    }
+
+    /// Prepare the command on a session, to be spawned or run like any other openssh command.
+    pub fn build<'s>(&self, session: &'s Session) -> OwningCommand<&'s Session> {
+        match self {
+            RemoteCmd::Exec { program, args } => {
+                let mut command = session.command(program.as_str());
+                command.args(args);
+                command
+            }
+            RemoteCmd::Shell(command) => session.shell(command),
+        }
+    }
+
+    /// The command as it can follow `exec` in a shell command line, so it replaces the shell.
+    pub fn exec_line(&self) -> String {
+        match self {
+            RemoteCmd::Exec { .. } => self.to_string(),
+            RemoteCmd::Shell(command) => format!("sh -c {}", shell_quote(command)),
+        }
+    }
+
+    /// Run the command on `host` and collect its output, recording it in the [command_log].
+    pub async fn output(&self, host: &Host) -> Result<Output, openssh::Error> {
+        let start = SystemTime::now();
+        let result = self.build(&host.session).output().await;
+        record_result(host, self, start, &result);
+        result
+    }
+}
+
+/// Record a command in the [command_log] and the [metrics].
+fn record_result(
+    host: &Host,
+    command: &RemoteCmd,
+    start: SystemTime,
+    result: &Result<Output, openssh::Error>,
+) {
+    let result = match result {
+        Ok(output) => Ok(output.status),
+        Err(err) => Err(err as &dyn fmt::Display),
+    };
+    metrics::record_command(&host.id, !result.is_ok_and(|status| status.success()));
+    command_log::record(host, command, start, result);
+}
+
+/// Shows the command as it could be typed in a shell.
+impl fmt::Display for RemoteCmd {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RemoteCmd::Exec { program, args } => {
+                f.write_str(&shell_quote(program))?;
+                for arg in args {
+                    write!(f, " {}", shell_quote(arg))?;
+                }
+                Ok(())
+            }
+            RemoteCmd::Shell(command) => f.write_str(command),
+        }
+    }
+}
+
+/// Saved as the command line it shows, see [Display](fmt::Display).
+impl Serialize for RemoteCmd {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl From<String> for RemoteCmd {
+    fn from(command: String) -> Self {
+        RemoteCmd::Shell(command)
+    }
+}
+
+impl From<&str> for RemoteCmd {
+    fn from(command: &str) -> Self {
+        RemoteCmd::Shell(command.to_string())
+    }
+}
+
+/// Quote `value` so a POSIX shell reads it as a single word, without expanding anything in it.
+pub fn shell_quote(value: &str) -> String {
+    let plain = |c: char| c.is_ascii_alphanumeric() || "_-./:=,+@%".contains(c);
+    if !value.is_empty() && value.chars().all(plain) {
+        return value.to_string();
+    }
+    format!("'{}'", value.replace('\'', r"'\''"))
+}
+
+/// How many lines of output an error of a failed command includes.
+const ERROR_OUTPUT_LINES: usize = 5;
+
+impl Host {
+    /// Run a command line in the shell of the host, see [Host::command_checked].
+    pub async fn shell_checked(&self, command: impl Into<String>) -> anyhow::Result<Output> {
+        self.command_checked(RemoteCmd::shell(command)).await
+    }
+
+    /// Run a command on the host, failing if it could not be run or exits with an error.
+    ///
+    /// The error contains the command and the end of its output, while the full output is logged
+    /// at debug level.
+    pub async fn command_checked(&self, command: RemoteCmd) -> anyhow::Result<Output> {
+        let output = command
+            .output(self)
+            .await
+            .with_context(|| format!("failed to run `{command}` on `{}`", self.id))?;
+        if !output.status.success() {
+            debug!(
+                host = self.id,
+                "`{command}` failed with {}\nstdout:\n{}\nstderr:\n{}",
+                output.status,
+                log_text(&output.stdout),
+                log_text(&output.stderr)
+            );
+            anyhow::bail!(exit_error(&command.to_string(), &output));
+        }
+        Ok(output)
+    }
+}
+
+/// Describe a command that exited with an error, with the last lines of its error output, or of
+/// its regular output if it printed no errors.
+pub fn exit_error(command: &str, output: &Output) -> String {
+    let stderr = log_text(&output.stderr);
+    let stdout = log_text(&output.stdout);
+    let printed = match stderr.trim() {
+        "" => stdout.trim(),
+        stderr => stderr,
+    };
+    let lines: Vec<_> = printed.lines().collect();
+    let skipped = lines.len().saturating_sub(ERROR_OUTPUT_LINES);
+    let tail = lines[skipped..].join("\n");
+    match (tail.is_empty(), skipped > 0) {
+        (true, _) => format!("`{command}` failed with {}", output.status),
+        (false, false) => format!("`{command}` failed with {}: {tail}", output.status),
+        (false, true) => format!("`{command}` failed with {}: ...\n{tail}", output.status),
+    }
+}
+
+/// The result of a command started through [spawn_all].
+pub type CommandResult = (Arc<Host>, Result<Output, openssh::Error>);
+
+/// Start a command on every host without waiting for them to complete.
+///
+/// The command for each host is created by `func`, either a [RemoteCmd] or a shell command line.
+/// Results can be processed as they complete using [JoinSet::join_next].
+pub fn spawn_all<'a, F, C>(
+    hosts: impl IntoIterator<Item = &'a Arc<Host>>,
     mut func: F,
-) -> anyhow::Result<Vec<(Arc<Host>, Output)>>
+) -> JoinSet<CommandResult>
 where
-    F: FnMut(&Arc<Host>) -> String,
+    F: FnMut(&Arc<Host>) -> C,
+    C: Into<RemoteCmd>,
 {
     let mut commands = JoinSet::new();
 
     hosts.into_iter().for_each(|host| {
         let host = host.clone();
-        let command = func(&host);
-        commands.spawn(async move { (host.clone(), host.session.shell(command).output().await) });
+        let command = func(&host).into();
+        commands.spawn(async move {
+            let result = command.output(&host).await;
+            (host, result)
+        });
     });
 
+    commands
+}
+
+/// The output stream a line was read from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Line {
+    Stdout,
+    Stderr,
+}
+
+/// Like [spawn_all], but calls `on_line` for every line of output as soon as it is received.
+///
+/// The complete output is still collected and returned when the command completes.
+pub fn spawn_all_streaming<'a, F, C, L>(
+    hosts: impl IntoIterator<Item = &'a Arc<Host>>,
+    mut func: F,
+    on_line: L,
+) -> JoinSet<CommandResult>
+where
+    F: FnMut(&Arc<Host>) -> C,
+    C: Into<RemoteCmd>,
+    L: Fn(&Host, Line, &str) + Send + Sync + 'static,
+{
+    let on_line: LineHandler = Arc::new(on_line);
+    let mut commands = JoinSet::new();
+
+    hosts.into_iter().for_each(|host| {
+        let command = func(host);
+        spawn_one(&mut commands, host.clone(), command, Some(on_line.clone()));
+    });
+
+    commands
+}
+
+/// Lines longer than this are passed to a [LineHandler] in parts, so output without newlines is
+/// not buffered as a whole before it is handled.
+const MAX_LINE_LENGTH: usize = 64 * 1024;
+
+/// A callback receiving the output of a command line by line.
+pub type LineHandler = Arc<dyn Fn(&Host, Line, &str) + Send + Sync>;
+
+/// Start a single command in an existing set of commands, for example to retry a command
+/// that was started through [spawn_all] or [spawn_all_streaming].
+///
+/// If `on_line` is set, the output is streamed to it like with [spawn_all_streaming].
+pub fn spawn_one(
+    commands: &mut JoinSet<CommandResult>,
+    host: Arc<Host>,
+    command: impl Into<RemoteCmd>,
+    on_line: Option<LineHandler>,
+) {
+    let command = command.into();
+    commands.spawn(async move {
+        let result = match on_line {
+            Some(on_line) => stream_output(&host, &command, &*on_line).await,
+            None => command.output(&host).await,
+        };
+        (host, result)
+    });
+}
+
+/// Run a command on a host while passing every line of output to `on_line`.
+async fn stream_output(
+    host: &Host,
+    command: &RemoteCmd,
+    on_line: &(dyn Fn(&Host, Line, &str) + Send + Sync),
+) -> Result<Output, openssh::Error> {
+    let start = SystemTime::now();
+    let result = stream_lines(host, command, on_line).await;
+    record_result(host, command, start, &result);
+    result
+}
+
+async fn stream_lines(
+    host: &Host,
+    command: &RemoteCmd,
+    on_line: &(dyn Fn(&Host, Line, &str) + Send + Sync),
+) -> Result<Output, openssh::Error> {
+    let mut child = command
+        .build(&host.session)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .await?;
+
+    // SAFETY: Both streams were set to `Stdio::piped()` above.
+    let stdout = child.stdout().take().expect("missing stdout handle");
+    let stderr = child.stderr().take().expect("missing stderr handle");
+
+    let on_line = |kind, line: &str| on_line(host, kind, line);
+    let (stdout, stderr) = tokio::try_join!(
+        read_lines(stdout, Line::Stdout, &on_line),
+        read_lines(stderr, Line::Stderr, &on_line),
+    )
+    .map_err(openssh::Error::ChildIo)?;
+
+    let status = child.wait().await?;
+    Ok(Output {
+        status,
+        stdout,
+        stderr,
+    })
+}
+
+/// Read a stream to the end, passing every line to `on_line`. Returns everything that was read.
+///
+/// Lines longer than [MAX_LINE_LENGTH] are split. The next line is only read once `on_line`
+/// returns, so a slow handler slows down reading instead of buffering the output.
+async fn read_lines(
+    stream: impl AsyncRead + Unpin,
+    kind: Line,
+    on_line: &(dyn Fn(Line, &str) + Send + Sync),
+) -> std::io::Result<Vec<u8>> {
+    let mut reader = BufReader::new(stream);
+    let mut all = Vec::new();
+    let mut line = Vec::new();
+    loop {
+        line.clear();
+        let read = (&mut reader)
+            .take(MAX_LINE_LENGTH as u64)
+            .read_until(b'\n', &mut line)
+            .await?;
+        if read == 0 {
+            break;
+        }
+        all.extend_from_slice(&line);
+        on_line(kind, &log_text(&line));
+    }
+    Ok(all)
+}
+
+/// Run a command on every host concurrently and wait for all of them to complete.
+///
+/// The command for each host is created by `func`, like with [spawn_all]. The outputs are in the
+/// order of `hosts`.
+pub async fn run_all<F, C>(
+    hosts: impl IntoIterator<Item = &Arc<Host>>,
+    func: F,
+) -> anyhow::Result<Vec<(Arc<Host>, Output)>>
+where
+    F: FnMut(&Arc<Host>) -> C,
+    C: Into<RemoteCmd>,
+{
+    run_all_with(hosts, func, RunOptions::default()).await
+}
+
+/// How [run_all_with] runs the commands.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RunOptions {
+    /// Run failed commands again as allowed by the policy.
+    pub retry: Option<RetryPolicy>,
+    /// How many commands may run at the same time, unlimited if `None`. Relays limit the number
+    /// of sessions that can be open at once, so running many commands through one can fail.
+    pub max_concurrency: Option<usize>,
+}
+
+/// Like [run_all], with the behaviour configured through `options`.
+pub async fn run_all_with<F, C>(
+    hosts: impl IntoIterator<Item = &Arc<Host>>,
+    mut func: F,
+    options: RunOptions,
+) -> anyhow::Result<Vec<(Arc<Host>, Output)>>
+where
+    F: FnMut(&Arc<Host>) -> C,
+    C: Into<RemoteCmd>,
+{
+    let permits = options
+        .max_concurrency
+        .map(|limit| Arc::new(Semaphore::new(limit.max(1))));
+    let mut commands = JoinSet::new();
+    for (i, host) in hosts.into_iter().enumerate() {
+        let host = host.clone();
+        let command = func(&host).into();
+        let permits = permits.clone();
+        commands.spawn(async move {
+            // SAFETY: The semaphore is never closed.
+            let _permit = match &permits {
+                Some(permits) => Some(permits.acquire().await.expect("semaphore is closed")),
+                None => None,
+            };
+            let result = match options.retry {
+                Some(policy) => run_with_retry(&host, &command, policy).await,
+                None => command.output(&host).await,
+            };
+            (i, (host, result))
+        });
+    }
+
+    // The commands complete in any order, so they are put back in the order they were started.
+    let mut results = commands.join_all().await;
+    results.sort_by_key(|(i, _)| *i);
+    collect_outputs(results.into_iter().map(|(_, result)| result))
+}
+
+/// Like [run_all], but calls `on_line` for every line of output of every host as soon as it is
+/// received, see [spawn_all_streaming].
+pub async fn run_all_streaming<'a, F, C, L>(
+    hosts: impl IntoIterator<Item = &'a Arc<Host>>,
+    func: F,
+    on_line: L,
+) -> anyhow::Result<Vec<(Arc<Host>, Output)>>
+where
+    F: FnMut(&Arc<Host>) -> C,
+    C: Into<RemoteCmd>,
+    L: Fn(&Host, Line, &str) + Send + Sync + 'static,
+{
+    collect_outputs(spawn_all_streaming(hosts, func, on_line).join_all().await)
+}
+
+/// What [run_sequential] does when a host fails.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OnError {
+    /// Skip the remaining hosts.
+    Stop,
+    /// Carry on with the next host.
+    Continue,
+}
+
+/// The outcome on a single host of [run_sequential] or [for_each_sequential].
+#[derive(Debug)]
+pub struct SequentialResult<T> {
+    pub host: Arc<Host>,
+    /// When the host was started, relative to the start of the first host.
+    pub start: Duration,
+    /// How long the host took.
+    pub elapsed: Duration,
+    pub result: anyhow::Result<T>,
+}
+
+/// Run a command on every host strictly one after the other, like [run_all] but never
+/// concurrently. `delay_between` is waited after each host completes before starting the next.
+///
+/// A command fails if it could not be run or exits with an error. With [OnError::Stop] the
+/// remaining hosts are skipped, so the failure is the last result.
+pub async fn run_sequential<F, C>(
+    hosts: impl IntoIterator<Item = &Arc<Host>>,
+    delay_between: Duration,
+    mut func: F,
+    on_error: OnError,
+) -> Vec<SequentialResult<Output>>
+where
+    F: FnMut(&Arc<Host>) -> C,
+    C: Into<RemoteCmd>,
+{
+    for_each_sequential(hosts, delay_between, on_error, |host| {
+        let command = func(&host).into();
+        async move { host.command_checked(command).await }
+    })
+    .await
+}
+
+/// Like [run_sequential], but runs any operation on the hosts rather than a command.
+pub async fn for_each_sequential<F, Fut, T>(
+    hosts: impl IntoIterator<Item = &Arc<Host>>,
+    delay_between: Duration,
+    on_error: OnError,
+    mut func: F,
+) -> Vec<SequentialResult<T>>
+where
+    F: FnMut(Arc<Host>) -> Fut,
+    Fut: Future<Output = anyhow::Result<T>>,
+{
+    let first_start = Instant::now();
+    let mut results = Vec::new();
+    for (i, host) in hosts.into_iter().enumerate() {
+        if i > 0 && !delay_between.is_zero() {
+            sleep(delay_between).await;
+        }
+        let start = Instant::now();
+        let result = func(host.clone()).await;
+        let failed = result.is_err();
+        if let Err(err) = &result {
+            warn!(host = host.id, "Failed: {err:#}");
+        }
+        results.push(SequentialResult {
+            host: host.clone(),
+            start: start - first_start,
+            elapsed: start.elapsed(),
+            result,
+        });
+        if failed && on_error == OnError::Stop {
+            debug!("Skipping the remaining hosts");
+            break;
+        }
+    }
+    results
+}
+
+/// How many times [clock_offset] measures the clock, keeping the sample with the shortest round
+/// trip.
+const CLOCK_SAMPLES: usize = 3;
+
+/// The start of the line a command started by [barrier_start] prints to its stderr when it
+/// starts, followed by the time.
+const BARRIER_MARKER: &str = "barrier-start";
+
+/// The difference between the clock of `host` and that of the controller in seconds, positive if
+/// the host is ahead. This is accurate to about half the round trip of a command.
+pub async fn clock_offset(host: &Host) -> anyhow::Result<f64> {
+    let mut best: Option<(f64, f64)> = None;
+    for _ in 0..CLOCK_SAMPLES {
+        let sent = SystemTime::now();
+        let output = host.shell_checked("date +%s.%N").await?;
+        let round_trip = sent.elapsed().unwrap_or_default().as_secs_f64();
+        let remote: f64 = String::from_utf8_lossy(&output.stdout)
+            .trim()
+            .parse()
+            .context("the host does not report the time in nanoseconds")?;
+        let offset = remote - (unix_time(sent) + round_trip / 2.0);
+        if best.is_none_or(|(shortest, _)| round_trip < shortest) {
+            best = Some((round_trip, offset));
+        }
+    }
+    Ok(best.expect("the clock was sampled").1)
+}
+
+/// Like [run_all], but starts the commands on all hosts at the same instant `at`, which should be
+/// a few seconds ahead to leave time to measure the clocks of the hosts.
+///
+/// Every host waits until `at` by its own clock, corrected for its [clock_offset], so the skew
+/// between the hosts does not depend on how long it takes to start the commands. The skew that
+/// was achieved is logged. Hosts whose clock can not be measured are started by the controller
+/// instead, at best effort.
+pub async fn barrier_start<F, C>(
+    hosts: impl IntoIterator<Item = &Arc<Host>>,
+    at: SystemTime,
+    mut func: F,
+) -> anyhow::Result<Vec<(Arc<Host>, Output)>>
+where
+    F: FnMut(&Arc<Host>) -> C,
+    C: Into<RemoteCmd>,
+{
+    let hosts: Vec<Arc<Host>> = hosts.into_iter().cloned().collect();
+    let mut measurements = JoinSet::new();
+    for (i, host) in hosts.iter().cloned().enumerate() {
+        measurements.spawn(async move { (i, clock_offset(&host).await) });
+    }
+    let mut offsets = vec![None; hosts.len()];
+    for (i, offset) in measurements.join_all().await {
+        match offset {
+            Ok(offset) => {
+                debug!(host = hosts[i].id, "Clock is {:.1} ms ahead", offset * 1e3);
+                offsets[i] = Some(offset);
+            }
+            Err(err) => warn!(
+                host = hosts[i].id,
+                "Clock offset is unknown, starting on a best effort basis: {err:#}"
+            ),
+        }
+    }
+    if at < SystemTime::now() {
+        warn!("The synchronized start time already passed, starting right away");
+    }
+
+    let target = unix_time(at);
+    let mut commands = JoinSet::new();
+    for (i, (host, offset)) in hosts.iter().zip(&offsets).enumerate() {
+        let command = func(host).into();
+        let host = host.clone();
+        let offset = *offset;
+        commands.spawn(async move {
+            let result = match offset {
+                Some(offset) => wait_until(&command, target + offset).output(&host).await,
+                None => {
+                    if let Ok(wait) = at.duration_since(SystemTime::now()) {
+                        sleep(wait).await;
+                    }
+                    command.output(&host).await
+                }
+            };
+            (i, (host, result))
+        });
+    }
+    let mut results = commands.join_all().await;
+    results.sort_by_key(|(i, _)| *i);
+    let mut outputs = collect_outputs(results.into_iter().map(|(_, result)| result))?;
+
+    // The hosts report when they started on their own clock.
+    let mut delays = Vec::new();
+    for ((host, output), offset) in outputs.iter_mut().zip(&offsets) {
+        let Some(offset) = offset else {
+            continue;
+        };
+        match take_barrier_start(&mut output.stderr) {
+            Some(start) => delays.push(start - offset - target),
+            None => warn!(host = host.id, "Host did not report when it started"),
+        }
+    }
+    let earliest = delays.iter().copied().fold(f64::INFINITY, f64::min);
+    let latest = delays.iter().copied().fold(f64::NEG_INFINITY, f64::max);
+    if !delays.is_empty() {
+        info!(
+            "Started {} hosts within {:.1} ms of each other, at most {:.1} ms late",
+            delays.len(),
+            (latest - earliest) * 1e3,
+            latest * 1e3
+        );
+    }
+    Ok(outputs)
+}
+
+/// Wrap `command` so it waits until `target`, in seconds since the unix epoch on the clock of the
+/// host, and reports when it started before running.
+fn wait_until(command: &RemoteCmd, target: f64) -> RemoteCmd {
+    let command = command.exec_line();
+    RemoteCmd::shell(format!(
+        "sleep $(awk -v t={target:.6} -v n=$(date +%s.%N) \
+         'BEGIN {{ d = t - n; printf \"%.6f\", (d > 0 ? d : 0) }}'); \
+         echo \"{BARRIER_MARKER} $(date +%s.%N)\" >&2; \
+         exec {command}"
+    ))
+}
+
+/// Remove the line printed by [wait_until] from the start of `stderr`, returning the time in it.
+fn take_barrier_start(stderr: &mut Vec<u8>) -> Option<f64> {
+    let end = stderr.iter().position(|b| *b == b'\n')?;
+    let line = std::str::from_utf8(&stderr[..end]).ok()?;
+    let start = line.strip_prefix(BARRIER_MARKER)?.trim().parse().ok()?;
+    stderr.drain(..=end);
+    Some(start)
+}
+
+/// When [run_all_with] runs a failed command again. Only use this for commands that can safely run more than
+/// once.
+#[derive(Debug, Clone, Copy)]
+pub struct RetryPolicy {
+    /// How many times to run the command at most, including the first attempt.
+    pub attempts: u32,
+    /// How long to wait before running the command again.
+    pub delay: Duration,
+    /// Whether a failed attempt should be retried. Only called for attempts that could not be run
+    /// or exited with an error.
+    pub retry_if: fn(&Result<Output, openssh::Error>) -> bool,
+}
+
+impl RetryPolicy {
+    /// Retry commands that failed because of the SSH connection rather than the command itself.
+    pub const SSH: RetryPolicy = RetryPolicy {
+        attempts: 3,
+        delay: Duration::from_millis(500),
+        retry_if: is_ssh_failure,
+    };
+}
+
+/// Whether a command failed because of the SSH connection. ssh exits with 255 if it could not run
+/// the command, for example when the multiplexed session could not be opened.
+pub fn is_ssh_failure(result: &Result<Output, openssh::Error>) -> bool {
+    match result {
+        Ok(output) => {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            output.status.code() == Some(255)
+                && (stderr.contains("mux_client_request_session")
+                    || stderr.contains("session request failed"))
+        }
+        Err(_) => true,
+    }
+}
+
+async fn run_with_retry(
+    host: &Host,
+    command: &RemoteCmd,
+    policy: RetryPolicy,
+) -> Result<Output, openssh::Error> {
+    retry(&host.id, policy, || command.output(host)).await
+}
+
+/// Make attempts with `run` on host `id` until one succeeds or `policy` gives up, returning the
+/// result of the last attempt.
+async fn retry<F, Fut>(id: &str, policy: RetryPolicy, mut run: F) -> Result<Output, openssh::Error>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<Output, openssh::Error>>,
+{
+    let mut attempt = 1;
+    loop {
+        let result = run().await;
+        let error = match &result {
+            Ok(output) if output.status.success() => return result,
+            Ok(output) => format!(
+                "exited with {}: {}",
+                output.status,
+                log_text(&output.stderr).trim_start()
+            ),
+            Err(err) => err.to_string(),
+        };
+        if attempt >= policy.attempts || !(policy.retry_if)(&result) {
+            return result;
+        }
+        warn!(
+            host = id,
+            "Command failed on attempt {attempt}/{}, retrying: {error}", policy.attempts
+        );
+        sleep(policy.delay).await;
+        attempt += 1;
+    }
+}
+
+/// Collect the outputs of completed commands, failing if any of them could not be run.
+fn collect_outputs(
+    results: impl IntoIterator<Item = CommandResult>,
+) -> anyhow::Result<Vec<(Arc<Host>, Output)>> {
     let mut out = Vec::new();
-    for (host, result) in commands.join_all().await {
+    for (host, result) in results {
         let result = match result {
             Ok(v) => (host, v),
             Err(err) => {
@@ -36,3 +752,626 @@ where
 
     Ok(out)
 }
+
+/// Parse a bitrate in bits per second with an optional `K`, `M` or `G` suffix, like iperf does.
+///
+/// Suffixes are powers of 1000 and fractional values such as `1.5G` are allowed.
+pub fn parse_bitrate(s: &str) -> Result<u64, String> {
+    let s = s.trim();
+    let (number, multiplier) = match s.chars().last() {
+        Some('k' | 'K') => (&s[..s.len() - 1], 1_000f64),
+        Some('m' | 'M') => (&s[..s.len() - 1], 1_000_000f64),
+        Some('g' | 'G') => (&s[..s.len() - 1], 1_000_000_000f64),
+        _ => (s, 1f64),
+    };
+
+    let number: f64 = number
+        .parse()
+        .map_err(|_| format!("invalid bitrate `{s}`, expected a number like `100M`"))?;
+    if !number.is_finite() || number < 0.0 {
+        return Err(format!("invalid bitrate `{s}`, must be a positive number"));
+    }
+    Ok((number * multiplier).round() as u64)
+}
+
+/// Parse a channel width in MHz, which is 20, 40, 80 or 160.
+pub fn parse_bandwidth(s: &str) -> Result<u32, String> {
+    match s.trim().parse() {
+        Ok(width @ (20 | 40 | 80 | 160)) => Ok(width),
+        _ => Err(format!(
+            "invalid bandwidth `{s}`, expected 20, 40, 80 or 160 MHz"
+        )),
+    }
+}
+
+/// Parse the frequency of a 20 MHz channel in MHz, like `5180` for channel 36.
+pub fn parse_frequency(s: &str) -> Result<u32, String> {
+    let frequency = s
+        .trim()
+        .parse()
+        .map_err(|_| format!("invalid frequency `{s}`, expected a number in MHz like `5180`"))?;
+    match ap::channel(frequency) {
+        Some(_) => Ok(frequency),
+        None => Err(format!(
+            "{frequency} MHz is not the center of a channel in the 2.4, 5 or 6 GHz band"
+        )),
+    }
+}
+
+/// Check that the primary channel at `frequency` MHz is part of a `bandwidth` MHz wide channel.
+pub fn check_channel(frequency: u32, bandwidth: u32) -> Result<(), String> {
+    let Some((band, channel)) = ap::channel(frequency) else {
+        return Err(format!("{frequency} MHz is not a known channel"));
+    };
+    if ap::center_channel(band, channel, bandwidth).is_none() {
+        return Err(format!(
+            "channel {channel} ({frequency} MHz) is not part of a {bandwidth} MHz wide channel"
+        ));
+    }
+    Ok(())
+}
+
+/// Parse a duration in seconds with an optional `s`, `m` or `h` suffix, like `90s`, `15m` or
+/// `2h`. Fractional values such as `1.5h` are allowed.
+pub fn parse_duration(s: &str) -> Result<Duration, String> {
+    let s = s.trim();
+    let (number, multiplier) = match s.chars().last() {
+        Some('s') => (&s[..s.len() - 1], 1f64),
+        Some('m') => (&s[..s.len() - 1], 60f64),
+        Some('h') => (&s[..s.len() - 1], 3600f64),
+        _ => (s, 1f64),
+    };
+
+    let number: f64 = number
+        .parse()
+        .map_err(|_| format!("invalid duration `{s}`, expected a number like `15m`"))?;
+    if !number.is_finite() || number <= 0.0 {
+        return Err(format!("invalid duration `{s}`, must be a positive number"));
+    }
+    Duration::try_from_secs_f64(number * multiplier)
+        .map_err(|_| format!("invalid duration `{s}`, the duration is too large"))
+}
+
+/// Format a bitrate using the largest suffix that represents it exactly, the inverse of
+/// [parse_bitrate].
+pub fn format_bitrate(bits: u64) -> String {
+    match bits {
+        0 => "0".to_string(),
+        b if b % 1_000_000_000 == 0 => format!("{}G", b / 1_000_000_000),
+        b if b % 1_000_000 == 0 => format!("{}M", b / 1_000_000),
+        b if b % 1_000 == 0 => format!("{}K", b / 1_000),
+        b => b.to_string(),
+    }
+}
+
+/// Run a shell command on the controller itself and capture its output. Fails if the command
+/// exits with an error.
+pub async fn run_local(command: &str) -> anyhow::Result<Output> {
+    let mut sh = tokio::process::Command::new("sh");
+    sh.args(["-c", command]);
+    let output = run_local_streaming(sh, &|_, _| {})
+        .await
+        .with_context(|| format!("failed to run `{command}`"))?;
+    if !output.status.success() {
+        anyhow::bail!(
+            "`{command}` exited with error code {}: {}",
+            output.status,
+            log_text(&output.stderr).trim_start()
+        );
+    }
+    Ok(output)
+}
+
+/// Run a process on the controller itself, passing every line of its output to `on_line` as soon
+/// as it is received, like [spawn_all_streaming] does for the hosts. The complete output is still
+/// returned, also if the process exits with an error.
+pub async fn run_local_streaming(
+    mut command: tokio::process::Command,
+    on_line: &(dyn Fn(Line, &str) + Send + Sync),
+) -> std::io::Result<Output> {
+    let mut child = command
+        .stdin(std::process::Stdio::null())
+        .stdout(std::process::Stdio::piped())
+        .stderr(std::process::Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()?;
+
+    // SAFETY: Both streams were set to `Stdio::piped()` above.
+    let stdout = child.stdout.take().expect("missing stdout handle");
+    let stderr = child.stderr.take().expect("missing stderr handle");
+    let (stdout, stderr) = tokio::try_join!(
+        read_lines(stdout, Line::Stdout, on_line),
+        read_lines(stderr, Line::Stderr, on_line),
+    )?;
+
+    let status = child.wait().await?;
+    Ok(Output {
+        status,
+        stdout,
+        stderr,
+    })
+}
+
+/// The user and machine running the controller, as `user@hostname`.
+pub async fn controller_name() -> String {
+    let user = std::env::var("USER").unwrap_or_else(|_| "unknown".to_string());
+    let hostname = tokio::fs::read_to_string("/proc/sys/kernel/hostname")
+        .await
+        .map_or_else(|_| "unknown".to_string(), |h| h.trim().to_string());
+    format!("{user}@{hostname}")
+}
+
+/// Convert a time to seconds since the unix epoch.
+pub fn unix_time(time: SystemTime) -> f64 {
+    time.duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs_f64()
+}
+
+/// The name the files of host `id` are written under, like `<stem>.pcapng`. Letters, digits,
+/// `-` and `_` are kept and every other character is replaced by `_`. An id that had to be
+/// changed gets the CRC-32 of the original appended, so ids like `nuc.1` and `nuc/1` do not end
+/// up with the same name. Ids that are safe as they are are used as is.
+pub fn sanitize_file_stem(id: &str) -> String {
+    let safe = |c: char| c.is_alphanumeric() || c == '-' || c == '_';
+    if !id.is_empty() && id.chars().all(safe) {
+        return id.to_string();
+    }
+    let stem: String = id.chars().map(|c| if safe(c) { c } else { '_' }).collect();
+    format!("{stem}-{:08x}", crc32fast::hash(id.as_bytes()))
+}
+
+/// The file of host `id` in `dir` with `extension`, like `<dir>/<id>.pcapng`. See
+/// [sanitize_file_stem].
+pub fn host_file(dir: &Path, id: &str, extension: &str) -> PathBuf {
+    dir.join(format!("{}.{extension}", sanitize_file_stem(id)))
+}
+
+/// Marks remote output in logs that was not valid UTF-8 and had bytes replaced.
+pub const INVALID_UTF8_MARKER: &str = "[invalid UTF-8 replaced]";
+
+/// Remote output as text for a log line or error message, without trailing whitespace. Output
+/// that is not UTF-8 is converted lossily and ends with [INVALID_UTF8_MARKER], so the replacement
+/// characters are not mistaken for what the host printed. Output that is saved to a file is
+/// written as the raw bytes instead.
+pub fn log_text(output: &[u8]) -> Cow<'_, str> {
+    match std::str::from_utf8(output) {
+        Ok(text) => Cow::Borrowed(text.trim_end()),
+        Err(_) => Cow::Owned(format!(
+            "{} {INVALID_UTF8_MARKER}",
+            String::from_utf8_lossy(output).trim_end()
+        )),
+    }
+}
+
+/// The first IPv4 address in the output of a command that prints one per line, `None` if it
+/// printed nothing. Fails if the output is anything other than an address.
+pub fn ipv4_of(output: &[u8]) -> anyhow::Result<Option<Ipv4Addr>> {
+    let text = std::str::from_utf8(output)
+        .map_err(|_| anyhow::anyhow!("expected an IPv4 address, got `{}`", log_text(output)))?;
+    let Some(first) = text.split_whitespace().next() else {
+        return Ok(None);
+    };
+    first
+        .parse()
+        .map(Some)
+        .with_context(|| format!("expected an IPv4 address, got `{first}`"))
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::process::Command;
+
+    use super::*;
+
+    /// Values that a shell would split or expand if they were not quoted.
+    const HOSTILE: &[&str] = &[
+        "my network",
+        "it's",
+        "\"quoted\"",
+        "$HOME",
+        "${PATH}",
+        "$(id)",
+        "`id`",
+        "a'b\"c$d e",
+        "",
+    ];
+
+    /// The arguments `sh` passes to a program when running `command_line`.
+    pub(crate) fn shell_words(command_line: &str) -> Vec<String> {
+        let script = format!("for arg in {command_line}; do printf '%s\\0' \"$arg\"; done");
+        // `for` over a word list gets the same words the shell would pass as arguments.
+        let output = Command::new("sh").arg("-c").arg(script).output().unwrap();
+        assert!(output.status.success());
+        String::from_utf8(output.stdout)
+            .unwrap()
+            .split_terminator('\0')
+            .map(str::to_string)
+            .collect()
+    }
+
+    #[test]
+    fn plain_values_are_not_quoted() {
+        assert_eq!(shell_quote("wlan0"), "wlan0");
+        assert_eq!(shell_quote("192.168.1.1/24"), "192.168.1.1/24");
+        assert_eq!(shell_quote("he-mcs-5=1:11,2"), "he-mcs-5=1:11,2");
+    }
+
+    #[test]
+    fn hostile_values_are_quoted() {
+        assert_eq!(shell_quote(""), "''");
+        assert_eq!(shell_quote("my network"), "'my network'");
+        assert_eq!(shell_quote("it's"), r"'it'\''s'");
+        assert_eq!(shell_quote("$HOME"), "'$HOME'");
+        for value in HOSTILE {
+            assert_eq!(shell_words(&shell_quote(value)), [*value], "{value:?}");
+        }
+    }
+
+    #[test]
+    fn exec_command_shows_quoted_arguments() {
+        let command = RemoteCmd::new("nmcli")
+            .args(["dev", "wifi", "connect"])
+            .arg("it's $HOME");
+        assert_eq!(
+            command.to_string(),
+            r"nmcli dev wifi connect 'it'\''s $HOME'"
+        );
+        assert_eq!(command.exec_line(), command.to_string());
+
+        let command = RemoteCmd::new("printf").args(HOSTILE);
+        let mut expected = vec!["printf".to_string()];
+        expected.extend(HOSTILE.iter().map(|v| v.to_string()));
+        assert_eq!(shell_words(&command.to_string()), expected);
+        assert_eq!(shell_words(&command.exec_line()), expected);
+    }
+
+    #[test]
+    fn shell_command_quotes_added_arguments() {
+        let command = RemoteCmd::shell("echo $USER |").arg("tr a b").arg("$HOME");
+        assert_eq!(command.to_string(), "echo $USER | 'tr a b' '$HOME'");
+        assert_eq!(
+            command.exec_line(),
+            r"sh -c 'echo $USER | '\''tr a b'\'' '\''$HOME'\'''"
+        );
+        // The shell receives the command line unchanged, so its own features still work.
+        assert_eq!(
+            shell_words(&command.exec_line()),
+            ["sh", "-c", "echo $USER | 'tr a b' '$HOME'"]
+        );
+    }
+
+    #[test]
+    fn log_text_of_utf8_output() {
+        assert_eq!(log_text(b"wlan0: connected\n\n"), "wlan0: connected");
+        assert_eq!(
+            log_text("caf\u{e9} \u{1f4f6}\n".as_bytes()),
+            "caf\u{e9} \u{1f4f6}"
+        );
+        assert_eq!(log_text(b""), "");
+    }
+
+    #[test]
+    fn log_text_of_non_utf8_output() {
+        // An SSID in Latin-1, as iw prints the raw bytes.
+        assert_eq!(
+            log_text(b"SSID: caf\xe9\n"),
+            format!("SSID: caf\u{fffd} {INVALID_UTF8_MARKER}")
+        );
+        assert_eq!(
+            log_text(b"\xff\xfe"),
+            format!("\u{fffd}\u{fffd} {INVALID_UTF8_MARKER}")
+        );
+    }
+
+    #[test]
+    fn ipv4_of_command_output() {
+        assert_eq!(
+            ipv4_of(b"192.168.1.10\n").unwrap(),
+            Some(Ipv4Addr::new(192, 168, 1, 10))
+        );
+        assert_eq!(
+            ipv4_of(b"10.0.0.1\n10.0.0.2\n").unwrap(),
+            Some(Ipv4Addr::new(10, 0, 0, 1))
+        );
+        assert_eq!(ipv4_of(b"").unwrap(), None);
+        assert_eq!(ipv4_of(b" \n").unwrap(), None);
+        assert!(ipv4_of(b"fe80::1\n").is_err());
+    }
+
+    #[test]
+    fn ipv4_of_non_utf8_output() {
+        let err = ipv4_of(b"10.0.0.\xff\n").unwrap_err().to_string();
+        assert_eq!(
+            err,
+            format!("expected an IPv4 address, got `10.0.0.\u{fffd} {INVALID_UTF8_MARKER}`")
+        );
+    }
+
+    #[test]
+    fn safe_host_ids_are_file_stems() {
+        assert_eq!(sanitize_file_stem("nuc-1"), "nuc-1");
+        assert_eq!(sanitize_file_stem("ap_2"), "ap_2");
+        // Unicode letters are safe in file names.
+        assert_eq!(sanitize_file_stem("café"), "café");
+    }
+
+    #[test]
+    fn unsafe_host_ids_are_replaced() {
+        let stem = |id: &str, safe: &str| format!("{safe}-{:08x}", crc32fast::hash(id.as_bytes()));
+        assert_eq!(sanitize_file_stem("nuc.1"), stem("nuc.1", "nuc_1"));
+        assert_eq!(sanitize_file_stem("nuc/1"), stem("nuc/1", "nuc_1"));
+        assert_eq!(sanitize_file_stem("../etc"), stem("../etc", "___etc"));
+        assert_eq!(sanitize_file_stem("café 📶"), stem("café 📶", "café__"));
+        assert_eq!(sanitize_file_stem(""), stem("", ""));
+    }
+
+    #[test]
+    fn replaced_host_ids_do_not_collide() {
+        let ids = ["nuc.1", "nuc/1", "nuc 1", "nuc_1"];
+        let stems: std::collections::BTreeSet<_> =
+            ids.iter().map(|id| sanitize_file_stem(id)).collect();
+        assert_eq!(stems.len(), ids.len());
+    }
+
+    #[test]
+    fn host_files_stay_in_their_directory() {
+        let dir = Path::new("/results/run");
+        for id in ["../../etc/passwd", "/abs", "a/b", ".", ".."] {
+            let file = host_file(dir, id, "pcapng");
+            assert_eq!(file.parent(), Some(dir), "{id}");
+            assert!(file.to_str().unwrap().ends_with(".pcapng"));
+        }
+        assert_eq!(
+            host_file(dir, "ap", "txt"),
+            Path::new("/results/run/ap.txt")
+        );
+    }
+
+    fn output(code: i32, stdout: &str, stderr: &str) -> Output {
+        use std::os::unix::process::ExitStatusExt;
+        Output {
+            status: std::process::ExitStatus::from_raw(code << 8),
+            stdout: stdout.as_bytes().to_vec(),
+            stderr: stderr.as_bytes().to_vec(),
+        }
+    }
+
+    #[test]
+    fn exit_error_shows_the_error_output() {
+        let output = output(1, "scanning\n", "iw: command failed: Device busy (-16)\n");
+        assert_eq!(
+            exit_error("iw dev wlan0 scan", &output),
+            "`iw dev wlan0 scan` failed with exit status: 1: iw: command failed: Device busy (-16)"
+        );
+    }
+
+    #[test]
+    fn exit_error_falls_back_to_the_regular_output() {
+        let output = output(2, "Error: no such network\n", " \n");
+        assert_eq!(
+            exit_error("nmcli dev wifi connect x", &output),
+            "`nmcli dev wifi connect x` failed with exit status: 2: Error: no such network"
+        );
+    }
+
+    #[test]
+    fn exit_error_without_output() {
+        assert_eq!(
+            exit_error("false", &output(1, "", "")),
+            "`false` failed with exit status: 1"
+        );
+    }
+
+    #[test]
+    fn exit_error_keeps_the_last_lines() {
+        let stderr: String = (1..=7).map(|i| format!("line {i}\n")).collect();
+        assert_eq!(
+            exit_error("make", &output(2, "", &stderr)),
+            "`make` failed with exit status: 2: ...\nline 3\nline 4\nline 5\nline 6\nline 7"
+        );
+        let stderr: String = (1..=5).map(|i| format!("line {i}\n")).collect();
+        assert_eq!(
+            exit_error("make", &output(2, "", &stderr)),
+            "`make` failed with exit status: 2: line 1\nline 2\nline 3\nline 4\nline 5"
+        );
+    }
+
+    /// The lines [read_lines] passes on for `input`, and what it returns.
+    async fn lines_of(input: &[u8]) -> (Vec<String>, Vec<u8>) {
+        let lines = std::sync::Mutex::new(Vec::new());
+        let on_line = |kind, line: &str| {
+            assert_eq!(kind, Line::Stderr);
+            lines.lock().unwrap().push(line.to_string());
+        };
+        let all = read_lines(input, Line::Stderr, &on_line).await.unwrap();
+        (lines.into_inner().unwrap(), all)
+    }
+
+    #[tokio::test]
+    async fn streamed_lines() {
+        let input = b"[  5]   0.00-1.00 sec  11.2 MBytes  94.1 Mbits/sec\n\
+                      [  5]   1.00-2.00 sec  11.1 MBytes  93.3 Mbits/sec\r\n\
+                      \n\
+                      - - - - - -";
+        let (lines, all) = lines_of(input).await;
+        assert_eq!(
+            lines,
+            [
+                "[  5]   0.00-1.00 sec  11.2 MBytes  94.1 Mbits/sec",
+                "[  5]   1.00-2.00 sec  11.1 MBytes  93.3 Mbits/sec",
+                "",
+                "- - - - - -",
+            ]
+        );
+        assert_eq!(all, input);
+    }
+
+    #[tokio::test]
+    async fn streamed_lines_without_output() {
+        assert_eq!(lines_of(b"").await, (Vec::new(), Vec::new()));
+    }
+
+    #[tokio::test]
+    async fn long_streamed_lines_are_split() {
+        let mut input = vec![b'x'; MAX_LINE_LENGTH + 10];
+        input.extend_from_slice(b"\nend\n");
+        let (lines, all) = lines_of(&input).await;
+        assert_eq!(lines.len(), 3);
+        assert_eq!(lines[0].len(), MAX_LINE_LENGTH);
+        assert_eq!(lines[1], "x".repeat(10));
+        assert_eq!(lines[2], "end");
+        assert_eq!(all, input);
+    }
+
+    #[tokio::test]
+    async fn streamed_lines_that_are_not_utf8() {
+        let input = b"SSID: caf\xe9\nok\n";
+        let (lines, all) = lines_of(input).await;
+        assert_eq!(
+            lines,
+            [
+                format!("SSID: caf\u{fffd} {INVALID_UTF8_MARKER}"),
+                "ok".to_string()
+            ]
+        );
+        // The raw bytes are kept for saving the output.
+        assert_eq!(all, input);
+    }
+
+    /// Retry with `policy`, making the attempts in `results` in order. Returns the result and how
+    /// many attempts were made.
+    async fn retried(
+        policy: RetryPolicy,
+        results: Vec<Output>,
+    ) -> (Result<Output, openssh::Error>, usize) {
+        let mut results = results.into_iter();
+        let mut attempts = 0;
+        let result = retry("host", policy, || {
+            attempts += 1;
+            let output = results.next().expect("no more attempts");
+            async move { Ok(output) }
+        })
+        .await;
+        (result, attempts)
+    }
+
+    const SSH_NOW: RetryPolicy = RetryPolicy {
+        delay: Duration::ZERO,
+        ..RetryPolicy::SSH
+    };
+
+    fn mux_failure() -> Output {
+        output(
+            255,
+            "",
+            "mux_client_request_session: read from master failed\n",
+        )
+    }
+
+    #[tokio::test]
+    async fn command_failing_once_is_retried() {
+        let (result, attempts) = retried(SSH_NOW, vec![mux_failure(), output(0, "up\n", "")]).await;
+        assert_eq!(result.unwrap().stdout, b"up\n");
+        assert_eq!(attempts, 2);
+    }
+
+    #[tokio::test]
+    async fn successful_command_is_not_retried() {
+        let (result, attempts) = retried(SSH_NOW, vec![output(0, "up\n", "")]).await;
+        assert!(result.unwrap().status.success());
+        assert_eq!(attempts, 1);
+    }
+
+    #[tokio::test]
+    async fn failing_command_is_not_retried() {
+        // The command ran and failed itself, so running it again does not help.
+        let failed = output(1, "", "No such device\n");
+        let (result, attempts) = retried(SSH_NOW, vec![failed]).await;
+        assert_eq!(result.unwrap().status.code(), Some(1));
+        assert_eq!(attempts, 1);
+    }
+
+    #[tokio::test]
+    async fn retries_stop_after_the_attempts() {
+        let failures = vec![mux_failure(), mux_failure(), mux_failure()];
+        let (result, attempts) = retried(SSH_NOW, failures).await;
+        assert_eq!(result.unwrap().status.code(), Some(255));
+        assert_eq!(attempts, 3);
+    }
+
+    #[test]
+    fn ssh_failures() {
+        assert!(is_ssh_failure(&Ok(mux_failure())));
+        let refused = output(255, "", "session request failed on channel 0\n");
+        assert!(is_ssh_failure(&Ok(refused)));
+        assert!(is_ssh_failure(&Err(openssh::Error::Disconnected)));
+        // Commands can exit with 255 themselves.
+        assert!(!is_ssh_failure(&Ok(output(255, "", "iperf3: error\n"))));
+        assert!(!is_ssh_failure(&Ok(output(
+            1,
+            "",
+            "mux_client_request_session\n"
+        ))));
+    }
+
+    #[test]
+    fn durations_with_units() {
+        assert_eq!(parse_duration("90"), Ok(Duration::from_secs(90)));
+        assert_eq!(parse_duration("90s"), Ok(Duration::from_secs(90)));
+        assert_eq!(parse_duration("15m"), Ok(Duration::from_secs(900)));
+        assert_eq!(parse_duration("1.5h"), Ok(Duration::from_secs(5400)));
+    }
+
+    #[test]
+    fn invalid_durations() {
+        for value in ["", "m", "abc", "0", "-5m", "nanh", "infs"] {
+            assert!(parse_duration(value).is_err(), "{value}");
+        }
+    }
+
+    #[test]
+    fn durations_that_are_too_large() {
+        assert_eq!(
+            parse_duration("1e300h"),
+            Err("invalid duration `1e300h`, the duration is too large".to_string())
+        );
+        assert!(parse_duration("1e20s").is_err());
+    }
+
+    #[test]
+    fn valid_channels() {
+        for (frequency, bandwidth) in [
+            (2412, 20),
+            (2437, 40),
+            (2472, 40),
+            (5180, 80),
+            (5240, 80),
+            (5180, 160),
+            (5500, 160),
+            (5745, 80),
+            (5825, 80),
+            (5955, 160),
+        ] {
+            assert_eq!(check_channel(frequency, bandwidth), Ok(()), "{frequency}");
+        }
+    }
+
+    #[test]
+    fn invalid_channels() {
+        for (frequency, bandwidth) in [(2437, 80), (2412, 160), (5720, 160), (5165, 20)] {
+            assert!(
+                check_channel(frequency, bandwidth).is_err(),
+                "{frequency} {bandwidth}"
+            );
+        }
+        assert_eq!(
+            check_channel(2437, 80),
+            Err("channel 6 (2437 MHz) is not part of a 80 MHz wide channel".to_string())
+        );
+        assert_eq!(
+            check_channel(5175, 40),
+            Err("5175 MHz is not a known channel".to_string())
+        );
+    }
+}